@@ -28,7 +28,9 @@ pub fn init<'a>() -> ArgMatches<'a> {
                 .arg(Arg::with_name("command-buffer-size").long("command-buffer-size")
                      .takes_value(true).required(true).help("Worker's channel buffer size"))
                 .arg(Arg::with_name("max-command-buffer-size").long("max-command-buffer-size")
-                     .takes_value(true).required(true).help("Worker's channel max buffer size")))
+                     .takes_value(true).required(true).help("Worker's channel max buffer size"))
+                .arg(Arg::with_name("cpus").long("cpus")
+                     .takes_value(true).required(false).help("comma separated list of the CPU cores the worker is bound to")))
     .subcommand(SubCommand::with_name("upgrade")
                 .about("start a new master process (internal command, should not be used directly)")
                 .arg(Arg::with_name("fd").long("fd")
@@ -75,6 +77,14 @@ pub fn get_max_buffer_size<'a>(matches: &ArgMatches<'a>, buffer_size: usize) ->
     .unwrap_or(buffer_size * 2)
 }
 
+pub fn get_cpus<'a>(matches: &ArgMatches<'a>) -> Vec<usize> {
+  matches.value_of("cpus").map(parse_cpus).unwrap_or_default()
+}
+
+pub fn parse_cpus(cpus: &str) -> Vec<usize> {
+  cpus.split(',').map(|cpu| cpu.trim().parse::<usize>().expect("the CPU cores must be numbers")).collect()
+}
+
 pub fn get_upgrade_fd<'a>(matches: &ArgMatches<'a>) -> i32 {
   matches.value_of("upgrade-fd").expect("needs an upgrade file descriptor")
     .parse::<i32>().expect("the file descriptor must be a number")
@@ -88,8 +98,9 @@ pub fn upgrade_worker<'a>(matches: &ArgMatches<'a>) -> Option<()> {
     let id = get_id(worker_matches);
     let buffer_size = get_buffer_size(worker_matches);
     let max_buffer_size = get_max_buffer_size(worker_matches, buffer_size);
+    let cpus = get_cpus(worker_matches);
 
-    begin_worker_process(fd, scm, configuration_state_fd, id, buffer_size, max_buffer_size);
+    begin_worker_process(fd, scm, configuration_state_fd, id, buffer_size, max_buffer_size, cpus);
     Some(())
   })
 }
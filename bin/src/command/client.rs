@@ -1,9 +1,6 @@
 use mio::*;
 use mio_uds::UnixStream;
-use std::str::from_utf8;
 use std::collections::VecDeque;
-use nom::IResult;
-use serde_json::from_str;
 
 use sozu_command::channel::Channel;
 
@@ -34,10 +31,3 @@ impl CommandClient {
     self.channel.readiness().is_readable() || (!self.queue.is_empty() && self.channel.readiness().is_writable())
   }
 }
-
-pub fn parse(input: &[u8]) -> IResult<&[u8], Vec<CommandRequest>> {
-  many0!(input,
-    complete!(terminated!(map_res!(map_res!(is_not!("\0"), from_utf8), from_str), char!('\0')))
-  )
-}
-
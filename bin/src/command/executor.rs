@@ -6,7 +6,7 @@ use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
 use slab::Slab;
 use std::collections::{HashSet, VecDeque};
-use sozu_command::proxy::{ProxyRequest, ProxyResponse, ProxyResponseStatus};
+use sozu_command::proxy::{ProxyRequest, ProxyRequestData, ProxyResponse, ProxyResponseStatus};
 use sozu_command::command::CommandResponse;
 use super::FrontToken;
 
@@ -43,10 +43,13 @@ pub enum MessageStatus {
   Other,
 }
 
-#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+#[derive(Clone, PartialEq, Eq, Hash)]
 pub enum StateChange {
   StopWorker(Token),
   StopMaster,
+  /// orders reverting a state change that failed on a worker, applied to
+  /// the state and sent to the workers
+  Rollback(Vec<ProxyRequestData>),
 }
 
 impl Runner {
@@ -118,6 +121,11 @@ impl Executor {
     queue.push_back(StateChange::StopMaster);
   }
 
+  pub fn rollback(orders: Vec<ProxyRequestData>) {
+    let mut queue = EXECUTOR.state_queue.lock().unwrap();
+    queue.push_back(StateChange::Rollback(orders));
+  }
+
   pub fn get_state_change() -> Option<StateChange> {
     let mut queue = EXECUTOR.state_queue.lock().unwrap();
     queue.pop_front()
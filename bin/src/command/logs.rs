@@ -0,0 +1,129 @@
+//! clients following the access logs of the workers
+//!
+//! The workers only send their access logs while at least one client is
+//! subscribed: the first subscription starts the stream, and the
+//! disconnection of the last subscriber stops it.
+use sozu_command::command::LogFilter;
+use sozu_command::proxy::AccessLog;
+
+use super::FrontToken;
+
+pub struct LogSubscribers {
+  subscribers: Vec<(FrontToken, LogFilter)>,
+}
+
+impl LogSubscribers {
+  pub fn new() -> LogSubscribers {
+    LogSubscribers { subscribers: Vec::new() }
+  }
+
+  /// returns true for the first subscriber, the workers must start streaming.
+  /// A client subscribing again replaces its filter
+  pub fn subscribe(&mut self, token: FrontToken, filter: LogFilter) -> bool {
+    if let Some(subscriber) = self.subscribers.iter_mut().find(|&&mut (t, _)| t == token) {
+      subscriber.1 = filter;
+      return false;
+    }
+
+    self.subscribers.push((token, filter));
+    self.subscribers.len() == 1
+  }
+
+  /// returns true if the last subscriber left, the workers can stop streaming
+  pub fn unsubscribe(&mut self, token: FrontToken) -> bool {
+    match self.subscribers.iter().position(|&(t, _)| t == token) {
+      Some(pos) => {
+        self.subscribers.remove(pos);
+        self.subscribers.is_empty()
+      },
+      None => false,
+    }
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.subscribers.is_empty()
+  }
+
+  /// clients whose filter matches this access log
+  pub fn recipients(&self, log: &AccessLog) -> Vec<FrontToken> {
+    self.subscribers.iter()
+      .filter(|&&(_, ref filter)| filter.matches(log))
+      .map(|&(token, _)| token)
+      .collect()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn access_log(app_id: &str, status: u16) -> AccessLog {
+    AccessLog {
+      time:          String::from("2019-03-12T10:00:00.000000Z"),
+      message:       format!("request to {}", app_id),
+      request_id:    String::from("ID-1"),
+      app_id:        Some(String::from(app_id)),
+      backend_id:    None,
+      client:        None,
+      backend:       None,
+      protocol:      String::from("HTTP"),
+      method:        Some(String::from("GET")),
+      host:          Some(String::from("example.com")),
+      path:          Some(String::from("/")),
+      status:        Some(status),
+      response_time: 12,
+      service_time:  3,
+      latency:       10,
+      bytes_in:      100,
+      bytes_out:     2000,
+      error:         None,
+      retries:       0,
+    }
+  }
+
+  fn filter(app_id: Option<&str>, status: Option<u16>) -> LogFilter {
+    LogFilter { app_id: app_id.map(String::from), status }
+  }
+
+  #[test]
+  fn subscription_lifecycle() {
+    let mut subscribers = LogSubscribers::new();
+    assert!(subscribers.is_empty());
+
+    // only the first subscription starts the stream
+    assert!(subscribers.subscribe(FrontToken(1), filter(None, None)));
+    assert!(!subscribers.subscribe(FrontToken(2), filter(Some("app_1"), None)));
+    assert!(!subscribers.subscribe(FrontToken(2), filter(Some("app_2"), None)));
+
+    // a client that did not subscribe does not change the stream
+    assert!(!subscribers.unsubscribe(FrontToken(3)));
+    assert!(!subscribers.unsubscribe(FrontToken(1)));
+    assert!(!subscribers.is_empty());
+    // the last disconnection stops it
+    assert!(subscribers.unsubscribe(FrontToken(2)));
+    assert!(subscribers.is_empty());
+    assert!(!subscribers.unsubscribe(FrontToken(2)));
+
+    assert!(subscribers.subscribe(FrontToken(2), filter(None, None)));
+  }
+
+  #[test]
+  fn filtered_recipients() {
+    let mut subscribers = LogSubscribers::new();
+    subscribers.subscribe(FrontToken(1), filter(None, None));
+    subscribers.subscribe(FrontToken(2), filter(Some("app_1"), None));
+    subscribers.subscribe(FrontToken(3), filter(Some("app_1"), Some(502)));
+    subscribers.subscribe(FrontToken(4), filter(None, Some(404)));
+
+    assert_eq!(subscribers.recipients(&access_log("app_1", 200)), vec![FrontToken(1), FrontToken(2)]);
+    assert_eq!(subscribers.recipients(&access_log("app_1", 502)), vec![FrontToken(1), FrontToken(2), FrontToken(3)]);
+    assert_eq!(subscribers.recipients(&access_log("app_2", 404)), vec![FrontToken(1), FrontToken(4)]);
+
+    // the new filter replaces the previous one
+    subscribers.subscribe(FrontToken(2), filter(Some("app_2"), None));
+    assert_eq!(subscribers.recipients(&access_log("app_2", 404)), vec![FrontToken(1), FrontToken(2), FrontToken(4)]);
+
+    subscribers.unsubscribe(FrontToken(1));
+    assert_eq!(subscribers.recipients(&access_log("app_1", 200)), Vec::<FrontToken>::new());
+  }
+}
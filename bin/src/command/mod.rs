@@ -9,28 +9,53 @@ use std::io::{self,ErrorKind};
 use std::os::unix::fs::PermissionsExt;
 use std::collections::{HashMap,VecDeque};
 use std::time::Duration;
+use std::sync::atomic::{AtomicBool,Ordering};
 use libc::pid_t;
 use nix::unistd::Pid;
-use nix::sys::signal::{kill,Signal};
+use nix::sys::signal::{kill,sigaction,Signal,SigAction,SigHandler,SaFlags,SigSet};
 
 use sozu::metrics::METRICS;
 use sozu_command::config::Config;
+use sozu_command::certificate::{IntermediateCache, OcspCache};
 use sozu_command::channel::Channel;
 use sozu_command::state::ConfigState;
 use sozu_command::command::{self,CommandRequest,CommandResponse,CommandResponseData,CommandStatus,RunState};
-use sozu_command::proxy::{ProxyRequest,ProxyResponse,ProxyResponseData};
+use sozu_command::proxy::{ProxyRequest,ProxyRequestData,ProxyResponse,ProxyResponseData,ProxyResponseStatus,AccessLog};
 use sozu_command::scm_socket::{Listeners,ScmSocket};
 
 pub mod executor;
 pub mod orders;
 pub mod client;
+pub mod logs;
+pub mod prometheus;
 
 use worker::{start_worker, get_executable_path};
 use self::client::CommandClient;
 use self::executor::{Executor, StateChange};
+use self::logs::LogSubscribers;
+use self::prometheus::PrometheusEndpoint;
 
 const SERVER: Token = Token(0);
 const HALF_USIZE: usize = usize::MAX / 2 + 1;
+/// between the worker tokens and the client tokens
+const PROMETHEUS: Token = Token(HALF_USIZE + 1);
+/// timeout in seconds of the intermediate certificate downloads
+pub const INTERMEDIATE_FETCH_TIMEOUT: u64 = 5;
+
+/// set by the SIGHUP handler, the event loop then reloads the configuration.
+/// Several signals received before the reload only trigger one reload
+static RELOAD_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn request_reload(_: libc::c_int) {
+  RELOAD_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+fn handle_reload_signal() {
+  let action = SigAction::new(SigHandler::Handler(request_reload), SaFlags::SA_RESTART, SigSet::empty());
+  if let Err(e) = unsafe { sigaction(Signal::SIGHUP, &action) } {
+    error!("could not set the SIGHUP handler, the configuration will not be reloaded on SIGHUP: {}", e);
+  }
+}
 
 #[derive(Copy,Clone,Debug,PartialEq,Eq,PartialOrd,Ord,Hash)]
 pub struct FrontToken(pub usize);
@@ -55,19 +80,22 @@ pub struct Worker {
   pub run_state:     RunState,
   pub queue:         VecDeque<ProxyRequest>,
   pub scm:           ScmSocket,
+  /// CPU cores the worker is bound to
+  pub cpus:          Vec<usize>,
 }
 
 impl Worker {
-  pub fn new(id: u32, pid: pid_t, channel: Channel<ProxyRequest,ProxyResponse>, scm: ScmSocket, _: &Config)
+  pub fn new(id: u32, pid: pid_t, channel: Channel<ProxyRequest,ProxyResponse>, scm: ScmSocket, config: &Config)
     -> Worker {
     Worker {
       id:         id,
       channel:    channel,
       token:      None,
       pid:        pid,
-      run_state:  RunState::Running,
+      run_state:  RunState::Initializing,
       queue:      VecDeque::new(),
       scm:        scm,
+      cpus:       config.worker_cpus(id),
     }
   }
 
@@ -100,6 +128,9 @@ pub struct CommandServer {
   clients:           Slab<CommandClient,FrontToken>,
   workers:           HashMap<Token, Worker>,
   event_subscribers: Vec<FrontToken>,
+  log_subscribers:   LogSubscribers,
+  /// last run state of each worker sent to the event subscribers
+  reported_workers:  HashMap<u32, RunState>,
   next_id:           u32,
   state:             ConfigState,
   pub poll:          Poll,
@@ -111,6 +142,12 @@ pub struct CommandServer {
   backends_count:    usize,
   //caching the number of frontends instead of going through the whole state.http/hhtps/tcp_fronts hashmaps
   frontends_count:   usize,
+  /// intermediate certificates fetched to complete the certificate chains
+  intermediates:     IntermediateCache,
+  /// OCSP responses stapled by the workers, if `ocsp_stapling` is enabled
+  ocsp_responses:    OcspCache,
+  /// HTTP endpoint serving the metrics, if `prometheus` is configured
+  prometheus:        Option<PrometheusEndpoint>,
 }
 
 impl CommandServer {
@@ -154,6 +191,20 @@ impl CommandServer {
     }
 
 
+    let prometheus = config.prometheus.as_ref().and_then(|prometheus| {
+      match PrometheusEndpoint::bind(prometheus) {
+        Ok(endpoint) => {
+          poll.register(&endpoint.listener, PROMETHEUS, Ready::readable(), PollOpt::edge())
+            .expect("should register the prometheus listener");
+          Some(endpoint)
+        },
+        Err(e) => {
+          error!("could not bind the prometheus endpoint on {}: {}", prometheus.address, e);
+          None
+        }
+      }
+    });
+
     let next_id = worker_vec.len();
 
     let mut workers = HashMap::new();
@@ -184,6 +235,8 @@ impl CommandServer {
       max_buffer_size:   config.max_command_buffer_size,
       clients:           Slab::with_capacity(1024),
       event_subscribers: Vec::new(),
+      log_subscribers:   LogSubscribers::new(),
+      reported_workers:  HashMap::new(),
       workers:           workers,
       next_id:           next_id as u32,
       state:             state,
@@ -194,6 +247,9 @@ impl CommandServer {
       executable_path:   path,
       backends_count:    backends_count,
       frontends_count:   frontends_count,
+      intermediates:     IntermediateCache::new(Duration::from_secs(INTERMEDIATE_FETCH_TIMEOUT)),
+      ocsp_responses:    OcspCache::new(Duration::from_secs(INTERMEDIATE_FETCH_TIMEOUT)),
+      prometheus,
     }
   }
 
@@ -213,18 +269,27 @@ impl CommandServer {
     let poll_timeout = Some(Duration::from_millis(1000));
     let max_poll_errors = 10000;
     let mut current_poll_errors = 0;
+    handle_reload_signal();
     loop {
       if current_poll_errors == max_poll_errors {
         error!("Something is going very wrong. Last {} poll() calls failed, crashing..", current_poll_errors);
         panic!("poll() calls failed {} times in a row", current_poll_errors);
       }
 
-      if let Err(error) = self.poll.poll(&mut events, poll_timeout) {
-        error!("Error while polling events: {:?}", error);
-        current_poll_errors += 1;
-        continue;
-      } else {
-        current_poll_errors = 0;
+      if RELOAD_REQUESTED.swap(false, Ordering::SeqCst) {
+        info!("received SIGHUP, reloading the configuration");
+        self.reload_configuration();
+      }
+
+      match self.poll.poll(&mut events, poll_timeout) {
+        // a signal interrupted the poll, it is handled at the start of the loop
+        Err(ref error) if error.kind() == ErrorKind::Interrupted => continue,
+        Err(error) => {
+          error!("Error while polling events: {:?}", error);
+          current_poll_errors += 1;
+          continue;
+        },
+        Ok(_) => current_poll_errors = 0,
       }
 
       for event in events.iter() {
@@ -278,6 +343,8 @@ impl CommandServer {
         }
       }
 
+      self.notify_worker_events();
+      self.refresh_ocsp_responses();
 
       METRICS.with(|metrics| {
         (*metrics.borrow_mut()).send_data();
@@ -310,6 +377,7 @@ impl CommandServer {
           (*metrics.borrow_mut()).writable();
         });
       },
+      PROMETHEUS => self.accept_prometheus(),
       Token(i) if i < HALF_USIZE + 1 => {
         if let Some(ref mut worker) =self.workers.get_mut(&Token(i)) {
           worker.channel.handle_events(events);
@@ -358,6 +426,18 @@ impl CommandServer {
         },
         StateChange::StopMaster => {
           self.must_stop = true;
+        },
+        StateChange::Rollback(orders) => {
+          info!("rolling back {} orders", orders.len());
+          for (index, order) in orders.into_iter().enumerate() {
+            self.state.handle_order(&order);
+            for worker in self.workers.values_mut()
+              .filter(|worker| worker.run_state != RunState::Stopping && worker.run_state != RunState::Stopped) {
+              worker.push_message(ProxyRequest { id: format!("ROLLBACK-{}", index), order: order.clone() });
+            }
+          }
+          self.backends_count = self.state.count_backends();
+          self.frontends_count = self.state.count_frontends();
         }
       }
     }
@@ -465,6 +545,9 @@ impl CommandServer {
         if let Some(pos) = self.event_subscribers.iter().position(|t| t == &conn_token) {
           let _ = self.event_subscribers.remove(pos);
         }
+        if self.log_subscribers.unsubscribe(conn_token) {
+          self.stream_access_logs(false);
+        }
 
         trace!("closed client [{}]", conn_token.0);
       } else {
@@ -537,22 +620,67 @@ impl CommandServer {
 
   fn handle_worker_message(&mut self, token: Token, msg: ProxyResponse) {
     if let Some(ProxyResponseData::Event(data)) = msg.data {
-      let event: command::Event = data.into();
-      for client_token in self.event_subscribers.iter() {
-        let event = CommandResponse::new(
-          msg.id.to_string(),
-          CommandStatus::Processing,
-          format!("{}", token.0),
-          Some(CommandResponseData::Event(event.clone()))
-        );
-
-        self.clients.get_mut(*client_token).map(|cl| cl.push_message(event));
-      }
+      self.push_event(&msg.id, format!("{}", token.0), data.into());
+    } else if let Some(ProxyResponseData::AccessLog(log)) = msg.data {
+      self.push_access_log(token, log);
     } else {
+      if msg.id.ends_with("CONFIGURATION-LOADED") && msg.status == ProxyResponseStatus::Ok {
+        if !self.log_subscribers.is_empty() {
+          if let Some(worker) = self.workers.get_mut(&token) {
+            worker.push_message(ProxyRequest { id: String::from("STREAM-ACCESS-LOGS"), order: ProxyRequestData::StreamAccessLogs(true) });
+          }
+        }
+        if let Some(worker) = self.workers.get_mut(&token) {
+          worker.run_state = worker.run_state.configured();
+        }
+        if let Some(id) = self.workers.get(&token).map(|worker| worker.id) {
+          // the worker's start is reported before its configuration
+          self.notify_worker_events();
+          self.push_event("LIFECYCLE", format!("{}", id), command::Event::WorkerConfigured(id));
+        }
+      }
+
       Executor::handle_message(token, msg);
     }
   }
 
+  /// sends an event to the clients subscribed to events
+  pub fn push_event(&mut self, id: &str, message: String, event: command::Event) {
+    for client_token in self.event_subscribers.iter() {
+      let event = CommandResponse::new(
+        id.to_string(),
+        CommandStatus::Processing,
+        message.clone(),
+        Some(CommandResponseData::Event(event.clone()))
+      );
+
+      self.clients.get_mut(*client_token).map(|cl| cl.push_message(event));
+    }
+  }
+
+  /// sends a worker's access log to the clients following the logs
+  fn push_access_log(&mut self, token: Token, log: AccessLog) {
+    let worker_id = self.workers.get(&token).map(|worker| worker.id.to_string()).unwrap_or_default();
+    for client_token in self.log_subscribers.recipients(&log) {
+      let message = CommandResponse::new(
+        String::from("ACCESS-LOG"),
+        CommandStatus::Processing,
+        worker_id.clone(),
+        Some(CommandResponseData::AccessLog(log.clone()))
+      );
+
+      self.clients.get_mut(client_token).map(|cl| cl.push_message(message));
+    }
+  }
+
+  /// starts or stops the access log stream of the running workers
+  pub fn stream_access_logs(&mut self, enabled: bool) {
+    for ref mut worker in self.workers.values_mut()
+      .filter(|worker| worker.run_state != RunState::Stopping && worker.run_state != RunState::Stopped) {
+      worker.push_message(ProxyRequest { id: String::from("STREAM-ACCESS-LOGS"), order: ProxyRequestData::StreamAccessLogs(enabled) });
+    }
+  }
+
   pub fn check_worker_status(&mut self, token: Token) {
     {
       let ref mut worker = self.workers.get_mut(&token).expect("there should be a worker at that token");
@@ -618,6 +746,10 @@ impl CommandServer {
           });
           count += 1;
         }
+        worker.push_message(ProxyRequest {
+          id: format!("RESTART-{}-CONFIGURATION-LOADED", id),
+          order: ProxyRequestData::ConfigurationLoaded,
+        });
 
         self.poll.register(&worker.channel.sock, Token(worker_token),
           Ready::readable() | Ready::writable() | UnixReady::error() | UnixReady::hup(),
@@ -662,6 +794,8 @@ pub fn start(config: Config, command_socket_path: String, workers: Vec<Worker>)
         server.load_state(None, "INITIALIZATION", state_path);
       });
 
+      server.notify_configuration_loaded();
+
       gauge!("configuration.applications", server.state.applications.len());
       gauge!("configuration.backends", server.backends_count);
       gauge!("configuration.frontends", server.frontends_count);
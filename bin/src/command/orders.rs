@@ -1,36 +1,42 @@
 use std::fs;
-use std::str;
+use std::path::Path;
 use std::process;
-use std::io::{self,Read,Write};
+use std::io::{self,Write};
 use std::convert::Into;
 use std::thread::sleep;
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use std::collections::{HashMap,BTreeMap};
 use std::os::unix::io::{AsRawFd,FromRawFd};
+use libc;
 use slab::Slab;
+use tempfile::NamedTempFile;
 use serde_json;
 use mio::unix::UnixReady;
 use mio_uds::{UnixListener,UnixStream};
 use mio::{Poll,PollOpt,Ready,Token};
-use nom::{Err,HexDisplay,Offset};
+use mio::net::TcpListener;
 
-use sozu_command::buffer::Buffer;
 use sozu_command::channel::Channel;
 use sozu_command::scm_socket::{Listeners, ScmSocket};
 use sozu_command::proxy::{ProxyRequestData, ProxyRequest, Query, QueryAnswer, QueryApplicationType,
-MetricsData, AggregatedMetricsData, ProxyResponseData, HttpFront, TcpFront, ProxyResponseStatus};
-use sozu_command::command::{CommandResponseData,CommandRequestData,CommandRequest,CommandResponse,CommandStatus,RunState,WorkerInfo};
-use sozu_command::state::get_application_ids_by_domain;
+MetricsData, AggregatedMetricsData, ProxyResponseData, HttpFront, TcpFront, DrainBackend, ResetBackend, ProxyResponseStatus,
+ListenerType, AppTimeout};
+use sozu_command::command::{CommandResponseData,CommandRequestData,CommandRequest,CommandResponse,CommandStatus,RunState,WorkerInfo,Event,StateFormat};
+use sozu_command::state::{ConfigState,get_application_ids_by_domain,to_canonical_json,verify_state_file};
+use sozu_command::config::{FileConfig,application_orders};
 use sozu_command::logging;
+use sozu_command::certificate::{IntermediateCache, OcspCache};
 use sozu::metrics::METRICS;
 
-use super::{CommandServer,FrontToken,Worker};
-use super::client::parse;
+use super::{CommandServer,FrontToken,Worker,INTERMEDIATE_FETCH_TIMEOUT,PROMETHEUS};
+use super::prometheus::PrometheusEndpoint;
 use worker::{start_worker,get_executable_path};
 use upgrade::{start_new_master_process,SerializedWorker,UpgradeData};
 use util;
 
 use super::executor;
+use super::logs::LogSubscribers;
+use super::prometheus;
 use futures::future::join_all;
 use futures::Future;
 
@@ -39,8 +45,8 @@ impl CommandServer {
     //info!("handle_client_message: front token = {:?}, message = {:#?}", token, message);
     let config_command = message.data.clone();
     match config_command {
-      CommandRequestData::SaveState(path) => {
-        self.save_state(token, &message.id, &path);
+      CommandRequestData::SaveState(path, format) => {
+        self.save_state(token, &message.id, &path, format);
       },
       CommandRequestData::DumpState => {
         self.dump_state(token, &message.id);
@@ -61,6 +67,7 @@ impl CommandServer {
       CommandRequestData::Proxy(order) => {
         match order {
           ProxyRequestData::Metrics => self.metrics(token, &message.id),
+          ProxyRequestData::ResetMetrics => self.reset_metrics(token, &message.id),
           ProxyRequestData::Query(query) => self.query(token, &message.id, query),
           order => {
             self.worker_order(token, &message.id, order, message.worker_id);
@@ -73,6 +80,15 @@ impl CommandServer {
       CommandRequestData::SubscribeEvents => {
         self.event_subscribers.push(token);
       },
+      CommandRequestData::SubscribeLogs(ref filter) => {
+        if self.log_subscribers.subscribe(token, filter.clone()) {
+          self.stream_access_logs(true);
+        }
+      },
+      // answered by the master without contacting the workers, to check it is alive
+      CommandRequestData::Ping => {
+        self.answer_success(token, message.id.as_str(), "pong", None);
+      },
     }
   }
 
@@ -101,54 +117,21 @@ impl CommandServer {
 
   }
 
-  pub fn save_state(&mut self, token: FrontToken, message_id: &str, path: &str) {
-    if let Ok(mut f) = fs::File::create(&path) {
-
-      let res = self.save_state_to_file(&mut f);
-
-      match res {
-        Ok(counter) => {
-          info!("wrote {} commands to {}", counter, path);
-          self.answer_success(token, message_id, format!("saved {} config messages to {}", counter, path), None);
-        },
-        Err(e) => {
-          error!("failed writing state to file: {:?}", e);
-          self.answer_error(token, message_id, "could not save state to file", None);
-        }
+  pub fn save_state(&mut self, token: FrontToken, message_id: &str, path: &str, format: StateFormat) {
+    match write_state_file(path, |f| self.save_state_to_file(f, format)) {
+      Ok(counter) => {
+        info!("wrote {} commands to {}", counter, path);
+        self.answer_success(token, message_id, format!("saved {} config messages to {}", counter, path), None);
+      },
+      Err(e) => {
+        error!("failed writing state to {}: {:?}", path, e);
+        self.answer_error(token, message_id, format!("could not save state to {}: {}", path, state_file_error(&e)), None);
       }
-    } else {
-      error!("could not open file: {}", &path);
-      self.answer_error(token, message_id, "could not open file", None);
     }
   }
 
-  pub fn save_state_to_file(&mut self, f: &mut fs::File) -> io::Result<usize> {
-    let mut counter = 0usize;
-    let orders = self.state.generate_orders();
-
-    let res: io::Result<usize> = (move || {
-      for command in orders {
-        let message = CommandRequest::new(
-          format!("SAVE-{}", counter),
-          CommandRequestData::Proxy(command),
-          None
-        );
-
-        f.write_all(&serde_json::to_string(&message).map(|s| s.into_bytes()).unwrap_or(vec!()))?;
-        f.write_all(&b"\n\0"[..])?;
-
-        if counter % 1000 == 0 {
-          info!("writing command {}", counter);
-          f.sync_all()?;
-        }
-        counter += 1;
-      }
-      f.sync_all()?;
-
-      Ok(counter)
-    })();
-
-    res
+  pub fn save_state_to_file(&mut self, f: &mut fs::File, format: StateFormat) -> io::Result<usize> {
+    write_state_orders(f, &self.state, format)
   }
 
   pub fn dump_state(&mut self, token: FrontToken, message_id: &str) {
@@ -157,136 +140,116 @@ impl CommandServer {
   }
 
   pub fn load_state(&mut self, token_opt: Option<FrontToken>, message_id: &str, path: &str) {
-    match fs::File::open(&path) {
+    let data = match fs::read(&path) {
       Err(e)   => {
         error!("cannot open file at path '{}': {:?}", path, e);
         if let Some(token) = token_opt {
           self.answer_error(token, message_id, format!("cannot open file at path '{}': {:?}", path, e), None);
         }
+        return;
       },
-      Ok(mut file) => {
-        let mut buffer = Buffer::with_capacity(200000);
-
-        info!("starting to load state from {}", path);
-
-        let mut message_counter = 0;
-        let mut diff_counter = 0;
-
-        let mut futures = Vec::new();
-        loop {
-          let previous = buffer.available_data();
-          //FIXME: we should read in streaming here
-          if let Ok(sz) = file.read(buffer.space()) {
-            buffer.fill(sz);
-          } else {
-            error!("error reading state file");
-            break;
-          }
-
-          if buffer.available_data() == 0 {
-            break;
-          }
+      Ok(data) => data,
+    };
 
+    info!("starting to load state from {}", path);
 
-          let mut offset = 0;
-          match parse(buffer.data()) {
-            Ok((i, orders)) => {
-              if i.len() > 0 {
-                //info!("could not parse {} bytes", i.len());
-                if previous == buffer.available_data() {
-                  error!("error consuming load state message");
-                  break;
-                }
-              }
-              offset = buffer.data().offset(i);
-
-              let mut new_state = self.state.clone();
-              for message in orders {
-                if let CommandRequestData::Proxy(order) = message.data {
-                  message_counter += 1;
-                  new_state.handle_order(&order);
-                }
-              }
+    // the whole file is verified before sending anything to the workers
+    let (new_state, message_counter) = match verify_state_file(&data, &self.state) {
+      Ok(verified) => verified,
+      Err(errors) => {
+        let msg = format!("invalid state file {}, nothing was loaded:\n{}", path, errors.join("\n"));
+        error!("{}", msg);
+        if let Some(token) = token_opt {
+          self.answer_error(token, message_id, msg, None);
+        }
+        return;
+      }
+    };
 
-              let diff = self.state.diff(&new_state);
-              for order in diff {
-                diff_counter += 1;
-                self.state.handle_order(&order);
+    let diff = self.state.diff(&new_state);
+    // applied if a worker refuses one of the orders
+    let rollback = new_state.diff(&self.state);
+    let mut diff_counter = 0;
 
-                let mut found = false;
-                let id = format!("LOAD-STATE-{}-{}", message_id, diff_counter);
+    let mut futures = Vec::new();
+    for order in diff {
+      diff_counter += 1;
+      self.state.handle_order(&order);
+
+      let mut found = false;
+      let id = format!("LOAD-STATE-{}-{}", message_id, diff_counter);
+
+      for ref mut worker in self.workers.values_mut()
+        .filter(|worker| worker.run_state != RunState::Stopping && worker.run_state != RunState::Stopped) {
+        let o = order.clone();
+        futures.push(
+          executor::send(worker.token.expect("worker should have a token"), ProxyRequest { id: id.clone(), order: o })
+        );
+        found = true;
 
-                for ref mut worker in self.workers.values_mut()
-                  .filter(|worker| worker.run_state != RunState::Stopping && worker.run_state != RunState::Stopped) {
-                  let o = order.clone();
-                  futures.push(
-                    executor::send(worker.token.expect("worker should have a token"), ProxyRequest { id: id.clone(), order: o })
-                  );
-                  found = true;
+      }
 
-                }
+      if !found {
+        // FIXME: should send back error here
+        error!("no worker found");
+      }
+    }
 
-                if !found {
-                  // FIXME: should send back error here
-                  error!("no worker found");
-                }
-              }
-            },
-            Err(Err::Incomplete(_)) => {
-              if buffer.available_data() == buffer.capacity() {
-                error!("message too big, stopping parsing:\n{}", buffer.data().to_hex(16));
-                break;
-              }
+    info!("read {} messages from {}, generated {} diff messages", message_counter, path, diff_counter);
+    if diff_counter > 0 {
+      info!("state loaded from {}, will start sending {} messages to workers", path, diff_counter);
+      let id = message_id.to_string();
+      executor::Executor::execute(
+        join_all(futures).map(move |v| {
+          let errors = v.iter().filter_map(|response| match response.status {
+            ProxyResponseStatus::Error(ref e) => Some(format!("{}: {}", response.id, e)),
+            _ => None,
+          }).collect::<Vec<_>>();
+
+          if errors.is_empty() {
+            info!("load_state: {} messages loaded", v.len());
+            if let Some(token) = token_opt {
+              executor::Executor::send_client(token, CommandResponse::new(
+                id,
+                CommandStatus::Ok,
+                format!("ok: {} messages, error: 0", v.len()),
+                None
+              ));
+            }
+          } else {
+            error!("load_state: {} messages failed, rolling back: {}", errors.len(), errors.join(", "));
+            executor::Executor::rollback(rollback);
+            if let Some(token) = token_opt {
+              executor::Executor::send_client(token, CommandResponse::new(
+                id,
+                CommandStatus::Error,
+                format!("the workers refused {} messages, the previous state was restored: {}", errors.len(), errors.join(", ")),
+                None
+              ));
             }
-            Err(e) => {
-              error!("saved state parse error: {:?}", e);
-              break;
-            },
-          }
-          buffer.consume(offset);
-        }
-
-        error!("stopped loading data from file, remaining: {} bytes, saw {} messages, generated {} diff messages",
-          buffer.available_data(), message_counter, diff_counter);
-        if diff_counter > 0 {
-          info!("state loaded from {}, will start sending {} messages to workers", path, diff_counter);
-          let id = message_id.to_string();
-          executor::Executor::execute(
-            //FIXME: join_all will stop at the first error, and we will end up accumulating messages
-            join_all(futures).map(move |v| {
-              info!("load_state: {} messages loaded", v.len());
-              if let Some(token) = token_opt {
-                executor::Executor::send_client(token, CommandResponse::new(
-                  id,
-                  CommandStatus::Ok,
-                  format!("ok: {} messages, error: 0", v.len()),
-                  None
-                ));
-              }
-            }).map_err(|e| {
-              error!("load_state error: {}", e);
-            })
-          );
-        } else {
-          info!("no messages sent to workers: local state already had those messages");
-          if let Some(token) = token_opt {
-            let answer = CommandResponse::new(
-              message_id.to_string(),
-              CommandStatus::Ok,
-              format!("ok: 0 messages, error: 0"),
-              None
-            );
-            self.clients[token].push_message(answer);
           }
-        }
-
-        self.backends_count = self.state.count_backends();
-        self.frontends_count = self.state.count_frontends();
-        gauge!("configuration.applications", self.state.applications.len());
-        gauge!("configuration.backends", self.backends_count);
-        gauge!("configuration.frontends", self.frontends_count);
+        }).map_err(|e| {
+          error!("load_state error: {}", e);
+        })
+      );
+    } else {
+      info!("no messages sent to workers: local state already had those messages");
+      if let Some(token) = token_opt {
+        let answer = CommandResponse::new(
+          message_id.to_string(),
+          CommandStatus::Ok,
+          format!("ok: 0 messages, error: 0"),
+          None
+        );
+        self.clients[token].push_message(answer);
       }
     }
+
+    self.backends_count = self.state.count_backends();
+    self.frontends_count = self.state.count_frontends();
+    gauge!("configuration.applications", self.state.applications.len());
+    gauge!("configuration.backends", self.backends_count);
+    gauge!("configuration.frontends", self.frontends_count);
   }
 
   pub fn list_workers(&mut self, token: FrontToken, message_id: &str) {
@@ -295,6 +258,7 @@ impl CommandServer {
         id:         worker.id,
         pid:        worker.pid,
         run_state:  worker.run_state.clone(),
+        cpus:       worker.cpus.clone(),
       }
     }).collect();
     self.answer_success(token, message_id, "", Some(CommandResponseData::Workers(workers)));
@@ -339,6 +303,16 @@ impl CommandServer {
         });
         count += 1;
       }
+      for (index, response) in self.ocsp_responses.orders().into_iter().enumerate() {
+        worker.push_message(ProxyRequest {
+          id: format!("{}-OCSP-{}", id, index),
+          order: ProxyRequestData::SetOcspResponse(response),
+        });
+      }
+      worker.push_message(ProxyRequest {
+        id: format!("{}-CONFIGURATION-LOADED", id),
+        order: ProxyRequestData::ConfigurationLoaded,
+      });
 
       self.workers.insert(Token(worker_token), worker);
 
@@ -351,7 +325,14 @@ impl CommandServer {
   pub fn upgrade_worker(&mut self, token: FrontToken, message_id: &str, id: u32) {
     info!("client[{}] msg {} wants to upgrade worker {}", token.0, message_id, id);
 
-    // same as launch_worker
+    if self.workers.values().find(|worker| {
+      worker.id == id && worker.run_state != RunState::Stopping && worker.run_state != RunState::Stopped
+    }).is_none() {
+      self.answer_error(token, message_id, "worker not found", None);
+      return;
+    }
+
+    // same as launch_worker. The old worker is only stopped once its replacement is launched
     let next_id = self.next_id;
     let worker_token = self.token_count + 1;
     let mut worker = if let Ok(mut worker) = start_worker(next_id, &self.config, self.executable_path.clone(), &self.state, None) {
@@ -377,18 +358,13 @@ impl CommandServer {
 
       worker
     } else {
-      return self.answer_error(token, message_id, "failed creating worker", None);
+      error!("could not launch a worker to replace worker {}, keeping it running", id);
+      return self.answer_error(token, message_id,
+        format!("failed creating a new worker, worker {} was not stopped", id), None);
     };
 
-    if self.workers.values().find(|worker| {
-      worker.id == id && worker.run_state != RunState::Stopping && worker.run_state != RunState::Stopped
-    }).is_none() {
-      self.answer_error(token, message_id, "worker not found", None);
-      return;
-    }
-
     let mut listeners = None;
-    {
+    let old_worker_token = {
       let old_worker = self.workers.values_mut().filter(|worker| worker.id == id).next().unwrap();
 
       old_worker.channel.set_blocking(true);
@@ -412,25 +388,21 @@ impl CommandServer {
         }
       }
       old_worker.run_state = RunState::Stopping;
-      let old_worker_token = old_worker.token.expect("worker should have a valid token");
-      executor::Executor::execute(
-        executor::send(
-          old_worker_token,
-          ProxyRequest { id: message_id.to_string(), order: ProxyRequestData::SoftStop })
-        .map(move |_| {
-          executor::Executor::stop_worker(old_worker_token)
-        }).map_err(|s| {
-          error!("error stopping worker: {:?}", s);
-        })
-      );
-    }
+      old_worker.token.expect("worker should have a valid token")
+    };
 
+    // the new worker waits for its listeners before reading its orders: without the
+    // old worker's sockets, it gets an empty list and binds new ones with SO_REUSEPORT
     match listeners {
       Some(l) => {
         info!("sending listeners: to the new worker: {:?}", worker.scm.send_listeners(&l));
         l.close();
       },
-      None => error!("could not get the list of listeners from the previous worker"),
+      None => {
+        error!("could not get the list of listeners from the previous worker, the new worker will bind its own");
+        let empty = Listeners { http: Vec::new(), tls: Vec::new(), tcp: Vec::new() };
+        info!("sending empty listeners to the new worker: {:?}", worker.scm.send_listeners(&empty));
+      },
     };
     let activate_orders = self.state.generate_activate_orders();
     let mut count = 0;
@@ -441,9 +413,42 @@ impl CommandServer {
       });
       count += 1;
     }
-    self.workers.insert(Token(worker_token), worker);
+    for (index, response) in self.ocsp_responses.orders().into_iter().enumerate() {
+      worker.push_message(ProxyRequest {
+        id: format!("{}-OCSP-{}", message_id, index),
+        order: ProxyRequestData::SetOcspResponse(response),
+      });
+    }
 
-    self.answer_success(token, message_id, "", None);
+    // the executor's messages are queued after the activation orders, so the old
+    // worker is only stopped once the new one accepts on the listeners. Its answer
+    // makes the new worker Running, then the client gets the upgrade's answer
+    let stop_id = message_id.to_string();
+    let answer_id = message_id.to_string();
+    executor::Executor::execute(
+      executor::send(
+        Token(worker_token),
+        ProxyRequest { id: format!("{}-CONFIGURATION-LOADED", message_id), order: ProxyRequestData::ConfigurationLoaded })
+      .and_then(move |answer| {
+        if let ProxyResponseStatus::Error(ref e) = answer.status {
+          error!("the new worker could not load its configuration: {}", e);
+        }
+        executor::Executor::send_client(token, CommandResponse::new(
+          answer_id,
+          CommandStatus::Ok,
+          format!("worker {} is running, stopping worker {}", next_id, id),
+          None
+        ));
+        executor::send(
+          old_worker_token,
+          ProxyRequest { id: stop_id, order: ProxyRequestData::SoftStop })
+      }).map(move |_| {
+        executor::Executor::stop_worker(old_worker_token)
+      }).map_err(|s| {
+        error!("error stopping worker: {:?}", s);
+      })
+    );
+    self.workers.insert(Token(worker_token), worker);
   }
 
   pub fn upgrade_master(&mut self, token: FrontToken, message_id: &str) {
@@ -477,6 +482,63 @@ impl CommandServer {
   }
 
   pub fn metrics(&mut self, token: FrontToken, message_id: &str) {
+    let id = message_id.to_string();
+
+    executor::Executor::execute(
+      self.aggregated_metrics(message_id).map(move |aggregated_data| {
+        executor::Executor::send_client(token, CommandResponse::new(
+          id,
+          CommandStatus::Ok,
+          String::new(),
+          Some(CommandResponseData::Metrics(aggregated_data))
+        ));
+      }).map_err(|e| {
+        error!("metrics error: {}", e);
+      })
+    );
+  }
+
+  /// accepts the connections to the Prometheus endpoint, and answers the
+  /// metrics requests once the workers sent their metrics
+  pub fn accept_prometheus(&mut self) {
+    loop {
+      let accepted = match self.prometheus.as_ref() {
+        Some(endpoint) => endpoint.accept(),
+        None           => return,
+      };
+
+      let mut stream = match accepted {
+        Ok(Some(stream)) => stream,
+        Ok(None)         => continue,
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return,
+        Err(e) => {
+          error!("could not answer the prometheus request: {}", e);
+          continue;
+        }
+      };
+
+      let prefix = self.config.metrics.as_ref().and_then(|metrics| metrics.prefix.clone())
+        .unwrap_or_else(|| String::from("sozu"));
+      executor::Executor::execute(
+        self.aggregated_metrics(&prometheus::query_id()).then(move |result| {
+          let written = match result {
+            Ok(data) => prometheus::write_metrics(&mut stream, &data, &prefix),
+            Err(e)   => {
+              error!("prometheus metrics error: {}", e);
+              prometheus::write_answer(&mut stream, "500 Internal Server Error", "text/plain", "could not get the metrics\n")
+            },
+          };
+          if let Err(e) = written {
+            error!("could not write the prometheus answer: {}", e);
+          }
+          Ok::<(), ()>(())
+        })
+      );
+    }
+  }
+
+  /// queries the metrics of the running workers, and adds the metrics of the master
+  pub fn aggregated_metrics(&mut self, message_id: &str) -> impl Future<Item = AggregatedMetricsData, Error = String> {
     let mut futures = Vec::new();
     let id = message_id.to_string();
 
@@ -495,30 +557,61 @@ impl CommandServer {
       (*metrics.borrow_mut()).dump_process_data()
     });
 
+    //FIXME: join_all will stop at the first error, and we will end up accumulating messages
+    join_all(futures).map(move |v| {
+      let data: BTreeMap<String, MetricsData> = v.into_iter().filter_map(|(tag, metrics)| {
+        if let Some(ProxyResponseData::Metrics(d)) = metrics.data {
+          Some((tag, d))
+        } else {
+          None
+        }
+      }).collect();
+
+      AggregatedMetricsData {
+        master: master_metrics,
+        workers: data,
+      }
+    })
+  }
+
+  pub fn reset_metrics(&mut self, token: FrontToken, message_id: &str) {
+    let mut futures = Vec::new();
+    let id = message_id.to_string();
+
+    for ref mut worker in self.workers.values_mut()
+      .filter(|worker| worker.run_state != RunState::Stopped) {
+
+      let tag = worker.id.to_string();
+      futures.push(
+        executor::send(
+          worker.token.expect("worker should have a token"),
+          ProxyRequest { id: id.clone(), order: ProxyRequestData::ResetMetrics }).map(|data| (tag, data))
+      );
+    }
+
+    let master_count = METRICS.with(|metrics| {
+      (*metrics.borrow_mut()).reset_local()
+    });
+
     executor::Executor::execute(
-      //FIXME: join_all will stop at the first error, and we will end up accumulating messages
       join_all(futures).map(move |v| {
-        let data: BTreeMap<String, MetricsData> = v.into_iter().filter_map(|(tag, metrics)| {
-          if let Some(ProxyResponseData::Metrics(d)) = metrics.data {
-            Some((tag, d))
-          } else {
-            None
+        let mut message = format!("master: {}", master_count);
+        let mut total = master_count;
+        for (tag, answer) in v.into_iter() {
+          if let Some(ProxyResponseData::ResetMetrics(count)) = answer.data {
+            message.push_str(&format!(", worker {}: {}", tag, count));
+            total += count;
           }
-        }).collect();
-
-        let aggregated_data = AggregatedMetricsData {
-          master: master_metrics,
-          workers: data,
-        };
+        }
 
         executor::Executor::send_client(token, CommandResponse::new(
           id,
           CommandStatus::Ok,
-          String::new(),
-          Some(CommandResponseData::Metrics(aggregated_data))
+          format!("reset {} metric series ({})", total, message),
+          None
         ));
       }).map_err(|e| {
-        error!("metrics error: {}", e);
+        error!("reset metrics error: {}", e);
       })
     );
   }
@@ -605,10 +698,26 @@ impl CommandServer {
           error!("certificates query error: {}", e);
         }));
       },
+      &Query::Routes | &Query::RouteMatch(_) | &Query::BackendStats(_) => {
+        executor::Executor::execute(f.map(move |data| {
+          executor::Executor::send_client(token, CommandResponse::new(
+            id,
+            CommandStatus::Ok,
+            String::new(),
+            Some(CommandResponseData::Query(data))
+          ));
+        }).map_err(|e| {
+          //FIXME: send back errors
+          error!("routes query error: {}", e);
+        }));
+      },
     };
   }
 
-  pub fn worker_order(&mut self, token: FrontToken, message_id: &str, order: ProxyRequestData, worker_id: Option<u32>) {
+  pub fn worker_order(&mut self, token: FrontToken, message_id: &str, mut order: ProxyRequestData, worker_id: Option<u32>) {
+    self.complete_certificate_chain(&mut order);
+    self.update_ocsp_responses(&order);
+
     if let &ProxyRequestData::AddCertificate(_) = &order {
       debug!("workerconfig client order AddCertificate()");
     } else {
@@ -626,16 +735,90 @@ impl CommandServer {
       ::std::env::set_var("RUST_LOG", logging_filter);
     }
 
+    if let &ProxyRequestData::SetTrafficSplit(ref split) = &order {
+      if split.percentage > 100 {
+        let msg = format!("invalid traffic split percentage {}, expected a value from 0 to 100", split.percentage);
+        error!("{}", msg);
+        self.answer_error(token, message_id, msg, None);
+        return;
+      }
+    }
+
+    if let &ProxyRequestData::LogFormat(format) = &order {
+      debug!("Changing master log format to {:?}", format);
+      logging::LOGGER.with(|l| l.borrow_mut().set_format(format));
+    }
+
+    // the state does not know about the purged objects after handling the order
+    let purged = match order {
+      ProxyRequestData::PurgeApplication(ref app_id) => Some((app_id.clone(), self.state.application_objects(app_id))),
+      _ => None,
+    };
+
     if !self.state.handle_order(&order) {
       // Check if the backend or frontend exist before deleting it
       if worker_id.is_none() {
         match order {
+          ProxyRequestData::AddHttpFront(ref front)
+          | ProxyRequestData::AddHttpsFront(ref front) => {
+            let https = if let ProxyRequestData::AddHttpsFront(_) = order { true } else { false };
+            if let Some(other) = self.state.conflicting_front(front, https) {
+              let msg = format!("the frontend {}{} on {} is already used by the application {}",
+                front.hostname, front.path_begin, front.address, other.app_id);
+              error!("{}", msg);
+              self.answer_error(token, message_id, msg, None);
+              return;
+            }
+          },
           ProxyRequestData::RemoveBackend(ref backend) => {
             let msg = format!("No such backend {} at {} for the application {}", backend.backend_id, backend.address, backend.app_id);
             error!("{}", msg);
             self.answer_error(token, message_id, msg, None);
             return;
           },
+          ProxyRequestData::ReplaceBackend(ref backend) => {
+            let msg = format!("Could not replace the backend {} for the application {}: unknown backend or address already in use", backend.backend_id, backend.app_id);
+            error!("{}", msg);
+            self.answer_error(token, message_id, msg, None);
+            return;
+          },
+          ProxyRequestData::DrainBackend(DrainBackend{ ref app_id, ref backend_id })
+          | ProxyRequestData::ResetBackend(ResetBackend{ ref app_id, ref backend_id }) => {
+            let msg = format!("No such backend {} for the application {}", backend_id, app_id);
+            error!("{}", msg);
+            self.answer_error(token, message_id, msg, None);
+            return;
+          },
+          ProxyRequestData::RebalanceApplication(ref rebalance) => {
+            let msg = format!("No backends to rebalance for the application {}", rebalance.app_id);
+            error!("{}", msg);
+            self.answer_error(token, message_id, msg, None);
+            return;
+          },
+          ProxyRequestData::EnableApplication(ref app_id)
+          | ProxyRequestData::DisableApplication(ref app_id)
+          | ProxyRequestData::PurgeApplication(ref app_id)
+          | ProxyRequestData::SetAppTimeout(AppTimeout { ref app_id, .. }) => {
+            let msg = format!("No such application {}", app_id);
+            error!("{}", msg);
+            self.answer_error(token, message_id, msg, None);
+            return;
+          },
+          ProxyRequestData::SetTrafficSplit(ref split) => {
+            let msg = format!("No traffic split on {} for the application {}", split.hostname, split.app_id);
+            error!("{}", msg);
+            self.answer_error(token, message_id, msg, None);
+            return;
+          },
+          ProxyRequestData::SetDefaultApp(ref default) => {
+            let msg = match default.proxy {
+              ListenerType::TCP => format!("The TCP listener at {} cannot have a default application", default.front),
+              ref proxy => format!("No {:?} listener at {}", proxy, default.front),
+            };
+            error!("{}", msg);
+            self.answer_error(token, message_id, msg, None);
+            return;
+          },
           ProxyRequestData::RemoveHttpFront(HttpFront{ ref app_id, ref address, .. })
           | ProxyRequestData::RemoveHttpsFront(HttpFront{ ref app_id, ref address, .. })
           | ProxyRequestData::RemoveTcpFront(TcpFront{ ref app_id, ref address }) => {
@@ -652,15 +835,18 @@ impl CommandServer {
     if self.config.automatic_state_save {
       if order != ProxyRequestData::SoftStop || order != ProxyRequestData::HardStop {
         if let Some(path) = self.config.saved_state.clone() {
-          if let Ok(mut f) = fs::File::create(&path) {
-            let _ = self.save_state_to_file(&mut f).map_err(|e| {
-              error!("could not save state automatically to {}: {:?}", path, e);
-            });
-          }
+          let _ = write_state_file(&path, |f| self.save_state_to_file(f, StateFormat::Default)).map_err(|e| {
+            error!("could not save state automatically to {}: {}", path, state_file_error(&e));
+          });
         }
       }
     }
 
+    let stops_worker = match order {
+      ProxyRequestData::SoftStop | ProxyRequestData::DrainAndStop(_) | ProxyRequestData::HardStop => true,
+      _ => false,
+    };
+
     let mut found = false;
     let mut futures = Vec::new();
     for ref mut worker in self.workers.values_mut()
@@ -673,7 +859,7 @@ impl CommandServer {
       }
 
       let worker_token = worker.token.expect("worker should have a token");
-      let should_stop_worker = order == ProxyRequestData::SoftStop || order == ProxyRequestData::HardStop;
+      let should_stop_worker = stops_worker;
       if should_stop_worker {
         worker.run_state = RunState::Stopping;
       }
@@ -700,7 +886,7 @@ impl CommandServer {
     }
 
     let id = message_id.to_string();
-    let should_stop_master = (order == ProxyRequestData::SoftStop || order == ProxyRequestData::HardStop) && worker_id.is_none();
+    let should_stop_master = stops_worker && worker_id.is_none();
     let f = join_all(futures).map(move |r| {
       if should_stop_master {
         executor::Executor::stop_master();
@@ -712,6 +898,10 @@ impl CommandServer {
     executor::Executor::execute(
       f.map(move |v| {
           let mut messages = vec![];
+          let mut drained = vec![];
+          if let Some((app_id, count)) = purged {
+            drained.push(format!("removed {} frontends and backends of application {}", count, app_id));
+          }
           let mut has_error = false;
           for response in v.iter() {
               if let ProxyResponseStatus::Error(ref e) = response.1.status {
@@ -721,6 +911,9 @@ impl CommandServer {
                 messages.push(format!("{}: OK", response.0));
               }
 
+              if let Some(ProxyResponseData::DrainedSessions(count)) = response.1.data {
+                drained.push(format!("worker {}: {} sessions were still active after the drain timeout", response.0, count));
+              }
           }
           if has_error {
               executor::Executor::send_client(token, CommandResponse::new(
@@ -734,7 +927,7 @@ impl CommandServer {
               executor::Executor::send_client(token, CommandResponse::new(
                       id,
                       CommandStatus::Ok,
-                      String::new(),
+                      drained.join(", "),
                       None
                       ));
           }
@@ -760,10 +953,78 @@ impl CommandServer {
     gauge!("configuration.frontends", self.frontends_count);
   }
 
+  /// if enabled, adds the missing intermediate certificates to the chain
+  /// of a new certificate. The certificate is still added if it fails
+  fn complete_certificate_chain(&mut self, order: &mut ProxyRequestData) {
+    if !self.config.fetch_intermediate_certificates {
+      return;
+    }
+
+    let certificate = match *order {
+      ProxyRequestData::AddCertificate(ref mut add) => &mut add.certificate,
+      ProxyRequestData::ReplaceCertificate(ref mut replace) => &mut replace.new_certificate,
+      _ => return,
+    };
+
+    match self.intermediates.complete_chain(certificate) {
+      Ok(0) => {},
+      Ok(added) => { info!("added {} intermediate certificates to the chain", added); },
+      Err(e) => { warn!("could not complete the certificate chain: {}", e); },
+    }
+  }
+
+  /// tracks the OCSP responses of the certificates added, replaced or removed
+  /// by an order. They are fetched at the next refresh
+  fn update_ocsp_responses(&mut self, order: &ProxyRequestData) {
+    if !self.config.ocsp_stapling {
+      return;
+    }
+
+    match *order {
+      ProxyRequestData::AddCertificate(ref add) => {
+        if !self.ocsp_responses.add(add.front, &add.certificate) {
+          debug!("no OCSP responder or issuer for the certificate, it will be served without staple");
+        }
+      },
+      ProxyRequestData::ReplaceCertificate(ref replace) => {
+        self.ocsp_responses.remove(replace.front, &replace.old_fingerprint);
+        self.ocsp_responses.add(replace.front, &replace.new_certificate);
+      },
+      ProxyRequestData::RemoveCertificate(ref remove) => self.ocsp_responses.remove(remove.front, &remove.fingerprint),
+      _ => {},
+    }
+  }
+
+  /// fetches the OCSP responses due for a refresh, and sends them to the workers
+  pub fn refresh_ocsp_responses(&mut self) {
+    if self.ocsp_responses.is_empty() {
+      return;
+    }
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0);
+    for (index, response) in self.ocsp_responses.refresh(now).into_iter().enumerate() {
+      if response.ocsp_response.is_empty() {
+        warn!("the OCSP response of the certificate {} expired, it is not stapled anymore", response.fingerprint);
+      } else {
+        info!("refreshed the OCSP response of the certificate {}", response.fingerprint);
+      }
+
+      for ref mut worker in self.workers.values_mut()
+        .filter(|worker| worker.run_state != RunState::Stopping && worker.run_state != RunState::Stopped) {
+        worker.push_message(ProxyRequest {
+          id:    format!("OCSP-{}-{}", now, index),
+          order: ProxyRequestData::SetOcspResponse(response.clone()),
+        });
+      }
+    }
+  }
+
   pub fn load_static_application_configuration(&mut self) {
     //FIXME: too many loops, this could be cleaner
     for message in self.config.generate_config_messages() {
-      if let CommandRequestData::Proxy(order) = message.data {
+      if let CommandRequestData::Proxy(mut order) = message.data {
+        self.complete_certificate_chain(&mut order);
+        self.update_ocsp_responses(&order);
         self.state.handle_order(&order);
 
         if let &ProxyRequestData::AddCertificate(_) = &order {
@@ -794,23 +1055,98 @@ impl CommandServer {
     gauge!("configuration.frontends", self.frontends_count);
   }
 
+  /// reloads the applications of the configuration file when the master receives
+  /// SIGHUP, like `sozuctl reload-apps`. Only the orders changing the running state
+  /// are sent to the workers, so reloading an unchanged file does nothing. The
+  /// listeners and the other options of the file are not reloaded
+  pub fn reload_configuration(&mut self) {
+    let path = self.config.config_path.clone();
+    let orders = match reload_orders(&self.state, &path) {
+      Ok(orders) => orders,
+      Err(e) => {
+        error!("could not reload the configuration, the running state is kept: {}", e);
+        return;
+      }
+    };
+
+    if orders.is_empty() {
+      info!("configuration reloaded from {}: no change", path);
+      return;
+    }
+
+    let total = orders.len();
+    let removals = orders.iter().filter(|order| order.is_removal()).count();
+    for (index, mut order) in orders.into_iter().enumerate() {
+      self.complete_certificate_chain(&mut order);
+      self.update_ocsp_responses(&order);
+      self.state.handle_order(&order);
+
+      for ref mut worker in self.workers.values_mut()
+        .filter(|worker| worker.run_state != RunState::Stopping && worker.run_state != RunState::Stopped) {
+        worker.push_message(ProxyRequest { id: format!("RELOAD-{}", index), order: order.clone() });
+      }
+    }
+
+    if self.config.automatic_state_save {
+      if let Some(path) = self.config.saved_state.clone() {
+        let _ = write_state_file(&path, |f| self.save_state_to_file(f, StateFormat::Default)).map_err(|e| {
+          error!("could not save state automatically to {}: {}", path, state_file_error(&e));
+        });
+      }
+    }
+
+    self.backends_count = self.state.count_backends();
+    self.frontends_count = self.state.count_frontends();
+    gauge!("configuration.applications", self.state.applications.len());
+    gauge!("configuration.backends", self.backends_count);
+    gauge!("configuration.frontends", self.frontends_count);
+
+    info!("configuration reloaded from {}: {} orders sent to the workers, {} additions and {} removals",
+      path, total, total - removals, removals);
+  }
+
+  /// the workers started with the main process wait for this order before
+  /// accepting connections, to avoid answering 404 until they are configured
+  pub fn notify_configuration_loaded(&mut self) {
+    for ref mut worker in self.workers.values_mut()
+      .filter(|worker| worker.run_state != RunState::Stopping && worker.run_state != RunState::Stopped) {
+      worker.push_message(ProxyRequest { id: String::from("CONFIGURATION-LOADED"), order: ProxyRequestData::ConfigurationLoaded });
+    }
+  }
+
+  /// sends the lifecycle events of the workers whose run state changed since the last call
+  pub fn notify_worker_events(&mut self) {
+    let workers: Vec<(u32, i32, RunState)> = self.workers.values()
+      .map(|worker| (worker.id, worker.pid, worker.run_state)).collect();
+
+    for (id, event) in worker_lifecycle_events(&mut self.reported_workers, &workers) {
+      self.push_event("LIFECYCLE", format!("{}", id), event);
+    }
+  }
+
   pub fn disable_cloexec_before_upgrade(&mut self) {
     for ref mut worker in self.workers.values() {
-      if worker.run_state == RunState::Running {
+      if worker.run_state == RunState::Running || worker.run_state == RunState::Initializing {
         util::disable_close_on_exec(worker.channel.sock.as_raw_fd());
       }
     }
     trace!("disabling cloexec on listener: {}", self.sock.as_raw_fd());
     util::disable_close_on_exec(self.sock.as_raw_fd());
+    if let Some(endpoint) = self.prometheus.as_ref() {
+      util::disable_close_on_exec(endpoint.listener.as_raw_fd());
+    }
   }
 
   pub fn enable_cloexec_after_upgrade(&mut self) {
     for ref mut worker in self.workers.values() {
-      if worker.run_state == RunState::Running {
+      if worker.run_state == RunState::Running || worker.run_state == RunState::Initializing {
         util::enable_close_on_exec(worker.channel.sock.as_raw_fd());
       }
     }
         util::enable_close_on_exec(self.sock.as_raw_fd());
+    if let Some(endpoint) = self.prometheus.as_ref() {
+      util::enable_close_on_exec(endpoint.listener.as_raw_fd());
+    }
   }
 
   pub fn generate_upgrade_data(&self) -> UpgradeData {
@@ -825,6 +1161,7 @@ impl CommandServer {
       state:       state,
       next_id:     self.next_id,
       token_count: self.token_count,
+      prometheus:  self.prometheus.as_ref().map(|endpoint| endpoint.listener.as_raw_fd()),
     }
   }
 
@@ -837,6 +1174,7 @@ impl CommandServer {
       state,
       next_id,
       token_count,
+      prometheus,
     } = upgrade_data;
 
     debug!("listener is: {}", command);
@@ -872,14 +1210,34 @@ impl CommandServer {
               run_state:  serialized.run_state.clone(),
               queue:      serialized.queue.clone().into(),
               scm:        ScmSocket::new(serialized.scm),
+              cpus:       serialized.cpus.clone(),
             }
           )
         )
       } else { None }
     }).collect();
 
+    let prometheus = match (prometheus, config.prometheus.as_ref()) {
+      (Some(fd), Some(prometheus)) => {
+        let listener = unsafe { TcpListener::from_raw_fd(fd) };
+        poll.register(&listener, PROMETHEUS, Ready::readable(), PollOpt::edge())
+          .expect("should register the prometheus listener");
+        Some(PrometheusEndpoint::from_listener(listener, prometheus))
+      },
+      _ => None,
+    };
+
     let config_state = state.clone();
 
+    let mut ocsp_responses = OcspCache::new(Duration::from_secs(INTERMEDIATE_FETCH_TIMEOUT));
+    if config.ocsp_stapling {
+      for (front, certificates) in config_state.certificates.iter() {
+        for &(ref certificate, _) in certificates.values() {
+          ocsp_responses.add(*front, certificate);
+        }
+      }
+    }
+
     let backends_count  = config_state.count_backends();
     let frontends_count = config_state.count_frontends();
 
@@ -893,6 +1251,8 @@ impl CommandServer {
       //FIXME: deserialize client connections as well, otherwise they might leak?
       clients:           Slab::with_capacity(1024),
       event_subscribers: Vec::new(),
+      log_subscribers:   LogSubscribers::new(),
+      reported_workers:  HashMap::new(),
       workers:           workers,
       next_id:           next_id,
       state:             config_state,
@@ -903,6 +1263,453 @@ impl CommandServer {
       executable_path:   path,
       backends_count:    backends_count,
       frontends_count:   frontends_count,
+      intermediates:     IntermediateCache::new(Duration::from_secs(INTERMEDIATE_FETCH_TIMEOUT)),
+      ocsp_responses,
+      prometheus,
+    }
+  }
+}
+
+/// compares the run state of the workers (id, pid, run state) with the ones already
+/// reported, and returns the lifecycle events of the changes, by worker id. A worker
+/// that does not appear anymore without being stopped is reported as crashed
+pub fn worker_lifecycle_events(reported: &mut HashMap<u32, RunState>, workers: &[(u32, i32, RunState)]) -> Vec<(u32, Event)> {
+  let mut workers = workers.to_vec();
+  workers.sort_by_key(|&(id, _, _)| id);
+
+  let mut events = Vec::new();
+  for &(id, pid, run_state) in workers.iter() {
+    let previous = match reported.insert(id, run_state) {
+      Some(previous) => previous,
+      None => {
+        events.push((id, Event::WorkerStarted(id, pid)));
+        RunState::Running
+      }
+    };
+
+    if previous == run_state {
+      continue;
+    }
+
+    match run_state {
+      RunState::Initializing | RunState::Running => {},
+      RunState::Stopping     => events.push((id, Event::WorkerDraining(id))),
+      RunState::NotAnswering => events.push((id, Event::WorkerCrashed(id))),
+      // the crash was already reported
+      RunState::Stopped      => if previous != RunState::NotAnswering {
+        events.push((id, Event::WorkerStopped(id)));
+      },
+    }
+  }
+
+  let mut removed: Vec<(u32, RunState)> = reported.iter()
+    .filter(|&(id, _)| !workers.iter().any(|&(worker_id, _, _)| worker_id == *id))
+    .map(|(id, run_state)| (*id, *run_state))
+    .collect();
+  removed.sort_by_key(|&(id, _)| id);
+
+  for (id, run_state) in removed {
+    reported.remove(&id);
+    if run_state != RunState::Stopped && run_state != RunState::NotAnswering {
+      events.push((id, Event::WorkerCrashed(id)));
+    }
+  }
+
+  events
+}
+
+/// orders applying the applications of the configuration file at `path` to the
+/// running state
+pub fn reload_orders(state: &ConfigState, path: &str) -> Result<Vec<ProxyRequestData>, String> {
+  let file = FileConfig::load_from_path(path)
+    .map_err(|e| format!("could not load the configuration from {}: {}", path, e))?;
+  let desired = application_orders(file.applications.unwrap_or_default(), state)
+    .map_err(|e| format!("invalid application in {}: {}", path, e))?;
+
+  Ok(state.reconcile_applications(&desired))
+}
+
+/// writes the state to a temporary file in the same directory, then renames it
+/// over `path`: if the write fails, the previous state file is left untouched
+/// and no truncated file remains
+pub fn write_state_file<F>(path: &str, write: F) -> io::Result<usize>
+  where F: FnOnce(&mut fs::File) -> io::Result<usize> {
+  let path = Path::new(path);
+  let dir = match path.parent() {
+    Some(dir) if !dir.as_os_str().is_empty() => dir,
+    _ => Path::new("."),
+  };
+
+  // the temporary file is removed when dropped, if it was not persisted
+  let mut tmp = NamedTempFile::new_in(dir)?;
+  let counter = write(tmp.as_file_mut())?;
+  tmp.as_file().sync_all()?;
+  tmp.persist(path).map_err(|e| e.error)?;
+
+  Ok(counter)
+}
+
+/// writes the orders recreating the state, one message per line. With the
+/// canonical format, the orders are sorted and their keys too, so saving the
+/// same configuration twice gives the same file
+pub fn write_state_orders(f: &mut fs::File, state: &ConfigState, format: StateFormat) -> io::Result<usize> {
+  let mut counter = 0usize;
+  let orders = match format {
+    StateFormat::Default   => state.generate_orders(),
+    StateFormat::Canonical => state.generate_canonical_orders(),
+  };
+
+  let res: io::Result<usize> = (move || {
+    for command in orders {
+      let message = CommandRequest::new(
+        format!("SAVE-{}", counter),
+        CommandRequestData::Proxy(command),
+        None
+      );
+
+      let serialized = match format {
+        StateFormat::Default   => serde_json::to_string(&message).unwrap_or_default(),
+        StateFormat::Canonical => to_canonical_json(&message),
+      };
+      f.write_all(serialized.as_bytes())?;
+      f.write_all(&b"\n\0"[..])?;
+
+      if counter % 1000 == 0 {
+        info!("writing command {}", counter);
+        f.sync_all()?;
+      }
+      counter += 1;
+    }
+    f.sync_all()?;
+
+    Ok(counter)
+  })();
+
+  res
+}
+
+/// describes a state file error in a way the user can act on
+pub fn state_file_error(e: &io::Error) -> String {
+  match e.raw_os_error() {
+    Some(libc::ENOSPC) | Some(libc::EDQUOT) => return String::from("disk full"),
+    _ => {},
+  }
+
+  match e.kind() {
+    io::ErrorKind::NotFound         => String::from("path missing"),
+    io::ErrorKind::PermissionDenied => String::from("permission denied"),
+    _                               => e.to_string(),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::str;
+  use tempfile::tempdir;
+  use sozu_command::proxy::{Backend,CertificateAndKey,AddCertificate};
+
+  #[test]
+  fn reload_configuration_orders() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("config.toml");
+    let path = path.to_str().unwrap();
+
+    fs::write(path, r#"
+      [applications.app_1]
+      protocol = "http"
+      frontends = [{ address = "0.0.0.0:8080", hostname = "lolcatho.st" }]
+      backends = [{ address = "127.0.0.1:1026" }, { address = "127.0.0.1:1027" }]
+
+      [applications.app_2]
+      protocol = "http"
+      frontends = [{ address = "0.0.0.0:8080", hostname = "example.com" }]
+      backends = [{ address = "127.0.0.1:1028" }]
+    "#).unwrap();
+
+    let mut state = ConfigState::new();
+    for order in reload_orders(&state, path).unwrap() {
+      state.handle_order(&order);
+    }
+    assert_eq!(state.applications.len(), 2);
+
+    // app_2 is removed, app_1 loses a backend
+    fs::write(path, r#"
+      [applications.app_1]
+      protocol = "http"
+      frontends = [{ address = "0.0.0.0:8080", hostname = "lolcatho.st" }]
+      backends = [{ address = "127.0.0.1:1026" }]
+    "#).unwrap();
+
+    let orders = reload_orders(&state, path).unwrap();
+    assert!(orders.iter().all(|order| order.is_removal()), "orders: {:?}", orders);
+    assert!(orders.contains(&ProxyRequestData::RemoveApplication(String::from("app_2"))));
+    assert!(orders.iter().any(|order| match order {
+      ProxyRequestData::RemoveBackend(backend) => backend.backend_id == "app_1-1",
+      _ => false,
+    }));
+    // app_2: application, frontend and backend. app_1: backend
+    assert_eq!(orders.len(), 4, "orders: {:?}", orders);
+
+    // reloading again the same file does nothing
+    for order in orders.iter() {
+      state.handle_order(order);
+    }
+    assert_eq!(reload_orders(&state, path).unwrap(), Vec::new());
+
+    // an invalid file keeps the running state
+    fs::write(path, "[applications.app_1]\nprotocol = \"http\"\nfrontends = 1\n").unwrap();
+    assert!(reload_orders(&state, path).is_err());
+    assert!(reload_orders(&state, dir.path().join("missing.toml").to_str().unwrap()).is_err());
+  }
+
+  fn dir_entries(dir: &Path) -> Vec<String> {
+    let mut entries: Vec<String> = fs::read_dir(dir).unwrap()
+      .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+      .collect();
+    entries.sort();
+    entries
+  }
+
+  #[test]
+  fn worker_lifecycle_events_for_launch_then_stop() {
+    let (mut ctl, mut master) = Channel::<CommandRequest,CommandResponse>::generate(1000, 10000)
+      .expect("should create a channel");
+    master.set_blocking(true);
+
+    let mut reported = HashMap::new();
+    let mut send = |workers: &[(u32, i32, RunState)]| {
+      for (id, event) in worker_lifecycle_events(&mut reported, workers) {
+        master.write_message(&CommandResponse::new(String::from("LIFECYCLE"), CommandStatus::Processing,
+          format!("{}", id), Some(CommandResponseData::Event(event))));
+      }
+    };
+
+    send(&[(0, 100, RunState::Running)]);
+    // nothing changed
+    send(&[(0, 100, RunState::Running)]);
+    send(&[(0, 100, RunState::Running), (1, 101, RunState::Running)]);
+    send(&[(0, 100, RunState::Stopping), (1, 101, RunState::Running)]);
+    send(&[(0, 100, RunState::Stopped), (1, 101, RunState::NotAnswering)]);
+    // the crashed worker is replaced
+    send(&[(0, 100, RunState::Stopped), (2, 102, RunState::Running)]);
+    // removed without being stopped
+    send(&[(0, 100, RunState::Stopped)]);
+    // the start is reported once, the end of the initialization is a WorkerConfigured event
+    send(&[(0, 100, RunState::Stopped), (3, 103, RunState::Initializing)]);
+    send(&[(0, 100, RunState::Stopped), (3, 103, RunState::Running)]);
+    send(&[(0, 100, RunState::Stopped), (3, 103, RunState::Stopping)]);
+
+    let expected = vec![
+      Event::WorkerStarted(0, 100),
+      Event::WorkerStarted(1, 101),
+      Event::WorkerDraining(0),
+      Event::WorkerStopped(0),
+      Event::WorkerCrashed(1),
+      Event::WorkerStarted(2, 102),
+      Event::WorkerCrashed(2),
+      Event::WorkerStarted(3, 103),
+      Event::WorkerDraining(3),
+    ];
+
+    for event in expected {
+      let message = ctl.read_message().expect("should receive an event");
+      assert_eq!(message.status, CommandStatus::Processing);
+      assert_eq!(message.data, Some(CommandResponseData::Event(event)));
+    }
+  }
+
+  #[test]
+  fn save_state_file() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("state.json");
+    let path_str = path.to_str().unwrap();
+
+    let res = write_state_file(path_str, |f| {
+      f.write_all(b"{}\n\0")?;
+      Ok(1)
+    });
+    assert_eq!(res.unwrap(), 1);
+    assert_eq!(fs::read(&path).unwrap(), b"{}\n\0".to_vec());
+    assert_eq!(dir_entries(dir.path()), vec![String::from("state.json")]);
+  }
+
+  #[test]
+  fn canonical_state_file() {
+    let dir = tempdir().unwrap();
+    let config = dir.path().join("config.toml");
+    let config = config.to_str().unwrap();
+    fs::write(config, r#"
+      [applications.app_1]
+      protocol = "http"
+      frontends = [{ address = "0.0.0.0:8080", hostname = "lolcatho.st" }]
+      backends = [{ address = "127.0.0.1:1026" }, { address = "127.0.0.1:1027" }]
+
+      [applications.app_2]
+      protocol = "http"
+      frontends = [{ address = "0.0.0.0:8080", hostname = "example.com" }]
+      backends = [{ address = "127.0.0.1:1028" }, { address = "127.0.0.1:1029" }]
+    "#).unwrap();
+
+    // the same configuration, built in two orders
+    let orders = reload_orders(&ConfigState::new(), config).unwrap();
+    let mut state = ConfigState::new();
+    for order in orders.iter() {
+      state.handle_order(order);
+    }
+    let mut state2 = ConfigState::new();
+    for order in orders.iter().rev() {
+      state2.handle_order(order);
+    }
+
+    let save = |state: &ConfigState, name: &str| {
+      let path = dir.path().join(name);
+      write_state_file(path.to_str().unwrap(), |f| write_state_orders(f, state, StateFormat::Canonical)).unwrap();
+      fs::read(&path).unwrap()
+    };
+    let saved = save(&state, "state.json");
+    assert_eq!(saved, save(&state2, "state2.json"));
+
+    // the file can be loaded back, each message carries the protocol version
+    let messages = str::from_utf8(&saved).unwrap().split("\n\0").filter(|s| !s.is_empty())
+      .map(|s| serde_json::from_str::<CommandRequest>(s).unwrap()).collect::<Vec<_>>();
+    assert_eq!(messages.len(), orders.len());
+    assert!(messages.iter().all(|message| message.version == sozu_command::command::PROTOCOL_VERSION));
+  }
+
+  fn state_file(orders: &[ProxyRequestData]) -> Vec<u8> {
+    let mut data = Vec::new();
+    for (index, order) in orders.iter().enumerate() {
+      let message = CommandRequest::new(format!("SAVE-{}", index), CommandRequestData::Proxy(order.clone()), None);
+      data.extend_from_slice(serde_json::to_string(&message).unwrap().as_bytes());
+      data.extend_from_slice(b"\n\0");
+    }
+    data
+  }
+
+  fn application_orders_from(config: &str) -> Vec<ProxyRequestData> {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("config.toml");
+    fs::write(&path, config).unwrap();
+    reload_orders(&ConfigState::new(), path.to_str().unwrap()).unwrap()
+  }
+
+  fn add_certificate(key: &str) -> ProxyRequestData {
+    ProxyRequestData::AddCertificate(AddCertificate {
+      front: "0.0.0.0:8443".parse().unwrap(),
+      certificate: CertificateAndKey {
+        certificate: String::from(include_str!("../../../command/assets/certificate.pem")),
+        certificate_chain: vec![],
+        key: String::from(key),
+      },
+      names: vec![],
+    })
+  }
+
+  #[test]
+  fn load_valid_state_file() {
+    let mut orders = application_orders_from(r#"
+      [applications.app_1]
+      protocol = "http"
+      frontends = [{ address = "0.0.0.0:8080", hostname = "lolcatho.st" }]
+      backends = [{ address = "127.0.0.1:1026" }]
+    "#);
+    orders.push(add_certificate(include_str!("../../../command/assets/key.pem")));
+
+    let (state, counter) = verify_state_file(&state_file(&orders), &ConfigState::new()).unwrap();
+    assert_eq!(counter, orders.len());
+    let mut expected = ConfigState::new();
+    for order in orders.iter() {
+      expected.handle_order(order);
     }
+    assert_eq!(state, expected);
+  }
+
+  #[test]
+  fn load_state_file_with_invalid_certificate() {
+    let mut orders = application_orders_from(r#"
+      [applications.app_1]
+      protocol = "http"
+      frontends = [{ address = "0.0.0.0:8080", hostname = "lolcatho.st" }]
+      backends = [{ address = "127.0.0.1:1026" }]
+    "#);
+    orders.push(add_certificate(include_str!("../../../command/assets/aia-leaf-key.pem")));
+    orders.push(ProxyRequestData::AddBackend(Backend {
+      app_id: String::from("app_1"),
+      backend_id: String::from("app_1-1"),
+      address: "0.0.0.0:1027".parse().unwrap(),
+      load_balancing_parameters: None,
+      sticky_id: None,
+      backup: None,
+      hostname: None,
+    }));
+
+    // every error is reported, and the whole file is rejected
+    let errors = verify_state_file(&state_file(&orders), &ConfigState::new()).unwrap_err();
+    assert_eq!(errors, vec![
+      format!("line {}: certificate for 0.0.0.0:8443: the private key does not match the certificate", orders.len() - 1),
+      format!("line {}: backend app_1-1 of the application app_1: invalid address 0.0.0.0:1027", orders.len()),
+    ]);
+
+    let mut data = state_file(&orders[..1]);
+    data.extend_from_slice(b"{\"id\":\"SAVE-1\",\"version\":0}\n\0");
+    let errors = verify_state_file(&data, &ConfigState::new()).unwrap_err();
+    assert_eq!(errors.len(), 1);
+    assert!(errors[0].starts_with("line 2: "), "errors: {:?}", errors);
+  }
+
+  #[test]
+  fn load_state_file_with_conflicting_frontend() {
+    let mut state = ConfigState::new();
+    for order in application_orders_from(r#"
+      [applications.app_1]
+      protocol = "http"
+      frontends = [{ address = "0.0.0.0:8080", hostname = "lolcatho.st" }]
+      backends = [{ address = "127.0.0.1:1026" }]
+    "#) {
+      state.handle_order(&order);
+    }
+
+    let orders = application_orders_from(r#"
+      [applications.app_2]
+      protocol = "http"
+      frontends = [{ address = "0.0.0.0:8080", hostname = "lolcatho.st" }]
+      backends = [{ address = "127.0.0.1:1027" }]
+    "#);
+    let errors = verify_state_file(&state_file(&orders), &state).unwrap_err();
+    assert_eq!(errors.len(), 1, "errors: {:?}", errors);
+    assert!(errors[0].ends_with("frontend lolcatho.st on 0.0.0.0:8080 of the application app_2 is already used by the application app_1"),
+      "errors: {:?}", errors);
+
+    // the same frontend for the same application is not a conflict
+    let (_, counter) = verify_state_file(&state_file(&state.generate_orders()), &state).unwrap();
+    assert_eq!(counter, state.generate_orders().len());
+  }
+
+  #[test]
+  fn failed_save_keeps_previous_state() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("state.json");
+    let path_str = path.to_str().unwrap();
+    fs::write(&path, b"previous").unwrap();
+
+    let res = write_state_file(path_str, |f| {
+      f.write_all(b"{\"id\":")?;
+      Err(io::Error::from_raw_os_error(libc::ENOSPC))
+    });
+    let e = res.unwrap_err();
+    assert_eq!(state_file_error(&e), "disk full");
+    assert_eq!(fs::read(&path).unwrap(), b"previous".to_vec());
+    assert_eq!(dir_entries(dir.path()), vec![String::from("state.json")]);
+  }
+
+  #[test]
+  fn save_state_to_missing_directory() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("missing").join("state.json");
+
+    let e = write_state_file(path.to_str().unwrap(), |_| Ok(0)).unwrap_err();
+    assert_eq!(state_file_error(&e), "path missing");
+    assert_eq!(dir_entries(dir.path()), Vec::<String>::new());
   }
 }
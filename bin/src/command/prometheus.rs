@@ -0,0 +1,183 @@
+//! HTTP endpoint of the master serving the metrics in the Prometheus format,
+//! for the scrapers that do not go through the command socket
+//!
+//! The connections are handled with blocking sockets and short timeouts: a
+//! scraper sends its request right after connecting, and the answer is written
+//! once the workers sent their metrics.
+use std::io::{self,Read,Write};
+use std::net::{IpAddr,TcpStream};
+use std::time::Duration;
+use std::sync::atomic::{AtomicUsize,Ordering};
+use mio::net::TcpListener;
+
+use sozu::metrics::prometheus;
+use sozu_command::config::PrometheusConfig;
+use sozu_command::proxy::{AggregatedMetricsData,IpNetwork};
+
+/// timeout in seconds of the reads and writes on the scraper connections
+const TIMEOUT: u64 = 1;
+const MAX_REQUEST_SIZE: usize = 8192;
+
+/// numbers the metrics queries sent to the workers for the scrapers
+static QUERY_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+pub fn query_id() -> String {
+  format!("PROMETHEUS-{}", QUERY_COUNT.fetch_add(1, Ordering::SeqCst))
+}
+
+pub struct PrometheusEndpoint {
+  pub listener:    TcpListener,
+  allowed_sources: Vec<IpNetwork>,
+}
+
+impl PrometheusEndpoint {
+  pub fn bind(config: &PrometheusConfig) -> io::Result<PrometheusEndpoint> {
+    let listener = TcpListener::bind(&config.address)?;
+    Ok(PrometheusEndpoint::from_listener(listener, config))
+  }
+
+  /// uses the listener passed by the previous master during an upgrade
+  pub fn from_listener(listener: TcpListener, config: &PrometheusConfig) -> PrometheusEndpoint {
+    PrometheusEndpoint { listener, allowed_sources: config.allowed_sources.clone() }
+  }
+
+  pub fn is_allowed(&self, ip: &IpAddr) -> bool {
+    self.allowed_sources.is_empty() || self.allowed_sources.iter().any(|network| network.contains(ip))
+  }
+
+  /// accepts a scraper connection and reads its request. The metrics must be
+  /// written with `write_metrics` if it returns a connection, the other
+  /// requests are answered here
+  pub fn accept(&self) -> io::Result<Option<TcpStream>> {
+    let (mut stream, address) = self.listener.accept_std()?;
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(Duration::from_secs(TIMEOUT)))?;
+    stream.set_write_timeout(Some(Duration::from_secs(TIMEOUT)))?;
+
+    if !self.is_allowed(&address.ip()) {
+      warn!("refusing metrics request from {}", address);
+      write_answer(&mut stream, "403 Forbidden", "text/plain", "forbidden\n")?;
+      return Ok(None);
+    }
+
+    match read_request(&mut stream)? {
+      Request::Metrics          => Ok(Some(stream)),
+      Request::NotFound         => write_answer(&mut stream, "404 Not Found", "text/plain", "not found\n").map(|_| None),
+      Request::MethodNotAllowed => write_answer(&mut stream, "405 Method Not Allowed", "text/plain", "method not allowed\n").map(|_| None),
+      Request::Invalid          => write_answer(&mut stream, "400 Bad Request", "text/plain", "bad request\n").map(|_| None),
+    }
+  }
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Request {
+  Metrics,
+  NotFound,
+  MethodNotAllowed,
+  Invalid,
+}
+
+/// reads the request line and headers, the metrics are served on `/metrics`
+pub fn read_request<R: Read>(stream: &mut R) -> io::Result<Request> {
+  let mut data = Vec::new();
+  let mut buffer = [0; 1024];
+  while !data.windows(4).any(|window| window == &b"\r\n\r\n"[..]) {
+    if data.len() > MAX_REQUEST_SIZE {
+      return Ok(Request::Invalid);
+    }
+    match stream.read(&mut buffer)? {
+      0  => return Ok(Request::Invalid),
+      sz => data.extend_from_slice(&buffer[..sz]),
+    }
+  }
+
+  let line = data.split(|c| *c == b'\n').next().unwrap_or(&[]);
+  let mut parts = line.split(|c| *c == b' ');
+  let (method, path) = match (parts.next(), parts.next()) {
+    (Some(method), Some(path)) => (method, path),
+    _                          => return Ok(Request::Invalid),
+  };
+  let path = path.split(|c| *c == b'?').next().unwrap_or(path);
+
+  Ok(if path != &b"/metrics"[..] {
+    Request::NotFound
+  } else if method != &b"GET"[..] {
+    Request::MethodNotAllowed
+  } else {
+    Request::Metrics
+  })
+}
+
+pub fn write_metrics<W: Write>(stream: &mut W, data: &AggregatedMetricsData, prefix: &str) -> io::Result<()> {
+  write_answer(stream, "200 OK", prometheus::CONTENT_TYPE, &prometheus::exposition(data, prefix))
+}
+
+pub fn write_answer<W: Write>(stream: &mut W, status: &str, content_type: &str, body: &str) -> io::Result<()> {
+  write!(stream, "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+    status, content_type, body.len(), body)?;
+  stream.flush()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::collections::BTreeMap;
+  use std::io::Cursor;
+  use std::net::TcpListener as StdTcpListener;
+  use std::thread;
+  use sozu_command::proxy::{FilteredData,MetricsData};
+
+  #[test]
+  fn requests() {
+    let request = |data: &str| read_request(&mut Cursor::new(data.as_bytes().to_vec())).unwrap();
+    assert_eq!(request("GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n"), Request::Metrics);
+    assert_eq!(request("GET /metrics?name[]=sozu_http_requests HTTP/1.1\r\n\r\n"), Request::Metrics);
+    assert_eq!(request("GET / HTTP/1.1\r\n\r\n"), Request::NotFound);
+    assert_eq!(request("POST /metrics HTTP/1.1\r\n\r\n"), Request::MethodNotAllowed);
+    assert_eq!(request("GET /metrics HTTP/1.1\r\n"), Request::Invalid);
+    assert_eq!(request("garbage\r\n\r\n"), Request::Invalid);
+  }
+
+  #[test]
+  fn allowed_sources() {
+    let endpoint = |allowed_sources: &[&str]| PrometheusEndpoint::bind(&PrometheusConfig {
+      address: "127.0.0.1:0".parse().unwrap(),
+      allowed_sources: allowed_sources.iter().map(|network| network.parse().unwrap()).collect(),
+    }).expect("could not bind");
+
+    let localhost: IpAddr = "127.0.0.1".parse().unwrap();
+    assert!(endpoint(&[]).is_allowed(&localhost));
+    assert!(endpoint(&["10.0.0.0/8", "127.0.0.0/8"]).is_allowed(&localhost));
+    assert!(!endpoint(&["10.0.0.0/8"]).is_allowed(&localhost));
+  }
+
+  #[test]
+  fn serve_metrics() {
+    let listener = StdTcpListener::bind("127.0.0.1:0").expect("could not bind");
+    let address = listener.local_addr().unwrap();
+
+    let client = thread::spawn(move || {
+      let mut stream = TcpStream::connect(address).expect("could not connect");
+      stream.write_all(&b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n"[..]).unwrap();
+      let mut answer = String::new();
+      stream.read_to_string(&mut answer).expect("could not read the answer");
+      answer
+    });
+
+    let (mut stream, _) = listener.accept().expect("could not accept");
+    assert_eq!(read_request(&mut stream).unwrap(), Request::Metrics);
+
+    let mut proxy = BTreeMap::new();
+    proxy.insert(String::from("http.requests"), FilteredData::Count(42));
+    let mut workers = BTreeMap::new();
+    workers.insert(String::from("0"), MetricsData { proxy, applications: BTreeMap::new(), timestamp: 0, uptime: 0 });
+    let data = AggregatedMetricsData { master: BTreeMap::new(), workers };
+    write_metrics(&mut stream, &data, "sozu").unwrap();
+    drop(stream);
+
+    let answer = client.join().unwrap();
+    let body = "# TYPE sozu_http_requests counter\nsozu_http_requests{worker=\"0\"} 42\n";
+    assert_eq!(answer, format!("HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\n\
+      Content-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body));
+  }
+}
@@ -39,8 +39,6 @@ use std::panic;
 use sozu_command::config::Config;
 use clap::ArgMatches;
 
-#[cfg(target_os = "linux")]
-use libc::{cpu_set_t,pid_t};
 
 use command::Worker;
 use worker::{start_workers,get_executable_path};
@@ -84,7 +82,8 @@ fn main() {
     .and_then(|config| check_process_limits(&config).map(|()| config))
     .and_then(|config| init_workers(&config).map(|workers| (config, workers)))
     .map(|(config, workers)| {
-      if config.handle_process_affinity {
+      // the workers with configured cores bind themselves when they start
+      if config.handle_process_affinity && config.worker_cpus.is_empty() {
         set_workers_affinity(&workers);
       }
       let command_socket_path = config.command_socket_path();
@@ -161,7 +160,7 @@ fn set_workers_affinity(workers: &Vec<Worker>) {
   }
 
   let master_pid = unsafe { libc::getpid() };
-  set_process_affinity(master_pid, cpu_count);
+  util::set_process_affinity(master_pid, &[cpu_count]);
   cpu_count = cpu_count + 1;
 
   for ref worker in workers {
@@ -169,7 +168,7 @@ fn set_workers_affinity(workers: &Vec<Worker>) {
       cpu_count = 0;
     }
 
-    set_process_affinity(worker.pid, cpu_count);
+    util::set_process_affinity(worker.pid, &[cpu_count]);
 
     cpu_count = cpu_count + 1;
   }
@@ -183,21 +182,6 @@ fn set_workers_affinity(workers: &Vec<Worker>) {
 fn set_workers_affinity(_: &Vec<Worker>) {
 }
 
-/// Set a specific process to run onto a specific CPU core
-#[cfg(target_os = "linux")]
-use std::mem;
-#[cfg(target_os = "linux")]
-fn set_process_affinity(pid: pid_t, cpu: usize) {
-  unsafe {
-    let mut cpu_set: cpu_set_t = mem::zeroed();
-    let size_cpu_set = mem::size_of::<cpu_set_t>();
-    libc::CPU_SET(cpu, &mut cpu_set);
-    libc::sched_setaffinity(pid, size_cpu_set, &mut cpu_set);
-
-    debug!("Worker {} bound to CPU core {}", pid, cpu);
-  };
-}
-
 #[cfg(target_os="linux")]
 // We check the hard_limit. The soft_limit can be changed at runtime
 // by the process or any user. hard_limit can only be changed by root
@@ -28,6 +28,8 @@ pub struct SerializedWorker {
   pub token:      Option<usize>,
   pub queue:      Vec<ProxyRequest>,
   pub scm:        i32,
+  #[serde(default)]
+  pub cpus:       Vec<usize>,
 }
 
 impl SerializedWorker {
@@ -40,6 +42,7 @@ impl SerializedWorker {
       token:      worker.token.clone().map(|Token(t)| t),
       queue:      worker.queue.clone().into(),
       scm:        worker.scm.raw_fd(),
+      cpus:       worker.cpus.clone(),
     }
   }
 }
@@ -53,6 +56,9 @@ pub struct UpgradeData {
   pub state:       ConfigState,
   pub next_id:     u32,
   pub token_count: usize,
+  /// listen socket of the Prometheus endpoint
+  #[serde(default)]
+  pub prometheus:  Option<i32>,
 }
 
 pub fn start_new_master_process(executable_path: String, upgrade_data: UpgradeData) -> (pid_t, Channel<(),bool>) {
@@ -2,7 +2,9 @@ use nix::fcntl::{fcntl,FcntlArg,FdFlag};
 use std::os::unix::io::RawFd;
 use std::fs::File;
 use std::io::Write;
-use libc;
+use libc::{self,pid_t};
+#[cfg(target_os = "linux")]
+use std::mem;
 
 use logging;
 use sozu_command::config::Config;
@@ -33,6 +35,28 @@ pub fn disable_close_on_exec(fd: RawFd) -> Option<i32> {
   })
 }
 
+/// binds a process to the CPU cores, see man sched_setaffinity. The pid 0
+/// designates the calling process. Only works on Linux
+#[cfg(target_os = "linux")]
+pub fn set_process_affinity(pid: pid_t, cpus: &[usize]) {
+  unsafe {
+    let mut cpu_set: libc::cpu_set_t = mem::zeroed();
+    for cpu in cpus.iter() {
+      libc::CPU_SET(*cpu, &mut cpu_set);
+    }
+    if libc::sched_setaffinity(pid, mem::size_of::<libc::cpu_set_t>(), &cpu_set) != 0 {
+      error!("could not bind process {} to CPU cores {:?}", pid, cpus);
+    } else {
+      debug!("process {} bound to CPU cores {:?}", pid, cpus);
+    }
+  };
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_process_affinity(_: pid_t, cpus: &[usize]) {
+  debug!("binding the processes to CPU cores {:?} is not supported on this platform", cpus);
+}
+
 pub fn setup_logging(config: &Config) {
   //FIXME: should have an id for the master too
   logging::setup("MASTER".to_string(), &config.log_level,
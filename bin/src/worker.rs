@@ -50,7 +50,8 @@ pub fn start_workers(executable_path: String, config: &Config) -> nix::Result<Ve
       tls:  Vec::new(),
       tcp:  Vec::new(),
     });
-    match start_worker_process(&index.to_string(), config, executable_path.clone(), &state, listeners) {
+    let cpus = config.worker_cpus(index as u32);
+    match start_worker_process(&index.to_string(), config, executable_path.clone(), &state, listeners, &cpus) {
       Ok((pid, command, scm)) => {
         let w =  Worker::new(index as u32, pid, command, scm, config);
         workers.push(w);
@@ -62,7 +63,7 @@ pub fn start_workers(executable_path: String, config: &Config) -> nix::Result<Ve
 }
 
 pub fn start_worker(id: u32, config: &Config, executable_path: String, state: &ConfigState, listeners: Option<Listeners>) -> nix::Result<Worker> {
-  match start_worker_process(&id.to_string(), config, executable_path, state, listeners) {
+  match start_worker_process(&id.to_string(), config, executable_path, state, listeners, &config.worker_cpus(id)) {
     Ok((pid, command, scm)) => {
       let w = Worker::new(id, pid, command, scm, config);
       Ok(w)
@@ -72,7 +73,7 @@ pub fn start_worker(id: u32, config: &Config, executable_path: String, state: &C
 }
 
 pub fn begin_worker_process(fd: i32, scm: i32, configuration_state_fd: i32, id: i32,
-  command_buffer_size: usize, max_command_buffer_size: usize) {
+  command_buffer_size: usize, max_command_buffer_size: usize, cpus: Vec<usize>) {
   let mut command: Channel<ProxyResponse,Config> = Channel::new(
     unsafe { UnixStream::from_raw_fd(fd) },
     command_buffer_size,
@@ -100,15 +101,21 @@ pub fn begin_worker_process(fd: i32, scm: i32, configuration_state_fd: i32, id:
   if let Some(ref metrics) = worker_config.metrics.as_ref() {
     metrics::setup(&metrics.address, worker_id, metrics.tagged_metrics, metrics.prefix.clone());
   }
+  metrics::set_latency_buckets(worker_config.latency_buckets.clone());
 
   let mut server = Server::new_from_config(command, ScmSocket::new(scm), worker_config, config_state);
 
+  if !cpus.is_empty() {
+    util::set_process_affinity(0, &cpus);
+  }
+
   info!("starting event loop");
   server.run();
   info!("ending event loop");
 }
 
-pub fn start_worker_process(id: &str, config: &Config, executable_path: String, state: &ConfigState, listeners: Option<Listeners>) -> nix::Result<(pid_t, Channel<ProxyRequest,ProxyResponse>, ScmSocket)> {
+pub fn start_worker_process(id: &str, config: &Config, executable_path: String, state: &ConfigState, listeners: Option<Listeners>,
+  cpus: &[usize]) -> nix::Result<(pid_t, Channel<ProxyRequest,ProxyResponse>, ScmSocket)> {
   trace!("parent({})", unsafe { libc::getpid() });
 
   let mut state_file = tempfile().expect("could not create temporary file for configuration state");
@@ -154,19 +161,7 @@ pub fn start_worker_process(id: &str, config: &Config, executable_path: String,
     Ok(ForkResult::Child) => {
       trace!("child({}):\twill spawn a child", unsafe { libc::getpid() });
       Command::new(executable_path)
-        .arg("worker")
-        .arg("--id")
-        .arg(id)
-        .arg("--fd")
-        .arg(client.as_raw_fd().to_string())
-        .arg("--scm")
-        .arg(scm_client.as_raw_fd().to_string())
-        .arg("--configuration-state-fd")
-        .arg(state_file.as_raw_fd().to_string())
-        .arg("--command-buffer-size")
-        .arg(config.command_buffer_size.to_string())
-        .arg("--max-command-buffer-size")
-        .arg(config.max_command_buffer_size.to_string())
+        .args(worker_arguments(id, client.as_raw_fd(), scm_client.as_raw_fd(), state_file.as_raw_fd(), config, cpus))
         .exec();
 
       unreachable!();
@@ -178,6 +173,28 @@ pub fn start_worker_process(id: &str, config: &Config, executable_path: String,
   }
 }
 
+/// command line of the worker process, the CPU cores are only passed if the
+/// worker must be bound to them
+pub fn worker_arguments(id: &str, fd: i32, scm: i32, configuration_state_fd: i32, config: &Config, cpus: &[usize])
+  -> Vec<String> {
+  let mut arguments = vec![
+    String::from("worker"),
+    String::from("--id"), id.to_string(),
+    String::from("--fd"), fd.to_string(),
+    String::from("--scm"), scm.to_string(),
+    String::from("--configuration-state-fd"), configuration_state_fd.to_string(),
+    String::from("--command-buffer-size"), config.command_buffer_size.to_string(),
+    String::from("--max-command-buffer-size"), config.max_command_buffer_size.to_string(),
+  ];
+
+  if !cpus.is_empty() {
+    arguments.push(String::from("--cpus"));
+    arguments.push(cpus.iter().map(|cpu| cpu.to_string()).collect::<Vec<_>>().join(","));
+  }
+
+  arguments
+}
+
 #[cfg(target_os = "linux")]
 pub unsafe fn get_executable_path() -> String {
   use std::fs;
@@ -251,3 +268,33 @@ pub unsafe fn get_executable_path() -> String {
 
     String::from_raw_parts(path.as_mut_ptr(), capacity - 1, path.len())
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::fs;
+  use tempfile::tempdir;
+  use cli::parse_cpus;
+
+  #[test]
+  fn worker_cpus_arguments() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("config.toml");
+    let path = path.to_str().unwrap();
+    fs::write(path, "worker_count = 3\nworker_cpus = [[0, 1], [2]]\n").unwrap();
+    let config = Config::load_from_path(path).unwrap();
+
+    let arguments = worker_arguments("1", 3, 4, 5, &config, &config.worker_cpus(1));
+    assert_eq!(&arguments[arguments.len() - 2..], &["--cpus", "2"]);
+
+    let arguments = worker_arguments("2", 3, 4, 5, &config, &config.worker_cpus(2));
+    assert_eq!(&arguments[arguments.len() - 2..], &["--cpus", "0,1"]);
+    assert_eq!(parse_cpus(&arguments[arguments.len() - 1]), vec![0, 1]);
+
+    fs::write(path, "worker_count = 3\n").unwrap();
+    let config = Config::load_from_path(path).unwrap();
+    let arguments = worker_arguments("0", 3, 4, 5, &config, &config.worker_cpus(0));
+    assert!(!arguments.iter().any(|argument| argument == "--cpus"));
+    assert_eq!(arguments.len(), 13);
+  }
+}
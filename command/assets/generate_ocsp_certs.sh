@@ -0,0 +1,23 @@
+#!/bin/sh
+# certificates and OCSP response used by the OCSP stapling tests
+set -e
+TMP=$(mktemp -d)
+
+openssl req -x509 -nodes -days 36500 -newkey ec -pkeyopt ec_paramgen_curve:prime256v1 \
+  -keyout "$TMP/ca-key.pem" -out ocsp-ca.pem -subj "/O=Sozu/CN=Sozu test OCSP CA" \
+  -addext "basicConstraints=critical,CA:TRUE" -addext "keyUsage=critical,keyCertSign,cRLSign,digitalSignature"
+
+# the OCSP responder URL points to the test responder
+openssl req -nodes -newkey ec -pkeyopt ec_paramgen_curve:prime256v1 -subj "/CN=lolcatho.st" \
+  -keyout ocsp-leaf-key.pem -out "$TMP/leaf.csr"
+printf "basicConstraints=critical,CA:FALSE\nsubjectAltName=DNS:lolcatho.st\nauthorityInfoAccess=OCSP;URI:http://127.0.0.1:1132/ocsp\n" > "$TMP/leaf.ext"
+openssl x509 -req -days 36500 -in "$TMP/leaf.csr" -CA ocsp-ca.pem -CAkey "$TMP/ca-key.pem" \
+  -set_serial 0x1084 -extfile "$TMP/leaf.ext" -out ocsp-leaf.pem
+
+# the response is signed by the CA, and valid for 100 years
+printf "V\t21260101000000Z\t\t1084\tunknown\t/CN=lolcatho.st\n" > "$TMP/index.txt"
+openssl ocsp -issuer ocsp-ca.pem -cert ocsp-leaf.pem -no_nonce -reqout "$TMP/request.der"
+openssl ocsp -index "$TMP/index.txt" -rsigner ocsp-ca.pem -rkey "$TMP/ca-key.pem" -CA ocsp-ca.pem \
+  -reqin "$TMP/request.der" -ndays 36500 -respout ocsp-response.der
+
+rm -r "$TMP"
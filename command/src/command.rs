@@ -6,14 +6,14 @@ use std::net::SocketAddr;
 use std::collections::BTreeMap;
 
 use state::ConfigState;
-use proxy::{AggregatedMetricsData,ProxyRequestData,QueryAnswer,ProxyEvent};
+use proxy::{AggregatedMetricsData,ProxyRequestData,QueryAnswer,ProxyEvent,AccessLog};
 
 pub const PROTOCOL_VERSION: u8 = 0;
 
 #[derive(Debug,Clone,PartialEq,Eq,Hash)]
 pub enum CommandRequestData {
   Proxy(ProxyRequestData),
-  SaveState(String),
+  SaveState(String, StateFormat),
   LoadState(String),
   DumpState,
   ListWorkers,
@@ -21,6 +21,9 @@ pub enum CommandRequestData {
   UpgradeMaster,
   UpgradeWorker(u32),
   SubscribeEvents,
+  /// streams the access logs of the workers to this client until it disconnects
+  SubscribeLogs(LogFilter),
+  Ping,
 }
 
 #[derive(Debug,Clone,PartialEq,Eq,Hash)]
@@ -58,6 +61,7 @@ pub enum CommandResponseData {
   Query(BTreeMap<String, QueryAnswer>),
   State(ConfigState),
   Event(Event),
+  AccessLog(AccessLog),
 }
 
 #[derive(Debug,Clone,PartialEq,Eq,Serialize,Deserialize)]
@@ -84,22 +88,85 @@ impl CommandResponse {
 #[derive(Debug,Clone,Copy,PartialEq,Eq,Hash,Serialize,Deserialize)]
 #[serde(rename_all = "SCREAMING_SNAKE_CASE")]
 pub enum RunState {
+  /// launched, the worker does not accept connections until it answered the
+  /// ConfigurationLoaded order sent after its initial state
+  Initializing,
   Running,
   Stopping,
   Stopped,
   NotAnswering,
 }
 
+impl RunState {
+  /// the worker accepts connections and answers the queries
+  pub fn is_routable(&self) -> bool {
+    *self == RunState::Running
+  }
+
+  /// run state once the worker answered the ConfigurationLoaded order
+  pub fn configured(self) -> RunState {
+    match self {
+      RunState::Initializing => RunState::Running,
+      run_state              => run_state,
+    }
+  }
+}
+
 #[derive(Debug,Clone,PartialEq,Eq,Hash,Serialize,Deserialize)]
 pub struct WorkerInfo {
   pub id:         u32,
   pub pid:        i32,
   pub run_state:  RunState,
+  /// CPU cores the worker is bound to, empty if it is not bound
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub cpus:       Vec<usize>,
+}
+
+/// format of the state files. Both are a list of command messages, each
+/// carrying the protocol version, that can be loaded with LoadState
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Hash,Serialize,Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum StateFormat {
+  /// the orders in the iteration order of the state
+  Default,
+  /// the orders sorted, with the keys of their JSON objects sorted, so the same
+  /// configuration always gives the same file
+  Canonical,
+}
+
+impl StateFormat {
+  fn is_default(&self) -> bool {
+    *self == StateFormat::Default
+  }
+}
+
+impl Default for StateFormat {
+  fn default() -> StateFormat {
+    StateFormat::Default
+  }
 }
 
 #[derive(Deserialize)]
 struct SaveStateData {
-  path : String
+  path : String,
+  #[serde(default)]
+  format: StateFormat,
+}
+
+/// access logs sent to a client following the logs. A missing field matches all the logs
+#[derive(Debug,Clone,Default,PartialEq,Eq,Hash,Serialize,Deserialize)]
+pub struct LogFilter {
+  #[serde(default)]
+  pub app_id: Option<String>,
+  #[serde(default)]
+  pub status: Option<u16>,
+}
+
+impl LogFilter {
+  pub fn matches(&self, log: &AccessLog) -> bool {
+    self.app_id.as_ref().map(|app_id| log.app_id.as_ref() == Some(app_id)).unwrap_or(true) &&
+      self.status.map(|status| log.status == Some(status)).unwrap_or(true)
+  }
 }
 
 #[derive(Debug,Clone,PartialEq,Eq,Hash,Serialize,Deserialize)]
@@ -107,6 +174,17 @@ struct SaveStateData {
 pub enum Event {
   BackendDown(String, SocketAddr),
   NoAvailableBackends(String),
+  /// the backend failed too many requests, it is not selected for a while
+  BackendEjected(String, SocketAddr),
+  /// worker id and pid of a new worker
+  WorkerStarted(u32, i32),
+  /// the worker applied its initial configuration and accepts connections
+  WorkerConfigured(u32),
+  /// the worker is stopping, after its current sessions end
+  WorkerDraining(u32),
+  WorkerStopped(u32),
+  /// the worker stopped answering or exited without being asked to
+  WorkerCrashed(u32),
 }
 
 impl From<ProxyEvent> for Event {
@@ -114,6 +192,7 @@ impl From<ProxyEvent> for Event {
     match e {
       ProxyEvent::BackendDown(id, addr) => Event::BackendDown(id, addr),
       ProxyEvent::NoAvailableBackends(app_id) => Event::NoAvailableBackends(app_id),
+      ProxyEvent::BackendEjected(id, addr) => Event::BackendEjected(id, addr),
     }
   }
 }
@@ -215,7 +294,7 @@ impl<'de> serde::de::Visitor<'de> for CommandRequestVisitor {
         None => return Err(serde::de::Error::missing_field("data")),
       };
       let state: SaveStateData = serde_json::from_value(data).or_else(|_| Err(serde::de::Error::custom("save state")))?;
-      CommandRequestData::SaveState(state.path)
+      CommandRequestData::SaveState(state.path, state.format)
     } else if config_type == "DUMP_STATE" {
       CommandRequestData::DumpState
     } else if config_type == "LOAD_STATE" {
@@ -243,6 +322,14 @@ impl<'de> serde::de::Visitor<'de> for CommandRequestVisitor {
       CommandRequestData::UpgradeWorker(serde_json::from_value(data).or_else(|_| Err(serde::de::Error::custom("upgrade worker")))?)
     } else if config_type == "SUBSCRIBE_EVENTS" {
       CommandRequestData::SubscribeEvents
+    } else if config_type == "SUBSCRIBE_LOGS" {
+      let filter = match data {
+        Some(data) => serde_json::from_value(data).or_else(|_| Err(serde::de::Error::custom("subscribe logs")))?,
+        None => LogFilter::default(),
+      };
+      CommandRequestData::SubscribeLogs(filter)
+    } else if config_type == "PING" {
+      CommandRequestData::Ping
     } else {
       return Err(serde::de::Error::custom("unrecognized command"));
     };
@@ -269,6 +356,13 @@ struct StatePath {
   path: String
 }
 
+#[derive(Serialize)]
+struct SaveStatePath<'a> {
+  path:   &'a str,
+  #[serde(skip_serializing_if = "StateFormat::is_default")]
+  format: StateFormat,
+}
+
 impl serde::Serialize for CommandRequest {
   fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
       where S: serde::Serializer,
@@ -291,9 +385,9 @@ impl serde::Serialize for CommandRequest {
         map.serialize_entry("type", "PROXY")?;
         map.serialize_entry("data", order)?;
       },
-      CommandRequestData::SaveState(ref path) => {
+      CommandRequestData::SaveState(ref path, format) => {
         map.serialize_entry("type", "SAVE_STATE")?;
-        map.serialize_entry("data", &StatePath { path: path.to_string() })?;
+        map.serialize_entry("data", &SaveStatePath { path, format })?;
       },
       CommandRequestData::LoadState(ref path) => {
         map.serialize_entry("type", "LOAD_STATE")?;
@@ -319,6 +413,13 @@ impl serde::Serialize for CommandRequest {
       CommandRequestData::SubscribeEvents => {
         map.serialize_entry("type", "SUBSCRIBE_EVENTS")?;
       },
+      CommandRequestData::SubscribeLogs(ref filter) => {
+        map.serialize_entry("type", "SUBSCRIBE_LOGS")?;
+        map.serialize_entry("data", filter)?;
+      },
+      CommandRequestData::Ping => {
+        map.serialize_entry("type", "PING")?;
+      },
     };
 
     map.end()
@@ -332,10 +433,12 @@ mod tests {
   use serde_json;
   use hex::FromHex;
   use certificate::split_certificate_chain;
-  use proxy::{Application,CertificateAndKey,CertFingerprint,ProxyRequestData,HttpFront,Backend,
-    AppMetricsData,MetricsData,FilteredData,Percentiles,RemoveBackend,
-    AddCertificate,RemoveCertificate,LoadBalancingParams};
-  use config::{LoadBalancingAlgorithms,ProxyProtocolConfig};
+  use logging::LogFormat;
+  use proxy::{Application,CertificateAndKey,CertFingerprint,ProxyRequestData,HttpFront,TrailingSlash,Backend,
+    AppMetricsData,MetricsData,FilteredData,Percentiles,Bucket,RemoveBackend,ReplaceBackend,
+    AddCertificate,RemoveCertificate,LoadBalancingParams,Trace,TraceFilter,AppTrace,HeaderRule,
+    RetryPolicyDefaults,DrainBackend,ResetBackend,Rebalance,SetOcspResponse,AppTimeout,RequestLimit};
+  use config::{ProxyProtocolConfig,ProxyProtocolVersion};
 
   #[test]
   fn config_message_test() {
@@ -346,7 +449,11 @@ mod tests {
       app_id: String::from("xxx"),
       hostname: String::from("yyy"),
       path_begin: String::from("xxx"),
+      trailing_slash: TrailingSlash::Strict,
       address: "0.0.0.0:8080".parse().unwrap(),
+      method: None,
+      header_match: None,
+      split: None,
     })));
   }
 
@@ -402,8 +509,20 @@ mod tests {
                   sticky_session: true,
                   https_redirect: true,
                   proxy_protocol: Some(ProxyProtocolConfig::ExpectHeader),
-                  load_balancing_policy: LoadBalancingAlgorithms::RoundRobin,
-                  answer_503: None,
+                  proxy_protocol_version: ProxyProtocolVersion::V1,
+                  request_headers: vec![HeaderRule::Add {
+                    name: String::from("Authorization"),
+                    value: String::from("Basic dXNlcjpwYXNz"),
+                    replace: true,
+                  }],
+                  response_headers: vec![HeaderRule::Remove { name: String::from("X-Powered-By") }],
+                  max_request_body_size: Some(10485760),
+                  request_id_headers: vec![String::from("X-Request-Id"), String::from("X-Correlation-Id")],
+                  rewrite_location: true,
+                  fallback_backend: Some("127.0.0.1:8080".parse().unwrap()),
+                  retry_unanswered_requests: true,
+                  request_limit: Some(RequestLimit { max_requests: 100, queue_size: 10 }),
+                  ..Default::default()
       })),
       worker_id: None
     });
@@ -415,6 +534,30 @@ mod tests {
       worker_id: None
     });
 
+  test_message!(enable_application, "../assets/enable_application.json", CommandRequest {
+      id:       "ID_TEST".to_string(),
+      version:  0,
+      data:     CommandRequestData::Proxy(ProxyRequestData::EnableApplication( String::from("xxx") )),
+      worker_id: None
+    });
+
+  test_message!(disable_application, "../assets/disable_application.json", CommandRequest {
+      id:       "ID_TEST".to_string(),
+      version:  0,
+      data:     CommandRequestData::Proxy(ProxyRequestData::DisableApplication( String::from("xxx") )),
+      worker_id: None
+    });
+
+  test_message!(set_app_timeout, "../assets/set_app_timeout.json", CommandRequest {
+      id:       "ID_TEST".to_string(),
+      version:  0,
+      data:     CommandRequestData::Proxy(ProxyRequestData::SetAppTimeout(AppTimeout {
+                  app_id:  String::from("xxx"),
+                  timeout: Some(1500),
+      })),
+      worker_id: None
+    });
+
   test_message!(add_http_front, "../assets/add_http_front.json", CommandRequest {
       id:       "ID_TEST".to_string(),
       version:  0,
@@ -422,7 +565,11 @@ mod tests {
                   app_id: String::from("xxx"),
                   hostname: String::from("yyy"),
                   path_begin: String::from("xxx"),
+                  trailing_slash: TrailingSlash::Strict,
                   address: "0.0.0.0:8080".parse().unwrap(),
+                  method: None,
+                  header_match: None,
+                  split: None,
       })),
       worker_id: None
     });
@@ -434,7 +581,11 @@ mod tests {
                   app_id: String::from("xxx"),
                   hostname: String::from("yyy"),
                   path_begin: String::from("xxx"),
+                  trailing_slash: TrailingSlash::Strict,
                   address: "0.0.0.0:8080".parse().unwrap(),
+                  method: None,
+                  header_match: None,
+                  split: None,
       })),
       worker_id: None
     });
@@ -446,7 +597,11 @@ mod tests {
                   app_id: String::from("xxx"),
                   hostname: String::from("yyy"),
                   path_begin: String::from("xxx"),
+                  trailing_slash: TrailingSlash::Strict,
                   address: "0.0.0.0:8443".parse().unwrap(),
+                  method: None,
+                  header_match: None,
+                  split: None,
       })),
       worker_id: None
     });
@@ -458,7 +613,11 @@ mod tests {
                   app_id: String::from("xxx"),
                   hostname: String::from("yyy"),
                   path_begin: String::from("xxx"),
+                  trailing_slash: TrailingSlash::Strict,
                   address: "0.0.0.0:8443".parse().unwrap(),
+                  method: None,
+                  header_match: None,
+                  split: None,
       })),
       worker_id: None
     });
@@ -493,6 +652,17 @@ mod tests {
       worker_id: None
     });
 
+  test_message!(set_ocsp_response, "../assets/set_ocsp_response.json", CommandRequest {
+      id:       "ID_TEST".to_string(),
+      version:  0,
+      data:     CommandRequestData::Proxy(ProxyRequestData::SetOcspResponse(SetOcspResponse {
+          front: "0.0.0.0:443".parse().unwrap(),
+          fingerprint: CertFingerprint(FromHex::from_hex("ab2618b674e15243fd02a5618c66509e4840ba60e7d64cebec84cdbfeceee0c5").unwrap()),
+          ocsp_response: vec![0x30, 0x03, 0x0a, 0x01, 0x01],
+      })),
+      worker_id: None
+    });
+
   test_message!(add_backend, "../assets/add_backend.json", CommandRequest {
       id:       "ID_TEST".to_string(),
       version:  0,
@@ -503,6 +673,7 @@ mod tests {
                   load_balancing_parameters: Some(LoadBalancingParams{ weight: 0 }),
                   sticky_id: Some(String::from("xxx-0")),
                   backup: Some(false),
+                  hostname: None,
       })),
       worker_id: None
     });
@@ -518,6 +689,81 @@ mod tests {
       worker_id: None
     });
 
+  test_message!(replace_backend, "../assets/replace_backend.json", CommandRequest {
+      id:       "ID_TEST".to_string(),
+      version:  0,
+      data:     CommandRequestData::Proxy(ProxyRequestData::ReplaceBackend(ReplaceBackend{
+                  app_id: String::from("xxx"),
+                  backend_id: String::from("xxx-0"),
+                  address: "127.0.0.2:8080".parse().unwrap(),
+      })),
+      worker_id: None
+    });
+
+  test_message!(drain_backend, "../assets/drain_backend.json", CommandRequest {
+      id:       "ID_TEST".to_string(),
+      version:  0,
+      data:     CommandRequestData::Proxy(ProxyRequestData::DrainBackend(DrainBackend{
+                  app_id: String::from("xxx"),
+                  backend_id: String::from("xxx-0"),
+      })),
+      worker_id: None
+    });
+
+  test_message!(reset_backend, "../assets/reset_backend.json", CommandRequest {
+      id:       "ID_TEST".to_string(),
+      version:  0,
+      data:     CommandRequestData::Proxy(ProxyRequestData::ResetBackend(ResetBackend{
+                  app_id: String::from("xxx"),
+                  backend_id: String::from("xxx-0"),
+      })),
+      worker_id: None
+    });
+
+  test_message!(rebalance_application, "../assets/rebalance_application.json", CommandRequest {
+      id:       "ID_TEST".to_string(),
+      version:  0,
+      data:     CommandRequestData::Proxy(ProxyRequestData::RebalanceApplication(Rebalance{
+                  app_id: String::from("xxx"),
+                  duration: 60,
+      })),
+      worker_id: None
+    });
+
+  test_message!(trace, "../assets/trace.json", CommandRequest {
+      id:       "ID_TEST".to_string(),
+      version:  0,
+      data:     CommandRequestData::Proxy(ProxyRequestData::Trace(Trace {
+                  filter: TraceFilter {
+                    hostname: Some(String::from("example.com")),
+                    path_begin: Some(String::from("/api")),
+                    client_ip: Some("192.168.1.10".parse().unwrap()),
+                  },
+                  duration: 60,
+      })),
+      worker_id: None
+    });
+
+  test_message!(trace_app, "../assets/trace_app.json", CommandRequest {
+      id:       "ID_TEST".to_string(),
+      version:  0,
+      data:     CommandRequestData::Proxy(ProxyRequestData::TraceApp(AppTrace {
+                  app_id: String::from("xxx"),
+                  enabled: true,
+      })),
+      worker_id: None
+    });
+
+  test_message!(set_retry_policy, "../assets/set_retry_policy.json", CommandRequest {
+      id:       "ID_TEST".to_string(),
+      version:  0,
+      data:     CommandRequestData::Proxy(ProxyRequestData::SetRetryPolicy(RetryPolicyDefaults {
+                  max_tries: 3,
+                  max_interval: Some(60),
+      })),
+      worker_id: None
+    });
+
   test_message!(soft_stop, "../assets/soft_stop.json", CommandRequest {
       id:       "ID_TEST".to_string(),
       version:  0,
@@ -525,6 +771,13 @@ mod tests {
       worker_id: Some(0),
     });
 
+  test_message!(drain_and_stop, "../assets/drain_and_stop.json", CommandRequest {
+      id:       "ID_TEST".to_string(),
+      version:  0,
+      data:     CommandRequestData::Proxy(ProxyRequestData::DrainAndStop(30)),
+      worker_id: None
+    });
+
   test_message!(hard_stop, "../assets/hard_stop.json", CommandRequest {
       id:       "ID_TEST".to_string(),
       version:  0,
@@ -532,6 +785,20 @@ mod tests {
       worker_id: Some(0),
     });
 
+  test_message!(log_format, "../assets/log_format.json", CommandRequest {
+      id:       "ID_TEST".to_string(),
+      version:  0,
+      data:     CommandRequestData::Proxy(ProxyRequestData::LogFormat(LogFormat::Json)),
+      worker_id: None
+    });
+
+  test_message!(reset_metrics, "../assets/reset_metrics.json", CommandRequest {
+      id:       "ID_TEST".to_string(),
+      version:  0,
+      data:     CommandRequestData::Proxy(ProxyRequestData::ResetMetrics),
+      worker_id: None
+    });
+
   test_message!(status, "../assets/status.json", CommandRequest {
       id:       "ID_TEST".to_string(),
       version:  0,
@@ -549,7 +816,14 @@ mod tests {
   test_message!(save_state, "../assets/save_state.json", CommandRequest {
       id:       "ID_TEST".to_string(),
       version:  0,
-      data:     CommandRequestData::SaveState(String::from("./config_dump.json")),
+      data:     CommandRequestData::SaveState(String::from("./config_dump.json"), StateFormat::Default),
+      worker_id: None
+    });
+
+  test_message!(save_canonical_state, "../assets/save_canonical_state.json", CommandRequest {
+      id:       "ID_TEST".to_string(),
+      version:  0,
+      data:     CommandRequestData::SaveState(String::from("./config_dump.json"), StateFormat::Canonical),
       worker_id: None
     });
 
@@ -574,6 +848,76 @@ mod tests {
       worker_id: None
     });
 
+  test_message!(ping, "../assets/ping.json", CommandRequest {
+      id:       "ID_TEST".to_string(),
+      version:  0,
+      data:     CommandRequestData::Ping,
+      worker_id: None
+    });
+
+  test_message!(subscribe_logs, "../assets/subscribe_logs.json", CommandRequest {
+      id:       "ID_TEST".to_string(),
+      version:  0,
+      data:     CommandRequestData::SubscribeLogs(LogFilter {
+                  app_id: Some(String::from("app_1")),
+                  status: Some(502),
+      }),
+      worker_id: None
+    });
+
+  #[test]
+  fn worker_initialization() {
+    assert!(!RunState::Initializing.is_routable());
+    assert_eq!(RunState::Initializing.configured(), RunState::Running);
+    assert!(RunState::Initializing.configured().is_routable());
+
+    // a configuration loaded again does not bring a stopping worker back
+    assert_eq!(RunState::Running.configured(), RunState::Running);
+    assert_eq!(RunState::Stopping.configured(), RunState::Stopping);
+    assert!(!RunState::Stopping.is_routable());
+    assert!(!RunState::NotAnswering.is_routable());
+
+    assert_eq!(serde_json::to_string(&RunState::Initializing).unwrap(), "\"INITIALIZING\"");
+  }
+
+  #[test]
+  fn log_filter() {
+    let log = AccessLog {
+      time:          String::from("2019-03-12T10:00:00.000000Z"),
+      message:       String::from("request to app_1"),
+      request_id:    String::from("ID-1"),
+      app_id:        Some(String::from("app_1")),
+      backend_id:    Some(String::from("app_1-0")),
+      client:        Some("192.168.1.1:4567".parse().unwrap()),
+      backend:       Some("127.0.0.1:1026".parse().unwrap()),
+      protocol:      String::from("HTTP"),
+      method:        Some(String::from("GET")),
+      host:          Some(String::from("example.com")),
+      path:          Some(String::from("/")),
+      status:        Some(502),
+      response_time: 12,
+      service_time:  3,
+      latency:       10,
+      bytes_in:      100,
+      bytes_out:     2000,
+      error:         None,
+      retries:       0,
+    };
+    let filter = |app_id: Option<&str>, status: Option<u16>| LogFilter { app_id: app_id.map(String::from), status };
+
+    assert!(filter(None, None).matches(&log));
+    assert!(filter(Some("app_1"), None).matches(&log));
+    assert!(filter(Some("app_1"), Some(502)).matches(&log));
+    assert!(!filter(Some("app_2"), Some(502)).matches(&log));
+    assert!(!filter(None, Some(200)).matches(&log));
+
+    // a log without application or status only matches the filters without them
+    let log = AccessLog { app_id: None, status: None, ..log };
+    assert!(filter(None, None).matches(&log));
+    assert!(!filter(Some("app_1"), None).matches(&log));
+    assert!(!filter(None, Some(502)).matches(&log));
+  }
+
   test_message!(upgrade_worker, "../assets/upgrade_worker.json", CommandRequest {
       id:       "ID_TEST".to_string(),
       version:  0,
@@ -591,11 +935,13 @@ mod tests {
           id:        1,
           pid:       5678,
           run_state: RunState::Running,
+          cpus:      Vec::new(),
         },
         WorkerInfo {
           id:        0,
           pid:       1234,
           run_state: RunState::Stopping,
+          cpus:      Vec::new(),
         },
       ))),
     });
@@ -630,6 +976,10 @@ mod tests {
                     p_99_99: 20,
                     p_99_999: 22,
                     p_100: 30,
+                    buckets: vec![
+                      Bucket { le: 1,  count: 25 },
+                      Bucket { le: 10, count: 40 },
+                    ],
                   }))
                 ].iter().cloned().collect(),
                 backends: [
@@ -645,11 +995,14 @@ mod tests {
                       p_99_99: 20,
                       p_99_999: 22,
                       p_100: 30,
+                      buckets: vec![],
                     }))
                   ].iter().cloned().collect())
                 ].iter().cloned().collect(),
               })
-            ].iter().cloned().collect()
+            ].iter().cloned().collect(),
+            timestamp: 1546300800000,
+            uptime:    3600000,
           })
         ].iter().cloned().collect()
       }))
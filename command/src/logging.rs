@@ -3,11 +3,15 @@ use std::fs::File;
 use std::str::FromStr;
 use std::cell::RefCell;
 use std::cmp::{self,Ord};
+use std::collections::VecDeque;
 use std::fmt::{Arguments,format};
 use std::io::{stdout,Stdout,Write,LineWriter,BufWriter};
 use std::net::{SocketAddr,UdpSocket};
 use std::net::TcpStream;
 use mio_uds::UnixDatagram;
+use serde;
+use serde_json;
+use proxy::AccessLog;
 
 thread_local! {
   pub static LOGGER: RefCell<Logger> = RefCell::new(Logger::new());
@@ -20,11 +24,18 @@ pub struct Logger {
   pub directives:     Vec<LogDirective>,
   pub backend:        LoggerBackend,
   pub access_backend: Option<LoggerBackend>,
+  pub format:         LogFormat,
   pub tag:            String,
   pub pid:            i32,
   pub initialized:    bool,
+  /// set while a client of the master follows the access logs
+  pub stream_access_logs: bool,
+  streamed_access_logs:   VecDeque<AccessLog>,
 }
 
+/// access logs kept until the worker sends them, the oldest ones are dropped
+const MAX_STREAMED_ACCESS_LOGS: usize = 1000;
+
 impl Logger {
   pub fn new() -> Logger {
     Logger {
@@ -34,9 +45,12 @@ impl Logger {
       }),
       backend:        LoggerBackend::Stdout(stdout()),
       access_backend: None,
+      format:         LogFormat::Text,
       tag:            "SOZU".to_string(),
       pid:            0,
       initialized:    false,
+      stream_access_logs:   false,
+      streamed_access_logs: VecDeque::new(),
     }
   }
 
@@ -59,107 +73,70 @@ impl Logger {
 
   }
 
-  pub fn log(&mut self, meta: &Metadata, args: Arguments) {
+  pub fn log(&mut self, meta: &Metadata, line: LogLine) {
     if self.enabled(meta) {
-      match self.backend {
-        LoggerBackend::Stdout(ref mut stdout) => {
-          let _ = stdout.write_fmt(args);
-        },
-        //FIXME: should have a buffer to write to instead of allocating a string
-        LoggerBackend::Unix(ref mut socket) => {
-          let _ = socket.send(format(args).as_bytes()).map_err(|e| {
-            println!("cannot write logs to Unix socket: {:?}", e);
-          });
-        },
-        //FIXME: should have a buffer to write to instead of allocating a string
-        LoggerBackend::Udp(ref mut socket, ref address) => {
-          let _ = socket.send_to(format(args).as_bytes(), address).map_err(|e| {
-            println!("cannot write logs to UDP socket: {:?}", e);
-          });
+      let format = self.format;
+      self.backend.write_line(format, &line);
+    }
+  }
+
+  /// writes the log line without checking the log level
+  pub fn log_verbose(&mut self, line: LogLine) {
+    let format = self.format;
+    self.backend.write_line(format, &line);
+  }
+
+  /// the streamed access logs do not depend on the log level
+  pub fn log_access(&mut self, meta: &Metadata, line: LogLine) {
+    if self.stream_access_logs {
+      if let Some(record) = line.access {
+        if self.streamed_access_logs.len() == MAX_STREAMED_ACCESS_LOGS {
+          self.streamed_access_logs.pop_front();
         }
-        LoggerBackend::Tcp(ref mut socket) => {
-          let _ = socket.write_fmt(args).map_err(|e| {
-            println!("cannot write logs to TCP socket: {:?}", e);
-          });
-        },
-        LoggerBackend::File(ref mut file) => {
-          let _ = file.write_fmt(args).map_err(|e| {
-            println!("cannot write logs to file: {:?}", e);
-          });
-        },
+        self.streamed_access_logs.push_back(record.to_access_log(&line));
       }
     }
-  }
 
-  pub fn log_access(&mut self, meta: &Metadata, args: Arguments) {
     if self.enabled(meta) {
+      let format = self.format;
       let backend = self.access_backend.as_mut().unwrap_or(&mut self.backend);
-      match *backend {
-        LoggerBackend::Stdout(ref mut stdout) => {
-          let _ = stdout.write_fmt(args);
-        },
-        //FIXME: should have a buffer to write to instead of allocating a string
-        LoggerBackend::Unix(ref mut socket) => {
-          let _ = socket.send(format(args).as_bytes()).map_err(|e| {
-            println!("cannot write logs to Unix socket: {:?}", e);
-          });
-        },
-        //FIXME: should have a buffer to write to instead of allocating a string
-        LoggerBackend::Udp(ref mut socket, ref address) => {
-          let _ = socket.send_to(format(args).as_bytes(), address).map_err(|e| {
-            println!("cannot write logs to UDP socket: {:?}", e);
-          });
-        }
-        LoggerBackend::Tcp(ref mut socket) => {
-          let _ = socket.write_fmt(args).map_err(|e| {
-            println!("cannot write logs to TCP socket: {:?}", e);
-          });
-        },
-        LoggerBackend::File(ref mut file) => {
-          let _ = file.write_fmt(args).map_err(|e| {
-            println!("cannot write logs to file: {:?}", e);
-          });
-        },
-      }
+      backend.write_line(format, &line);
     }
   }
 
-  pub fn compat_log(&mut self, meta: &log::Metadata, args: Arguments) {
+  pub fn compat_log(&mut self, meta: &log::Metadata, line: LogLine) {
     if self.compat_enabled(meta) {
-      match self.backend {
-        LoggerBackend::Stdout(ref mut stdout) => {
-          let _ = stdout.write_fmt(args);
-        },
-        //FIXME: should have a buffer to write to instead of allocating a string
-        LoggerBackend::Unix(ref mut socket) => {
-          let _= socket.send(format(args).as_bytes()).map_err(|e| {
-            println!("cannot write logs to Unix socket: {:?}", e);
-          });
-        },
-        //FIXME: should have a buffer to write to instead of allocating a string
-        LoggerBackend::Udp(ref mut socket, ref address) => {
-          let _ = socket.send_to(format(args).as_bytes(), address).map_err(|e| {
-            println!("cannot write logs to UDP socket: {:?}", e);
-          });
-        }
-        LoggerBackend::Tcp(ref mut socket) => {
-          let _ = socket.write_fmt(args).map_err(|e| {
-            println!("cannot write logs to TCP socket: {:?}", e);
-          });
-        },
-        LoggerBackend::File(ref mut file) => {
-          let _ = file.write_fmt(args).map_err(|e| {
-            println!("cannot write logs to file: {:?}", e);
-          });
-        },
-      }
+      let format = self.format;
+      self.backend.write_line(format, &line);
     }
   }
 
+  /// the access logs not sent yet are dropped when the stream stops
+  pub fn set_access_log_stream(&mut self, enabled: bool) {
+    self.stream_access_logs = enabled;
+    if !enabled {
+      self.streamed_access_logs.clear();
+    }
+  }
+
+  pub fn take_streamed_access_logs(&mut self) -> Vec<AccessLog> {
+    self.streamed_access_logs.drain(..).collect()
+  }
+
   pub fn set_directives(&mut self, directives: Vec<LogDirective>) {
     self.directives = directives;
   }
 
+  /// the lines buffered by the backends are written before switching,
+  /// so they keep their order and are not mixed with the new format
+  pub fn set_format(&mut self, format: LogFormat) {
+    self.backend.flush();
+    if let Some(backend) = self.access_backend.as_mut() {
+      backend.flush();
+    }
+    self.format = format;
+  }
+
   fn enabled(&self, meta: &Metadata) -> bool {
     // Search for the longest match, the vector is assumed to be pre-sorted.
     for directive in self.directives.iter().rev() {
@@ -188,6 +165,109 @@ impl Logger {
   }
 }
 
+/// format of the log lines, changed at runtime with the `LogFormat` order
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Hash,Serialize,Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+  Text,
+  /// one JSON object per line, for log aggregators
+  Json,
+}
+
+impl Default for LogFormat {
+  fn default() -> Self {
+    LogFormat::Text
+  }
+}
+
+impl FromStr for LogFormat {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "text" => Ok(LogFormat::Text),
+      "json" => Ok(LogFormat::Json),
+      _      => Err(format!("unknown log format '{}', expected 'text' or 'json'", s)),
+    }
+  }
+}
+
+/// a log line, as generated by the logging macros
+#[derive(Serialize)]
+pub struct LogLine<'a> {
+  #[serde(serialize_with = "serialize_display")]
+  pub time:      Rfc3339Time,
+  #[serde(rename = "timestamp")]
+  pub precise_time: i128,
+  pub pid:       i32,
+  pub tag:       &'a str,
+  pub level:     &'a str,
+  pub message:   Arguments<'a>,
+  #[serde(flatten, skip_serializing_if = "Option::is_none")]
+  pub access:    Option<&'a AccessRecord<'a>>,
+}
+
+/// fields of an HTTP or TCP access log. They are only written in the JSON format,
+/// the text format already has them in the message
+#[derive(Debug,Clone,PartialEq,Serialize)]
+pub struct AccessRecord<'a> {
+  pub request_id: String,
+  pub app_id:     Option<&'a str>,
+  pub backend_id: Option<&'a str>,
+  pub client:     Option<SocketAddr>,
+  pub backend:    Option<SocketAddr>,
+  pub protocol:   &'a str,
+  pub method:     Option<String>,
+  pub host:       Option<&'a str>,
+  pub path:       Option<&'a str>,
+  pub status:     Option<u16>,
+  /// in milliseconds
+  pub response_time: i64,
+  /// in milliseconds
+  pub service_time: i64,
+  /// in milliseconds, from the reception of the request line to the end of the response
+  pub latency:    i64,
+  pub bytes_in:   usize,
+  pub bytes_out:  usize,
+  pub error:      Option<&'a str>,
+  /// backend connections that failed or were closed without an answer before
+  /// the last one, 0 if the first backend served the request
+  pub retries:    u8,
+  /// subject of the verified certificate of a HTTPS client
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub client_certificate: Option<&'a str>,
+}
+
+impl<'a> AccessRecord<'a> {
+  pub fn to_access_log(&self, line: &LogLine) -> AccessLog {
+    AccessLog {
+      time:          line.time.to_string(),
+      message:       format!("{}", line.message),
+      request_id:    self.request_id.clone(),
+      app_id:        self.app_id.map(String::from),
+      backend_id:    self.backend_id.map(String::from),
+      client:        self.client,
+      backend:       self.backend,
+      protocol:      self.protocol.to_string(),
+      method:        self.method.clone(),
+      host:          self.host.map(String::from),
+      path:          self.path.map(String::from),
+      status:        self.status,
+      response_time: self.response_time,
+      service_time:  self.service_time,
+      latency:       self.latency,
+      bytes_in:      self.bytes_in,
+      bytes_out:     self.bytes_out,
+      error:         self.error.map(String::from),
+      retries:       self.retries,
+    }
+  }
+}
+
+fn serialize_display<T: ::std::fmt::Display, S: serde::Serializer>(value: &T, serializer: S) -> Result<S::Ok, S::Error> {
+  serializer.collect_str(value)
+}
+
 pub enum LoggerBackend {
   Stdout(Stdout),
   Unix(UnixDatagram),
@@ -196,6 +276,59 @@ pub enum LoggerBackend {
   File(::writer::MultiLineWriter<File>),
 }
 
+impl LoggerBackend {
+  fn write_line(&mut self, format: LogFormat, line: &LogLine) {
+    match format {
+      LogFormat::Text => self.write(format_args!("{} {} {} {} {}\t{}\n",
+        line.time, line.precise_time, line.pid, line.tag, line.level, line.message)),
+      LogFormat::Json => match serde_json::to_string(line) {
+        Ok(json) => self.write(format_args!("{}\n", json)),
+        Err(e)   => println!("cannot serialize log line: {:?}", e),
+      },
+    }
+  }
+
+  fn write(&mut self, args: Arguments) {
+    match *self {
+      LoggerBackend::Stdout(ref mut stdout) => {
+        let _ = stdout.write_fmt(args);
+      },
+      //FIXME: should have a buffer to write to instead of allocating a string
+      LoggerBackend::Unix(ref mut socket) => {
+        let _ = socket.send(format(args).as_bytes()).map_err(|e| {
+          println!("cannot write logs to Unix socket: {:?}", e);
+        });
+      },
+      //FIXME: should have a buffer to write to instead of allocating a string
+      LoggerBackend::Udp(ref mut socket, ref address) => {
+        let _ = socket.send_to(format(args).as_bytes(), address).map_err(|e| {
+          println!("cannot write logs to UDP socket: {:?}", e);
+        });
+      }
+      LoggerBackend::Tcp(ref mut socket) => {
+        let _ = socket.write_fmt(args).map_err(|e| {
+          println!("cannot write logs to TCP socket: {:?}", e);
+        });
+      },
+      LoggerBackend::File(ref mut file) => {
+        let _ = file.write_fmt(args).map_err(|e| {
+          println!("cannot write logs to file: {:?}", e);
+        });
+      },
+    }
+  }
+
+  fn flush(&mut self) {
+    let _ = match *self {
+      LoggerBackend::Stdout(ref mut stdout) => stdout.flush(),
+      LoggerBackend::Tcp(ref mut socket)    => socket.flush(),
+      LoggerBackend::File(ref mut file)     => file.flush(),
+      // datagrams are sent as soon as they are written
+      LoggerBackend::Unix(_) | LoggerBackend::Udp(_, _) => Ok(()),
+    };
+  }
+}
+
 #[repr(usize)]
 #[derive(Copy, Eq, Debug)]
 pub enum LogLevel {
@@ -470,10 +603,11 @@ macro_rules! log {
             let (now, precise_time) = $crate::logging::now();
             l.borrow_mut().log(
               &_META,
-              format_args!(
-                concat!("{} {} {} {} {}\t", $format, '\n'),
-                now, precise_time, pid, tag,
-                $level_tag $(, $final_args)*)
+              $crate::logging::LogLine {
+                time: now, precise_time, pid, tag, level: $level_tag,
+                message: format_args!($format $(, $final_args)*),
+                access: None,
+              }
             );
           })
         });
@@ -490,13 +624,13 @@ macro_rules! log {
 
 #[macro_export]
 macro_rules! log_access {
-    (__inner__ $target:expr, $lvl:expr, $format:expr, $level_tag:expr,
+    (__inner__ $target:expr, $record:expr, $lvl:expr, $format:expr, $level_tag:expr,
      [$($transformed_args:ident),*], [$first_ident:ident $(, $other_idents:ident)*], $first_arg:expr $(, $other_args:expr)*) => ({
       let $first_ident = &$first_arg;
-      log_access!(__inner__ $target, $lvl, $format, $level_tag, [$($transformed_args,)* $first_ident], [$($other_idents),*] $(, $other_args)*);
+      log_access!(__inner__ $target, $record, $lvl, $format, $level_tag, [$($transformed_args,)* $first_ident], [$($other_idents),*] $(, $other_args)*);
     });
 
-    (__inner__ $target:expr, $lvl:expr, $format:expr, $level_tag:expr,
+    (__inner__ $target:expr, $record:expr, $lvl:expr, $format:expr, $level_tag:expr,
      [$($final_args:ident),*], [$($idents:ident),*]) => ({
       static _META: $crate::logging::Metadata = $crate::logging::Metadata {
           level:  $lvl,
@@ -511,21 +645,26 @@ macro_rules! log_access {
             let (now, precise_time) = $crate::logging::now();
             l.borrow_mut().log_access(
               &_META,
-              format_args!(
-                concat!("{} {} {} {} {}\t", $format, '\n'),
-                now, precise_time, pid, tag,
-                $level_tag $(, $final_args)*)
+              $crate::logging::LogLine {
+                time: now, precise_time, pid, tag, level: $level_tag,
+                message: format_args!($format $(, $final_args)*),
+                access: $record,
+              }
             );
           })
         });
       }
     });
+    (@record $record:expr; $lvl:expr, $format:expr, $level_tag:expr $(, $args:expr)+) => {
+      log_access!(__inner__ module_path!(), Some($record), $lvl, $format, $level_tag, [], [a,b,c,d,e,f,g,h,i,j,k,l,m,n,o,p,q,r,s,t,u,v]
+                  $(, $args)+)
+    };
     ($lvl:expr, $format:expr, $level_tag:expr $(, $args:expr)+) => {
-      log_access!(__inner__ module_path!(), $lvl, $format, $level_tag, [], [a,b,c,d,e,f,g,h,i,j,k,l,m,n,o,p,q,r,s,t,u,v]
+      log_access!(__inner__ module_path!(), None, $lvl, $format, $level_tag, [], [a,b,c,d,e,f,g,h,i,j,k,l,m,n,o,p,q,r,s,t,u,v]
                   $(, $args)+)
     };
     ($lvl:expr, $format:expr, $level_tag:expr) => {
-      log_access!(__inner__ module_path!(), $lvl, $format, $level_tag, [], [a,b,c,d,e,f,g,h,i,j,k,l,m,n,o,p,q,r,s,t,u,v])
+      log_access!(__inner__ module_path!(), None, $lvl, $format, $level_tag, [], [a,b,c,d,e,f,g,h,i,j,k,l,m,n,o,p,q,r,s,t,u,v])
     };
 }
 
@@ -541,6 +680,9 @@ macro_rules! error {
 
 #[macro_export]
 macro_rules! error_access {
+    (@record $record:expr; $format:expr, $($arg:tt)*) => {
+        log_access!(@record $record; $crate::logging::LogLevel::Error, $format, "ERROR", $($arg)*);
+    };
     ($format:expr, $($arg:tt)*) => {
         log_access!($crate::logging::LogLevel::Error, $format, "ERROR", $($arg)*);
     };
@@ -572,6 +714,9 @@ macro_rules! info {
 
 #[macro_export]
 macro_rules! info_access {
+    (@record $record:expr; $format:expr, $($arg:tt)*) => {
+        log_access!(@record $record; $crate::logging::LogLevel::Info, $format, "INFO", $($arg)*);
+    };
     ($format:expr, $($arg:tt)*) => {
         log_access!($crate::logging::LogLevel::Info, $format, "INFO", $($arg)*);
     };
@@ -609,6 +754,28 @@ macro_rules! trace {
 }
 
 
+/// logs a message without checking the log level, used to trace
+/// some specific requests without changing the global logging level
+#[macro_export]
+macro_rules! verbose {
+    ($format:expr $(, $args:expr)*) => ({
+      $crate::logging::TAG.with(|tag| {
+        $crate::logging::LOGGER.with(|l| {
+          let pid = l.borrow().pid;
+
+          let (now, precise_time) = $crate::logging::now();
+          l.borrow_mut().log_verbose(
+            $crate::logging::LogLine {
+              time: now, precise_time, pid, tag, level: "VERBOSE",
+              message: format_args!($format $(, $args)*),
+              access: None,
+            }
+          );
+        })
+      });
+    });
+}
+
 #[macro_export]
 macro_rules! fixme {
     () => {
@@ -645,12 +812,14 @@ impl log::Log for CompatLogger {
       LOGGER.with(|l| {
         let pid = l.borrow().pid;
         let (now, precise_time) = now();
+        let level: LogLevel = record.level().into();
         l.borrow_mut().compat_log(
           record.metadata(),
-          format_args!(
-            concat!("{} {} {} {} {}\t{}\n"),
-            now, precise_time, pid, tag,
-            record.level(), record.args())
+          LogLine {
+            time: now, precise_time, pid, tag, level: LOG_LEVEL_NAMES[level as usize],
+            message: *record.args(),
+            access: None,
+          }
         );
       })
     });
@@ -685,3 +854,161 @@ pub fn now() -> (Rfc3339Time, i128) {
   let t = time::PrimitiveDateTime::now();
   (Rfc3339Time { inner: t, }, (t - time::PrimitiveDateTime::unix_epoch()).whole_nanoseconds())
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::Value;
+
+  static META: Metadata = Metadata {
+    level:  LogLevel::Info,
+    target: "sozu_command::logging::tests",
+  };
+
+  fn logger() -> (Logger, UnixDatagram) {
+    let (backend, receiver) = UnixDatagram::pair().expect("should create a socket pair");
+    let mut logger = Logger::new();
+    logger.set_directives(parse_logging_spec("info"));
+    logger.backend = LoggerBackend::Unix(backend);
+    (logger, receiver)
+  }
+
+  fn receive(receiver: &UnixDatagram) -> String {
+    let mut buf = [0u8; 4096];
+    let size = receiver.recv(&mut buf).expect("should receive a log line");
+    String::from_utf8(buf[..size].to_vec()).unwrap()
+  }
+
+  fn line<'a>(message: Arguments<'a>, access: Option<&'a AccessRecord<'a>>) -> LogLine<'a> {
+    let (time, precise_time) = now();
+    LogLine { time, precise_time, pid: 1234, tag: "TEST", level: "INFO", message, access }
+  }
+
+  #[test]
+  fn json_access_log() {
+    let (mut logger, receiver) = logger();
+    logger.set_format(LogFormat::Json);
+
+    let record = AccessRecord {
+      request_id:    String::from("ID-1"),
+      app_id:        Some("app_1"),
+      backend_id:    Some("app_1-0"),
+      client:        Some("192.168.1.1:4567".parse().unwrap()),
+      backend:       Some("127.0.0.1:1026".parse().unwrap()),
+      protocol:      "HTTP",
+      method:        Some(String::from("GET")),
+      host:          Some("example.com"),
+      path:          Some("/index.html"),
+      status:        Some(200),
+      response_time: 12,
+      service_time:  3,
+      latency:       10,
+      bytes_in:      100,
+      bytes_out:     2000,
+      error:         None,
+      retries:       1,
+      client_certificate: Some("CN=client.example.com"),
+    };
+    logger.log_access(&META, line(format_args!("request {}", "success"), Some(&record)));
+
+    let text = receive(&receiver);
+    assert!(text.ends_with('\n'));
+    let json: Value = serde_json::from_str(&text).expect("should be a JSON object");
+    assert_eq!(json["level"], "INFO");
+    assert_eq!(json["tag"], "TEST");
+    assert_eq!(json["pid"], 1234);
+    assert_eq!(json["message"], "request success");
+    assert_eq!(json["method"], "GET");
+    assert_eq!(json["host"], "example.com");
+    assert_eq!(json["path"], "/index.html");
+    assert_eq!(json["status"], 200);
+    assert_eq!(json["backend"], "127.0.0.1:1026");
+    assert_eq!(json["client"], "192.168.1.1:4567");
+    assert_eq!(json["app_id"], "app_1");
+    assert_eq!(json["response_time"], 12);
+    assert_eq!(json["service_time"], 3);
+    assert_eq!(json["latency"], 10);
+    assert_eq!(json["error"], Value::Null);
+    assert_eq!(json["retries"], 1);
+    assert_eq!(json["client_certificate"], "CN=client.example.com");
+  }
+
+  #[test]
+  fn switching_format() {
+    let (mut logger, receiver) = logger();
+
+    logger.log(&META, line(format_args!("first {}", 1), None));
+    logger.set_format(LogFormat::Json);
+    logger.log(&META, line(format_args!("second {}", 2), None));
+    logger.set_format(LogFormat::Text);
+    logger.log(&META, line(format_args!("third\t{}", 3), None));
+
+    let first = receive(&receiver);
+    assert!(first.ends_with(" 1234 TEST INFO\tfirst 1\n"), "{}", first);
+    let second: Value = serde_json::from_str(&receive(&receiver)).expect("should be a JSON object");
+    assert_eq!(second["message"], "second 2");
+    assert!(second.get("method").is_none());
+    assert!(receive(&receiver).ends_with(" 1234 TEST INFO\tthird\t3\n"));
+  }
+
+  #[test]
+  fn streamed_access_logs() {
+    let (mut logger, _receiver) = logger();
+    // the access logs are streamed even if their level is filtered
+    logger.set_directives(parse_logging_spec("error"));
+
+    let record = |status| AccessRecord {
+      request_id:    String::from("ID-1"),
+      app_id:        Some("app_1"),
+      backend_id:    None,
+      client:        None,
+      backend:       None,
+      protocol:      "HTTP",
+      method:        Some(String::from("GET")),
+      host:          Some("example.com"),
+      path:          Some("/"),
+      status:        Some(status),
+      response_time: 12,
+      service_time:  3,
+      latency:       10,
+      bytes_in:      100,
+      bytes_out:     2000,
+      error:         None,
+      retries:       0,
+      client_certificate: None,
+    };
+
+    logger.log_access(&META, line(format_args!("not streamed"), Some(&record(200))));
+    assert!(logger.take_streamed_access_logs().is_empty());
+
+    logger.set_access_log_stream(true);
+    logger.log_access(&META, line(format_args!("streamed {}", 1), Some(&record(200))));
+    logger.log_access(&META, line(format_args!("no access record"), None));
+    let logs = logger.take_streamed_access_logs();
+    assert_eq!(logs.len(), 1);
+    assert_eq!(logs[0].message, "streamed 1");
+    assert_eq!(logs[0].app_id, Some(String::from("app_1")));
+    assert_eq!(logs[0].status, Some(200));
+    assert!(logger.take_streamed_access_logs().is_empty());
+
+    // only the most recent logs are kept
+    for _ in 0..MAX_STREAMED_ACCESS_LOGS {
+      logger.log_access(&META, line(format_args!("old"), Some(&record(200))));
+    }
+    logger.log_access(&META, line(format_args!("new"), Some(&record(502))));
+    let logs = logger.take_streamed_access_logs();
+    assert_eq!(logs.len(), MAX_STREAMED_ACCESS_LOGS);
+    assert_eq!(logs.last().map(|log| log.status), Some(Some(502)));
+
+    logger.log_access(&META, line(format_args!("dropped"), Some(&record(200))));
+    logger.set_access_log_stream(false);
+    assert!(logger.take_streamed_access_logs().is_empty());
+  }
+
+  #[test]
+  fn parse_log_format() {
+    assert_eq!("json".parse(), Ok(LogFormat::Json));
+    assert_eq!("text".parse(), Ok(LogFormat::Text));
+    assert!("xml".parse::<LogFormat>().is_err());
+  }
+}
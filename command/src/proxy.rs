@@ -3,13 +3,15 @@ use serde::de::{self, Visitor};
 use hex::{self,FromHex};
 use std::fmt;
 use std::cmp::Ordering;
+use std::str::FromStr;
 use std::convert::From;
 use std::default::Default;
-use std::net::SocketAddr;
+use std::net::{IpAddr,SocketAddr};
 use std::collections::{HashMap,BTreeMap,HashSet};
 
 
-use config::{ProxyProtocolConfig, LoadBalancingAlgorithms};
+use config::{ProxyProtocolConfig, ProxyProtocolVersion, LoadBalancingAlgorithms};
+use logging::LogFormat;
 
 pub type MessageId = String;
 
@@ -38,8 +40,14 @@ pub enum ProxyResponseStatus {
 pub enum ProxyResponseData {
   //placeholder for now
   Metrics(MetricsData),
+  /// number of metric series that were reset
+  ResetMetrics(usize),
+  /// number of sessions still active when the drain timeout expired
+  DrainedSessions(usize),
   Query(QueryAnswer),
   Event(ProxyEvent),
+  /// access log of a session, sent while the access logs are streamed
+  AccessLog(AccessLog),
 }
 
 #[derive(Debug,Clone,PartialEq,Eq, Serialize, Deserialize)]
@@ -52,6 +60,13 @@ pub struct AggregatedMetricsData {
 pub struct MetricsData {
   pub proxy:        BTreeMap<String, FilteredData>,
   pub applications: BTreeMap<String, AppMetricsData>,
+  /// time of the collection, in milliseconds since the UNIX epoch
+  #[serde(default)]
+  pub timestamp:    u64,
+  /// time since the worker started, in milliseconds. Unlike the timestamp,
+  /// it does not go back when the system clock is changed
+  #[serde(default)]
+  pub uptime:       u64,
 }
 
 #[derive(Debug,Clone,PartialEq,Eq, Serialize, Deserialize)]
@@ -96,6 +111,17 @@ pub struct Percentiles {
   pub p_99_99:  u64,
   pub p_99_999: u64,
   pub p_100:    u64,
+  /// cumulative counts of the samples at the configured boundaries
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub buckets:  Vec<Bucket>,
+}
+
+/// number of samples lower or equal to a boundary, like the buckets of
+/// a Prometheus histogram
+#[derive(Debug,Clone,PartialEq,Eq,Hash, Serialize, Deserialize)]
+pub struct Bucket {
+  pub le:    u64,
+  pub count: u64,
 }
 
 #[derive(Debug,Clone,PartialEq,Eq, Serialize, Deserialize)]
@@ -110,6 +136,38 @@ pub struct BackendMetricsData {
 pub enum ProxyEvent {
   BackendDown(String, SocketAddr),
   NoAvailableBackends(String),
+  /// the backend failed too many requests, it is not selected for a while
+  BackendEjected(String, SocketAddr),
+}
+
+/// access log sent by a worker to the clients following the logs. It has the
+/// fields of the JSON access log, the message is the text format line
+#[derive(Debug,Clone,PartialEq,Eq,Serialize,Deserialize)]
+pub struct AccessLog {
+  pub time:       String,
+  pub message:    String,
+  pub request_id: String,
+  pub app_id:     Option<String>,
+  pub backend_id: Option<String>,
+  pub client:     Option<SocketAddr>,
+  pub backend:    Option<SocketAddr>,
+  pub protocol:   String,
+  pub method:     Option<String>,
+  pub host:       Option<String>,
+  pub path:       Option<String>,
+  pub status:     Option<u16>,
+  /// in milliseconds
+  pub response_time: i64,
+  /// in milliseconds
+  pub service_time: i64,
+  /// in milliseconds
+  pub latency:    i64,
+  pub bytes_in:   usize,
+  pub bytes_out:  usize,
+  pub error:      Option<String>,
+  /// backend connections tried before the last one
+  #[serde(default)]
+  pub retries:    u8,
 }
 
 #[derive(Debug,Clone,Serialize,Deserialize)]
@@ -129,28 +187,55 @@ impl fmt::Display for ProxyRequest {
 pub enum ProxyRequestData {
     AddApplication(Application),
     RemoveApplication(String),
+    /// removes an application with all its frontends and backends
+    PurgeApplication(String),
+    /// routes the traffic of a disabled application again
+    EnableApplication(String),
+    /// answers the requests of an application with its maintenance answer,
+    /// without removing its frontends and backends
+    DisableApplication(String),
+    /// changes the response timeout of an application, for its next requests
+    SetAppTimeout(AppTimeout),
 
     AddHttpFront(HttpFront),
     RemoveHttpFront(HttpFront),
 
     AddHttpsFront(HttpFront),
     RemoveHttpsFront(HttpFront),
+    /// changes the percentage of the requests sent to the canary application
+    /// by the HTTP and HTTPS frontends of an application on a hostname
+    SetTrafficSplit(TrafficSplitPercentage),
 
     AddCertificate(AddCertificate),
     ReplaceCertificate(ReplaceCertificate),
     RemoveCertificate(RemoveCertificate),
+    /// OCSP response stapled to the handshakes using a certificate. The
+    /// responses are fetched and refreshed by the master, not kept in the state
+    SetOcspResponse(SetOcspResponse),
+
+    /// certificates presented to an ACME server validating a domain with the
+    /// tls-alpn-01 challenge. They are temporary and not kept in the state
+    AddAcmeChallenge(AcmeChallenge),
+    RemoveAcmeChallenge(RemoveAcmeChallenge),
 
     AddTcpFront(TcpFront),
     RemoveTcpFront(TcpFront),
 
     AddBackend(Backend),
     RemoveBackend(RemoveBackend),
+    ReplaceBackend(ReplaceBackend),
+    DrainBackend(DrainBackend),
+    ResetBackend(ResetBackend),
+    /// sends the new connections of an application to its least loaded
+    /// backends for a while, to fill a backend that was just added
+    RebalanceApplication(Rebalance),
 
     AddHttpListener(HttpListener),
     AddHttpsListener(HttpsListener),
     AddTcpListener(TcpListener),
 
     RemoveListener(RemoveListener),
+    SetDefaultApp(DefaultApp),
 
     ActivateListener(ActivateListener),
     DeactivateListener(DeactivateListener),
@@ -158,13 +243,31 @@ pub enum ProxyRequestData {
     Query(Query),
 
     SoftStop,
+    /// soft stop closing the sessions still active after this number of seconds
+    DrainAndStop(u32),
     HardStop,
 
     Status,
     Metrics,
+    /// zeroes the counters and histograms of the workers, the gauges are kept
+    ResetMetrics,
     Logging(String),
+    /// switches the format of the log lines, including the access logs
+    LogFormat(LogFormat),
+    Trace(Trace),
+    TraceApp(AppTrace),
+    /// starts or stops sending the access logs to the master, as
+    /// `AccessLog` responses. It is not kept in the state
+    StreamAccessLogs(bool),
+    /// changes the retry policy of the backends added after this order.
+    /// It is not kept in the state: workers started later use the default
+    SetRetryPolicy(RetryPolicyDefaults),
 
     ReturnListenSockets,
+
+    /// sent to a new worker after its initial configuration: it only
+    /// accepts connections once the applications are all configured
+    ConfigurationLoaded,
 }
 
 
@@ -224,21 +327,263 @@ pub struct Application {
     pub https_redirect:    bool,
     #[serde(default)]
     pub proxy_protocol:    Option<ProxyProtocolConfig>,
+    /// version of the header sent to the backends with the `SendHeader` configuration
+    #[serde(default)]
+    pub proxy_protocol_version: ProxyProtocolVersion,
     #[serde(rename = "load_balancing_policy")]
     pub load_balancing_policy: LoadBalancingAlgorithms,
     pub answer_503:        Option<String>,
+    /// add the X-Forwarded-TLS-Version and X-Forwarded-TLS-Cipher headers
+    /// to the requests received on HTTPS listeners
+    #[serde(default)]
+    pub forward_tls_info:  bool,
+    /// add the Forwarded and X-Forwarded-* headers to the requests. If disabled,
+    /// the X-Forwarded-For header sent by the client is kept as is
+    #[serde(default = "default_add_forwarded_headers")]
+    pub add_forwarded_headers: bool,
+    /// header rules applied to the requests before sending them to a backend
+    #[serde(default)]
+    pub request_headers:   Vec<HeaderRule>,
+    /// header rules applied to the responses before sending them to the client
+    #[serde(default)]
+    pub response_headers:  Vec<HeaderRule>,
+    /// requests with a larger body are refused with a 413
+    #[serde(default)]
+    pub max_request_body_size: Option<usize>,
+    /// other names of the Sozu-Id header, the request id is
+    /// sent to the backends under each of them
+    #[serde(default)]
+    pub request_id_headers: Vec<String>,
+    /// rewrite the Location headers of responses that point at the backend, or at
+    /// the public host with the wrong scheme, to the public host and scheme
+    #[serde(default)]
+    pub rewrite_location: bool,
+    /// host names the backends use for themselves, like "app.internal". The
+    /// Location rewrite handles them like the backend address
+    #[serde(default)]
+    #[serde(skip_serializing_if="Vec::is_empty")]
+    pub backend_hosts: Vec<String>,
+    /// replace the Domain attribute of the Set-Cookie headers naming one of the
+    /// backend hosts with the public host
+    #[serde(default)]
+    pub rewrite_cookie_domain: bool,
+    /// backend receiving the traffic when none of the application's backends
+    /// is available, like a maintenance server
+    #[serde(default)]
+    pub fallback_backend: Option<SocketAddr>,
+    /// send a request again on a new backend connection when the backend closes
+    /// the connection without answering, if no byte of the request reached it or
+    /// if the request is idempotent
+    #[serde(default)]
+    pub retry_unanswered_requests: bool,
+    /// a disabled application keeps its configuration, but its HTTP requests
+    /// get the maintenance answer and its TCP connections are closed
+    #[serde(default = "default_application_enabled")]
+    pub enabled: bool,
+    /// answer sent to the requests of the application while it is disabled,
+    /// its 503 answer if None
+    #[serde(default)]
+    pub maintenance_answer: Option<String>,
+    /// status codes of the backend answers counted as failures of the backend,
+    /// like the failed connections, by its retry policy. The answer is still
+    /// sent to the client
+    #[serde(default)]
+    #[serde(skip_serializing_if="Vec::is_empty")]
+    pub failure_statuses: Vec<u16>,
+    /// time in milliseconds given to the backends to send their complete answer,
+    /// from the start of the request. They get a 504 answer once it expires. Only
+    /// the front timeout of the listener applies if None
+    #[serde(default)]
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub response_timeout: Option<u32>,
+    /// maximum number of requests sent to the backends at once, the next ones
+    /// wait in a bounded queue and get a 503 answer once it is full
+    #[serde(default)]
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub request_limit: Option<RequestLimit>,
+}
+
+impl Default for Application {
+  fn default() -> Application {
+    Application {
+      app_id:            String::new(),
+      sticky_session:    false,
+      https_redirect:    false,
+      proxy_protocol:    None,
+      proxy_protocol_version: ProxyProtocolVersion::default(),
+      load_balancing_policy: LoadBalancingAlgorithms::default(),
+      answer_503:        None,
+      forward_tls_info:  false,
+      add_forwarded_headers: default_add_forwarded_headers(),
+      request_headers:   Vec::new(),
+      response_headers:  Vec::new(),
+      max_request_body_size: None,
+      request_id_headers: Vec::new(),
+      rewrite_location:  false,
+      backend_hosts:     Vec::new(),
+      rewrite_cookie_domain: false,
+      fallback_backend:  None,
+      retry_unanswered_requests: false,
+      enabled:           default_application_enabled(),
+      maintenance_answer: None,
+      failure_statuses:  Vec::new(),
+      response_timeout:  None,
+      request_limit:     None,
+    }
+  }
+}
+
+/// concurrency limit of the HTTP requests of an application
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Hash,Serialize,Deserialize)]
+pub struct RequestLimit {
+    pub max_requests: u32,
+    /// requests waiting for one of the `max_requests` slots
+    #[serde(default)]
+    pub queue_size:   u32,
+}
+
+pub fn default_application_enabled() -> bool {
+  true
+}
+
+/// modification of the headers of an HTTP request or response
+#[derive(Debug,Clone,PartialEq,Eq,Hash,Serialize,Deserialize)]
+#[serde(tag = "action", rename_all = "lowercase")]
+pub enum HeaderRule {
+    /// adds a header after the existing ones with the same name,
+    /// or instead of them if `replace` is set
+    Add {
+      name:    String,
+      value:   String,
+      #[serde(default)]
+      replace: bool,
+    },
+    /// removes the headers with this name, if there are any
+    Remove {
+      name: String,
+    },
+}
+
+pub fn default_add_forwarded_headers() -> bool {
+  true
 }
 
 fn socketaddr_cmp(a: &SocketAddr, b: &SocketAddr) -> Ordering {
   a.ip().cmp(&b.ip()).then(a.port().cmp(&b.port()))
 }
 
+/// how trailing slashes are handled when matching the request path
+/// with the `path_begin` of a frontend
+#[derive(Debug,Copy,Clone,PartialEq,Eq,Hash,PartialOrd,Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TrailingSlash {
+  /// `path_begin` is a prefix of the request path: `/api` matches `/api`,
+  /// `/api/`, `/api/x` and `/apix`, while `/api/` does not match `/api`
+  Strict,
+  /// the trailing slashes of `path_begin` and of the request path are
+  /// ignored, and the path matches on a segment boundary: `/api` and `/api/`
+  /// both match `/api`, `/api/` and `/api/x`, but not `/apix`
+  Normalize,
+}
+
+impl TrailingSlash {
+  pub fn is_strict(&self) -> bool {
+    *self == TrailingSlash::Strict
+  }
+}
+
+impl Default for TrailingSlash {
+  fn default() -> TrailingSlash { TrailingSlash::Strict }
+}
+
+impl FromStr for TrailingSlash {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "strict"    => Ok(TrailingSlash::Strict),
+      "normalize" => Ok(TrailingSlash::Normalize),
+      _ => Err(format!("unknown trailing slash mode '{}', expected 'strict' or 'normalize'", s)),
+    }
+  }
+}
+
+/// a request header that must be present with this value. The name is
+/// compared without case, the value is compared exactly
+#[derive(Debug,Clone,PartialEq,Eq,Hash,PartialOrd,Ord,Serialize,Deserialize)]
+pub struct HeaderMatch {
+  pub name:  String,
+  pub value: String,
+}
+
+/// parses `Name: value`
+impl FromStr for HeaderMatch {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let mut parts = s.splitn(2, ':');
+    match (parts.next().map(|name| name.trim()), parts.next().map(|value| value.trim())) {
+      (Some(name), Some(value)) if !name.is_empty() => Ok(HeaderMatch { name: name.to_string(), value: value.to_string() }),
+      _ => Err(format!("invalid header match '{}', expected 'Name: value'", s)),
+    }
+  }
+}
+
 #[derive(Debug,Clone,PartialEq,Eq,Hash, Serialize, Deserialize)]
 pub struct HttpFront {
     pub app_id:     String,
     pub address:    SocketAddr,
     pub hostname:   String,
     pub path_begin: String,
+    #[serde(default)]
+    #[serde(skip_serializing_if="TrailingSlash::is_strict")]
+    pub trailing_slash: TrailingSlash,
+    /// only the requests with this method use the frontend. Otherwise, the
+    /// frontends without a method are used
+    #[serde(default)]
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub method:     Option<String>,
+    /// only the requests with this header use the frontend
+    #[serde(default)]
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub header_match: Option<HeaderMatch>,
+    #[serde(default)]
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub split:      Option<TrafficSplit>,
+}
+
+/// sends a part of the clients of a frontend to a canary application, the
+/// other ones go to the application of the frontend. A client always goes to
+/// the same side for a given percentage
+#[derive(Debug,Clone,PartialEq,Eq,Hash,PartialOrd,Ord,Serialize,Deserialize)]
+pub struct TrafficSplit {
+  pub canary_app_id: String,
+  /// percentage of the clients sent to the canary application, from 0 to 100
+  pub percentage:    u8,
+  /// the clients are identified by the value of this cookie, or by their
+  /// address if the request does not have it
+  #[serde(default)]
+  #[serde(skip_serializing_if="Option::is_none")]
+  pub cookie:        Option<String>,
+}
+
+#[derive(Debug,Clone,PartialEq,Eq,Hash,Serialize,Deserialize)]
+pub struct TrafficSplitPercentage {
+  /// application of the frontends
+  pub app_id:     String,
+  pub hostname:   String,
+  pub percentage: u8,
+}
+
+impl HttpFront {
+  /// the other frontend belongs to another application and matches exactly the
+  /// same requests, so the routing between them would be ambiguous
+  pub fn conflicts_with(&self, other: &HttpFront) -> bool {
+    self.app_id != other.app_id && self.address == other.address && self.hostname == other.hostname
+      && self.path_begin == other.path_begin && self.header_match == other.header_match
+      // the methods are compared without case, like in the routing
+      && self.method.as_ref().map(|m| m.to_uppercase()) == other.method.as_ref().map(|m| m.to_uppercase())
+  }
 }
 
 impl Ord for HttpFront {
@@ -247,6 +592,10 @@ impl Ord for HttpFront {
       .then(self.hostname.cmp(&o.hostname))
       .then(self.path_begin.cmp(&o.path_begin))
       .then(socketaddr_cmp(&self.address, &o.address))
+      .then(self.trailing_slash.cmp(&o.trailing_slash))
+      .then(self.method.cmp(&o.method))
+      .then(self.header_match.cmp(&o.header_match))
+      .then(self.split.cmp(&o.split))
   }
 }
 
@@ -294,6 +643,29 @@ pub struct ReplaceCertificate {
     pub new_names: Vec<String>,
 }
 
+#[derive(Debug,Clone,PartialEq,Eq,Hash, Serialize, Deserialize)]
+pub struct SetOcspResponse {
+    pub front:         SocketAddr,
+    pub fingerprint:   CertFingerprint,
+    /// DER encoded OCSP response. The staple is removed if it is empty
+    pub ocsp_response: Vec<u8>,
+}
+
+/// the certificate must contain the acmeIdentifier extension generated
+/// by the ACME client for `domain`
+#[derive(Debug,Clone,PartialEq,Eq,Hash, Serialize, Deserialize)]
+pub struct AcmeChallenge {
+    pub front:       SocketAddr,
+    pub domain:      String,
+    pub certificate: CertificateAndKey,
+}
+
+#[derive(Debug,Clone,PartialEq,Eq,Hash, Serialize, Deserialize)]
+pub struct RemoveAcmeChallenge {
+    pub front:  SocketAddr,
+    pub domain: String,
+}
+
 #[derive(Debug,Clone,PartialEq,Eq,Hash, Serialize, Deserialize)]
 pub struct TcpFront {
     pub app_id:  String,
@@ -326,6 +698,11 @@ pub struct Backend {
     pub load_balancing_parameters: Option<LoadBalancingParams>,
     #[serde(default)]
     pub backup:     Option<bool>,
+    /// "host:port" resolved again when connecting to the backend, the
+    /// address is then the last resolved one
+    #[serde(default)]
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub hostname:   Option<String>,
 }
 
 impl Ord for Backend {
@@ -335,6 +712,7 @@ impl Ord for Backend {
       .then(self.sticky_id.cmp(&o.sticky_id))
       .then(self.load_balancing_parameters.cmp(&o.load_balancing_parameters))
       .then(self.backup.cmp(&o.backup))
+      .then(self.hostname.cmp(&o.hostname))
       .then(socketaddr_cmp(&self.address, &o.address))
   }
 }
@@ -352,6 +730,98 @@ pub struct RemoveBackend {
     pub address:    SocketAddr,
 }
 
+/// changes the address of an existing backend. Connections already opened
+/// to the old address are not closed, they will drain as usual, while new
+/// connections use the new address
+#[derive(Debug,Clone,PartialEq,Eq,Hash, Serialize, Deserialize)]
+pub struct ReplaceBackend {
+    pub app_id:     String,
+    pub backend_id: String,
+    pub address:    SocketAddr,
+}
+
+/// selects the requests that will be logged verbosely
+#[derive(Debug,Clone,PartialEq,Eq,Hash, Serialize, Deserialize)]
+pub struct TraceFilter {
+    pub hostname:   Option<String>,
+    pub path_begin: Option<String>,
+    pub client_ip:  Option<IpAddr>,
+}
+
+impl TraceFilter {
+    pub fn matches(&self, hostname: Option<&str>, path: Option<&str>, client_ip: Option<IpAddr>) -> bool {
+      let hostname_matches = match (self.hostname.as_ref(), hostname) {
+        (None, _)                => true,
+        (Some(h1), Some(h2))     => h1.eq_ignore_ascii_case(h2),
+        (Some(_), None)          => false,
+      };
+
+      let path_matches = match (self.path_begin.as_ref(), path) {
+        (None, _)                => true,
+        (Some(prefix), Some(p))  => p.starts_with(prefix.as_str()),
+        (Some(_), None)          => false,
+      };
+
+      let ip_matches = match (self.client_ip, client_ip) {
+        (None, _)                => true,
+        (Some(ip1), Some(ip2))   => ip1 == ip2,
+        (Some(_), None)          => false,
+      };
+
+      hostname_matches && path_matches && ip_matches
+    }
+}
+
+/// verbose logging for the requests matching the filter, for `duration` seconds
+#[derive(Debug,Clone,PartialEq,Eq,Hash, Serialize, Deserialize)]
+pub struct Trace {
+    pub filter:   TraceFilter,
+    pub duration: u64,
+}
+
+/// verbose logging for the requests of an application, until it is disabled
+#[derive(Debug,Clone,PartialEq,Eq,Hash, Serialize, Deserialize)]
+pub struct AppTrace {
+    pub app_id:  String,
+    pub enabled: bool,
+}
+
+/// stops sending new connections to a backend, the current ones are kept
+/// until they close. The backend is not removed from the configuration
+#[derive(Debug,Clone,PartialEq,Eq,Hash, Serialize, Deserialize)]
+pub struct DrainBackend {
+    pub app_id:     String,
+    pub backend_id: String,
+}
+
+/// forgets the connection failures of a backend, so it is tried again right
+/// away instead of after its retry policy's backoff. A drained backend stays drained
+#[derive(Debug,Clone,PartialEq,Eq,Hash, Serialize, Deserialize)]
+pub struct ResetBackend {
+    pub app_id:     String,
+    pub backend_id: String,
+}
+
+/// the new connections of the application go to the backends with the least
+/// active connections until their counts are even, or for `duration` seconds.
+/// The existing connections are never moved
+#[derive(Debug,Clone,PartialEq,Eq,Hash, Serialize, Deserialize)]
+pub struct Rebalance {
+    pub app_id:   String,
+    pub duration: u32,
+}
+
+/// parameters of the retry policy given to new backends
+#[derive(Debug,Clone,PartialEq,Eq,Hash, Serialize, Deserialize)]
+pub struct RetryPolicyDefaults {
+    /// failed connection attempts before a backend is considered down
+    pub max_tries: usize,
+    /// longest wait between two connection attempts to a failing backend, in
+    /// seconds. Without it, the wait doubles at each failure
+    #[serde(default)]
+    pub max_interval: Option<u64>,
+}
+
 #[derive(Debug,Clone,PartialEq,Eq,Hash,PartialOrd,Ord, Serialize, Deserialize)]
 pub struct LoadBalancingParams {
     pub weight: u8,
@@ -383,6 +853,23 @@ pub struct RemoveListener {
   pub proxy: ListenerType,
 }
 
+#[derive(Debug,Clone,PartialEq,Eq,Hash, Serialize, Deserialize)]
+pub struct AppTimeout {
+  pub app_id:  String,
+  /// in milliseconds, the front timeout of the listeners applies if None
+  #[serde(default)]
+  pub timeout: Option<u32>,
+}
+
+/// sets or removes the application receiving the requests whose host matches
+/// no frontend of an HTTP or HTTPS listener
+#[derive(Debug,Clone,PartialEq,Eq,Hash, Serialize, Deserialize)]
+pub struct DefaultApp {
+  pub front:  SocketAddr,
+  pub proxy:  ListenerType,
+  pub app_id: Option<String>,
+}
+
 #[derive(Debug,Clone,PartialEq,Eq,Hash, Serialize, Deserialize)]
 pub struct ActivateListener {
   pub front:    SocketAddr,
@@ -407,6 +894,42 @@ pub struct HttpListener {
     pub expect_proxy:   bool,
     #[serde(default = "default_sticky_name")]
     pub sticky_name:    String,
+    #[serde(default)]
+    pub retry_after:    Option<RetryAfter>,
+    #[serde(default)]
+    pub interface:      Option<String>,
+    #[serde(default)]
+    pub preserve_proxy_connection: bool,
+    #[serde(default)]
+    pub ignore_unsupported_expect: bool,
+    #[serde(default)]
+    pub unexpected_body_policy: UnexpectedBodyPolicy,
+    #[serde(default)]
+    pub error_pages:    BTreeMap<u16, ErrorPage>,
+    #[serde(default)]
+    pub timeouts:       ListenerTimeouts,
+    #[serde(default)]
+    pub client_ip:      ClientIpConfig,
+    #[serde(default)]
+    pub connection_rate_limit: Option<ConnectionRateLimit>,
+    #[serde(default)]
+    pub max_sessions:   Option<usize>,
+    /// accepts the connections on a Unix domain socket instead of the front
+    /// address, which still identifies the listener
+    #[serde(default)]
+    pub unix_socket:    Option<UnixSocket>,
+    #[serde(default)]
+    pub header_limits:  HeaderLimits,
+    /// the front connection is closed after serving this many requests,
+    /// unlimited if not set
+    #[serde(default)]
+    pub max_keepalive_requests: Option<usize>,
+    /// gzip compression of the responses, disabled if not set
+    #[serde(default)]
+    pub compression:    Option<ResponseCompression>,
+    /// application receiving the requests whose host matches no frontend
+    #[serde(default)]
+    pub default_app:    Option<String>,
 }
 
 impl Default for HttpListener {
@@ -418,10 +941,305 @@ impl Default for HttpListener {
       answer_503:      String::from("HTTP/1.1 503 your application is in deployment\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n"),
       expect_proxy:    false,
       sticky_name:     String::from("SOZUBALANCEID"),
+      retry_after:     None,
+      interface:       None,
+      preserve_proxy_connection: false,
+      ignore_unsupported_expect: false,
+      unexpected_body_policy: UnexpectedBodyPolicy::Reject,
+      error_pages:     BTreeMap::new(),
+      timeouts:        ListenerTimeouts::default(),
+      client_ip:       ClientIpConfig::default(),
+      connection_rate_limit: None,
+      max_sessions:    None,
+      unix_socket:     None,
+      header_limits:   HeaderLimits::default(),
+      max_keepalive_requests: None,
+      compression:     None,
+      default_app:     None,
+    }
+  }
+}
+
+/// body sent instead of the built-in answer for a status code. The status
+/// line and headers are generated by the proxy
+#[derive(Debug,Clone,PartialEq,Eq,Hash, Serialize, Deserialize)]
+pub struct ErrorPage {
+    pub body:         String,
+    pub content_type: String,
+}
+
+/// range (in seconds) of the `Retry-After` header added to the 503 answers.
+/// The value is chosen randomly for each answer, to avoid synchronized
+/// retries from the clients
+#[derive(Debug,Copy,Clone,PartialEq,Eq,Hash, Serialize, Deserialize)]
+pub struct RetryAfter {
+    pub min: u32,
+    pub max: u32,
+}
+
+/// timeouts (in seconds) of the sessions accepted on a listener, overriding
+/// the global `front_timeout`, `idle_timeout` and `connect_timeout`
+/// options when they are set
+#[derive(Debug,Copy,Clone,Default,PartialEq,Eq,Hash, Serialize, Deserialize)]
+pub struct ListenerTimeouts {
+    #[serde(default)]
+    pub front_timeout:   Option<u32>,
+    #[serde(default)]
+    pub idle_timeout:    Option<u32>,
+    #[serde(default)]
+    pub header_timeout:  Option<u32>,
+    #[serde(default)]
+    pub connect_timeout: Option<u32>,
+}
+
+pub const DEFAULT_MAX_HEADER_SIZE: usize = 65536;
+pub const DEFAULT_MAX_HEADER_COUNT: usize = 100;
+pub const DEFAULT_MAX_RESPONSE_HEADER_SIZE: usize = 65536;
+pub const DEFAULT_MAX_REQUEST_LINE_SIZE: usize = 8192;
+
+/// limits on the header block of the requests, the request line included.
+/// The requests over one of them get a 431 answer and the connection is closed.
+/// The requests with a longer request line than `max_request_line_size` get a 414.
+/// The responses with a larger header block than `max_response_size` get a 502
+#[derive(Debug,Copy,Clone,PartialEq,Eq,Hash, Serialize, Deserialize)]
+pub struct HeaderLimits {
+    /// size in bytes, up to the empty line ending the header block
+    #[serde(default = "default_max_header_size")]
+    pub max_size:  usize,
+    /// number of header lines
+    #[serde(default = "default_max_header_count")]
+    pub max_count: usize,
+    /// size in bytes of the response header block, the status line included
+    #[serde(default = "default_max_response_header_size")]
+    pub max_response_size: usize,
+    /// size in bytes of the request line, without the line ending
+    #[serde(default = "default_max_request_line_size")]
+    pub max_request_line_size: usize,
+}
+
+impl Default for HeaderLimits {
+  fn default() -> HeaderLimits {
+    HeaderLimits {
+      max_size:  DEFAULT_MAX_HEADER_SIZE,
+      max_count: DEFAULT_MAX_HEADER_COUNT,
+      max_response_size: DEFAULT_MAX_RESPONSE_HEADER_SIZE,
+      max_request_line_size: DEFAULT_MAX_REQUEST_LINE_SIZE,
+    }
+  }
+}
+
+fn default_max_header_size() -> usize {
+  DEFAULT_MAX_HEADER_SIZE
+}
+
+fn default_max_header_count() -> usize {
+  DEFAULT_MAX_HEADER_COUNT
+}
+
+fn default_max_response_header_size() -> usize {
+  DEFAULT_MAX_RESPONSE_HEADER_SIZE
+}
+
+fn default_max_request_line_size() -> usize {
+  DEFAULT_MAX_REQUEST_LINE_SIZE
+}
+
+pub const DEFAULT_COMPRESSION_MIN_SIZE: usize = 1024;
+pub const DEFAULT_COMPRESSIBLE_TYPES: &[&str] = &["text/html", "text/plain", "text/css", "text/javascript",
+  "application/javascript", "application/json", "application/xml", "image/svg+xml"];
+
+/// gzip compression of the responses the backends did not encode, for the
+/// clients accepting it
+#[derive(Debug,Clone,PartialEq,Eq,Hash, Serialize, Deserialize)]
+pub struct ResponseCompression {
+    /// media types of the compressed responses, `type/*` matches all the subtypes
+    #[serde(default = "default_compressible_types")]
+    pub content_types: Vec<String>,
+    /// responses with a smaller Content-Length are sent as is. The size of the
+    /// chunked responses is not known, they are always compressed
+    #[serde(default = "default_compression_min_size")]
+    pub min_size:      usize,
+}
+
+impl Default for ResponseCompression {
+  fn default() -> ResponseCompression {
+    ResponseCompression {
+      content_types: default_compressible_types(),
+      min_size:      DEFAULT_COMPRESSION_MIN_SIZE,
     }
   }
 }
 
+fn default_compressible_types() -> Vec<String> {
+  DEFAULT_COMPRESSIBLE_TYPES.iter().map(|t| t.to_string()).collect()
+}
+
+fn default_compression_min_size() -> usize {
+  DEFAULT_COMPRESSION_MIN_SIZE
+}
+
+/// token bucket limiting the new connections accepted on a listener. The
+/// connections above the limit get a 429 answer on HTTP listeners, and are
+/// closed right away on HTTPS and TCP listeners
+#[derive(Debug,Copy,Clone,PartialEq,Eq,Hash, Serialize, Deserialize)]
+pub struct ConnectionRateLimit {
+    /// connections accepted per second, on average
+    pub rate:       u32,
+    /// connections that can be accepted at once, the size of the bucket
+    pub burst:      u32,
+    /// limits each client address separately instead of the whole listener
+    #[serde(default)]
+    pub per_client: bool,
+}
+
+/// Unix domain socket a listener is bound on. The socket file is created
+/// when the listener is activated and removed when it is closed
+#[derive(Debug,Clone,PartialEq,Eq,Hash, Serialize, Deserialize)]
+pub struct UnixSocket {
+    pub path: String,
+    /// permissions of the socket file, the umask applies when it is not set
+    #[serde(default)]
+    pub mode: Option<u32>,
+}
+
+/// origin of the client address of HTTP requests
+#[derive(Debug,Clone,PartialEq,Eq,Hash,Serialize,Deserialize)]
+#[serde(tag = "source", rename_all = "snake_case")]
+pub enum ClientIpSource {
+  /// address of the TCP connection's peer
+  Peer,
+  /// source address of the PROXY protocol header, on listeners with expect_proxy
+  ProxyProtocol,
+  /// the last address of the X-Forwarded-For headers that is not a trusted proxy
+  XForwardedFor,
+  /// address contained in a header, like X-Real-IP
+  Header { name: String },
+}
+
+/// how the client address of the requests accepted on a listener is found. It is
+/// used in the access logs, the request traces and by the HTTP transformer
+#[derive(Debug,Clone,Default,PartialEq,Eq,Hash,Serialize,Deserialize)]
+pub struct ClientIpConfig {
+  /// sources tried in order, the first one giving an address is used. If there
+  /// is none, or none of them gives an address, the PROXY protocol address is
+  /// used if there is one, then the peer address
+  #[serde(default)]
+  pub sources: Vec<ClientIpSource>,
+  /// the X-Forwarded-For and custom headers are only used for the requests
+  /// coming from those proxies
+  #[serde(default)]
+  pub trusted_proxies: Vec<IpNetwork>,
+}
+
+/// an IP address, or a network in the CIDR notation, like 10.0.0.0/8
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Hash)]
+pub struct IpNetwork {
+  pub address:    IpAddr,
+  pub prefix_len: u8,
+}
+
+impl IpNetwork {
+  pub fn contains(&self, ip: &IpAddr) -> bool {
+    match (self.address, ip) {
+      (IpAddr::V4(network), IpAddr::V4(ip)) => {
+        prefix_matches(&network.octets(), &ip.octets(), self.prefix_len)
+      },
+      (IpAddr::V6(network), IpAddr::V6(ip)) => {
+        prefix_matches(&network.octets(), &ip.octets(), self.prefix_len)
+      },
+      _ => false,
+    }
+  }
+}
+
+fn prefix_matches(network: &[u8], ip: &[u8], prefix_len: u8) -> bool {
+  let full_bytes = prefix_len as usize / 8;
+  if network[..full_bytes] != ip[..full_bytes] {
+    return false;
+  }
+
+  let remaining_bits = prefix_len % 8;
+  if remaining_bits == 0 {
+    return true;
+  }
+  let mask = 0xffu8 << (8 - remaining_bits);
+  network[full_bytes] & mask == ip[full_bytes] & mask
+}
+
+impl FromStr for IpNetwork {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<IpNetwork, String> {
+    let mut it = s.splitn(2, '/');
+    let address: IpAddr = it.next().unwrap_or("").parse()
+      .map_err(|e| format!("invalid address in '{}': {}", s, e))?;
+    let max_len = if address.is_ipv4() { 32 } else { 128 };
+
+    let prefix_len = match it.next() {
+      None => max_len,
+      Some(len) => match len.parse::<u8>() {
+        Ok(len) if len <= max_len => len,
+        _ => return Err(format!("invalid prefix length in '{}'", s)),
+      },
+    };
+
+    Ok(IpNetwork { address, prefix_len })
+  }
+}
+
+impl fmt::Display for IpNetwork {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}/{}", self.address, self.prefix_len)
+  }
+}
+
+impl serde::Serialize for IpNetwork {
+  fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+      where S: serde::Serializer,
+  {
+    serializer.serialize_str(&self.to_string())
+  }
+}
+
+struct IpNetworkVisitor;
+
+impl<'de> Visitor<'de> for IpNetworkVisitor {
+  type Value = IpNetwork;
+
+  fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+    formatter.write_str("an IP address or a network like 10.0.0.0/8")
+  }
+
+  fn visit_str<E>(self, value: &str) -> Result<IpNetwork, E>
+    where E: de::Error
+  {
+    value.parse().map_err(E::custom)
+  }
+}
+
+impl<'de> serde::Deserialize<'de> for IpNetwork {
+  fn deserialize<D>(deserializer: D) -> Result<IpNetwork, D::Error>
+        where D: serde::de::Deserializer<'de> {
+    deserializer.deserialize_str(IpNetworkVisitor{})
+  }
+}
+
+/// what to do with a GET, HEAD or TRACE request carrying a body.
+/// Those bodies have no defined semantics, and backends disagreeing on
+/// where the request ends can be abused for request smuggling
+#[derive(Debug,Copy,Clone,PartialEq,Eq,Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UnexpectedBodyPolicy {
+  /// answer with a 400 Bad Request
+  Reject,
+  /// forward the request and its body to the backend
+  Forward,
+}
+
+impl Default for UnexpectedBodyPolicy {
+    fn default() -> UnexpectedBodyPolicy { UnexpectedBodyPolicy::Reject }
+}
+
 #[derive(Debug,Copy,Clone,PartialEq,Eq,Hash, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum TlsProvider {
@@ -433,7 +1251,7 @@ impl Default for TlsProvider {
     fn default() -> TlsProvider { TlsProvider::Rustls }
 }
 
-#[derive(Debug,Clone,PartialEq,Eq,Hash, Serialize, Deserialize)]
+#[derive(Debug,Clone,Copy,PartialEq,Eq,PartialOrd,Ord,Hash, Serialize, Deserialize)]
 pub enum TlsVersion {
   SSLv2,
   SSLv3,
@@ -462,6 +1280,46 @@ pub struct HttpsListener {
     pub expect_proxy:       bool,
     #[serde(default = "default_sticky_name")]
     pub sticky_name:        String,
+    #[serde(default)]
+    pub retry_after:        Option<RetryAfter>,
+    #[serde(default)]
+    pub interface:          Option<String>,
+    #[serde(default)]
+    pub preserve_proxy_connection: bool,
+    #[serde(default)]
+    pub ignore_unsupported_expect: bool,
+    #[serde(default)]
+    pub unexpected_body_policy: UnexpectedBodyPolicy,
+    #[serde(default)]
+    pub error_pages:    BTreeMap<u16, ErrorPage>,
+    #[serde(default)]
+    pub timeouts:       ListenerTimeouts,
+    #[serde(default)]
+    pub client_ip:      ClientIpConfig,
+    #[serde(default)]
+    pub connection_rate_limit: Option<ConnectionRateLimit>,
+    #[serde(default)]
+    pub max_sessions:   Option<usize>,
+    /// PEM encoded CA certificates. If set, the clients must present a
+    /// certificate signed by one of them
+    #[serde(default)]
+    pub client_ca:      Option<String>,
+    #[serde(default)]
+    pub header_limits:  HeaderLimits,
+    /// the front connection is closed after serving this many requests,
+    /// unlimited if not set
+    #[serde(default)]
+    pub max_keepalive_requests: Option<usize>,
+    /// gzip compression of the responses, disabled if not set
+    #[serde(default)]
+    pub compression:    Option<ResponseCompression>,
+    /// application receiving the requests whose host matches no frontend
+    #[serde(default)]
+    pub default_app:    Option<String>,
+    /// the requests are routed on the TLS SNI hostname. If set, the ones whose
+    /// Host header does not match it, or without SNI, are refused
+    #[serde(default)]
+    pub reject_sni_mismatch: bool,
 }
 
 impl Default for HttpsListener {
@@ -490,6 +1348,22 @@ impl Default for HttpsListener {
       tls_provider:        TlsProvider::Rustls,
       expect_proxy:        false,
       sticky_name:     String::from("SOZUBALANCEID"),
+      retry_after:         None,
+      interface:           None,
+      preserve_proxy_connection: false,
+      ignore_unsupported_expect: false,
+      unexpected_body_policy: UnexpectedBodyPolicy::Reject,
+      error_pages:     BTreeMap::new(),
+      timeouts:        ListenerTimeouts::default(),
+      client_ip:       ClientIpConfig::default(),
+      connection_rate_limit: None,
+      max_sessions:    None,
+      client_ca:       None,
+      header_limits:   HeaderLimits::default(),
+      max_keepalive_requests: None,
+      compression:     None,
+      default_app:     None,
+      reject_sni_mismatch: false,
     }
   }
 }
@@ -500,6 +1374,14 @@ pub struct TcpListener {
   pub public_address: Option<SocketAddr>,
   #[serde(default)]
   pub expect_proxy:   bool,
+  #[serde(default)]
+  pub interface:      Option<String>,
+  #[serde(default)]
+  pub timeouts:       ListenerTimeouts,
+  #[serde(default)]
+  pub connection_rate_limit: Option<ConnectionRateLimit>,
+  #[serde(default)]
+  pub max_sessions:   Option<usize>,
 }
 
 #[derive(Debug,Clone,PartialEq,Eq,Hash, Serialize, Deserialize)]
@@ -508,6 +1390,27 @@ pub enum Query {
   Applications(QueryApplicationType),
   Certificates(QueryCertificateType),
   ApplicationsHashes,
+  Routes,
+  /// runs the frontend lookup of the workers for this request
+  RouteMatch(QueryRouteMatch),
+  /// live connection and failure counts of one backend
+  BackendStats(QueryBackendStats),
+}
+
+#[derive(Debug,Clone,PartialEq,Eq,Hash, Serialize, Deserialize)]
+pub struct QueryRouteMatch {
+  pub hostname: String,
+  pub path:     String,
+  /// method of the request, the frontends restricted to a method are skipped without it
+  #[serde(default)]
+  #[serde(skip_serializing_if="Option::is_none")]
+  pub method:   Option<String>,
+}
+
+#[derive(Debug,Clone,PartialEq,Eq,Hash, Serialize, Deserialize)]
+pub struct QueryBackendStats {
+  pub app_id:     String,
+  pub backend_id: String,
 }
 
 #[derive(Debug,Clone,PartialEq,Eq,Hash, Serialize, Deserialize)]
@@ -529,6 +1432,8 @@ pub enum QueryCertificateType {
   All,
   Domain(String),
   Fingerprint(Vec<u8>),
+  /// domains and expiration date of every certificate
+  List,
 }
 
 #[derive(Debug,Clone,PartialEq,Eq, Serialize, Deserialize)]
@@ -538,6 +1443,10 @@ pub enum QueryAnswer {
   /// application id, hash of application information
   ApplicationsHashes(BTreeMap<String, u64>),
   Certificates(QueryAnswerCertificate),
+  Routes(QueryAnswerRoutes),
+  RouteMatch(QueryAnswerRouteMatch),
+  /// None if the worker has no backend with this id for the application
+  BackendStats(Option<BackendStats>),
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
@@ -561,6 +1470,75 @@ impl Default for QueryAnswerApplication {
   }
 }
 
+/// frontends as they are routed by a worker, sorted by hostname for each listener
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct QueryAnswerRoutes {
+  pub http:  Vec<HttpFront>,
+  pub https: Vec<HttpFront>,
+}
+
+/// result of the frontend lookup on each HTTP and HTTPS listener
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct QueryAnswerRouteMatch {
+  pub http:  Vec<ListenerRouteMatch>,
+  pub https: Vec<ListenerRouteMatch>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct ListenerRouteMatch {
+  pub listener: SocketAddr,
+  pub route:    RouteMatch,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(tag = "type", content = "data", rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum RouteMatch {
+  /// no frontend matches the hostname and path
+  NoMatch,
+  /// a frontend matches, but none of the application's backends can be used
+  NoAvailableBackend(MatchedRoute),
+  Match(MatchedRoute),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct MatchedRoute {
+  pub frontend: HttpFront,
+  /// backends of the application, in the order of the worker's list
+  pub backends: Vec<RouteBackend>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct RouteBackend {
+  pub backend_id:         String,
+  pub address:            SocketAddr,
+  /// NORMAL, CLOSING or CLOSED
+  pub status:             String,
+  /// the backend can receive a new connection, according to its status and retry policy
+  pub available:          bool,
+  pub backup:             bool,
+  pub active_connections: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct BackendStats {
+  pub backend_id:         String,
+  pub address:            SocketAddr,
+  /// NORMAL, CLOSING or CLOSED
+  pub status:             String,
+  pub active_connections: usize,
+  /// failed connection attempts counted by the retry policy
+  pub failures:           usize,
+  /// the backend can receive a new connection, according to its status and retry policy
+  pub available:          bool,
+  /// state of the retry policy: CLOSED without failure since the last success,
+  /// OPEN while the backend is in backoff, HALF_OPEN once it can be tried again
+  #[serde(default)]
+  pub retry_state:        String,
+  /// unix timestamp from which the backend is tried again, if it is in backoff
+  #[serde(default)]
+  pub next_retry:         Option<i64>,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum QueryAnswerCertificate {
   /// returns a list of domain -> fingerprint
@@ -569,37 +1547,83 @@ pub enum QueryAnswerCertificate {
   Domain(HashMap<SocketAddr, Option<(String, Vec<u8>)>>),
   /// returns the certificate
   Fingerprint(Option<(String, Vec<String>)>),
+  /// returns the certificates of every listener
+  List(Vec<CertificateSummary>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct CertificateSummary {
+  pub listener:    SocketAddr,
+  pub fingerprint: Vec<u8>,
+  pub domains:     Vec<String>,
+  /// notAfter date, as a unix timestamp
+  pub not_after:   i64,
 }
 
 impl ProxyRequestData {
+  /// the order removes an application, frontend, backend or certificate
+  pub fn is_removal(&self) -> bool {
+    match *self {
+      ProxyRequestData::RemoveApplication(_) | ProxyRequestData::PurgeApplication(_) | ProxyRequestData::RemoveBackend(_) |
+      ProxyRequestData::RemoveHttpFront(_) | ProxyRequestData::RemoveHttpsFront(_) |
+      ProxyRequestData::RemoveTcpFront(_) | ProxyRequestData::RemoveCertificate(_) => true,
+      _ => false,
+    }
+  }
+
   pub fn get_topics(&self) -> HashSet<Topic> {
     match *self {
       ProxyRequestData::AddApplication(_)      => [Topic::HttpProxyConfig, Topic::HttpsProxyConfig, Topic::TcpProxyConfig].iter().cloned().collect(),
       ProxyRequestData::RemoveApplication(_)   => [Topic::HttpProxyConfig, Topic::HttpsProxyConfig, Topic::TcpProxyConfig].iter().cloned().collect(),
+      ProxyRequestData::PurgeApplication(_)    => [Topic::HttpProxyConfig, Topic::HttpsProxyConfig, Topic::TcpProxyConfig].iter().cloned().collect(),
+      ProxyRequestData::EnableApplication(_)   => [Topic::HttpProxyConfig, Topic::HttpsProxyConfig, Topic::TcpProxyConfig].iter().cloned().collect(),
+      ProxyRequestData::DisableApplication(_)  => [Topic::HttpProxyConfig, Topic::HttpsProxyConfig, Topic::TcpProxyConfig].iter().cloned().collect(),
+      ProxyRequestData::SetAppTimeout(_)       => [Topic::HttpProxyConfig, Topic::HttpsProxyConfig].iter().cloned().collect(),
       ProxyRequestData::AddHttpFront(_)        => [Topic::HttpProxyConfig].iter().cloned().collect(),
       ProxyRequestData::RemoveHttpFront(_)     => [Topic::HttpProxyConfig].iter().cloned().collect(),
       ProxyRequestData::AddHttpsFront(_)       => [Topic::HttpsProxyConfig].iter().cloned().collect(),
       ProxyRequestData::RemoveHttpsFront(_)    => [Topic::HttpsProxyConfig].iter().cloned().collect(),
+      ProxyRequestData::SetTrafficSplit(_)     => [Topic::HttpProxyConfig, Topic::HttpsProxyConfig].iter().cloned().collect(),
       ProxyRequestData::AddCertificate(_)      => [Topic::HttpsProxyConfig].iter().cloned().collect(),
       ProxyRequestData::ReplaceCertificate(_)  => [Topic::HttpsProxyConfig].iter().cloned().collect(),
       ProxyRequestData::RemoveCertificate(_)   => [Topic::HttpsProxyConfig].iter().cloned().collect(),
+      ProxyRequestData::SetOcspResponse(_)     => [Topic::HttpsProxyConfig].iter().cloned().collect(),
+      ProxyRequestData::AddAcmeChallenge(_)    => [Topic::HttpsProxyConfig].iter().cloned().collect(),
+      ProxyRequestData::RemoveAcmeChallenge(_) => [Topic::HttpsProxyConfig].iter().cloned().collect(),
       ProxyRequestData::AddTcpFront(_)         => [Topic::TcpProxyConfig].iter().cloned().collect(),
       ProxyRequestData::RemoveTcpFront(_)      => [Topic::TcpProxyConfig].iter().cloned().collect(),
       ProxyRequestData::AddBackend(_)          => [Topic::HttpProxyConfig, Topic::HttpsProxyConfig, Topic::TcpProxyConfig].iter().cloned().collect(),
       ProxyRequestData::RemoveBackend(_)       => [Topic::HttpProxyConfig, Topic::HttpsProxyConfig, Topic::TcpProxyConfig].iter().cloned().collect(),
+      ProxyRequestData::ReplaceBackend(_)      => [Topic::HttpProxyConfig, Topic::HttpsProxyConfig, Topic::TcpProxyConfig].iter().cloned().collect(),
+      ProxyRequestData::DrainBackend(_)        => [Topic::HttpProxyConfig, Topic::HttpsProxyConfig, Topic::TcpProxyConfig].iter().cloned().collect(),
+      ProxyRequestData::ResetBackend(_)        => [Topic::HttpProxyConfig, Topic::HttpsProxyConfig, Topic::TcpProxyConfig].iter().cloned().collect(),
+      ProxyRequestData::RebalanceApplication(_) => [Topic::HttpProxyConfig, Topic::HttpsProxyConfig, Topic::TcpProxyConfig].iter().cloned().collect(),
       ProxyRequestData::AddHttpListener(_)     => [Topic::HttpProxyConfig].iter().cloned().collect(),
       ProxyRequestData::AddHttpsListener(_)    => [Topic::HttpsProxyConfig].iter().cloned().collect(),
       ProxyRequestData::AddTcpListener(_)      => [Topic::TcpProxyConfig].iter().cloned().collect(),
       ProxyRequestData::RemoveListener(_)      => [Topic::HttpProxyConfig, Topic::HttpsProxyConfig, Topic::TcpProxyConfig].iter().cloned().collect(),
+      ProxyRequestData::SetDefaultApp(ref default) => match default.proxy {
+        ListenerType::HTTP  => [Topic::HttpProxyConfig].iter().cloned().collect(),
+        ListenerType::HTTPS => [Topic::HttpsProxyConfig].iter().cloned().collect(),
+        ListenerType::TCP   => [Topic::TcpProxyConfig].iter().cloned().collect(),
+      },
       ProxyRequestData::ActivateListener(_)    => [Topic::HttpProxyConfig, Topic::HttpsProxyConfig, Topic::TcpProxyConfig].iter().cloned().collect(),
       ProxyRequestData::DeactivateListener(_)  => [Topic::HttpProxyConfig, Topic::HttpsProxyConfig, Topic::TcpProxyConfig].iter().cloned().collect(),
       ProxyRequestData::Query(_)               => [Topic::HttpsProxyConfig].iter().cloned().collect(),
       ProxyRequestData::SoftStop               => [Topic::HttpProxyConfig, Topic::HttpsProxyConfig, Topic::TcpProxyConfig].iter().cloned().collect(),
+      ProxyRequestData::DrainAndStop(_)        => [Topic::HttpProxyConfig, Topic::HttpsProxyConfig, Topic::TcpProxyConfig].iter().cloned().collect(),
       ProxyRequestData::HardStop               => [Topic::HttpProxyConfig, Topic::HttpsProxyConfig, Topic::TcpProxyConfig].iter().cloned().collect(),
       ProxyRequestData::Status                 => [Topic::HttpProxyConfig, Topic::HttpsProxyConfig, Topic::TcpProxyConfig].iter().cloned().collect(),
       ProxyRequestData::Metrics                => HashSet::new(),
+      ProxyRequestData::ResetMetrics           => HashSet::new(),
       ProxyRequestData::Logging(_)             => [Topic::HttpsProxyConfig, Topic::HttpProxyConfig, Topic::TcpProxyConfig].iter().cloned().collect(),
+      ProxyRequestData::LogFormat(_)           => [Topic::HttpsProxyConfig, Topic::HttpProxyConfig, Topic::TcpProxyConfig].iter().cloned().collect(),
+      ProxyRequestData::Trace(_)               => HashSet::new(),
+      ProxyRequestData::TraceApp(_)            => HashSet::new(),
+      ProxyRequestData::StreamAccessLogs(_)    => HashSet::new(),
+      ProxyRequestData::SetRetryPolicy(_)      => HashSet::new(),
       ProxyRequestData::ReturnListenSockets    => HashSet::new(),
+      ProxyRequestData::ConfigurationLoaded    => HashSet::new(),
     }
   }
 }
@@ -616,6 +1640,29 @@ mod tests {
   use super::*;
   use serde_json;
 
+  #[test]
+  fn client_ip_config() {
+    let raw_json = r#"{"sources": [{"source": "header", "name": "X-Real-IP"}, {"source": "x_forwarded_for"}, {"source": "peer"}], "trusted_proxies": ["10.0.0.0/8", "2001:db8::1"]}"#;
+    let config: ClientIpConfig = serde_json::from_str(raw_json).expect("could not parse json");
+    assert_eq!(config.sources, vec![
+      ClientIpSource::Header { name: String::from("X-Real-IP") },
+      ClientIpSource::XForwardedFor,
+      ClientIpSource::Peer,
+    ]);
+    assert_eq!(config.trusted_proxies[1].prefix_len, 128);
+
+    let network = &config.trusted_proxies[0];
+    assert!(network.contains(&"10.200.3.4".parse().unwrap()));
+    assert!(!network.contains(&"11.0.0.1".parse().unwrap()));
+    assert!(!network.contains(&"::ffff:10.0.0.1".parse().unwrap()));
+
+    let network: IpNetwork = "192.168.1.128/25".parse().unwrap();
+    assert!(network.contains(&"192.168.1.200".parse().unwrap()));
+    assert!(!network.contains(&"192.168.1.100".parse().unwrap()));
+    assert!("10.0.0.0/33".parse::<IpNetwork>().is_err());
+    assert!(serde_json::from_str::<ClientIpConfig>(r#"{"trusted_proxies": ["10.0.0"]}"#).is_err());
+  }
+
   #[test]
   fn add_front_test() {
     let raw_json = r#"{"type": "ADD_HTTP_FRONT", "data": {"app_id": "xxx", "hostname": "yyy", "path_begin": "xxx", "address": "127.0.0.1:4242", "sticky_session": false}}"#;
@@ -625,7 +1672,11 @@ mod tests {
       app_id: String::from("xxx"),
       hostname: String::from("yyy"),
       path_begin: String::from("xxx"),
+      trailing_slash: TrailingSlash::Strict,
       address: "127.0.0.1:4242".parse().unwrap(),
+      method: None,
+      header_match: None,
+      split: None,
     }));
   }
 
@@ -638,7 +1689,11 @@ mod tests {
       app_id: String::from("xxx"),
       hostname: String::from("yyy"),
       path_begin: String::from("xxx"),
+      trailing_slash: TrailingSlash::Strict,
       address: "127.0.0.1:4242".parse().unwrap(),
+      method: None,
+      header_match: None,
+      split: None,
     }));
   }
 
@@ -655,6 +1710,7 @@ mod tests {
       sticky_id: None,
       load_balancing_parameters: Some(LoadBalancingParams{ weight: 0 }),
       backup: None,
+      hostname: None,
     }));
   }
 
@@ -679,7 +1735,11 @@ mod tests {
       app_id: String::from("aa"),
       hostname: String::from("cltdl.fr"),
       path_begin: String::from(""),
+      trailing_slash: TrailingSlash::Strict,
       address: "127.0.0.1:4242".parse().unwrap(),
+      method: None,
+      header_match: None,
+      split: None,
     }));
   }
 
@@ -692,7 +1752,11 @@ mod tests {
       app_id: String::from("aa"),
       hostname: String::from("cltdl.fr"),
       path_begin: String::from(""),
+      trailing_slash: TrailingSlash::Strict,
       address: "127.0.0.1:4242".parse().unwrap(),
+      method: None,
+      header_match: None,
+      split: None,
     });
   }
 }
@@ -223,3 +223,92 @@ impl Listeners {
     }
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::io::{Read, Write};
+  use std::net::{TcpListener as StdTcpListener, TcpStream};
+  use std::os::unix::io::AsRawFd;
+  use std::thread;
+
+  fn pair() -> (net::UnixStream, net::UnixStream) {
+    net::UnixStream::pair().expect("could not create the unix socket pair")
+  }
+
+  #[test]
+  fn send_and_receive_listeners() {
+    let (a, b) = pair();
+    let sender   = ScmSocket::new(a.as_raw_fd());
+    let receiver = ScmSocket::new(b.as_raw_fd());
+
+    let http = StdTcpListener::bind("127.0.0.1:0").expect("could not bind");
+    let tcp  = StdTcpListener::bind("127.0.0.1:0").expect("could not bind");
+    let http_address = http.local_addr().unwrap();
+    let tcp_address  = tcp.local_addr().unwrap();
+
+    let listeners = Listeners {
+      http: vec![(http_address, http.as_raw_fd())],
+      tls:  Vec::new(),
+      tcp:  vec![(tcp_address, tcp.as_raw_fd())],
+    };
+    sender.send_listeners(&listeners).expect("could not send the listeners");
+
+    let mut received = receiver.receive_listeners().expect("could not receive the listeners");
+    assert_eq!(received.http.len(), 1);
+    assert!(received.tls.is_empty());
+    assert_eq!(received.tcp.len(), 1);
+    assert_eq!(received.get_https(&http_address), None);
+
+    // the file descriptors are new ones, pointing to the same sockets
+    let http_fd = received.get_http(&http_address).expect("missing HTTP listener");
+    let tcp_fd  = received.get_tcp(&tcp_address).expect("missing TCP listener");
+    assert_ne!(http_fd, http.as_raw_fd());
+    let http_copy = unsafe { StdTcpListener::from_raw_fd(http_fd) };
+    let tcp_copy  = unsafe { StdTcpListener::from_raw_fd(tcp_fd) };
+    assert_eq!(http_copy.local_addr().unwrap(), http_address);
+    assert_eq!(tcp_copy.local_addr().unwrap(), tcp_address);
+  }
+
+  #[test]
+  fn received_listener_keeps_accepting() {
+    let (a, b) = pair();
+    let sender   = ScmSocket::new(a.as_raw_fd());
+    let receiver = ScmSocket::new(b.as_raw_fd());
+
+    let listener = StdTcpListener::bind("127.0.0.1:0").expect("could not bind");
+    let address  = listener.local_addr().unwrap();
+
+    // a client connecting while the listener is handed over waits in the backlog
+    let mut pending = TcpStream::connect(address).expect("could not connect");
+
+    let listeners = Listeners { http: vec![(address, listener.as_raw_fd())], tls: Vec::new(), tcp: Vec::new() };
+    sender.send_listeners(&listeners).expect("could not send the listeners");
+    // like the master after sending them, the previous owner closes its copy
+    drop(listener);
+
+    let new_worker = thread::spawn(move || {
+      let mut received = receiver.receive_listeners().expect("could not receive the listeners");
+      let fd = received.get_http(&address).expect("missing HTTP listener");
+      let listener = unsafe { StdTcpListener::from_raw_fd(fd) };
+      assert_eq!(listener.local_addr().unwrap(), address);
+
+      for _ in 0..2 {
+        let (mut stream, _) = listener.accept().expect("could not accept");
+        let mut buffer = [0; 4];
+        stream.read_exact(&mut buffer).unwrap();
+        stream.write_all(&buffer).unwrap();
+      }
+    });
+
+    let mut after = TcpStream::connect(address).expect("could not connect after the handover");
+    for stream in [&mut pending, &mut after].iter_mut() {
+      stream.write_all(b"ping").unwrap();
+      let mut buffer = [0; 4];
+      stream.read_exact(&mut buffer).unwrap();
+      assert_eq!(&buffer, b"ping");
+    }
+
+    new_worker.join().unwrap();
+  }
+}
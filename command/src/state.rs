@@ -2,14 +2,18 @@ use std::collections::{BTreeMap,HashMap,HashSet,BTreeSet};
 use std::collections::hash_map::DefaultHasher;
 use std::hash::{Hash, Hasher};
 use std::net::SocketAddr;
+use std::str;
 use std::iter::{repeat,FromIterator};
-use certificate::calculate_fingerprint;
+use serde::Serialize;
+use serde_json;
+use certificate::{calculate_fingerprint,parse_certificate_info,verify_key_pair,verify_chain};
+use command::{CommandRequest,CommandRequestData};
 
 use proxy::{Application,CertFingerprint,CertificateAndKey,ProxyRequestData,
   HttpFront,TcpFront,Backend,QueryAnswerApplication,
-  AddCertificate, RemoveCertificate, RemoveBackend,
+  AddCertificate, RemoveCertificate, ReplaceCertificate, RemoveBackend,
   HttpListener,HttpsListener,TcpListener,ListenerType,
-  ActivateListener,RemoveListener, DeactivateListener};
+  ActivateListener,RemoveListener, DeactivateListener, DefaultApp, CertificateSummary};
 
 pub type AppId = String;
 
@@ -83,6 +87,26 @@ impl ConfigState {
       &ProxyRequestData::RemoveApplication(ref app_id) => {
         self.applications.remove(app_id).is_some()
       },
+      // returns true if there was something to remove
+      &ProxyRequestData::PurgeApplication(ref app_id) => {
+        let found = self.applications.remove(app_id).is_some();
+        let objects = self.application_objects(app_id);
+        self.backends.remove(app_id);
+        self.http_fronts.remove(app_id);
+        self.https_fronts.remove(app_id);
+        self.tcp_fronts.remove(app_id);
+        found || objects > 0
+      },
+      // returns true if the application exists
+      &ProxyRequestData::EnableApplication(ref app_id) => {
+        self.applications.get_mut(app_id).map(|app| app.enabled = true).is_some()
+      },
+      &ProxyRequestData::DisableApplication(ref app_id) => {
+        self.applications.get_mut(app_id).map(|app| app.enabled = false).is_some()
+      },
+      &ProxyRequestData::SetAppTimeout(ref timeout) => {
+        self.applications.get_mut(&timeout.app_id).map(|app| app.response_timeout = timeout.timeout).is_some()
+      },
       &ProxyRequestData::AddHttpListener(ref listener) => {
         if self.http_listeners.contains_key(&listener.front) {
           false
@@ -128,7 +152,19 @@ impl ConfigState {
           ListenerType::TCP =>   self.tcp_listeners.get_mut(&deactivate.front).map(|t| t.1 = false).is_some(),
         }
       },
+      // returns false if there is no HTTP or HTTPS listener at this address
+      &ProxyRequestData::SetDefaultApp(ref default) => {
+        match default.proxy {
+          ListenerType::HTTP =>  self.http_listeners.get_mut(&default.front).map(|t| t.0.default_app = default.app_id.clone()).is_some(),
+          ListenerType::HTTPS => self.https_listeners.get_mut(&default.front).map(|t| t.0.default_app = default.app_id.clone()).is_some(),
+          ListenerType::TCP =>   false,
+        }
+      },
+      // returns false if another application already has the same frontend
       &ProxyRequestData::AddHttpFront(ref front) => {
+        if self.conflicting_front(front, false).is_some() {
+          return false;
+        }
         let front_vec = self.http_fronts.entry(front.app_id.clone()).or_insert_with(Vec::new);
         if !front_vec.contains(front) {
           front_vec.push(front.clone());
@@ -140,7 +176,7 @@ impl ConfigState {
       &ProxyRequestData::RemoveHttpFront(ref front) => {
         if let Some(front_list) = self.http_fronts.get_mut(&front.app_id) {
           let len = front_list.len();
-          front_list.retain(|el| el.hostname != front.hostname || el.path_begin != front.path_begin);
+          front_list.retain(|el| el.hostname != front.hostname || el.path_begin != front.path_begin || el.method != front.method);
 
           front_list.len() != len
         } else {
@@ -189,6 +225,9 @@ impl ConfigState {
         }
       },
       &ProxyRequestData::AddHttpsFront(ref front) => {
+        if self.conflicting_front(front, true).is_some() {
+          return false;
+        }
         let front_vec = self.https_fronts.entry(front.app_id.clone()).or_insert_with(Vec::new);
         if !front_vec.contains(front) {
           front_vec.push(front.clone());
@@ -200,12 +239,26 @@ impl ConfigState {
       &ProxyRequestData::RemoveHttpsFront(ref front) => {
         if let Some(front_list) = self.https_fronts.get_mut(&front.app_id) {
           let len = front_list.len();
-          front_list.retain(|el| el.hostname != front.hostname || el.path_begin != front.path_begin);
+          front_list.retain(|el| el.hostname != front.hostname || el.path_begin != front.path_begin || el.method != front.method);
           front_list.len() != len
         } else {
           false
         }
       },
+      // returns true if the application has a traffic split on this hostname
+      &ProxyRequestData::SetTrafficSplit(ref split) => {
+        let mut found = false;
+        for fronts in self.http_fronts.get_mut(&split.app_id).into_iter()
+          .chain(self.https_fronts.get_mut(&split.app_id).into_iter()) {
+          for front in fronts.iter_mut().filter(|front| front.hostname == split.hostname) {
+            if let Some(ref mut front_split) = front.split {
+              front_split.percentage = split.percentage;
+              found = true;
+            }
+          }
+        }
+        found
+      },
       &ProxyRequestData::AddTcpFront(ref front) => {
         let front_vec = self.tcp_fronts.entry(front.app_id.clone()).or_insert_with(Vec::new);
         if !front_vec.contains(front) {
@@ -242,8 +295,45 @@ impl ConfigState {
           false
         }
       },
+      &ProxyRequestData::ReplaceBackend(ref replace) => {
+        if let Some(backend_list) = self.backends.get_mut(&replace.app_id) {
+          if backend_list.iter().any(|b| b.backend_id != replace.backend_id && b.address == replace.address) {
+            false
+          } else if let Some(backend) = backend_list.iter_mut().find(|b| b.backend_id == replace.backend_id) {
+            backend.address = replace.address;
+            true
+          } else {
+            false
+          }
+        } else {
+          false
+        }
+      },
+      // the OCSP responses only exist in the master's cache and in the workers
+      &ProxyRequestData::SetOcspResponse(ref set) => {
+        self.certificates.get(&set.front).map(|certs| certs.contains_key(&set.fingerprint)).unwrap_or(false)
+      },
+      // the draining status only exists in the workers
+      &ProxyRequestData::DrainBackend(ref drain) => {
+        self.backends.get(&drain.app_id)
+          .map(|backend_list| backend_list.iter().any(|b| b.backend_id == drain.backend_id))
+          .unwrap_or(false)
+      },
+      // the connection failures only exist in the workers
+      &ProxyRequestData::ResetBackend(ref reset) => {
+        self.backends.get(&reset.app_id)
+          .map(|backend_list| backend_list.iter().any(|b| b.backend_id == reset.backend_id))
+          .unwrap_or(false)
+      },
+      // the rebalancing only exists in the workers
+      &ProxyRequestData::RebalanceApplication(ref rebalance) => {
+        self.backends.get(&rebalance.app_id).map(|backend_list| !backend_list.is_empty()).unwrap_or(false)
+      },
       // This is to avoid the error message
-      &ProxyRequestData::Logging(_) | &ProxyRequestData::Status | &ProxyRequestData::Query(_) => {false},
+      &ProxyRequestData::Logging(_) | &ProxyRequestData::LogFormat(_) | &ProxyRequestData::Trace(_) | &ProxyRequestData::TraceApp(_) | &ProxyRequestData::Status |
+        &ProxyRequestData::SetRetryPolicy(_) | &ProxyRequestData::StreamAccessLogs(_) |
+        &ProxyRequestData::Query(_) | &ProxyRequestData::ConfigurationLoaded |
+        &ProxyRequestData::AddAcmeChallenge(_) | &ProxyRequestData::RemoveAcmeChallenge(_) => {false},
       o => {
         error!("state cannot handle order message: {:#?}", o);
         false
@@ -328,6 +418,45 @@ impl ConfigState {
     v
   }
 
+  /// number of frontends and backends of the application
+  pub fn application_objects(&self, app_id: &str) -> usize {
+    self.backends.get(app_id).map(|v| v.len()).unwrap_or(0)
+      + self.http_fronts.get(app_id).map(|v| v.len()).unwrap_or(0)
+      + self.https_fronts.get(app_id).map(|v| v.len()).unwrap_or(0)
+      + self.tcp_fronts.get(app_id).map(|v| v.len()).unwrap_or(0)
+  }
+
+  /// the HTTP, or HTTPS, frontend of another application matching the same requests
+  pub fn conflicting_front(&self, front: &HttpFront, https: bool) -> Option<&HttpFront> {
+    let fronts = if https { &self.https_fronts } else { &self.http_fronts };
+    fronts.values().flat_map(|fronts| fronts.iter()).find(|f| f.conflicts_with(front))
+  }
+
+  /// the orders of `generate_orders` in an order that does not depend on the
+  /// maps of the state: grouped by kind, in the order they must be applied,
+  /// then sorted by their canonical JSON serialization
+  pub fn generate_canonical_orders(&self) -> Vec<ProxyRequestData> {
+    let mut orders: Vec<(u8, String, ProxyRequestData)> = self.generate_orders().into_iter().map(|order| {
+      let rank = match order {
+        ProxyRequestData::AddHttpListener(_)  => 0,
+        ProxyRequestData::AddHttpsListener(_) => 1,
+        ProxyRequestData::AddTcpListener(_)   => 2,
+        ProxyRequestData::ActivateListener(_) => 3,
+        ProxyRequestData::AddApplication(_)   => 4,
+        ProxyRequestData::AddHttpFront(_)     => 5,
+        ProxyRequestData::AddCertificate(_)   => 6,
+        ProxyRequestData::AddHttpsFront(_)    => 7,
+        ProxyRequestData::AddTcpFront(_)      => 8,
+        ProxyRequestData::AddBackend(_)       => 9,
+        _                                     => 10,
+      };
+      (rank, to_canonical_json(&order), order)
+    }).collect();
+
+    orders.sort_by(|a, b| (a.0, &a.1).cmp(&(b.0, &b.1)));
+    orders.into_iter().map(|(_, _, order)| order).collect()
+  }
+
   pub fn generate_activate_orders(&self) -> Vec<ProxyRequestData> {
     let mut v = Vec::new();
     for front in self.http_listeners.iter().filter(|(_,t)| t.1).map(|(k,_)| k) {
@@ -356,6 +485,22 @@ impl ConfigState {
     v
   }
 
+  /// orders converging the applications, backends and frontends to the ones created
+  /// by `desired`. The listeners and certificates are kept
+  pub fn reconcile_applications(&self, desired: &[ProxyRequestData]) -> Vec<ProxyRequestData> {
+    let mut target = self.clone();
+    target.applications.clear();
+    target.backends.clear();
+    target.http_fronts.clear();
+    target.https_fronts.clear();
+    target.tcp_fronts.clear();
+    for order in desired {
+      target.handle_order(order);
+    }
+
+    self.diff(&target)
+  }
+
   pub fn diff(&self, other:&ConfigState) -> Vec<ProxyRequestData> {
     let my_apps: HashSet<&AppId>    = self.applications.keys().collect();
     let their_apps: HashSet<&AppId> = other.applications.keys().collect();
@@ -584,12 +729,24 @@ impl ConfigState {
       let (their_listener, their_active) = &other.http_listeners[addr];
 
       if my_listener != their_listener {
-        v.push(ProxyRequestData::RemoveListener(RemoveListener {
-          front: **addr,
-          proxy: ListenerType::HTTP
-        }));
+        // the default application is changed without recreating the listener
+        let mut listener = their_listener.clone();
+        listener.default_app = my_listener.default_app.clone();
+
+        if *my_listener == listener {
+          v.push(ProxyRequestData::SetDefaultApp(DefaultApp {
+            front: **addr,
+            proxy: ListenerType::HTTP,
+            app_id: their_listener.default_app.clone(),
+          }));
+        } else {
+          v.push(ProxyRequestData::RemoveListener(RemoveListener {
+            front: **addr,
+            proxy: ListenerType::HTTP
+          }));
 
-        v.push(ProxyRequestData::AddHttpListener(their_listener.clone()));
+          v.push(ProxyRequestData::AddHttpListener(their_listener.clone()));
+        }
       }
 
       if *my_active && !*their_active {
@@ -614,12 +771,24 @@ impl ConfigState {
       let (their_listener, their_active) = &other.https_listeners[addr];
 
       if my_listener != their_listener {
-        v.push(ProxyRequestData::RemoveListener(RemoveListener {
-          front: **addr,
-          proxy: ListenerType::HTTPS
-        }));
+        // the default application is changed without recreating the listener
+        let mut listener = their_listener.clone();
+        listener.default_app = my_listener.default_app.clone();
+
+        if *my_listener == listener {
+          v.push(ProxyRequestData::SetDefaultApp(DefaultApp {
+            front: **addr,
+            proxy: ListenerType::HTTPS,
+            app_id: their_listener.default_app.clone(),
+          }));
+        } else {
+          v.push(ProxyRequestData::RemoveListener(RemoveListener {
+            front: **addr,
+            proxy: ListenerType::HTTPS
+          }));
 
-        v.push(ProxyRequestData::AddHttpsListener(their_listener.clone()));
+          v.push(ProxyRequestData::AddHttpsListener(their_listener.clone()));
+        }
       }
 
       if *my_active && !*their_active {
@@ -749,6 +918,12 @@ impl ConfigState {
   }
 }
 
+/// JSON serialization with the keys of the objects sorted, so equal values
+/// are serialized the same way even if they contain hash maps
+pub fn to_canonical_json<T: Serialize>(value: &T) -> String {
+  serde_json::to_value(value).map(|value| value.to_string()).unwrap_or_default()
+}
+
 pub fn get_application_ids_by_domain(state: &ConfigState, hostname: String, path_begin: Option<String>) -> HashSet<AppId> {
   let domain_check = |front_hostname: &str, front_path_begin: &str, hostname: &str, path_begin: &Option<String>| -> bool {
     if hostname != front_hostname {
@@ -787,21 +962,133 @@ pub fn get_certificate(state: &ConfigState, fingerprint: &[u8]) -> Option<(Strin
     .map(|(c, names)| (c.certificate.clone(), names.clone())).next()
 }
 
+/// certificates of every listener, sorted by expiration date
+pub fn list_certificates(state: &ConfigState) -> Vec<CertificateSummary> {
+  let mut summaries = state.certificates.iter().flat_map(|(listener, certificates)| {
+    certificates.iter().filter_map(move |(fingerprint, (certificate_and_key, _))| {
+      let der = ::pem::parse(&certificate_and_key.certificate).ok()?;
+      let info = parse_certificate_info(&der.contents)?;
+
+      Some(CertificateSummary {
+        listener:    *listener,
+        fingerprint: fingerprint.0.clone(),
+        domains:     info.domains,
+        not_after:   info.not_after,
+      })
+    })
+  }).collect::<Vec<_>>();
+
+  summaries.sort_by(|a, b| (a.not_after, a.listener, &a.fingerprint).cmp(&(b.not_after, b.listener, &b.fingerprint)));
+  summaries
+}
+
+/// parses a state file and applies its orders to a copy of the state, returning
+/// it with the number of orders. Nothing is applied if one message is invalid:
+/// every error is returned, with the line of the message
+pub fn verify_state_file(data: &[u8], state: &ConfigState) -> Result<(ConfigState, usize), Vec<String>> {
+  let mut new_state = state.clone();
+  let mut errors = Vec::new();
+  let mut message_counter = 0;
+  let mut line = 1;
+
+  for message in data.split(|c| *c == 0) {
+    let message_line = line;
+    line += message.iter().filter(|c| **c == b'\n').count();
+
+    let text = match str::from_utf8(message) {
+      Ok(text) => text,
+      Err(e) => {
+        errors.push(format!("line {}: {}", message_line, e));
+        continue;
+      }
+    };
+    if text.trim().is_empty() {
+      continue;
+    }
+    let message_line = message_line + text[..text.len() - text.trim_start().len()].matches('\n').count();
+
+    let order = match serde_json::from_str::<CommandRequest>(text) {
+      Ok(CommandRequest { data: CommandRequestData::Proxy(order), .. }) => order,
+      Ok(_) => continue,
+      Err(e) => {
+        errors.push(format!("line {}: {}", message_line, e));
+        continue;
+      }
+    };
+
+    message_counter += 1;
+    match verify_order(&new_state, &order) {
+      Ok(()) => { new_state.handle_order(&order); },
+      Err(e) => errors.push(format!("line {}: {}", message_line, e)),
+    }
+  }
+
+  if errors.is_empty() {
+    Ok((new_state, message_counter))
+  } else {
+    Err(errors)
+  }
+}
+
+/// checks the certificates, the addresses, and that a frontend is not
+/// already used by another application
+pub fn verify_order(state: &ConfigState, order: &ProxyRequestData) -> Result<(), String> {
+  match order {
+    ProxyRequestData::AddCertificate(AddCertificate { ref front, ref certificate, .. })
+    | ProxyRequestData::ReplaceCertificate(ReplaceCertificate { ref front, new_certificate: ref certificate, .. }) => {
+      verify_key_pair(&certificate.certificate, &certificate.key)
+        .and_then(|_| verify_chain(&certificate.certificate, &certificate.certificate_chain))
+        .map_err(|e| format!("certificate for {}: {}", front, e))
+    },
+    ProxyRequestData::AddBackend(ref backend) => {
+      if backend.address.port() == 0 || backend.address.ip().is_unspecified() {
+        Err(format!("backend {} of the application {}: invalid address {}", backend.backend_id, backend.app_id, backend.address))
+      } else {
+        Ok(())
+      }
+    },
+    ProxyRequestData::AddHttpFront(ref front) => verify_http_front(state, front, false),
+    ProxyRequestData::AddHttpsFront(ref front) => verify_http_front(state, front, true),
+    ProxyRequestData::AddTcpFront(ref front) => {
+      if front.address.port() == 0 {
+        return Err(format!("frontend of the application {}: invalid address {}", front.app_id, front.address));
+      }
+      match state.tcp_fronts.values().flat_map(|fronts| fronts.iter()).find(|f| f.address == front.address && f.app_id != front.app_id) {
+        Some(f) => Err(format!("frontend {} of the application {} is already used by the application {}", front.address, front.app_id, f.app_id)),
+        None    => Ok(()),
+      }
+    },
+    _ => Ok(()),
+  }
+}
+
+fn verify_http_front(state: &ConfigState, front: &HttpFront, https: bool) -> Result<(), String> {
+  if front.address.port() == 0 {
+    return Err(format!("frontend {}{} of the application {}: invalid address {}", front.hostname, front.path_begin, front.app_id, front.address));
+  }
+
+  match state.conflicting_front(front, https) {
+    Some(f) => Err(format!("frontend {}{} on {} of the application {} is already used by the application {}",
+      front.hostname, front.path_begin, front.address, front.app_id, f.app_id)),
+    None    => Ok(()),
+  }
+}
+
 #[cfg(test)]
 mod tests {
   use super::*;
-  use config::LoadBalancingAlgorithms;
-  use proxy::{ProxyRequestData,HttpFront,Backend,LoadBalancingParams,TlsProvider};
+  use proxy::{ProxyRequestData,HttpFront,TcpFront,TrailingSlash,Backend,LoadBalancingParams,TlsProvider,ReplaceBackend,DrainBackend,
+    ListenerTimeouts,UnexpectedBodyPolicy,ClientIpConfig,TrafficSplit,TrafficSplitPercentage,HeaderLimits,AppTimeout,Rebalance};
 
   #[test]
   fn serialize() {
     let mut state:ConfigState = Default::default();
-    state.handle_order(&ProxyRequestData::AddHttpFront(HttpFront { app_id: String::from("app_1"), hostname: String::from("lolcatho.st:8080"), path_begin: String::from("/"), address: "0.0.0.0:8080".parse().unwrap() }));
-    state.handle_order(&ProxyRequestData::AddHttpFront(HttpFront { app_id: String::from("app_2"), hostname: String::from("test.local"), path_begin: String::from("/abc"), address: "0.0.0.0:8080".parse().unwrap() }));
-    state.handle_order(&ProxyRequestData::AddBackend(Backend { app_id: String::from("app_1"), backend_id: String::from("app_1-0"), address: "127.0.0.1:1026".parse().unwrap(), load_balancing_parameters: Some(LoadBalancingParams::default()), sticky_id: None, backup: None  }));
-    state.handle_order(&ProxyRequestData::AddBackend(Backend { app_id: String::from("app_1"), backend_id: String::from("app_1-1"), address: "127.0.0.2:1027".parse().unwrap(), load_balancing_parameters: Some(LoadBalancingParams::default()), sticky_id: None, backup: None  }));
-    state.handle_order(&ProxyRequestData::AddBackend(Backend { app_id: String::from("app_2"), backend_id: String::from("app_2-0"), address: "192.167.1.2:1026".parse().unwrap(), load_balancing_parameters: Some(LoadBalancingParams::default()), sticky_id: None, backup: None  }));
-    state.handle_order(&ProxyRequestData::AddBackend(Backend { app_id: String::from("app_1"), backend_id: String::from("app_1-3"), address: "192.168.1.3:1027".parse().unwrap(), load_balancing_parameters: Some(LoadBalancingParams::default()) , sticky_id: None, backup: None }));
+    state.handle_order(&ProxyRequestData::AddHttpFront(HttpFront { app_id: String::from("app_1"), hostname: String::from("lolcatho.st:8080"), path_begin: String::from("/"), address: "0.0.0.0:8080".parse().unwrap(), trailing_slash: TrailingSlash::Strict, method: None, header_match: None, split: None }));
+    state.handle_order(&ProxyRequestData::AddHttpFront(HttpFront { app_id: String::from("app_2"), hostname: String::from("test.local"), path_begin: String::from("/abc"), address: "0.0.0.0:8080".parse().unwrap(), trailing_slash: TrailingSlash::Strict, method: None, header_match: None, split: None }));
+    state.handle_order(&ProxyRequestData::AddBackend(Backend { app_id: String::from("app_1"), backend_id: String::from("app_1-0"), address: "127.0.0.1:1026".parse().unwrap(), load_balancing_parameters: Some(LoadBalancingParams::default()), sticky_id: None, backup: None, hostname: None  }));
+    state.handle_order(&ProxyRequestData::AddBackend(Backend { app_id: String::from("app_1"), backend_id: String::from("app_1-1"), address: "127.0.0.2:1027".parse().unwrap(), load_balancing_parameters: Some(LoadBalancingParams::default()), sticky_id: None, backup: None, hostname: None  }));
+    state.handle_order(&ProxyRequestData::AddBackend(Backend { app_id: String::from("app_2"), backend_id: String::from("app_2-0"), address: "192.167.1.2:1026".parse().unwrap(), load_balancing_parameters: Some(LoadBalancingParams::default()), sticky_id: None, backup: None, hostname: None  }));
+    state.handle_order(&ProxyRequestData::AddBackend(Backend { app_id: String::from("app_1"), backend_id: String::from("app_1-3"), address: "192.168.1.3:1027".parse().unwrap(), load_balancing_parameters: Some(LoadBalancingParams::default()) , sticky_id: None, backup: None, hostname: None }));
     state.handle_order(&ProxyRequestData::RemoveBackend(RemoveBackend { app_id: String::from("app_1"), backend_id: String::from("app_1-3"), address: "192.168.1.3:1027".parse().unwrap() }));
 
     /*
@@ -818,26 +1105,26 @@ mod tests {
   #[test]
   fn diff() {
     let mut state:ConfigState = Default::default();
-    state.handle_order(&ProxyRequestData::AddHttpFront(HttpFront { app_id: String::from("app_1"), hostname: String::from("lolcatho.st:8080"), path_begin: String::from("/"), address: "0.0.0.0:8080".parse().unwrap() }));
-    state.handle_order(&ProxyRequestData::AddHttpFront(HttpFront { app_id: String::from("app_2"), hostname: String::from("test.local"), path_begin: String::from("/abc"), address: "0.0.0.0:8080".parse().unwrap() }));
-    state.handle_order(&ProxyRequestData::AddBackend(Backend { app_id: String::from("app_1"), backend_id: String::from("app_1-0"), address: "127.0.0.1:1026".parse().unwrap(), load_balancing_parameters: Some(LoadBalancingParams::default()), sticky_id: None, backup: None  }));
-    state.handle_order(&ProxyRequestData::AddBackend(Backend { app_id: String::from("app_1"), backend_id: String::from("app_1-1"), address: "127.0.0.2:1027".parse().unwrap(), load_balancing_parameters: Some(LoadBalancingParams::default()), sticky_id: None, backup: None  }));
-    state.handle_order(&ProxyRequestData::AddBackend(Backend { app_id: String::from("app_2"), backend_id: String::from("app_2-0"), address: "192.167.1.2:1026".parse().unwrap(), load_balancing_parameters: Some(LoadBalancingParams::default()), sticky_id: None, backup: None  }));
-    state.handle_order(&ProxyRequestData::AddApplication(Application { app_id: String::from("app_2"), sticky_session: true, https_redirect: true, proxy_protocol: None, load_balancing_policy: LoadBalancingAlgorithms::RoundRobin, answer_503: None }));
+    state.handle_order(&ProxyRequestData::AddHttpFront(HttpFront { app_id: String::from("app_1"), hostname: String::from("lolcatho.st:8080"), path_begin: String::from("/"), address: "0.0.0.0:8080".parse().unwrap(), trailing_slash: TrailingSlash::Strict, method: None, header_match: None, split: None }));
+    state.handle_order(&ProxyRequestData::AddHttpFront(HttpFront { app_id: String::from("app_2"), hostname: String::from("test.local"), path_begin: String::from("/abc"), address: "0.0.0.0:8080".parse().unwrap(), trailing_slash: TrailingSlash::Strict, method: None, header_match: None, split: None }));
+    state.handle_order(&ProxyRequestData::AddBackend(Backend { app_id: String::from("app_1"), backend_id: String::from("app_1-0"), address: "127.0.0.1:1026".parse().unwrap(), load_balancing_parameters: Some(LoadBalancingParams::default()), sticky_id: None, backup: None, hostname: None  }));
+    state.handle_order(&ProxyRequestData::AddBackend(Backend { app_id: String::from("app_1"), backend_id: String::from("app_1-1"), address: "127.0.0.2:1027".parse().unwrap(), load_balancing_parameters: Some(LoadBalancingParams::default()), sticky_id: None, backup: None, hostname: None  }));
+    state.handle_order(&ProxyRequestData::AddBackend(Backend { app_id: String::from("app_2"), backend_id: String::from("app_2-0"), address: "192.167.1.2:1026".parse().unwrap(), load_balancing_parameters: Some(LoadBalancingParams::default()), sticky_id: None, backup: None, hostname: None  }));
+    state.handle_order(&ProxyRequestData::AddApplication(Application { app_id: String::from("app_2"), sticky_session: true, https_redirect: true, ..Default::default() }));
 
     let mut state2:ConfigState = Default::default();
-    state2.handle_order(&ProxyRequestData::AddHttpFront(HttpFront { app_id: String::from("app_1"), hostname: String::from("lolcatho.st:8080"), path_begin: String::from("/"), address: "0.0.0.0:8080".parse().unwrap() }));
-    state2.handle_order(&ProxyRequestData::AddBackend(Backend { app_id: String::from("app_1"), backend_id: String::from("app_1-0"), address: "127.0.0.1:1026".parse().unwrap(), load_balancing_parameters: Some(LoadBalancingParams::default()), sticky_id: None, backup: None  }));
-    state2.handle_order(&ProxyRequestData::AddBackend(Backend { app_id: String::from("app_1"), backend_id: String::from("app_1-1"), address: "127.0.0.2:1027".parse().unwrap(), load_balancing_parameters: Some(LoadBalancingParams::default()), sticky_id: None, backup: None  }));
-    state2.handle_order(&ProxyRequestData::AddBackend(Backend { app_id: String::from("app_1"), backend_id: String::from("app_1-2"), address: "127.0.0.2:1028".parse().unwrap(), load_balancing_parameters: Some(LoadBalancingParams::default()), sticky_id: None, backup: None  }));
-    state2.handle_order(&ProxyRequestData::AddApplication(Application { app_id: String::from("app_3"), sticky_session: false, https_redirect: false, proxy_protocol: None, load_balancing_policy: LoadBalancingAlgorithms::RoundRobin, answer_503: None }));
+    state2.handle_order(&ProxyRequestData::AddHttpFront(HttpFront { app_id: String::from("app_1"), hostname: String::from("lolcatho.st:8080"), path_begin: String::from("/"), address: "0.0.0.0:8080".parse().unwrap(), trailing_slash: TrailingSlash::Strict, method: None, header_match: None, split: None }));
+    state2.handle_order(&ProxyRequestData::AddBackend(Backend { app_id: String::from("app_1"), backend_id: String::from("app_1-0"), address: "127.0.0.1:1026".parse().unwrap(), load_balancing_parameters: Some(LoadBalancingParams::default()), sticky_id: None, backup: None, hostname: None  }));
+    state2.handle_order(&ProxyRequestData::AddBackend(Backend { app_id: String::from("app_1"), backend_id: String::from("app_1-1"), address: "127.0.0.2:1027".parse().unwrap(), load_balancing_parameters: Some(LoadBalancingParams::default()), sticky_id: None, backup: None, hostname: None  }));
+    state2.handle_order(&ProxyRequestData::AddBackend(Backend { app_id: String::from("app_1"), backend_id: String::from("app_1-2"), address: "127.0.0.2:1028".parse().unwrap(), load_balancing_parameters: Some(LoadBalancingParams::default()), sticky_id: None, backup: None, hostname: None  }));
+    state2.handle_order(&ProxyRequestData::AddApplication(Application { app_id: String::from("app_3"), ..Default::default() }));
 
    let e = vec!(
-     ProxyRequestData::RemoveHttpFront(HttpFront { app_id: String::from("app_2"), hostname: String::from("test.local"), path_begin: String::from("/abc"), address: "0.0.0.0:8080".parse().unwrap() }),
+     ProxyRequestData::RemoveHttpFront(HttpFront { app_id: String::from("app_2"), hostname: String::from("test.local"), path_begin: String::from("/abc"), address: "0.0.0.0:8080".parse().unwrap(), trailing_slash: TrailingSlash::Strict, method: None, header_match: None, split: None }),
      ProxyRequestData::RemoveBackend(RemoveBackend { app_id: String::from("app_2"), backend_id: String::from("app_2-0"), address: "192.167.1.2:1026".parse().unwrap() }),
-     ProxyRequestData::AddBackend(Backend { app_id: String::from("app_1"), backend_id: String::from("app_1-2"), address: "127.0.0.2:1028".parse().unwrap(), load_balancing_parameters: Some(LoadBalancingParams::default()), sticky_id: None, backup: None }),
+     ProxyRequestData::AddBackend(Backend { app_id: String::from("app_1"), backend_id: String::from("app_1-2"), address: "127.0.0.2:1028".parse().unwrap(), load_balancing_parameters: Some(LoadBalancingParams::default()), sticky_id: None, backup: None, hostname: None }),
      ProxyRequestData::RemoveApplication(String::from("app_2")),
-     ProxyRequestData::AddApplication(Application { app_id: String::from("app_3"), sticky_session: false, https_redirect: false, proxy_protocol: None, load_balancing_policy: LoadBalancingAlgorithms::RoundRobin, answer_503: None }),
+     ProxyRequestData::AddApplication(Application { app_id: String::from("app_3"), ..Default::default() }),
    );
    let expected_diff:HashSet<&ProxyRequestData> = HashSet::from_iter(e.iter());
 
@@ -849,7 +1136,7 @@ mod tests {
    let hash1 = state.hash_state();
    let hash2 = state2.hash_state();
    let mut state3 = state.clone();
-   state3.handle_order(&ProxyRequestData::AddBackend(Backend { app_id: String::from("app_1"), backend_id: String::from("app_1-2"), address: "127.0.0.2:1028".parse().unwrap(), load_balancing_parameters: Some(LoadBalancingParams::default()), sticky_id: None, backup: None }));
+   state3.handle_order(&ProxyRequestData::AddBackend(Backend { app_id: String::from("app_1"), backend_id: String::from("app_1-2"), address: "127.0.0.2:1028".parse().unwrap(), load_balancing_parameters: Some(LoadBalancingParams::default()), sticky_id: None, backup: None, hostname: None }));
    let hash3 = state3.hash_state();
    println!("state 1 hashes: {:#?}", hash1);
    println!("state 2 hashes: {:#?}", hash2);
@@ -865,28 +1152,44 @@ mod tests {
       app_id: String::from("MyApp_1"),
       hostname: String::from("lolcatho.st"),
       path_begin: String::from(""),
+      trailing_slash: TrailingSlash::Strict,
       address: "0.0.0.0:8080".parse().unwrap(),
+      method: None,
+      header_match: None,
+      split: None,
     };
 
     let https_front_app1 = HttpFront {
       app_id: String::from("MyApp_1"),
       hostname: String::from("lolcatho.st"),
       path_begin: String::from(""),
+      trailing_slash: TrailingSlash::Strict,
       address: "0.0.0.0:8443".parse().unwrap(),
+      method: None,
+      header_match: None,
+      split: None,
     };
 
     let http_front_app2 = HttpFront {
       app_id: String::from("MyApp_2"),
       hostname: String::from("lolcatho.st"),
       path_begin: String::from("/api"),
+      trailing_slash: TrailingSlash::Strict,
       address: "0.0.0.0:8080".parse().unwrap(),
+      method: None,
+      header_match: None,
+      split: None,
     };
 
     let https_front_app2 = HttpFront {
       app_id: String::from("MyApp_2"),
       hostname: String::from("lolcatho.st"),
       path_begin: String::from("/api"),
+      trailing_slash: TrailingSlash::Strict,
       address: "0.0.0.0:8443".parse().unwrap(),
+      method: None,
+      header_match: None,
+      split: None,
     };
 
     let add_http_front_order_app1 = ProxyRequestData::AddHttpFront(http_front_app1);
@@ -921,7 +1224,8 @@ mod tests {
       address: "127.0.0.1:1026".parse().unwrap(),
       load_balancing_parameters: Some(LoadBalancingParams::default()),
       sticky_id: None,
-      backup: None
+      backup: None,
+      hostname: None
     }));
 
     let b = Backend {
@@ -930,7 +1234,8 @@ mod tests {
       address: "127.0.0.1:1026".parse().unwrap(),
       load_balancing_parameters: Some(LoadBalancingParams::default()),
       sticky_id: Some("sticky".to_string()),
-      backup: None
+      backup: None,
+      hostname: None
     };
 
     state.handle_order(&ProxyRequestData::AddBackend(b.clone()));
@@ -938,6 +1243,240 @@ mod tests {
     assert_eq!(state.backends.get("app_1").unwrap(), &vec![b]);
   }
 
+  #[test]
+  fn replace_backend() {
+    let mut state:ConfigState = Default::default();
+    let mut b = Backend {
+      app_id: String::from("app_1"),
+      backend_id: String::from("app_1-0"),
+      address: "127.0.0.1:1026".parse().unwrap(),
+      load_balancing_parameters: Some(LoadBalancingParams::default()),
+      sticky_id: Some("sticky".to_string()),
+      backup: None,
+      hostname: None
+    };
+    state.handle_order(&ProxyRequestData::AddBackend(b.clone()));
+    state.handle_order(&ProxyRequestData::AddBackend(Backend {
+      app_id: String::from("app_1"),
+      backend_id: String::from("app_1-1"),
+      address: "127.0.0.1:1027".parse().unwrap(),
+      load_balancing_parameters: Some(LoadBalancingParams::default()),
+      sticky_id: None,
+      backup: None,
+      hostname: None
+    }));
+
+    assert!(state.handle_order(&ProxyRequestData::ReplaceBackend(ReplaceBackend {
+      app_id: String::from("app_1"),
+      backend_id: String::from("app_1-0"),
+      address: "127.0.0.2:1026".parse().unwrap(),
+    })));
+    b.address = "127.0.0.2:1026".parse().unwrap();
+    assert_eq!(&state.backends.get("app_1").unwrap()[0], &b);
+
+    // the new address is already used by another backend
+    assert!(!state.handle_order(&ProxyRequestData::ReplaceBackend(ReplaceBackend {
+      app_id: String::from("app_1"),
+      backend_id: String::from("app_1-0"),
+      address: "127.0.0.1:1027".parse().unwrap(),
+    })));
+    // unknown backend
+    assert!(!state.handle_order(&ProxyRequestData::ReplaceBackend(ReplaceBackend {
+      app_id: String::from("app_1"),
+      backend_id: String::from("app_1-2"),
+      address: "127.0.0.3:1026".parse().unwrap(),
+    })));
+    assert_eq!(&state.backends.get("app_1").unwrap()[0], &b);
+  }
+
+  #[test]
+  fn drain_backend() {
+    let mut state:ConfigState = Default::default();
+    let b = Backend {
+      app_id: String::from("app_1"),
+      backend_id: String::from("app_1-0"),
+      address: "127.0.0.1:1026".parse().unwrap(),
+      load_balancing_parameters: Some(LoadBalancingParams::default()),
+      sticky_id: None,
+      backup: None,
+      hostname: None
+    };
+    state.handle_order(&ProxyRequestData::AddBackend(b.clone()));
+
+    assert!(state.handle_order(&ProxyRequestData::DrainBackend(DrainBackend {
+      app_id: String::from("app_1"),
+      backend_id: String::from("app_1-0"),
+    })));
+    // the backend stays in the configuration
+    assert_eq!(state.backends.get("app_1").unwrap(), &vec![b]);
+
+    assert!(!state.handle_order(&ProxyRequestData::DrainBackend(DrainBackend {
+      app_id: String::from("app_1"),
+      backend_id: String::from("app_1-1"),
+    })));
+    assert!(!state.handle_order(&ProxyRequestData::DrainBackend(DrainBackend {
+      app_id: String::from("app_2"),
+      backend_id: String::from("app_1-0"),
+    })));
+
+    // only the applications with backends can be rebalanced
+    assert!(state.handle_order(&ProxyRequestData::RebalanceApplication(Rebalance { app_id: String::from("app_1"), duration: 60 })));
+    assert!(!state.handle_order(&ProxyRequestData::RebalanceApplication(Rebalance { app_id: String::from("app_2"), duration: 60 })));
+  }
+
+  #[test]
+  fn traffic_split() {
+    let mut state:ConfigState = Default::default();
+    let split = Some(TrafficSplit { canary_app_id: String::from("app_canary"), percentage: 10, cookie: None });
+    let front = HttpFront { app_id: String::from("app_1"), hostname: String::from("lolcatho.st"), path_begin: String::from("/"), address: "0.0.0.0:8080".parse().unwrap(), trailing_slash: TrailingSlash::Strict, method: None, header_match: None, split };
+    state.handle_order(&ProxyRequestData::AddHttpFront(front.clone()));
+    state.handle_order(&ProxyRequestData::AddHttpsFront(HttpFront { address: "0.0.0.0:8443".parse().unwrap(), ..front.clone() }));
+    state.handle_order(&ProxyRequestData::AddHttpFront(HttpFront { hostname: String::from("other.domain"), ..front.clone() }));
+
+    assert!(state.handle_order(&ProxyRequestData::SetTrafficSplit(TrafficSplitPercentage {
+      app_id: String::from("app_1"),
+      hostname: String::from("lolcatho.st"),
+      percentage: 50,
+    })));
+    let percentages = |fronts: &HashMap<AppId, Vec<HttpFront>>| fronts["app_1"].iter()
+      .map(|front| front.split.as_ref().unwrap().percentage).collect::<Vec<_>>();
+    assert_eq!(percentages(&state.http_fronts), vec![50, 10]);
+    assert_eq!(percentages(&state.https_fronts), vec![50]);
+
+    // the frontends of another application, or without a split, are not changed
+    assert!(!state.handle_order(&ProxyRequestData::SetTrafficSplit(TrafficSplitPercentage {
+      app_id: String::from("app_canary"),
+      hostname: String::from("lolcatho.st"),
+      percentage: 50,
+    })));
+    state.handle_order(&ProxyRequestData::AddHttpFront(HttpFront { app_id: String::from("app_2"), path_begin: String::from("/api"), split: None, ..front.clone() }));
+    assert!(!state.handle_order(&ProxyRequestData::SetTrafficSplit(TrafficSplitPercentage {
+      app_id: String::from("app_2"),
+      hostname: String::from("lolcatho.st"),
+      percentage: 50,
+    })));
+  }
+
+  #[test]
+  fn default_app() {
+    let mut state:ConfigState = Default::default();
+    let front: SocketAddr = "0.0.0.0:8080".parse().unwrap();
+    state.handle_order(&ProxyRequestData::AddHttpListener(HttpListener { front, ..Default::default() }));
+
+    let set_default = |app_id: Option<&str>, proxy: ListenerType| ProxyRequestData::SetDefaultApp(DefaultApp {
+      front, proxy, app_id: app_id.map(String::from)
+    });
+    let mut state2 = state.clone();
+    assert!(state2.handle_order(&set_default(Some("landing"), ListenerType::HTTP)));
+    assert_eq!(state2.http_listeners[&front].0.default_app, Some(String::from("landing")));
+
+    // the default application is changed without recreating the listener
+    assert_eq!(state.diff(&state2), vec![set_default(Some("landing"), ListenerType::HTTP)]);
+    assert_eq!(state2.diff(&state), vec![set_default(None, ListenerType::HTTP)]);
+
+    // there is no HTTPS listener at this address
+    assert!(!state2.handle_order(&set_default(Some("landing"), ListenerType::HTTPS)));
+    assert!(!state2.handle_order(&set_default(Some("landing"), ListenerType::TCP)));
+  }
+
+  #[test]
+  fn conflicting_fronts() {
+    let mut state:ConfigState = Default::default();
+    let front = HttpFront { app_id: String::from("app_1"), hostname: String::from("lolcatho.st"), path_begin: String::from("/"), address: "0.0.0.0:8080".parse().unwrap(), trailing_slash: TrailingSlash::Strict, method: None, header_match: None, split: None };
+    assert!(state.handle_order(&ProxyRequestData::AddHttpFront(front.clone())));
+
+    let other = HttpFront { app_id: String::from("app_2"), ..front.clone() };
+    assert!(!state.handle_order(&ProxyRequestData::AddHttpFront(other.clone())));
+    assert_eq!(state.conflicting_front(&other, false), Some(&front));
+    assert_eq!(state.http_fronts.get("app_2"), None);
+
+    // another path, method or address is not a conflict, nor the HTTPS frontend
+    assert!(state.handle_order(&ProxyRequestData::AddHttpFront(HttpFront { path_begin: String::from("/api"), ..other.clone() })));
+    assert!(state.handle_order(&ProxyRequestData::AddHttpFront(HttpFront { method: Some(String::from("POST")), ..other.clone() })));
+    assert!(state.handle_order(&ProxyRequestData::AddHttpFront(HttpFront { address: "0.0.0.0:8081".parse().unwrap(), ..other.clone() })));
+    let https_front = HttpFront { address: "0.0.0.0:8443".parse().unwrap(), ..other.clone() };
+    assert!(state.handle_order(&ProxyRequestData::AddHttpsFront(https_front.clone())));
+    assert!(!state.handle_order(&ProxyRequestData::AddHttpsFront(HttpFront { app_id: String::from("app_1"), ..https_front.clone() })));
+
+    // the frontend can be moved once removed
+    assert!(state.handle_order(&ProxyRequestData::RemoveHttpFront(front.clone())));
+    assert!(state.handle_order(&ProxyRequestData::AddHttpFront(other.clone())));
+  }
+
+  #[test]
+  fn disable_application() {
+    let mut state:ConfigState = Default::default();
+    let application = Application { app_id: String::from("app_1"), ..Default::default() };
+    state.handle_order(&ProxyRequestData::AddApplication(application.clone()));
+
+    assert!(state.handle_order(&ProxyRequestData::DisableApplication(String::from("app_1"))));
+    // the application stays in the configuration, and a new worker gets it disabled
+    let disabled = Application { enabled: false, ..application.clone() };
+    assert_eq!(state.applications.get("app_1"), Some(&disabled));
+    assert!(state.generate_orders().contains(&ProxyRequestData::AddApplication(disabled)));
+
+    assert!(state.handle_order(&ProxyRequestData::EnableApplication(String::from("app_1"))));
+    assert_eq!(state.applications.get("app_1"), Some(&application));
+
+    assert!(!state.handle_order(&ProxyRequestData::DisableApplication(String::from("app_2"))));
+    assert!(!state.handle_order(&ProxyRequestData::EnableApplication(String::from("app_2"))));
+  }
+
+  #[test]
+  fn set_app_timeout() {
+    let mut state:ConfigState = Default::default();
+    let application = Application { app_id: String::from("app_1"), ..Default::default() };
+    state.handle_order(&ProxyRequestData::AddApplication(application.clone()));
+
+    let set_timeout = |app_id: &str, timeout: Option<u32>| ProxyRequestData::SetAppTimeout(AppTimeout {
+      app_id: String::from(app_id), timeout,
+    });
+    assert!(state.handle_order(&set_timeout("app_1", Some(500))));
+    // a new worker gets the timeout with the application
+    let with_timeout = Application { response_timeout: Some(500), ..application.clone() };
+    assert!(state.generate_orders().contains(&ProxyRequestData::AddApplication(with_timeout)));
+
+    assert!(state.handle_order(&set_timeout("app_1", None)));
+    assert_eq!(state.applications.get("app_1"), Some(&application));
+
+    assert!(!state.handle_order(&set_timeout("app_2", Some(500))));
+  }
+
+  #[test]
+  fn purge_application() {
+    let mut state:ConfigState = Default::default();
+    let application = Application { app_id: String::from("app_1"), ..Default::default() };
+    let front = HttpFront { app_id: String::from("app_1"), hostname: String::from("lolcatho.st"), path_begin: String::from("/"), address: "0.0.0.0:8080".parse().unwrap(), trailing_slash: TrailingSlash::Strict, method: None, header_match: None, split: None };
+    let backend = Backend { app_id: String::from("app_1"), backend_id: String::from("app_1-0"), address: "127.0.0.1:1026".parse().unwrap(), load_balancing_parameters: Some(LoadBalancingParams::default()), sticky_id: None, backup: None, hostname: None };
+
+    for app_id in &["app_1", "app_2"] {
+      let app_id = String::from(*app_id);
+      state.handle_order(&ProxyRequestData::AddApplication(Application { app_id: app_id.clone(), ..application.clone() }));
+      state.handle_order(&ProxyRequestData::AddHttpFront(HttpFront { app_id: app_id.clone(), hostname: format!("{}.lolcatho.st", app_id), ..front.clone() }));
+      state.handle_order(&ProxyRequestData::AddHttpsFront(HttpFront { app_id: app_id.clone(), hostname: format!("{}.lolcatho.st", app_id), address: "0.0.0.0:8443".parse().unwrap(), ..front.clone() }));
+      state.handle_order(&ProxyRequestData::AddBackend(Backend { app_id: app_id.clone(), backend_id: format!("{}-0", app_id), ..backend.clone() }));
+      state.handle_order(&ProxyRequestData::AddBackend(Backend { app_id: app_id.clone(), backend_id: format!("{}-1", app_id), address: "127.0.0.1:1027".parse().unwrap(), ..backend.clone() }));
+    }
+    state.handle_order(&ProxyRequestData::AddTcpFront(TcpFront { app_id: String::from("app_1"), address: "0.0.0.0:8081".parse().unwrap() }));
+    let app_2 = state.application_state("app_2");
+
+    assert_eq!(state.application_objects("app_1"), 5);
+    assert!(state.handle_order(&ProxyRequestData::PurgeApplication(String::from("app_1"))));
+    assert_eq!(state.application_objects("app_1"), 0);
+    assert_eq!(state.applications.get("app_1"), None);
+    assert_eq!(state.backends.get("app_1"), None);
+    assert_eq!(state.http_fronts.get("app_1"), None);
+    assert_eq!(state.https_fronts.get("app_1"), None);
+    assert_eq!(state.tcp_fronts.get("app_1"), None);
+
+    // the other applications are left untouched
+    assert_eq!(state.application_objects("app_2"), 4);
+    assert_eq!(state.application_state("app_2"), app_2);
+
+    assert!(!state.handle_order(&ProxyRequestData::PurgeApplication(String::from("app_1"))));
+    assert!(!state.handle_order(&ProxyRequestData::PurgeApplication(String::from("app_3"))));
+  }
+
   #[test]
   fn listener_diff() {
     let mut state:ConfigState = Default::default();
@@ -945,6 +1484,10 @@ mod tests {
       front: "0.0.0.0:1234".parse().unwrap(),
       public_address: None,
       expect_proxy: false,
+      interface: None,
+      timeouts: ListenerTimeouts::default(),
+      connection_rate_limit: None,
+      max_sessions: None,
     }));
     state.handle_order(&ProxyRequestData::ActivateListener(ActivateListener {
       front: "0.0.0.0:1234".parse().unwrap(),
@@ -958,6 +1501,21 @@ mod tests {
       answer_404: String::new(),
       answer_503: String::new(),
       sticky_name: String::new(),
+      retry_after: None,
+      interface: None,
+      preserve_proxy_connection: false,
+      ignore_unsupported_expect: false,
+      unexpected_body_policy: UnexpectedBodyPolicy::Reject,
+      error_pages: BTreeMap::new(),
+      timeouts: ListenerTimeouts::default(),
+      connection_rate_limit: None,
+      max_sessions: None,
+      unix_socket: None,
+      client_ip: ClientIpConfig::default(),
+      header_limits: HeaderLimits::default(),
+      max_keepalive_requests: None,
+      compression: None,
+      default_app: None,
     }));
     state.handle_order(&ProxyRequestData::AddHttpsListener(HttpsListener {
       front: "0.0.0.0:8443".parse().unwrap(),
@@ -966,6 +1524,22 @@ mod tests {
       answer_404: String::new(),
       answer_503: String::new(),
       sticky_name: String::new(),
+      retry_after: None,
+      interface: None,
+      preserve_proxy_connection: false,
+      ignore_unsupported_expect: false,
+      unexpected_body_policy: UnexpectedBodyPolicy::Reject,
+      error_pages: BTreeMap::new(),
+      timeouts: ListenerTimeouts::default(),
+      connection_rate_limit: None,
+      max_sessions: None,
+      client_ca: None,
+      client_ip: ClientIpConfig::default(),
+      header_limits: HeaderLimits::default(),
+      max_keepalive_requests: None,
+      compression: None,
+      default_app: None,
+      reject_sni_mismatch: false,
       versions: Vec::new(),
       cipher_list: String::new(),
       rustls_cipher_list: Vec::new(),
@@ -982,6 +1556,10 @@ mod tests {
       front: "0.0.0.0:1234".parse().unwrap(),
       public_address: None,
       expect_proxy: true,
+      interface: None,
+      timeouts: ListenerTimeouts::default(),
+      connection_rate_limit: None,
+      max_sessions: None,
     }));
     state2.handle_order(&ProxyRequestData::AddHttpListener(HttpListener {
       front: "0.0.0.0:8080".parse().unwrap(),
@@ -990,6 +1568,21 @@ mod tests {
       answer_404: "test".to_string(),
       answer_503: String::new(),
       sticky_name: String::new(),
+      retry_after: None,
+      interface: None,
+      preserve_proxy_connection: false,
+      ignore_unsupported_expect: false,
+      unexpected_body_policy: UnexpectedBodyPolicy::Reject,
+      error_pages: BTreeMap::new(),
+      timeouts: ListenerTimeouts::default(),
+      connection_rate_limit: None,
+      max_sessions: None,
+      unix_socket: None,
+      client_ip: ClientIpConfig::default(),
+      header_limits: HeaderLimits::default(),
+      max_keepalive_requests: None,
+      compression: None,
+      default_app: None,
     }));
     state2.handle_order(&ProxyRequestData::ActivateListener(ActivateListener {
       front: "0.0.0.0:8080".parse().unwrap(),
@@ -1003,6 +1596,22 @@ mod tests {
       answer_404: String::from("test"),
       answer_503: String::new(),
       sticky_name: String::new(),
+      retry_after: None,
+      interface: None,
+      preserve_proxy_connection: false,
+      ignore_unsupported_expect: false,
+      unexpected_body_policy: UnexpectedBodyPolicy::Reject,
+      error_pages: BTreeMap::new(),
+      timeouts: ListenerTimeouts::default(),
+      connection_rate_limit: None,
+      max_sessions: None,
+      client_ca: None,
+      client_ip: ClientIpConfig::default(),
+      header_limits: HeaderLimits::default(),
+      max_keepalive_requests: None,
+      compression: None,
+      default_app: None,
+      reject_sni_mismatch: false,
       versions: Vec::new(),
       cipher_list: String::new(),
       rustls_cipher_list: Vec::new(),
@@ -1023,6 +1632,10 @@ mod tests {
         front: "0.0.0.0:1234".parse().unwrap(),
         public_address: None,
         expect_proxy: true,
+        interface: None,
+        timeouts: ListenerTimeouts::default(),
+        connection_rate_limit: None,
+        max_sessions: None,
       }),
       ProxyRequestData::DeactivateListener(DeactivateListener {
         front: "0.0.0.0:1234".parse().unwrap(),
@@ -1040,6 +1653,21 @@ mod tests {
         answer_404: String::from("test"),
         answer_503: String::new(),
         sticky_name: String::new(),
+        retry_after: None,
+        interface: None,
+        preserve_proxy_connection: false,
+        ignore_unsupported_expect: false,
+        unexpected_body_policy: UnexpectedBodyPolicy::Reject,
+        error_pages: BTreeMap::new(),
+        timeouts: ListenerTimeouts::default(),
+        connection_rate_limit: None,
+        max_sessions: None,
+        unix_socket: None,
+        client_ip: ClientIpConfig::default(),
+      header_limits: HeaderLimits::default(),
+      max_keepalive_requests: None,
+      compression: None,
+      default_app: None,
       }),
       ProxyRequestData::ActivateListener(ActivateListener {
         front: "0.0.0.0:8080".parse().unwrap(),
@@ -1057,6 +1685,22 @@ mod tests {
         answer_404: String::from("test"),
         answer_503: String::new(),
         sticky_name: String::new(),
+        retry_after: None,
+        interface: None,
+        preserve_proxy_connection: false,
+        ignore_unsupported_expect: false,
+        unexpected_body_policy: UnexpectedBodyPolicy::Reject,
+        error_pages: BTreeMap::new(),
+        timeouts: ListenerTimeouts::default(),
+        connection_rate_limit: None,
+        max_sessions: None,
+        client_ca: None,
+        client_ip: ClientIpConfig::default(),
+      header_limits: HeaderLimits::default(),
+      max_keepalive_requests: None,
+      compression: None,
+      default_app: None,
+      reject_sni_mismatch: false,
         versions: Vec::new(),
         cipher_list: String::new(),
         rustls_cipher_list: Vec::new(),
@@ -1075,4 +1719,59 @@ mod tests {
     assert_eq!(diff, e);
   }
 
+  #[test]
+  fn certificates_sorted_by_expiration() {
+    let mut state:ConfigState = Default::default();
+    let front = "0.0.0.0:8443".parse().unwrap();
+    for (certificate, key) in [(include_str!("../assets/aia-leaf.pem"), include_str!("../assets/aia-leaf-key.pem")),
+      (include_str!("../assets/certificate.pem"), include_str!("../assets/key.pem"))].iter() {
+      state.handle_order(&ProxyRequestData::AddCertificate(AddCertificate {
+        front,
+        certificate: CertificateAndKey {
+          certificate: String::from(*certificate),
+          certificate_chain: vec![],
+          key: String::from(*key),
+        },
+        names: vec![],
+      }));
+    }
+
+    let summaries = list_certificates(&state);
+    assert_eq!(summaries.iter().map(|s| s.not_after).collect::<Vec<_>>(), vec![1450451258, 4945565856]);
+    assert_eq!(summaries[0].listener, front);
+    assert_eq!(summaries[0].fingerprint, calculate_fingerprint(include_bytes!("../assets/certificate.pem")).unwrap());
+    assert_eq!(summaries[1].domains, vec![String::from("lolcatho.st")]);
+  }
+
+  #[test]
+  fn canonical_orders() {
+    let application = Application { app_id: String::from("app_0"), ..Default::default() };
+    let mut orders = Vec::new();
+    for i in 0..10 {
+      let app_id = format!("app_{}", i);
+      orders.push(ProxyRequestData::AddApplication(Application { app_id: app_id.clone(), ..application.clone() }));
+      orders.push(ProxyRequestData::AddHttpFront(HttpFront { app_id: app_id.clone(), hostname: format!("host{}.local", i), path_begin: String::from("/"), address: "0.0.0.0:8080".parse().unwrap(), trailing_slash: TrailingSlash::Strict, method: None, header_match: None, split: None }));
+      for j in 0..3 {
+        orders.push(ProxyRequestData::AddBackend(Backend { app_id: app_id.clone(), backend_id: format!("{}-{}", app_id, j), address: format!("127.0.0.{}:{}", i + 1, 1026 + j).parse().unwrap(), load_balancing_parameters: Some(LoadBalancingParams::default()), sticky_id: None, backup: None, hostname: None }));
+      }
+    }
+
+    let mut state:ConfigState = Default::default();
+    for order in orders.iter() {
+      state.handle_order(order);
+    }
+    // the same configuration, built in another order
+    let mut state2:ConfigState = Default::default();
+    for order in orders.iter().rev() {
+      state2.handle_order(order);
+    }
+
+    let canonical = state.generate_canonical_orders();
+    assert_eq!(canonical, state2.generate_canonical_orders());
+    assert_eq!(canonical.len(), orders.len());
+    // the applications are declared before their frontends and backends
+    assert!(canonical[..10].iter().all(|order| if let ProxyRequestData::AddApplication(_) = order { true } else { false }));
+    assert_eq!(to_canonical_json(&canonical), to_canonical_json(&state2.generate_canonical_orders()));
+  }
+
 }
@@ -1,5 +1,10 @@
-use sozu_command::config::LoadBalancingAlgorithms;
-use std::net::SocketAddr;
+use sozu_command::config::{LoadBalancingAlgorithms, ProxyProtocolVersion};
+use sozu_command::logging::LogFormat;
+use sozu_command::proxy::{TrailingSlash,HeaderMatch};
+use std::io::Write;
+use std::net::{IpAddr,SocketAddr};
+use structopt::StructOpt;
+use structopt::clap::Shell;
 
 #[derive(StructOpt, PartialEq, Debug)]
 pub struct App {
@@ -7,6 +12,10 @@ pub struct App {
   pub config: Option<String>,
   #[structopt(short="t", long = "timeout", help = "Sets a custom timeout for commands (in milliseconds). 0 disables the timeout")]
   pub timeout: Option<u64>,
+  #[structopt(long = "dry-run", help = "Prints the orders that would be sent to the proxy, without sending them")]
+  pub dry_run: bool,
+  #[structopt(long = "wait", help = "Sends the orders to each running worker and waits until all of them applied it, or until the timeout")]
+  pub wait: bool,
   #[structopt(subcommand)]
   pub cmd: SubCmd,
 }
@@ -19,27 +28,126 @@ pub enum SubCmd {
     hard: bool,
     #[structopt(short = "w", long = "worker", help = "shuts down the worker with this id")]
     worker: Option<u32>,
+    #[structopt(long = "drain-timeout", conflicts_with = "hard",
+      help = "closes the sessions still active after this number of seconds")]
+    drain_timeout: Option<u32>,
   },
   #[structopt(name = "upgrade", about = "upgrade the proxy")]
   Upgrade {
     #[structopt(short = "w", long = "worker", help = "Upgrade the worker with this id")]
     worker: Option<u32>,
+    #[structopt(long = "phase-timeout", help = "Aborts the upgrade if a phase sends no answer in this time (in milliseconds, 30000 by default for the master, the command timeout for a worker). 0 disables the timeout")]
+    phase_timeout: Option<u64>,
+  },
+  #[structopt(name = "restart", about = "restarts a worker, its replacement takes over its listeners")]
+  Restart {
+    #[structopt(short = "w", long = "worker", help = "Restart the worker with this id")]
+    worker: u32,
+    #[structopt(long = "force", help = "Restart the worker even if it is the last one running")]
+    force: bool,
+    #[structopt(long = "phase-timeout", help = "Aborts the restart if a phase sends no answer in this time (in milliseconds, the command timeout by default). 0 disables the timeout")]
+    phase_timeout: Option<u64>,
   },
   #[structopt(name = "status", about = "gets information on the running workers")]
   Status {
     #[structopt(short = "j", long = "json", help = "Print the command result in JSON format")]
-    json: bool
+    json: bool,
+    #[structopt(long = "csv", conflicts_with = "json", help = "Print the command result as comma separated values")]
+    csv: bool,
   },
   #[structopt(name = "metrics", about = "gets statistics on the master and its workers")]
   Metrics {
     #[structopt(short = "j", long = "json", help = "Print the command result in JSON format")]
-    json: bool
+    json: bool,
+    #[structopt(long = "csv", conflicts_with = "json", help = "Print the command result as comma separated values")]
+    csv: bool,
+    #[structopt(short = "b", long = "buckets", conflicts_with_all = &["json", "csv"], help = "display the latency buckets of the time metrics")]
+    buckets: bool,
+    #[structopt(short = "w", long = "watch", help = "refresh the metrics every <interval> seconds")]
+    watch: Option<u64>,
+    #[structopt(short = "i", long = "id", help = "only display the metrics of this application")]
+    id: Option<String>
+  },
+  #[structopt(name = "reset-metrics", about = "zero the counters and percentiles of the master and workers, the gauges are kept")]
+  ResetMetrics,
+  #[structopt(name = "throughput", about = "requests, bytes and connections per second of all the workers")]
+  Throughput {
+    #[structopt(short = "j", long = "json", help = "Print the command result in JSON format")]
+    json: bool,
+    #[structopt(short = "i", long = "interval", default_value = "1", help = "measure the rates over <interval> seconds")]
+    interval: u64,
   },
   #[structopt(name = "logging", about = "change logging level")]
   Logging {
     #[structopt(short = "l", long = "level", help = "change logging level")]
     level: String
   },
+  #[structopt(name = "log-format", about = "switch the format of the logs")]
+  LogFormat {
+    #[structopt(possible_values = &["text", "json"], help = "text, or one JSON object per line")]
+    format: LogFormat,
+  },
+  #[structopt(name = "trace", about = "log verbosely the requests matching a filter, for a limited time")]
+  Trace {
+    #[structopt(long = "hostname", help = "only trace the requests for this hostname")]
+    hostname: Option<String>,
+    #[structopt(long = "path", help = "only trace the requests whose path begins with this prefix")]
+    path: Option<String>,
+    #[structopt(long = "ip", help = "only trace the requests from this client IP address")]
+    ip: Option<IpAddr>,
+    #[structopt(short = "d", long = "duration", help = "tracing duration (in seconds)", default_value = "60")]
+    duration: u64,
+  },
+  #[structopt(name = "trace-app", about = "log verbosely the reads, writes, parsing states and backend selections of the requests of an application")]
+  TraceApp {
+    #[structopt(short = "i", long = "id", help = "app id of the application")]
+    id: String,
+    #[structopt(long = "off", help = "stop tracing the application")]
+    off: bool,
+  },
+  #[structopt(name = "retry-policy", about = "change the retry policy of the backends added from now on")]
+  RetryPolicy {
+    #[structopt(short = "m", long = "max-tries", help = "failed connection attempts before a backend is considered down")]
+    max_tries: usize,
+    #[structopt(long = "max-interval", help = "longest wait between two connection attempts to a failing backend, in seconds")]
+    max_interval: Option<u64>,
+  },
+  #[structopt(name = "traffic-split", about = "change the percentage of the requests sent to the canary application by the frontends of an application")]
+  TrafficSplit {
+    #[structopt(short = "i", long = "id", help = "app id of the frontends")]
+    id: String,
+    #[structopt(long = "hostname", help = "hostname of the frontends")]
+    hostname: String,
+    #[structopt(short = "p", long = "percentage", help = "percentage of the clients sent to the canary application")]
+    percentage: u8,
+  },
+  #[structopt(name = "default-app", about = "change the application receiving the requests whose host matches no frontend of a listener")]
+  DefaultApp {
+    #[structopt(short = "a", long = "address", help = "listener address, format: IP:port")]
+    address: SocketAddr,
+    #[structopt(long = "https", help = "the listener is an HTTPS listener")]
+    https: bool,
+    #[structopt(short = "i", long = "id", help = "app id of the default application, the configured one is removed if not set")]
+    id: Option<String>,
+  },
+  #[structopt(name = "slo", about = "checks the metrics against SLO thresholds, exits with an error if one of them is breached")]
+  Slo {
+    #[structopt(short = "l", long = "latency", help = "maximum latency of a percentile, in milliseconds (example: \"p99<200\"). Can be repeated")]
+    latency: Vec<String>,
+    #[structopt(long = "metric", default_value = "request_time", help = "application time metric checked by the latency thresholds")]
+    metric: String,
+    #[structopt(short = "e", long = "error-rate", help = "maximum percentage of responses with a 5xx status, since the workers started")]
+    error_rate: Option<f64>,
+    #[structopt(short = "i", long = "id", help = "only check the latency of this application")]
+    id: Option<String>,
+  },
+  #[structopt(name = "apply", about = "sends the orders from a JSON or TOML file")]
+  Apply {
+    #[structopt(short = "f", long = "file", help = "JSON array of orders, or TOML file with an `orders` array")]
+    file: String,
+    #[structopt(long = "continue-on-error", help = "send the remaining orders even if one of them failed")]
+    continue_on_error: bool,
+  },
   #[structopt(name = "state", about = "state management")]
   State {
     #[structopt(subcommand)]
@@ -77,8 +185,24 @@ pub enum SubCmd {
     #[structopt(subcommand)]
     cmd: ConfigCmd
   },
-  #[structopt(name = "events", about = "receive sozu events")]
-  Events
+  #[structopt(name = "ping", about = "checks that the master process answers, exits with an error otherwise")]
+  Ping,
+  #[structopt(name = "events", about = "receive sozu events: backend and worker lifecycle changes")]
+  Events,
+  #[structopt(name = "logs", about = "prints the access logs of the workers until interrupted")]
+  Logs {
+    #[structopt(short = "j", long = "json", help = "Print the access logs in JSON format")]
+    json: bool,
+    #[structopt(long = "app", help = "only print the access logs of this application")]
+    app_id: Option<String>,
+    #[structopt(long = "status", help = "only print the access logs with this response status")]
+    status: Option<u16>,
+  },
+  #[structopt(name = "completions", about = "generates the completion script for a shell")]
+  Completions {
+    #[structopt(possible_values = &Shell::variants(), case_insensitive = true, help = "shell for which the script is generated")]
+    shell: String,
+  },
 }
 
 #[derive(StructOpt, PartialEq, Debug)]
@@ -87,17 +211,31 @@ pub enum StateCmd {
   Save {
     #[structopt(short = "f", long = "file")]
     file: String,
+    #[structopt(long = "canonical", help = "sorts the orders and their keys, so the same configuration always gives the same file")]
+    canonical: bool,
   },
   #[structopt(name = "load", about = "Load state from that file")]
   Load {
     #[structopt(short = "f", long = "file")]
     file: String,
   },
+  #[structopt(name = "reload-apps", about = "Apply the applications of that configuration file, only sending the orders for what changed")]
+  ReloadApps {
+    #[structopt(short = "f", long = "file", help = "configuration file with the [applications] sections")]
+    file: String,
+  },
   #[structopt(name = "dump", about = "Dump current state to STDOUT")]
   Dump {
     #[structopt(short = "j", long = "json", help = "Print the command result in JSON format")]
     json: bool
   },
+  #[structopt(name = "routes", about = "Display the applications with their frontends and backends as a tree")]
+  Routes,
+  #[structopt(name = "commands", about = "Print the sozuctl commands recreating the applications, frontends, backends and certificates")]
+  Commands {
+    #[structopt(long = "certificates-directory", help = "directory where the certificate files loaded by the commands are written, the certificates are only listed without it")]
+    certificates_directory: Option<String>,
+  },
 }
 
 #[derive(StructOpt, PartialEq, Debug)]
@@ -106,6 +244,8 @@ pub enum ApplicationCmd {
   Remove {
     #[structopt(short = "i", long = "id")]
     id: String,
+    #[structopt(long = "purge", help = "also remove the frontends and backends of the application")]
+    purge: bool,
   },
   #[structopt(name = "add", about = "Add an application")]
   Add{
@@ -115,12 +255,50 @@ pub enum ApplicationCmd {
     sticky_session: bool,
     #[structopt(short = "h", long = "https-redirect")]
     https_redirect: bool,
-    #[structopt(long = "send-proxy", help = "Enforces use of the PROXY protocol version 2 over any connection established to this server.")]
+    #[structopt(long = "send-proxy", help = "Enforces use of the PROXY protocol over any connection established to this server.")]
     send_proxy: bool,
+    #[structopt(long = "proxy-protocol-version", help = "Version of the PROXY protocol header sent with --send-proxy, 1 or 2 (default)")]
+    proxy_protocol_version: Option<ProxyProtocolVersion>,
     #[structopt(long = "expect-proxy", help = "Configures the client-facing connection to receive a PROXY protocol header version 2")]
     expect_proxy: bool,
-    #[structopt(long = "load-balancing-policy", help = "Configures the load balancing policy. Possible values are 'roundrobin', 'random' or 'leastconnections'")]
+    #[structopt(long = "load-balancing-policy", help = "Configures the load balancing policy. Possible values are 'roundrobin', 'random', 'leastconnections' or 'leastlatency'")]
     load_balancing_policy: LoadBalancingAlgorithms,
+    #[structopt(long = "forward-tls-info", help = "Adds the frontend TLS version and cipher to the requests, in the X-Forwarded-TLS-Version and X-Forwarded-TLS-Cipher headers")]
+    forward_tls_info: bool,
+    #[structopt(long = "no-forwarded-headers", help = "Does not add the Forwarded and X-Forwarded-* headers to the requests")]
+    no_forwarded_headers: bool,
+    #[structopt(long = "fallback-backend", help = "backend receiving the traffic when no other backend is available, format: IP:port")]
+    fallback_backend: Option<SocketAddr>,
+    #[structopt(long = "retry-unanswered-requests", help = "Sends a request again on a new backend connection when the backend closes it without answering, if the request is idempotent or did not reach the backend")]
+    retry_unanswered_requests: bool,
+    #[structopt(long = "max-concurrent-requests", help = "Maximum number of requests sent to the backends at once, the next ones wait in the queue")]
+    max_concurrent_requests: Option<u32>,
+    #[structopt(long = "request-queue-size", requires = "max-concurrent-requests", help = "Number of requests waiting for a slot when the concurrent requests limit is reached, the next ones get a 503 answer (0 by default)")]
+    request_queue_size: Option<u32>,
+  },
+  #[structopt(name = "enable", about = "Enable an application, its requests reach its backends again")]
+  Enable {
+    #[structopt(short = "i", long = "id")]
+    id: String,
+  },
+  #[structopt(name = "disable", about = "Disable an application without removing it, its requests get the maintenance answer")]
+  Disable {
+    #[structopt(short = "i", long = "id")]
+    id: String,
+  },
+  #[structopt(name = "timeout", about = "Set the time given to the backends of an application to answer its next requests, they get a 504 answer after it")]
+  Timeout {
+    #[structopt(short = "i", long = "id")]
+    id: String,
+    #[structopt(long = "timeout", help = "timeout in milliseconds, from the start of the request. Without it, only the front timeout applies")]
+    timeout: Option<u32>,
+  },
+  #[structopt(name = "rebalance", about = "Send the new connections of an application to its least loaded backends, after adding a backend. The current connections are not moved")]
+  Rebalance {
+    #[structopt(short = "i", long = "id")]
+    id: String,
+    #[structopt(short = "d", long = "duration", default_value = "60", help = "the rebalancing stops after this number of seconds, or when the backends have as many connections")]
+    duration: u32,
   },
 }
 
@@ -141,12 +319,37 @@ pub enum BackendCmd {
     id: String,
     #[structopt(long = "backend-id")]
     backend_id: String,
-    #[structopt(short = "a", long = "address", help = "server address, format: IP:port")]
+    #[structopt(short = "a", long = "address", parse(try_from_str = parse_address), help = "server address, format: IP:port")]
     address: SocketAddr,
     #[structopt(short = "s", long = "sticky-id", help = "value for the sticky session cookie")]
     sticky_id: Option<String>,
     #[structopt(short = "b", long = "backup", help = "set backend as a backup backend")]
     backup: Option<bool>,
+    #[structopt(short = "w", long = "weight", help = "weight used by the load balancing algorithm")]
+    weight: Option<u8>,
+  },
+  #[structopt(name = "replace", about = "Change the address of a backend, existing connections are kept until they close")]
+  Replace {
+    #[structopt(short = "i", long = "id")]
+    id: String,
+    #[structopt(long = "backend-id")]
+    backend_id: String,
+    #[structopt(short = "a", long = "address", parse(try_from_str = parse_address), help = "new server address, format: IP:port")]
+    address: SocketAddr,
+  },
+  #[structopt(name = "drain", about = "Stop sending new connections to a backend, existing connections are kept until they close")]
+  Drain {
+    #[structopt(short = "i", long = "id")]
+    id: String,
+    #[structopt(long = "backend-id")]
+    backend_id: String,
+  },
+  #[structopt(name = "reset", about = "Forget the connection failures of a backend, so it is tried again right away")]
+  Reset {
+    #[structopt(short = "i", long = "id")]
+    id: String,
+    #[structopt(long = "backend-id")]
+    backend_id: String,
   },
 }
 
@@ -181,6 +384,20 @@ pub enum HttpFrontendCmd {
     hostname: String,
     #[structopt(short = "p", long = "path", help="URL prefix of the frontend")]
     path_begin: Option<String>,
+    #[structopt(long = "trailing-slash", default_value = "strict", help="trailing slash handling of the path: strict or normalize")]
+    trailing_slash: TrailingSlash,
+    #[structopt(long = "method", help="HTTP method of the requests using the frontend, any method if absent")]
+    method: Option<String>,
+    #[structopt(long = "header-match", help="request header required to use the frontend, format: 'Name: value'")]
+    header_match: Option<HeaderMatch>,
+    #[structopt(long = "canary-app", help="app id of the canary application receiving a part of the requests")]
+    canary_app: Option<String>,
+    #[structopt(long = "canary-percentage", default_value = "0", help="percentage of the clients sent to the canary application")]
+    canary_percentage: u8,
+    #[structopt(long = "canary-cookie", help="cookie identifying the clients for the canary split, the client address is used without it")]
+    canary_cookie: Option<String>,
+    #[structopt(long = "force", help="replaces the frontend of another application matching the same requests, instead of failing")]
+    force: bool,
   },
   #[structopt(name = "remove")]
   Remove {
@@ -192,6 +409,10 @@ pub enum HttpFrontendCmd {
     hostname: String,
     #[structopt(short = "p", long = "path", help="URL prefix of the frontend")]
     path_begin: Option<String>,
+    #[structopt(long = "method", help="HTTP method of the frontend, if it was added with one")]
+    method: Option<String>,
+    #[structopt(long = "header-match", help="request header of the frontend, if it was added with one, format: 'Name: value'")]
+    header_match: Option<HeaderMatch>,
   },
 }
 
@@ -201,7 +422,7 @@ pub enum TcpFrontendCmd {
   Add {
     #[structopt(short = "i", long = "id", help = "app id of the frontend")]
     id: String,
-    #[structopt(short = "a", long = "address", help = "frontend address, format: IP:port")]
+    #[structopt(short = "a", long = "address", parse(try_from_str = parse_address), help = "frontend address, format: IP:port")]
     address: SocketAddr,
   },
   #[structopt(name = "remove")]
@@ -225,6 +446,8 @@ pub enum CertificateCmd {
     chain: String,
     #[structopt(long = "key", help = "path to the key")]
     key: String,
+    #[structopt(long = "key-passphrase-file", help = "file containing the passphrase of an encrypted key. The key is decrypted locally with the openssl command")]
+    passphrase_file: Option<String>,
   },
   #[structopt(name = "remove", about = "Remove a certificate")]
   Remove {
@@ -245,10 +468,26 @@ pub enum CertificateCmd {
     chain: String,
     #[structopt(long = "new-key", help = "path to the new key")]
     key: String,
+    #[structopt(long = "key-passphrase-file", help = "file containing the passphrase of an encrypted key. The key is decrypted locally with the openssl command")]
+    passphrase_file: Option<String>,
     #[structopt(short = "old-cert", long = "old-certificate", help = "path to the old certificate")]
     old_certificate: Option<String>,
     #[structopt(short = "f", long = "fingerprint", help = "old certificate fingerprint")]
     old_fingerprint: Option<String>,
+  },
+  #[structopt(name = "reload", about = "Reload the certificates of a listener from a directory, without changing the routing")]
+  Reload {
+    #[structopt(short = "a", long = "address", help = "listener address, format: IP:port")]
+    address: SocketAddr,
+    #[structopt(short = "d", long = "directory", help = "directory containing one subdirectory per hostname, with cert.pem, chain.pem and privkey.pem")]
+    directory: String,
+  },
+  #[structopt(name = "list", about = "List the certificates, sorted by expiration date")]
+  List {
+    #[structopt(long = "expires-in", default_value = "30", help = "flag the certificates expiring within this number of days")]
+    expires_in: u32,
+    #[structopt(short = "j", long = "json", help = "Print the command result in JSON format")]
+    json: bool,
   }
 }
 
@@ -268,11 +507,99 @@ pub enum QueryCmd {
     fingerprint: Option<String>,
     #[structopt(short = "d", long="domain", help="domain name")]
     domain: Option<String>
-  }
+  },
+
+  #[structopt(name = "routes", about = "Dump the frontends as they are routed by each worker")]
+  Routes {},
+
+  #[structopt(name = "route", about = "Show the frontend and backends each worker would use for a request")]
+  Route {
+    #[structopt(long = "hostname", help = "hostname of the request")]
+    hostname: String,
+    #[structopt(long = "path", default_value = "/", help = "path of the request")]
+    path: String,
+    #[structopt(long = "method", help = "method of the request")]
+    method: Option<String>,
+  },
+
+  #[structopt(name = "backend", about = "Show the status, connections and failures of a backend in each worker")]
+  Backend {
+    #[structopt(short = "i", long = "id", help = "application identifier")]
+    id: String,
+    #[structopt(long = "backend-id")]
+    backend_id: String,
+  },
 }
 
 #[derive(StructOpt, PartialEq, Debug)]
 pub enum ConfigCmd {
-  #[structopt(name = "check", about = "check configuration file syntax and exit")]
-  Check {}
+  #[structopt(name = "check", about = "validate a configuration file without a running proxy: certificates, addresses and frontend conflicts")]
+  Check {
+    #[structopt(short = "f", long = "file", help = "configuration or state file to check instead of the configuration given with --config")]
+    file: Option<String>,
+  }
+}
+
+/// parses an IP:port address, with an error telling which part is invalid,
+/// so a typo is caught before any order is sent
+pub fn parse_address(address: &str) -> Result<SocketAddr, String> {
+  let index = address.rfind(':').ok_or_else(|| format!("missing port in \"{}\", format: IP:port", address))?;
+  let (ip, port) = (&address[..index], &address[index+1..]);
+
+  // IPv6 addresses are written between brackets, like [::1]:8080
+  let ip = if ip.starts_with('[') && ip.ends_with(']') { &ip[1..ip.len()-1] } else { ip };
+  let ip = ip.parse::<IpAddr>().map_err(|_| format!("invalid IP address \"{}\"", ip))?;
+
+  match port.parse::<u16>() {
+    Ok(0) | Err(_) => Err(format!("invalid port \"{}\", it should be between 1 and 65535", port)),
+    Ok(port) => Ok(SocketAddr::new(ip, port)),
+  }
+}
+
+/// writes the completion script of all the subcommands and options for this shell
+pub fn generate_completions<W: Write>(shell: &str, out: &mut W) -> Result<(), String> {
+  let shell = shell.parse::<Shell>()?;
+  App::clap().gen_completions_to("sozuctl", shell, out);
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn completions(shell: &str) -> String {
+    let mut out = Vec::new();
+    generate_completions(shell, &mut out).expect("could not generate the completions");
+    String::from_utf8(out).expect("the completion script should be UTF-8")
+  }
+
+  #[test]
+  fn completion_scripts() {
+    for shell in &["bash", "zsh", "fish"] {
+      let script = completions(shell);
+      for subcommand in &["shutdown", "upgrade", "status", "metrics", "application", "backend",
+        "frontend", "certificate", "query", "config", "events", "completions"] {
+        assert!(script.contains(subcommand), "the {} script is missing the {} subcommand", shell, subcommand);
+      }
+      assert!(script.contains("sozuctl"));
+      assert!(script.contains("timeout"));
+    }
+
+    assert!(generate_completions("tcsh", &mut Vec::new()).is_err());
+  }
+
+  #[test]
+  fn address_parsing() {
+    assert_eq!(parse_address("127.0.0.1:8080"), Ok("127.0.0.1:8080".parse().unwrap()));
+    assert_eq!(parse_address("[::1]:443"), Ok("[::1]:443".parse().unwrap()));
+    assert_eq!(parse_address("[2001:db8::42]:65535"), Ok("[2001:db8::42]:65535".parse().unwrap()));
+
+    assert_eq!(parse_address("127.0.0.1"), Err(String::from("missing port in \"127.0.0.1\", format: IP:port")));
+    assert_eq!(parse_address("localhost:80"), Err(String::from("invalid IP address \"localhost\"")));
+    assert_eq!(parse_address("127.0.0.256:80"), Err(String::from("invalid IP address \"127.0.0.256\"")));
+    assert!(parse_address("127.0.0.1:0").is_err());
+    assert!(parse_address("127.0.0.1:65536").is_err());
+    assert!(parse_address("127.0.0.1:http").is_err());
+    assert!(parse_address("[::1]").is_err());
+  }
 }
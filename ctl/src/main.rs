@@ -7,123 +7,197 @@ extern crate serde;
 extern crate serde_json;
 #[macro_use] extern crate serde_derive;
 extern crate hex;
+extern crate toml;
+extern crate time;
 
 mod command;
 mod cli;
 
 use std::io;
+use std::time::Duration;
 use structopt::StructOpt;
 
 use sozu_command::config::Config;
 use sozu_command::channel::Channel;
-use sozu_command::command::{CommandRequest,CommandResponse};
+use sozu_command::command::{CommandRequest,CommandResponse,StateFormat};
+use sozu_command::proxy::{TraceFilter,TrafficSplit,RequestLimit};
 
-use command::{add_application,remove_application,dump_state,load_state,
+use command::{add_application,remove_application,purge_application,enable_application,disable_application,set_app_timeout,rebalance,dump_state,show_routes,generate_commands,load_state,
   save_state, soft_stop, hard_stop, upgrade_master, status,metrics,
-  remove_backend, add_backend, remove_http_frontend, add_http_frontend,
+  remove_backend, add_backend, replace_backend, drain_backend, reset_backend, remove_http_frontend, add_http_frontend,
   remove_tcp_frontend, add_tcp_frontend, add_certificate, remove_certificate,
-  replace_certificate, query_application, logging_filter, upgrade_worker,
-  events,query_certificate};
+  replace_certificate, query_application, logging_filter, upgrade_worker, restart_worker,
+  events,logs,query_certificate,dump_routes,route_test,backend_stats,trace,trace_app,set_retry_policy,set_traffic_split,set_default_app,apply_file,check_slo,SloSpec,reload_certs,reload_apps,
+  list_certificates,ping_command,log_format,throughput,reset_metrics,check_config,
+  OrderOptions, DEFAULT_UPGRADE_PHASE_TIMEOUT};
 
 use cli::*;
 
+/// in milliseconds, the master answers pings without contacting the workers
+const PING_TIMEOUT: u64 = 1000;
+
 fn main() {
   let matches = App::from_args();
 
-  let config_file = matches.config.or(option_env!("SOZU_CONFIG").map(|s| s.to_string())).expect("missing --config <configuration file> option");
+  // generating the completions does not need a configuration file
+  if let SubCmd::Completions{ ref shell } = matches.cmd {
+    if let Err(e) = generate_completions(shell, &mut io::stdout()) {
+      eprintln!("could not generate the completions: {}", e);
+      std::process::exit(1);
+    }
+    std::process::exit(0);
+  }
 
-  let config  = Config::load_from_path(config_file.as_str()).expect("could not parse configuration file");
+  // checking a file does not need a running proxy
+  if let SubCmd::Config{ cmd: ConfigCmd::Check{ file: Some(ref file) } } = matches.cmd {
+    check_config(file);
+    std::process::exit(0);
+  }
 
-  // If the command is `config check` then exit because if we are here, the configuration is valid
-  if let SubCmd::Config{ cmd: ConfigCmd::Check{} } = matches.cmd {
-    println!("Configuration file is valid");
+  let config_file = matches.config.or(option_env!("SOZU_CONFIG").map(|s| s.to_string())).expect("missing --config <configuration file> option");
+
+  if let SubCmd::Config{ cmd: ConfigCmd::Check{ file: None } } = matches.cmd {
+    check_config(&config_file);
     std::process::exit(0);
   }
 
+  let config  = Config::load_from_path(config_file.as_str()).expect("could not parse configuration file");
+
   let channel = create_channel(&config).expect("could not connect to the command unix socket");
   let timeout: u64 = matches.timeout.unwrap_or(config.ctl_command_timeout);
+  let options = OrderOptions { timeout, dry_run: matches.dry_run, wait: matches.wait };
 
   match matches.cmd {
-    SubCmd::Shutdown{ hard, worker} => {
+    SubCmd::Shutdown{ hard, worker, drain_timeout } => {
       if hard {
         hard_stop(channel, worker, timeout);
       } else {
-        soft_stop(channel, worker);
+        soft_stop(channel, worker, drain_timeout);
       }
     },
-    SubCmd::Upgrade { worker: None } => upgrade_master(channel, &config),
-    SubCmd::Upgrade { worker: Some(id) } => { upgrade_worker(channel, timeout, id); },
-    SubCmd::Status{ json } => status(channel, json),
-    SubCmd::Metrics{ json } => metrics(channel, json),
-    SubCmd::Logging{ level } => logging_filter(channel, timeout, &level),
+    SubCmd::Upgrade { worker: None, phase_timeout } =>
+      upgrade_master(channel, &config, phase_timeout.unwrap_or(DEFAULT_UPGRADE_PHASE_TIMEOUT)),
+    SubCmd::Upgrade { worker: Some(id), phase_timeout } => { upgrade_worker(channel, phase_timeout.unwrap_or(timeout), id); },
+    SubCmd::Restart { worker, force, phase_timeout } => restart_worker(channel, phase_timeout.unwrap_or(timeout), worker, force),
+    SubCmd::Status{ json, csv } => status(channel, json, csv),
+    SubCmd::Metrics{ json, csv, buckets, watch, id } => metrics(channel, json, csv, buckets, watch.map(Duration::from_secs),
+      id.as_ref().map(|s| s.as_str())),
+    SubCmd::ResetMetrics => reset_metrics(channel),
+    SubCmd::Throughput{ json, interval } => throughput(channel, json, Duration::from_secs(interval)),
+    SubCmd::Logging{ level } => logging_filter(channel, options, &level),
+    SubCmd::LogFormat{ format } => log_format(channel, options, format),
+    SubCmd::Trace{ hostname, path, ip, duration } => trace(channel, options, TraceFilter {
+      hostname, path_begin: path, client_ip: ip,
+    }, duration),
+    SubCmd::TraceApp{ id, off } => trace_app(channel, options, &id, !off),
+    SubCmd::RetryPolicy{ max_tries, max_interval } => set_retry_policy(channel, options, max_tries, max_interval),
+    SubCmd::TrafficSplit{ id, hostname, percentage } => set_traffic_split(channel, options, &id, &hostname, percentage),
+    SubCmd::DefaultApp{ address, https, id } => set_default_app(channel, options, address, https, id),
+    SubCmd::Slo{ latency, metric, error_rate, id } => {
+      match SloSpec::parse(&latency, metric, error_rate) {
+        Ok(spec) => check_slo(channel, &spec, id.as_ref().map(|s| s.as_str())),
+        Err(e) => {
+          eprintln!("invalid SLO threshold: {}", e);
+          std::process::exit(1);
+        }
+      }
+    },
+    SubCmd::Apply{ file, continue_on_error } => apply_file(channel, options, &file, continue_on_error),
     SubCmd::State{ cmd } => {
       match cmd {
-        StateCmd::Save{ file } => save_state(channel, timeout, file),
+        StateCmd::Save{ file, canonical } => save_state(channel, timeout, file,
+          if canonical { StateFormat::Canonical } else { StateFormat::Default }),
         StateCmd::Load{ file } => load_state(channel, timeout, file),
+        StateCmd::ReloadApps{ file } => reload_apps(channel, options, &file),
         StateCmd::Dump{ json } => dump_state(channel, timeout, json),
+        StateCmd::Routes => show_routes(channel, timeout),
+        StateCmd::Commands{ certificates_directory } => generate_commands(channel, timeout, certificates_directory),
       }
     },
     SubCmd::Application{ cmd } => {
       match cmd {
-        ApplicationCmd::Add{ id, sticky_session, https_redirect, send_proxy, expect_proxy, load_balancing_policy } => add_application(channel, timeout, &id, sticky_session, https_redirect, send_proxy, expect_proxy, load_balancing_policy),
-        ApplicationCmd::Remove{ id } => remove_application(channel, timeout, &id),
+        ApplicationCmd::Add{ id, sticky_session, https_redirect, send_proxy, proxy_protocol_version, expect_proxy, load_balancing_policy, forward_tls_info, no_forwarded_headers, fallback_backend, retry_unanswered_requests, max_concurrent_requests, request_queue_size } => add_application(channel, options, &id, sticky_session, https_redirect, send_proxy, proxy_protocol_version, expect_proxy, load_balancing_policy, forward_tls_info, !no_forwarded_headers, fallback_backend, retry_unanswered_requests,
+          max_concurrent_requests.map(|max_requests| RequestLimit { max_requests, queue_size: request_queue_size.unwrap_or(0) })),
+        ApplicationCmd::Remove{ id, purge: false } => remove_application(channel, options, &id),
+        ApplicationCmd::Remove{ id, purge: true } => purge_application(channel, options, &id),
+        ApplicationCmd::Enable{ id } => enable_application(channel, options, &id),
+        ApplicationCmd::Disable{ id } => disable_application(channel, options, &id),
+        ApplicationCmd::Timeout{ id, timeout } => set_app_timeout(channel, options, &id, timeout),
+        ApplicationCmd::Rebalance{ id, duration } => rebalance(channel, options, &id, duration),
       }
     },
     SubCmd::Backend{ cmd } => {
       match cmd {
-        BackendCmd::Add{ id, backend_id, address, sticky_id, backup } => add_backend(channel, timeout, &id, &backend_id, address, sticky_id, backup),
-        BackendCmd::Remove{ id, backend_id, address } => remove_backend(channel, timeout, &id, &backend_id, address),
+        BackendCmd::Add{ id, backend_id, address, sticky_id, backup, weight } => add_backend(channel, options, &id, &backend_id, address, sticky_id, backup, weight),
+        BackendCmd::Remove{ id, backend_id, address } => remove_backend(channel, options, &id, &backend_id, address),
+        BackendCmd::Replace{ id, backend_id, address } => replace_backend(channel, options, &id, &backend_id, address),
+        BackendCmd::Drain{ id, backend_id } => drain_backend(channel, options, &id, &backend_id),
+        BackendCmd::Reset{ id, backend_id } => reset_backend(channel, options, &id, &backend_id),
       }
     },
     SubCmd::Frontend{ cmd } => {
       match cmd {
         FrontendCmd::Http{ cmd } => match cmd {
-          HttpFrontendCmd::Add{ id, hostname, path_begin, address } => {
-            add_http_frontend(channel, timeout, &id, address, &hostname, &path_begin.unwrap_or("".to_string()), false)
+          HttpFrontendCmd::Add{ id, hostname, path_begin, trailing_slash, method, header_match, canary_app, canary_percentage, canary_cookie, address, force } => {
+            let split = canary_app.map(|canary_app_id| TrafficSplit { canary_app_id, percentage: canary_percentage, cookie: canary_cookie });
+            add_http_frontend(channel, options, &id, address, &hostname, &path_begin.unwrap_or("".to_string()), trailing_slash, method, header_match, split, false, force)
           },
-          HttpFrontendCmd::Remove{ id, hostname, path_begin, address } => {
-            remove_http_frontend(channel, timeout, &id, address, &hostname, &path_begin.unwrap_or("".to_string()), false)
+          HttpFrontendCmd::Remove{ id, hostname, path_begin, method, header_match, address } => {
+            remove_http_frontend(channel, options, &id, address, &hostname, &path_begin.unwrap_or("".to_string()), method, header_match, false)
           },
         },
         FrontendCmd::Https{ cmd } => match cmd {
-          HttpFrontendCmd::Add{ id, hostname, path_begin, address } => {
-            add_http_frontend(channel, timeout, &id, address, &hostname, &path_begin.unwrap_or("".to_string()), true)
+          HttpFrontendCmd::Add{ id, hostname, path_begin, trailing_slash, method, header_match, canary_app, canary_percentage, canary_cookie, address, force } => {
+            let split = canary_app.map(|canary_app_id| TrafficSplit { canary_app_id, percentage: canary_percentage, cookie: canary_cookie });
+            add_http_frontend(channel, options, &id, address, &hostname, &path_begin.unwrap_or("".to_string()), trailing_slash, method, header_match, split, true, force)
           },
-          HttpFrontendCmd::Remove{ id, hostname, path_begin, address } => {
-            remove_http_frontend(channel, timeout, &id, address, &hostname, &path_begin.unwrap_or("".to_string()), true)
+          HttpFrontendCmd::Remove{ id, hostname, path_begin, method, header_match, address } => {
+            remove_http_frontend(channel, options, &id, address, &hostname, &path_begin.unwrap_or("".to_string()), method, header_match, true)
           },
         },
         FrontendCmd::Tcp { cmd } => match cmd {
           TcpFrontendCmd::Add{ id, address } =>
-            add_tcp_frontend(channel, timeout, &id, address),
+            add_tcp_frontend(channel, options, &id, address),
           TcpFrontendCmd::Remove{ id, address } =>
-            remove_tcp_frontend(channel, timeout, &id, address),
+            remove_tcp_frontend(channel, options, &id, address),
         }
       }
     },
     SubCmd::Certificate{ cmd } => {
       match cmd {
-        CertificateCmd::Add{ certificate, chain, key, address } => {
-          add_certificate(channel, timeout, address, &certificate, &chain, &key)
+        CertificateCmd::Add{ certificate, chain, key, passphrase_file, address } => {
+          add_certificate(channel, options, address, &certificate, &chain, &key, passphrase_file.as_deref())
         },
         CertificateCmd::Remove{ certificate, address, fingerprint } => {
-          remove_certificate(channel, timeout, address, certificate.as_deref(),
+          remove_certificate(channel, options, address, certificate.as_deref(),
             fingerprint.as_deref())
         },
-        CertificateCmd::Replace{ certificate, chain, key, old_certificate, address, old_fingerprint } => {
-          replace_certificate(channel, timeout, address, &certificate, &chain,
-            &key, old_certificate.as_deref(), old_fingerprint.as_deref())
+        CertificateCmd::Replace{ certificate, chain, key, passphrase_file, old_certificate, address, old_fingerprint } => {
+          replace_certificate(channel, options, address, &certificate, &chain,
+            &key, passphrase_file.as_deref(), old_certificate.as_deref(), old_fingerprint.as_deref())
+        },
+        CertificateCmd::Reload{ address, directory } => {
+          reload_certs(channel, options, address, &directory)
         },
+        CertificateCmd::List{ expires_in, json } => list_certificates(channel, json, expires_in),
       }
     },
     SubCmd::Query{ cmd, json } => {
       match cmd {
         QueryCmd::Applications{ id, domain } => query_application(channel, json, id, domain),
         QueryCmd::Certificates{ fingerprint, domain } => query_certificate(channel, json, fingerprint, domain),
+        QueryCmd::Routes{} => dump_routes(channel, json),
+        QueryCmd::Route{ hostname, path, method } => route_test(channel, json, &hostname, &path, method),
+        QueryCmd::Backend{ id, backend_id } => backend_stats(channel, json, &id, &backend_id),
       }
     },
     SubCmd::Config{ cmd: _ } => {}, // noop, handled at the beginning of the method
+    SubCmd::Completions{ .. } => {}, // noop, handled at the beginning of the method
     SubCmd::Events => events(channel),
+    SubCmd::Logs{ json, app_id, status } => logs(channel, json, app_id, status),
+    // without a deadline, a stuck master would block the ping forever
+    SubCmd::Ping => ping_command(channel,
+      Duration::from_millis(matches.timeout.filter(|&t| t > 0).unwrap_or(PING_TIMEOUT))),
   }
 }
 
@@ -0,0 +1,32 @@
+#!/bin/sh
+# certificates used by the client certificate (mTLS) tests
+set -e
+TMP=$(mktemp -d)
+
+# CA checking the client certificates, it also signs the server certificate
+openssl req -x509 -nodes -days 36500 -newkey ec -pkeyopt ec_paramgen_curve:prime256v1 \
+  -keyout "$TMP/ca-key.pem" -out client-ca.pem -subj "/O=Sozu/CN=Sozu test client CA" \
+  -addext "basicConstraints=critical,CA:TRUE" -addext "keyUsage=critical,keyCertSign,cRLSign"
+
+openssl req -nodes -newkey ec -pkeyopt ec_paramgen_curve:prime256v1 -subj "/CN=localhost" \
+  -keyout mtls-server-key.pem -out "$TMP/server.csr"
+printf "basicConstraints=critical,CA:FALSE\nextendedKeyUsage=serverAuth\nsubjectAltName=DNS:localhost\n" > "$TMP/server.ext"
+openssl x509 -req -days 36500 -in "$TMP/server.csr" -CA client-ca.pem -CAkey "$TMP/ca-key.pem" \
+  -CAcreateserial -extfile "$TMP/server.ext" -out mtls-server-certificate.pem
+
+openssl req -nodes -newkey ec -pkeyopt ec_paramgen_curve:prime256v1 -subj "/C=FR/O=Sozu/CN=client.example.com" \
+  -keyout client-key.pem -out "$TMP/client.csr"
+printf "basicConstraints=critical,CA:FALSE\nextendedKeyUsage=clientAuth\n" > "$TMP/client.ext"
+openssl x509 -req -days 36500 -in "$TMP/client.csr" -CA client-ca.pem -CAkey "$TMP/ca-key.pem" \
+  -CAcreateserial -extfile "$TMP/client.ext" -out client-certificate.pem
+
+# signed by another CA
+openssl req -x509 -nodes -days 36500 -newkey ec -pkeyopt ec_paramgen_curve:prime256v1 \
+  -keyout "$TMP/other-ca-key.pem" -out "$TMP/other-ca.pem" -subj "/CN=Other CA" \
+  -addext "basicConstraints=critical,CA:TRUE" -addext "keyUsage=critical,keyCertSign,cRLSign"
+openssl req -nodes -newkey ec -pkeyopt ec_paramgen_curve:prime256v1 -subj "/CN=untrusted.example.com" \
+  -keyout untrusted-client-key.pem -out "$TMP/untrusted.csr"
+openssl x509 -req -days 36500 -in "$TMP/untrusted.csr" -CA "$TMP/other-ca.pem" -CAkey "$TMP/other-ca-key.pem" \
+  -CAcreateserial -extfile "$TMP/client.ext" -out untrusted-client-certificate.pem
+
+rm -r "$TMP" client-ca.srl
@@ -39,7 +39,11 @@ fn main() {
     app_id:     String::from("app_1"),
     address:    "127.0.0.1:8080".parse().unwrap(),
     hostname:   String::from("lolcatho.st"),
-    path_begin: String::from("/")
+    path_begin: String::from("/"),
+    trailing_slash: proxy::TrailingSlash::Strict,
+    method: None,
+    header_match: None,
+    split: None,
   };
 
   let http_backend = proxy::Backend {
@@ -49,6 +53,7 @@ fn main() {
     address:     "127.0.0.1:1026".parse().unwrap(),
     load_balancing_parameters: Some(LoadBalancingParams::default()),
     backup:      None,
+    hostname: None,
   };
 
   command.write_message(&proxy::ProxyRequest {
@@ -113,6 +118,10 @@ fn main() {
     address:     "127.0.0.1:8443".parse().unwrap(),
     hostname:    String::from("lolcatho.st"),
     path_begin:  String::from("/"),
+    trailing_slash: proxy::TrailingSlash::Strict,
+    method: None,
+    header_match: None,
+    split: None,
   };
 
   command2.write_message(&proxy::ProxyRequest {
@@ -126,6 +135,7 @@ fn main() {
     address:     "127.0.0.1:1026".parse().unwrap(),
     load_balancing_parameters: Some(LoadBalancingParams::default()),
     backup:      None,
+    hostname: None,
   };
 
   command2.write_message(&proxy::ProxyRequest {
@@ -156,6 +166,10 @@ fn main() {
     address:     "127.0.0.1:8443".parse().unwrap(),
     hostname:    String::from("test.local"),
     path_begin:  String::from("/"),
+    trailing_slash: proxy::TrailingSlash::Strict,
+    method: None,
+    header_match: None,
+    split: None,
   };
 
   command2.write_message(&proxy::ProxyRequest {
@@ -170,6 +184,7 @@ fn main() {
     address:     "127.0.0.1:1026".parse().unwrap(),
     load_balancing_parameters: Some(LoadBalancingParams::default()),
     backup:      None,
+    hostname: None,
   };
 
   command2.write_message(&proxy::ProxyRequest {
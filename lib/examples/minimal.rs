@@ -38,6 +38,10 @@ fn main() {
     address:    "127.0.0.1:8080".parse().unwrap(),
     hostname:   String::from("example.com"),
     path_begin: String::from("/"),
+    trailing_slash: proxy::TrailingSlash::Strict,
+    method: None,
+    header_match: None,
+    split: None,
   };
   let http_backend = proxy::Backend {
     app_id:                    String::from("test"),
@@ -46,6 +50,7 @@ fn main() {
     load_balancing_parameters: Some(LoadBalancingParams::default()),
     sticky_id:                 None,
     backup:                    None,
+    hostname: None,
   };
 
   command.write_message(&proxy::ProxyRequest {
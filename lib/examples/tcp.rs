@@ -31,6 +31,10 @@ fn main() {
       front: "127.0.0.1:8080".parse().unwrap(),
       public_address: None,
       expect_proxy: false,
+      interface: None,
+      timeouts: Default::default(),
+      connection_rate_limit: None,
+      max_sessions: None,
     };
     Logger::init("TCP".to_string(), "debug", LoggerBackend::Stdout(stdout()), None);
     sozu::tcp::start(listener, max_buffers, buffer_size, channel);
@@ -47,6 +51,7 @@ fn main() {
     load_balancing_parameters: Some(LoadBalancingParams::default()),
     sticky_id:   None,
     backup:      None,
+    hostname: None,
   };
 
   command.write_message(&proxy::ProxyRequest {
@@ -0,0 +1,219 @@
+//! idle keep-alive connections to the backends
+//!
+//! When an HTTP session ends while its backend connection is idle and kept
+//! alive by the backend, the connection is added to the pool instead of being
+//! closed, and the next request sent to the same backend uses it instead of
+//! opening a new one. The pool keeps at most `max_idle` connections per backend,
+//! for at most `idle_timeout`. The pooled sockets are not registered in the
+//! event loop, so a connection the backend closed is only noticed when it is
+//! taken out of the pool, and discarded then.
+//! The idle connections do not count in the backend's active connections.
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use mio::net::TcpStream;
+use time::{Duration, SteadyTime};
+
+use super::{AppId,Backend,BackendStatus};
+
+#[derive(Debug)]
+struct IdleConnection {
+  socket:  TcpStream,
+  /// address of the backend when the connection was opened
+  address: SocketAddr,
+  since:   SteadyTime,
+}
+
+#[derive(Debug)]
+pub struct ConnectionPool {
+  /// maximum number of idle connections kept for each backend, 0 disables the pool
+  pub max_idle:     usize,
+  pub idle_timeout: Duration,
+  /// idle connections by application and backend id, the most recent last
+  connections:      HashMap<(AppId, String), Vec<IdleConnection>>,
+}
+
+impl ConnectionPool {
+  pub fn new(max_idle: usize, idle_timeout: Duration) -> ConnectionPool {
+    ConnectionPool {
+      max_idle,
+      idle_timeout,
+      connections: HashMap::new(),
+    }
+  }
+
+  /// adds an idle connection to the backend. The connection is given back if
+  /// the pool has no room for it or if it cannot be used anymore
+  pub fn checkin(&mut self, app_id: &str, backend: &Backend, socket: TcpStream, now: SteadyTime) -> Result<(), TcpStream> {
+    self.remove_expired(now);
+
+    let key = (app_id.to_string(), backend.backend_id.clone());
+    let max_idle = self.max_idle;
+    if backend.status != BackendStatus::Normal || !is_idle(&socket)
+      || self.connections.get(&key).map(|connections| connections.len() >= max_idle).unwrap_or(max_idle == 0) {
+      return Err(socket);
+    }
+
+    self.connections.entry(key).or_insert_with(Vec::new).push(IdleConnection {
+      socket,
+      address: backend.address,
+      since: now,
+    });
+    Ok(())
+  }
+
+  /// takes the most recent idle connection to the backend. The connections that
+  /// expired, that the backend closed, or that were opened to a previous address
+  /// of the backend are closed
+  pub fn checkout(&mut self, app_id: &str, backend: &Backend, now: SteadyTime) -> Option<TcpStream> {
+    let key = (app_id.to_string(), backend.backend_id.clone());
+    let idle_timeout = self.idle_timeout;
+
+    let socket = {
+      let connections = self.connections.get_mut(&key)?;
+      let mut socket = None;
+      while let Some(connection) = connections.pop() {
+        if connection.address == backend.address && now - connection.since < idle_timeout && is_idle(&connection.socket) {
+          socket = Some(connection.socket);
+          break;
+        }
+        incr!("backend.pool.discarded");
+      }
+      socket
+    };
+
+    if self.connections.get(&key).map(|connections| connections.is_empty()).unwrap_or(false) {
+      self.connections.remove(&key);
+    }
+
+    if socket.is_some() {
+      incr!("backend.pool.reused");
+    }
+    socket
+  }
+
+  /// closes the connections that stayed idle longer than the idle timeout
+  pub fn remove_expired(&mut self, now: SteadyTime) {
+    let idle_timeout = self.idle_timeout;
+    for connections in self.connections.values_mut() {
+      connections.retain(|connection| now - connection.since < idle_timeout);
+    }
+    self.connections.retain(|_, connections| !connections.is_empty());
+  }
+
+  pub fn idle_connections(&self, app_id: &str, backend_id: &str) -> usize {
+    self.connections.get(&(app_id.to_string(), backend_id.to_string()))
+      .map(|connections| connections.len()).unwrap_or(0)
+  }
+}
+
+/// the backend did not close an idle connection or send data on it
+fn is_idle(socket: &TcpStream) -> bool {
+  let mut buf = [0u8; 1];
+  match socket.peek(&mut buf[..]) {
+    Err(ref e) => e.kind() == ErrorKind::WouldBlock,
+    Ok(_)      => false,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::{thread, net};
+
+  /// returns a connected pair of sockets, the client side being non blocking
+  fn connection(listener: &net::TcpListener) -> (TcpStream, net::TcpStream) {
+    let client = net::TcpStream::connect(listener.local_addr().unwrap()).expect("could not connect");
+    let (server, _) = listener.accept().expect("could not accept");
+    (TcpStream::from_stream(client).expect("could not create the mio socket"), server)
+  }
+
+  #[test]
+  fn checkout_and_return() {
+    let listener = net::TcpListener::bind("127.0.0.1:1113").expect("could not bind");
+    let mut backend = Backend::new("app_1-0", "127.0.0.1:1113".parse().unwrap(), None, None, None);
+    let mut pool = ConnectionPool::new(2, Duration::seconds(10));
+    let now = SteadyTime::now();
+
+    let (socket_1, _server_1) = connection(&listener);
+    let (socket_2, _server_2) = connection(&listener);
+    let (socket_3, _server_3) = connection(&listener);
+    let address_2 = socket_2.local_addr().unwrap();
+    assert!(pool.checkin("app_1", &backend, socket_1, now).is_ok());
+    assert!(pool.checkin("app_1", &backend, socket_2, now).is_ok());
+    // the pool keeps 2 connections per backend
+    assert!(pool.checkin("app_1", &backend, socket_3, now).is_err());
+    assert_eq!(pool.idle_connections("app_1", "app_1-0"), 2);
+
+    // the most recent connection comes first
+    let socket = pool.checkout("app_1", &backend, now).expect("there should be an idle connection");
+    assert_eq!(socket.local_addr().unwrap(), address_2);
+    assert!(pool.checkout("app_2", &backend, now).is_none());
+    assert_eq!(pool.idle_connections("app_1", "app_1-0"), 1);
+
+    // connections to a previous address of the backend are not used
+    backend.address = "127.0.0.1:1114".parse().unwrap();
+    assert!(pool.checkout("app_1", &backend, now).is_none());
+    assert_eq!(pool.idle_connections("app_1", "app_1-0"), 0);
+
+    // nor the ones to a backend that is closing
+    backend.address = "127.0.0.1:1113".parse().unwrap();
+    backend.inc_connections();
+    backend.set_closing();
+    let (socket_4, _server_4) = connection(&listener);
+    assert!(pool.checkin("app_1", &backend, socket_4, now).is_err());
+
+    let mut disabled = ConnectionPool::new(0, Duration::seconds(10));
+    let (socket_5, _server_5) = connection(&listener);
+    assert!(disabled.checkin("app_1", &Backend::new("app_1-0", "127.0.0.1:1113".parse().unwrap(), None, None, None), socket_5, now).is_err());
+  }
+
+  #[test]
+  fn idle_eviction() {
+    let listener = net::TcpListener::bind("127.0.0.1:1115").expect("could not bind");
+    let backend = Backend::new("app_1-0", "127.0.0.1:1115".parse().unwrap(), None, None, None);
+    let mut pool = ConnectionPool::new(4, Duration::seconds(10));
+    let now = SteadyTime::now();
+
+    let (socket_1, _server_1) = connection(&listener);
+    let (socket_2, _server_2) = connection(&listener);
+    assert!(pool.checkin("app_1", &backend, socket_1, now).is_ok());
+    assert!(pool.checkin("app_1", &backend, socket_2, now + Duration::seconds(5)).is_ok());
+
+    pool.remove_expired(now + Duration::seconds(9));
+    assert_eq!(pool.idle_connections("app_1", "app_1-0"), 2);
+    pool.remove_expired(now + Duration::seconds(10));
+    assert_eq!(pool.idle_connections("app_1", "app_1-0"), 1);
+
+    // an expired connection is not used even if it was not removed yet
+    assert!(pool.checkout("app_1", &backend, now + Duration::seconds(15)).is_none());
+    assert_eq!(pool.idle_connections("app_1", "app_1-0"), 0);
+  }
+
+  #[test]
+  fn closed_connection_is_not_reused() {
+    let listener = net::TcpListener::bind("127.0.0.1:1116").expect("could not bind");
+    let backend = Backend::new("app_1-0", "127.0.0.1:1116".parse().unwrap(), None, None, None);
+    let mut pool = ConnectionPool::new(4, Duration::seconds(10));
+    let now = SteadyTime::now();
+
+    let (socket_1, server_1) = connection(&listener);
+    let (socket_2, _server_2) = connection(&listener);
+    let address_2 = socket_2.local_addr().unwrap();
+    assert!(pool.checkin("app_1", &backend, socket_2, now).is_ok());
+    assert!(pool.checkin("app_1", &backend, socket_1, now).is_ok());
+
+    // the backend closes the most recent connection while it is in the pool
+    drop(server_1);
+    thread::sleep(::std::time::Duration::from_millis(100));
+    let socket = pool.checkout("app_1", &backend, now).expect("the other connection should be used");
+    assert_eq!(socket.local_addr().unwrap(), address_2);
+
+    // a connection with unexpected data is not added to the pool
+    let (socket_3, mut server_3) = connection(&listener);
+    ::std::io::Write::write_all(&mut server_3, b"HTTP/1.1 200 OK\r\n").unwrap();
+    thread::sleep(::std::time::Duration::from_millis(100));
+    assert!(pool.checkin("app_1", &backend, socket_3, now).is_err());
+    assert_eq!(pool.idle_connections("app_1", "app_1-0"), 0);
+  }
+}
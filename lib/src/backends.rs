@@ -2,18 +2,44 @@ use std::rc::Rc;
 use std::cell::RefCell;
 use std::net::SocketAddr;
 use std::collections::HashMap;
+use std::time::{Duration as StdDuration, Instant, SystemTime, UNIX_EPOCH};
 use mio::net::TcpStream;
+use time::{Duration, SteadyTime};
+use rand::thread_rng;
 
-use sozu_command::{proxy, config::LoadBalancingAlgorithms};
+use sozu_command::{proxy, config::{LoadBalancingAlgorithms,RetryBudget}};
 
-use super::{AppId,Backend,ConnectionError,load_balancing::*};
-use server::push_event;
+use super::{AppId,Backend,BackendStatus,ConnectionError,load_balancing::*};
+use backend_pool::ConnectionPool;
+use retry::RetryState;
+use retry_budget::RetryBudgetCounter;
+use request_limit::RequestLimits;
+use server::{push_event,CONN_RETRIES};
+use socket::SocketConfig;
 
 #[derive(Debug)]
 pub struct BackendMap {
   pub backends:     HashMap<AppId, BackendList>,
   pub max_failures: usize,
   pub available:    bool,
+  /// backends that failed a connection less than this duration ago are
+  /// only selected if no other backend is available
+  pub failure_cooldown: Option<Duration>,
+  /// applied to the backend sockets when they connect
+  pub socket_config: SocketConfig,
+  /// maximum number of backend connection attempts for one request
+  pub connection_attempts: u8,
+  /// retry the failed connections of requests with a method that is not idempotent
+  pub retry_non_idempotent: bool,
+  /// idle connections used before opening new ones, shared with the HTTP sessions
+  /// that return their backend connection to it
+  pub connection_pool: Rc<RefCell<ConnectionPool>>,
+  /// limit of the connection retries of each application, None if unlimited
+  pub retry_budget: Option<RetryBudget>,
+  pub retry_budgets: HashMap<AppId, RetryBudgetCounter>,
+  /// requests in flight and queued for the applications with a concurrency limit,
+  /// shared with the HTTP sessions that hold a slot
+  pub request_limits: Rc<RefCell<RequestLimits>>,
 }
 
 impl BackendMap {
@@ -22,6 +48,14 @@ impl BackendMap {
       backends:     HashMap::new(),
       max_failures: 3,
       available:    true,
+      failure_cooldown: None,
+      socket_config: SocketConfig::default(),
+      connection_attempts: CONN_RETRIES,
+      retry_non_idempotent: true,
+      connection_pool: Rc::new(RefCell::new(ConnectionPool::new(0, Duration::seconds(30)))),
+      retry_budget: None,
+      retry_budgets: HashMap::new(),
+      request_limits: Rc::new(RefCell::new(RequestLimits::new())),
     }
   }
 
@@ -35,14 +69,149 @@ impl BackendMap {
     self.backends.entry(app_id.to_string()).or_insert_with(BackendList::new).add_backend(backend);
   }
 
-  pub fn remove_backend(&mut self, app_id: &str, backend_address: &SocketAddr) {
+  pub fn remove_backend(&mut self, app_id: &str, backend_id: &str, backend_address: &SocketAddr) {
     if let Some(backends) = self.backends.get_mut(app_id) {
-      backends.remove_backend(backend_address);
+      backends.remove_backend(backend_id, backend_address);
     } else {
       error!("Backend was already removed: app id {}, address {:?}", app_id, backend_address);
     }
   }
 
+  /// removes all the backends of an application, and its fallback backend
+  pub fn remove_application(&mut self, app_id: &str) {
+    self.backends.remove(app_id);
+    self.retry_budgets.remove(app_id);
+    self.request_limits.borrow_mut().remove_application(app_id);
+  }
+
+  /// counts a request of the application for its retry budget
+  pub fn record_request(&mut self, app_id: &str) {
+    if let Some(config) = self.retry_budget {
+      self.retry_budgets.entry(app_id.to_string()).or_insert_with(RetryBudgetCounter::new)
+        .record_request(&config, SteadyTime::now());
+    }
+  }
+
+  /// true if the retry budget of the application allows another connection attempt
+  pub fn take_retry(&mut self, app_id: &str) -> bool {
+    let config = match self.retry_budget {
+      Some(config) => config,
+      None         => return true,
+    };
+
+    let allowed = self.retry_budgets.entry(app_id.to_string()).or_insert_with(RetryBudgetCounter::new)
+      .take_retry(&config, SteadyTime::now());
+    if !allowed {
+      incr!("backend.retry_budget_exhausted");
+    }
+    allowed
+  }
+
+  /// changes the address of a backend in place, keeping its retry policy and
+  /// connection count. Sessions already connected to the old address keep
+  /// their connection until it closes, new connections use the new address
+  pub fn replace_backend_address(&mut self, app_id: &str, backend_id: &str, address: SocketAddr) -> Result<(), String> {
+    if let Some(backends) = self.backends.get_mut(app_id) {
+      backends.replace_backend_address(backend_id, address)
+    } else {
+      Err(format!("no backends for app {}", app_id))
+    }
+  }
+
+  /// new connections will not use this backend anymore, existing ones
+  /// are kept until they close
+  pub fn drain_backend(&mut self, app_id: &str, backend_id: &str) -> Result<(), String> {
+    let backend = self.backends.get_mut(app_id)
+      .and_then(|backends| backends.backends.iter().find(|b| b.borrow().backend_id == backend_id))
+      .ok_or_else(|| format!("no backend {} for app {}", backend_id, app_id))?;
+
+    let mut backend = backend.borrow_mut();
+    backend.set_closing();
+    info!("draining backend {} of app {}: {} active connections", backend_id, app_id, backend.active_connections);
+    Ok(())
+  }
+
+  /// new connections go to the least loaded backends of the application for
+  /// `duration`, or until their connection counts are even. The current
+  /// connections stay on their backend
+  pub fn rebalance(&mut self, app_id: &str, duration: Duration) -> Result<(), String> {
+    let backends = self.backends.get_mut(app_id)
+      .filter(|backends| !backends.backends.is_empty())
+      .ok_or_else(|| format!("no backends for app {}", app_id))?;
+
+    backends.rebalance_until = Some(SteadyTime::now() + duration);
+    info!("rebalancing the connections of app {} for {} seconds", app_id, duration.num_seconds());
+    Ok(())
+  }
+
+  /// forgets the connection failures of a backend, new connections can use it right away
+  pub fn reset_backend(&mut self, app_id: &str, backend_id: &str) -> Result<(), String> {
+    let backend = self.backends.get_mut(app_id)
+      .and_then(|backends| backends.backends.iter().find(|b| b.borrow().backend_id == backend_id))
+      .ok_or_else(|| format!("no backend {} for app {}", backend_id, app_id))?;
+
+    let mut backend = backend.borrow_mut();
+    info!("resetting backend {} of app {} after {} failures", backend_id, app_id, backend.failures);
+    backend.reset();
+    Ok(())
+  }
+
+  /// describes the backends that a request for this frontend could use.
+  /// `None` means that no frontend matched the request
+  pub fn route_match(&self, frontend: Option<proxy::HttpFront>) -> proxy::RouteMatch {
+    let frontend = match frontend {
+      Some(frontend) => frontend,
+      None           => return proxy::RouteMatch::NoMatch,
+    };
+
+    let backends: Vec<proxy::RouteBackend> = self.backends.get(&frontend.app_id).map(|list| {
+      list.backends.iter().chain(list.fallback.iter()).map(|backend| {
+        let backend = backend.borrow();
+        proxy::RouteBackend {
+          backend_id:         backend.backend_id.clone(),
+          address:            backend.address,
+          status:             String::from(status_name(&backend.status)),
+          available:          backend.can_open(),
+          backup:             backend.backup,
+          active_connections: backend.active_connections,
+        }
+      }).collect()
+    }).unwrap_or_else(Vec::new);
+
+    if backends.iter().any(|b| b.available) {
+      proxy::RouteMatch::Match(proxy::MatchedRoute { frontend, backends })
+    } else {
+      proxy::RouteMatch::NoAvailableBackend(proxy::MatchedRoute { frontend, backends })
+    }
+  }
+
+  /// connections currently open to the backends of all the applications
+  pub fn active_connections(&self) -> usize {
+    self.backends.values()
+      .flat_map(|list| list.backends.iter().chain(list.fallback.iter()))
+      .map(|backend| backend.borrow().active_connections)
+      .sum()
+  }
+
+  /// current state of a backend, None if the application has no backend with this id
+  pub fn backend_stats(&self, app_id: &str, backend_id: &str) -> Option<proxy::BackendStats> {
+    let app_backends = self.backends.get(app_id)?;
+    let backend = app_backends.backends.iter().chain(app_backends.fallback.iter())
+      .find(|backend| backend.borrow().backend_id == backend_id)?;
+    let backend = backend.borrow();
+
+    Some(proxy::BackendStats {
+      backend_id:         backend.backend_id.clone(),
+      address:            backend.address,
+      status:             String::from(status_name(&backend.status)),
+      active_connections: backend.active_connections,
+      failures:           backend.failures,
+      available:          backend.can_open(),
+      retry_state:        String::from(retry_state_name(backend.retry_policy.state())),
+      next_retry:         backend.retry_policy.next_retry().map(unix_timestamp),
+    })
+  }
+
   pub fn close_backend_connection(&mut self, app_id: &str, addr: &SocketAddr) {
     if let Some(app_backends) = self.backends.get_mut(app_id) {
       if let Some(ref mut backend) = app_backends.find_backend(addr) {
@@ -58,17 +227,23 @@ impl BackendMap {
   }
 
   pub fn backend_from_app_id(&mut self, app_id: &str) -> Result<(Rc<RefCell<Backend>>,TcpStream),ConnectionError> {
+    self.untried_backend_from_app_id(app_id, &[])
+  }
+
+  /// selects a backend that is not in `tried`, the ids of the backends a request
+  /// already failed to connect to
+  pub fn untried_backend_from_app_id(&mut self, app_id: &str, tried: &[String]) -> Result<(Rc<RefCell<Backend>>,TcpStream),ConnectionError> {
     if let Some(ref mut app_backends) = self.backends.get_mut(app_id) {
-      if app_backends.backends.is_empty() {
+      if app_backends.backends.is_empty() && app_backends.fallback.is_none() {
         self.available = false;
         return Err(ConnectionError::NoBackendAvailable);
       }
 
-      if let Some(ref mut b) = app_backends.next_available_backend() {
+      if let Some(ref mut b) = app_backends.next_untried_backend(self.failure_cooldown, tried) {
         let ref mut backend = *b.borrow_mut();
 
         debug!("Connecting {} -> {:?}", app_id, (backend.address, backend.active_connections, backend.failures));
-        let conn = backend.try_connect();
+        let conn = connect(app_id, backend, &self.connection_pool, &self.socket_config);
 
         let res = conn.map(|c| {
           (b.clone(), c)
@@ -97,12 +272,14 @@ impl BackendMap {
   }
 
   pub fn backend_from_sticky_session(&mut self, app_id: &str, sticky_session: &str) -> Result<(Rc<RefCell<Backend>>,TcpStream),ConnectionError> {
+    let socket_config = self.socket_config;
+    let connection_pool = self.connection_pool.clone();
     let sticky_conn: Option<Result<(Rc<RefCell<Backend>>,TcpStream),ConnectionError>> = self.backends
       .get_mut(app_id)
       .and_then(|app_backends| app_backends.find_sticky(sticky_session))
       .map(|b| {
         let ref mut backend = *b.borrow_mut();
-        let conn = backend.try_connect();
+        let conn = connect(app_id, backend, &connection_pool, &socket_config);
 
         conn.map(|c| (b.clone(), c)).map_err(|e| {
           error!("could not connect {} to {:?} using session {} ({} failures)",
@@ -111,14 +288,29 @@ impl BackendMap {
         })
       });
 
-    if let Some(res) = sticky_conn {
-      return res;
-    } else {
-      debug!("Couldn't find a backend corresponding to sticky_session {} for app {}", sticky_session, app_id);
-      return self.backend_from_app_id(app_id);
+    match sticky_conn {
+      Some(Ok(res)) => Ok(res),
+      Some(Err(_)) => {
+        // the pinned backend failed, another one is selected and the cookie will be rewritten
+        self.backend_from_app_id(app_id)
+      },
+      None => {
+        debug!("Couldn't find a backend corresponding to sticky_session {} for app {}", sticky_session, app_id);
+        self.backend_from_app_id(app_id)
+      }
     }
   }
 
+  /// sets the backend used when none of the application's backends is available
+  pub fn set_fallback_backend_for_app(&mut self, app_id: &str, address: Option<SocketAddr>) {
+    let app_backends = self.get_or_create_backend_list_for_app(app_id);
+    app_backends.set_fallback(app_id, address);
+  }
+
+  pub fn set_request_limit_for_app(&mut self, app_id: &str, limit: Option<proxy::RequestLimit>) {
+    self.request_limits.borrow_mut().set_limit(app_id, limit);
+  }
+
   pub fn set_load_balancing_policy_for_app(&mut self, app_id: &str, lb_algo: LoadBalancingAlgorithms) {
     // The application can be created before the backends were registered because of the async config messages.
     // So when we set the load balancing policy, we have to create the backend list if if it doesn't exist yet.
@@ -136,6 +328,11 @@ pub struct BackendList {
   pub backends:       Vec<Rc<RefCell<Backend>>>,
   pub next_id:        u32,
   pub load_balancing: Box<LoadBalancingAlgorithm>,
+  /// used when none of the backends can be selected, with its own retry policy
+  pub fallback:       Option<Rc<RefCell<Backend>>>,
+  /// until then, the least connections algorithm replaces the load balancing
+  /// policy while the backends' connection counts are uneven
+  pub rebalance_until: Option<SteadyTime>,
 }
 
 impl BackendList {
@@ -144,14 +341,17 @@ impl BackendList {
       backends:       Vec::new(),
       next_id:        0,
       load_balancing: Box::new(RandomAlgorithm{}),
+      fallback:       None,
+      rebalance_until: None,
     }
   }
 
   pub fn import_configuration_state(backend_vec: &Vec<proxy::Backend>) -> BackendList {
     let mut list = BackendList::new();
     for ref backend in backend_vec {
-      let backend = Backend::new(&backend.backend_id, backend.address, backend.sticky_id.clone(), backend.load_balancing_parameters.clone(), backend.backup);
-      list.add_backend(backend);
+      let mut new_backend = Backend::new(&backend.backend_id, backend.address, backend.sticky_id.clone(), backend.load_balancing_parameters.clone(), backend.backup);
+      new_backend.hostname = backend.hostname.clone();
+      list.add_backend(new_backend);
     }
 
     list
@@ -165,8 +365,27 @@ impl BackendList {
     }
   }
 
-  pub fn remove_backend(&mut self, backend_address: &SocketAddr) {
-    self.backends.retain(|backend| &(*backend.borrow()).address != backend_address);
+  /// a backend with a hostname is also removed by id, since its address
+  /// may have changed since the master sent it
+  pub fn remove_backend(&mut self, backend_id: &str, backend_address: &SocketAddr) {
+    self.backends.retain(|backend| {
+      let backend = backend.borrow();
+      &backend.address != backend_address && !(backend.hostname.is_some() && backend.backend_id == backend_id)
+    });
+  }
+
+  pub fn replace_backend_address(&mut self, backend_id: &str, address: SocketAddr) -> Result<(), String> {
+    if self.backends.iter().any(|b| b.borrow().backend_id != backend_id && b.borrow().address == address) {
+      return Err(format!("the address {} is already used by another backend", address));
+    }
+
+    match self.backends.iter().find(|b| b.borrow().backend_id == backend_id) {
+      Some(backend) => {
+        backend.borrow_mut().address = address;
+        Ok(())
+      },
+      None => Err(format!("no backend with id {}", backend_id)),
+    }
   }
 
   pub fn has_backend(&self, backend_address: &SocketAddr) -> bool {
@@ -174,14 +393,29 @@ impl BackendList {
   }
 
   pub fn find_backend(&mut self, backend_address: &SocketAddr) -> Option<&mut Rc<RefCell<Backend>>> {
-    self.backends.iter_mut().find(|backend| &(*backend.borrow()).address == backend_address)
+    self.backends.iter_mut().chain(self.fallback.iter_mut())
+      .find(|backend| &(*backend.borrow()).address == backend_address)
+  }
+
+  /// the fallback backend is kept if its address did not change,
+  /// so its retry policy and connection count are not lost
+  pub fn set_fallback(&mut self, app_id: &str, address: Option<SocketAddr>) {
+    match address {
+      None => self.fallback = None,
+      Some(address) => {
+        if self.fallback.as_ref().map(|fallback| fallback.borrow().address != address).unwrap_or(true) {
+          let backend = Backend::new(&format!("{}-fallback", app_id), address, None, None, None);
+          self.fallback = Some(Rc::new(RefCell::new(backend)));
+        }
+      }
+    }
   }
 
   pub fn find_sticky(&mut self, sticky_session: &str) -> Option<&mut Rc<RefCell<Backend>>> {
     self.backends.iter_mut()
-      .find(|b| b.borrow().sticky_id.as_ref().map(|s| s.as_str()) == Some(sticky_session) )
+      .find(|b| b.borrow().sticky_session_id() == sticky_session)
       .and_then(|b| {
-        if b.borrow().can_open() {
+        if b.borrow().can_open() && !b.borrow().is_ejected(SteadyTime::now()) {
           Some(b)
         } else {
           None
@@ -196,29 +430,129 @@ impl BackendList {
       .collect()
   }
 
-  pub fn next_available_backend(&mut self) -> Option<Rc<RefCell<Backend>>> {
-    let mut backends = self.available_backends(false);
+  pub fn next_available_backend(&mut self, failure_cooldown: Option<Duration>) -> Option<Rc<RefCell<Backend>>> {
+    self.next_untried_backend(failure_cooldown, &[])
+  }
+
+  /// like `next_available_backend`, without the backends whose id is in `tried`
+  pub fn next_untried_backend(&mut self, failure_cooldown: Option<Duration>, tried: &[String]) -> Option<Rc<RefCell<Backend>>> {
+    let is_untried = |backend: &Rc<RefCell<Backend>>| !tried.contains(&backend.borrow().backend_id);
+    let mut backends: Vec<_> = self.available_backends(false).into_iter().filter(&is_untried).collect();
 
     if backends.is_empty() {
-      backends = self.available_backends(true);
+      backends = self.available_backends(true).into_iter().filter(&is_untried).collect();
     }
 
+    let now = SteadyTime::now();
+    if let Some(cooldown) = failure_cooldown {
+      backends = without_recent_failures(backends, cooldown, now);
+    }
+    let (ejected, backends): (Vec<_>, Vec<_>) = backends.into_iter().partition(|backend| backend.borrow().is_ejected(now));
+
     if backends.is_empty() {
-      None
+      let fallback = self.fallback.as_ref().filter(|fallback| fallback.borrow().can_open() && is_untried(fallback)).cloned();
+      if fallback.is_some() {
+        incr!("backend.fallback");
+        fallback
+      } else if !ejected.is_empty() {
+        // refusing the requests would be worse than sending them to an ejected backend
+        self.load_balancing.next_available_backend(&ejected, &mut thread_rng())
+      } else {
+        None
+      }
     } else {
-      self.load_balancing.next_available_backend(&backends)
+      self.select(&backends, now)
+    }
+  }
+
+  fn select(&mut self, backends: &Vec<Rc<RefCell<Backend>>>, now: SteadyTime) -> Option<Rc<RefCell<Backend>>> {
+    if self.rebalance_until.map(|until| now < until).unwrap_or(false) {
+      if !self.connections_are_even() {
+        incr!("backend.rebalanced_connections");
+        return LeastConnectionsAlgorithm{}.next_available_backend(backends, &mut thread_rng());
+      }
+      debug!("the backend connections are even, end of the rebalancing");
+    }
+
+    self.rebalance_until = None;
+    self.load_balancing.next_available_backend(backends, &mut thread_rng())
+  }
+
+  /// the available backends have at most one connection more than each other
+  pub fn connections_are_even(&self) -> bool {
+    let counts = self.backends.iter()
+      .map(|backend| backend.borrow())
+      .filter(|backend| !backend.backup && backend.can_open())
+      .map(|backend| backend.active_connections);
+
+    match (counts.clone().min(), counts.max()) {
+      (Some(min), Some(max)) => max - min <= 1,
+      _                      => true,
     }
   }
 
   pub fn set_load_balancing_policy(&mut self, load_balancing_policy: LoadBalancingAlgorithms) {
     match load_balancing_policy {
-      LoadBalancingAlgorithms::RoundRobin => self.load_balancing = Box::new(RoundRobinAlgorithm{ next_backend: 0 }),
+      LoadBalancingAlgorithms::RoundRobin => self.load_balancing = Box::new(RoundRobinAlgorithm::new()),
       LoadBalancingAlgorithms::Random => self.load_balancing = Box::new(RandomAlgorithm{}),
       LoadBalancingAlgorithms::LeastConnections => self.load_balancing = Box::new(LeastConnectionsAlgorithm{}),
+      LoadBalancingAlgorithms::LeastLatency => self.load_balancing = Box::new(LeastLatencyAlgorithm{}),
     }
   }
 }
 
+/// name of the status in the query answers
+fn status_name(status: &BackendStatus) -> &'static str {
+  match *status {
+    BackendStatus::Normal  => "NORMAL",
+    BackendStatus::Closing => "CLOSING",
+    BackendStatus::Closed  => "CLOSED",
+  }
+}
+
+fn retry_state_name(state: RetryState) -> &'static str {
+  match state {
+    RetryState::Closed   => "CLOSED",
+    RetryState::Open     => "OPEN",
+    RetryState::HalfOpen => "HALF_OPEN",
+  }
+}
+
+/// rounded up to the next second, the backend is not tried before it
+fn unix_timestamp(instant: Instant) -> i64 {
+  let now = Instant::now();
+  let remaining = if instant > now { instant - now } else { StdDuration::default() };
+  let since_epoch = (SystemTime::now() + remaining).duration_since(UNIX_EPOCH).unwrap_or_default();
+  since_epoch.as_secs() as i64 + if since_epoch.subsec_nanos() > 0 { 1 } else { 0 }
+}
+
+/// uses an idle connection to the backend from the pool, or opens a new one
+fn connect(app_id: &str, backend: &mut Backend, pool: &RefCell<ConnectionPool>, socket_config: &SocketConfig) -> Result<TcpStream, ConnectionError> {
+  if backend.status == BackendStatus::Normal {
+    if let Some(socket) = pool.borrow_mut().checkout(app_id, backend, SteadyTime::now()) {
+      backend.inc_connections();
+      return Ok(socket);
+    }
+  }
+
+  backend.try_connect(socket_config)
+}
+
+/// removes the backends that failed a connection less than `cooldown` ago,
+/// unless all of them did. Unlike the retry policy, the cooldown only
+/// changes the order of preference between the available backends
+pub fn without_recent_failures(backends: Vec<Rc<RefCell<Backend>>>, cooldown: Duration, now: SteadyTime) -> Vec<Rc<RefCell<Backend>>> {
+  let (cooling_down, ready): (Vec<_>, Vec<_>) = backends.into_iter().partition(|backend| {
+    backend.borrow().last_failure.map(|failure| now - failure < cooldown).unwrap_or(false)
+  });
+
+  if ready.is_empty() {
+    cooling_down
+  } else {
+    ready
+  }
+}
+
 #[cfg(test)]
 mod backends_test {
 
@@ -331,4 +665,457 @@ mod backends_test {
 
     assert_eq!(1, backends_list.backends.len());
   }
+
+  #[test]
+  fn remove_resolved_backend() {
+    let mut backend_map = BackendMap::new();
+    let mut resolved = Backend::new("app_1-0", "127.0.0.1:9001".parse().unwrap(), None, None, None);
+    resolved.hostname = Some(String::from("localhost:9001"));
+    backend_map.add_backend("app_1", resolved);
+    backend_map.add_backend("app_1", Backend::new("app_1-1", "127.0.0.1:9002".parse().unwrap(), None, None, None));
+
+    // the hostname now resolves to another address than the one the master knows
+    backend_map.backends.get("app_1").unwrap().backends[0].borrow_mut().address = "127.0.0.2:9001".parse().unwrap();
+    backend_map.remove_backend("app_1", "app_1-0", &"127.0.0.1:9001".parse().unwrap());
+    // the id is only used for backends with a hostname
+    backend_map.remove_backend("app_1", "app_1-1", &"127.0.0.1:9003".parse().unwrap());
+
+    let backends = &backend_map.backends.get("app_1").unwrap().backends;
+    assert_eq!(backends.len(), 1);
+    assert_eq!(backends[0].borrow().backend_id, "app_1-1");
+  }
+
+  #[test]
+  fn active_connections_of_all_applications() {
+    let mut backend_map = BackendMap::new();
+    backend_map.add_backend("app_1", Backend::new("app_1-0", "127.0.0.1:9001".parse().unwrap(), None, None, None));
+    backend_map.add_backend("app_1", Backend::new("app_1-1", "127.0.0.1:9002".parse().unwrap(), None, None, None));
+    backend_map.add_backend("app_2", Backend::new("app_2-0", "127.0.0.1:9003".parse().unwrap(), None, None, None));
+    assert_eq!(backend_map.active_connections(), 0);
+
+    backend_map.backends["app_1"].backends[0].borrow_mut().active_connections = 2;
+    backend_map.backends["app_1"].backends[1].borrow_mut().active_connections = 1;
+    backend_map.backends["app_2"].backends[0].borrow_mut().active_connections = 4;
+    assert_eq!(backend_map.active_connections(), 7);
+  }
+
+  #[test]
+  fn it_should_replace_a_backend_address_and_keep_its_state() {
+    let mut backend_map = BackendMap::new();
+    let app_id = "myapp";
+    backend_map.add_backend(app_id, Backend::new("myapp-1", "127.0.0.1:9001".parse().unwrap(), None, None, None));
+    backend_map.add_backend(app_id, Backend::new("myapp-2", "127.0.0.1:9002".parse().unwrap(), None, None, None));
+
+    let backend = backend_map.backends[app_id].backends[0].clone();
+    backend.borrow_mut().active_connections = 2;
+    backend.borrow_mut().failures = 1;
+
+    assert!(backend_map.replace_backend_address(app_id, "myapp-1", "127.0.0.2:9001".parse().unwrap()).is_ok());
+    assert_eq!(backend.borrow().address, "127.0.0.2:9001".parse().unwrap());
+    assert_eq!(backend.borrow().active_connections, 2);
+    assert_eq!(backend.borrow().failures, 1);
+
+    // a session connected to the old address still decrements the same backend
+    backend.borrow_mut().dec_connections();
+    assert_eq!(backend_map.backends[app_id].backends[0].borrow().active_connections, 1);
+
+    assert!(backend_map.replace_backend_address(app_id, "myapp-1", "127.0.0.1:9002".parse().unwrap()).is_err());
+    assert!(backend_map.replace_backend_address(app_id, "myapp-3", "127.0.0.3:9001".parse().unwrap()).is_err());
+    assert!(backend_map.replace_backend_address("other", "myapp-1", "127.0.0.3:9001".parse().unwrap()).is_err());
+  }
+
+  #[test]
+  fn sticky_sessions_pin_reuse_and_fall_back() {
+    let mut backend_map = BackendMap::new();
+    let app_id = "myapp";
+
+    let (sender1, receiver1) = channel();
+    run_mock_tcp_server("127.0.0.1:1067", receiver1);
+    let (sender2, receiver2) = channel();
+    run_mock_tcp_server("127.0.0.1:1068", receiver2);
+
+    // without a sticky_id, the backend id is used as cookie value
+    backend_map.add_backend(app_id, Backend::new("myapp-1", "127.0.0.1:1067".parse().unwrap(), None, None, None));
+    backend_map.add_backend(app_id, Backend::new("myapp-2", "127.0.0.1:1068".parse().unwrap(), Some("sticky-2".to_string()), None, None));
+
+    // first request: the cookie pins the selected backend
+    let (backend, _conn) = backend_map.backend_from_app_id(app_id).expect("should select a backend");
+    let sticky_id = backend.borrow().sticky_session_id().to_string();
+    let expected = if backend.borrow().backend_id == "myapp-1" { "myapp-1" } else { "sticky-2" };
+    assert_eq!(sticky_id, expected);
+
+    // next requests carrying the cookie go to the same backend
+    for _ in 0..4 {
+      let (sticky_backend, _conn) = backend_map.backend_from_sticky_session(app_id, &sticky_id).expect("should find the sticky backend");
+      assert!(Rc::ptr_eq(&sticky_backend, &backend));
+    }
+
+    // the pinned backend is unavailable: another one is selected, with a different cookie
+    backend.borrow_mut().set_closing();
+    let (other, _conn) = backend_map.backend_from_sticky_session(app_id, &sticky_id).expect("should fall back to another backend");
+    assert!(!Rc::ptr_eq(&other, &backend));
+    assert!(other.borrow().sticky_session_id() != sticky_id);
+
+    sender1.send(()).unwrap();
+    sender2.send(()).unwrap();
+  }
+
+  #[test]
+  fn recently_failed_backends_are_used_last() {
+    let mut backends_list = BackendList::new();
+    backends_list.add_backend(Backend::new("myback-1", "127.0.0.1:1073".parse().unwrap(), None, None, None));
+    backends_list.add_backend(Backend::new("myback-2", "127.0.0.1:1074".parse().unwrap(), None, None, None));
+    let cooldown = Some(Duration::seconds(60));
+
+    let failed = backends_list.backends[0].clone();
+    failed.borrow_mut().last_failure = Some(SteadyTime::now());
+
+    // the other backend is preferred while the failed one cools down
+    for _ in 0..10 {
+      let backend = backends_list.next_available_backend(cooldown).expect("should find a backend");
+      assert_eq!(backend.borrow().backend_id, "myback-2");
+    }
+
+    // without a cooldown, the failed backend can still be selected by the load balancing
+    assert!((0..100).any(|_| {
+      backends_list.next_available_backend(None).map(|b| b.borrow().backend_id == "myback-1").unwrap_or(false)
+    }));
+
+    // it is used when it is the only option
+    backends_list.backends[1].borrow_mut().set_closing();
+    let backend = backends_list.next_available_backend(cooldown).expect("should fall back to the failed backend");
+    assert_eq!(backend.borrow().backend_id, "myback-1");
+
+    // all the backends failed recently
+    backends_list.backends[1].borrow_mut().status = ::BackendStatus::Normal;
+    backends_list.backends[1].borrow_mut().last_failure = Some(SteadyTime::now());
+    assert!(backends_list.next_available_backend(cooldown).is_some());
+
+    // once the cooldown expired, it is selected again
+    failed.borrow_mut().last_failure = Some(SteadyTime::now() - Duration::seconds(120));
+    let backend = backends_list.next_available_backend(cooldown).expect("should find a backend");
+    assert_eq!(backend.borrow().backend_id, "myback-1");
+  }
+
+  #[test]
+  fn draining_backends() {
+    let mut backend_map = BackendMap::new();
+    let app_id = "myapp";
+    backend_map.add_backend(app_id, Backend::new("myapp-1", "127.0.0.1:1084".parse().unwrap(), None, None, None));
+    backend_map.add_backend(app_id, Backend::new("myapp-2", "127.0.0.1:1085".parse().unwrap(), None, None, None));
+    let busy = backend_map.backends[app_id].backends[0].clone();
+    let idle = backend_map.backends[app_id].backends[1].clone();
+    busy.borrow_mut().inc_connections();
+    busy.borrow_mut().inc_connections();
+
+    assert!(backend_map.drain_backend(app_id, "myapp-3").is_err());
+    assert!(backend_map.drain_backend("otherapp", "myapp-1").is_err());
+
+    // without connections, the backend is closed right away
+    assert!(backend_map.drain_backend(app_id, "myapp-2").is_ok());
+    assert_eq!(idle.borrow().status, ::BackendStatus::Closed);
+
+    // the current connections are kept, but new ones are not routed there
+    assert!(backend_map.drain_backend(app_id, "myapp-1").is_ok());
+    assert_eq!(busy.borrow().status, ::BackendStatus::Closing);
+    assert_eq!(busy.borrow_mut().inc_connections(), None);
+    assert!(backend_map.backends.get_mut(app_id).unwrap().next_available_backend(None).is_none());
+
+    backend_map.close_backend_connection(app_id, &"127.0.0.1:1084".parse().unwrap());
+    assert_eq!(busy.borrow().status, ::BackendStatus::Closing);
+    backend_map.close_backend_connection(app_id, &"127.0.0.1:1084".parse().unwrap());
+    assert_eq!(busy.borrow().status, ::BackendStatus::Closed);
+    assert_eq!(busy.borrow().active_connections, 0);
+  }
+
+  #[test]
+  fn rebalancing_favors_the_new_backend() {
+    let mut backend_map = BackendMap::new();
+    let app_id = "myapp";
+    backend_map.add_backend(app_id, Backend::new("myapp-1", "127.0.0.1:1086".parse().unwrap(), None, None, None));
+    backend_map.add_backend(app_id, Backend::new("myapp-2", "127.0.0.1:1087".parse().unwrap(), None, None, None));
+    for backend in backend_map.backends[app_id].backends.iter() {
+      backend.borrow_mut().active_connections = 10;
+    }
+    backend_map.add_backend(app_id, Backend::new("myapp-3", "127.0.0.1:1088".parse().unwrap(), None, None, None));
+
+    assert!(backend_map.rebalance("otherapp", Duration::seconds(60)).is_err());
+    assert!(backend_map.rebalance(app_id, Duration::seconds(60)).is_ok());
+
+    // the new backend gets the new connections until it caught up
+    let list = backend_map.backends.get_mut(app_id).unwrap();
+    for _ in 0..9 {
+      let backend = list.next_available_backend(None).expect("should find a backend");
+      assert_eq!(backend.borrow().backend_id, "myapp-3");
+      backend.borrow_mut().inc_connections();
+    }
+    assert!(list.rebalance_until.is_some());
+
+    // the existing connections were not moved
+    assert_eq!(list.backends[0].borrow().active_connections, 10);
+    assert_eq!(list.backends[1].borrow().active_connections, 10);
+
+    // once the counts are even, the load balancing policy applies again
+    assert!(list.connections_are_even());
+    assert!(list.next_available_backend(None).is_some());
+    assert!(list.rebalance_until.is_none());
+    assert!((0..100).any(|_| {
+      list.next_available_backend(None).map(|b| b.borrow().backend_id != "myapp-3").unwrap_or(false)
+    }));
+  }
+
+  #[test]
+  fn rebalancing_ends_after_its_duration() {
+    let mut list = BackendList::new();
+    list.add_backend(Backend::new("myback-1", "127.0.0.1:1089".parse().unwrap(), None, None, None));
+    list.add_backend(Backend::new("myback-2", "127.0.0.1:1090".parse().unwrap(), None, None, None));
+    list.backends[0].borrow_mut().active_connections = 10;
+    assert!(!list.connections_are_even());
+
+    list.rebalance_until = Some(SteadyTime::now() - Duration::seconds(1));
+    assert!((0..100).any(|_| {
+      list.next_available_backend(None).map(|b| b.borrow().backend_id == "myback-1").unwrap_or(false)
+    }));
+    assert!(list.rebalance_until.is_none());
+  }
+
+  #[test]
+  fn reset_failed_backend() {
+    use retry::RetryPolicy;
+
+    let mut backend_map = BackendMap::new();
+    let app_id = "myapp";
+    backend_map.add_backend(app_id, Backend::new("myapp-1", "127.0.0.1:1084".parse().unwrap(), None, None, None));
+    backend_map.add_backend(app_id, Backend::new("myapp-2", "127.0.0.1:1085".parse().unwrap(), None, None, None));
+    let failed = backend_map.backends[app_id].backends[0].clone();
+    let drained = backend_map.backends[app_id].backends[1].clone();
+
+    for backend in &[&failed, &drained] {
+      let mut backend = backend.borrow_mut();
+      backend.retry_policy.fail();
+      backend.failures += 1;
+      backend.last_failure = Some(SteadyTime::now());
+      assert!(!backend.can_open());
+    }
+    assert!(backend_map.drain_backend(app_id, "myapp-2").is_ok());
+
+    assert!(backend_map.reset_backend(app_id, "myapp-3").is_err());
+    assert!(backend_map.reset_backend("otherapp", "myapp-1").is_err());
+
+    assert!(backend_map.reset_backend(app_id, "myapp-1").is_ok());
+    assert!(failed.borrow().can_open());
+    assert_eq!(failed.borrow().failures, 0);
+    assert_eq!(failed.borrow().last_failure, None);
+    assert_eq!(failed.borrow().retry_policy.current_tries(), 0);
+
+    // a drained backend stays drained
+    assert!(backend_map.reset_backend(app_id, "myapp-2").is_ok());
+    assert_eq!(drained.borrow().status, ::BackendStatus::Closed);
+    assert!(!drained.borrow().can_open());
+  }
+
+  #[test]
+  fn untried_backends() {
+    let mut backends_list = BackendList::new();
+    backends_list.add_backend(Backend::new("myback-1", "127.0.0.1:1079".parse().unwrap(), None, None, None));
+    backends_list.add_backend(Backend::new("myback-2", "127.0.0.1:1080".parse().unwrap(), None, None, None));
+    backends_list.add_backend(Backend::new("myback-3", "127.0.0.1:1081".parse().unwrap(), None, None, Some(true)));
+    backends_list.set_fallback("myapp", Some("127.0.0.1:1082".parse().unwrap()));
+
+    // the load balancing picks between the normal backends that were not tried yet
+    let mut tried = Vec::new();
+    for _ in 0..2 {
+      let backend = backends_list.next_untried_backend(None, &tried).expect("should find a backend");
+      assert!(!tried.contains(&backend.borrow().backend_id));
+      tried.push(backend.borrow().backend_id.clone());
+    }
+
+    // then the backups and the fallback
+    let backend = backends_list.next_untried_backend(None, &tried).expect("should find a backend");
+    assert_eq!(backend.borrow().backend_id, "myback-3");
+    tried.push(backend.borrow().backend_id.clone());
+    let backend = backends_list.next_untried_backend(None, &tried).expect("should use the fallback backend");
+    assert_eq!(backend.borrow().backend_id, "myapp-fallback");
+    tried.push(backend.borrow().backend_id.clone());
+
+    assert!(backends_list.next_untried_backend(None, &tried).is_none());
+    assert!(backends_list.next_available_backend(None).is_some());
+  }
+
+  #[test]
+  fn fallback_backend_when_no_backend_is_available() {
+    use retry::RetryPolicy;
+
+    let mut backends_list = BackendList::new();
+    backends_list.add_backend(Backend::new("myback-1", "127.0.0.1:1075".parse().unwrap(), None, None, None));
+    backends_list.add_backend(Backend::new("myback-2", "127.0.0.1:1076".parse().unwrap(), None, None, Some(true)));
+    backends_list.set_fallback("myapp", Some("127.0.0.1:1077".parse().unwrap()));
+    let primary = backends_list.backends[0].clone();
+    let backup = backends_list.backends[1].clone();
+    let fallback = backends_list.fallback.clone().expect("there should be a fallback backend");
+
+    // the normal and backup backends come first
+    let backend = backends_list.next_available_backend(None).expect("should find a backend");
+    assert_eq!(backend.borrow().backend_id, "myback-1");
+    primary.borrow_mut().retry_policy().fail();
+    let backend = backends_list.next_available_backend(None).expect("should find a backend");
+    assert_eq!(backend.borrow().backend_id, "myback-2");
+
+    backup.borrow_mut().set_closing();
+    let backend = backends_list.next_available_backend(None).expect("should use the fallback backend");
+    assert!(Rc::ptr_eq(&backend, &fallback));
+    assert_eq!(backend.borrow().backend_id, "myapp-fallback");
+
+    // the fallback has its own retry policy
+    fallback.borrow_mut().retry_policy().fail();
+    assert!(backends_list.next_available_backend(None).is_none());
+    fallback.borrow_mut().retry_policy().succeed();
+
+    // back to the primary backend once it recovers
+    primary.borrow_mut().retry_policy().succeed();
+    let backend = backends_list.next_available_backend(None).expect("should find a backend");
+    assert_eq!(backend.borrow().backend_id, "myback-1");
+
+    // the fallback keeps its state if the application is updated with the same address
+    fallback.borrow_mut().active_connections = 1;
+    backends_list.set_fallback("myapp", Some("127.0.0.1:1077".parse().unwrap()));
+    assert!(Rc::ptr_eq(backends_list.fallback.as_ref().unwrap(), &fallback));
+    backends_list.set_fallback("myapp", Some("127.0.0.1:1078".parse().unwrap()));
+    assert_eq!(backends_list.fallback.as_ref().unwrap().borrow().active_connections, 0);
+    backends_list.set_fallback("myapp", None);
+    assert!(backends_list.fallback.is_none());
+  }
+
+  #[test]
+  fn fallback_backend_connections() {
+    let mut backend_map = BackendMap::new();
+    let app_id = "myapp";
+
+    let fallback_server = TcpListener::bind("127.0.0.1:0").expect("could not bind");
+    let fallback_address = fallback_server.local_addr().unwrap();
+    let primary_server = TcpListener::bind("127.0.0.1:0").expect("could not bind");
+    let primary_address = primary_server.local_addr().unwrap();
+
+    backend_map.set_fallback_backend_for_app(app_id, Some(fallback_address));
+
+    // the application has no backend yet
+    let (backend, _conn) = backend_map.backend_from_app_id(app_id).expect("should connect to the fallback");
+    assert_eq!(backend.borrow().address, fallback_address);
+    assert_eq!(backend.borrow().active_connections, 1);
+    backend_map.close_backend_connection(app_id, &fallback_address);
+    assert_eq!(backend.borrow().active_connections, 0);
+
+    backend_map.add_backend(app_id, Backend::new("myapp-1", primary_address, None, None, None));
+    let (backend, _conn) = backend_map.backend_from_app_id(app_id).expect("should connect to the backend");
+    assert_eq!(backend.borrow().address, primary_address);
+  }
+
+  #[test]
+  fn outlier_ejection() {
+    use outlier::set_outlier_detection;
+    use sozu_command::config::OutlierDetection;
+
+    set_outlier_detection(Some(OutlierDetection { error_percentage: 50, min_requests: 4, window: 10, ejection_time: 30 }));
+    let mut backends_list = BackendList::new();
+    backends_list.add_backend(Backend::new("myback-1", "127.0.0.1:1079".parse().unwrap(), None, None, None));
+    backends_list.add_backend(Backend::new("myback-2", "127.0.0.1:1080".parse().unwrap(), None, None, None));
+    let failing = backends_list.backends[0].clone();
+
+    // 5xx answers do not change the retry policy, but eject the backend
+    let now = SteadyTime::now();
+    failing.borrow_mut().record_outcome(false, now);
+    for _ in 0..3 {
+      failing.borrow_mut().record_outcome(true, now);
+    }
+    assert!(failing.borrow().can_open());
+    assert!(failing.borrow().is_ejected(now));
+    for _ in 0..10 {
+      let backend = backends_list.next_available_backend(None).expect("should find a backend");
+      assert_eq!(backend.borrow().backend_id, "myback-2");
+    }
+
+    // an ejected backend is still used if all the other ones are
+    backends_list.backends[1].borrow_mut().set_closing();
+    let backend = backends_list.next_available_backend(None).expect("should find a backend");
+    assert_eq!(backend.borrow().backend_id, "myback-1");
+    backends_list.backends[1].borrow_mut().status = BackendStatus::Normal;
+
+    // readmitted once the ejection time is over
+    failing.borrow_mut().outlier.ejected_until = Some(SteadyTime::now() - Duration::seconds(1));
+    assert!(!failing.borrow().is_ejected(SteadyTime::now()));
+    assert!((0..50).any(|_| {
+      backends_list.next_available_backend(None).map(|b| b.borrow().backend_id == "myback-1").unwrap_or(false)
+    }));
+
+    // resetting the backend readmits it too
+    for _ in 0..4 {
+      failing.borrow_mut().record_outcome(true, now);
+    }
+    assert!(failing.borrow().is_ejected(now));
+    failing.borrow_mut().reset();
+    assert!(!failing.borrow().is_ejected(now));
+
+    set_outlier_detection(None);
+  }
+
+  #[test]
+  fn retry_budget() {
+    let mut backend_map = BackendMap::new();
+    assert!((0..100).all(|_| backend_map.take_retry("app_1")));
+
+    backend_map.retry_budget = Some(RetryBudget { percentage: 20, min_retries: 1, window: 10 });
+    for _ in 0..10 {
+      backend_map.record_request("app_1");
+    }
+    assert!(backend_map.take_retry("app_1"));
+    assert!(backend_map.take_retry("app_1"));
+    assert!(!backend_map.take_retry("app_1"));
+
+    // each application has its own budget
+    assert!(backend_map.take_retry("app_2"));
+    assert!(!backend_map.take_retry("app_2"));
+
+    backend_map.remove_application("app_1");
+    assert!(backend_map.take_retry("app_1"));
+  }
+
+  #[test]
+  fn backend_stats() {
+    use retry::RetryPolicy;
+
+    let mut backend_map = BackendMap::new();
+    let app_id = "myapp";
+    backend_map.add_backend(app_id, Backend::new("myapp-1", "127.0.0.1:9001".parse().unwrap(), None, None, None));
+
+    let backend = backend_map.backends[app_id].backends[0].clone();
+    backend.borrow_mut().active_connections = 3;
+    backend.borrow_mut().failures = 2;
+
+    let stats = backend_map.backend_stats(app_id, "myapp-1").expect("the backend should be found");
+    assert_eq!(stats.address, "127.0.0.1:9001".parse().unwrap());
+    assert_eq!(stats.status, "NORMAL");
+    assert_eq!(stats.active_connections, 3);
+    assert_eq!(stats.failures, 2);
+    assert!(stats.available);
+    assert_eq!(stats.retry_state, "CLOSED");
+    assert_eq!(stats.next_retry, None);
+
+    // a failed connection puts the backend in backoff
+    backend.borrow_mut().retry_policy.fail();
+    let stats = backend_map.backend_stats(app_id, "myapp-1").expect("the backend should be found");
+    assert_eq!(stats.retry_state, "OPEN");
+    assert!(!stats.available);
+    let now = ::time::get_time().sec;
+    let next_retry = stats.next_retry.expect("the backend should have a next retry time");
+    assert!(next_retry > now && next_retry <= now + 2, "next retry {} at {}", next_retry, now);
+    backend.borrow_mut().retry_policy.succeed();
+
+    backend.borrow_mut().set_closing();
+    let stats = backend_map.backend_stats(app_id, "myapp-1").expect("the backend should be found");
+    assert_eq!(stats.status, "CLOSING");
+    assert!(!stats.available);
+
+    assert!(backend_map.backend_stats(app_id, "myapp-2").is_none());
+    assert!(backend_map.backend_stats("other", "myapp-1").is_none());
+  }
 }
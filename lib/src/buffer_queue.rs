@@ -1,10 +1,21 @@
 use sozu_command::buffer::Buffer;
 use pool_crate::Reset;
 use std::io::{self,Write};
+use std::cell::Cell;
 use std::cmp::{min,max};
 use std::{fmt,str};
 use pool::{Pool,Checkout};
 
+thread_local! {
+  static HIGH_WATER_MARK: Cell<Option<usize>> = Cell::new(None);
+}
+
+/// high-water mark of the buffer queues created from now on in this worker, in
+/// bytes. Without one, a session stops reading into a buffer when it is full
+pub fn set_high_water_mark(mark: Option<usize>) {
+  HIGH_WATER_MARK.with(|high_water_mark| high_water_mark.set(mark));
+}
+
 #[derive(Debug,PartialEq,Clone)]
 pub enum InputElement {
   /// length in the stream
@@ -52,20 +63,40 @@ pub struct BufferQueue {
   /// Vec<(start, length)>
   pub input_queue:            Vec<InputElement>,
   pub output_queue:           Vec<OutputElement>,
+  /// bytes received (read or spliced in) since the buffer was checked out
+  bytes_in:                   usize,
+  /// bytes sent (copied, inserted or spliced out) since the buffer was checked out
+  bytes_out:                  usize,
+  /// amount of buffered data above which the session stops reading into the
+  /// buffer until part of it was sent
+  high_water_mark:            usize,
 }
 
 impl BufferQueue {
   pub fn with_buffer(buffer: Checkout<Buffer>) -> BufferQueue {
+    let capacity = buffer.capacity();
+    let high_water_mark = HIGH_WATER_MARK.with(|mark| mark.get())
+      .map(|mark| min(mark, capacity)).unwrap_or(capacity);
+
     BufferQueue {
       buffer_position:        0,
       parsed_position:        0,
       start_parsing_position: 0,
       input_queue:            Vec::with_capacity(8),
       output_queue:           Vec::with_capacity(8),
+      bytes_in:               0,
+      bytes_out:              0,
+      high_water_mark,
       buffer,
     }
   }
 
+  /// there is enough buffered data to stop reading into the buffer, and part
+  /// of it can be sent
+  pub fn is_above_high_water_mark(&self) -> bool {
+    self.buffer.available_data() >= self.high_water_mark && !self.next_output_data().is_empty()
+  }
+
   pub fn invariant(&self) {
     debug_assert!(self.buffer_position <= self.parsed_position,
       "buffer_position {} should be smaller than parsed_position {}",
@@ -83,7 +114,16 @@ impl BufferQueue {
     })
   }
 
+  pub fn bytes_in(&self) -> usize {
+    self.bytes_in
+  }
+
+  pub fn bytes_out(&self) -> usize {
+    self.bytes_out
+  }
+
   pub fn sliced_input(&mut self, count: usize) {
+    self.bytes_in += count;
     let needed = self.start_parsing_position - self.parsed_position;
     if needed > 0 {
       if count > needed {
@@ -104,6 +144,7 @@ impl BufferQueue {
 
   pub fn spliced_input(&mut self, count: usize) {
     //FIXME: do the same thing with needed data as in sliced_input
+    self.bytes_in += count;
     if count > 0 {
       self.input_queue.push(InputElement::Splice(count));
     }
@@ -217,6 +258,23 @@ impl BufferQueue {
     self.output_queue.push(OutputElement::Insert(v));
   }
 
+  /// inserts data that will be written before the rest of the output queue
+  pub fn prepend_output(&mut self, v: Vec<u8>) {
+    self.output_queue.insert(0, OutputElement::Insert(v));
+  }
+
+  /// appends data to the last inserted element, to add
+  /// more headers to the ones inserted at the end of the header
+  pub fn extend_last_insert(&mut self, data: &[u8]) -> bool {
+    for el in self.output_queue.iter_mut().rev() {
+      if let &mut OutputElement::Insert(ref mut v) = el {
+        v.extend_from_slice(data);
+        return true;
+      }
+    }
+    false
+  }
+
   pub fn has_output_data(&self) -> bool {
     self.output_queue.len() > 0
   }
@@ -374,6 +432,7 @@ impl BufferQueue {
 
   /// should only be called with a count inferior to self.input_data_size()
   pub fn consume_output_data(&mut self, size: usize) {
+    self.bytes_out += size;
     let mut to_consume = size;
     while to_consume > 0 {
       let new_first_element = match self.output_queue.first() {
@@ -451,6 +510,7 @@ impl Write for BufferQueue {
       Err(e) => Err(e),
       Ok(sz) => {
         if sz > 0 {
+          self.bytes_in += sz;
           self.input_queue.push(InputElement::Slice(sz));
         }
         Ok(sz)
@@ -471,6 +531,8 @@ impl Reset for BufferQueue {
     self.buffer.reset();
     self.input_queue.clear();
     self.output_queue.clear();
+    self.bytes_in = 0;
+    self.bytes_out = 0;
   }
 }
 
@@ -500,7 +562,7 @@ mod tests {
   #[test]
   #[cfg(target_pointer_width = "64")]
   fn size_test() {
-    assert_size!(BufferQueue, 88);
+    assert_size!(BufferQueue, 112);
     assert_size!(Buffer, 48);
   }
 
@@ -593,4 +655,71 @@ mod tests {
 
     b.write(&b"KLMNOP"[..]).unwrap();
   }
+
+  #[test]
+  fn bytes_transferred() {
+    let (_pool, mut b) = buf_with_capacity(32);
+    b.write(&b"GET / HTTP/1.1\r\n"[..]).unwrap();
+    assert_eq!(b.bytes_in(), 16);
+    assert_eq!(b.bytes_out(), 0);
+
+    // the data is read from a socket directly in the buffer
+    b.buffer.write(&b"ABCDEFGH"[..]).unwrap();
+    b.sliced_input(8);
+    assert_eq!(b.bytes_in(), 24);
+
+    // 6 bytes of the input are removed, and 4 bytes are inserted
+    b.consume_parsed_data(24);
+    b.slice_output(16);
+    b.delete_output(6);
+    b.insert_output(Vec::from(&b"WXYZ"[..]));
+    b.slice_output(2);
+    assert_eq!(b.output_data_size(), 22);
+
+    b.consume_output_data(10);
+    assert_eq!(b.bytes_out(), 10);
+    b.consume_output_data(12);
+    assert_eq!(b.bytes_out(), 22);
+    assert!(!b.has_output_data());
+    assert_eq!(b.bytes_in(), 24);
+
+    b.reset();
+    assert_eq!(b.bytes_in(), 0);
+    assert_eq!(b.bytes_out(), 0);
+  }
+
+  #[test]
+  fn high_water_mark() {
+    set_high_water_mark(Some(8));
+    let (_pool, mut b) = buf_with_capacity(16);
+    set_high_water_mark(None);
+
+    b.write(&b"ABCDEFGHIJ"[..]).unwrap();
+    // the data cannot be sent yet, so the buffer keeps being filled
+    assert!(!b.is_above_high_water_mark());
+    b.consume_parsed_data(10);
+    b.slice_output(10);
+    assert!(b.is_above_high_water_mark());
+
+    b.consume_output_data(2);
+    assert!(b.is_above_high_water_mark());
+    b.consume_output_data(1);
+    assert!(!b.is_above_high_water_mark());
+
+    // the mark cannot be above the capacity of the buffer
+    set_high_water_mark(Some(64));
+    let (_pool, mut b) = buf_with_capacity(16);
+    set_high_water_mark(None);
+    b.write(&b"ABCDEFGHIJKLMNOP"[..]).unwrap();
+    b.consume_parsed_data(16);
+    b.slice_output(16);
+    assert!(b.is_above_high_water_mark());
+
+    // without a mark, the session reads until the buffer is full
+    let (_pool, mut b) = buf_with_capacity(16);
+    b.write(&b"ABCDEFGHIJ"[..]).unwrap();
+    b.consume_parsed_data(10);
+    b.slice_output(10);
+    assert!(!b.is_above_high_water_mark());
+  }
 }
@@ -0,0 +1,152 @@
+//! soft limit on the number of file descriptors used by a worker
+//!
+//! If a worker runs out of file descriptors, `accept()` and `connect()` fail
+//! with EMFILE at random points of the session lifecycle. To avoid this, the
+//! worker stops accepting new connections (load shedding) once its file
+//! descriptor usage reaches the soft limit, and resumes when enough of them
+//! were closed.
+
+/// file descriptors used by the worker that we do not track as sessions:
+/// standard streams, logging, the event loop, the command and scm sockets...
+pub const RESERVED_FDS: usize = 16;
+
+pub struct FdLimit {
+  soft_limit: Option<usize>,
+  shedding:   bool,
+}
+
+impl FdLimit {
+  pub fn new(soft_limit: Option<usize>) -> FdLimit {
+    FdLimit {
+      soft_limit,
+      shedding: false,
+    }
+  }
+
+  /// uses the configured limit if there is one, otherwise 90% of RLIMIT_NOFILE
+  pub fn from_config(soft_limit: Option<usize>) -> FdLimit {
+    let soft_limit = soft_limit.or_else(|| nofile_limit().map(|limit| limit * 90 / 100));
+    if let (Some(soft), Some(hard)) = (soft_limit, nofile_limit()) {
+      if soft >= hard {
+        warn!("the file descriptor soft limit ({}) is above the open files limit ({}), \
+          the worker might fail with EMFILE errors", soft, hard);
+      }
+    }
+
+    FdLimit::new(soft_limit)
+  }
+
+  pub fn soft_limit(&self) -> Option<usize> {
+    self.soft_limit
+  }
+
+  pub fn is_shedding(&self) -> bool {
+    self.shedding
+  }
+
+  /// returns true if the worker can open a new connection, while `open_fds`
+  /// file descriptors are in use. Once the soft limit is reached, new
+  /// connections are refused until the usage goes back under 90% of the limit
+  pub fn can_accept(&mut self, open_fds: usize) -> bool {
+    let soft_limit = match self.soft_limit {
+      Some(limit) => limit,
+      None        => return true,
+    };
+
+    gauge!("fd.open", open_fds);
+
+    if self.shedding {
+      if open_fds < soft_limit * 90 / 100 {
+        info!("{} file descriptors in use (soft limit: {}), accepting new connections again",
+          open_fds, soft_limit);
+        gauge!("fd.shedding", 0);
+        self.shedding = false;
+      }
+    } else if open_fds >= soft_limit {
+      warn!("{} file descriptors in use, reaching the soft limit of {}, refusing new connections",
+        open_fds, soft_limit);
+      incr!("fd.soft_limit_reached");
+      gauge!("fd.shedding", 1);
+      self.shedding = true;
+    }
+
+    !self.shedding
+  }
+}
+
+/// current RLIMIT_NOFILE of the process, None if unlimited or unavailable
+pub fn nofile_limit() -> Option<usize> {
+  let mut limit = ::libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+  let res = unsafe { ::libc::getrlimit(::libc::RLIMIT_NOFILE, &mut limit) };
+  if res != 0 || limit.rlim_cur == ::libc::RLIM_INFINITY {
+    None
+  } else {
+    Some(limit.rlim_cur as usize)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::net::TcpListener;
+
+  #[test]
+  fn shedding_starts_at_the_soft_limit() {
+    let mut limit = FdLimit::new(Some(100));
+
+    for open_fds in 0..100 {
+      assert!(limit.can_accept(open_fds));
+    }
+    assert!(!limit.can_accept(100));
+    assert!(limit.is_shedding());
+
+    // not enough descriptors were closed yet
+    assert!(!limit.can_accept(95));
+    assert!(!limit.can_accept(90));
+    assert!(limit.can_accept(89));
+    assert!(!limit.is_shedding());
+
+    let mut unlimited = FdLimit::new(None);
+    assert!(unlimited.can_accept(1_000_000));
+  }
+
+  #[test]
+  fn shedding_happens_before_emfile() {
+    let hard_limit = match nofile_limit() {
+      Some(limit) => limit,
+      None        => return,
+    };
+
+    let mut limit = FdLimit::from_config(None);
+    let soft_limit = limit.soft_limit().expect("there should be a default limit");
+    assert!(soft_limit < hard_limit);
+
+    // file descriptors already opened by the test harness
+    let base = match ::std::fs::read_dir("/proc/self/fd") {
+      Ok(entries) => entries.count(),
+      Err(_)      => RESERVED_FDS,
+    };
+
+    // avoid opening too many sockets on hosts with a large limit
+    if soft_limit > 65536 {
+      return;
+    }
+
+    // open sockets like a worker accepting connections, until the limit refuses them
+    let mut sockets = Vec::new();
+    loop {
+      if !limit.can_accept(base + sockets.len()) {
+        break;
+      }
+
+      match TcpListener::bind("127.0.0.1:0") {
+        Ok(socket) => sockets.push(socket),
+        Err(e) => panic!("could not open a socket before reaching the soft limit: {:?} ({} sockets)",
+          e, sockets.len()),
+      }
+    }
+
+    assert!(limit.is_shedding());
+    assert_eq!(base + sockets.len(), soft_limit);
+  }
+}
@@ -3,7 +3,7 @@ use std::io::ErrorKind;
 use std::rc::{Rc,Weak};
 use std::cell::RefCell;
 use std::os::unix::io::IntoRawFd;
-use std::net::{SocketAddr,Shutdown};
+use std::net::{IpAddr,SocketAddr,Shutdown};
 use std::str::from_utf8_unchecked;
 use mio::*;
 use mio::net::*;
@@ -16,24 +16,30 @@ use mio_extras::timer::{Timer, Timeout};
 
 use sozu_command::scm_socket::{Listeners,ScmSocket};
 use sozu_command::proxy::{Application,ProxyRequestData,HttpFront,HttpListener,
-  ProxyRequest,ProxyResponse,ProxyResponseStatus,ProxyEvent};
+  ProxyRequest,ProxyResponse,ProxyResponseStatus,ProxyEvent,ListenerTimeouts,ConnectionRateLimit,UnexpectedBodyPolicy,
+  ListenerRouteMatch,ClientIpConfig,TrafficSplitPercentage,HeaderLimits,ResponseCompression};
 use sozu_command::logging;
+use sozu_command::config::ProxyProtocolConfig;
+use sozu_command::state::ConfigState;
 use sozu_command::buffer::Buffer;
 
-use super::{AppId,Backend,SessionResult,ConnectionError,Protocol,Readiness,SessionMetrics,
+use super::{AppId,SessionResult,ConnectionError,Protocol,Readiness,SessionMetrics,
   ProxySession,ProxyConfiguration,AcceptError,BackendConnectAction,BackendConnectionStatus,
-  CloseResult};
+  CloseResult,ConnectionGuard};
 use super::backends::BackendMap;
+use super::request_limit::{self, Admission};
+use super::traffic_split;
+use super::backend_pool::ConnectionPool;
 use super::pool::Pool;
 use super::protocol::{ProtocolResult,StickySession,Http,Pipe};
-use super::protocol::http::{DefaultAnswerStatus, TimeoutStatus, answers::{DefaultAnswers, CustomAnswers, HttpAnswers}};
+use super::protocol::http::{DefaultAnswerStatus, TimeoutStatus, FrontendRule, frontend_match, answers::{DefaultAnswers, CustomAnswers, HttpAnswers, connection_error_status}};
 use super::protocol::proxy_protocol::expect::ExpectProxyProtocol;
 use super::server::{Server,ProxyChannel,ListenToken,ListenPortState,SessionToken,
-  ListenSession, CONN_RETRIES, push_event};
-use super::socket::server_bind;
+  ListenSession, push_event};
+use super::socket::{server_bind,UnixSocketListener};
 use super::retry::RetryPolicy;
-use super::protocol::http::parser::{hostname_and_port, RequestState};
-use super::trie::TrieNode;
+use super::protocol::http::parser::{hostname_and_port, hostname_without_port, header_value, request_cookie, RequestState, ResponseState};
+use super::trie::{TrieNode,InsertResult};
 use util::UnwrapLog;
 
 #[derive(PartialEq)]
@@ -50,24 +56,39 @@ pub enum State {
 
 pub struct Session {
   frontend_token:     Token,
-  backend:            Option<Rc<RefCell<Backend>>>,
+  backend:            Option<ConnectionGuard>,
   back_connected:     BackendConnectionStatus,
   protocol:           Option<State>,
   pool:               Weak<RefCell<Pool<Buffer>>>,
   metrics:            SessionMetrics,
   pub app_id:         Option<String>,
   sticky_name:        String,
+  preserve_proxy_connection: bool,
+  ignore_unsupported_expect: bool,
+  unexpected_body_policy: UnexpectedBodyPolicy,
+  client_ip:          Rc<ClientIpConfig>,
+  header_limits:      HeaderLimits,
+  max_keepalive_requests: Option<usize>,
+  compression:        Option<Rc<ResponseCompression>>,
   front_timeout:      Timeout,
   last_event:         SteadyTime,
   pub listen_token:   Token,
   connection_attempt: u8,
+  /// ids of the backends the current request failed to connect to
+  tried_backends:     Vec<String>,
   answers:            Rc<RefCell<HttpAnswers>>,
+  proxy_protocol_client: Option<IpAddr>,
+  /// receives the idle backend connections when the session ends
+  connection_pool:    Weak<RefCell<ConnectionPool>>,
 }
 
 impl Session {
   pub fn new(sock: TcpStream, token: Token, pool: Weak<RefCell<Pool<Buffer>>>,
-    public_address: SocketAddr, expect_proxy: bool, sticky_name: String, timeout: Timeout,
-    answers: Rc<RefCell<HttpAnswers>>, listen_token: Token, delay: Duration) -> Option<Session> {
+    public_address: SocketAddr, expect_proxy: bool, sticky_name: String, preserve_proxy_connection: bool,
+    ignore_unsupported_expect: bool, unexpected_body_policy: UnexpectedBodyPolicy, client_ip: Rc<ClientIpConfig>,
+    header_limits: HeaderLimits, max_keepalive_requests: Option<usize>,
+    compression: Option<Rc<ResponseCompression>>, timeout: Timeout, answers: Rc<RefCell<HttpAnswers>>, listen_token: Token, delay: Duration,
+    connection_pool: Weak<RefCell<ConnectionPool>>) -> Option<Session> {
     let request_id = Uuid::new_v4().to_hyphenated();
     let protocol = if expect_proxy {
       trace!("starting in expect proxy state");
@@ -76,8 +97,14 @@ impl Session {
     } else {
       gauge_add!("protocol.http", 1);
       let session_address = sock.peer_addr().ok();
-      Some(State::Http(Http::new(sock, token, request_id, pool.clone(), public_address,
-        session_address, sticky_name.clone(), Protocol::HTTP)))
+      let mut http = Http::new(sock, token, request_id, pool.clone(), public_address,
+        session_address, sticky_name.clone(), preserve_proxy_connection, ignore_unsupported_expect,
+        unexpected_body_policy, Protocol::HTTP);
+      http.client_ip = client_ip.clone();
+      http.header_limits = header_limits;
+      http.set_max_keepalive_requests(max_keepalive_requests);
+      http.compression = compression.clone();
+      Some(State::Http(http))
     };
 
     let metrics = SessionMetrics::new(Some(delay));
@@ -92,11 +119,21 @@ impl Session {
         metrics,
         app_id:             None,
         sticky_name,
+        preserve_proxy_connection,
+        ignore_unsupported_expect,
+        unexpected_body_policy,
+        client_ip,
+        header_limits,
+        max_keepalive_requests,
+        compression,
         front_timeout:      timeout,
         last_event:         SteadyTime::now(),
         listen_token,
         connection_attempt: 0,
+        tried_backends:     Vec::new(),
         answers,
+        proxy_protocol_client: None,
+        connection_pool,
       };
 
       session.front_readiness().interest = UnixReady::from(Ready::readable()) | UnixReady::hup() | UnixReady::error();
@@ -105,6 +142,39 @@ impl Session {
     })
   }
 
+  /// gives an idle keep-alive backend connection to the connection pool instead of
+  /// closing it. The socket is removed from the session if the pool takes it
+  fn pool_backend_connection(&mut self, poll: &mut Poll) {
+    let connection_pool = match self.connection_pool.upgrade() {
+      Some(connection_pool) => connection_pool,
+      None => return,
+    };
+    let (app_id, backend) = match (self.app_id.as_ref(), self.backend.as_ref()) {
+      (Some(app_id), Some(backend)) => (app_id.clone(), Rc::clone(backend)),
+      _ => return,
+    };
+    if connection_pool.borrow().max_idle == 0 {
+      return;
+    }
+
+    let socket = match self.protocol {
+      Some(State::Http(ref mut http)) if http.is_backend_reusable() => http.backend.take(),
+      _ => None,
+    };
+
+    if let Some(socket) = socket {
+      if let Err(e) = poll.deregister(&socket) {
+        error!("error deregistering back socket({:?}): {:?}", socket, e);
+        return;
+      }
+
+      let result = connection_pool.borrow_mut().checkin(&app_id, &backend.borrow(), socket, SteadyTime::now());
+      if result.is_ok() {
+        debug!("{}\tthe idle backend connection goes to the connection pool", self.log_context());
+      }
+    }
+  }
+
   pub fn upgrade(&mut self) -> bool {
     debug!("HTTP::upgrade");
     let protocol = unwrap_msg!(self.protocol.take());
@@ -156,14 +226,18 @@ impl Session {
       true
     } else if let State::Expect(expect) = protocol {
       debug!("switching to HTTP");
-      if let Some((Some(public_address), Some(client_address))) = expect.addresses.as_ref().map(|add| {
-        (add.destination(), add.source())
-      }) {
+      if let Some((public_address, client_address)) = expect.session_addresses() {
         let readiness = expect.readiness;
         let mut http = Http::new(expect.frontend, expect.frontend_token, expect.request_id,
           self.pool.clone(), public_address, Some(client_address),
-          self.sticky_name.clone(), Protocol::HTTP);
+          self.sticky_name.clone(), self.preserve_proxy_connection, self.ignore_unsupported_expect,
+          self.unexpected_body_policy, Protocol::HTTP);
+        http.client_ip = self.client_ip.clone();
+        http.header_limits = self.header_limits;
+        http.set_max_keepalive_requests(self.max_keepalive_requests);
+        http.compression = self.compression.clone();
         http.front_readiness.event = readiness.event;
+        self.proxy_protocol_client = Some(client_address.ip());
 
         gauge_add!("protocol.proxy.expect", -1);
         gauge_add!("protocol.http", 1);
@@ -203,7 +277,7 @@ impl Session {
   fn front_hup(&mut self) -> SessionResult {
     match *unwrap_msg!(self.protocol.as_mut()) {
       State::Http(ref mut http)      => http.front_hup(),
-      State::WebSocket(ref mut pipe) => pipe.front_hup(&mut self.metrics),
+      State::WebSocket(ref mut pipe) => pipe.front_hup(),
       _                              => SessionResult::CloseSession,
     }
   }
@@ -211,7 +285,7 @@ impl Session {
   fn back_hup(&mut self) -> SessionResult {
     match *unwrap_msg!(self.protocol.as_mut()) {
       State::Http(ref mut http)      => http.back_hup(),
-      State::WebSocket(ref mut pipe) => pipe.back_hup(&mut self.metrics),
+      State::WebSocket(ref mut pipe) => pipe.back_hup(),
       _                              => SessionResult::CloseSession,
     }
   }
@@ -277,6 +351,15 @@ impl Session {
       State::Expect(_)               => return SessionResult::CloseSession,
     };
 
+    // the backend broke its response, the request goes to another one
+    if let SessionResult::ReconnectBackend(_,_) = result {
+      if self.http_mut().map(|http| http.take_backend_failure()).unwrap_or(false) {
+        if let Some(backend_id) = self.backend.as_ref().map(|backend| backend.borrow().backend_id.clone()) {
+          self.tried_backends.push(backend_id);
+        }
+      }
+    }
+
     if upgrade == ProtocolResult::Continue {
       result
     } else if self.upgrade() {
@@ -342,9 +425,12 @@ impl Session {
       self.backend.as_ref().map(|backend| {
         let ref mut backend = *backend.borrow_mut();
         let was_unavailable = backend.retry_policy.is_down();
-        //successful connection, reset failure counter
-        backend.failures = 0;
-        backend.retry_policy.succeed();
+        //successful connection, reset failure counter, unless the failures
+        //come from its answers
+        if !backend.failing_answers {
+          backend.failures = 0;
+          backend.retry_policy.succeed();
+        }
 
         if was_unavailable {
           incr!("up", self.app_id.as_ref().map(|s| s.as_str()), self.metrics.backend_id.as_ref().map(|s| s.as_str()));
@@ -363,10 +449,10 @@ impl Session {
       self.back_token().map(|t| format!("{}", t.0)).unwrap_or_else(|| "-".to_string()));
     */
 
-    if let Some(backend) = self.backend.take() {
+    if let Some(mut backend) = self.backend.take() {
       self.http_mut().map(|h| h.clear_back_token());
 
-      (*backend.borrow_mut()).dec_connections();
+      backend.close();
     }
   }
 
@@ -390,6 +476,8 @@ impl Session {
     self.backend.as_ref().map(|backend| {
       let ref mut backend = *backend.borrow_mut();
       backend.failures += 1;
+      backend.last_failure = Some(SteadyTime::now());
+      backend.record_outcome(true, SteadyTime::now());
 
       let already_unavailable = backend.retry_policy.is_down();
       backend.retry_policy.fail();
@@ -405,6 +493,12 @@ impl Session {
 
   fn reset_connection_attempt(&mut self) {
     self.connection_attempt = 0;
+    self.tried_backends.clear();
+  }
+
+  fn is_idempotent_request(&self) -> bool {
+    self.http().and_then(|http| http.request.as_ref()).and_then(|request| request.get_request_line())
+      .map(|request_line| request_line.method.is_idempotent()).unwrap_or(true)
   }
 }
 
@@ -449,11 +543,34 @@ impl ProxySession for Session {
     result
   }
 
-  fn timeout(&mut self, token: Token, timer: &mut Timer<Token>, front_timeout: &Duration) -> SessionResult {
+  fn timeout(&mut self, token: Token, timer: &mut Timer<Token>, front_timeout: &Duration,
+    idle_timeout: Option<&Duration>, header_timeout: Option<&Duration>, connect_timeout: &Duration) -> SessionResult {
     if self.frontend_token == token {
-      let dur = SteadyTime::now() - self.last_event;
+      let now = SteadyTime::now();
+      self.metrics.update_activity(self.last_event);
+      if idle_timeout.map(|t| self.metrics.is_idle_at(*t, now)).unwrap_or(false) {
+        info!("{}\tclosing idle session", self.log_context());
+        incr!("idle_timeout");
+        return SessionResult::CloseSession;
+      }
+
+      // the client keeps sending data, but not the complete header block
+      let header_time_left = header_timeout.and_then(|t| self.http().and_then(|h| h.header_time_left(*t, now)));
+      if header_time_left.map(|left| left <= Duration::zero()).unwrap_or(false) {
+        info!("{}\trequest headers not received in time, closing", self.log_context());
+        incr!("http.header_timeout");
+        let answer = self.answers.borrow().get(DefaultAnswerStatus::Answer408, None);
+        self.set_answer(DefaultAnswerStatus::Answer408, answer);
+        return self.writable();
+      }
+
+      let dur = now - self.last_event;
       if dur < *front_timeout {
-        timer.set_timeout((*front_timeout - dur).to_std().unwrap(), token);
+        let mut delay = self.metrics.next_timeout_at(*front_timeout - dur, idle_timeout, now);
+        if let Some(header_timeout) = header_timeout {
+          delay = ::std::cmp::min(delay, header_time_left.unwrap_or(*header_timeout));
+        }
+        timer.set_timeout(delay.to_std().unwrap(), token);
         SessionResult::Continue
       } else {
         match self.http().map(|h| h.timeout_status()) {
@@ -472,7 +589,30 @@ impl ProxySession for Session {
           }
         }
       }
+    } else if self.back_token() == Some(token) && self.response_time_left()
+      .map(|left| left <= Duration::zero()).unwrap_or(false) {
+      error!("{} the backend did not answer before the response timeout of the application", self.log_context());
+      incr!("backend.response_timeout");
+      // the client already got part of the response, it cannot get an error answer
+      if self.http().map(|h| h.response_started()).unwrap_or(true) {
+        return SessionResult::CloseSession;
+      }
+      let answer = self.answers.borrow().get(DefaultAnswerStatus::Answer504, None);
+      self.set_answer(DefaultAnswerStatus::Answer504, answer);
+      self.writable()
+    } else if self.back_token() == Some(token) && self.back_connected() == BackendConnectionStatus::Connecting {
+      // the timer could have been set for a previous connection attempt
+      if !self.metrics.is_connect_timed_out_at(*connect_timeout, SteadyTime::now()) {
+        return SessionResult::Continue;
+      }
+
+      error!("{} timeout connecting to backend, trying again", self.log_context());
+      incr!("backend.connect_timeout");
+      self.connection_attempt += 1;
+      self.fail_backend_connection();
+      SessionResult::ReconnectBackend(Some(self.frontend_token), Some(token))
     } else {
+      // invalid token, obsolete timeout triggered
       SessionResult::Continue
     }
   }
@@ -483,6 +623,9 @@ impl ProxySession for Session {
 
   //FIXME: check the token passed as argument
   fn close_backend(&mut self, _: Token, poll: &mut Poll) {
+    if self.back_connected() == BackendConnectionStatus::Connected {
+      self.pool_backend_connection(poll);
+    }
     self.remove_backend();
 
     let back_connected = self.back_connected();
@@ -518,6 +661,7 @@ impl ProxySession for Session {
 
   fn process_events(&mut self, token: Token, events: Ready) {
     trace!("token {:?} got event {}", token, super::unix_ready_to_string(UnixReady::from(events)));
+    self.metrics.update_activity(self.last_event);
     self.last_event = SteadyTime::now();
     self.metrics.wait_start();
 
@@ -561,6 +705,10 @@ impl ProxySession for Session {
         SessionResult::CloseSession => {
           return order;
         },
+        SessionResult::Continue => {
+          // a half closed frontend can still receive data
+          self.front_readiness().event.remove(UnixReady::hup());
+        },
         _ => {
           self.front_readiness().event.remove(UnixReady::hup());
           return order;
@@ -630,6 +778,16 @@ impl ProxySession for Session {
         };
       }
 
+      if back_interest.is_error() && !front_interest.is_error()
+        && self.http().map(|h| h.response == Some(ResponseState::Initial)).unwrap_or(false) {
+        // the backend reset the connection during the request upload, but
+        // its answer might still be available in the socket's buffer
+        self.back_readiness().map(|r| r.event.remove(UnixReady::error()));
+        self.http_mut().map(|h| h.back_closed_during_request());
+        counter += 1;
+        continue;
+      }
+
       if front_interest.is_error() || back_interest.is_error() {
         if front_interest.is_error() {
           error!("PROXY session {:?} front error, disconnecting", self.frontend_token);
@@ -664,6 +822,14 @@ impl ProxySession for Session {
     SessionResult::Continue
   }
 
+  fn back_connection_status(&self) -> BackendConnectionStatus {
+    self.back_connected
+  }
+
+  fn response_time_left(&self) -> Option<Duration> {
+    self.http().and_then(|http| http.response_time_left(SteadyTime::now()))
+  }
+
   fn shutting_down(&mut self) -> SessionResult {
     match &mut self.protocol {
       Some(State::Http(h)) => h.shutting_down(),
@@ -671,6 +837,11 @@ impl ProxySession for Session {
     }
   }
 
+  fn take_proxy_protocol_client(&mut self) -> Option<(ListenToken, IpAddr)> {
+    let listen_token = ListenToken(self.listen_token.0);
+    self.proxy_protocol_client.take().map(|ip| (listen_token, ip))
+  }
+
   fn last_event(&self) -> SteadyTime {
     self.last_event
   }
@@ -712,6 +883,7 @@ pub type Hostname = String;
 
 pub struct Listener {
   listener:       Option<TcpListener>,
+  unix_listener:  Option<UnixSocketListener>,
   pub address:    SocketAddr,
   fronts:         TrieNode<Vec<HttpFront>>,
   answers:        Rc<RefCell<HttpAnswers>>,
@@ -770,12 +942,44 @@ impl Proxy {
     }).collect()
   }
 
+  pub fn has_unix_listener(&self, address: SocketAddr) -> bool {
+    self.listeners.values().any(|l| l.address == address && l.unix_listener.is_some())
+  }
+
+  pub fn close_unix_listener(&mut self, event_loop: &mut Poll, address: SocketAddr) -> Option<Token> {
+    self.listeners.values_mut().find(|l| l.address == address && l.unix_listener.is_some()).map(|l| {
+      l.close_unix_listener(event_loop);
+      l.token
+    })
+  }
+
   pub fn give_back_listener(&mut self, address: SocketAddr) -> Option<(Token, TcpListener)> {
     self.listeners.values_mut().find(|l| l.address == address).and_then(|l| {
       l.listener.take().map(|sock| (l.token, sock))
     })
   }
 
+  /// lists the frontends of every listener, sorted by listener address then hostname
+  pub fn routes(&self) -> Vec<HttpFront> {
+    let mut listeners: Vec<&Listener> = self.listeners.values().collect();
+    listeners.sort_by_key(|l| l.address);
+
+    listeners.iter().flat_map(|l| {
+      l.fronts.entries().into_iter().flat_map(|&(_, ref fronts)| fronts.iter().cloned())
+    }).collect()
+  }
+
+  /// runs the frontend lookup of every listener for this request
+  pub fn route_match(&self, hostname: &str, method: Option<&str>, path: &str) -> Vec<ListenerRouteMatch> {
+    let mut listeners: Vec<&Listener> = self.listeners.values().collect();
+    listeners.sort_by_key(|l| l.address);
+
+    listeners.iter().map(|l| ListenerRouteMatch {
+      listener: l.address,
+      route:    self.backends.borrow().route_match(l.frontend_from_request(hostname, method, path, |_| None).cloned()),
+    }).collect()
+  }
+
   pub fn add_application(&mut self, mut application: Application) {
     if let Some(answer_503) = application.answer_503.as_ref() {
       for l in self.listeners.values_mut() {
@@ -794,6 +998,44 @@ impl Proxy {
     }
   }
 
+  /// removes the application with its frontends on every listener,
+  /// returns the number of frontends removed
+  pub fn purge_application(&mut self, app_id: &str) -> usize {
+    let fronts: Vec<HttpFront> = self.routes().into_iter().filter(|front| front.app_id == app_id).collect();
+    let count = fronts.len();
+    for front in fronts {
+      if let Some(listener) = self.listeners.values_mut().find(|l| l.address == front.address) {
+        if let Err(e) = listener.remove_http_front(front) {
+          error!("could not remove a frontend of application {}: {}", app_id, e);
+        }
+      }
+    }
+
+    self.remove_application(app_id);
+    count
+  }
+
+  pub fn set_application_enabled(&mut self, app_id: &str, enabled: bool, id: String) -> ProxyResponse {
+    match self.applications.get_mut(app_id) {
+      Some(app) => {
+        app.enabled = enabled;
+        ProxyResponse{ id, status: ProxyResponseStatus::Ok, data: None }
+      },
+      None => ProxyResponse{ id, status: ProxyResponseStatus::Error(format!("no such application {}", app_id)), data: None },
+    }
+  }
+
+  /// the sessions use the new timeout from their next request
+  pub fn set_app_timeout(&mut self, app_id: &str, timeout: Option<u32>, id: String) -> ProxyResponse {
+    match self.applications.get_mut(app_id) {
+      Some(app) => {
+        app.response_timeout = timeout;
+        ProxyResponse{ id, status: ProxyResponseStatus::Ok, data: None }
+      },
+      None => ProxyResponse{ id, status: ProxyResponseStatus::Error(format!("no such application {}", app_id)), data: None },
+    }
+  }
+
   pub fn backend_from_request(&mut self, session: &mut Session, app_id: &str,
   front_should_stick: bool) -> Result<TcpStream,ConnectionError> {
     session.http_mut().map(|h| h.set_app_id(String::from(app_id)));
@@ -803,38 +1045,42 @@ impl Proxy {
       .and_then(|r| r.get_sticky_session());
 
     let res = match (front_should_stick, sticky_session) {
-      (true, Some(sticky_session)) => {
+      // once the sticky backend failed, the request is retried on another one
+      (true, Some(sticky_session)) if session.tried_backends.is_empty() => {
         self.backends.borrow_mut().backend_from_sticky_session(app_id, &sticky_session)
           .map_err(|e| {
             debug!("Couldn't find a backend corresponding to sticky_session {} for app {}", sticky_session, app_id);
             e
           })
       },
-      _ => self.backends.borrow_mut().backend_from_app_id(app_id),
+      _ => self.backends.borrow_mut().untried_backend_from_app_id(app_id, &session.tried_backends),
     };
 
     match res {
       Err(e) => {
+        session.http().map(|http| http.trace_event(format_args!("no backend available for application {}: {:?}", app_id, e)));
         let answer = self.get_service_unavailable_answer(Some(app_id), session.listen_token);
         session.set_answer(DefaultAnswerStatus::Answer503, answer);
         Err(e)
       },
       Ok((backend, conn))  => {
+        session.http().map(|http| http.trace_event(format_args!("selected backend {} at {}, sticky session: {:?}, tried backends: {:?}",
+          backend.borrow().backend_id, backend.borrow().address, sticky_session, session.tried_backends)));
         if front_should_stick {
           let sticky_name =  self.listeners[&session.listen_token].config.sticky_name.clone();
           session.http_mut().map(|http| {
             http.sticky_session =
-              Some(StickySession::new(backend.borrow().sticky_id.clone().unwrap_or_else(|| {
-                backend.borrow().backend_id.clone()})));
+              Some(StickySession::new(backend.borrow().sticky_session_id().to_string()));
             http.sticky_name = sticky_name;
           });
         }
         session.metrics.backend_id = Some(backend.borrow().backend_id.clone());
         session.metrics.backend_start();
+        session.tried_backends.push(backend.borrow().backend_id.clone());
         session.http_mut().map(|http| {
-          http.set_backend_id(backend.borrow().backend_id.clone());
+          http.set_backend(backend.clone());
         });
-        session.backend = Some(backend);
+        session.backend = Some(ConnectionGuard::new(backend));
 
         Ok(conn)
       }
@@ -842,8 +1088,10 @@ impl Proxy {
   }
 
   fn app_id_from_request(&mut self, session: &mut Session) -> Result<String, ConnectionError> {
-    let h = session.http().and_then(|h| h.request.as_ref())
-      .and_then(|s| s.get_host()).ok_or(ConnectionError::NoHostGiven)?;
+    let h = match session.http().and_then(|h| h.request.as_ref()).and_then(|s| s.get_host()) {
+      Some(h) => h,
+      None    => return Err(self.answer_connection_error(session, ConnectionError::NoHostGiven)),
+    };
 
     let host: &str = if let Ok((i, (hostname, port))) = hostname_and_port(h.as_bytes()) {
       if i != &b""[..] {
@@ -870,12 +1118,20 @@ impl Proxy {
       return Err(ConnectionError::InvalidHost);
     };
 
-    let rl = session.http().and_then(|h| h.request.as_ref())
-      .and_then(|s| s.get_request_line()).ok_or(ConnectionError::NoRequestLineGiven)?;
+    let rl = match session.http().and_then(|h| h.request.as_ref()).and_then(|s| s.get_request_line()) {
+      Some(rl) => rl,
+      None     => return Err(self.answer_connection_error(session, ConnectionError::NoRequestLineGiven)),
+    };
 
+    let front_buf = session.http().and_then(|h| h.front_buf.as_ref());
+    let client_ip = session.http().and_then(|h| h.get_session_address()).map(|address| address.ip());
     let app_id = match self.listeners.get(&session.listen_token).as_ref()
-      .and_then(|l| l.frontend_from_request(&host, &rl.uri))
-      .map(|ref front| front.app_id.clone()) {
+      .and_then(|l| l.frontend_from_request(&host, Some(&rl.method.to_string()), rl.path(),
+        |name| front_buf.and_then(|buf| header_value(buf, name.as_bytes()))))
+      .map(|front| traffic_split::app_id(&front.app_id, front.split.as_ref(),
+        |name| front_buf.and_then(|buf| request_cookie(buf, name)), client_ip).to_string())
+      // the default application only gets the requests that no frontend matches
+      .or_else(|| self.listeners.get(&session.listen_token).and_then(|l| l.config.default_app.clone())) {
       Some(app_id) => app_id,
       None => {
         let answer = self.listeners[&session.listen_token].answers.borrow().get(DefaultAnswerStatus::Answer404, None);
@@ -886,7 +1142,7 @@ impl Proxy {
 
     let front_should_redirect_https = self.applications.get(&app_id).map(|ref app| app.https_redirect).unwrap_or(false);
     if front_should_redirect_https {
-      let answer = format!("HTTP/1.1 301 Moved Permanently\r\nContent-Length: 0\r\nLocation: https://{}{}\r\n\r\n", host, rl.uri);
+      let answer = format!("HTTP/1.1 301 Moved Permanently\r\nContent-Length: 0\r\nLocation: https://{}{}\r\n\r\n", host, rl.path());
       session.set_answer(DefaultAnswerStatus::Answer301, Rc::new(answer.into_bytes()));
       return Err(ConnectionError::HttpsRedirect);
     }
@@ -894,15 +1150,83 @@ impl Proxy {
     Ok(app_id)
   }
 
+  /// answers the requests of a disabled application with its maintenance answer,
+  /// or its 503 answer without it
+  fn check_application_enabled(&self, session: &mut Session, app_id: &str) -> Result<(), ConnectionError> {
+    let maintenance_answer = match self.applications.get(app_id) {
+      Some(app) if !app.enabled => app.maintenance_answer.clone(),
+      _ => return Ok(()),
+    };
+
+    let answer = match maintenance_answer {
+      Some(answer) => Rc::new(answer.into_bytes()),
+      None         => self.get_service_unavailable_answer(Some(app_id), session.listen_token),
+    };
+    session.set_answer(DefaultAnswerStatus::Answer503, answer);
+    Err(ConnectionError::ApplicationDisabled)
+  }
+
+  /// takes a slot in the concurrency limit of the application. Returns false if
+  /// the request waits in the queue, and answers 503 if the queue is full
+  fn take_request_slot(&self, session: &mut Session, app_id: &str) -> Result<bool, ConnectionError> {
+    let limits = self.backends.borrow().request_limits.clone();
+    let current = session.http_mut().and_then(|http| http.take_request_slot());
+    let was_queued = current.as_ref().map(|slot| !slot.is_held()).unwrap_or(false);
+    match request_limit::admit(&limits, app_id, session.frontend_token, current) {
+      Admission::Admitted(slot) => {
+        session.http_mut().map(|http| http.set_request_slot(slot));
+        if was_queued {
+          session.front_readiness().interest.insert(Ready::readable());
+          session.back_readiness().map(|r| r.interest.insert(Ready::writable()));
+        }
+        Ok(true)
+      },
+      Admission::Queued(slot) => {
+        // nothing is read or sent to a kept alive backend connection until it gets a slot
+        session.http_mut().map(|http| http.set_request_slot(Some(slot)));
+        session.front_readiness().interest.remove(Ready::readable());
+        session.back_readiness().map(|r| r.interest.remove(Ready::writable()));
+        Ok(false)
+      },
+      Admission::Rejected => {
+        let answer = self.get_service_unavailable_answer(Some(app_id), session.listen_token);
+        session.set_answer(DefaultAnswerStatus::Answer503, answer);
+        Err(ConnectionError::RequestQueueFull)
+      },
+    }
+  }
+
+  /// sets the default answer for a request that could not be sent to a backend
+  fn answer_connection_error(&self, session: &mut Session, error: ConnectionError) -> ConnectionError {
+    let status = connection_error_status(&error);
+    let answer = self.listeners[&session.listen_token].answers.borrow()
+      .get(status, session.app_id.as_ref().map(|app_id| app_id.as_str()));
+    session.set_answer(status, answer);
+    error
+  }
+
   fn check_circuit_breaker(&mut self, session: &mut Session) -> Result<(), ConnectionError> {
-    if session.connection_attempt == CONN_RETRIES {
+    let (connection_attempts, retry_non_idempotent) = {
+      let backends = self.backends.borrow();
+      (backends.connection_attempts, backends.retry_non_idempotent)
+    };
+
+    if session.connection_attempt >= connection_attempts {
       error!("{} max connection attempt reached", session.log_context());
-      let answer = self.get_service_unavailable_answer(session.app_id.as_ref().map(|app_id| app_id.as_str()), session.listen_token);
-      session.set_answer(DefaultAnswerStatus::Answer503, answer);
-      Err(ConnectionError::NoBackendAvailable)
+    } else if session.connection_attempt > 0 && !retry_non_idempotent && !session.is_idempotent_request() {
+      error!("{} backend connection failed, not retrying a non idempotent request", session.log_context());
+    } else if session.connection_attempt > 0 && !session.app_id.as_ref()
+      .map(|app_id| self.backends.borrow_mut().take_retry(app_id)).unwrap_or(true) {
+      error!("{} backend connection failed, the retry budget of the application is exhausted", session.log_context());
     } else {
-      Ok(())
+      return Ok(());
     }
+
+    // the next request of the connection gets its own attempts
+    session.reset_connection_attempt();
+    let answer = self.get_service_unavailable_answer(session.app_id.as_ref().map(|app_id| app_id.as_str()), session.listen_token);
+    session.set_answer(DefaultAnswerStatus::Answer503, answer);
+    Err(ConnectionError::NoBackendAvailable)
   }
 
   fn get_service_unavailable_answer(&self, app_id: Option<&str>, listen_token: Token) -> Rc<Vec<u8>> {
@@ -912,11 +1236,15 @@ impl Proxy {
 
 impl Listener {
   pub fn new(config: HttpListener, token: Token) -> Listener {
+    let mut answers = HttpAnswers::new(&config.answer_404, &config.answer_503, config.retry_after);
+    answers.set_error_pages(&config.error_pages);
+
     Listener {
       listener: None,
+      unix_listener: None,
       address: config.front,
       fronts:  TrieNode::root(),
-      answers: Rc::new(RefCell::new(HttpAnswers::new(&config.answer_404, &config.answer_503))),
+      answers: Rc::new(RefCell::new(answers)),
       config,
       token,
       active: false,
@@ -928,7 +1256,25 @@ impl Listener {
       return Some(self.token);
     }
 
-    let listener = tcp_listener.or_else(|| server_bind(&self.config.front).map_err(|e| {
+    if let Some(ref unix_socket) = self.config.unix_socket {
+      let listener = match UnixSocketListener::bind(unix_socket) {
+        Ok(listener) => listener,
+        Err(e) => {
+          error!("could not create listener on the Unix domain socket {}: {:?}", unix_socket.path, e);
+          return None;
+        }
+      };
+
+      if let Err(e) = event_loop.register(&listener.listener, self.token, Ready::readable(), PollOpt::edge()) {
+        error!("error registering listener socket({:?}): {:?}", listener, e);
+      }
+
+      self.unix_listener = Some(listener);
+      self.active = true;
+      return Some(self.token);
+    }
+
+    let listener = tcp_listener.or_else(|| server_bind(&self.config.front, self.config.interface.as_ref().map(|s| s.as_str())).map_err(|e| {
       error!("could not create listener {:?}: {:?}", self.config.front, e);
     }).ok());
 
@@ -952,6 +1298,10 @@ impl Listener {
         let front2 = http_front.clone();
         let front3 = http_front.clone();
         if let Some((_, ref mut fronts)) = self.fronts.domain_lookup_mut(&http_front.hostname.clone().into_bytes(), false) {
+            if let Some(other) = fronts.iter().find(|f| f.conflicts_with(&http_front)) {
+              return Err(format!("the frontend {}{} is already used by the application {}",
+                http_front.hostname, http_front.path_begin, other.app_id));
+            }
             if !fronts.contains(&front2) {
               fronts.push(front2);
             }
@@ -961,7 +1311,10 @@ impl Listener {
         // FIXME: separate the port and hostname, match the hostname separately
 
         if self.fronts.domain_lookup(&http_front.hostname.clone().into_bytes(), false).is_none() {
-          self.fronts.domain_insert(http_front.hostname.into_bytes(), vec![front3]);
+          let hostname = http_front.hostname;
+          if self.fronts.domain_insert(hostname.clone().into_bytes(), vec![front3]) == InsertResult::Failed {
+            return Err(format!("invalid hostname {}: a wildcard can only replace the leftmost label", hostname));
+          }
         }
         Ok(())
       },
@@ -979,7 +1332,7 @@ impl Listener {
           let fronts_opt = self.fronts.domain_lookup_mut(front.hostname.as_bytes(), false);
 
           if let Some((_, fronts)) = fronts_opt {
-            fronts.retain(|f| f != &front);
+            fronts.retain(|f| f.app_id != front.app_id || f.path_begin != front.path_begin || f.method != front.method);
           }
 
           fronts_opt.as_ref().map(|(_,fronts)| fronts.is_empty()).unwrap_or(false)
@@ -995,52 +1348,61 @@ impl Listener {
     }
   }
 
-  pub fn frontend_from_request(&self, host: &str, uri: &str) -> Option<&HttpFront> {
-    let host: &str = if let Ok((i, (hostname, _))) = hostname_and_port(host.as_bytes()) {
-      if i != &b""[..] {
-        error!("frontend_from_request: invalid remaining chars after hostname. Host: {}", host);
-        return None;
+  /// changes the percentage of the traffic split of the application's frontends
+  /// on this hostname. Returns false if there is none
+  pub fn set_traffic_split(&mut self, split: &TrafficSplitPercentage) -> bool {
+    let mut found = false;
+    if let Some((_, fronts)) = self.fronts.domain_lookup_mut(split.hostname.as_bytes(), false) {
+      for front in fronts.iter_mut().filter(|front| front.app_id == split.app_id) {
+        if let Some(ref mut front_split) = front.split {
+          front_split.percentage = split.percentage;
+          found = true;
+        }
       }
+    }
+    found
+  }
 
-      /*if port == Some(&b"80"[..]) {
-      // it is alright to call from_utf8_unchecked,
-      // we already verified that there are only ascii
-      // chars in there
-        unsafe { from_utf8_unchecked(hostname) }
-      } else {
-        host
+  pub fn frontend_from_request<H>(&self, host: &str, method: Option<&str>, uri: &str, header: H) -> Option<&HttpFront>
+    where H: Fn(&str) -> Option<String> {
+    // the fronts are stored without port
+    let host = match hostname_without_port(host) {
+      Some(hostname) => hostname,
+      None => {
+        error!("hostname parsing failed for: '{}'", host);
+        return None;
       }
-      */
-      unsafe { from_utf8_unchecked(hostname) }
-    } else {
-      error!("hostname parsing failed for: '{}'", host);
-      return None;
     };
 
     if let Some((_, http_fronts)) = self.fronts.domain_lookup(host.as_bytes(), true) {
-      let matching_fronts = http_fronts.iter().filter(|f| uri.starts_with(&f.path_begin)); // ToDo match on uri
-      let mut front = None;
-
-      for f in matching_fronts {
-        if front.is_none() {
-          front = Some(f);
-        }
-
-        if let Some(ff) = front {
-          if f.path_begin.len() > ff.path_begin.len() {
-            front = Some(f)
-          }
-        }
-      }
-      front
+      frontend_match(http_fronts, method, uri, header, |f| FrontendRule { method: f.method.as_ref().map(|m| m.as_str()), path_begin: &f.path_begin, trailing_slash: f.trailing_slash, header_match: f.header_match.as_ref() })
     } else {
       None
     }
   }
 
+  /// stops accepting connections on the Unix domain socket and removes its file
+  fn close_unix_listener(&mut self, event_loop: &mut Poll) {
+    if let Some(listener) = self.unix_listener.take() {
+      if let Err(e) = event_loop.deregister(&listener.listener) {
+        error!("error deregistering listen socket({:?}): {:?}", listener, e);
+      }
+    }
+  }
+
   fn accept(&mut self) -> Result<TcpStream, AcceptError> {
 
-    if let Some(ref sock) = self.listener {
+    if let Some(ref listener) = self.unix_listener {
+      listener.accept().map_err(|e| {
+        match e.kind() {
+          ErrorKind::WouldBlock => AcceptError::WouldBlock,
+          _ => {
+            error!("accept() IO error: {:?}", e);
+            AcceptError::IoError
+          }
+        }
+      })
+    } else if let Some(ref sock) = self.listener {
       sock.accept().map_err(|e| {
         match e.kind() {
           ErrorKind::WouldBlock => AcceptError::WouldBlock,
@@ -1065,6 +1427,38 @@ impl ProxyConfiguration<Session> for Proxy {
     self.check_circuit_breaker(session)?;
 
     let app_id = self.app_id_from_request(session)?;
+    session.http_mut().map(|http| http.trace_application(&app_id));
+    self.check_application_enabled(session, &app_id)?;
+    if !self.take_request_slot(session, &app_id)? {
+      session.http().map(|http| http.trace_event(format_args!("waiting for a request slot of application {}", app_id)));
+      return Ok(BackendConnectAction::Queued);
+    }
+
+    if session.connection_attempt == 0 {
+      self.backends.borrow_mut().record_request(&app_id);
+    }
+
+    let add_forwarded_headers = self.applications.get(&app_id).map(|app| app.add_forwarded_headers).unwrap_or(true);
+    session.http_mut().map(|http| http.add_forwarded_headers(add_forwarded_headers));
+
+    if let Some(app) = self.applications.get(&app_id) {
+      session.http_mut().map(|http| http.add_header_rules(&app.request_headers, &app.response_headers, &app.request_id_headers));
+      session.http_mut().map(|http| http.set_host_rewriting(app.rewrite_location, app.backend_hosts.clone(), app.rewrite_cookie_domain));
+      session.http_mut().map(|http| http.set_failure_statuses(app.failure_statuses.clone()));
+      session.http_mut().map(|http| http.set_response_timeout(app.response_timeout.map(|t| Duration::milliseconds(i64::from(t)))));
+
+      let request_retries = if app.retry_unanswered_requests {
+        self.backends.borrow().connection_attempts.saturating_sub(1)
+      } else {
+        0
+      };
+      session.http_mut().map(|http| http.set_max_request_retries(request_retries));
+    }
+
+    let max_request_body_size = self.applications.get(&app_id).and_then(|app| app.max_request_body_size);
+    if session.http_mut().map(|http| http.limit_request_body_size(max_request_body_size)).unwrap_or(false) {
+      return Err(ConnectionError::PayloadTooLarge);
+    }
 
     if (session.http().and_then(|h| h.app_id.as_ref()) == Some(&app_id)) && session.back_connected == BackendConnectionStatus::Connected {
       let has_backend = session.backend.as_ref().map(|backend| {
@@ -1079,6 +1473,8 @@ impl ProxyConfiguration<Session> for Proxy {
         //matched on keepalive
         session.metrics.backend_id = session.backend.as_ref().map(|i| i.borrow().backend_id.clone());
         session.metrics.backend_start();
+        session.http().map(|http| http.trace_event(format_args!("reusing the keep-alive connection to backend {:?}",
+          session.metrics.backend_id)));
         return Ok(BackendConnectAction::Reuse);
       } else if let Some(token) = session.back_token() {
         session.close_backend(token, poll);
@@ -1105,8 +1501,12 @@ impl ProxyConfiguration<Session> for Proxy {
     let front_should_stick = self.applications.get(&app_id).map(|ref app| app.sticky_session).unwrap_or(false);
     let socket = self.backend_from_request(session, &app_id, front_should_stick)?;
 
+    let proxy_protocol_version = self.applications.get(&app_id)
+      .filter(|app| app.proxy_protocol == Some(ProxyProtocolConfig::SendHeader))
+      .map(|app| app.proxy_protocol_version);
     session.http_mut().map(|http| {
       http.app_id = Some(app_id.clone());
+      http.set_proxy_protocol_header(proxy_protocol_version);
     });
 
     if let Err(e) = socket.set_nodelay(true) {
@@ -1159,6 +1559,23 @@ impl ProxyConfiguration<Session> for Proxy {
         self.remove_application(&application);
         ProxyResponse{ id: message.id, status: ProxyResponseStatus::Ok, data: None }
       },
+      ProxyRequestData::PurgeApplication(app_id) => {
+        let count = self.purge_application(&app_id);
+        debug!("{} purge application {}: {} frontends removed", message.id, app_id, count);
+        ProxyResponse{ id: message.id, status: ProxyResponseStatus::Ok, data: None }
+      },
+      ProxyRequestData::EnableApplication(app_id) => {
+        debug!("{} enable application {}", message.id, app_id);
+        self.set_application_enabled(&app_id, true, message.id)
+      },
+      ProxyRequestData::DisableApplication(app_id) => {
+        debug!("{} disable application {}", message.id, app_id);
+        self.set_application_enabled(&app_id, false, message.id)
+      },
+      ProxyRequestData::SetAppTimeout(timeout) => {
+        debug!("{} set the response timeout of application {} to {:?}", message.id, timeout.app_id, timeout.timeout);
+        self.set_app_timeout(&timeout.app_id, timeout.timeout, message.id)
+      },
       ProxyRequestData::AddHttpFront(front) => {
         debug!("{} add front {:?}", message.id, front);
         if let Some(listener) = self.listeners.values_mut().find(|l| l.address == front.address) {
@@ -1184,6 +1601,22 @@ impl ProxyConfiguration<Session> for Proxy {
           panic!("trying to remove front from non existing listener");
         }
       },
+      ProxyRequestData::SetTrafficSplit(split) => {
+        debug!("{} set traffic split {:?}", message.id, split);
+        for listener in self.listeners.values_mut() {
+          listener.set_traffic_split(&split);
+        }
+        ProxyResponse{ id: message.id, status: ProxyResponseStatus::Ok, data: None }
+      },
+      ProxyRequestData::SetDefaultApp(default) => {
+        debug!("{} set default app {:?}", message.id, default);
+        if let Some(listener) = self.listeners.values_mut().find(|l| l.address == default.front) {
+          listener.config.default_app = default.app_id;
+          ProxyResponse{ id: message.id, status: ProxyResponseStatus::Ok, data: None }
+        } else {
+          ProxyResponse{ id: message.id, status: ProxyResponseStatus::Error(format!("no HTTP listener at address {:?}", default.front)), data: None }
+        }
+      },
       ProxyRequestData::RemoveListener(remove) => {
         debug!("removing HTTP listener at address {:?}", remove.front);
         if !self.remove_listener(remove.front) {
@@ -1204,6 +1637,7 @@ impl ProxyConfiguration<Session> for Proxy {
               error!("error deregistering listen socket({:?}): {:?}", sock, e);
             }
           });
+          l.close_unix_listener(event_loop);
         }
         ProxyResponse{ id: message.id, status: ProxyResponseStatus::Processing, data: None }
       },
@@ -1215,6 +1649,7 @@ impl ProxyConfiguration<Session> for Proxy {
               error!("error deregistering listen socket({:?}): {:?}", sock, e);
             }
           });
+          l.close_unix_listener(event_loop);
         }
         ProxyResponse{ id: message.id, status: ProxyResponseStatus::Processing, data: None }
       },
@@ -1230,6 +1665,11 @@ impl ProxyConfiguration<Session> for Proxy {
         });
         ProxyResponse{ id: message.id, status: ProxyResponseStatus::Ok, data: None }
       },
+      ProxyRequestData::LogFormat(format) => {
+        info!("{} changing log format to {:?}", message.id, format);
+        logging::LOGGER.with(|l| l.borrow_mut().set_format(format));
+        ProxyResponse{ id: message.id, status: ProxyResponseStatus::Ok, data: None }
+      },
       command => {
         debug!("{} unsupported message for HTTP proxy, ignoring: {:?}", message.id, command);
         ProxyResponse{ id: message.id, status: ProxyResponseStatus::Error(String::from("unsupported message")), data: None }
@@ -1245,13 +1685,18 @@ impl ProxyConfiguration<Session> for Proxy {
     poll: &mut Poll, session_token: Token, timeout: Timeout, delay: Duration)
   -> Result<(Rc<RefCell<Session>>, bool), AcceptError> {
     if let Some(ref listener) = self.listeners.get(&Token(listen_token.0)) {
-      if let Err(e) = frontend_sock.set_nodelay(true) {
-        error!("error setting nodelay on front socket({:?}): {:?}", frontend_sock, e);
+      if listener.unix_listener.is_none() {
+        if let Err(e) = frontend_sock.set_nodelay(true) {
+          error!("error setting nodelay on front socket({:?}): {:?}", frontend_sock, e);
+        }
       }
       if let Some(c) = Session::new(frontend_sock, session_token, Rc::downgrade(&self.pool),
       listener.config.public_address.unwrap_or(listener.config.front),
-      listener.config.expect_proxy, listener.config.sticky_name.clone(), timeout,
-      listener.answers.clone(), listener.token, delay) {
+      listener.config.expect_proxy, listener.config.sticky_name.clone(),
+      listener.config.preserve_proxy_connection, listener.config.ignore_unsupported_expect,
+      listener.config.unexpected_body_policy, Rc::new(listener.config.client_ip.clone()),
+      listener.config.header_limits, listener.config.max_keepalive_requests,
+      listener.config.compression.clone().map(Rc::new), timeout, listener.answers.clone(), listener.token, delay, Rc::downgrade(&self.backends.borrow().connection_pool)) {
         if let Err(e) = poll.register(
           c.front_socket(),
           session_token,
@@ -1279,9 +1724,40 @@ impl ProxyConfiguration<Session> for Proxy {
     //let token = Token(0);
     //if port == &self.listeners[&token].address.port() { ListenPortState::InUse } else { ListenPortState::Available }
   }
+
+  fn listener_timeouts(&self, token: ListenToken) -> ListenerTimeouts {
+    self.listeners.get(&Token(token.0)).map(|listener| listener.config.timeouts).unwrap_or_default()
+  }
+
+  fn listener_rate_limit(&self, token: ListenToken) -> Option<ConnectionRateLimit> {
+    self.listeners.get(&Token(token.0)).and_then(|listener| listener.config.connection_rate_limit)
+  }
+
+  fn listener_expects_proxy_protocol(&self, token: ListenToken) -> bool {
+    self.listeners.get(&Token(token.0)).map(|listener| listener.config.expect_proxy).unwrap_or(false)
+  }
+
+  fn listener_max_sessions(&self, token: ListenToken) -> Option<usize> {
+    self.listeners.get(&Token(token.0)).and_then(|listener| listener.config.max_sessions)
+  }
+
+  fn pause_listener(&mut self, event_loop: &mut Poll, token: ListenToken, paused: bool) {
+    if let Some(listener) = self.listeners.get(&Token(token.0)) {
+      if let Some(ref unix_listener) = listener.unix_listener {
+        ::socket::pause_listener(event_loop, &unix_listener.listener, Token(token.0), paused);
+      } else if let Some(ref sock) = listener.listener {
+        ::socket::pause_listener(event_loop, sock, Token(token.0), paused);
+      }
+    }
+  }
 }
 
 pub fn start(config: HttpListener, channel: ProxyChannel, max_buffers: usize, buffer_size: usize) {
+  start_with_state(config, channel, max_buffers, buffer_size, None)
+}
+
+fn start_with_state(config: HttpListener, channel: ProxyChannel, max_buffers: usize, buffer_size: usize,
+  config_state: Option<ConfigState>) {
   use super::server::{self,ProxySessionCast};
   let mut event_loop  = Poll::new().expect("could not create event loop");
 
@@ -1329,7 +1805,7 @@ pub fn start(config: HttpListener, channel: ProxyChannel, max_buffers: usize, bu
   let mut server_config: server::ServerConfig = Default::default();
   server_config.max_connections = max_buffers;
   let mut server    = Server::new(event_loop, channel, ScmSocket::new(scm_server.into_raw_fd()),
-    sessions, pool, backends, Some(proxy), None, None, server_config, None);
+    sessions, pool, None, backends, Some(proxy), None, None, server_config, config_state);
 
   println!("starting event loop");
   server.run();
@@ -1344,14 +1820,16 @@ mod tests {
   use std::io::{Read,Write};
   use std::{thread,str};
   use std::sync::{
-    Arc, Barrier,
+    Arc, Barrier, mpsc,
   };
   use std::net::SocketAddr;
   use std::str::FromStr;
   use std::time::Duration;
-  use sozu_command::proxy::{ProxyRequestData,HttpFront,Backend,HttpListener,ProxyRequest,LoadBalancingParams};
-  use sozu_command::config::LoadBalancingAlgorithms;
+  use sozu_command::proxy::{ProxyRequestData,HttpFront,Backend,HttpListener,ProxyRequest,LoadBalancingParams,TrailingSlash,AppTimeout,RequestLimit};
+  use sozu_command::config::ProxyProtocolVersion;
   use sozu_command::channel::Channel;
+  use protocol::http::hooks;
+  use protocol::http::parser::{RRequestLine,Method,Version};
 
   /*
   #[test]
@@ -1385,9 +1863,9 @@ mod tests {
       start(config, channel, 10, 16384);
     });
 
-    let front = HttpFront { app_id: String::from("app_1"), address: "127.0.0.1:1024".parse().unwrap(), hostname: String::from("localhost"), path_begin: String::from("/") };
+    let front = HttpFront { app_id: String::from("app_1"), address: "127.0.0.1:1024".parse().unwrap(), hostname: String::from("localhost"), path_begin: String::from("/"), trailing_slash: TrailingSlash::Strict, method: None, header_match: None, split: None };
     command.write_message(&ProxyRequest { id: String::from("ID_ABCD"), order: ProxyRequestData::AddHttpFront(front) });
-    let backend = Backend { app_id: String::from("app_1"),backend_id: String::from("app_1-0"), address: "127.0.0.1:1025".parse().unwrap(), load_balancing_parameters: Some(LoadBalancingParams::default()), sticky_id: None, backup: None };
+    let backend = Backend { app_id: String::from("app_1"),backend_id: String::from("app_1-0"), address: "127.0.0.1:1025".parse().unwrap(), load_balancing_parameters: Some(LoadBalancingParams::default()), sticky_id: None, backup: None, hostname: None };
     command.write_message(&ProxyRequest { id: String::from("ID_EFGH"), order: ProxyRequestData::AddBackend(backend) });
 
     println!("test received: {:?}", command.read_message());
@@ -1441,9 +1919,9 @@ mod tests {
       start(config, channel, 10, 16384);
     });
 
-    let front = HttpFront { app_id: String::from("app_1"), address: "127.0.0.1:1031".parse().unwrap(), hostname: String::from("localhost"), path_begin: String::from("/") };
+    let front = HttpFront { app_id: String::from("app_1"), address: "127.0.0.1:1031".parse().unwrap(), hostname: String::from("localhost"), path_begin: String::from("/"), trailing_slash: TrailingSlash::Strict, method: None, header_match: None, split: None };
     command.write_message(&ProxyRequest { id: String::from("ID_ABCD"), order: ProxyRequestData::AddHttpFront(front) });
-    let backend = Backend { app_id: String::from("app_1"), backend_id: String::from("app_1-0"), address: "127.0.0.1:1028".parse().unwrap(), load_balancing_parameters: Some(LoadBalancingParams::default()), sticky_id: None, backup: None };
+    let backend = Backend { app_id: String::from("app_1"), backend_id: String::from("app_1-0"), address: "127.0.0.1:1028".parse().unwrap(), load_balancing_parameters: Some(LoadBalancingParams::default()), sticky_id: None, backup: None, hostname: None };
     command.write_message(&ProxyRequest { id: String::from("ID_EFGH"), order: ProxyRequestData::AddBackend(backend) });
 
     println!("test received: {:?}", command.read_message());
@@ -1518,11 +1996,11 @@ mod tests {
       start(config, channel, 10, 16384);
     });
 
-    let application = Application { app_id: String::from("app_1"), sticky_session: false, https_redirect: true, proxy_protocol: None, load_balancing_policy: LoadBalancingAlgorithms::default(), answer_503: None };
+    let application = Application { app_id: String::from("app_1"), https_redirect: true, ..Default::default() };
     command.write_message(&ProxyRequest { id: String::from("ID_ABCD"), order: ProxyRequestData::AddApplication(application) });
-    let front = HttpFront { app_id: String::from("app_1"), address: "127.0.0.1:1041".parse().unwrap(), hostname: String::from("localhost"), path_begin: String::from("/") };
+    let front = HttpFront { app_id: String::from("app_1"), address: "127.0.0.1:1041".parse().unwrap(), hostname: String::from("localhost"), path_begin: String::from("/"), trailing_slash: TrailingSlash::Strict, method: None, header_match: None, split: None };
     command.write_message(&ProxyRequest { id: String::from("ID_EFGH"), order: ProxyRequestData::AddHttpFront(front) });
-    let backend = Backend { app_id: String::from("app_1"),backend_id: String::from("app_1-0"), address: "127.0.0.1:1040".parse().unwrap(), load_balancing_parameters: Some(LoadBalancingParams::default()), sticky_id: None, backup: None };
+    let backend = Backend { app_id: String::from("app_1"),backend_id: String::from("app_1-0"), address: "127.0.0.1:1040".parse().unwrap(), load_balancing_parameters: Some(LoadBalancingParams::default()), sticky_id: None, backup: None, hostname: None };
     command.write_message(&ProxyRequest { id: String::from("ID_IJKL"), order: ProxyRequestData::AddBackend(backend) });
 
     println!("test received: {:?}", command.read_message());
@@ -1561,71 +2039,3064 @@ mod tests {
   }
 
 
-  use self::tiny_http::{Server, Response};
+  struct TestTransformer;
 
-  fn start_server(port: u16, barrier: Arc<Barrier>) {
-    thread::spawn(move|| {
-      let server = Server::http(&format!("127.0.0.1:{}", port)).expect("could not create server");
-      info!("starting web server in port {}", port);
-      barrier.wait();
+  impl hooks::HttpTransformer for TestTransformer {
+    fn on_request(&mut self, request: &hooks::RequestInfo) -> hooks::Transform {
+      if request.request_line.uri.starts_with("/forbidden") {
+        hooks::Transform::Answer(Vec::from(&b"HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"[..]))
+      } else {
+        hooks::Transform::AddHeaders(vec![(String::from("X-Hook"), String::from("injected"))])
+      }
+    }
 
-      for request in server.incoming_requests() {
-        println!("backend web server got request -> method: {:?}, url: {:?}, headers: {:?}",
-          request.method(),
-          request.url(),
-          request.headers()
-        );
+    fn on_response(&mut self, response: &hooks::ResponseInfo) -> hooks::Transform {
+      hooks::Transform::AddHeaders(vec![
+        (String::from("X-Hook-App"), response.app_id.unwrap_or("-").to_string()),
+      ])
+    }
+  }
 
-        let response = Response::from_string("hello world");
-        request.respond(response).unwrap();
-        println!("backend web server sent response");
-        barrier.wait();
-        println!("server session stopped");
+  fn read_until(client: &mut TcpStream, expected: &str) -> String {
+    let mut buffer = Vec::new();
+    let mut chunk = [0;4096];
+    loop {
+      match client.read(&mut chunk) {
+        Ok(0) | Err(_) => break,
+        Ok(sz) => buffer.extend_from_slice(&chunk[..sz]),
+      }
+      if str::from_utf8(&buffer).map(|s| s.contains(expected)).unwrap_or(false) {
+        break;
       }
+    }
+    String::from_utf8(buffer).expect("could not make string from buffer")
+  }
 
-      println!("server on port {} closed", port);
-    });
+  /// reads up to the end of the header block, one byte at a time so the body
+  /// or the next request stays in the socket
+  fn read_headers(stream: &mut TcpStream) -> String {
+    let mut data = Vec::new();
+    let mut buffer = [0;1];
+    while !data.ends_with(&b"\r\n\r\n"[..]) {
+      let sz = stream.read(&mut buffer[..]).expect("could not read headers");
+      assert!(sz > 0, "connection closed while reading headers");
+      data.push(buffer[0]);
+    }
+    String::from_utf8(data).expect("could not make string from buffer")
   }
 
   #[test]
-  fn frontend_from_request_test() {
-    let app_id1 = "app_1".to_owned();
-    let app_id2 = "app_2".to_owned();
-    let app_id3 = "app_3".to_owned();
-    let uri1 = "/".to_owned();
-    let uri2 = "/yolo".to_owned();
-    let uri3 = "/yolo/swag".to_owned();
+  fn wait_for_initial_configuration() {
+    setup_test_logger!();
 
-    let mut fronts = TrieNode::root();
-    fronts.domain_insert(Vec::from(&b"lolcatho.st"[..]), vec![
-      HttpFront { app_id: app_id1, address: "0.0.0.0:80".parse().unwrap(), hostname: "lolcatho.st".to_owned(), path_begin: uri1 },
-      HttpFront { app_id: app_id2, address: "0.0.0.0:80".parse().unwrap(), hostname: "lolcatho.st".to_owned(), path_begin: uri2 },
-      HttpFront { app_id: app_id3, address: "0.0.0.0:80".parse().unwrap(), hostname: "lolcatho.st".to_owned(), path_begin: uri3 }
-    ]);
-    fronts.domain_insert(Vec::from(&b"other.domain"[..]), vec![
-      HttpFront { app_id: "app_1".to_owned(), address: "0.0.0.0:80".parse().unwrap(), hostname: "other.domain".to_owned(), path_begin: "/test".to_owned() },
-    ]);
+    let barrier = Arc::new(Barrier::new(2));
+    start_server(1063, barrier.clone());
+    barrier.wait();
 
-    let front: SocketAddr = FromStr::from_str("127.0.0.1:1030").expect("could not parse address");
-    let listener = Listener {
-      listener: None,
-      address:  front,
-      fronts,
-      answers: Rc::new(RefCell::new(HttpAnswers::new("HTTP/1.1 404 Not Found\r\n\r\n", "HTTP/1.1 503 your application is in deployment\r\n\r\n"))),
-      config: Default::default(),
-      token: Token(0),
-      active: true,
+    let front: SocketAddr = FromStr::from_str("127.0.0.1:1064").expect("could not parse address");
+    let config = HttpListener {
+      front,
+      ..Default::default()
     };
 
-    let frontend1 = listener.frontend_from_request("lolcatho.st", "/");
-    let frontend2 = listener.frontend_from_request("lolcatho.st", "/test");
-    let frontend3 = listener.frontend_from_request("lolcatho.st", "/yolo/test");
-    let frontend4 = listener.frontend_from_request("lolcatho.st", "/yolo/swag");
-    let frontend5 = listener.frontend_from_request("domain", "/");
-    assert_eq!(frontend1.expect("should find frontend").app_id, "app_1");
-    assert_eq!(frontend2.expect("should find frontend").app_id, "app_1");
-    assert_eq!(frontend3.expect("should find frontend").app_id, "app_2");
-    assert_eq!(frontend4.expect("should find frontend").app_id, "app_3");
-    assert_eq!(frontend5, None);
+    // like a worker launched by the main process, with a configuration that comes later
+    let (mut command, channel) = Channel::generate(1000, 10000).expect("should create a channel");
+    thread::spawn(move|| {
+      start_with_state(config, channel, 10, 16384, Some(ConfigState::new()));
+    });
+
+    command.write_message(&ProxyRequest { id: String::from("ID_STATUS"), order: ProxyRequestData::Status });
+    let status = command.read_message().expect("should receive the status");
+    assert_eq!(status.status, ProxyResponseStatus::Error(String::from("waiting for the initial configuration")));
+
+    // the listener is bound, but the connection stays in the listen queue
+    let mut client = TcpStream::connect(("127.0.0.1", 1064)).expect("could not parse address");
+    client.write(&b"GET / HTTP/1.1\r\nHost: localhost:1064\r\nConnection: Close\r\n\r\n"[..]).unwrap();
+    client.set_read_timeout(Some(Duration::from_millis(500)));
+    let mut buffer = [0;4096];
+    assert!(client.read(&mut buffer[..]).is_err(), "the worker should not answer before being configured");
+
+    let front = HttpFront { app_id: String::from("app_1"), address: "127.0.0.1:1064".parse().unwrap(), hostname: String::from("localhost"), path_begin: String::from("/"), trailing_slash: TrailingSlash::Strict, method: None, header_match: None, split: None };
+    command.write_message(&ProxyRequest { id: String::from("ID_ABCD"), order: ProxyRequestData::AddHttpFront(front) });
+    let backend = Backend { app_id: String::from("app_1"), backend_id: String::from("app_1-0"), address: "127.0.0.1:1063".parse().unwrap(), load_balancing_parameters: Some(LoadBalancingParams::default()), sticky_id: None, backup: None, hostname: None };
+    command.write_message(&ProxyRequest { id: String::from("ID_EFGH"), order: ProxyRequestData::AddBackend(backend) });
+    command.write_message(&ProxyRequest { id: String::from("ID_LOADED"), order: ProxyRequestData::ConfigurationLoaded });
+
+    println!("test received: {:?}", command.read_message());
+    println!("test received: {:?}", command.read_message());
+    println!("test received: {:?}", command.read_message());
+
+    // the queued connection is routed with the complete configuration
+    client.set_read_timeout(Some(Duration::new(5,0)));
+    barrier.wait();
+    let answer = read_until(&mut client, "hello world");
+    println!("Response: {}", answer);
+    assert!(answer.starts_with("HTTP/1.1 200 "));
+    assert!(answer.contains("hello world"));
+
+    command.write_message(&ProxyRequest { id: String::from("ID_STATUS2"), order: ProxyRequestData::Status });
+    let status = command.read_message().expect("should receive the status");
+    assert_eq!(status.status, ProxyResponseStatus::Ok);
+  }
+
+  #[test]
+  fn transformer() {
+    setup_test_logger!();
+    // the backend answers with the value of the X-Hook header
+    thread::spawn(move|| {
+      let server = Server::http("127.0.0.1:1050").expect("could not create server");
+      for request in server.incoming_requests() {
+        let hook = request.headers().iter()
+          .find(|header| header.field.equiv("X-Hook"))
+          .map(|header| header.value.as_str().to_string())
+          .unwrap_or(String::from("none"));
+        request.respond(Response::from_string(format!("hook: {}", hook))).unwrap();
+      }
+    });
+
+    let front: SocketAddr = FromStr::from_str("127.0.0.1:1051").expect("could not parse address");
+    let config = HttpListener {
+      front,
+      ..Default::default()
+    };
+
+    let (mut command, channel) = Channel::generate(1000, 10000).expect("should create a channel");
+    thread::spawn(move|| {
+      hooks::set_transformer(Box::new(TestTransformer), ::time::Duration::milliseconds(100));
+      start(config, channel, 10, 16384);
+    });
+
+    let front = HttpFront { app_id: String::from("app_1"), address: "127.0.0.1:1051".parse().unwrap(), hostname: String::from("localhost"), path_begin: String::from("/"), trailing_slash: TrailingSlash::Strict, method: None, header_match: None, split: None };
+    command.write_message(&ProxyRequest { id: String::from("ID_ABCD"), order: ProxyRequestData::AddHttpFront(front) });
+    let backend = Backend { app_id: String::from("app_1"), backend_id: String::from("app_1-0"), address: "127.0.0.1:1050".parse().unwrap(), load_balancing_parameters: Some(LoadBalancingParams::default()), sticky_id: None, backup: None, hostname: None };
+    command.write_message(&ProxyRequest { id: String::from("ID_EFGH"), order: ProxyRequestData::AddBackend(backend) });
+
+    println!("test received: {:?}", command.read_message());
+    println!("test received: {:?}", command.read_message());
+
+    let mut client = TcpStream::connect(("127.0.0.1", 1051)).expect("could not parse address");
+    client.set_read_timeout(Some(Duration::new(5,0)));
+    client.write(&b"GET / HTTP/1.1\r\nHost: localhost:1051\r\n\r\n"[..]).unwrap();
+    let answer = read_until(&mut client, "hook: injected");
+    println!("Response: {}", answer);
+    assert!(answer.contains("X-Hook-App: app_1\r\n"));
+    assert!(answer.ends_with("hook: injected"));
+
+    let mut client = TcpStream::connect(("127.0.0.1", 1051)).expect("could not parse address");
+    client.set_read_timeout(Some(Duration::new(5,0)));
+    client.write(&b"GET /forbidden HTTP/1.1\r\nHost: localhost:1051\r\n\r\n"[..]).unwrap();
+    let answer = read_until(&mut client, "\r\n\r\n");
+    println!("Response: {}", answer);
+    assert_eq!(answer, "HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+  }
+
+  #[test]
+  fn backend_closes_during_upload() {
+    setup_test_logger!();
+    use std::net::TcpListener;
+
+    // the backend answers with a 413 as soon as it gets the headers and closes
+    let backend = TcpListener::bind("127.0.0.1:1053").expect("could not bind");
+    thread::spawn(move|| {
+      for stream in backend.incoming() {
+        let mut stream = stream.unwrap();
+        let mut buffer = [0;4096];
+        let mut request = Vec::new();
+        while !str::from_utf8(&request).map(|s| s.contains("\r\n\r\n")).unwrap_or(false) {
+          let sz = stream.read(&mut buffer).unwrap();
+          request.extend_from_slice(&buffer[..sz]);
+        }
+        stream.write_all(&b"HTTP/1.1 413 Payload Too Large\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"[..]).unwrap();
+      }
+    });
+
+    let front: SocketAddr = FromStr::from_str("127.0.0.1:1054").expect("could not parse address");
+    let config = HttpListener {
+      front,
+      ..Default::default()
+    };
+
+    let (mut command, channel) = Channel::generate(1000, 10000).expect("should create a channel");
+    thread::spawn(move|| {
+      start(config, channel, 10, 16384);
+    });
+
+    let front = HttpFront { app_id: String::from("app_1"), address: "127.0.0.1:1054".parse().unwrap(), hostname: String::from("localhost"), path_begin: String::from("/"), trailing_slash: TrailingSlash::Strict, method: None, header_match: None, split: None };
+    command.write_message(&ProxyRequest { id: String::from("ID_ABCD"), order: ProxyRequestData::AddHttpFront(front) });
+    let backend = Backend { app_id: String::from("app_1"), backend_id: String::from("app_1-0"), address: "127.0.0.1:1053".parse().unwrap(), load_balancing_parameters: Some(LoadBalancingParams::default()), sticky_id: None, backup: None, hostname: None };
+    command.write_message(&ProxyRequest { id: String::from("ID_EFGH"), order: ProxyRequestData::AddBackend(backend) });
+
+    println!("test received: {:?}", command.read_message());
+    println!("test received: {:?}", command.read_message());
+
+    let mut client = TcpStream::connect(("127.0.0.1", 1054)).expect("could not parse address");
+    client.set_read_timeout(Some(Duration::new(5,0)));
+    client.write(&b"POST /upload HTTP/1.1\r\nHost: localhost:1054\r\nContent-Length: 10000000\r\n\r\n"[..]).unwrap();
+
+    // keep uploading while the backend has closed the connection
+    let chunk = [b'a'; 4096];
+    for _ in 0..100 {
+      if client.write(&chunk[..]).is_err() {
+        break;
+      }
+      thread::sleep(Duration::from_millis(5));
+    }
+
+    let mut answer = Vec::new();
+    let res = client.read_to_end(&mut answer);
+    let answer = str::from_utf8(&answer).expect("could not make string from buffer");
+    println!("Response: {:?} {}", res, answer);
+    // the session must be closed, not left waiting for the timeout
+    match res {
+      Ok(_)  => assert!(answer.starts_with("HTTP/1.1 413 Payload Too Large\r\n")),
+      Err(e) => assert_eq!(e.kind(), ::std::io::ErrorKind::ConnectionReset),
+    }
+  }
+
+  #[test]
+  fn backend_connect_timeout() {
+    setup_test_logger!();
+    use std::net::TcpListener;
+    use std::os::unix::io::AsRawFd;
+    use std::time::Instant;
+
+    // with an accept queue of one connection, already used, the kernel drops
+    // the next SYN packets and the proxy's connection never completes
+    let backend = TcpListener::bind("127.0.0.1:1055").expect("could not bind");
+    unsafe { ::libc::listen(backend.as_raw_fd(), 0) };
+    let _queued = TcpStream::connect("127.0.0.1:1055").expect("could not connect");
+
+    let front: SocketAddr = FromStr::from_str("127.0.0.1:1056").expect("could not parse address");
+    let config = HttpListener {
+      front,
+      ..Default::default()
+    };
+
+    let (mut command, channel) = Channel::generate(1000, 10000).expect("should create a channel");
+    thread::spawn(move|| {
+      start(config, channel, 10, 16384);
+    });
+
+    let front = HttpFront { app_id: String::from("app_1"), address: "127.0.0.1:1056".parse().unwrap(), hostname: String::from("localhost"), path_begin: String::from("/"), trailing_slash: TrailingSlash::Strict, method: None, header_match: None, split: None };
+    command.write_message(&ProxyRequest { id: String::from("ID_ABCD"), order: ProxyRequestData::AddHttpFront(front) });
+    let backend = Backend { app_id: String::from("app_1"), backend_id: String::from("app_1-0"), address: "127.0.0.1:1055".parse().unwrap(), load_balancing_parameters: Some(LoadBalancingParams::default()), sticky_id: None, backup: None, hostname: None };
+    command.write_message(&ProxyRequest { id: String::from("ID_EFGH"), order: ProxyRequestData::AddBackend(backend) });
+
+    println!("test received: {:?}", command.read_message());
+    println!("test received: {:?}", command.read_message());
+
+    let start_time = Instant::now();
+    let mut client = TcpStream::connect(("127.0.0.1", 1056)).expect("could not parse address");
+    client.set_read_timeout(Some(Duration::new(20,0)));
+    client.write(&b"GET / HTTP/1.1\r\nHost: localhost:1056\r\n\r\n"[..]).unwrap();
+
+    let mut buffer = [0;4096];
+    let sz = client.read(&mut buffer[..]).expect("the proxy should answer before the read timeout");
+    let answer = str::from_utf8(&buffer[..sz]).expect("could not make string from buffer");
+    println!("Response: {}", answer);
+
+    // once the connect timeout expires, the backend is marked as failed and the
+    // session tries to reconnect, but there is no other backend available
+    assert!(answer.starts_with("HTTP/1.1 503 "));
+    let elapsed = start_time.elapsed();
+    assert!(elapsed >= Duration::from_secs(3));
+    assert!(elapsed < Duration::from_secs(10));
+  }
+
+  #[test]
+  fn retry_on_another_backend() {
+    setup_test_logger!();
+    use std::net::TcpListener;
+
+
+    // nothing listens on 127.0.0.1:1105, 1108 and 1109
+    let backend = TcpListener::bind("127.0.0.1:1106").expect("could not bind");
+    thread::spawn(move|| {
+      for stream in backend.incoming() {
+        let mut stream = stream.expect("could not accept");
+        read_headers(&mut stream);
+        stream.write_all(&b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok"[..]).unwrap();
+      }
+    });
+
+    let front: SocketAddr = FromStr::from_str("127.0.0.1:1107").expect("could not parse address");
+    let config = HttpListener {
+      front,
+      ..Default::default()
+    };
+
+    let (mut command, channel) = Channel::generate(1000, 10000).expect("should create a channel");
+    thread::spawn(move|| {
+      start(config, channel, 10, 16384);
+    });
+
+    let fronts = [("app_1", "localhost"), ("app_2", "example.com")];
+    let backends = [("app_1", "127.0.0.1:1105"), ("app_1", "127.0.0.1:1106"),
+      ("app_2", "127.0.0.1:1108"), ("app_2", "127.0.0.1:1109")];
+    for &(app_id, hostname) in fronts.iter() {
+      let front = HttpFront { app_id: String::from(app_id), address: "127.0.0.1:1107".parse().unwrap(), hostname: String::from(hostname), path_begin: String::from("/"), trailing_slash: TrailingSlash::Strict, method: None, header_match: None, split: None };
+      command.write_message(&ProxyRequest { id: format!("ID_{}", app_id), order: ProxyRequestData::AddHttpFront(front) });
+    }
+    for (i, &(app_id, address)) in backends.iter().enumerate() {
+      let backend = Backend { app_id: String::from(app_id), backend_id: format!("{}-{}", app_id, i), address: address.parse().unwrap(), load_balancing_parameters: Some(LoadBalancingParams::default()), sticky_id: None, backup: None, hostname: None };
+      command.write_message(&ProxyRequest { id: format!("ID_{}", i), order: ProxyRequestData::AddBackend(backend) });
+    }
+
+    for _ in 0..(fronts.len() + backends.len()) {
+      println!("test received: {:?}", command.read_message());
+    }
+
+    // whichever backend is selected first, the request ends on the one that is up
+    for _ in 0..4 {
+      let mut client = TcpStream::connect(("127.0.0.1", 1107)).expect("could not parse address");
+      client.set_read_timeout(Some(Duration::new(5,0)));
+      client.write_all(&b"GET / HTTP/1.1\r\nHost: localhost:1107\r\n\r\n"[..]).unwrap();
+      let answer = read_until(&mut client, "\r\n\r\nok");
+      assert!(answer.starts_with("HTTP/1.1 200 OK\r\n"), "unexpected answer: {}", answer);
+    }
+
+    // every backend failed once, a 503 answer is sent instead of retrying them again
+    let mut client = TcpStream::connect(("127.0.0.1", 1107)).expect("could not parse address");
+    client.set_read_timeout(Some(Duration::new(5,0)));
+    client.write_all(&b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n"[..]).unwrap();
+    let mut buffer = [0;4096];
+    let sz = client.read(&mut buffer[..]).expect("the proxy should answer before the read timeout");
+    let answer = str::from_utf8(&buffer[..sz]).expect("could not make string from buffer");
+    assert!(answer.starts_with("HTTP/1.1 503 "), "unexpected answer: {}", answer);
+  }
+
+  #[test]
+  fn resend_unanswered_requests() {
+    setup_test_logger!();
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+
+
+    // the backend closes the first and third connections after reading the request
+    let (tx, rx) = mpsc::channel();
+    let backend = TcpListener::bind("127.0.0.1:1111").expect("could not bind");
+    thread::spawn(move|| {
+      for (i, stream) in backend.incoming().enumerate() {
+        let mut stream = stream.expect("could not accept");
+        let request = read_headers(&mut stream);
+        tx.send(request).unwrap();
+        if i % 2 == 1 {
+          stream.write_all(&b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok"[..]).unwrap();
+        }
+      }
+    });
+
+    let front: SocketAddr = FromStr::from_str("127.0.0.1:1112").expect("could not parse address");
+    let config = HttpListener {
+      front,
+      ..Default::default()
+    };
+
+    let (mut command, channel) = Channel::generate(1000, 10000).expect("should create a channel");
+    thread::spawn(move|| {
+      start(config, channel, 10, 16384);
+    });
+
+    let application = Application { app_id: String::from("app_1"), retry_unanswered_requests: true, ..Default::default() };
+    command.write_message(&ProxyRequest { id: String::from("ID_APP"), order: ProxyRequestData::AddApplication(application) });
+    let front = HttpFront { app_id: String::from("app_1"), address: "127.0.0.1:1112".parse().unwrap(), hostname: String::from("localhost"), path_begin: String::from("/"), trailing_slash: TrailingSlash::Strict, method: None, header_match: None, split: None };
+    command.write_message(&ProxyRequest { id: String::from("ID_ABCD"), order: ProxyRequestData::AddHttpFront(front) });
+    let backend = Backend { app_id: String::from("app_1"), backend_id: String::from("app_1-0"), address: "127.0.0.1:1111".parse().unwrap(), load_balancing_parameters: Some(LoadBalancingParams::default()), sticky_id: None, backup: None, hostname: None };
+    command.write_message(&ProxyRequest { id: String::from("ID_EFGH"), order: ProxyRequestData::AddBackend(backend) });
+
+    for _ in 0..3 {
+      println!("test received: {:?}", command.read_message());
+    }
+
+    // the GET request is sent again on a new connection
+    let mut client = TcpStream::connect(("127.0.0.1", 1112)).expect("could not parse address");
+    client.set_read_timeout(Some(Duration::new(5,0)));
+    client.write_all(&b"GET / HTTP/1.1\r\nHost: localhost:1112\r\n\r\n"[..]).unwrap();
+    let answer = read_until(&mut client, "\r\n\r\nok");
+    assert!(answer.starts_with("HTTP/1.1 200 OK\r\n"), "unexpected answer: {}", answer);
+
+    let first = rx.recv_timeout(Duration::new(1,0)).expect("the backend should receive the request");
+    let second = rx.recv_timeout(Duration::new(1,0)).expect("the backend should receive the request again");
+    assert!(first.starts_with("GET / HTTP/1.1\r\n"));
+    assert_eq!(first, second);
+
+    // the beginning of the POST request reached the backend, it is not sent again
+    let mut client = TcpStream::connect(("127.0.0.1", 1112)).expect("could not parse address");
+    client.set_read_timeout(Some(Duration::new(5,0)));
+    client.write_all(&b"POST / HTTP/1.1\r\nHost: localhost:1112\r\nContent-Length: 10\r\n\r\nabc"[..]).unwrap();
+    let request = rx.recv_timeout(Duration::new(1,0)).expect("the backend should receive the request");
+    assert!(request.starts_with("POST / HTTP/1.1\r\n"));
+
+    let mut buffer = [0;4096];
+    let sz = client.read(&mut buffer[..]).unwrap_or(0);
+    let answer = str::from_utf8(&buffer[..sz]).expect("could not make string from buffer");
+    assert!(!answer.starts_with("HTTP/1.1 200 "), "unexpected answer: {}", answer);
+    assert!(rx.recv_timeout(Duration::from_millis(500)).is_err(), "the POST request should not be sent again");
+  }
+
+  #[test]
+  fn backend_closed_during_response() {
+    setup_test_logger!();
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+
+
+    // closes the connection during the status line, or after a part of the body
+    let broken = TcpListener::bind("127.0.0.1:1119").expect("could not bind");
+    thread::spawn(move|| {
+      for stream in broken.incoming() {
+        let mut stream = stream.expect("could not accept");
+        let request = read_headers(&mut stream);
+        if request.starts_with("GET /body ") {
+          stream.write_all(&b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nabc"[..]).unwrap();
+          thread::sleep(Duration::from_millis(200));
+        } else {
+          stream.write_all(&b"HTTP/1.1 2"[..]).unwrap();
+        }
+      }
+    });
+
+    let (tx, rx) = mpsc::channel();
+    let broken = TcpListener::bind("127.0.0.1:1120").expect("could not bind");
+    thread::spawn(move|| {
+      for stream in broken.incoming() {
+        let mut stream = stream.expect("could not accept");
+        tx.send(read_headers(&mut stream)).unwrap();
+        stream.write_all(&b"HTTP/1.1 2"[..]).unwrap();
+      }
+    });
+
+    let working = TcpListener::bind("127.0.0.1:1121").expect("could not bind");
+    thread::spawn(move|| {
+      for stream in working.incoming() {
+        let mut stream = stream.expect("could not accept");
+        read_headers(&mut stream);
+        stream.write_all(&b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok"[..]).unwrap();
+      }
+    });
+
+    let front: SocketAddr = FromStr::from_str("127.0.0.1:1122").expect("could not parse address");
+    let config = HttpListener {
+      front,
+      ..Default::default()
+    };
+
+    let (mut command, channel) = Channel::generate(1000, 10000).expect("should create a channel");
+    thread::spawn(move|| {
+      start(config, channel, 10, 16384);
+    });
+
+    for &(app_id, retry_unanswered_requests, hostname) in &[("app_1", false, "localhost"), ("app_2", true, "example.com")] {
+      let application = Application { app_id: String::from(app_id), retry_unanswered_requests, ..Default::default() };
+      command.write_message(&ProxyRequest { id: String::from("ID_APP"), order: ProxyRequestData::AddApplication(application) });
+      let front = HttpFront { app_id: String::from(app_id), address: "127.0.0.1:1122".parse().unwrap(), hostname: String::from(hostname), path_begin: String::from("/"), trailing_slash: TrailingSlash::Strict, method: None, header_match: None, split: None };
+      command.write_message(&ProxyRequest { id: String::from("ID_ABCD"), order: ProxyRequestData::AddHttpFront(front) });
+    }
+    for &(app_id, backend_id, address) in &[("app_1", "app_1-0", "127.0.0.1:1119"), ("app_2", "app_2-0", "127.0.0.1:1120"), ("app_2", "app_2-1", "127.0.0.1:1121")] {
+      let backend = Backend { app_id: String::from(app_id), backend_id: String::from(backend_id), address: address.parse().unwrap(), load_balancing_parameters: Some(LoadBalancingParams::default()), sticky_id: None, backup: None, hostname: None };
+      command.write_message(&ProxyRequest { id: String::from("ID_EFGH"), order: ProxyRequestData::AddBackend(backend) });
+    }
+
+    for _ in 0..7 {
+      println!("test received: {:?}", command.read_message());
+    }
+
+    // nothing was sent to the client yet, it gets a 502
+    let mut client = TcpStream::connect(("127.0.0.1", 1122)).expect("could not parse address");
+    client.set_read_timeout(Some(Duration::new(5,0)));
+    client.write_all(&b"GET /headers HTTP/1.1\r\nHost: localhost:1122\r\n\r\n"[..]).unwrap();
+    let answer = read_until(&mut client, "\r\n\r\n");
+    assert!(answer.starts_with("HTTP/1.1 502 Bad Gateway\r\n"), "unexpected answer: {}", answer);
+
+    // the request is sent again to the other backend of the application
+    for _ in 0..2 {
+      let mut client = TcpStream::connect(("127.0.0.1", 1122)).expect("could not parse address");
+      client.set_read_timeout(Some(Duration::new(5,0)));
+      client.write_all(&b"GET /headers HTTP/1.1\r\nHost: example.com:1122\r\n\r\n"[..]).unwrap();
+      let answer = read_until(&mut client, "\r\n\r\nok");
+      assert!(answer.starts_with("HTTP/1.1 200 OK\r\n"), "unexpected answer: {}", answer);
+    }
+    assert!(rx.recv_timeout(Duration::new(1,0)).is_ok(), "the broken backend should have received a request");
+
+    // a part of the response was forwarded, the client connection is closed
+    let mut client = TcpStream::connect(("127.0.0.1", 1122)).expect("could not parse address");
+    client.set_read_timeout(Some(Duration::new(5,0)));
+    client.write_all(&b"GET /body HTTP/1.1\r\nHost: localhost:1122\r\n\r\n"[..]).unwrap();
+    let mut answer = Vec::new();
+    client.read_to_end(&mut answer).expect("the client connection should be closed");
+    let answer = String::from_utf8(answer).expect("could not make string from buffer");
+    assert!(answer.starts_with("HTTP/1.1 200 OK\r\n"), "unexpected answer: {}", answer);
+    assert!(answer.ends_with("\r\n\r\nabc"), "unexpected answer: {}", answer);
+  }
+
+  #[test]
+  fn connection_error_metrics() {
+    setup_test_logger!();
+    use sozu_command::proxy::{FilteredData, ProxyResponseData};
+
+    let front: SocketAddr = FromStr::from_str("127.0.0.1:1123").expect("could not parse address");
+    let config = HttpListener {
+      front,
+      ..Default::default()
+    };
+
+    let (mut command, channel) = Channel::generate(1000, 10000).expect("should create a channel");
+    thread::spawn(move|| {
+      start(config, channel, 10, 16384);
+    });
+
+    // the application has no backend
+    let front = HttpFront { app_id: String::from("app_1"), address: "127.0.0.1:1123".parse().unwrap(), hostname: String::from("localhost"), path_begin: String::from("/"), trailing_slash: TrailingSlash::Strict, method: None, header_match: None, split: None };
+    command.write_message(&ProxyRequest { id: String::from("ID_ABCD"), order: ProxyRequestData::AddHttpFront(front) });
+    println!("test received: {:?}", command.read_message());
+
+    let requests: &[(&[u8], &str)] = &[
+      (&b"GET / HTTP/1.1\r\nHost: localhost:abc\r\n\r\n"[..], "HTTP/1.1 400 "),
+      (&b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n"[..], "HTTP/1.1 404 "),
+      (&b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n"[..], "HTTP/1.1 404 "),
+      (&b"GET / HTTP/1.1\r\nHost: localhost:1123\r\n\r\n"[..], "HTTP/1.1 503 "),
+    ];
+    for &(request, status_line) in requests {
+      let mut client = TcpStream::connect(("127.0.0.1", 1123)).expect("could not parse address");
+      client.set_read_timeout(Some(Duration::new(5,0)));
+      client.write_all(request).unwrap();
+      let answer = read_until(&mut client, "\r\n\r\n");
+      assert!(answer.starts_with(status_line), "unexpected answer: {}", answer);
+    }
+
+    command.write_message(&ProxyRequest { id: String::from("ID_METRICS"), order: ProxyRequestData::Metrics });
+    let metrics = match command.read_message().unwrap().data {
+      Some(ProxyResponseData::Metrics(metrics)) => metrics.proxy,
+      data => panic!("expected metrics, got {:?}", data),
+    };
+
+    assert_eq!(metrics.get("connection_errors.invalid_host"), Some(&FilteredData::Count(1)));
+    assert_eq!(metrics.get("connection_errors.host_not_found"), Some(&FilteredData::Count(2)));
+    assert_eq!(metrics.get("connection_errors.no_backend_available"), Some(&FilteredData::Count(1)));
+    assert_eq!(metrics.get("connection_errors.no_host_given"), None);
+  }
+
+  #[test]
+  fn header_routing() {
+    setup_test_logger!();
+    use sozu_command::proxy::HeaderMatch;
+
+    let front: SocketAddr = FromStr::from_str("127.0.0.1:1124").expect("could not parse address");
+    let config = HttpListener {
+      front,
+      ..Default::default()
+    };
+
+    let (mut command, channel) = Channel::generate(1000, 10000).expect("should create a channel");
+    thread::spawn(move|| {
+      start(config, channel, 10, 16384);
+    });
+
+    // the application has no backend, so a routed request is answered with a 503
+    let header_match = Some(HeaderMatch { name: String::from("X-Version"), value: String::from("canary") });
+    let front = HttpFront { app_id: String::from("app_canary"), address: "127.0.0.1:1124".parse().unwrap(), hostname: String::from("localhost"), path_begin: String::from("/"), trailing_slash: TrailingSlash::Strict, method: None, header_match, split: None };
+    command.write_message(&ProxyRequest { id: String::from("ID_ABCD"), order: ProxyRequestData::AddHttpFront(front) });
+    println!("test received: {:?}", command.read_message());
+
+    let requests: &[(&[u8], &str)] = &[
+      (&b"GET / HTTP/1.1\r\nHost: localhost\r\nX-Version: canary\r\n\r\n"[..], "HTTP/1.1 503 "),
+      (&b"GET / HTTP/1.1\r\nHost: localhost\r\nx-version: canary\r\n\r\n"[..], "HTTP/1.1 503 "),
+      (&b"GET / HTTP/1.1\r\nHost: localhost\r\nX-Version: stable\r\n\r\n"[..], "HTTP/1.1 404 "),
+      (&b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n"[..], "HTTP/1.1 404 "),
+    ];
+    for &(request, status_line) in requests {
+      let mut client = TcpStream::connect(("127.0.0.1", 1124)).expect("could not parse address");
+      client.set_read_timeout(Some(Duration::new(5,0)));
+      client.write_all(request).unwrap();
+      let answer = read_until(&mut client, "\r\n\r\n");
+      assert!(answer.starts_with(status_line), "unexpected answer: {}", answer);
+    }
+  }
+
+  #[test]
+  fn listener_connect_timeout() {
+    setup_test_logger!();
+    use std::net::TcpListener;
+    use std::os::unix::io::AsRawFd;
+    use std::time::Instant;
+
+    let backend = TcpListener::bind("127.0.0.1:1076").expect("could not bind");
+    unsafe { ::libc::listen(backend.as_raw_fd(), 0) };
+    let _queued = TcpStream::connect("127.0.0.1:1076").expect("could not connect");
+
+    // the listener's connect timeout replaces the global one of 3 seconds
+    let front: SocketAddr = FromStr::from_str("127.0.0.1:1077").expect("could not parse address");
+    let config = HttpListener {
+      front,
+      timeouts: ListenerTimeouts { connect_timeout: Some(1), ..Default::default() },
+      ..Default::default()
+    };
+
+    let (mut command, channel) = Channel::generate(1000, 10000).expect("should create a channel");
+    thread::spawn(move|| {
+      start(config, channel, 10, 16384);
+    });
+
+    let front = HttpFront { app_id: String::from("app_1"), address: "127.0.0.1:1077".parse().unwrap(), hostname: String::from("localhost"), path_begin: String::from("/"), trailing_slash: TrailingSlash::Strict, method: None, header_match: None, split: None };
+    command.write_message(&ProxyRequest { id: String::from("ID_ABCD"), order: ProxyRequestData::AddHttpFront(front) });
+    let backend = Backend { app_id: String::from("app_1"), backend_id: String::from("app_1-0"), address: "127.0.0.1:1076".parse().unwrap(), load_balancing_parameters: Some(LoadBalancingParams::default()), sticky_id: None, backup: None, hostname: None };
+    command.write_message(&ProxyRequest { id: String::from("ID_EFGH"), order: ProxyRequestData::AddBackend(backend) });
+
+    println!("test received: {:?}", command.read_message());
+    println!("test received: {:?}", command.read_message());
+
+    let start_time = Instant::now();
+    let mut client = TcpStream::connect(("127.0.0.1", 1077)).expect("could not parse address");
+    client.set_read_timeout(Some(Duration::new(20,0)));
+    client.write(&b"GET / HTTP/1.1\r\nHost: localhost:1077\r\n\r\n"[..]).unwrap();
+
+    let mut buffer = [0;4096];
+    let sz = client.read(&mut buffer[..]).expect("the proxy should answer before the read timeout");
+    let answer = str::from_utf8(&buffer[..sz]).expect("could not make string from buffer");
+    println!("Response: {}", answer);
+
+    assert!(answer.starts_with("HTTP/1.1 503 "));
+    let elapsed = start_time.elapsed();
+    assert!(elapsed >= Duration::from_secs(1));
+    assert!(elapsed < Duration::from_secs(3));
+  }
+
+  #[test]
+  fn session_gauges() {
+    setup_test_logger!();
+    use std::net::TcpListener;
+    use std::os::unix::io::AsRawFd;
+    use sozu_command::proxy::{FilteredData, ProxyResponseData};
+
+    // connections to this backend stay pending since its accept queue is full
+    let pending = TcpListener::bind("127.0.0.1:1126").expect("could not bind");
+    unsafe { ::libc::listen(pending.as_raw_fd(), 0) };
+    let _queued = TcpStream::connect("127.0.0.1:1126").expect("could not connect");
+    // this one accepts the connections but never answers
+    let _held = TcpListener::bind("127.0.0.1:1127").expect("could not bind");
+
+    let front: SocketAddr = FromStr::from_str("127.0.0.1:1128").expect("could not parse address");
+    let config = HttpListener {
+      front,
+      ..Default::default()
+    };
+
+    let (mut command, channel) = Channel::generate(1000, 10000).expect("should create a channel");
+    thread::spawn(move|| {
+      start(config, channel, 10, 16384);
+    });
+
+    for &(app_id, hostname, backend_address) in [("app_pending", "pending", "127.0.0.1:1126"), ("app_held", "localhost", "127.0.0.1:1127")].iter() {
+      let front = HttpFront { app_id: String::from(app_id), address: "127.0.0.1:1128".parse().unwrap(), hostname: String::from(hostname), path_begin: String::from("/"), trailing_slash: TrailingSlash::Strict, method: None, header_match: None, split: None };
+      command.write_message(&ProxyRequest { id: format!("ID_FRONT_{}", app_id), order: ProxyRequestData::AddHttpFront(front) });
+      let backend = Backend { app_id: String::from(app_id), backend_id: format!("{}-0", app_id), address: backend_address.parse().unwrap(), load_balancing_parameters: Some(LoadBalancingParams::default()), sticky_id: None, backup: None, hostname: None };
+      command.write_message(&ProxyRequest { id: format!("ID_BACKEND_{}", app_id), order: ProxyRequestData::AddBackend(backend) });
+      println!("test received: {:?}", command.read_message());
+      println!("test received: {:?}", command.read_message());
+    }
+
+    let mut connected = TcpStream::connect(("127.0.0.1", 1128)).expect("could not connect");
+    connected.write_all(&b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n"[..]).unwrap();
+    let mut connecting = TcpStream::connect(("127.0.0.1", 1128)).expect("could not connect");
+    connecting.write_all(&b"GET / HTTP/1.1\r\nHost: pending\r\n\r\n"[..]).unwrap();
+    let _idle = TcpStream::connect(("127.0.0.1", 1128)).expect("could not connect");
+    thread::sleep(Duration::from_millis(500));
+
+    command.write_message(&ProxyRequest { id: String::from("ID_METRICS"), order: ProxyRequestData::Metrics });
+    let metrics = match command.read_message().unwrap().data {
+      Some(ProxyResponseData::Metrics(metrics)) => metrics.proxy,
+      data => panic!("expected metrics, got {:?}", data),
+    };
+
+    assert_eq!(metrics.get("sessions.front"), Some(&FilteredData::Gauge(3)));
+    assert_eq!(metrics.get("sessions.back.connecting"), Some(&FilteredData::Gauge(1)));
+    assert_eq!(metrics.get("sessions.back.connected"), Some(&FilteredData::Gauge(1)));
+    assert_eq!(metrics.get("backends.active_connections"), Some(&FilteredData::Gauge(2)));
+  }
+
+  #[test]
+  fn websocket_upgrade_tunnel() {
+    setup_test_logger!();
+    use std::net::{TcpListener, Shutdown};
+
+
+    let backend = TcpListener::bind("127.0.0.1:1057").expect("could not bind");
+    thread::spawn(move|| {
+      let (mut stream, _) = backend.accept().expect("could not accept");
+      let request = read_headers(&mut stream);
+      println!("backend received: {}", request);
+      stream.write_all(&b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\r\n"[..]).unwrap();
+
+      let mut buffer = [0;4];
+      stream.read_exact(&mut buffer[..]).expect("could not read from the tunnel");
+      assert_eq!(&buffer[..], &b"ping"[..]);
+      stream.write_all(&b"pong"[..]).unwrap();
+
+      // the client's half close reaches the backend, which can still answer
+      let mut rest = Vec::new();
+      stream.read_to_end(&mut rest).expect("could not read until the end of stream");
+      assert!(rest.is_empty());
+      stream.write_all(&b"bye"[..]).unwrap();
+    });
+
+    let front: SocketAddr = FromStr::from_str("127.0.0.1:1058").expect("could not parse address");
+    let config = HttpListener {
+      front,
+      ..Default::default()
+    };
+
+    let (mut command, channel) = Channel::generate(1000, 10000).expect("should create a channel");
+    thread::spawn(move|| {
+      start(config, channel, 10, 16384);
+    });
+
+    let front = HttpFront { app_id: String::from("app_1"), address: "127.0.0.1:1058".parse().unwrap(), hostname: String::from("localhost"), path_begin: String::from("/"), trailing_slash: TrailingSlash::Strict, method: None, header_match: None, split: None };
+    command.write_message(&ProxyRequest { id: String::from("ID_ABCD"), order: ProxyRequestData::AddHttpFront(front) });
+    let backend = Backend { app_id: String::from("app_1"), backend_id: String::from("app_1-0"), address: "127.0.0.1:1057".parse().unwrap(), load_balancing_parameters: Some(LoadBalancingParams::default()), sticky_id: None, backup: None, hostname: None };
+    command.write_message(&ProxyRequest { id: String::from("ID_EFGH"), order: ProxyRequestData::AddBackend(backend) });
+
+    println!("test received: {:?}", command.read_message());
+    println!("test received: {:?}", command.read_message());
+
+    let mut client = TcpStream::connect(("127.0.0.1", 1058)).expect("could not parse address");
+    client.set_read_timeout(Some(Duration::new(5,0)));
+    client.write_all(&b"GET /ws HTTP/1.1\r\nHost: localhost:1058\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\r\n"[..]).unwrap();
+
+    let answer = read_headers(&mut client);
+    println!("Response: {}", answer);
+    assert!(answer.starts_with("HTTP/1.1 101 "));
+
+    // after the upgrade, the bytes are forwarded as is in both directions
+    client.write_all(&b"ping"[..]).unwrap();
+    let mut buffer = [0;4];
+    client.read_exact(&mut buffer[..]).expect("could not read from the tunnel");
+    assert_eq!(&buffer[..], &b"pong"[..]);
+
+    client.shutdown(Shutdown::Write).unwrap();
+    let mut rest = Vec::new();
+    client.read_to_end(&mut rest).expect("the backend's end of stream should be forwarded");
+    assert_eq!(&rest[..], &b"bye"[..]);
+  }
+
+  #[test]
+  fn application_header_rules() {
+    setup_test_logger!();
+    use std::net::TcpListener;
+    use sozu_command::proxy::HeaderRule;
+
+
+    let backend = TcpListener::bind("127.0.0.1:1065").expect("could not bind");
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move|| {
+      let (mut stream, _) = backend.accept().expect("could not accept");
+      tx.send(read_headers(&mut stream)).unwrap();
+      stream.write_all(&b"HTTP/1.1 200 OK\r\nX-Powered-By: php\r\nX-Frame-Options: SAMEORIGIN\r\nContent-Length: 2\r\n\r\nok"[..]).unwrap();
+    });
+
+    let front: SocketAddr = FromStr::from_str("127.0.0.1:1066").expect("could not parse address");
+    let config = HttpListener {
+      front,
+      ..Default::default()
+    };
+
+    let (mut command, channel) = Channel::generate(1000, 10000).expect("should create a channel");
+    thread::spawn(move|| {
+      start(config, channel, 10, 16384);
+    });
+
+    let application = Application {
+      app_id: String::from("app_1"),
+      request_headers: vec![
+        HeaderRule::Add { name: String::from("Authorization"), value: String::from("Bearer secret"), replace: true },
+        HeaderRule::Add { name: String::from("X-Tag"), value: String::from("sozu"), replace: false },
+        HeaderRule::Remove { name: String::from("X-Internal") },
+        HeaderRule::Remove { name: String::from("X-Missing") },
+      ],
+      response_headers: vec![
+        HeaderRule::Remove { name: String::from("x-powered-by") },
+        HeaderRule::Add { name: String::from("X-Frame-Options"), value: String::from("DENY"), replace: false },
+      ],
+      request_id_headers: vec![String::from("X-Request-Id"), String::from("Request-Id")],
+      ..Default::default()
+    };
+    command.write_message(&ProxyRequest { id: String::from("ID_APP"), order: ProxyRequestData::AddApplication(application) });
+    let front = HttpFront { app_id: String::from("app_1"), address: "127.0.0.1:1066".parse().unwrap(), hostname: String::from("localhost"), path_begin: String::from("/"), trailing_slash: TrailingSlash::Strict, method: None, header_match: None, split: None };
+    command.write_message(&ProxyRequest { id: String::from("ID_ABCD"), order: ProxyRequestData::AddHttpFront(front) });
+    let backend = Backend { app_id: String::from("app_1"), backend_id: String::from("app_1-0"), address: "127.0.0.1:1065".parse().unwrap(), load_balancing_parameters: Some(LoadBalancingParams::default()), sticky_id: None, backup: None, hostname: None };
+    command.write_message(&ProxyRequest { id: String::from("ID_EFGH"), order: ProxyRequestData::AddBackend(backend) });
+
+    println!("test received: {:?}", command.read_message());
+    println!("test received: {:?}", command.read_message());
+    println!("test received: {:?}", command.read_message());
+
+    let mut client = TcpStream::connect(("127.0.0.1", 1066)).expect("could not parse address");
+    client.set_read_timeout(Some(Duration::new(5,0)));
+    client.write_all(&b"GET / HTTP/1.1\r\nAuthorization: Basic dXNlcjpwYXNz\r\nX-Internal: 1\r\n\
+      Host: localhost:1066\r\nX-Tag: client\r\nX-Request-Id: client-id\r\n\r\n"[..]).unwrap();
+
+    let request = rx.recv_timeout(Duration::new(5,0)).expect("the backend should receive the request");
+    println!("backend received: {}", request);
+    assert!(request.contains("Authorization: Bearer secret\r\n"));
+    assert!(!request.contains("Basic"));
+    assert!(!request.contains("X-Internal"));
+    // added headers do not overwrite the existing ones unless replace is set
+    assert!(request.contains("X-Tag: client\r\n"));
+    assert!(request.contains("X-Tag: sozu\r\n"));
+    // the request id is sent under each configured name
+    let id = request.lines().find(|line| line.starts_with("Sozu-Id: ")).expect("should have a request id")["Sozu-Id: ".len()..].to_string();
+    assert!(request.contains(&format!("X-Request-Id: {}\r\n", id)));
+    assert!(request.contains(&format!("Request-Id: {}\r\n", id)));
+    assert!(!request.contains("client-id"));
+
+    let answer = read_headers(&mut client);
+    println!("Response: {}", answer);
+    assert!(answer.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(!answer.contains("X-Powered-By"));
+    assert!(answer.contains("X-Frame-Options: SAMEORIGIN\r\n"));
+    assert!(answer.contains("X-Frame-Options: DENY\r\n"));
+
+    let mut body = [0;2];
+    client.read_exact(&mut body[..]).expect("could not read the body");
+    assert_eq!(&body[..], &b"ok"[..]);
+  }
+
+  #[test]
+  fn location_rewriting() {
+    setup_test_logger!();
+    use std::net::TcpListener;
+
+
+    let backend = TcpListener::bind("127.0.0.1:1089").expect("could not bind");
+    thread::spawn(move|| {
+      let (mut stream, _) = backend.accept().expect("could not accept");
+      read_headers(&mut stream);
+      stream.write_all(&b"HTTP/1.1 302 Found\r\nLocation: http://127.0.0.1:1089/login?next=%2F\r\nContent-Length: 0\r\n\r\n"[..]).unwrap();
+      read_headers(&mut stream);
+      stream.write_all(&b"HTTP/1.1 302 Found\r\nLocation: /login\r\nContent-Length: 0\r\n\r\n"[..]).unwrap();
+    });
+
+    let front: SocketAddr = FromStr::from_str("127.0.0.1:1090").expect("could not parse address");
+    let config = HttpListener {
+      front,
+      ..Default::default()
+    };
+
+    let (mut command, channel) = Channel::generate(1000, 10000).expect("should create a channel");
+    thread::spawn(move|| {
+      start(config, channel, 10, 16384);
+    });
+
+    let application = Application {
+      app_id: String::from("app_1"),
+      rewrite_location: true,
+      ..Default::default()
+    };
+    command.write_message(&ProxyRequest { id: String::from("ID_APP"), order: ProxyRequestData::AddApplication(application) });
+    let front = HttpFront { app_id: String::from("app_1"), address: "127.0.0.1:1090".parse().unwrap(), hostname: String::from("localhost"), path_begin: String::from("/"), trailing_slash: TrailingSlash::Strict, method: None, header_match: None, split: None };
+    command.write_message(&ProxyRequest { id: String::from("ID_ABCD"), order: ProxyRequestData::AddHttpFront(front) });
+    let backend = Backend { app_id: String::from("app_1"), backend_id: String::from("app_1-0"), address: "127.0.0.1:1089".parse().unwrap(), load_balancing_parameters: Some(LoadBalancingParams::default()), sticky_id: None, backup: None, hostname: None };
+    command.write_message(&ProxyRequest { id: String::from("ID_EFGH"), order: ProxyRequestData::AddBackend(backend) });
+
+    println!("test received: {:?}", command.read_message());
+    println!("test received: {:?}", command.read_message());
+    println!("test received: {:?}", command.read_message());
+
+    let mut client = TcpStream::connect(("127.0.0.1", 1090)).expect("could not parse address");
+    client.set_read_timeout(Some(Duration::new(5,0)));
+    client.write_all(&b"GET / HTTP/1.1\r\nHost: localhost:1090\r\n\r\n"[..]).unwrap();
+
+    let answer = read_headers(&mut client);
+    println!("Response: {}", answer);
+    assert!(answer.starts_with("HTTP/1.1 302 Found\r\n"));
+    assert!(answer.contains("Location: http://localhost:1090/login?next=%2F\r\n"));
+    assert!(!answer.contains("127.0.0.1:1089"));
+
+    // relative locations are kept
+    client.write_all(&b"GET / HTTP/1.1\r\nHost: localhost:1090\r\n\r\n"[..]).unwrap();
+    let answer = read_headers(&mut client);
+    println!("Response: {}", answer);
+    assert!(answer.contains("Location: /login\r\n"));
+  }
+
+  #[test]
+  fn backend_host_rewriting() {
+    setup_test_logger!();
+    use std::net::TcpListener;
+
+    let backend = TcpListener::bind("127.0.0.1:1152").expect("could not bind");
+    thread::spawn(move|| {
+      let (mut stream, _) = backend.accept().expect("could not accept");
+      read_until(&mut stream, "\r\n\r\n");
+      stream.write_all(&b"HTTP/1.1 302 Found\r\nLocation: http://app.internal:8080/login\r\n\
+        Set-Cookie: session=abc; Path=/; Domain=app.internal; HttpOnly\r\n\
+        Set-Cookie: tracker=1; Domain=cdn.example.org\r\n\
+        Set-Cookie: lang=fr; Domain=.app.internal\r\n\
+        X-Served-By: app.internal\r\nContent-Length: 0\r\n\r\n"[..]).unwrap();
+      read_until(&mut stream, "\r\n\r\n");
+      // api.internal is not one of the backend hosts
+      stream.write_all(&b"HTTP/1.1 302 Found\r\nLocation: http://127.0.0.1:1152/v1\r\n\
+        Set-Cookie: token=xyz; Domain=api.internal\r\nContent-Length: 0\r\n\r\n"[..]).unwrap();
+    });
+
+    let front: SocketAddr = "127.0.0.1:1153".parse().expect("could not parse address");
+    let config = HttpListener {
+      front,
+      ..Default::default()
+    };
+
+    let (mut command, channel) = Channel::generate(1000, 10000).expect("should create a channel");
+    thread::spawn(move|| {
+      start(config, channel, 10, 16384);
+    });
+
+    let application = Application {
+      app_id: String::from("app_1"),
+      rewrite_location: true,
+      backend_hosts: vec![String::from("app.internal")],
+      rewrite_cookie_domain: true,
+      ..Default::default()
+    };
+    command.write_message(&ProxyRequest { id: String::from("ID_APP"), order: ProxyRequestData::AddApplication(application) });
+    let front = HttpFront { app_id: String::from("app_1"), address: front, hostname: String::from("localhost"), path_begin: String::from("/"), trailing_slash: TrailingSlash::Strict, method: None, header_match: None, split: None };
+    command.write_message(&ProxyRequest { id: String::from("ID_ABCD"), order: ProxyRequestData::AddHttpFront(front) });
+    let backend = Backend { app_id: String::from("app_1"), backend_id: String::from("app_1-0"), address: "127.0.0.1:1152".parse().unwrap(), load_balancing_parameters: Some(LoadBalancingParams::default()), sticky_id: None, backup: None, hostname: None };
+    command.write_message(&ProxyRequest { id: String::from("ID_EFGH"), order: ProxyRequestData::AddBackend(backend) });
+
+    println!("test received: {:?}", command.read_message());
+    println!("test received: {:?}", command.read_message());
+    println!("test received: {:?}", command.read_message());
+
+    let mut client = TcpStream::connect(("127.0.0.1", 1153)).expect("could not parse address");
+    client.set_read_timeout(Some(Duration::new(5,0))).unwrap();
+    client.write_all(&b"GET / HTTP/1.1\r\nHost: localhost:1153\r\n\r\n"[..]).unwrap();
+
+    let answer = read_until(&mut client, "\r\n\r\n");
+    println!("Response: {}", answer);
+    assert!(answer.starts_with("HTTP/1.1 302 Found\r\n"));
+    assert!(answer.contains("Location: http://localhost:1153/login\r\n"));
+    assert!(answer.contains("Set-Cookie: session=abc; Path=/; Domain=localhost; HttpOnly\r\n"));
+    assert!(answer.contains("Set-Cookie: lang=fr; Domain=localhost\r\n"));
+    // the other cookies and headers are left alone
+    assert!(answer.contains("Set-Cookie: tracker=1; Domain=cdn.example.org\r\n"));
+    assert!(answer.contains("X-Served-By: app.internal\r\n"));
+    assert_eq!(answer.matches("Set-Cookie:").count(), 3);
+    assert_eq!(answer.matches("Location:").count(), 1);
+
+    client.write_all(&b"GET / HTTP/1.1\r\nHost: localhost:1153\r\n\r\n"[..]).unwrap();
+    let answer = read_until(&mut client, "\r\n\r\n");
+    println!("Response: {}", answer);
+    assert!(answer.contains("Location: http://localhost:1153/v1\r\n"));
+    assert!(answer.contains("Set-Cookie: token=xyz; Domain=api.internal\r\n"));
+  }
+
+  #[test]
+  fn half_closed_client() {
+    setup_test_logger!();
+    use std::net::{Shutdown, TcpListener};
+
+    let backend = TcpListener::bind("127.0.0.1:1091").expect("could not bind");
+    thread::spawn(move|| {
+      for body_size in [0, 4].iter() {
+        let (mut stream, _) = backend.accept().expect("could not accept");
+        read_headers(&mut stream);
+        let mut body = vec![0; *body_size];
+        stream.read_exact(&mut body).expect("could not read the body");
+        // answer after the client shut down its side
+        thread::sleep(Duration::from_millis(100));
+        stream.write_all(&b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello"[..]).unwrap();
+      }
+    });
+
+    let front: SocketAddr = FromStr::from_str("127.0.0.1:1092").expect("could not parse address");
+    let config = HttpListener {
+      front,
+      ..Default::default()
+    };
+
+    let (mut command, channel) = Channel::generate(1000, 10000).expect("should create a channel");
+    thread::spawn(move|| {
+      start(config, channel, 10, 16384);
+    });
+
+    let front = HttpFront { app_id: String::from("app_1"), address: "127.0.0.1:1092".parse().unwrap(), hostname: String::from("localhost"), path_begin: String::from("/"), trailing_slash: TrailingSlash::Strict, method: None, header_match: None, split: None };
+    command.write_message(&ProxyRequest { id: String::from("ID_ABCD"), order: ProxyRequestData::AddHttpFront(front) });
+    let backend = Backend { app_id: String::from("app_1"), backend_id: String::from("app_1-0"), address: "127.0.0.1:1091".parse().unwrap(), load_balancing_parameters: Some(LoadBalancingParams::default()), sticky_id: None, backup: None, hostname: None };
+    command.write_message(&ProxyRequest { id: String::from("ID_EFGH"), order: ProxyRequestData::AddBackend(backend) });
+
+    println!("test received: {:?}", command.read_message());
+    println!("test received: {:?}", command.read_message());
+
+    // with and without a body
+    for request in [
+      &b"GET / HTTP/1.1\r\nHost: localhost:1092\r\n\r\n"[..],
+      &b"POST / HTTP/1.1\r\nHost: localhost:1092\r\nContent-Length: 4\r\n\r\nbody"[..],
+    ].iter() {
+      let mut client = TcpStream::connect(("127.0.0.1", 1092)).expect("could not parse address");
+      client.set_read_timeout(Some(Duration::new(5,0)));
+      client.write_all(request).unwrap();
+      client.shutdown(Shutdown::Write).unwrap();
+
+      let mut answer = String::new();
+      client.read_to_string(&mut answer).expect("could not read the response");
+      println!("Response: {}", answer);
+      assert!(answer.starts_with("HTTP/1.1 200 OK\r\n"));
+      assert!(answer.ends_with("\r\n\r\nhello"));
+    }
+  }
+
+  #[test]
+  fn listener_session_limit() {
+    setup_test_logger!();
+    use std::net::TcpListener;
+
+    fn read_answer(stream: &mut TcpStream) -> ::std::io::Result<String> {
+      let mut data = Vec::new();
+      let mut buffer = [0;1];
+      while !data.ends_with(&b"\r\n\r\nhello"[..]) {
+        let sz = stream.read(&mut buffer[..])?;
+        assert!(sz > 0, "connection closed while reading the answer");
+        data.push(buffer[0]);
+      }
+      Ok(String::from_utf8(data).expect("could not make string from buffer"))
+    }
+
+    let backend = TcpListener::bind("127.0.0.1:1093").expect("could not bind");
+    thread::spawn(move|| {
+      for stream in backend.incoming() {
+        let mut stream = stream.expect("could not accept");
+        thread::spawn(move|| {
+          let mut data = Vec::new();
+          let mut buffer = [0;1];
+          loop {
+            match stream.read(&mut buffer[..]) {
+              Ok(0) | Err(_) => break,
+              Ok(_) => data.push(buffer[0]),
+            }
+            if data.ends_with(&b"\r\n\r\n"[..]) {
+              data.clear();
+              stream.write_all(&b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello"[..]).unwrap();
+            }
+          }
+        });
+      }
+    });
+
+    let front: SocketAddr = FromStr::from_str("127.0.0.1:1094").expect("could not parse address");
+    let config = HttpListener {
+      front,
+      max_sessions: Some(1),
+      ..Default::default()
+    };
+
+    let (mut command, channel) = Channel::generate(1000, 10000).expect("should create a channel");
+    thread::spawn(move|| {
+      start(config, channel, 10, 16384);
+    });
+
+    let front = HttpFront { app_id: String::from("app_1"), address: "127.0.0.1:1094".parse().unwrap(), hostname: String::from("localhost"), path_begin: String::from("/"), trailing_slash: TrailingSlash::Strict, method: None, header_match: None, split: None };
+    command.write_message(&ProxyRequest { id: String::from("ID_ABCD"), order: ProxyRequestData::AddHttpFront(front) });
+    let backend = Backend { app_id: String::from("app_1"), backend_id: String::from("app_1-0"), address: "127.0.0.1:1093".parse().unwrap(), load_balancing_parameters: Some(LoadBalancingParams::default()), sticky_id: None, backup: None, hostname: None };
+    command.write_message(&ProxyRequest { id: String::from("ID_EFGH"), order: ProxyRequestData::AddBackend(backend) });
+
+    println!("test received: {:?}", command.read_message());
+    println!("test received: {:?}", command.read_message());
+
+    let mut first = TcpStream::connect(("127.0.0.1", 1094)).expect("could not parse address");
+    first.set_read_timeout(Some(Duration::new(5,0)));
+    first.write_all(&b"GET / HTTP/1.1\r\nHost: localhost:1094\r\n\r\n"[..]).unwrap();
+    let answer = read_answer(&mut first).expect("could not read the answer");
+    assert!(answer.starts_with("HTTP/1.1 200 OK\r\n"));
+
+    // the listener is paused: the connection waits in the backlog instead of being closed
+    let mut second = TcpStream::connect(("127.0.0.1", 1094)).expect("could not parse address");
+    second.set_read_timeout(Some(Duration::from_millis(500)));
+    second.write_all(&b"GET / HTTP/1.1\r\nHost: localhost:1094\r\n\r\n"[..]).unwrap();
+    assert!(read_answer(&mut second).is_err());
+
+    // the first session ends, the listener accepts again
+    drop(first);
+    second.set_read_timeout(Some(Duration::new(5,0)));
+    let answer = read_answer(&mut second).expect("could not read the answer");
+    assert!(answer.starts_with("HTTP/1.1 200 OK\r\n"));
+  }
+
+  /// waits for the final answer to a stop order
+  fn read_stop_answer(command: &mut Channel<ProxyRequest,ProxyResponse>, id: &str) -> ProxyResponse {
+    loop {
+      let answer = command.read_message().expect("the proxy did not answer");
+      if answer.id == id && answer.status != ProxyResponseStatus::Processing {
+        return answer;
+      }
+    }
+  }
+
+  #[test]
+  fn drain_completes_before_the_timeout() {
+    setup_test_logger!();
+    use std::net::TcpListener;
+    use std::time::Instant;
+    use sozu_command::proxy::ProxyResponseData;
+
+    let backend = TcpListener::bind("127.0.0.1:1095").expect("could not bind");
+    thread::spawn(move|| {
+      let (mut stream, _) = backend.accept().expect("could not accept");
+      read_headers(&mut stream);
+      // the answer comes after the stop order
+      thread::sleep(Duration::from_millis(300));
+      stream.write_all(&b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello"[..]).unwrap();
+    });
+
+    let front: SocketAddr = FromStr::from_str("127.0.0.1:1096").expect("could not parse address");
+    let config = HttpListener {
+      front,
+      ..Default::default()
+    };
+
+    let (mut command, channel) = Channel::generate(1000, 10000).expect("should create a channel");
+    thread::spawn(move|| {
+      start(config, channel, 10, 16384);
+    });
+
+    let front = HttpFront { app_id: String::from("app_1"), address: "127.0.0.1:1096".parse().unwrap(), hostname: String::from("localhost"), path_begin: String::from("/"), trailing_slash: TrailingSlash::Strict, method: None, header_match: None, split: None };
+    command.write_message(&ProxyRequest { id: String::from("ID_ABCD"), order: ProxyRequestData::AddHttpFront(front) });
+    let backend = Backend { app_id: String::from("app_1"), backend_id: String::from("app_1-0"), address: "127.0.0.1:1095".parse().unwrap(), load_balancing_parameters: Some(LoadBalancingParams::default()), sticky_id: None, backup: None, hostname: None };
+    command.write_message(&ProxyRequest { id: String::from("ID_EFGH"), order: ProxyRequestData::AddBackend(backend) });
+
+    println!("test received: {:?}", command.read_message());
+    println!("test received: {:?}", command.read_message());
+
+    let mut client = TcpStream::connect(("127.0.0.1", 1096)).expect("could not parse address");
+    client.set_read_timeout(Some(Duration::new(5,0)));
+    client.write_all(&b"GET / HTTP/1.1\r\nHost: localhost:1096\r\n\r\n"[..]).unwrap();
+    thread::sleep(Duration::from_millis(100));
+
+    let start_time = Instant::now();
+    command.write_message(&ProxyRequest { id: String::from("ID_STOP"), order: ProxyRequestData::DrainAndStop(10) });
+
+    let mut answer = String::new();
+    client.read_to_string(&mut answer).expect("could not read the response");
+    println!("Response: {}", answer);
+    assert!(answer.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(answer.ends_with("\r\n\r\nhello"));
+
+    let stop = read_stop_answer(&mut command, "ID_STOP");
+    assert_eq!(stop.status, ProxyResponseStatus::Ok);
+    assert_eq!(stop.data, Some(ProxyResponseData::DrainedSessions(0)));
+    assert!(start_time.elapsed() < Duration::from_secs(5));
+  }
+
+  #[test]
+  fn drain_timeout_closes_the_sessions() {
+    setup_test_logger!();
+    use std::net::TcpListener;
+    use std::time::Instant;
+    use sozu_command::proxy::ProxyResponseData;
+
+    let backend = TcpListener::bind("127.0.0.1:1097").expect("could not bind");
+    thread::spawn(move|| {
+      let (mut stream, _) = backend.accept().expect("could not accept");
+      // never answers
+      let mut buffer = [0;1024];
+      while let Ok(sz) = stream.read(&mut buffer[..]) {
+        if sz == 0 {
+          break;
+        }
+      }
+    });
+
+    let front: SocketAddr = FromStr::from_str("127.0.0.1:1098").expect("could not parse address");
+    let config = HttpListener {
+      front,
+      ..Default::default()
+    };
+
+    let (mut command, channel) = Channel::generate(1000, 10000).expect("should create a channel");
+    thread::spawn(move|| {
+      start(config, channel, 10, 16384);
+    });
+
+    let front = HttpFront { app_id: String::from("app_1"), address: "127.0.0.1:1098".parse().unwrap(), hostname: String::from("localhost"), path_begin: String::from("/"), trailing_slash: TrailingSlash::Strict, method: None, header_match: None, split: None };
+    command.write_message(&ProxyRequest { id: String::from("ID_ABCD"), order: ProxyRequestData::AddHttpFront(front) });
+    let backend = Backend { app_id: String::from("app_1"), backend_id: String::from("app_1-0"), address: "127.0.0.1:1097".parse().unwrap(), load_balancing_parameters: Some(LoadBalancingParams::default()), sticky_id: None, backup: None, hostname: None };
+    command.write_message(&ProxyRequest { id: String::from("ID_EFGH"), order: ProxyRequestData::AddBackend(backend) });
+
+    println!("test received: {:?}", command.read_message());
+    println!("test received: {:?}", command.read_message());
+
+    let mut client = TcpStream::connect(("127.0.0.1", 1098)).expect("could not parse address");
+    client.set_read_timeout(Some(Duration::new(5,0)));
+    client.write_all(&b"GET / HTTP/1.1\r\nHost: localhost:1098\r\n\r\n"[..]).unwrap();
+    thread::sleep(Duration::from_millis(100));
+
+    let start_time = Instant::now();
+    command.write_message(&ProxyRequest { id: String::from("ID_STOP"), order: ProxyRequestData::DrainAndStop(1) });
+
+    let stop = read_stop_answer(&mut command, "ID_STOP");
+    assert_eq!(stop.status, ProxyResponseStatus::Ok);
+    assert_eq!(stop.data, Some(ProxyResponseData::DrainedSessions(1)));
+    assert!(start_time.elapsed() >= Duration::from_secs(1));
+
+    // the session was closed without an answer
+    let mut answer = Vec::new();
+    let _ = client.read_to_end(&mut answer);
+    assert!(answer.is_empty());
+  }
+
+  #[test]
+  fn unix_socket_listener() {
+    setup_test_logger!();
+    use std::net::TcpListener;
+    use std::os::unix::net::UnixStream;
+    use sozu_command::proxy::UnixSocket;
+
+    let backend = TcpListener::bind("127.0.0.1:1099").expect("could not bind");
+    thread::spawn(move|| {
+      let (mut stream, _) = backend.accept().expect("could not accept");
+      read_headers(&mut stream);
+      stream.write_all(&b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello"[..]).unwrap();
+    });
+
+    let path = ::std::env::temp_dir().join(format!("sozu-test-{}-http.sock", ::std::process::id()));
+    // the address identifies the listener, nothing is bound on it
+    let front: SocketAddr = FromStr::from_str("127.0.0.1:1100").expect("could not parse address");
+    let config = HttpListener {
+      front,
+      unix_socket: Some(UnixSocket { path: path.to_str().unwrap().to_string(), mode: Some(0o660) }),
+      ..Default::default()
+    };
+
+    let (mut command, channel) = Channel::generate(1000, 10000).expect("should create a channel");
+    thread::spawn(move|| {
+      start(config, channel, 10, 16384);
+    });
+
+    let front = HttpFront { app_id: String::from("app_1"), address: "127.0.0.1:1100".parse().unwrap(), hostname: String::from("localhost"), path_begin: String::from("/"), trailing_slash: TrailingSlash::Strict, method: None, header_match: None, split: None };
+    command.write_message(&ProxyRequest { id: String::from("ID_ABCD"), order: ProxyRequestData::AddHttpFront(front) });
+    let backend = Backend { app_id: String::from("app_1"), backend_id: String::from("app_1-0"), address: "127.0.0.1:1099".parse().unwrap(), load_balancing_parameters: Some(LoadBalancingParams::default()), sticky_id: None, backup: None, hostname: None };
+    command.write_message(&ProxyRequest { id: String::from("ID_EFGH"), order: ProxyRequestData::AddBackend(backend) });
+
+    println!("test received: {:?}", command.read_message());
+    println!("test received: {:?}", command.read_message());
+
+    assert!(TcpStream::connect(("127.0.0.1", 1100)).is_err());
+
+    let mut client = UnixStream::connect(&path).expect("could not connect");
+    client.set_read_timeout(Some(Duration::new(5,0)));
+    client.write_all(&b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n"[..]).unwrap();
+    let mut answer = String::new();
+    client.read_to_string(&mut answer).expect("could not read the response");
+    println!("Response: {}", answer);
+    assert!(answer.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(answer.ends_with("\r\n\r\nhello"));
+    drop(client);
+
+    command.write_message(&ProxyRequest { id: String::from("ID_STOP"), order: ProxyRequestData::SoftStop });
+    let stop = read_stop_answer(&mut command, "ID_STOP");
+    assert_eq!(stop.status, ProxyResponseStatus::Ok);
+    assert!(::std::fs::symlink_metadata(&path).is_err(), "the socket file was not removed");
+  }
+
+  #[test]
+  fn send_proxy_protocol_to_backend() {
+    setup_test_logger!();
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use sozu_command::config::ProxyProtocolConfig;
+
+
+    let (tx, rx) = mpsc::channel::<String>();
+    let backend = TcpListener::bind("127.0.0.1:1101").expect("could not bind");
+    thread::spawn(move|| {
+      let (mut stream, _) = backend.accept().expect("could not accept");
+      // the header comes once, before the first request of the connection
+      for _ in 0..2 {
+        let data = read_headers(&mut stream);
+        tx.send(data).unwrap();
+        stream.write_all(&b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok"[..]).unwrap();
+      }
+    });
+
+    let front: SocketAddr = FromStr::from_str("127.0.0.1:1102").expect("could not parse address");
+    let config = HttpListener {
+      front,
+      ..Default::default()
+    };
+
+    let (mut command, channel) = Channel::generate(1000, 10000).expect("should create a channel");
+    thread::spawn(move|| {
+      start(config, channel, 10, 16384);
+    });
+
+    let application = Application {
+      app_id: String::from("app_1"),
+      proxy_protocol: Some(ProxyProtocolConfig::SendHeader),
+      proxy_protocol_version: ProxyProtocolVersion::V1,
+      ..Default::default()
+    };
+    command.write_message(&ProxyRequest { id: String::from("ID_APP"), order: ProxyRequestData::AddApplication(application) });
+    let front = HttpFront { app_id: String::from("app_1"), address: "127.0.0.1:1102".parse().unwrap(), hostname: String::from("localhost"), path_begin: String::from("/"), trailing_slash: TrailingSlash::Strict, method: None, header_match: None, split: None };
+    command.write_message(&ProxyRequest { id: String::from("ID_ABCD"), order: ProxyRequestData::AddHttpFront(front) });
+    let backend = Backend { app_id: String::from("app_1"), backend_id: String::from("app_1-0"), address: "127.0.0.1:1101".parse().unwrap(), load_balancing_parameters: Some(LoadBalancingParams::default()), sticky_id: None, backup: None, hostname: None };
+    command.write_message(&ProxyRequest { id: String::from("ID_EFGH"), order: ProxyRequestData::AddBackend(backend) });
+
+    println!("test received: {:?}", command.read_message());
+    println!("test received: {:?}", command.read_message());
+    println!("test received: {:?}", command.read_message());
+
+    let mut client = ::std::net::TcpStream::connect(("127.0.0.1", 1102)).expect("could not parse address");
+    client.set_read_timeout(Some(Duration::new(5,0)));
+    let client_port = client.local_addr().unwrap().port();
+
+    for _ in 0..2 {
+      client.write_all(&b"GET / HTTP/1.1\r\nHost: localhost:1102\r\n\r\n"[..]).unwrap();
+      let answer = read_until(&mut client, "\r\n\r\nok");
+      assert!(answer.starts_with("HTTP/1.1 200 OK\r\n"));
+    }
+
+    let first = rx.recv_timeout(Duration::new(5,0)).expect("the backend should receive the request");
+    println!("backend received: {}", first);
+    assert!(first.starts_with(&format!("PROXY TCP4 127.0.0.1 127.0.0.1 {} 1102\r\nGET / HTTP/1.1\r\n", client_port)));
+    let second = rx.recv_timeout(Duration::new(5,0)).expect("the backend should receive the request");
+    assert!(second.starts_with("GET / HTTP/1.1\r\n"));
+  }
+
+  #[test]
+  fn expect_proxy_protocol_v1() {
+    setup_test_logger!();
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+
+
+    let (tx, rx) = mpsc::channel::<String>();
+    let backend = TcpListener::bind("127.0.0.1:1103").expect("could not bind");
+    thread::spawn(move|| {
+      let (mut stream, _) = backend.accept().expect("could not accept");
+      let data = read_headers(&mut stream);
+      tx.send(data).unwrap();
+      stream.write_all(&b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok"[..]).unwrap();
+    });
+
+    let front: SocketAddr = FromStr::from_str("127.0.0.1:1104").expect("could not parse address");
+    let config = HttpListener {
+      front,
+      expect_proxy: true,
+      ..Default::default()
+    };
+
+    let (mut command, channel) = Channel::generate(1000, 10000).expect("should create a channel");
+    thread::spawn(move|| {
+      start(config, channel, 10, 16384);
+    });
+
+    let front = HttpFront { app_id: String::from("app_1"), address: "127.0.0.1:1104".parse().unwrap(), hostname: String::from("localhost"), path_begin: String::from("/"), trailing_slash: TrailingSlash::Strict, method: None, header_match: None, split: None };
+    command.write_message(&ProxyRequest { id: String::from("ID_ABCD"), order: ProxyRequestData::AddHttpFront(front) });
+    let backend = Backend { app_id: String::from("app_1"), backend_id: String::from("app_1-0"), address: "127.0.0.1:1103".parse().unwrap(), load_balancing_parameters: Some(LoadBalancingParams::default()), sticky_id: None, backup: None, hostname: None };
+    command.write_message(&ProxyRequest { id: String::from("ID_EFGH"), order: ProxyRequestData::AddBackend(backend) });
+
+    println!("test received: {:?}", command.read_message());
+    println!("test received: {:?}", command.read_message());
+
+    // a malformed header closes the connection
+    let mut client = ::std::net::TcpStream::connect(("127.0.0.1", 1104)).expect("could not parse address");
+    client.set_read_timeout(Some(Duration::new(5,0)));
+    client.write_all(&b"GET / HTTP/1.1\r\nHost: localhost:1104\r\n\r\n"[..]).unwrap();
+    let mut buffer = [0;64];
+    assert_eq!(client.read(&mut buffer[..]).ok(), Some(0));
+
+    let mut client = ::std::net::TcpStream::connect(("127.0.0.1", 1104)).expect("could not parse address");
+    client.set_read_timeout(Some(Duration::new(5,0)));
+    client.write_all(&b"PROXY TCP4 192.0.2.10 127.0.0.1 4321 1104\r\nGET / HTTP/1.1\r\nHost: localhost:1104\r\n\r\n"[..]).unwrap();
+    let answer = read_until(&mut client, "\r\n\r\nok");
+    assert!(answer.starts_with("HTTP/1.1 200 OK\r\n"));
+
+    let request = rx.recv_timeout(Duration::new(5,0)).expect("the backend should receive the request");
+    println!("backend received: {}", request);
+    // the client address is the one conveyed by the header
+    assert!(request.contains("X-Forwarded-For: 192.0.2.10\r\n"));
+  }
+
+  #[test]
+  fn unsupported_expectations() {
+    setup_test_logger!();
+    use std::net::TcpListener;
+
+
+    let backend = TcpListener::bind("127.0.0.1:1069").expect("could not bind");
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move|| {
+      let (mut stream, _) = backend.accept().expect("could not accept");
+      tx.send(read_headers(&mut stream)).unwrap();
+      stream.write_all(&b"HTTP/1.1 100 Continue\r\n\r\n"[..]).unwrap();
+      let mut body = [0;2];
+      stream.read_exact(&mut body[..]).expect("could not read the body");
+      tx.send(String::from_utf8(body.to_vec()).unwrap()).unwrap();
+      stream.write_all(&b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n"[..]).unwrap();
+    });
+
+    let front: SocketAddr = FromStr::from_str("127.0.0.1:1070").expect("could not parse address");
+    let config = HttpListener {
+      front,
+      ..Default::default()
+    };
+
+    let (mut command, channel) = Channel::generate(1000, 10000).expect("should create a channel");
+    thread::spawn(move|| {
+      start(config, channel, 10, 16384);
+    });
+
+    let front = HttpFront { app_id: String::from("app_1"), address: "127.0.0.1:1070".parse().unwrap(), hostname: String::from("localhost"), path_begin: String::from("/"), trailing_slash: TrailingSlash::Strict, method: None, header_match: None, split: None };
+    command.write_message(&ProxyRequest { id: String::from("ID_ABCD"), order: ProxyRequestData::AddHttpFront(front) });
+    let backend = Backend { app_id: String::from("app_1"), backend_id: String::from("app_1-0"), address: "127.0.0.1:1069".parse().unwrap(), load_balancing_parameters: Some(LoadBalancingParams::default()), sticky_id: None, backup: None, hostname: None };
+    command.write_message(&ProxyRequest { id: String::from("ID_EFGH"), order: ProxyRequestData::AddBackend(backend) });
+
+    println!("test received: {:?}", command.read_message());
+    println!("test received: {:?}", command.read_message());
+
+    let mut client = TcpStream::connect(("127.0.0.1", 1070)).expect("could not parse address");
+    client.set_read_timeout(Some(Duration::new(5,0)));
+    client.write_all(&b"POST / HTTP/1.1\r\nHost: localhost:1070\r\nExpect: 200-ok\r\nContent-Length: 2\r\n\r\n"[..]).unwrap();
+    let answer = read_headers(&mut client);
+    println!("Response: {}", answer);
+    assert!(answer.starts_with("HTTP/1.1 417 Expectation Failed\r\n"));
+
+    // 100-continue is forwarded to the backend, and the body sent once it accepted it
+    let mut client = TcpStream::connect(("127.0.0.1", 1070)).expect("could not parse address");
+    client.set_read_timeout(Some(Duration::new(5,0)));
+    client.write_all(&b"POST / HTTP/1.1\r\nHost: localhost:1070\r\nExpect: 100-continue\r\nContent-Length: 2\r\n\r\n"[..]).unwrap();
+    let request = rx.recv_timeout(Duration::new(5,0)).expect("the backend should receive the request");
+    println!("backend received: {}", request);
+    assert!(request.contains("Expect: 100-continue\r\n"));
+
+    let answer = read_headers(&mut client);
+    assert!(answer.starts_with("HTTP/1.1 100 Continue\r\n"));
+    client.write_all(&b"ok"[..]).unwrap();
+    assert_eq!(rx.recv_timeout(Duration::new(5,0)).expect("the backend should receive the body"), "ok");
+
+    let answer = read_headers(&mut client);
+    println!("Response: {}", answer);
+    assert!(answer.starts_with("HTTP/1.1 200 OK\r\n"));
+  }
+
+  #[test]
+  fn request_body_size_limit() {
+    setup_test_logger!();
+    use std::net::TcpListener;
+
+
+    let backend = TcpListener::bind("127.0.0.1:1071").expect("could not bind");
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move|| {
+      for stream in backend.incoming() {
+        let mut stream = stream.expect("could not accept");
+        let tx = tx.clone();
+        thread::spawn(move|| {
+          let headers = read_headers(&mut stream);
+          let has_length = headers.contains("Content-Length: 10\r\n");
+          tx.send(headers).unwrap();
+          if has_length {
+            let mut body = [0;10];
+            stream.read_exact(&mut body[..]).expect("could not read the body");
+            stream.write_all(&b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n"[..]).unwrap();
+          }
+          let mut buffer = [0;128];
+          while let Ok(sz) = stream.read(&mut buffer[..]) {
+            if sz == 0 {
+              break;
+            }
+          }
+        });
+      }
+    });
+
+    let front: SocketAddr = FromStr::from_str("127.0.0.1:1072").expect("could not parse address");
+    let config = HttpListener {
+      front,
+      ..Default::default()
+    };
+
+    let (mut command, channel) = Channel::generate(1000, 10000).expect("should create a channel");
+    thread::spawn(move|| {
+      start(config, channel, 10, 16384);
+    });
+
+    let application = Application {
+      app_id: String::from("app_1"),
+      max_request_body_size: Some(10),
+      ..Default::default()
+    };
+    command.write_message(&ProxyRequest { id: String::from("ID_APP"), order: ProxyRequestData::AddApplication(application) });
+    let front = HttpFront { app_id: String::from("app_1"), address: "127.0.0.1:1072".parse().unwrap(), hostname: String::from("localhost"), path_begin: String::from("/"), trailing_slash: TrailingSlash::Strict, method: None, header_match: None, split: None };
+    command.write_message(&ProxyRequest { id: String::from("ID_ABCD"), order: ProxyRequestData::AddHttpFront(front) });
+    let backend = Backend { app_id: String::from("app_1"), backend_id: String::from("app_1-0"), address: "127.0.0.1:1071".parse().unwrap(), load_balancing_parameters: Some(LoadBalancingParams::default()), sticky_id: None, backup: None, hostname: None };
+    command.write_message(&ProxyRequest { id: String::from("ID_EFGH"), order: ProxyRequestData::AddBackend(backend) });
+
+    println!("test received: {:?}", command.read_message());
+    println!("test received: {:?}", command.read_message());
+    println!("test received: {:?}", command.read_message());
+
+    // a body at the limit is forwarded
+    let mut client = TcpStream::connect(("127.0.0.1", 1072)).expect("could not parse address");
+    client.set_read_timeout(Some(Duration::new(5,0)));
+    client.write_all(&b"POST / HTTP/1.1\r\nHost: localhost:1072\r\nContent-Length: 10\r\n\r\n0123456789"[..]).unwrap();
+    rx.recv_timeout(Duration::new(5,0)).expect("the backend should receive the request");
+    let answer = read_headers(&mut client);
+    println!("Response: {}", answer);
+    assert!(answer.starts_with("HTTP/1.1 200 OK\r\n"));
+
+    // the announced length is over the limit, the request is not forwarded
+    let mut client = TcpStream::connect(("127.0.0.1", 1072)).expect("could not parse address");
+    client.set_read_timeout(Some(Duration::new(5,0)));
+    client.write_all(&b"POST / HTTP/1.1\r\nHost: localhost:1072\r\nContent-Length: 11\r\n\r\n01234567890"[..]).unwrap();
+    let answer = read_headers(&mut client);
+    println!("Response: {}", answer);
+    assert!(answer.starts_with("HTTP/1.1 413 Payload Too Large\r\n"));
+    assert!(rx.try_recv().is_err());
+
+    // the chunked body goes over the limit after the first chunk was forwarded
+    let mut client = TcpStream::connect(("127.0.0.1", 1072)).expect("could not parse address");
+    client.set_read_timeout(Some(Duration::new(5,0)));
+    client.write_all(&b"POST / HTTP/1.1\r\nHost: localhost:1072\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n"[..]).unwrap();
+    let request = rx.recv_timeout(Duration::new(5,0)).expect("the backend should receive the request");
+    assert!(request.contains("Transfer-Encoding: chunked\r\n"));
+    client.write_all(&b"a\r\n0123456789\r\n0\r\n\r\n"[..]).unwrap();
+    let answer = read_headers(&mut client);
+    println!("Response: {}", answer);
+    assert!(answer.starts_with("HTTP/1.1 413 Payload Too Large\r\n"));
+  }
+
+  #[test]
+  fn disabled_application() {
+    setup_test_logger!();
+    use std::net::TcpListener;
+
+    let backend = TcpListener::bind("127.0.0.1:1131").expect("could not bind");
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move|| {
+      for stream in backend.incoming() {
+        let mut stream = stream.expect("could not accept");
+        let mut buffer = [0;4096];
+        let _ = stream.read(&mut buffer[..]);
+        tx.send(()).unwrap();
+        stream.write_all(&b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok"[..]).unwrap();
+      }
+    });
+
+    let front: SocketAddr = FromStr::from_str("127.0.0.1:1130").expect("could not parse address");
+    let config = HttpListener {
+      front,
+      ..Default::default()
+    };
+
+    let (mut command, channel) = Channel::generate(1000, 10000).expect("should create a channel");
+    thread::spawn(move|| {
+      start(config, channel, 10, 16384);
+    });
+
+    let application = Application {
+      app_id: String::from("app_1"),
+      enabled: false,
+      maintenance_answer: Some(String::from("HTTP/1.1 503 Service Unavailable\r\nContent-Length: 11\r\n\r\nmaintenance")),
+      ..Default::default()
+    };
+    command.write_message(&ProxyRequest { id: String::from("ID_APP"), order: ProxyRequestData::AddApplication(application) });
+    let front = HttpFront { app_id: String::from("app_1"), address: "127.0.0.1:1130".parse().unwrap(), hostname: String::from("localhost"), path_begin: String::from("/"), trailing_slash: TrailingSlash::Strict, method: None, header_match: None, split: None };
+    command.write_message(&ProxyRequest { id: String::from("ID_ABCD"), order: ProxyRequestData::AddHttpFront(front) });
+    let backend = Backend { app_id: String::from("app_1"), backend_id: String::from("app_1-0"), address: "127.0.0.1:1131".parse().unwrap(), load_balancing_parameters: Some(LoadBalancingParams::default()), sticky_id: None, backup: None, hostname: None };
+    command.write_message(&ProxyRequest { id: String::from("ID_EFGH"), order: ProxyRequestData::AddBackend(backend) });
+
+    for _ in 0..3 {
+      println!("test received: {:?}", command.read_message());
+    }
+
+    // the disabled application answers with its maintenance answer
+    let mut client = TcpStream::connect(("127.0.0.1", 1130)).expect("could not parse address");
+    client.set_read_timeout(Some(Duration::new(5,0)));
+    client.write_all(&b"GET / HTTP/1.1\r\nHost: localhost:1130\r\n\r\n"[..]).unwrap();
+    let answer = read_until(&mut client, "maintenance");
+    assert!(answer.starts_with("HTTP/1.1 503 Service Unavailable\r\n"), "unexpected answer: {}", answer);
+    assert!(answer.ends_with("\r\n\r\nmaintenance"), "unexpected answer: {}", answer);
+    assert!(rx.try_recv().is_err());
+
+    // once enabled, the requests reach the backend again
+    command.write_message(&ProxyRequest { id: String::from("ID_ENABLE"), order: ProxyRequestData::EnableApplication(String::from("app_1")) });
+    assert_eq!(read_stop_answer(&mut command, "ID_ENABLE").status, ProxyResponseStatus::Ok);
+
+    let mut client = TcpStream::connect(("127.0.0.1", 1130)).expect("could not parse address");
+    client.set_read_timeout(Some(Duration::new(5,0)));
+    client.write_all(&b"GET / HTTP/1.1\r\nHost: localhost:1130\r\n\r\n"[..]).unwrap();
+    let answer = read_until(&mut client, "\r\n\r\nok");
+    assert!(answer.starts_with("HTTP/1.1 200 OK\r\n"), "unexpected answer: {}", answer);
+    rx.recv_timeout(Duration::new(1,0)).expect("the backend should receive the request");
+
+    // an unknown application cannot be disabled
+    command.write_message(&ProxyRequest { id: String::from("ID_DISABLE"), order: ProxyRequestData::DisableApplication(String::from("app_2")) });
+    assert_eq!(read_stop_answer(&mut command, "ID_DISABLE").status, ProxyResponseStatus::Error(String::from("no such application app_2")));
+  }
+
+  #[test]
+  fn application_response_timeout() {
+    setup_test_logger!();
+    use std::net::TcpListener;
+
+    // the backend takes one second to answer
+    let backend = TcpListener::bind("127.0.0.1:1142").expect("could not bind");
+    thread::spawn(move|| {
+      for stream in backend.incoming() {
+        let mut stream = stream.expect("could not accept");
+        let mut buffer = [0;4096];
+        let _ = stream.read(&mut buffer[..]);
+        thread::sleep(Duration::new(1,0));
+        let _ = stream.write_all(&b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok"[..]);
+      }
+    });
+
+    let front: SocketAddr = FromStr::from_str("127.0.0.1:1141").expect("could not parse address");
+    let config = HttpListener {
+      front,
+      ..Default::default()
+    };
+
+    let (mut command, channel) = Channel::generate(1000, 10000).expect("should create a channel");
+    thread::spawn(move|| {
+      start(config, channel, 10, 16384);
+    });
+
+    let application = Application {
+      app_id: String::from("app_1"),
+      response_timeout: Some(100),
+      ..Default::default()
+    };
+    command.write_message(&ProxyRequest { id: String::from("ID_APP"), order: ProxyRequestData::AddApplication(application) });
+    let front = HttpFront { app_id: String::from("app_1"), address: "127.0.0.1:1141".parse().unwrap(), hostname: String::from("localhost"), path_begin: String::from("/"), trailing_slash: TrailingSlash::Strict, method: None, header_match: None, split: None };
+    command.write_message(&ProxyRequest { id: String::from("ID_ABCD"), order: ProxyRequestData::AddHttpFront(front) });
+    let backend = Backend { app_id: String::from("app_1"), backend_id: String::from("app_1-0"), address: "127.0.0.1:1142".parse().unwrap(), load_balancing_parameters: Some(LoadBalancingParams::default()), sticky_id: None, backup: None, hostname: None };
+    command.write_message(&ProxyRequest { id: String::from("ID_EFGH"), order: ProxyRequestData::AddBackend(backend) });
+
+    for _ in 0..3 {
+      println!("test received: {:?}", command.read_message());
+    }
+
+    // the backend does not answer before the timeout of the application
+    let mut client = TcpStream::connect(("127.0.0.1", 1141)).expect("could not parse address");
+    client.set_read_timeout(Some(Duration::new(5,0)));
+    client.write_all(&b"GET / HTTP/1.1\r\nHost: localhost:1141\r\n\r\n"[..]).unwrap();
+    let answer = read_until(&mut client, "\r\n\r\n");
+    assert!(answer.starts_with("HTTP/1.1 504 "), "unexpected answer: {}", answer);
+
+    // the new timeout applies to the next requests
+    command.write_message(&ProxyRequest { id: String::from("ID_TIMEOUT"), order: ProxyRequestData::SetAppTimeout(AppTimeout {
+      app_id: String::from("app_1"), timeout: Some(3000),
+    })});
+    assert_eq!(read_stop_answer(&mut command, "ID_TIMEOUT").status, ProxyResponseStatus::Ok);
+
+    let mut client = TcpStream::connect(("127.0.0.1", 1141)).expect("could not parse address");
+    client.set_read_timeout(Some(Duration::new(5,0)));
+    client.write_all(&b"GET / HTTP/1.1\r\nHost: localhost:1141\r\n\r\n"[..]).unwrap();
+    let answer = read_until(&mut client, "\r\n\r\nok");
+    assert!(answer.starts_with("HTTP/1.1 200 OK\r\n"), "unexpected answer: {}", answer);
+
+    command.write_message(&ProxyRequest { id: String::from("ID_UNKNOWN"), order: ProxyRequestData::SetAppTimeout(AppTimeout {
+      app_id: String::from("app_2"), timeout: None,
+    })});
+    assert_eq!(read_stop_answer(&mut command, "ID_UNKNOWN").status, ProxyResponseStatus::Error(String::from("no such application app_2")));
+  }
+
+  #[test]
+  fn application_request_limit() {
+    setup_test_logger!();
+    use std::net::TcpListener;
+
+    // the backend answers one request at a time, after half a second
+    let backend = TcpListener::bind("127.0.0.1:1144").expect("could not bind");
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move|| {
+      for stream in backend.incoming() {
+        let mut stream = stream.expect("could not accept");
+        let mut buffer = [0;4096];
+        let _ = stream.read(&mut buffer[..]);
+        tx.send(()).unwrap();
+        thread::sleep(Duration::from_millis(500));
+        let _ = stream.write_all(&b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok"[..]);
+      }
+    });
+
+    let front: SocketAddr = FromStr::from_str("127.0.0.1:1143").expect("could not parse address");
+    let config = HttpListener {
+      front,
+      ..Default::default()
+    };
+
+    let (mut command, channel) = Channel::generate(1000, 10000).expect("should create a channel");
+    thread::spawn(move|| {
+      start(config, channel, 10, 16384);
+    });
+
+    let application = Application {
+      app_id: String::from("app_1"),
+      request_limit: Some(RequestLimit { max_requests: 1, queue_size: 1 }),
+      ..Default::default()
+    };
+    command.write_message(&ProxyRequest { id: String::from("ID_APP"), order: ProxyRequestData::AddApplication(application) });
+    let front = HttpFront { app_id: String::from("app_1"), address: "127.0.0.1:1143".parse().unwrap(), hostname: String::from("localhost"), path_begin: String::from("/"), trailing_slash: TrailingSlash::Strict, method: None, header_match: None, split: None };
+    command.write_message(&ProxyRequest { id: String::from("ID_ABCD"), order: ProxyRequestData::AddHttpFront(front) });
+    let backend = Backend { app_id: String::from("app_1"), backend_id: String::from("app_1-0"), address: "127.0.0.1:1144".parse().unwrap(), load_balancing_parameters: Some(LoadBalancingParams::default()), sticky_id: None, backup: None, hostname: None };
+    command.write_message(&ProxyRequest { id: String::from("ID_EFGH"), order: ProxyRequestData::AddBackend(backend) });
+
+    for _ in 0..3 {
+      println!("test received: {:?}", command.read_message());
+    }
+
+    let request = || {
+      let mut client = TcpStream::connect(("127.0.0.1", 1143)).expect("could not parse address");
+      client.set_read_timeout(Some(Duration::new(5,0)));
+      client.write_all(&b"GET / HTTP/1.1\r\nHost: localhost:1143\r\n\r\n"[..]).unwrap();
+      client
+    };
+
+    // the first request is under the limit
+    let mut first = request();
+    rx.recv_timeout(Duration::new(1,0)).expect("the backend should receive the first request");
+
+    // the second one waits for a slot, the third one overflows the queue
+    let mut second = request();
+    thread::sleep(Duration::from_millis(100));
+    let mut third = request();
+    let answer = read_until(&mut third, "\r\n\r\n");
+    assert!(answer.starts_with("HTTP/1.1 503 "), "unexpected answer: {}", answer);
+    assert!(rx.try_recv().is_err(), "the queued request should not reach the backend");
+
+    let answer = read_until(&mut first, "\r\n\r\nok");
+    assert!(answer.starts_with("HTTP/1.1 200 OK\r\n"), "unexpected answer: {}", answer);
+
+    // the slot of the first request goes to the queued one
+    rx.recv_timeout(Duration::new(1,0)).expect("the backend should receive the queued request");
+    let answer = read_until(&mut second, "\r\n\r\nok");
+    assert!(answer.starts_with("HTTP/1.1 200 OK\r\n"), "unexpected answer: {}", answer);
+  }
+
+  #[test]
+  fn trace_app() {
+    setup_test_logger!();
+    use std::net::TcpListener;
+    use sozu_command::logging::{LOGGER,LoggerBackend};
+    use sozu_command::proxy::AppTrace;
+
+    let backend = TcpListener::bind("127.0.0.1:1148").expect("could not bind");
+    thread::spawn(move|| {
+      for stream in backend.incoming() {
+        let mut stream = stream.expect("could not accept");
+        let mut buffer = [0;4096];
+        let _ = stream.read(&mut buffer[..]);
+        let _ = stream.write_all(&b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok"[..]);
+      }
+    });
+
+    let front: SocketAddr = FromStr::from_str("127.0.0.1:1147").expect("could not parse address");
+    let config = HttpListener {
+      front,
+      ..Default::default()
+    };
+
+    // the log lines of the worker are sent to this socket
+    let logs = TcpListener::bind("127.0.0.1:1149").expect("could not bind");
+    let log_sender = TcpStream::connect(("127.0.0.1", 1149)).expect("could not connect");
+    let (mut log_receiver, _) = logs.accept().expect("could not accept");
+    log_receiver.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+    let (mut command, channel) = Channel::generate(1000, 10000).expect("should create a channel");
+    thread::spawn(move|| {
+      LOGGER.with(|logger| logger.borrow_mut().backend = LoggerBackend::Tcp(log_sender));
+      start(config, channel, 10, 16384);
+    });
+
+    for app_id in ["app_1", "app_2"].iter() {
+      let front = HttpFront { app_id: app_id.to_string(), address: "127.0.0.1:1147".parse().unwrap(), hostname: String::from("localhost"), path_begin: format!("/{}", app_id), trailing_slash: TrailingSlash::Strict, method: None, header_match: None, split: None };
+      command.write_message(&ProxyRequest { id: format!("ID_FRONT_{}", app_id), order: ProxyRequestData::AddHttpFront(front) });
+      let backend = Backend { app_id: app_id.to_string(), backend_id: format!("{}-0", app_id), address: "127.0.0.1:1148".parse().unwrap(), load_balancing_parameters: Some(LoadBalancingParams::default()), sticky_id: None, backup: None, hostname: None };
+      command.write_message(&ProxyRequest { id: format!("ID_BACKEND_{}", app_id), order: ProxyRequestData::AddBackend(backend) });
+    }
+    for _ in 0..4 {
+      println!("test received: {:?}", command.read_message());
+    }
+
+    let request = |path: &str| {
+      let mut client = TcpStream::connect(("127.0.0.1", 1147)).expect("could not parse address");
+      client.set_read_timeout(Some(Duration::new(5,0)));
+      client.write_all(format!("GET {} HTTP/1.1\r\nHost: localhost:1147\r\n\r\n", path).as_bytes()).unwrap();
+      let answer = read_until(&mut client, "\r\n\r\nok");
+      assert!(answer.starts_with("HTTP/1.1 200 OK\r\n"), "unexpected answer: {}", answer);
+    };
+
+    fn verbose_lines(log_receiver: &mut TcpStream) -> Vec<String> {
+      let mut data = Vec::new();
+      let mut buffer = [0u8; 4096];
+      while let Ok(sz) = log_receiver.read(&mut buffer[..]) {
+        data.extend_from_slice(&buffer[..sz]);
+      }
+      String::from_utf8_lossy(&data).lines().filter(|line| line.contains("VERBOSE")).map(String::from).collect()
+    }
+
+    command.write_message(&ProxyRequest { id: String::from("ID_TRACE"), order: ProxyRequestData::TraceApp(AppTrace {
+      app_id: String::from("app_1"), enabled: true,
+    })});
+    assert_eq!(read_stop_answer(&mut command, "ID_TRACE").status, ProxyResponseStatus::Ok);
+
+    request("/app_1");
+    request("/app_2");
+
+    let lines = verbose_lines(&mut log_receiver);
+    println!("verbose lines:\n{}", lines.join("\n"));
+    assert!(lines.iter().any(|line| line.contains("request routed to application app_1")));
+    assert!(lines.iter().any(|line| line.contains("selected backend app_1-0 at 127.0.0.1:1148")));
+    assert!(lines.iter().any(|line| line.contains("back wrote") && line.contains("app_1-0")));
+    assert!(lines.iter().any(|line| line.contains("response state")));
+    assert!(lines.iter().all(|line| !line.contains("app_2")), "only app_1 should be traced");
+
+    command.write_message(&ProxyRequest { id: String::from("ID_UNTRACE"), order: ProxyRequestData::TraceApp(AppTrace {
+      app_id: String::from("app_1"), enabled: false,
+    })});
+    assert_eq!(read_stop_answer(&mut command, "ID_UNTRACE").status, ProxyResponseStatus::Ok);
+
+    request("/app_1");
+    assert_eq!(verbose_lines(&mut log_receiver), Vec::<String>::new());
+  }
+
+  #[test]
+  fn chunked_bodies() {
+    setup_test_logger!();
+    use std::net::TcpListener;
+
+    // the backend reads a chunked request until the end of its trailers, and
+    // answers with a chunked response with trailers
+    let backend = TcpListener::bind("127.0.0.1:1146").expect("could not bind");
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move|| {
+      for stream in backend.incoming() {
+        let mut stream = stream.expect("could not accept");
+        let tx = tx.clone();
+        thread::spawn(move|| {
+          let mut data = Vec::new();
+          let mut buffer = [0;1024];
+          while data.windows(4).filter(|window| *window == &b"\r\n\r\n"[..]).count() < 2 {
+            match stream.read(&mut buffer[..]) {
+              Ok(0) | Err(_) => return,
+              Ok(sz) => data.extend_from_slice(&buffer[..sz]),
+            }
+          }
+          tx.send(String::from_utf8(data).expect("could not make string from buffer")).unwrap();
+          let _ = stream.write_all(&b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5;ext\r\nhello\r\n0\r\nX-Checksum: 42\r\n\r\n"[..]);
+        });
+      }
+    });
+
+    let front: SocketAddr = FromStr::from_str("127.0.0.1:1145").expect("could not parse address");
+    let config = HttpListener {
+      front,
+      ..Default::default()
+    };
+
+    let (mut command, channel) = Channel::generate(1000, 10000).expect("should create a channel");
+    thread::spawn(move|| {
+      start(config, channel, 10, 16384);
+    });
+
+    let front = HttpFront { app_id: String::from("app_1"), address: "127.0.0.1:1145".parse().unwrap(), hostname: String::from("localhost"), path_begin: String::from("/"), trailing_slash: TrailingSlash::Strict, method: None, header_match: None, split: None };
+    command.write_message(&ProxyRequest { id: String::from("ID_ABCD"), order: ProxyRequestData::AddHttpFront(front) });
+    let backend = Backend { app_id: String::from("app_1"), backend_id: String::from("app_1-0"), address: "127.0.0.1:1146".parse().unwrap(), load_balancing_parameters: Some(LoadBalancingParams::default()), sticky_id: None, backup: None, hostname: None };
+    command.write_message(&ProxyRequest { id: String::from("ID_EFGH"), order: ProxyRequestData::AddBackend(backend) });
+
+    println!("test received: {:?}", command.read_message());
+    println!("test received: {:?}", command.read_message());
+
+    // the body is sent in parts cutting the chunk headers, the data and the trailers
+    let mut client = TcpStream::connect(("127.0.0.1", 1145)).expect("could not parse address");
+    client.set_read_timeout(Some(Duration::new(5,0)));
+    client.set_nodelay(true).unwrap();
+    let parts: [&[u8]; 5] = [
+      b"POST / HTTP/1.1\r\nHost: localhost:1145\r\nTransfer-Encoding: chunked\r\n\r\n4;na",
+      b"me=value\r\nWi",
+      b"ki\r\n5\r\npedia\r\n0\r\n",
+      b"X-Checksum: 12",
+      b"34\r\nContent-Length: 9\r\n\r\n",
+    ];
+    for part in parts.iter() {
+      client.write_all(part).unwrap();
+      thread::sleep(Duration::from_millis(50));
+    }
+
+    let request = rx.recv_timeout(Duration::new(5,0)).expect("the backend should receive the request");
+    println!("backend received: {}", request);
+    assert!(request.starts_with("POST / HTTP/1.1\r\n"));
+    assert!(request.ends_with("\r\n\r\n4;name=value\r\nWiki\r\n5\r\npedia\r\n0\r\nX-Checksum: 1234\r\n\r\n"),
+      "the chunks and the allowed trailers should be forwarded: {}", request);
+
+    let answer = read_until(&mut client, "X-Checksum: 42\r\n\r\n");
+    println!("Response: {}", answer);
+    assert!(answer.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(answer.ends_with("\r\n\r\n5;ext\r\nhello\r\n0\r\nX-Checksum: 42\r\n\r\n"));
+
+    // a malformed chunk size closes the connection
+    let mut client = TcpStream::connect(("127.0.0.1", 1145)).expect("could not parse address");
+    client.set_read_timeout(Some(Duration::new(5,0)));
+    client.write_all(&b"POST / HTTP/1.1\r\nHost: localhost:1145\r\nTransfer-Encoding: chunked\r\n\r\n"[..]).unwrap();
+    thread::sleep(Duration::from_millis(50));
+    client.write_all(&b"zz\r\nhello\r\n0\r\n\r\n"[..]).unwrap();
+
+    let mut buffer = [0;1024];
+    match client.read(&mut buffer[..]) {
+      Ok(0) => {},
+      Err(ref e) if e.kind() == ErrorKind::ConnectionReset => {},
+      res => panic!("the connection should be closed, got {:?}", res),
+    }
+    assert!(rx.try_recv().is_err(), "the malformed request should not be completed");
+  }
+
+  #[test]
+  fn unexpected_request_bodies() {
+    setup_test_logger!();
+    use std::net::TcpListener;
+
+
+    let backend = TcpListener::bind("127.0.0.1:1078").expect("could not bind");
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move|| {
+      for stream in backend.incoming() {
+        let mut stream = stream.expect("could not accept");
+        let tx = tx.clone();
+        thread::spawn(move|| {
+          let headers = read_headers(&mut stream);
+          let mut body = Vec::new();
+          if headers.contains("Content-Length: 2\r\n") {
+            body.resize(2, 0);
+            stream.read_exact(&mut body[..]).expect("could not read the body");
+          }
+          tx.send((headers, String::from_utf8(body).unwrap())).unwrap();
+          stream.write_all(&b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n"[..]).unwrap();
+          let mut buffer = [0;128];
+          while let Ok(sz) = stream.read(&mut buffer[..]) {
+            if sz == 0 {
+              break;
+            }
+          }
+        });
+      }
+    });
+
+    let mut commands = Vec::new();
+    for &(port, policy) in [(1079, UnexpectedBodyPolicy::Reject), (1080, UnexpectedBodyPolicy::Forward)].iter() {
+      let front: SocketAddr = format!("127.0.0.1:{}", port).parse().expect("could not parse address");
+      let config = HttpListener {
+        front,
+        unexpected_body_policy: policy,
+        ..Default::default()
+      };
+
+      let (mut command, channel) = Channel::generate(1000, 10000).expect("should create a channel");
+      thread::spawn(move|| {
+        start(config, channel, 10, 16384);
+      });
+
+      let front = HttpFront { app_id: String::from("app_1"), address: front, hostname: String::from("localhost"), path_begin: String::from("/"), trailing_slash: TrailingSlash::Strict, method: None, header_match: None, split: None };
+      command.write_message(&ProxyRequest { id: String::from("ID_ABCD"), order: ProxyRequestData::AddHttpFront(front) });
+      let backend = Backend { app_id: String::from("app_1"), backend_id: String::from("app_1-0"), address: "127.0.0.1:1078".parse().unwrap(), load_balancing_parameters: Some(LoadBalancingParams::default()), sticky_id: None, backup: None, hostname: None };
+      command.write_message(&ProxyRequest { id: String::from("ID_EFGH"), order: ProxyRequestData::AddBackend(backend) });
+
+      println!("test received: {:?}", command.read_message());
+      println!("test received: {:?}", command.read_message());
+      commands.push(command);
+    }
+
+    // by default, a GET with a body is not forwarded
+    let mut client = TcpStream::connect(("127.0.0.1", 1079)).expect("could not parse address");
+    client.set_read_timeout(Some(Duration::new(5,0)));
+    client.write_all(&b"GET / HTTP/1.1\r\nHost: localhost:1079\r\nContent-Length: 2\r\n\r\nok"[..]).unwrap();
+    let answer = read_headers(&mut client);
+    println!("Response: {}", answer);
+    assert!(answer.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+
+    let mut client = TcpStream::connect(("127.0.0.1", 1079)).expect("could not parse address");
+    client.set_read_timeout(Some(Duration::new(5,0)));
+    client.write_all(&b"GET / HTTP/1.1\r\nHost: localhost:1079\r\nTransfer-Encoding: chunked\r\n\r\n2\r\nok\r\n0\r\n\r\n"[..]).unwrap();
+    let answer = read_headers(&mut client);
+    println!("Response: {}", answer);
+    assert!(answer.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    assert!(rx.try_recv().is_err());
+
+    // an empty body is not a body
+    let mut client = TcpStream::connect(("127.0.0.1", 1079)).expect("could not parse address");
+    client.set_read_timeout(Some(Duration::new(5,0)));
+    client.write_all(&b"GET / HTTP/1.1\r\nHost: localhost:1079\r\nContent-Length: 0\r\n\r\n"[..]).unwrap();
+    rx.recv_timeout(Duration::new(5,0)).expect("the backend should receive the request");
+    let answer = read_headers(&mut client);
+    println!("Response: {}", answer);
+    assert!(answer.starts_with("HTTP/1.1 200 OK\r\n"));
+
+    // the forward policy sends the request and its body to the backend
+    let mut client = TcpStream::connect(("127.0.0.1", 1080)).expect("could not parse address");
+    client.set_read_timeout(Some(Duration::new(5,0)));
+    client.write_all(&b"GET / HTTP/1.1\r\nHost: localhost:1080\r\nContent-Length: 2\r\n\r\nok"[..]).unwrap();
+    let (request, body) = rx.recv_timeout(Duration::new(5,0)).expect("the backend should receive the request");
+    assert!(request.starts_with("GET / HTTP/1.1\r\n"));
+    assert_eq!(body, "ok");
+    let answer = read_headers(&mut client);
+    println!("Response: {}", answer);
+    assert!(answer.starts_with("HTTP/1.1 200 OK\r\n"));
+  }
+
+  #[test]
+  fn buffer_sizes() {
+    setup_test_logger!();
+    use std::net::TcpListener;
+
+
+    let backend = TcpListener::bind("127.0.0.1:1081").expect("could not bind");
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move|| {
+      for stream in backend.incoming() {
+        let mut stream = stream.expect("could not accept");
+        let tx = tx.clone();
+        thread::spawn(move|| {
+          tx.send(read_headers(&mut stream)).unwrap();
+          stream.write_all(&b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n"[..]).unwrap();
+          let mut buffer = [0;128];
+          while let Ok(sz) = stream.read(&mut buffer[..]) {
+            if sz == 0 {
+              break;
+            }
+          }
+        });
+      }
+    });
+
+    let mut commands = Vec::new();
+    for &(port, buffer_size) in [(1082, 256), (1083, 65536)].iter() {
+      let front: SocketAddr = format!("127.0.0.1:{}", port).parse().expect("could not parse address");
+      let config = HttpListener {
+        front,
+        ..Default::default()
+      };
+
+      let (mut command, channel) = Channel::generate(1000, 10000).expect("should create a channel");
+      thread::spawn(move|| {
+        start(config, channel, 10, buffer_size);
+      });
+
+      let front = HttpFront { app_id: String::from("app_1"), address: front, hostname: String::from("localhost"), path_begin: String::from("/"), trailing_slash: TrailingSlash::Strict, method: None, header_match: None, split: None };
+      command.write_message(&ProxyRequest { id: String::from("ID_ABCD"), order: ProxyRequestData::AddHttpFront(front) });
+      let backend = Backend { app_id: String::from("app_1"), backend_id: String::from("app_1-0"), address: "127.0.0.1:1081".parse().unwrap(), load_balancing_parameters: Some(LoadBalancingParams::default()), sticky_id: None, backup: None, hostname: None };
+      command.write_message(&ProxyRequest { id: String::from("ID_EFGH"), order: ProxyRequestData::AddBackend(backend) });
+
+      println!("test received: {:?}", command.read_message());
+      println!("test received: {:?}", command.read_message());
+      commands.push(command);
+    }
+
+    let headers = |port: u16, header_count: usize, header_size: usize| {
+      let mut request = format!("GET / HTTP/1.1\r\nHost: localhost:{}\r\n", port);
+      for i in 0..header_count {
+        request.push_str(&format!("X-Header-{}: {}\r\n", i, "a".repeat(header_size)));
+      }
+      request.push_str("\r\n");
+      request
+    };
+
+    // the headers are larger than the buffer, but each line fits
+    let mut client = TcpStream::connect(("127.0.0.1", 1082)).expect("could not parse address");
+    client.set_read_timeout(Some(Duration::new(5,0)));
+    client.write_all(headers(1082, 10, 100).as_bytes()).unwrap();
+    let request = rx.recv_timeout(Duration::new(5,0)).expect("the backend should receive the request");
+    assert!(request.contains(&format!("X-Header-9: {}\r\n", "a".repeat(100))));
+    let answer = read_headers(&mut client);
+    println!("Response: {}", answer);
+    assert!(answer.starts_with("HTTP/1.1 200 OK\r\n"));
+
+    // a header line larger than the buffer
+    let mut client = TcpStream::connect(("127.0.0.1", 1082)).expect("could not parse address");
+    client.set_read_timeout(Some(Duration::new(5,0)));
+    client.write_all(headers(1082, 1, 300).as_bytes()).unwrap();
+    let answer = read_headers(&mut client);
+    println!("Response: {}", answer);
+    assert!(answer.starts_with("HTTP/1.1 431 Request Header Fields Too Large\r\n"));
+
+    // larger buffers accept larger header lines
+    let mut client = TcpStream::connect(("127.0.0.1", 1083)).expect("could not parse address");
+    client.set_read_timeout(Some(Duration::new(5,0)));
+    client.write_all(headers(1083, 2, 20000).as_bytes()).unwrap();
+    let request = loop {
+      let request = rx.recv_timeout(Duration::new(5,0)).expect("the backend should receive the request");
+      if request.contains("localhost:1083") {
+        break request;
+      }
+    };
+    assert!(request.contains(&format!("X-Header-1: {}\r\n", "a".repeat(20000))));
+    let answer = read_headers(&mut client);
+    println!("Response: {}", answer);
+    assert!(answer.starts_with("HTTP/1.1 200 OK\r\n"));
+  }
+
+  #[test]
+  fn header_limits() {
+    setup_test_logger!();
+    use std::net::TcpListener;
+
+
+    let backend = TcpListener::bind("127.0.0.1:5684").expect("could not bind");
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move|| {
+      for stream in backend.incoming() {
+        let mut stream = stream.expect("could not accept");
+        let tx = tx.clone();
+        thread::spawn(move|| {
+          // the proxy can close the connection in the middle of the headers
+          let mut data = Vec::new();
+          let mut buffer = [0;1];
+          while !data.ends_with(&b"\r\n\r\n"[..]) {
+            match stream.read(&mut buffer[..]) {
+              Ok(1) => data.push(buffer[0]),
+              _     => return,
+            }
+          }
+          tx.send(String::from_utf8(data).expect("could not make string from buffer")).unwrap();
+          stream.write_all(&b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n"[..]).unwrap();
+          let mut buffer = [0;128];
+          while let Ok(sz) = stream.read(&mut buffer[..]) {
+            if sz == 0 {
+              break;
+            }
+          }
+        });
+      }
+    });
+
+    let front: SocketAddr = "127.0.0.1:1133".parse().expect("could not parse address");
+    let config = HttpListener {
+      front,
+      header_limits: HeaderLimits { max_size: 200, max_count: 4, ..Default::default() },
+      ..Default::default()
+    };
+
+    let (mut command, channel) = Channel::generate(1000, 10000).expect("should create a channel");
+    thread::spawn(move|| {
+      start(config, channel, 10, 16384);
+    });
+
+    let front = HttpFront { app_id: String::from("app_1"), address: front, hostname: String::from("localhost"), path_begin: String::from("/"), trailing_slash: TrailingSlash::Strict, method: None, header_match: None, split: None };
+    command.write_message(&ProxyRequest { id: String::from("ID_ABCD"), order: ProxyRequestData::AddHttpFront(front) });
+    let backend = Backend { app_id: String::from("app_1"), backend_id: String::from("app_1-0"), address: "127.0.0.1:5684".parse().unwrap(), load_balancing_parameters: Some(LoadBalancingParams::default()), sticky_id: None, backup: None, hostname: None };
+    command.write_message(&ProxyRequest { id: String::from("ID_EFGH"), order: ProxyRequestData::AddBackend(backend) });
+    println!("test received: {:?}", command.read_message());
+    println!("test received: {:?}", command.read_message());
+
+    // the Host header and `header_count` other headers, the last one padded to fill `size` bytes
+    let request = |header_count: usize, size: usize| {
+      let mut request = String::from("GET / HTTP/1.1\r\nHost: localhost:1133\r\n");
+      for i in 1..header_count {
+        request.push_str(&format!("X-Header-{}: a\r\n", i));
+      }
+      let padding = size - request.len() - "X-Padding: \r\n\r\n".len();
+      request.push_str(&format!("X-Padding: {}\r\n\r\n", "a".repeat(padding)));
+      assert_eq!(request.len(), size);
+      request
+    };
+    let send = |request: &str| {
+      let mut client = TcpStream::connect(("127.0.0.1", 1133)).expect("could not parse address");
+      client.set_read_timeout(Some(Duration::new(5,0)));
+      client.write_all(request.as_bytes()).unwrap();
+      let answer = read_headers(&mut client);
+      println!("Response: {}", answer);
+      (client, answer)
+    };
+
+    // at the limits
+    let (_client, answer) = send(&request(3, 200));
+    assert!(answer.starts_with("HTTP/1.1 200 OK\r\n"));
+    let received = rx.recv_timeout(Duration::new(5,0)).expect("the backend should receive the request");
+    assert!(received.contains("X-Header-2: a\r\n"));
+
+    // one header too many
+    let (mut client, answer) = send(&request(4, 150));
+    assert!(answer.starts_with("HTTP/1.1 431 Request Header Fields Too Large\r\n"));
+    let mut buffer = [0;16];
+    assert_eq!(client.read(&mut buffer[..]).expect("the connection should be closed"), 0);
+
+    // one byte too many
+    let (mut client, answer) = send(&request(3, 201));
+    assert!(answer.starts_with("HTTP/1.1 431 Request Header Fields Too Large\r\n"));
+    assert_eq!(client.read(&mut buffer[..]).expect("the connection should be closed"), 0);
+    assert!(rx.recv_timeout(Duration::from_millis(100)).is_err(), "the backend should not receive the rejected requests");
+
+    // the headers received in several reads are added up
+    let mut client = TcpStream::connect(("127.0.0.1", 1133)).expect("could not parse address");
+    client.set_read_timeout(Some(Duration::new(5,0)));
+    let rejected = request(3, 201);
+    client.write_all(rejected[..150].as_bytes()).unwrap();
+    thread::sleep(Duration::from_millis(100));
+    client.write_all(rejected[150..].as_bytes()).unwrap();
+    let answer = read_headers(&mut client);
+    println!("Response: {}", answer);
+    assert!(answer.starts_with("HTTP/1.1 431 Request Header Fields Too Large\r\n"));
+  }
+
+  #[test]
+  fn response_header_limit() {
+    setup_test_logger!();
+    use std::net::TcpListener;
+
+
+    let backend = TcpListener::bind("127.0.0.1:5689").expect("could not bind");
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move|| {
+      for stream in backend.incoming() {
+        let mut stream = stream.expect("could not accept");
+        let tx = tx.clone();
+        thread::spawn(move|| {
+          let request = read_until(&mut stream, "\r\n\r\n");
+          // the path gives the size of the header sent back
+          let size: usize = request.split(' ').nth(1).and_then(|path| path[1..].parse().ok()).unwrap_or(0);
+          let response = format!("HTTP/1.1 200 OK\r\nX-Padding: {}\r\nContent-Length: 0\r\n\r\n", "a".repeat(size));
+          let _ = stream.write_all(response.as_bytes());
+
+          // the proxy closes the connections sending headers over the limit
+          let mut buffer = [0;128];
+          let closed = match stream.read(&mut buffer[..]) {
+            Ok(0) | Err(_) => true,
+            Ok(_)          => false,
+          };
+          tx.send((size, closed)).unwrap();
+        });
+      }
+    });
+
+    let front: SocketAddr = "127.0.0.1:1139".parse().expect("could not parse address");
+    let config = HttpListener {
+      front,
+      header_limits: HeaderLimits { max_response_size: 300, ..Default::default() },
+      ..Default::default()
+    };
+
+    let (mut command, channel) = Channel::generate(1000, 10000).expect("should create a channel");
+    thread::spawn(move|| {
+      start(config, channel, 10, 16384);
+    });
+
+    let front = HttpFront { app_id: String::from("app_1"), address: front, hostname: String::from("localhost"), path_begin: String::from("/"), trailing_slash: TrailingSlash::Strict, method: None, header_match: None, split: None };
+    command.write_message(&ProxyRequest { id: String::from("ID_ABCD"), order: ProxyRequestData::AddHttpFront(front) });
+    let backend = Backend { app_id: String::from("app_1"), backend_id: String::from("app_1-0"), address: "127.0.0.1:5689".parse().unwrap(), load_balancing_parameters: Some(LoadBalancingParams::default()), sticky_id: None, backup: None, hostname: None };
+    command.write_message(&ProxyRequest { id: String::from("ID_EFGH"), order: ProxyRequestData::AddBackend(backend) });
+    println!("test received: {:?}", command.read_message());
+    println!("test received: {:?}", command.read_message());
+
+    let send = |size: usize| {
+      let mut client = TcpStream::connect(("127.0.0.1", 1139)).expect("could not parse address");
+      client.set_read_timeout(Some(Duration::new(5,0)));
+      client.write_all(format!("GET /{} HTTP/1.1\r\nHost: localhost:1139\r\n\r\n", size).as_bytes()).unwrap();
+      let answer = read_headers(&mut client);
+      println!("Response: {}", answer);
+      (client, answer)
+    };
+
+    // under the limit
+    let (client, answer) = send(100);
+    assert!(answer.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(answer.contains(&format!("X-Padding: {}\r\n", "a".repeat(100))));
+    drop(client);
+    assert_eq!(rx.recv_timeout(Duration::new(5,0)).expect("the backend should answer"), (100, true));
+
+    // over the limit, the headers never reach the client
+    let (mut client, answer) = send(400);
+    assert!(answer.starts_with("HTTP/1.1 502 Bad Gateway\r\n"));
+    assert!(!answer.contains("X-Padding"));
+    let mut buffer = [0;16];
+    assert_eq!(client.read(&mut buffer[..]).expect("the connection should be closed"), 0);
+    assert_eq!(rx.recv_timeout(Duration::new(5,0)).expect("the backend should answer"), (400, true));
+
+    // headers larger than the buffers are rejected without waiting for their end
+    let (_client, answer) = send(100000);
+    assert!(answer.starts_with("HTTP/1.1 502 Bad Gateway\r\n"));
+    assert_eq!(rx.recv_timeout(Duration::new(5,0)).expect("the backend should answer"), (100000, true));
+  }
+
+  #[test]
+  fn request_line_limit() {
+    setup_test_logger!();
+    use std::net::TcpListener;
+
+    let backend = TcpListener::bind("127.0.0.1:1151").expect("could not bind");
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move|| {
+      for stream in backend.incoming() {
+        let mut stream = stream.expect("could not accept");
+        let tx = tx.clone();
+        thread::spawn(move|| {
+          let request = read_until(&mut stream, "\r\n\r\n");
+          tx.send(request).unwrap();
+          let _ = stream.write_all(&b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n"[..]);
+          let mut buffer = [0;128];
+          while let Ok(sz) = stream.read(&mut buffer[..]) {
+            if sz == 0 {
+              break;
+            }
+          }
+        });
+      }
+    });
+
+    let front: SocketAddr = "127.0.0.1:1150".parse().expect("could not parse address");
+    let config = HttpListener {
+      front,
+      header_limits: HeaderLimits { max_request_line_size: 100, ..Default::default() },
+      ..Default::default()
+    };
+
+    let (mut command, channel) = Channel::generate(1000, 10000).expect("should create a channel");
+    thread::spawn(move|| {
+      start(config, channel, 10, 16384);
+    });
+
+    let front = HttpFront { app_id: String::from("app_1"), address: front, hostname: String::from("localhost"), path_begin: String::from("/"), trailing_slash: TrailingSlash::Strict, method: None, header_match: None, split: None };
+    command.write_message(&ProxyRequest { id: String::from("ID_ABCD"), order: ProxyRequestData::AddHttpFront(front) });
+    let backend = Backend { app_id: String::from("app_1"), backend_id: String::from("app_1-0"), address: "127.0.0.1:1151".parse().unwrap(), load_balancing_parameters: Some(LoadBalancingParams::default()), sticky_id: None, backup: None, hostname: None };
+    command.write_message(&ProxyRequest { id: String::from("ID_EFGH"), order: ProxyRequestData::AddBackend(backend) });
+    println!("test received: {:?}", command.read_message());
+    println!("test received: {:?}", command.read_message());
+
+    // a request line of `size` bytes, without the line ending
+    let request_line = |size: usize| {
+      let line = format!("GET /{} HTTP/1.1", "a".repeat(size - "GET / HTTP/1.1".len()));
+      assert_eq!(line.len(), size);
+      line
+    };
+    let connect = || {
+      let client = TcpStream::connect(("127.0.0.1", 1150)).expect("could not parse address");
+      client.set_read_timeout(Some(Duration::new(5,0))).unwrap();
+      client
+    };
+
+    // at the limit
+    let mut client = connect();
+    client.write_all(format!("{}\r\nHost: localhost\r\n\r\n", request_line(100)).as_bytes()).unwrap();
+    let answer = read_until(&mut client, "\r\n\r\n");
+    println!("Response: {}", answer);
+    assert!(answer.starts_with("HTTP/1.1 200 OK\r\n"));
+    let received = rx.recv_timeout(Duration::new(5,0)).expect("the backend should receive the request");
+    assert!(received.starts_with(&request_line(100)));
+
+    // one byte too many
+    let mut client = connect();
+    client.write_all(format!("{}\r\nHost: localhost\r\n\r\n", request_line(101)).as_bytes()).unwrap();
+    let answer = read_until(&mut client, "\r\n\r\n");
+    println!("Response: {}", answer);
+    assert!(answer.starts_with("HTTP/1.1 414 URI Too Long\r\n"));
+    let mut buffer = [0;16];
+    assert_eq!(client.read(&mut buffer[..]).expect("the connection should be closed"), 0);
+
+    // the line ending can come in a later read
+    let mut client = connect();
+    client.write_all(format!("{}\r", request_line(100)).as_bytes()).unwrap();
+    thread::sleep(Duration::from_millis(100));
+    client.write_all(&b"\nHost: localhost\r\n\r\n"[..]).unwrap();
+    let answer = read_until(&mut client, "\r\n\r\n");
+    println!("Response: {}", answer);
+    assert!(answer.starts_with("HTTP/1.1 200 OK\r\n"));
+    rx.recv_timeout(Duration::new(5,0)).expect("the backend should receive the request");
+
+    // a request line that never ends is rejected once it gets over the limit,
+    // while the client is still sending it
+    let mut client = connect();
+    client.write_all(&request_line(100).as_bytes()[..60]).unwrap();
+    thread::sleep(Duration::from_millis(100));
+    client.write_all("a".repeat(60).as_bytes()).unwrap();
+    let answer = read_until(&mut client, "\r\n\r\n");
+    println!("Response: {}", answer);
+    assert!(answer.starts_with("HTTP/1.1 414 URI Too Long\r\n"));
+    assert_eq!(client.read(&mut buffer[..]).expect("the connection should be closed"), 0);
+    assert!(rx.recv_timeout(Duration::from_millis(100)).is_err(), "the backend should not receive the rejected requests");
+  }
+
+  #[test]
+  fn default_app() {
+    setup_test_logger!();
+    use std::net::TcpListener;
+    use sozu_command::proxy::{DefaultApp,ListenerType};
+
+    let backend = TcpListener::bind("127.0.0.1:5690").expect("could not bind");
+    thread::spawn(move|| {
+      for stream in backend.incoming() {
+        let mut stream = stream.expect("could not accept");
+        thread::spawn(move|| {
+          read_until(&mut stream, "\r\n\r\n");
+          stream.write_all(&b"HTTP/1.1 200 OK\r\nContent-Length: 7\r\nConnection: close\r\n\r\nlanding"[..]).unwrap();
+        });
+      }
+    });
+
+    let front: SocketAddr = "127.0.0.1:1140".parse().expect("could not parse address");
+    let config = HttpListener {
+      front,
+      ..Default::default()
+    };
+
+    let (mut command, channel) = Channel::generate(1000, 10000).expect("should create a channel");
+    thread::spawn(move|| {
+      start(config, channel, 10, 16384);
+    });
+
+    let backend = Backend { app_id: String::from("landing"), backend_id: String::from("landing-0"), address: "127.0.0.1:5690".parse().unwrap(), load_balancing_parameters: Some(LoadBalancingParams::default()), sticky_id: None, backup: None, hostname: None };
+    command.write_message(&ProxyRequest { id: String::from("ID_ABCD"), order: ProxyRequestData::AddBackend(backend) });
+    println!("test received: {:?}", command.read_message());
+
+    let send = |expected: &str| {
+      let mut client = TcpStream::connect(("127.0.0.1", 1140)).expect("could not parse address");
+      client.set_read_timeout(Some(Duration::new(5,0)));
+      client.write_all(&b"GET / HTTP/1.1\r\nHost: unknown.example.com\r\n\r\n"[..]).unwrap();
+      let answer = read_until(&mut client, expected);
+      println!("Response: {}", answer);
+      answer
+    };
+
+    // no frontend matches and there is no default application
+    assert!(send("\r\n\r\n").starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+    let set_default = |app_id: Option<&str>| ProxyRequest {
+      id: String::from("ID_DEFAULT"),
+      order: ProxyRequestData::SetDefaultApp(DefaultApp { front, proxy: ListenerType::HTTP, app_id: app_id.map(String::from) }),
+    };
+    command.write_message(&set_default(Some("landing")));
+    println!("test received: {:?}", command.read_message());
+    let answer = send("landing");
+    assert!(answer.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(answer.ends_with("\r\n\r\nlanding"));
+
+    command.write_message(&set_default(None));
+    println!("test received: {:?}", command.read_message());
+    assert!(send("\r\n\r\n").starts_with("HTTP/1.1 404 Not Found\r\n"));
+  }
+
+  #[test]
+  fn header_timeout() {
+    setup_test_logger!();
+    use std::net::TcpListener;
+    use std::time::Instant;
+
+    let backend = TcpListener::bind("127.0.0.1:5685").expect("could not bind");
+    thread::spawn(move|| {
+      for stream in backend.incoming() {
+        let mut stream = stream.expect("could not accept");
+        thread::spawn(move|| {
+          read_until(&mut stream, "\r\n\r\n");
+          stream.write_all(&b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n"[..]).unwrap();
+        });
+      }
+    });
+
+    let front: SocketAddr = "127.0.0.1:1134".parse().expect("could not parse address");
+    let config = HttpListener {
+      front,
+      timeouts: ListenerTimeouts { header_timeout: Some(1), ..Default::default() },
+      ..Default::default()
+    };
+
+    let (mut command, channel) = Channel::generate(1000, 10000).expect("should create a channel");
+    thread::spawn(move|| {
+      start(config, channel, 10, 16384);
+    });
+
+    let front = HttpFront { app_id: String::from("app_1"), address: front, hostname: String::from("localhost"), path_begin: String::from("/"), trailing_slash: TrailingSlash::Strict, method: None, header_match: None, split: None };
+    command.write_message(&ProxyRequest { id: String::from("ID_ABCD"), order: ProxyRequestData::AddHttpFront(front) });
+    let backend = Backend { app_id: String::from("app_1"), backend_id: String::from("app_1-0"), address: "127.0.0.1:5685".parse().unwrap(), load_balancing_parameters: Some(LoadBalancingParams::default()), sticky_id: None, backup: None, hostname: None };
+    command.write_message(&ProxyRequest { id: String::from("ID_EFGH"), order: ProxyRequestData::AddBackend(backend) });
+    println!("test received: {:?}", command.read_message());
+    println!("test received: {:?}", command.read_message());
+
+    // the headers arrive in time
+    let mut client = TcpStream::connect(("127.0.0.1", 1134)).expect("could not parse address");
+    client.set_read_timeout(Some(Duration::new(5,0)));
+    client.write_all(&b"GET / HTTP/1.1\r\n"[..]).unwrap();
+    thread::sleep(Duration::from_millis(300));
+    client.write_all(&b"Host: localhost:1134\r\n\r\n"[..]).unwrap();
+    let answer = read_until(&mut client, "\r\n\r\n");
+    println!("Response: {}", answer);
+    assert!(answer.starts_with("HTTP/1.1 200 OK\r\n"));
+
+    // the client sends a header line every 200ms, each read resets the front timeout
+    let mut client = TcpStream::connect(("127.0.0.1", 1134)).expect("could not parse address");
+    client.set_read_timeout(Some(Duration::new(5,0)));
+    let start = Instant::now();
+    let mut writer = client.try_clone().expect("could not clone the client socket");
+    thread::spawn(move|| {
+      writer.write_all(&b"GET / HTTP/1.1\r\n"[..]).unwrap();
+      for i in 0..25 {
+        thread::sleep(Duration::from_millis(200));
+        if writer.write_all(format!("X-Slow-{}: a\r\n", i).as_bytes()).is_err() {
+          break;
+        }
+      }
+    });
+
+    let answer = read_until(&mut client, "\r\n\r\n");
+    println!("Response: {}", answer);
+    assert!(answer.starts_with("HTTP/1.1 408 Request Timeout\r\n"));
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_millis(900) && elapsed < Duration::from_secs(3), "answered after {:?}", elapsed);
+    let mut buffer = [0;16];
+    assert_eq!(client.read(&mut buffer[..]).expect("the connection should be closed"), 0);
+  }
+
+  #[test]
+  fn max_keepalive_requests() {
+    setup_test_logger!();
+    use std::net::TcpListener;
+
+    let backend = TcpListener::bind("127.0.0.1:5686").expect("could not bind");
+    thread::spawn(move|| {
+      for stream in backend.incoming() {
+        let mut stream = stream.expect("could not accept");
+        thread::spawn(move|| {
+          loop {
+            let request = read_until(&mut stream, "\r\n\r\n");
+            if request.is_empty() {
+              break;
+            }
+            stream.write_all(&b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok"[..]).unwrap();
+          }
+        });
+      }
+    });
+
+    let front: SocketAddr = "127.0.0.1:1135".parse().expect("could not parse address");
+    let config = HttpListener {
+      front,
+      max_keepalive_requests: Some(3),
+      ..Default::default()
+    };
+
+    let (mut command, channel) = Channel::generate(1000, 10000).expect("should create a channel");
+    thread::spawn(move|| {
+      start(config, channel, 10, 16384);
+    });
+
+    let front = HttpFront { app_id: String::from("app_1"), address: front, hostname: String::from("localhost"), path_begin: String::from("/"), trailing_slash: TrailingSlash::Strict, method: None, header_match: None, split: None };
+    command.write_message(&ProxyRequest { id: String::from("ID_ABCD"), order: ProxyRequestData::AddHttpFront(front) });
+    let backend = Backend { app_id: String::from("app_1"), backend_id: String::from("app_1-0"), address: "127.0.0.1:5686".parse().unwrap(), load_balancing_parameters: Some(LoadBalancingParams::default()), sticky_id: None, backup: None, hostname: None };
+    command.write_message(&ProxyRequest { id: String::from("ID_EFGH"), order: ProxyRequestData::AddBackend(backend) });
+    println!("test received: {:?}", command.read_message());
+    println!("test received: {:?}", command.read_message());
+
+    let mut client = TcpStream::connect(("127.0.0.1", 1135)).expect("could not parse address");
+    client.set_read_timeout(Some(Duration::new(5,0)));
+    for i in 1..4 {
+      client.write_all(&b"GET / HTTP/1.1\r\nHost: localhost:1135\r\n\r\n"[..]).unwrap();
+      let answer = read_until(&mut client, "\r\n\r\nok");
+      println!("Response {}: {}", i, answer);
+      assert!(answer.starts_with("HTTP/1.1 200 OK\r\n"));
+      assert_eq!(answer.contains("Connection: close\r\n"), i == 3, "unexpected answer to the request {}: {}", i, answer);
+    }
+
+    // the connection is closed after the third response
+    let mut buffer = [0;16];
+    assert_eq!(client.read(&mut buffer[..]).expect("the connection should be closed"), 0);
+  }
+
+  #[test]
+  fn response_compression() {
+    setup_test_logger!();
+    use std::net::TcpListener;
+    use flate2::read::GzDecoder;
+    use protocol::http::compression::Dechunker;
+    use sozu_command::proxy::ResponseCompression;
+
+    /// reads a response, its body has a Content-Length or is chunked
+    fn read_response(stream: &mut TcpStream) -> (String, Vec<u8>) {
+      let mut data = Vec::new();
+      let mut buffer = [0;1];
+      while !data.ends_with(&b"\r\n\r\n"[..]) {
+        assert!(stream.read(&mut buffer[..]).expect("could not read") > 0, "connection closed while reading");
+        data.push(buffer[0]);
+      }
+      let headers = String::from_utf8(data).expect("could not make string from buffer");
+
+      let mut body = Vec::new();
+      if headers.contains("Transfer-Encoding: chunked\r\n") {
+        while !body.ends_with(&b"\r\n0\r\n\r\n"[..]) {
+          assert!(stream.read(&mut buffer[..]).expect("could not read") > 0, "connection closed while reading");
+          body.push(buffer[0]);
+        }
+        let mut data = Vec::new();
+        Dechunker::new().decode(&body, &mut data);
+        body = data;
+      } else {
+        let length: usize = headers.split("\r\n").find(|line| line.starts_with("Content-Length: "))
+          .map(|line| line["Content-Length: ".len()..].parse().unwrap()).unwrap();
+        body.resize(length, 0);
+        stream.read_exact(&mut body).expect("could not read the body");
+      }
+      (headers, body)
+    }
+
+    fn gunzip(body: &[u8]) -> String {
+      let mut text = String::new();
+      GzDecoder::new(body).read_to_string(&mut text).expect("could not decompress the body");
+      text
+    }
+
+    let text = "<p>a compressible paragraph</p>\n".repeat(200);
+    let chunks = ["<ul>\n", "<li>first</li>\n", "<li>second</li>\n</ul>\n"];
+
+    let backend = TcpListener::bind("127.0.0.1:5687").expect("could not bind");
+    let backend_text = text.clone();
+    thread::spawn(move|| {
+      let (mut stream, _) = backend.accept().expect("could not accept");
+      loop {
+        let mut request = Vec::new();
+        let mut buffer = [0;1];
+        while !request.ends_with(&b"\r\n\r\n"[..]) {
+          match stream.read(&mut buffer[..]) {
+            Ok(1) => request.push(buffer[0]),
+            _ => return,
+          }
+        }
+        let request = String::from_utf8(request).unwrap();
+
+        let response = if request.starts_with("GET /text ") {
+          format!("HTTP/1.1 200 OK\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: {}\r\n\r\n{}",
+            backend_text.len(), backend_text)
+        } else if request.starts_with("GET /small ") {
+          String::from("HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nContent-Length: 12\r\n\r\n<p>small</p>")
+        } else if request.starts_with("GET /encoded ") {
+          format!("HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nContent-Encoding: br\r\nContent-Length: {}\r\n\r\n{}",
+            backend_text.len(), backend_text)
+        } else if request.starts_with("GET /image ") {
+          format!("HTTP/1.1 200 OK\r\nContent-Type: image/png\r\nContent-Length: {}\r\n\r\n{}",
+            backend_text.len(), backend_text)
+        } else {
+          let mut response = String::from("HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nTransfer-Encoding: chunked\r\n\r\n");
+          for chunk in chunks.iter() {
+            response.push_str(&format!("{:x}\r\n{}\r\n", chunk.len(), chunk));
+          }
+          response.push_str("0\r\n\r\n");
+          response
+        };
+        stream.write_all(response.as_bytes()).unwrap();
+      }
+    });
+
+    let front: SocketAddr = "127.0.0.1:1136".parse().expect("could not parse address");
+    let config = HttpListener {
+      front,
+      compression: Some(ResponseCompression::default()),
+      ..Default::default()
+    };
+
+    let (mut command, channel) = Channel::generate(1000, 10000).expect("should create a channel");
+    thread::spawn(move|| {
+      start(config, channel, 10, 16384);
+    });
+
+    let front = HttpFront { app_id: String::from("app_1"), address: front, hostname: String::from("localhost"), path_begin: String::from("/"), trailing_slash: TrailingSlash::Strict, method: None, header_match: None, split: None };
+    command.write_message(&ProxyRequest { id: String::from("ID_ABCD"), order: ProxyRequestData::AddHttpFront(front) });
+    let backend = Backend { app_id: String::from("app_1"), backend_id: String::from("app_1-0"), address: "127.0.0.1:5687".parse().unwrap(), load_balancing_parameters: Some(LoadBalancingParams::default()), sticky_id: None, backup: None, hostname: None };
+    command.write_message(&ProxyRequest { id: String::from("ID_EFGH"), order: ProxyRequestData::AddBackend(backend) });
+    println!("test received: {:?}", command.read_message());
+    println!("test received: {:?}", command.read_message());
+
+    let mut client = TcpStream::connect(("127.0.0.1", 1136)).expect("could not parse address");
+    client.set_read_timeout(Some(Duration::new(5,0)));
+    let mut request = |path: &str, accept_encoding: &str| {
+      client.write_all(format!("GET {} HTTP/1.1\r\nHost: localhost\r\nAccept-Encoding: {}\r\n\r\n", path, accept_encoding).as_bytes()).unwrap();
+      read_response(&mut client)
+    };
+
+    // a compressible response, sent with chunked encoding
+    let (headers, body) = request("/text", "deflate, gzip");
+    println!("Response: {}", headers);
+    assert!(headers.contains("Content-Encoding: gzip\r\n"));
+    assert!(headers.contains("Vary: Accept-Encoding\r\n"));
+    assert!(!headers.contains("Content-Length"));
+    assert!(body.len() < text.len());
+    assert_eq!(gunzip(&body), text);
+
+    // a chunked response is decoded before being compressed
+    let (headers, body) = request("/chunked", "gzip");
+    println!("Response: {}", headers);
+    assert!(headers.contains("Content-Encoding: gzip\r\n"));
+    assert_eq!(gunzip(&body), chunks.concat());
+
+    // the responses below the minimum size are not compressed
+    let (headers, body) = request("/small", "gzip");
+    println!("Response: {}", headers);
+    assert!(!headers.contains("Content-Encoding"));
+    assert_eq!(&body[..], &b"<p>small</p>"[..]);
+
+    // neither are the responses already encoded, the ones with other media types,
+    // and the responses to clients that do not accept gzip
+    let (headers, body) = request("/encoded", "gzip, br");
+    println!("Response: {}", headers);
+    assert!(headers.contains("Content-Encoding: br\r\n"));
+    assert!(!headers.contains("gzip"));
+    assert_eq!(body, text.as_bytes());
+
+    let (headers, body) = request("/image", "gzip");
+    assert!(!headers.contains("Content-Encoding"));
+    assert_eq!(body, text.as_bytes());
+
+    let (headers, body) = request("/text", "gzip;q=0, identity");
+    assert!(!headers.contains("Content-Encoding"));
+    assert_eq!(body, text.as_bytes());
+  }
+
+  use self::tiny_http::{Server, Response};
+
+  fn start_server(port: u16, barrier: Arc<Barrier>) {
+    thread::spawn(move|| {
+      let server = Server::http(&format!("127.0.0.1:{}", port)).expect("could not create server");
+      info!("starting web server in port {}", port);
+      barrier.wait();
+
+      for request in server.incoming_requests() {
+        println!("backend web server got request -> method: {:?}, url: {:?}, headers: {:?}",
+          request.method(),
+          request.url(),
+          request.headers()
+        );
+
+        let response = Response::from_string("hello world");
+        request.respond(response).unwrap();
+        println!("backend web server sent response");
+        barrier.wait();
+        println!("server session stopped");
+      }
+
+      println!("server on port {} closed", port);
+    });
+  }
+
+  #[test]
+  fn frontend_from_request_test() {
+    let app_id1 = "app_1".to_owned();
+    let app_id2 = "app_2".to_owned();
+    let app_id3 = "app_3".to_owned();
+    let uri1 = "/".to_owned();
+    let uri2 = "/yolo".to_owned();
+    let uri3 = "/yolo/swag".to_owned();
+
+    let mut fronts = TrieNode::root();
+    fronts.domain_insert(Vec::from(&b"lolcatho.st"[..]), vec![
+      HttpFront { app_id: app_id1, address: "0.0.0.0:80".parse().unwrap(), hostname: "lolcatho.st".to_owned(), path_begin: uri1, trailing_slash: TrailingSlash::Strict, method: None, header_match: None, split: None },
+      HttpFront { app_id: app_id2, address: "0.0.0.0:80".parse().unwrap(), hostname: "lolcatho.st".to_owned(), path_begin: uri2, trailing_slash: TrailingSlash::Strict, method: None, header_match: None, split: None },
+      HttpFront { app_id: app_id3, address: "0.0.0.0:80".parse().unwrap(), hostname: "lolcatho.st".to_owned(), path_begin: uri3, trailing_slash: TrailingSlash::Strict, method: None, header_match: None, split: None }
+    ]);
+    fronts.domain_insert(Vec::from(&b"other.domain"[..]), vec![
+      HttpFront { app_id: "app_1".to_owned(), address: "0.0.0.0:80".parse().unwrap(), hostname: "other.domain".to_owned(), path_begin: "/test".to_owned(), trailing_slash: TrailingSlash::Strict, method: None, header_match: None, split: None },
+    ]);
+
+    let front: SocketAddr = FromStr::from_str("127.0.0.1:1030").expect("could not parse address");
+    let listener = Listener {
+      listener: None,
+      unix_listener: None,
+      address:  front,
+      fronts,
+      answers: Rc::new(RefCell::new(HttpAnswers::new("HTTP/1.1 404 Not Found\r\n\r\n", "HTTP/1.1 503 your application is in deployment\r\n\r\n", None))),
+      config: Default::default(),
+      token: Token(0),
+      active: true,
+    };
+
+    let frontend1 = listener.frontend_from_request("lolcatho.st", None, "/", |_| None);
+    let frontend2 = listener.frontend_from_request("lolcatho.st", None, "/test", |_| None);
+    let frontend3 = listener.frontend_from_request("lolcatho.st", None, "/yolo/test", |_| None);
+    let frontend4 = listener.frontend_from_request("lolcatho.st", None, "/yolo/swag", |_| None);
+    let frontend5 = listener.frontend_from_request("domain", None, "/", |_| None);
+    assert_eq!(frontend1.expect("should find frontend").app_id, "app_1");
+    assert_eq!(frontend2.expect("should find frontend").app_id, "app_1");
+    assert_eq!(frontend3.expect("should find frontend").app_id, "app_2");
+    assert_eq!(frontend4.expect("should find frontend").app_id, "app_3");
+    assert_eq!(frontend5, None);
+
+    // the absolute form request targets are routed on their path
+    let request_line = RRequestLine { method: Method::Get, uri: String::from("http://lolcatho.st/yolo/swag?page=2"), version: Version::V11 };
+    let frontend6 = listener.frontend_from_request("lolcatho.st", None, request_line.path(), |_| None);
+    assert_eq!(frontend6.expect("should find frontend").app_id, "app_3");
+  }
+
+  #[test]
+  fn frontend_from_request_longest_prefix() {
+    let front: SocketAddr = FromStr::from_str("127.0.0.1:1052").expect("could not parse address");
+    let mut listener = Listener {
+      listener: None,
+      unix_listener: None,
+      address:  front,
+      fronts:   TrieNode::root(),
+      answers: Rc::new(RefCell::new(HttpAnswers::new("HTTP/1.1 404 Not Found\r\n\r\n", "HTTP/1.1 503 your application is in deployment\r\n\r\n", None))),
+      config: Default::default(),
+      token: Token(0),
+      active: true,
+    };
+
+    // the most specific frontends are added first
+    for &(app_id, path_begin) in [("app_users", "/api/users"), ("app_api", "/api"), ("app_root", "/")].iter() {
+      listener.add_http_front(HttpFront { app_id: app_id.to_owned(), address: front, hostname: "lolcatho.st".to_owned(), path_begin: path_begin.to_owned(), trailing_slash: TrailingSlash::Strict, method: None, header_match: None, split: None })
+        .expect("should add frontend");
+    }
+
+    let app_id = |uri| listener.frontend_from_request("lolcatho.st", None, uri, |_| None).map(|f| f.app_id.as_str());
+    assert_eq!(app_id("/api/users/1"), Some("app_users"));
+    assert_eq!(app_id("/api/users"), Some("app_users"));
+    assert_eq!(app_id("/api/items"), Some("app_api"));
+    assert_eq!(app_id("/index.html"), Some("app_root"));
+  }
+
+  #[test]
+  fn frontend_from_request_method() {
+    let front: SocketAddr = FromStr::from_str("127.0.0.1:1053").expect("could not parse address");
+    let mut listener = Listener {
+      listener: None,
+      unix_listener: None,
+      address:  front,
+      fronts:   TrieNode::root(),
+      answers: Rc::new(RefCell::new(HttpAnswers::new("HTTP/1.1 404 Not Found\r\n\r\n", "HTTP/1.1 503 your application is in deployment\r\n\r\n", None))),
+      config: Default::default(),
+      token: Token(0),
+      active: true,
+    };
+
+    for &(app_id, method, path_begin) in [("app_get", Some("GET"), "/api"), ("app_post", Some("post"), "/api"), ("app_upload", Some("PUT"), "/upload")].iter() {
+      listener.add_http_front(HttpFront { app_id: app_id.to_owned(), address: front, hostname: "lolcatho.st".to_owned(), path_begin: path_begin.to_owned(), trailing_slash: TrailingSlash::Strict, method: method.map(String::from), header_match: None, split: None })
+        .expect("should add frontend");
+    }
+
+    {
+      let app_id = |method, uri| listener.frontend_from_request("lolcatho.st", method, uri, |_| None).map(|f| f.app_id.as_str());
+      assert_eq!(app_id(Some("GET"), "/api/users"), Some("app_get"));
+      // the methods are compared without case
+      assert_eq!(app_id(Some("POST"), "/api/users"), Some("app_post"));
+      assert_eq!(app_id(Some("PUT"), "/upload/1"), Some("app_upload"));
+      // no frontend accepts any method yet
+      assert_eq!(app_id(Some("DELETE"), "/api/users"), None);
+      assert_eq!(app_id(Some("GET"), "/upload/1"), None);
+      assert_eq!(app_id(None, "/api/users"), None);
+    }
+
+    listener.add_http_front(HttpFront { app_id: "app_any".to_owned(), address: front, hostname: "lolcatho.st".to_owned(), path_begin: "/".to_owned(), trailing_slash: TrailingSlash::Strict, method: None, header_match: None, split: None })
+      .expect("should add frontend");
+
+    let app_id = |method, uri| listener.frontend_from_request("lolcatho.st", method, uri, |_| None).map(|f| f.app_id.as_str());
+    assert_eq!(app_id(Some("GET"), "/api/users"), Some("app_get"));
+    // the frontends without a method are used when no method specific frontend matches
+    assert_eq!(app_id(Some("DELETE"), "/api/users"), Some("app_any"));
+    assert_eq!(app_id(Some("GET"), "/upload/1"), Some("app_any"));
+    assert_eq!(app_id(None, "/api/users"), Some("app_any"));
+  }
+
+  #[test]
+  fn conflicting_frontends() {
+    let front: SocketAddr = FromStr::from_str("127.0.0.1:1053").expect("could not parse address");
+    let mut listener = Listener {
+      listener: None,
+      unix_listener: None,
+      address:  front,
+      fronts:   TrieNode::root(),
+      answers: Rc::new(RefCell::new(HttpAnswers::new("HTTP/1.1 404 Not Found\r\n\r\n", "HTTP/1.1 503 your application is in deployment\r\n\r\n", None))),
+      config: Default::default(),
+      token: Token(0),
+      active: true,
+    };
+
+    let http_front = |app_id: &str, method: Option<&str>| HttpFront { app_id: app_id.to_owned(), address: front, hostname: "lolcatho.st".to_owned(), path_begin: "/api".to_owned(), trailing_slash: TrailingSlash::Strict, method: method.map(String::from), header_match: None, split: None };
+    listener.add_http_front(http_front("app_1", Some("GET"))).expect("should add frontend");
+    // adding it again is not a conflict
+    listener.add_http_front(http_front("app_1", Some("GET"))).expect("should add frontend");
+
+    assert_eq!(listener.add_http_front(http_front("app_2", Some("get"))),
+      Err(String::from("the frontend lolcatho.st/api is already used by the application app_1")));
+    listener.add_http_front(http_front("app_2", None)).expect("should add frontend");
+
+    // the first frontend is kept
+    let app_id = |method| listener.frontend_from_request("lolcatho.st", method, "/api", |_| None).map(|f| f.app_id.as_str());
+    assert_eq!(app_id(Some("GET")), Some("app_1"));
+    assert_eq!(app_id(Some("POST")), Some("app_2"));
+  }
+
+  #[test]
+  fn frontend_from_request_header() {
+    use sozu_command::proxy::HeaderMatch;
+
+    let front: SocketAddr = FromStr::from_str("127.0.0.1:1054").expect("could not parse address");
+    let mut listener = Listener {
+      listener: None,
+      unix_listener: None,
+      address:  front,
+      fronts:   TrieNode::root(),
+      answers: Rc::new(RefCell::new(HttpAnswers::new("HTTP/1.1 404 Not Found\r\n\r\n", "HTTP/1.1 503 your application is in deployment\r\n\r\n", None))),
+      config: Default::default(),
+      token: Token(0),
+      active: true,
+    };
+
+    let canary = || Some(HeaderMatch { name: String::from("X-Version"), value: String::from("canary") });
+    // the frontends without a header match are added first
+    for &(app_id, path_begin, ref header_match) in [("app_stable", "/", None), ("app_api", "/api", None), ("app_canary", "/", canary())].iter() {
+      listener.add_http_front(HttpFront { app_id: app_id.to_owned(), address: front, hostname: "lolcatho.st".to_owned(), path_begin: path_begin.to_owned(), trailing_slash: TrailingSlash::Strict, method: None, header_match: header_match.clone(), split: None })
+        .expect("should add frontend");
+    }
+
+    let app_id = |uri, version: Option<&'static str>| listener.frontend_from_request("lolcatho.st", Some("GET"), uri,
+      |name| if name.eq_ignore_ascii_case("X-Version") { version.map(String::from) } else { None }).map(|f| f.app_id.as_str());
+    assert_eq!(app_id("/index.html", Some("canary")), Some("app_canary"));
+    // the frontends without a header match are used when the header is missing or has another value
+    assert_eq!(app_id("/index.html", Some("stable")), Some("app_stable"));
+    assert_eq!(app_id("/index.html", None), Some("app_stable"));
+    // the path is more specific than the header
+    assert_eq!(app_id("/api/users", Some("canary")), Some("app_api"));
+  }
+
+  #[test]
+  fn listener_traffic_split() {
+    use sozu_command::proxy::{TrafficSplit, TrafficSplitPercentage};
+
+    let front: SocketAddr = FromStr::from_str("127.0.0.1:1055").expect("could not parse address");
+    let mut listener = Listener {
+      listener: None,
+      unix_listener: None,
+      address:  front,
+      fronts:   TrieNode::root(),
+      answers: Rc::new(RefCell::new(HttpAnswers::new("HTTP/1.1 404 Not Found\r\n\r\n", "HTTP/1.1 503 your application is in deployment\r\n\r\n", None))),
+      config: Default::default(),
+      token: Token(0),
+      active: true,
+    };
+
+    let split = Some(TrafficSplit { canary_app_id: String::from("app_canary"), percentage: 0, cookie: Some(String::from("SOZU_CANARY")) });
+    for &(app_id, path_begin, ref split) in [("app_stable", "/", split), ("app_other", "/other", None)].iter() {
+      listener.add_http_front(HttpFront { app_id: app_id.to_owned(), address: front, hostname: "lolcatho.st".to_owned(), path_begin: path_begin.to_owned(), trailing_slash: TrailingSlash::Strict, method: None, header_match: None, split: split.clone() })
+        .expect("should add frontend");
+    }
+
+    let percentage = |listener: &Listener, uri| listener.frontend_from_request("lolcatho.st", None, uri, |_| None)
+      .and_then(|f| f.split.as_ref()).map(|split| split.percentage);
+    assert_eq!(percentage(&listener, "/"), Some(0));
+
+    assert!(listener.set_traffic_split(&TrafficSplitPercentage { app_id: String::from("app_stable"), hostname: String::from("lolcatho.st"), percentage: 30 }));
+    assert_eq!(percentage(&listener, "/"), Some(30));
+    assert!(!listener.set_traffic_split(&TrafficSplitPercentage { app_id: String::from("app_other"), hostname: String::from("lolcatho.st"), percentage: 30 }));
+    assert_eq!(percentage(&listener, "/other"), None);
+    assert!(!listener.set_traffic_split(&TrafficSplitPercentage { app_id: String::from("app_stable"), hostname: String::from("other.domain"), percentage: 30 }));
+  }
+
+  #[test]
+  fn frontend_from_request_host_port() {
+    let front: SocketAddr = FromStr::from_str("127.0.0.1:1053").expect("could not parse address");
+    let mut listener = Listener::new(HttpListener { front, ..Default::default() }, Token(0));
+    for &(app_id, hostname) in [("app_domain", "lolcatho.st"), ("app_ipv6", "[::1]")].iter() {
+      listener.add_http_front(HttpFront { app_id: app_id.to_owned(), address: front, hostname: hostname.to_owned(), path_begin: "/".to_owned(), trailing_slash: TrailingSlash::Strict, method: None, header_match: None, split: None })
+        .expect("should add frontend");
+    }
+    let app_id = |host| listener.frontend_from_request(host, None, "/", |_| None).map(|f| f.app_id.as_str());
+
+    assert_eq!(app_id("lolcatho.st"), Some("app_domain"));
+    assert_eq!(app_id("lolcatho.st:8080"), Some("app_domain"));
+    assert_eq!(app_id("[::1]"), Some("app_ipv6"));
+    assert_eq!(app_id("[::1]:8080"), Some("app_ipv6"));
+
+    // invalid ports and unclosed brackets
+    assert_eq!(app_id("lolcatho.st:"), None);
+    assert_eq!(app_id("lolcatho.st:http"), None);
+    assert_eq!(app_id("[::1"), None);
+    assert_eq!(app_id("::1"), None);
+  }
+
+  #[test]
+  fn missing_host() {
+    setup_test_logger!();
+    let front: SocketAddr = FromStr::from_str("127.0.0.1:1138").expect("could not parse address");
+    let config = HttpListener {
+      front,
+      ..Default::default()
+    };
+
+    let (mut command, channel) = Channel::generate(1000, 10000).expect("should create a channel");
+    thread::spawn(move|| {
+      start(config, channel, 10, 16384);
+    });
+
+    let front = HttpFront { app_id: String::from("app_1"), address: "127.0.0.1:1138".parse().unwrap(), hostname: String::from("localhost"), path_begin: String::from("/"), trailing_slash: TrailingSlash::Strict, method: None, header_match: None, split: None };
+    command.write_message(&ProxyRequest { id: String::from("ID_ABCD"), order: ProxyRequestData::AddHttpFront(front) });
+    println!("test received: {:?}", command.read_message());
+
+    // the header block ends without a Host header
+    let mut client = TcpStream::connect(("127.0.0.1", 1138)).expect("could not connect");
+    client.set_read_timeout(Some(Duration::new(5,0))).unwrap();
+    client.write_all(&b"GET / HTTP/1.1\r\nAccept: */*\r\n\r\n"[..]).unwrap();
+    let answer = read_until(&mut client, "\r\n\r\n");
+    assert!(answer.starts_with("HTTP/1.1 400 Bad Request\r\n"), "unexpected answer: {}", answer);
+
+    // the port of the Host header is stripped, the application has no backend
+    let mut client = TcpStream::connect(("127.0.0.1", 1138)).expect("could not connect");
+    client.set_read_timeout(Some(Duration::new(5,0))).unwrap();
+    client.write_all(&b"GET / HTTP/1.1\r\nHost: localhost:1138\r\n\r\n"[..]).unwrap();
+    let answer = read_until(&mut client, "\r\n\r\n");
+    assert!(answer.starts_with("HTTP/1.1 503 "), "unexpected answer: {}", answer);
+
+    command.write_message(&ProxyRequest { id: String::from("ID_METRICS"), order: ProxyRequestData::Metrics });
+    let metrics = match command.read_message().unwrap().data {
+      Some(::sozu_command::proxy::ProxyResponseData::Metrics(metrics)) => metrics,
+      data => panic!("expected metrics, got {:?}", data),
+    };
+    assert_eq!(metrics.proxy.get("connection_errors.no_host_given"), Some(&::sozu_command::proxy::FilteredData::Count(1)));
+  }
+
+  #[test]
+  fn route_match() {
+    use sozu_command::proxy::RouteMatch;
+
+    let front: SocketAddr = FromStr::from_str("127.0.0.1:1086").expect("could not parse address");
+    let backends = Rc::new(RefCell::new(BackendMap::new()));
+    let pool = Rc::new(RefCell::new(Pool::with_capacity(1, 0, || Buffer::with_capacity(16384))));
+    let mut proxy = Proxy::new(pool, backends.clone());
+    let mut listener = Listener::new(HttpListener { front, ..Default::default() }, Token(0));
+    for &(app_id, path_begin) in [("app_api", "/api"), ("app_root", "/")].iter() {
+      listener.add_http_front(HttpFront { app_id: app_id.to_owned(), address: front, hostname: "lolcatho.st".to_owned(), path_begin: path_begin.to_owned(), trailing_slash: TrailingSlash::Strict, method: None, header_match: None, split: None })
+        .expect("should add frontend");
+    }
+    proxy.listeners.insert(Token(0), listener);
+
+    backends.borrow_mut().add_backend("app_api", ::Backend::new("app_api-0", "127.0.0.1:1087".parse().unwrap(), None, None, None));
+    backends.borrow_mut().add_backend("app_api", ::Backend::new("app_api-1", "127.0.0.1:1088".parse().unwrap(), None, None, Some(true)));
+
+    let matches = proxy.route_match("lolcatho.st", None, "/api/users");
+    assert_eq!(matches.len(), 1);
+    assert_eq!(matches[0].listener, front);
+    match matches[0].route {
+      RouteMatch::Match(ref route) => {
+        assert_eq!(route.frontend.app_id, "app_api");
+        assert_eq!(route.frontend.path_begin, "/api");
+        let ids: Vec<&str> = route.backends.iter().map(|b| b.backend_id.as_str()).collect();
+        assert_eq!(ids, vec!["app_api-0", "app_api-1"]);
+        assert!(route.backends.iter().all(|b| b.available && b.status == "NORMAL"));
+        assert!(route.backends[1].backup);
+      },
+      ref r => panic!("expected a match, got {:?}", r),
+    }
+
+    assert_eq!(proxy.route_match("unknown.domain", None, "/api")[0].route, RouteMatch::NoMatch);
+
+    // the application exists in the routing, but has no backend
+    match proxy.route_match("lolcatho.st", None, "/index.html")[0].route {
+      RouteMatch::NoAvailableBackend(ref route) => {
+        assert_eq!(route.frontend.app_id, "app_root");
+        assert!(route.backends.is_empty());
+      },
+      ref r => panic!("expected no available backend, got {:?}", r),
+    }
+
+    // all the backends are closed
+    assert!(backends.borrow_mut().drain_backend("app_api", "app_api-0").is_ok());
+    assert!(backends.borrow_mut().drain_backend("app_api", "app_api-1").is_ok());
+    match proxy.route_match("lolcatho.st", None, "/api")[0].route {
+      RouteMatch::NoAvailableBackend(ref route) => {
+        assert_eq!(route.frontend.app_id, "app_api");
+        assert_eq!(route.backends.len(), 2);
+        assert!(route.backends.iter().all(|b| !b.available && b.status == "CLOSED"));
+      },
+      ref r => panic!("expected no available backend, got {:?}", r),
+    }
   }
 }
@@ -11,11 +11,11 @@ use uuid::Uuid;
 use std::io::ErrorKind;
 use std::collections::{HashMap, HashSet};
 use slab::Slab;
-use std::net::SocketAddr;
+use std::net::{IpAddr,SocketAddr};
 use std::str::from_utf8_unchecked;
 use time::{SteadyTime, Duration};
 use openssl::ssl::{self, SslContext, SslContextBuilder, SslMethod, SslAlert,
-                   Ssl, SslOptions, SslRef, SslStream, SniError, NameType, SslSessionCacheMode};
+                   Ssl, SslOptions, SslRef, SslStream, SniError, NameType, SslSessionCacheMode, SslVerifyMode};
 use openssl::x509::X509;
 use openssl::dh::Dh;
 use openssl::pkey::PKey;
@@ -27,25 +27,30 @@ use mio_extras::timer::{Timer,Timeout};
 
 use sozu_command::scm_socket::ScmSocket;
 use sozu_command::proxy::{Application,CertFingerprint,CertificateAndKey,
-  ProxyRequestData,HttpFront,HttpsListener,ProxyRequest,ProxyResponse,
-  ProxyResponseStatus,TlsVersion,ProxyEvent,Query,QueryCertificateType,
-  QueryAnswer,QueryAnswerCertificate,ProxyResponseData};
+  ProxyRequestData,HttpFront,HeaderMatch,HttpsListener,ProxyRequest,ProxyResponse,
+  ProxyResponseStatus,TlsVersion,ProxyEvent,SetOcspResponse,HeaderLimits,Query,QueryCertificateType,
+  QueryAnswer,QueryAnswerCertificate,ProxyResponseData,ListenerTimeouts,ConnectionRateLimit,
+  UnexpectedBodyPolicy,ListenerRouteMatch,TrailingSlash,ClientIpConfig,TrafficSplit,TrafficSplitPercentage,ResponseCompression};
 use sozu_command::logging;
+use sozu_command::config::ProxyProtocolConfig;
 use sozu_command::buffer::Buffer;
 
-use protocol::http::{parser::{RequestState,RRequestLine,hostname_and_port}, answers::{DefaultAnswers, CustomAnswers, HttpAnswers}};
+use protocol::http::{parser::{RequestState,RRequestLine,hostname_and_port,hostname_without_port,header_value,request_cookie}, answers::{DefaultAnswers, CustomAnswers, HttpAnswers, connection_error_status}};
 use pool::Pool;
-use {AppId,Backend,SessionResult,ConnectionError,Protocol,Readiness,SessionMetrics,
+use backend_pool::ConnectionPool;
+use {AppId,SessionResult,ConnectionError,Protocol,Readiness,SessionMetrics,
   ProxySession,ProxyConfiguration,AcceptError,BackendConnectAction,BackendConnectionStatus,
-  CloseResult};
+  CloseResult,ConnectionGuard};
 use backends::BackendMap;
+use request_limit::{self, Admission};
+use traffic_split;
 use server::{Server,ProxyChannel,ListenToken,ListenPortState,SessionToken,
-  ListenSession, CONN_RETRIES, push_event};
+  ListenSession, push_event};
 use socket::server_bind;
 use trie::*;
 use protocol::{ProtocolResult,Http,Pipe,StickySession};
 use protocol::openssl::TlsHandshake;
-use protocol::http::{DefaultAnswerStatus, TimeoutStatus};
+use protocol::http::{self, DefaultAnswerStatus, TimeoutStatus, FrontendRule, frontend_match};
 use protocol::proxy_protocol::expect::ExpectProxyProtocol;
 use retry::RetryPolicy;
 use util::UnwrapLog;
@@ -55,6 +60,10 @@ pub struct TlsApp {
   pub app_id:           String,
   pub hostname:         String,
   pub path_begin:       String,
+  pub trailing_slash:   TrailingSlash,
+  pub method:           Option<String>,
+  pub header_match:     Option<HeaderMatch>,
+  pub split:            Option<TrafficSplit>,
 }
 
 pub enum State {
@@ -66,28 +75,43 @@ pub enum State {
 
 pub struct Session {
   frontend_token:     Token,
-  backend:            Option<Rc<RefCell<Backend>>>,
+  backend:            Option<ConnectionGuard>,
   back_connected:     BackendConnectionStatus,
   protocol:           Option<State>,
   public_address:     SocketAddr,
   ssl:                Option<Ssl>,
   pool:               Weak<RefCell<Pool<Buffer>>>,
   sticky_name:        String,
+  preserve_proxy_connection: bool,
+  ignore_unsupported_expect: bool,
+  unexpected_body_policy: UnexpectedBodyPolicy,
+  client_ip:          Rc<ClientIpConfig>,
+  header_limits:      HeaderLimits,
+  max_keepalive_requests: Option<usize>,
+  compression:        Option<Rc<ResponseCompression>>,
   metrics:            SessionMetrics,
   pub app_id:         Option<String>,
   timeout:            Timeout,
   last_event:         SteadyTime,
   pub listen_token:   Token,
   connection_attempt: u8,
+  /// ids of the backends the current request failed to connect to
+  tried_backends:     Vec<String>,
   peer_address:       Option<SocketAddr>,
   answers:            Rc<RefCell<HttpAnswers>>,
+  proxy_protocol_client: Option<IpAddr>,
+  /// receives the idle backend connections when the session ends
+  connection_pool:    Weak<RefCell<ConnectionPool>>,
 }
 
 impl Session {
   pub fn new(ssl:Ssl, sock: TcpStream, token: Token, pool: Weak<RefCell<Pool<Buffer>>>,
     public_address: SocketAddr, expect_proxy: bool, sticky_name: String,
-    timeout: Timeout, answers: Rc<RefCell<HttpAnswers>>, listen_token: Token,
-    delay: Duration) -> Session {
+    preserve_proxy_connection: bool, ignore_unsupported_expect: bool, unexpected_body_policy: UnexpectedBodyPolicy,
+    client_ip: Rc<ClientIpConfig>, header_limits: HeaderLimits,
+    max_keepalive_requests: Option<usize>,
+    compression: Option<Rc<ResponseCompression>>, timeout: Timeout, answers: Rc<RefCell<HttpAnswers>>, listen_token: Token,
+    delay: Duration, connection_pool: Weak<RefCell<ConnectionPool>>) -> Session {
 
     let peer_address = if expect_proxy {
       // Will be defined later once the expect proxy header has been received and parsed
@@ -117,14 +141,24 @@ impl Session {
       ssl:                None,
       pool,
       sticky_name,
+      preserve_proxy_connection,
+      ignore_unsupported_expect,
+      unexpected_body_policy,
+      client_ip,
+      header_limits,
+      max_keepalive_requests,
+      compression,
       metrics,
       app_id:             None,
       timeout,
       last_event:         SteadyTime::now(),
       listen_token,
       connection_attempt: 0,
+      tried_backends:     Vec::new(),
       peer_address,
       answers,
+      proxy_protocol_client: None,
+      connection_pool,
     };
     session.front_readiness().interest = UnixReady::from(Ready::readable()) | UnixReady::hup() | UnixReady::error();
 
@@ -159,25 +193,57 @@ impl Session {
     });
   }
 
+  /// gives an idle keep-alive backend connection to the connection pool instead of
+  /// closing it. The socket is removed from the session if the pool takes it
+  fn pool_backend_connection(&mut self, poll: &mut Poll) {
+    let connection_pool = match self.connection_pool.upgrade() {
+      Some(connection_pool) => connection_pool,
+      None => return,
+    };
+    let (app_id, backend) = match (self.app_id.as_ref(), self.backend.as_ref()) {
+      (Some(app_id), Some(backend)) => (app_id.clone(), Rc::clone(backend)),
+      _ => return,
+    };
+    if connection_pool.borrow().max_idle == 0 {
+      return;
+    }
+
+    let socket = match self.protocol {
+      Some(State::Http(ref mut http)) if http.is_backend_reusable() => http.backend.take(),
+      _ => None,
+    };
+
+    if let Some(socket) = socket {
+      if let Err(e) = poll.deregister(&socket) {
+        error!("error deregistering back socket({:?}): {:?}", socket, e);
+        return;
+      }
+
+      let result = connection_pool.borrow_mut().checkin(&app_id, &backend.borrow(), socket, SteadyTime::now());
+      if result.is_ok() {
+        debug!("{}\tthe idle backend connection goes to the connection pool", self.log_context());
+      }
+    }
+  }
+
   pub fn upgrade(&mut self) -> bool {
     let protocol = unwrap_msg!(self.protocol.take());
 
     if let State::Expect(expect, ssl) = protocol {
       debug!("switching to TLS handshake");
-      if let Some(ref addresses) = expect.addresses {
-        if let (Some(public_address), Some(session_address)) = (addresses.destination(), addresses.source()) {
-          self.public_address = public_address;
-          self.peer_address = Some(session_address);
-
-          let ExpectProxyProtocol { frontend, readiness, request_id, .. } = expect;
-          let mut tls = TlsHandshake::new(ssl, frontend, request_id, self.peer_address.clone());
-          tls.readiness.event = readiness.event;
-
-          gauge_add!("protocol.proxy.expect", -1);
-          gauge_add!("protocol.tls.handshake", 1);
-          self.protocol = Some(State::Handshake(tls));
-          return true;
-        }
+      if let Some((public_address, session_address)) = expect.session_addresses() {
+        self.public_address = public_address;
+        self.peer_address = Some(session_address);
+        self.proxy_protocol_client = Some(session_address.ip());
+
+        let ExpectProxyProtocol { frontend, readiness, request_id, .. } = expect;
+        let mut tls = TlsHandshake::new(ssl, frontend, request_id, self.peer_address.clone());
+        tls.readiness.event = readiness.event;
+
+        gauge_add!("protocol.proxy.expect", -1);
+        gauge_add!("protocol.tls.handshake", 1);
+        self.protocol = Some(State::Handshake(tls));
+        return true;
       }
 
       error!("failed to upgrade from expect");
@@ -196,10 +262,33 @@ impl Session {
         ssl.current_cipher().map(|c| incr!(c.name()));
       });
 
+      let mut tls_info = handshake.stream.as_ref().and_then(|s| {
+        let ssl = s.ssl();
+        ssl.current_cipher().map(|c| http::tls_info_headers(ssl.version_str(), c.name()))
+      });
+
+      // the certificate was already verified against the listener's client CA
+      let client_certificate = handshake.stream.as_ref()
+        .and_then(|s| s.ssl().peer_certificate())
+        .and_then(|certificate| certificate.to_der().ok())
+        .map(|der| http::ClientCertificate::from_der(&der));
+      if let Some(ref certificate) = client_certificate {
+        info!("{}\tclient certificate: subject \"{}\", fingerprint {}",
+          handshake.request_id, certificate.subject, certificate.fingerprint);
+        tls_info = tls_info.map(|headers| headers + &certificate.headers());
+      }
+
       let mut http = Http::new(unwrap_msg!(handshake.stream), self.frontend_token.clone(),
         handshake.request_id, pool, self.public_address.clone(), self.peer_address,
-        self.sticky_name.clone(), Protocol::HTTPS);
-
+        self.sticky_name.clone(), self.preserve_proxy_connection, self.ignore_unsupported_expect,
+        self.unexpected_body_policy, Protocol::HTTPS);
+
+      http.client_ip = self.client_ip.clone();
+      http.header_limits = self.header_limits;
+      http.set_max_keepalive_requests(self.max_keepalive_requests);
+      http.compression = self.compression.clone();
+      http.tls_info_headers = tls_info;
+      http.client_certificate = client_certificate;
       http.front_readiness = readiness;
       http.front_readiness.interest = UnixReady::from(Ready::readable()) | UnixReady::hup() | UnixReady::error();
 
@@ -262,7 +351,7 @@ impl Session {
   fn front_hup(&mut self)     -> SessionResult {
     match *unwrap_msg!(self.protocol.as_mut()) {
       State::Http(ref mut http)      => http.front_hup(),
-      State::WebSocket(ref mut pipe) => pipe.front_hup(&mut self.metrics),
+      State::WebSocket(ref mut pipe) => pipe.front_hup(),
       State::Handshake(_)            => {
         SessionResult::CloseSession
       },
@@ -275,7 +364,7 @@ impl Session {
   fn back_hup(&mut self)      -> SessionResult {
     match *unwrap_msg!(self.protocol.as_mut()) {
       State::Http(ref mut http)      => http.back_hup(),
-      State::WebSocket(ref mut pipe) => pipe.back_hup(&mut self.metrics),
+      State::WebSocket(ref mut pipe) => pipe.back_hup(),
       State::Handshake(_)            => {
         error!("why a backend HUP event while still in frontend handshake?");
         SessionResult::CloseSession
@@ -334,6 +423,15 @@ impl Session {
       State::WebSocket(ref mut pipe) => (ProtocolResult::Continue, pipe.back_readable(&mut self.metrics)),
     };
 
+    // the backend broke its response, the request goes to another one
+    if let SessionResult::ReconnectBackend(_,_) = result {
+      if self.http_mut().map(|http| http.take_backend_failure()).unwrap_or(false) {
+        if let Some(backend_id) = self.backend.as_ref().map(|backend| backend.borrow().backend_id.clone()) {
+          self.tried_backends.push(backend_id);
+        }
+      }
+    }
+
     if upgrade == ProtocolResult::Continue {
       result
     } else {
@@ -410,9 +508,12 @@ impl Session {
         let ref mut backend = *backend.borrow_mut();
         let was_unavailable = backend.retry_policy.is_down();
 
-        //successful connection, reset failure counter
-        backend.failures = 0;
-        backend.retry_policy.succeed();
+        //successful connection, reset failure counter, unless the failures
+        //come from its answers
+        if !backend.failing_answers {
+          backend.failures = 0;
+          backend.retry_policy.succeed();
+        }
 
         if was_unavailable {
             incr!("up", self.app_id.as_ref().map(|s| s.as_str()), self.metrics.backend_id.as_ref().map(|s| s.as_str()));
@@ -426,10 +527,10 @@ impl Session {
   }
 
   fn remove_backend(&mut self) {
-    if let Some(backend) = self.backend.take() {
+    if let Some(mut backend) = self.backend.take() {
        self.http_mut().map(|h| h.clear_back_token());
 
-       (*backend.borrow_mut()).dec_connections();
+       backend.close();
     }
   }
 
@@ -458,6 +559,8 @@ impl Session {
     self.backend.as_ref().map(|backend| {
       let ref mut backend = *backend.borrow_mut();
       backend.failures += 1;
+      backend.last_failure = Some(SteadyTime::now());
+      backend.record_outcome(true, SteadyTime::now());
 
       let already_unavailable = backend.retry_policy.is_down();
       backend.retry_policy.fail();
@@ -473,6 +576,12 @@ impl Session {
 
   fn reset_connection_attempt(&mut self) {
     self.connection_attempt = 0;
+    self.tried_backends.clear();
+  }
+
+  fn is_idempotent_request(&self) -> bool {
+    self.http().and_then(|http| http.request.as_ref()).and_then(|request| request.get_request_line())
+      .map(|request_line| request_line.method.is_idempotent()).unwrap_or(true)
   }
 }
 
@@ -522,11 +631,34 @@ impl ProxySession for Session {
     result
   }
 
-  fn timeout(&mut self, token: Token, timer: &mut Timer<Token>, front_timeout: &Duration) -> SessionResult {
+  fn timeout(&mut self, token: Token, timer: &mut Timer<Token>, front_timeout: &Duration,
+    idle_timeout: Option<&Duration>, header_timeout: Option<&Duration>, connect_timeout: &Duration) -> SessionResult {
     if self.frontend_token == token {
-      let dur = SteadyTime::now() - self.last_event;
+      let now = SteadyTime::now();
+      self.metrics.update_activity(self.last_event);
+      if idle_timeout.map(|t| self.metrics.is_idle_at(*t, now)).unwrap_or(false) {
+        info!("{}\tclosing idle session", self.log_context());
+        incr!("idle_timeout");
+        return SessionResult::CloseSession;
+      }
+
+      // the client keeps sending data, but not the complete header block
+      let header_time_left = header_timeout.and_then(|t| self.http().and_then(|h| h.header_time_left(*t, now)));
+      if header_time_left.map(|left| left <= Duration::zero()).unwrap_or(false) {
+        info!("{}\trequest headers not received in time, closing", self.log_context());
+        incr!("http.header_timeout");
+        let answer = self.answers.borrow().get(DefaultAnswerStatus::Answer408, None);
+        self.set_answer(DefaultAnswerStatus::Answer408, answer);
+        return self.writable();
+      }
+
+      let dur = now - self.last_event;
       if dur < *front_timeout {
-        timer.set_timeout((*front_timeout - dur).to_std().unwrap(), token);
+        let mut delay = self.metrics.next_timeout_at(*front_timeout - dur, idle_timeout, now);
+        if let Some(header_timeout) = header_timeout {
+          delay = ::std::cmp::min(delay, header_time_left.unwrap_or(*header_timeout));
+        }
+        timer.set_timeout(delay.to_std().unwrap(), token);
         SessionResult::Continue
       } else {
         match self.http().map(|h| h.timeout_status()) {
@@ -545,6 +677,28 @@ impl ProxySession for Session {
           }
         }
       }
+    } else if self.back_token() == Some(token) && self.response_time_left()
+      .map(|left| left <= Duration::zero()).unwrap_or(false) {
+      error!("{} the backend did not answer before the response timeout of the application", self.log_context());
+      incr!("backend.response_timeout");
+      // the client already got part of the response, it cannot get an error answer
+      if self.http().map(|h| h.response_started()).unwrap_or(true) {
+        return SessionResult::CloseSession;
+      }
+      let answer = self.answers.borrow().get(DefaultAnswerStatus::Answer504, None);
+      self.set_answer(DefaultAnswerStatus::Answer504, answer);
+      self.writable()
+    } else if self.back_token() == Some(token) && self.back_connected() == BackendConnectionStatus::Connecting {
+      // the timer could have been set for a previous connection attempt
+      if !self.metrics.is_connect_timed_out_at(*connect_timeout, SteadyTime::now()) {
+        return SessionResult::Continue;
+      }
+
+      error!("{} timeout connecting to backend, trying again", self.log_context());
+      incr!("backend.connect_timeout");
+      self.connection_attempt += 1;
+      self.fail_backend_connection();
+      SessionResult::ReconnectBackend(Some(self.frontend_token), Some(token))
     } else {
       // invalid token, obsolete timeout triggered
       SessionResult::Continue
@@ -557,6 +711,9 @@ impl ProxySession for Session {
 
   //FIXME: check the token passed as argument
   fn close_backend(&mut self, _: Token, poll: &mut Poll) {
+    if self.back_connected() == BackendConnectionStatus::Connected {
+      self.pool_backend_connection(poll);
+    }
     self.remove_backend();
 
     let back_connected = self.back_connected();
@@ -592,6 +749,7 @@ impl ProxySession for Session {
 
   fn process_events(&mut self, token: Token, events: Ready) {
     trace!("token {:?} got event {}", token, super::unix_ready_to_string(UnixReady::from(events)));
+    self.metrics.update_activity(self.last_event);
     self.last_event = SteadyTime::now();
     self.metrics.wait_start();
 
@@ -635,6 +793,10 @@ impl ProxySession for Session {
         SessionResult::CloseSession => {
           return order;
         },
+        SessionResult::Continue => {
+          // a half closed frontend can still receive data
+          self.front_readiness().event.remove(UnixReady::hup());
+        },
         _ => {
           self.front_readiness().event.remove(UnixReady::hup());
           return order;
@@ -743,6 +905,14 @@ impl ProxySession for Session {
     SessionResult::Continue
   }
 
+  fn back_connection_status(&self) -> BackendConnectionStatus {
+    self.back_connected
+  }
+
+  fn response_time_left(&self) -> Option<Duration> {
+    self.http().and_then(|http| http.response_time_left(SteadyTime::now()))
+  }
+
   fn shutting_down(&mut self) -> SessionResult {
     match &mut self.protocol {
       Some(State::Http(h)) => h.shutting_down(),
@@ -751,6 +921,11 @@ impl ProxySession for Session {
     }
   }
 
+  fn take_proxy_protocol_client(&mut self) -> Option<(ListenToken, IpAddr)> {
+    let listen_token = ListenToken(self.listen_token.0);
+    self.proxy_protocol_client.take().map(|ip| (listen_token, ip))
+  }
+
   fn last_event(&self) -> SteadyTime {
     self.last_event
   }
@@ -817,8 +992,10 @@ fn get_cert_names(cert: &X509) -> HashSet<String> {
 pub type HostName  = String;
 pub type PathBegin = String;
 pub struct TlsData {
-  context:     SslContext,
-  certificate: Vec<u8>,
+  context:       SslContext,
+  certificate:   Vec<u8>,
+  /// OCSP response stapled to the handshakes, read by the status callback
+  ocsp_response: Arc<Mutex<Option<Vec<u8>>>>,
 }
 
 pub struct Listener {
@@ -849,13 +1026,16 @@ impl Listener {
     let (default_context, ssl_options):(SslContext, SslOptions) =
       Self::create_default_context(&config, ref_ctx, ref_domains).expect("could not create default context");
 
+    let mut answers = HttpAnswers::new(&config.answer_404, &config.answer_503, config.retry_after);
+    answers.set_error_pages(&config.error_pages);
+
     Listener {
       listener:        None,
       address:         config.front.clone(),
       domains:         rc_domains,
       default_context: default_context,
       contexts:        rc_ctx,
-      answers:         Rc::new(RefCell::new(HttpAnswers::new(&config.answer_404, &config.answer_503))),
+      answers:         Rc::new(RefCell::new(answers)),
       active:          false,
       fronts,
       config,
@@ -869,7 +1049,7 @@ impl Listener {
       return Some(self.token);
     }
 
-    let listener = tcp_listener.or_else(|| server_bind(&self.config.front).map_err(|e| {
+    let listener = tcp_listener.or_else(|| server_bind(&self.config.front, self.config.interface.as_ref().map(|s| s.as_str())).map_err(|e| {
       error!("could not create listener {:?}: {:?}", self.config.front, e);
     }).ok());
 
@@ -941,6 +1121,14 @@ impl Listener {
       error!("could not set context cipher list: {:?}", e);
     }
 
+    if let Some(ref client_ca) = config.client_ca {
+      if let Err(e) = setup_client_verification(&mut context, client_ca) {
+        error!("could not set up the client certificate verification: {:?}", e);
+        // without a CA, the verification refuses every client certificate
+        context.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+      }
+    }
+
     let mut cert_read = &include_bytes!("../assets/certificate.pem")[..];
     let mut key_read  = &include_bytes!("../assets/key.pem")[..];
 
@@ -991,25 +1179,52 @@ impl Listener {
     Some((context.build(), ssl_options))
   }
 
-  pub fn add_https_front(&mut self, tls_front: HttpFront) -> bool {
+  pub fn add_https_front(&mut self, tls_front: HttpFront) -> Result<(), String> {
     //FIXME: should clone he hostname then do a into() here
     let app = TlsApp {
       app_id:           tls_front.app_id.clone(),
       hostname:         tls_front.hostname.clone(),
       path_begin:       tls_front.path_begin.clone(),
+      trailing_slash:   tls_front.trailing_slash,
+      method:           tls_front.method.clone(),
+      header_match:     tls_front.header_match.clone(),
+      split:            tls_front.split.clone(),
     };
 
     if let Some((_, ref mut fronts)) = self.fronts.domain_lookup_mut(&tls_front.hostname.clone().into_bytes(), false) {
+        let method = app.method.as_ref().map(|m| m.to_uppercase());
+        if let Some(other) = fronts.iter().find(|f| f.app_id != app.app_id && f.path_begin == app.path_begin
+          && f.header_match == app.header_match && f.method.as_ref().map(|m| m.to_uppercase()) == method) {
+          return Err(format!("the frontend {}{} is already used by the application {}",
+            app.hostname, app.path_begin, other.app_id));
+        }
         if ! fronts.contains(&app) {
           fronts.push(app.clone());
         }
     }
 
     if self.fronts.domain_lookup(&tls_front.hostname.clone().into_bytes(), false).is_none() {
-      self.fronts.domain_insert(tls_front.hostname.into_bytes(), vec![app]);
+      let hostname = tls_front.hostname;
+      if self.fronts.domain_insert(hostname.clone().into_bytes(), vec![app]) == InsertResult::Failed {
+        return Err(format!("invalid hostname {}: a wildcard can only replace the leftmost label", hostname));
+      }
     }
-
-    true
+    Ok(())
+  }
+
+  /// changes the percentage of the traffic split of the application's frontends
+  /// on this hostname. Returns false if there is none
+  pub fn set_traffic_split(&mut self, split: &TrafficSplitPercentage) -> bool {
+    let mut found = false;
+    if let Some((_, fronts)) = self.fronts.domain_lookup_mut(split.hostname.as_bytes(), false) {
+      for front in fronts.iter_mut().filter(|front| front.app_id == split.app_id) {
+        if let Some(ref mut front_split) = front.split {
+          front_split.percentage = split.percentage;
+          found = true;
+        }
+      }
+    }
+    found
   }
 
   pub fn remove_https_front(&mut self, front: HttpFront) {
@@ -1020,8 +1235,9 @@ impl Listener {
       if let Some((_, fronts)) = fronts_opt {
         if let Some(pos) = fronts.iter().position(|f| {
           &f.app_id == &front.app_id &&
-          &f.hostname == &front.hostname &&
-          &f.path_begin == &front.path_begin
+          f.hostname.eq_ignore_ascii_case(&front.hostname) &&
+          &f.path_begin == &front.path_begin &&
+          &f.method == &front.method
         }) {
           let front = fronts.remove(pos);
         }
@@ -1056,6 +1272,13 @@ impl Listener {
       error!("cannot set context cipher list: {:?}", e);
     }
 
+    if let Some(ref client_ca) = self.config.client_ca {
+      if let Err(e) = setup_client_verification(&mut ctx, client_ca) {
+        error!("could not set up the client certificate verification: {:?}", e);
+        ctx.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+      }
+    }
+
     let mut cert_read  = &certificate_and_key.certificate.as_bytes()[..];
     let mut key_read   = &certificate_and_key.key.as_bytes()[..];
     let cert_chain: Vec<X509> = certificate_and_key.certificate_chain.iter().filter_map(|c| {
@@ -1120,9 +1343,22 @@ impl Listener {
         return Err(SniError::ALERT_FATAL);
       });
 
+      let ocsp_response = Arc::new(Mutex::new(None));
+      let ref_ocsp_response = ocsp_response.clone();
+      let status_callback = ctx.set_status_callback(move |ssl: &mut SslRef| {
+        match *unwrap_msg!(ref_ocsp_response.lock()) {
+          Some(ref response) => ssl.set_ocsp_status(response).map(|_| true),
+          None               => Ok(false),
+        }
+      });
+      if let Err(e) = status_callback {
+        error!("could not set the OCSP status callback: {:?}", e);
+      }
+
       let tls_data = TlsData {
         context:     ctx.build(),
         certificate: cert_read.to_vec(),
+        ocsp_response,
       };
 
       // if the name or the fingerprint are already used,
@@ -1168,39 +1404,48 @@ impl Listener {
     }
   }
 
+  /// the new certificate is validated before removing the old one. Both
+  /// happen in the same order, so no handshake sees the domains without
+  /// certificate, and established sessions keep their SSL context
+  pub fn replace_certificate(&mut self, old_fingerprint: CertFingerprint, new_certificate: CertificateAndKey) -> bool {
+    if X509::from_pem(new_certificate.certificate.as_bytes()).is_err()
+      || PKey::private_key_from_pem(new_certificate.key.as_bytes()).is_err() {
+      error!("could not load the new certificate, keeping the certificate with fingerprint {:?}", old_fingerprint);
+      return false;
+    }
+
+    self.remove_certificate(old_fingerprint);
+    self.add_certificate(new_certificate)
+  }
+
+  /// an empty response removes the staple
+  pub fn set_ocsp_response(&mut self, set_ocsp_response: SetOcspResponse) -> bool {
+    debug!("setting the OCSP response of the certificate {}", set_ocsp_response.fingerprint);
+    let contexts = unwrap_msg!(self.contexts.lock());
+    match contexts.get(&set_ocsp_response.fingerprint) {
+      Some(tls_data) => {
+        let response = set_ocsp_response.ocsp_response;
+        *unwrap_msg!(tls_data.ocsp_response.lock()) = if response.is_empty() { None } else { Some(response) };
+        true
+      },
+      None => false,
+    }
+  }
+
   // ToDo factor out with http.rs
-  pub fn frontend_from_request(&self, host: &str, uri: &str) -> Option<&TlsApp> {
-    let host: &str = if let Ok((i, (hostname, _))) = hostname_and_port(host.as_bytes()) {
-      if i != &b""[..] {
-        error!("frontend_from_request: invalid remaining chars after hostname. Host: {}", host);
+  pub fn frontend_from_request<H>(&self, host: &str, method: Option<&str>, uri: &str, header: H) -> Option<&TlsApp>
+    where H: Fn(&str) -> Option<String> {
+    // the fronts are stored without port
+    let host = match hostname_without_port(host) {
+      Some(hostname) => hostname,
+      None => {
+        error!("hostname parsing failed for: '{}'", host);
         return None;
       }
-
-      // it is alright to call from_utf8_unchecked,
-      // we already verified that there are only ascii
-      // chars in there
-      unsafe { from_utf8_unchecked(hostname) }
-    } else {
-      error!("hostname parsing failed for: '{}'", host);
-      return None;
     };
 
     if let Some((_, http_fronts)) = self.fronts.domain_lookup(host.as_bytes(), true) {
-      let matching_fronts = http_fronts.iter().filter(|f| uri.starts_with(&f.path_begin)); // ToDo match on uri
-      let mut front = None;
-
-      for f in matching_fronts {
-        if front.is_none() {
-          front = Some(f);
-        }
-
-        if let Some(ff) = front {
-          if f.path_begin.len() > ff.path_begin.len() {
-            front = Some(f)
-          }
-        }
-      }
-      front
+      frontend_match(http_fronts, method, uri, header, |f| FrontendRule { method: f.method.as_ref().map(|m| m.as_str()), path_begin: &f.path_begin, trailing_slash: f.trailing_slash, header_match: f.header_match.as_ref() })
     } else {
       None
     }
@@ -1283,6 +1528,49 @@ impl Proxy {
     })
   }
 
+  /// lists the frontends of every listener, sorted by listener address then hostname
+  pub fn routes(&self) -> Vec<HttpFront> {
+    let mut listeners: Vec<&Listener> = self.listeners.values().collect();
+    listeners.sort_by_key(|l| l.address);
+
+    listeners.iter().flat_map(|l| {
+      l.fronts.entries().into_iter().flat_map(move |&(_, ref apps)| apps.iter().map(move |app| HttpFront {
+        app_id:     app.app_id.clone(),
+        address:    l.address,
+        hostname:   app.hostname.clone(),
+        path_begin: app.path_begin.clone(),
+        trailing_slash: app.trailing_slash,
+        method:     app.method.clone(),
+        header_match: app.header_match.clone(),
+        split:      app.split.clone(),
+      }))
+    }).collect()
+  }
+
+  /// runs the frontend lookup of every listener for this request
+  pub fn route_match(&self, hostname: &str, method: Option<&str>, path: &str) -> Vec<ListenerRouteMatch> {
+    let mut listeners: Vec<&Listener> = self.listeners.values().collect();
+    listeners.sort_by_key(|l| l.address);
+
+    listeners.iter().map(|l| {
+      let frontend = l.frontend_from_request(hostname, method, path, |_| None).map(|app| HttpFront {
+        app_id:     app.app_id.clone(),
+        address:    l.address,
+        hostname:   app.hostname.clone(),
+        path_begin: app.path_begin.clone(),
+        trailing_slash: app.trailing_slash,
+        method:     app.method.clone(),
+        header_match: app.header_match.clone(),
+        split:      app.split.clone(),
+      });
+
+      ListenerRouteMatch {
+        listener: l.address,
+        route:    self.backends.borrow().route_match(frontend),
+      }
+    }).collect()
+  }
+
   pub fn add_application(&mut self, mut application: Application) {
     if let Some(answer_503) = application.answer_503.take() {
       for l in self.listeners.values_mut() {
@@ -1300,6 +1588,42 @@ impl Proxy {
     }
   }
 
+  /// removes the application with its frontends on every listener,
+  /// returns the number of frontends removed
+  pub fn purge_application(&mut self, app_id: &str) -> usize {
+    let fronts: Vec<HttpFront> = self.routes().into_iter().filter(|front| front.app_id == app_id).collect();
+    let count = fronts.len();
+    for front in fronts {
+      if let Some(listener) = self.listeners.values_mut().find(|l| l.address == front.address) {
+        listener.remove_https_front(front);
+      }
+    }
+
+    self.remove_application(app_id);
+    count
+  }
+
+  pub fn set_application_enabled(&mut self, app_id: &str, enabled: bool, id: String) -> ProxyResponse {
+    match self.applications.get_mut(app_id) {
+      Some(app) => {
+        app.enabled = enabled;
+        ProxyResponse{ id, status: ProxyResponseStatus::Ok, data: None }
+      },
+      None => ProxyResponse{ id, status: ProxyResponseStatus::Error(format!("no such application {}", app_id)), data: None },
+    }
+  }
+
+  /// the sessions use the new timeout from their next request
+  pub fn set_app_timeout(&mut self, app_id: &str, timeout: Option<u32>, id: String) -> ProxyResponse {
+    match self.applications.get_mut(app_id) {
+      Some(app) => {
+        app.response_timeout = timeout;
+        ProxyResponse{ id, status: ProxyResponseStatus::Ok, data: None }
+      },
+      None => ProxyResponse{ id, status: ProxyResponseStatus::Error(format!("no such application {}", app_id)), data: None },
+    }
+  }
+
   pub fn backend_from_request(&mut self, session: &mut Session, app_id: &str,
   front_should_stick: bool) -> Result<TcpStream,ConnectionError> {
     session.http_mut().map(|h| h.set_app_id(String::from(app_id)));
@@ -1308,37 +1632,42 @@ impl Proxy {
       .and_then(|r| r.get_sticky_session());
 
     let res = match (front_should_stick, sticky_session) {
-      (true, Some(sticky_session)) => {
+      // once the sticky backend failed, the request is retried on another one
+      (true, Some(sticky_session)) if session.tried_backends.is_empty() => {
         self.backends.borrow_mut().backend_from_sticky_session(app_id, &sticky_session)
           .map_err(|e| {
             debug!("Couldn't find a backend corresponding to sticky_session {} for app {}", sticky_session, app_id);
             e
           })
       },
-      _ => self.backends.borrow_mut().backend_from_app_id(app_id),
+      _ => self.backends.borrow_mut().untried_backend_from_app_id(app_id, &session.tried_backends),
     };
 
     match res {
       Err(e) => {
+        session.http().map(|http| http.trace_event(format_args!("no backend available for application {}: {:?}", app_id, e)));
         let answer = self.get_service_unavailable_answer(Some(app_id), &session.listen_token);
         session.set_answer(DefaultAnswerStatus::Answer503, answer);
         Err(e)
       },
       Ok((backend, conn))  => {
+        session.http().map(|http| http.trace_event(format_args!("selected backend {} at {}, sticky session: {:?}, tried backends: {:?}",
+          backend.borrow().backend_id, backend.borrow().address, sticky_session, session.tried_backends)));
         if front_should_stick {
           let sticky_name = self.listeners[&session.listen_token].config.sticky_name.clone();
           session.http_mut().map(|http| {
             http.sticky_session =
-              Some(StickySession::new(backend.borrow().sticky_id.clone().unwrap_or(backend.borrow().backend_id.clone())));
+              Some(StickySession::new(backend.borrow().sticky_session_id().to_string()));
             http.sticky_name = sticky_name;
           });
         }
         session.metrics.backend_id = Some(backend.borrow().backend_id.clone());
         session.metrics.backend_start();
+        session.tried_backends.push(backend.borrow().backend_id.clone());
         session.http_mut().map(|http| {
-          http.set_backend_id(backend.borrow().backend_id.clone());
+          http.set_backend(backend.clone());
         });
-        session.backend = Some(backend);
+        session.backend = Some(ConnectionGuard::new(backend));
 
         Ok(conn)
       }
@@ -1346,10 +1675,12 @@ impl Proxy {
   }
 
   fn app_id_from_request(&mut self,  session: &mut Session) -> Result<String, ConnectionError> {
-    let h = session.http().and_then(|h| h.request.as_ref())
-      .and_then(|s| s.get_host()).ok_or(ConnectionError::NoHostGiven)?;
+    let h = match session.http().and_then(|h| h.request.as_ref()).and_then(|s| s.get_host()) {
+      Some(h) => h,
+      None    => return Err(self.answer_connection_error(session, ConnectionError::NoHostGiven)),
+    };
 
-    let host: &str = if let Ok((i, (hostname, port))) = hostname_and_port(h.as_bytes()) {
+    let host: String = if let Ok((i, (hostname, port))) = hostname_and_port(h.as_bytes()) {
       if i != &b""[..] {
         error!("connect_to_backend: invalid remaining chars after hostname. Host: {}", h);
         let answer = self.listeners[&session.listen_token].answers.borrow().get(DefaultAnswerStatus::Answer400, None);
@@ -1362,25 +1693,19 @@ impl Proxy {
       // chars in there
       let hostname_str =  unsafe { from_utf8_unchecked(hostname) };
 
-      //FIXME: what if we don't use SNI?
       let servername: Option<String> = session.http()
         .and_then(|h| h.frontend.ssl().servername(NameType::HOST_NAME)).map(|s| s.to_string());
-
-      if servername.as_ref().map(|s| s.as_str()) != Some(hostname_str) {
-        error!("TLS SNI hostname '{:?}' and Host header '{}' don't match", servername, hostname_str);
-        /*FIXME: deactivate this check for a temporary test
-        let answer = self.listeners[&session.listen_token].answers.borrow().get(DefaultAnswerStatus::Answer404, None);
-        unwrap_msg!(session.http()).set_answer(DefaultAnswerStatus::Answer404, answer);
-        return Err(ConnectionError::HostNotFound);
-        */
-      }
+      let reject_sni_mismatch = self.listeners[&session.listen_token].config.reject_sni_mismatch;
 
       //FIXME: we should check that the port is right too
-
-      if port == Some(&b"443"[..]) {
-        hostname_str
-      } else {
-        &h
+      match http::tls_routing_host(&h, hostname_str, port, servername.as_ref().map(|s| s.as_str()), reject_sni_mismatch) {
+        Some(host) => host,
+        None => {
+          error!("TLS SNI hostname '{:?}' and Host header '{}' don't match", servername, hostname_str);
+          let answer = self.listeners[&session.listen_token].answers.borrow().get(DefaultAnswerStatus::Answer404, None);
+          session.set_answer(DefaultAnswerStatus::Answer404, answer);
+          return Err(ConnectionError::HostNotFound);
+        },
       }
     } else {
       error!("hostname parsing failed");
@@ -1389,11 +1714,19 @@ impl Proxy {
       return Err(ConnectionError::InvalidHost);
     };
 
-    let rl:&RRequestLine = session.http().and_then(|h| h.request.as_ref()).and_then(|r| r.get_request_line())
-      .ok_or(ConnectionError::NoRequestLineGiven)?;
+    let rl:&RRequestLine = match session.http().and_then(|h| h.request.as_ref()).and_then(|r| r.get_request_line()) {
+      Some(rl) => rl,
+      None     => return Err(self.answer_connection_error(session, ConnectionError::NoRequestLineGiven)),
+    };
+    let front_buf = session.http().and_then(|h| h.front_buf.as_ref());
+    let client_ip = session.http().and_then(|h| h.get_session_address()).map(|address| address.ip());
     match self.listeners.get(&session.listen_token).as_ref()
-      .and_then(|l| l.frontend_from_request(&host, &rl.uri))
-      .map(|ref front| front.app_id.clone()) {
+      .and_then(|l| l.frontend_from_request(&host, Some(&rl.method.to_string()), rl.path(),
+        |name| front_buf.and_then(|buf| header_value(buf, name.as_bytes()))))
+      .map(|front| traffic_split::app_id(&front.app_id, front.split.as_ref(),
+        |name| front_buf.and_then(|buf| request_cookie(buf, name)), client_ip).to_string())
+      // the default application only gets the requests that no frontend matches
+      .or_else(|| self.listeners.get(&session.listen_token).and_then(|l| l.config.default_app.clone())) {
       Some(app_id) => Ok(app_id),
       None => {
         let answer = self.listeners[&session.listen_token].answers.borrow().get(DefaultAnswerStatus::Answer404, None);
@@ -1403,15 +1736,83 @@ impl Proxy {
     }
   }
 
+  /// answers the requests of a disabled application with its maintenance answer,
+  /// or its 503 answer without it
+  fn check_application_enabled(&self, session: &mut Session, app_id: &str) -> Result<(), ConnectionError> {
+    let maintenance_answer = match self.applications.get(app_id) {
+      Some(app) if !app.enabled => app.maintenance_answer.clone(),
+      _ => return Ok(()),
+    };
+
+    let answer = match maintenance_answer {
+      Some(answer) => Rc::new(answer.into_bytes()),
+      None         => self.get_service_unavailable_answer(Some(app_id), &session.listen_token),
+    };
+    session.set_answer(DefaultAnswerStatus::Answer503, answer);
+    Err(ConnectionError::ApplicationDisabled)
+  }
+
+  /// takes a slot in the concurrency limit of the application. Returns false if
+  /// the request waits in the queue, and answers 503 if the queue is full
+  fn take_request_slot(&self, session: &mut Session, app_id: &str) -> Result<bool, ConnectionError> {
+    let limits = self.backends.borrow().request_limits.clone();
+    let current = session.http_mut().and_then(|http| http.take_request_slot());
+    let was_queued = current.as_ref().map(|slot| !slot.is_held()).unwrap_or(false);
+    match request_limit::admit(&limits, app_id, session.frontend_token, current) {
+      Admission::Admitted(slot) => {
+        session.http_mut().map(|http| http.set_request_slot(slot));
+        if was_queued {
+          session.front_readiness().interest.insert(Ready::readable());
+          session.back_readiness().map(|r| r.interest.insert(Ready::writable()));
+        }
+        Ok(true)
+      },
+      Admission::Queued(slot) => {
+        // nothing is read or sent to a kept alive backend connection until it gets a slot
+        session.http_mut().map(|http| http.set_request_slot(Some(slot)));
+        session.front_readiness().interest.remove(Ready::readable());
+        session.back_readiness().map(|r| r.interest.remove(Ready::writable()));
+        Ok(false)
+      },
+      Admission::Rejected => {
+        let answer = self.get_service_unavailable_answer(Some(app_id), &session.listen_token);
+        session.set_answer(DefaultAnswerStatus::Answer503, answer);
+        Err(ConnectionError::RequestQueueFull)
+      },
+    }
+  }
+
+  /// sets the default answer for a request that could not be sent to a backend
+  fn answer_connection_error(&self, session: &mut Session, error: ConnectionError) -> ConnectionError {
+    let status = connection_error_status(&error);
+    let answer = self.listeners[&session.listen_token].answers.borrow()
+      .get(status, session.app_id.as_ref().map(|app_id| app_id.as_str()));
+    session.set_answer(status, answer);
+    error
+  }
+
   fn check_circuit_breaker(&mut self, session: &mut Session) -> Result<(), ConnectionError> {
-    if session.connection_attempt == CONN_RETRIES {
+    let (connection_attempts, retry_non_idempotent) = {
+      let backends = self.backends.borrow();
+      (backends.connection_attempts, backends.retry_non_idempotent)
+    };
+
+    if session.connection_attempt >= connection_attempts {
       error!("{} max connection attempt reached", session.log_context());
-      let answer = self.get_service_unavailable_answer(session.app_id.as_ref().map(|app_id| app_id.as_str()), &session.listen_token);
-      session.set_answer(DefaultAnswerStatus::Answer503, answer);
-      Err(ConnectionError::NoBackendAvailable)
+    } else if session.connection_attempt > 0 && !retry_non_idempotent && !session.is_idempotent_request() {
+      error!("{} backend connection failed, not retrying a non idempotent request", session.log_context());
+    } else if session.connection_attempt > 0 && !session.app_id.as_ref()
+      .map(|app_id| self.backends.borrow_mut().take_retry(app_id)).unwrap_or(true) {
+      error!("{} backend connection failed, the retry budget of the application is exhausted", session.log_context());
     } else {
-      Ok(())
+      return Ok(());
     }
+
+    // the next request of the connection gets its own attempts
+    session.reset_connection_attempt();
+    let answer = self.get_service_unavailable_answer(session.app_id.as_ref().map(|app_id| app_id.as_str()), &session.listen_token);
+    session.set_answer(DefaultAnswerStatus::Answer503, answer);
+    Err(ConnectionError::NoBackendAvailable)
   }
 
   fn get_service_unavailable_answer(&self, app_id: Option<&str>, listen_token: &Token) -> Rc<Vec<u8>> {
@@ -1443,7 +1844,10 @@ impl ProxyConfiguration<Session> for Proxy {
         let c = Session::new(ssl, frontend_sock, session_token, Rc::downgrade(&self.pool),
           listener.config.public_address.unwrap_or(listener.config.front),
           listener.config.expect_proxy, listener.config.sticky_name.clone(),
-          timeout, listener.answers.clone(), Token(token.0), delay);
+          listener.config.preserve_proxy_connection, listener.config.ignore_unsupported_expect,
+          listener.config.unexpected_body_policy, Rc::new(listener.config.client_ip.clone()),
+          listener.config.header_limits, listener.config.max_keepalive_requests,
+          listener.config.compression.clone().map(Rc::new), timeout, listener.answers.clone(), Token(token.0), delay, Rc::downgrade(&self.backends.borrow().connection_pool));
 
         Ok((Rc::new(RefCell::new(c)), false))
       } else {
@@ -1462,6 +1866,42 @@ impl ProxyConfiguration<Session> for Proxy {
     self.check_circuit_breaker(session)?;
 
     let app_id = self.app_id_from_request(session)?;
+    session.http_mut().map(|http| http.trace_application(&app_id));
+    self.check_application_enabled(session, &app_id)?;
+    if !self.take_request_slot(session, &app_id)? {
+      session.http().map(|http| http.trace_event(format_args!("waiting for a request slot of application {}", app_id)));
+      return Ok(BackendConnectAction::Queued);
+    }
+
+    if session.connection_attempt == 0 {
+      self.backends.borrow_mut().record_request(&app_id);
+    }
+
+    if self.applications.get(&app_id).map(|app| app.forward_tls_info).unwrap_or(false) {
+      session.http_mut().map(|http| http.add_tls_info_headers());
+    }
+
+    let add_forwarded_headers = self.applications.get(&app_id).map(|app| app.add_forwarded_headers).unwrap_or(true);
+    session.http_mut().map(|http| http.add_forwarded_headers(add_forwarded_headers));
+
+    if let Some(app) = self.applications.get(&app_id) {
+      session.http_mut().map(|http| http.add_header_rules(&app.request_headers, &app.response_headers, &app.request_id_headers));
+      session.http_mut().map(|http| http.set_host_rewriting(app.rewrite_location, app.backend_hosts.clone(), app.rewrite_cookie_domain));
+      session.http_mut().map(|http| http.set_failure_statuses(app.failure_statuses.clone()));
+      session.http_mut().map(|http| http.set_response_timeout(app.response_timeout.map(|t| Duration::milliseconds(i64::from(t)))));
+
+      let request_retries = if app.retry_unanswered_requests {
+        self.backends.borrow().connection_attempts.saturating_sub(1)
+      } else {
+        0
+      };
+      session.http_mut().map(|http| http.set_max_request_retries(request_retries));
+    }
+
+    let max_request_body_size = self.applications.get(&app_id).and_then(|app| app.max_request_body_size);
+    if session.http_mut().map(|http| http.limit_request_body_size(max_request_body_size)).unwrap_or(false) {
+      return Err(ConnectionError::PayloadTooLarge);
+    }
 
     if (session.http().and_then(|h| h.app_id.as_ref()) == Some(&app_id)) && session.back_connected == BackendConnectionStatus::Connected {
       let has_backend = session.backend.as_ref().map(|backend| {
@@ -1476,6 +1916,8 @@ impl ProxyConfiguration<Session> for Proxy {
         //matched on keepalive
         session.metrics.backend_id = session.backend.as_ref().map(|i| i.borrow().backend_id.clone());
         session.metrics.backend_start();
+        session.http().map(|http| http.trace_event(format_args!("reusing the keep-alive connection to backend {:?}",
+          session.metrics.backend_id)));
         return Ok(BackendConnectAction::Reuse);
       } else {
         if let Some(token) = session.back_token() {
@@ -1504,8 +1946,12 @@ impl ProxyConfiguration<Session> for Proxy {
     let front_should_stick = self.applications.get(&app_id).map(|ref app| app.sticky_session).unwrap_or(false);
     let socket = self.backend_from_request(session, &app_id, front_should_stick)?;
 
+    let proxy_protocol_version = self.applications.get(&app_id)
+      .filter(|app| app.proxy_protocol == Some(ProxyProtocolConfig::SendHeader))
+      .map(|app| app.proxy_protocol_version);
     session.http_mut().map(|http| {
       http.app_id = Some(app_id.clone());
+      http.set_proxy_protocol_header(proxy_protocol_version);
     });
 
     if let Err(e) = socket.set_nodelay(true) {
@@ -1559,11 +2005,30 @@ impl ProxyConfiguration<Session> for Proxy {
         self.remove_application(&application);
         ProxyResponse{ id: message.id, status: ProxyResponseStatus::Ok, data: None }
       },
+      ProxyRequestData::PurgeApplication(app_id) => {
+        let count = self.purge_application(&app_id);
+        debug!("{} purge application {}: {} frontends removed", message.id, app_id, count);
+        ProxyResponse{ id: message.id, status: ProxyResponseStatus::Ok, data: None }
+      },
+      ProxyRequestData::EnableApplication(app_id) => {
+        debug!("{} enable application {}", message.id, app_id);
+        self.set_application_enabled(&app_id, true, message.id)
+      },
+      ProxyRequestData::DisableApplication(app_id) => {
+        debug!("{} disable application {}", message.id, app_id);
+        self.set_application_enabled(&app_id, false, message.id)
+      },
+      ProxyRequestData::SetAppTimeout(timeout) => {
+        debug!("{} set the response timeout of application {} to {:?}", message.id, timeout.app_id, timeout.timeout);
+        self.set_app_timeout(&timeout.app_id, timeout.timeout, message.id)
+      },
       ProxyRequestData::AddHttpsFront(front) => {
         //info!("HTTPS\t{} add front {:?}", id, front);
         if let Some(mut listener) = self.listeners.values_mut().find(|l| l.address == front.address) {
-          listener.add_https_front(front);
-          ProxyResponse{ id: message.id, status: ProxyResponseStatus::Ok, data: None }
+          match listener.add_https_front(front) {
+            Ok(_) => ProxyResponse{ id: message.id, status: ProxyResponseStatus::Ok, data: None },
+            Err(err) => ProxyResponse{ id: message.id, status: ProxyResponseStatus::Error(err), data: None }
+          }
         } else {
           panic!("adding front {:?} to unknown listener", front);
         }
@@ -1577,6 +2042,22 @@ impl ProxyConfiguration<Session> for Proxy {
           panic!();
         }
       },
+      ProxyRequestData::SetTrafficSplit(split) => {
+        debug!("{} set traffic split {:?}", message.id, split);
+        for listener in self.listeners.values_mut() {
+          listener.set_traffic_split(&split);
+        }
+        ProxyResponse{ id: message.id, status: ProxyResponseStatus::Ok, data: None }
+      },
+      ProxyRequestData::SetDefaultApp(default) => {
+        debug!("{} set default app {:?}", message.id, default);
+        if let Some(listener) = self.listeners.values_mut().find(|l| l.address == default.front) {
+          listener.config.default_app = default.app_id;
+          ProxyResponse{ id: message.id, status: ProxyResponseStatus::Ok, data: None }
+        } else {
+          ProxyResponse{ id: message.id, status: ProxyResponseStatus::Error(format!("no HTTPS listener at address {:?}", default.front)), data: None }
+        }
+      },
       ProxyRequestData::AddCertificate(add_certificate) => {
         if let Some(mut listener) = self.listeners.values_mut().find(|l| l.address == add_certificate.front) {
           //info!("HTTPS\t{} add certificate: {:?}", id, certificate_and_key);
@@ -1601,14 +2082,37 @@ impl ProxyConfiguration<Session> for Proxy {
         if let Some(mut listener) = self.listeners.values_mut().find(|l| l.address == replace.front) {
           //info!("TLS\t{} replace certificate of fingerprint {:?} with {:?}", id,
           //  replace.old_fingerprint, replace.new_certificate);
-          listener.remove_certificate(replace.old_fingerprint);
-          listener.add_certificate(replace.new_certificate);
           //FIXME: should return an error if certificate still has fronts referencing it
-          ProxyResponse{ id: message.id, status: ProxyResponseStatus::Ok, data: None }
+          if listener.replace_certificate(replace.old_fingerprint, replace.new_certificate) {
+            ProxyResponse{ id: message.id, status: ProxyResponseStatus::Ok, data: None }
+          } else {
+            let err = String::from("could not load the new certificate, the old one is kept");
+            ProxyResponse{ id: message.id, status: ProxyResponseStatus::Error(err), data: None }
+          }
         } else {
           panic!();
         }
       },
+      ProxyRequestData::SetOcspResponse(set_ocsp_response) => {
+        let fingerprint = set_ocsp_response.fingerprint.clone();
+        match self.listeners.values_mut().find(|l| l.address == set_ocsp_response.front) {
+          Some(listener) => if listener.set_ocsp_response(set_ocsp_response) {
+            ProxyResponse{ id: message.id, status: ProxyResponseStatus::Ok, data: None }
+          } else {
+            let err = format!("no certificate with the fingerprint {}", fingerprint);
+            ProxyResponse{ id: message.id, status: ProxyResponseStatus::Error(err), data: None }
+          },
+          None => {
+            let err = format!("no HTTPS listener at {}", set_ocsp_response.front);
+            ProxyResponse{ id: message.id, status: ProxyResponseStatus::Error(err), data: None }
+          },
+        }
+      },
+      ProxyRequestData::AddAcmeChallenge(_) | ProxyRequestData::RemoveAcmeChallenge(_) => {
+        error!("{} ACME TLS-ALPN-01 challenges are only supported by the rustls proxy", message.id);
+        ProxyResponse{ id: message.id, status: ProxyResponseStatus::Error(
+          String::from("ACME TLS-ALPN-01 challenges are not supported by the OpenSSL proxy")), data: None }
+      },
       ProxyRequestData::RemoveListener(remove) => {
         debug!("removing HTTPS listener at address {:?}", remove.front);
         if !self.remove_listener(remove.front) {
@@ -1655,6 +2159,11 @@ impl ProxyConfiguration<Session> for Proxy {
         });
         ProxyResponse{ id: message.id, status: ProxyResponseStatus::Ok, data: None }
       },
+      ProxyRequestData::LogFormat(format) => {
+        debug!("{} changing log format to {:?}", message.id, format);
+        logging::LOGGER.with(|l| l.borrow_mut().set_format(format));
+        ProxyResponse{ id: message.id, status: ProxyResponseStatus::Ok, data: None }
+      },
       ProxyRequestData::Query(Query::Certificates(QueryCertificateType::All)) => {
         let res = self.listeners.iter().map(|(addr, listener)| {
           let mut domains = unwrap_msg!(listener.domains.lock()).to_hashmap();
@@ -1695,6 +2204,28 @@ impl ProxyConfiguration<Session> for Proxy {
     ListenPortState::Available
     //if port == &self.address.port() { ListenPortState::InUse } else { ListenPortState::Available }
   }
+
+  fn listener_timeouts(&self, token: ListenToken) -> ListenerTimeouts {
+    self.listeners.get(&Token(token.0)).map(|listener| listener.config.timeouts).unwrap_or_default()
+  }
+
+  fn listener_rate_limit(&self, token: ListenToken) -> Option<ConnectionRateLimit> {
+    self.listeners.get(&Token(token.0)).and_then(|listener| listener.config.connection_rate_limit)
+  }
+
+  fn listener_expects_proxy_protocol(&self, token: ListenToken) -> bool {
+    self.listeners.get(&Token(token.0)).map(|listener| listener.config.expect_proxy).unwrap_or(false)
+  }
+
+  fn listener_max_sessions(&self, token: ListenToken) -> Option<usize> {
+    self.listeners.get(&Token(token.0)).and_then(|listener| listener.config.max_sessions)
+  }
+
+  fn pause_listener(&mut self, event_loop: &mut Poll, token: ListenToken, paused: bool) {
+    if let Some(sock) = self.listeners.get(&Token(token.0)).and_then(|listener| listener.listener.as_ref()) {
+      ::socket::pause_listener(event_loop, sock, Token(token.0), paused);
+    }
+  }
 }
 
 
@@ -1732,6 +2263,16 @@ fn setup_curves(_: &mut SslContextBuilder) -> Result<(), ErrorStack> {
   Ok(())
 }
 
+/// requires a client certificate signed by one of the listener's client CA certificates
+fn setup_client_verification(ctx: &mut SslContextBuilder, client_ca: &str) -> Result<(), ErrorStack> {
+  for certificate in X509::stack_from_pem(client_ca.as_bytes())? {
+    ctx.add_client_ca(&certificate)?;
+    ctx.cert_store_mut().add_cert(certificate)?;
+  }
+  ctx.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+  Ok(())
+}
+
 fn setup_dh(ctx: &mut SslContextBuilder) -> Result<(), ErrorStack> {
   let dh = Dh::params_from_pem(
 b"
@@ -1791,7 +2332,7 @@ pub fn start(config: HttpsListener, channel: ProxyChannel, max_buffers: usize, b
       let mut server_config: server::ServerConfig = Default::default();
       server_config.max_connections = max_buffers;
       let mut server  = Server::new(event_loop, channel, ScmSocket::new(scm_server.as_raw_fd()),
-        sessions, pool, backends, None, Some(HttpsProvider::Openssl(configuration)), None, server_config, None);
+        sessions, pool, None, backends, None, Some(HttpsProvider::Openssl(configuration)), None, server_config, None);
 
       info!("starting event loop");
       server.run();
@@ -1842,18 +2383,30 @@ mod tests {
     let mut fronts = TrieNode::root();
     fronts.domain_insert(Vec::from(&b"lolcatho.st"[..]), vec![
       TlsApp {
-        app_id: app_id1, hostname: "lolcatho.st".to_owned(), path_begin: uri1,
+        app_id: app_id1, hostname: "lolcatho.st".to_owned(), path_begin: uri1, trailing_slash: TrailingSlash::Strict,
+        method: None,
+        header_match: None,
+        split: None,
       },
       TlsApp {
-        app_id: app_id2, hostname: "lolcatho.st".to_owned(), path_begin: uri2,
+        app_id: app_id2, hostname: "lolcatho.st".to_owned(), path_begin: uri2, trailing_slash: TrailingSlash::Strict,
+        method: None,
+        header_match: None,
+        split: None,
       },
       TlsApp {
-        app_id: app_id3, hostname: "lolcatho.st".to_owned(), path_begin: uri3,
+        app_id: app_id3, hostname: "lolcatho.st".to_owned(), path_begin: uri3, trailing_slash: TrailingSlash::Strict,
+        method: None,
+        header_match: None,
+        split: None,
       }
     ]);
     fronts.domain_insert(Vec::from(&b"other.domain"[..]), vec![
       TlsApp {
-        app_id: "app_1".to_owned(), hostname: "other.domain".to_owned(), path_begin: "/test".to_owned(),
+        app_id: "app_1".to_owned(), hostname: "other.domain".to_owned(), path_begin: "/test".to_owned(), trailing_slash: TrailingSlash::Strict,
+        method: None,
+        header_match: None,
+        split: None,
       },
     ]);
 
@@ -1872,7 +2425,7 @@ mod tests {
       domains:   rc_domains,
       default_context: context.build(),
       contexts: rc_ctx,
-      answers:   Rc::new(RefCell::new(HttpAnswers::new("HTTP/1.1 404 Not Found\r\n\r\n", "HTTP/1.1 503 your application is in deployment\r\n\r\n"))),
+      answers:   Rc::new(RefCell::new(HttpAnswers::new("HTTP/1.1 404 Not Found\r\n\r\n", "HTTP/1.1 503 your application is in deployment\r\n\r\n", None))),
       config: Default::default(),
       ssl_options: ssl::SslOptions::CIPHER_SERVER_PREFERENCE | ssl::SslOptions::NO_COMPRESSION | ssl::SslOptions::NO_TICKET |
         ssl::SslOptions::NO_SSLV2 | ssl::SslOptions::NO_SSLV3 | ssl::SslOptions::NO_TLSV1 | ssl::SslOptions::NO_TLSV1_1,
@@ -1882,19 +2435,19 @@ mod tests {
 
 
     println!("TEST {}", line!());
-    let frontend1 = listener.frontend_from_request("lolcatho.st", "/");
+    let frontend1 = listener.frontend_from_request("lolcatho.st", None, "/", |_| None);
     assert_eq!(frontend1.expect("should find a frontend").app_id, "app_1");
     println!("TEST {}", line!());
-    let frontend2 = listener.frontend_from_request("lolcatho.st", "/test");
+    let frontend2 = listener.frontend_from_request("lolcatho.st", None, "/test", |_| None);
     assert_eq!(frontend2.expect("should find a frontend").app_id, "app_1");
     println!("TEST {}", line!());
-    let frontend3 = listener.frontend_from_request("lolcatho.st", "/yolo/test");
+    let frontend3 = listener.frontend_from_request("lolcatho.st", None, "/yolo/test", |_| None);
     assert_eq!(frontend3.expect("should find a frontend").app_id, "app_2");
     println!("TEST {}", line!());
-    let frontend4 = listener.frontend_from_request("lolcatho.st", "/yolo/swag");
+    let frontend4 = listener.frontend_from_request("lolcatho.st", None, "/yolo/swag", |_| None);
     assert_eq!(frontend4.expect("should find a frontend").app_id, "app_3");
     println!("TEST {}", line!());
-    let frontend5 = listener.frontend_from_request("domain", "/");
+    let frontend5 = listener.frontend_from_request("domain", None, "/", |_| None);
     assert_eq!(frontend5, None);
    // assert!(false);
   }
@@ -0,0 +1,302 @@
+//! ACME TLS-ALPN-01 challenges (RFC 8737)
+//!
+//! The ACME server validates a domain by opening a TLS connection with the
+//! `acme-tls/1` ALPN protocol and the domain as SNI. It expects a self signed
+//! certificate containing the challenge, generated by the ACME client, and
+//! closes the connection once the handshake is done.
+//!
+//! The ACME client installs that certificate with `AddAcmeChallenge`, and
+//! removes it once the domain is validated. The issued certificate is then
+//! added like any other certificate.
+//!
+//! rustls does not give the ALPN protocols to the certificate resolver, so
+//! the ClientHello is inspected before the handshake starts, and validation
+//! handshakes are handled by a separate configuration that only knows about
+//! the challenge certificates.
+use std::sync::{Arc,Mutex};
+use std::collections::HashMap;
+use webpki;
+use rustls::{ResolvesServerCert, ServerConfig, ServerSession, NoClientAuth, SignatureScheme};
+use rustls::sign::CertifiedKey;
+
+use sozu_command::proxy::{AcmeChallenge, RemoveAcmeChallenge};
+
+use super::resolver::generate_certified_key;
+
+pub const ACME_TLS_ALPN_PROTOCOL: &[u8] = b"acme-tls/1";
+
+struct ChallengeResolver(Mutex<HashMap<String, CertifiedKey>>);
+
+impl ResolvesServerCert for ChallengeResolver {
+  fn resolve(
+        &self,
+        server_name: Option<webpki::DNSNameRef>,
+        _sigschemes: &[SignatureScheme]
+    ) -> Option<CertifiedKey> {
+    let name: &str = server_name?.into();
+
+    if let Ok(challenges) = self.0.lock() {
+      if let Some(cert) = challenges.get(&name.to_lowercase()) {
+        return Some(cert.clone());
+      }
+    }
+
+    error!("no ACME challenge for server name '{}'", name);
+    None
+  }
+}
+
+pub struct AcmeChallenges {
+  resolver: Arc<ChallengeResolver>,
+  config:   Arc<ServerConfig>,
+}
+
+impl AcmeChallenges {
+  pub fn new() -> AcmeChallenges {
+    let resolver = Arc::new(ChallengeResolver(Mutex::new(HashMap::new())));
+
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config.cert_resolver = resolver.clone();
+    config.alpn_protocols = vec![ACME_TLS_ALPN_PROTOCOL.to_vec()];
+
+    AcmeChallenges {
+      resolver,
+      config: Arc::new(config),
+    }
+  }
+
+  pub fn add_challenge(&self, challenge: AcmeChallenge) -> bool {
+    match generate_certified_key(challenge.certificate) {
+      Some(cert) => {
+        info!("adding an ACME challenge for {}", challenge.domain);
+        if let Ok(mut challenges) = self.resolver.0.lock() {
+          challenges.insert(challenge.domain.to_lowercase(), cert);
+          true
+        } else {
+          false
+        }
+      },
+      None => {
+        error!("could not load the ACME challenge certificate for {}", challenge.domain);
+        false
+      }
+    }
+  }
+
+  pub fn remove_challenge(&self, remove: RemoveAcmeChallenge) -> bool {
+    info!("removing the ACME challenge for {}", remove.domain);
+    self.resolver.0.lock().ok()
+      .and_then(|mut challenges| challenges.remove(&remove.domain.to_lowercase()))
+      .is_some()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.resolver.0.lock().map(|challenges| challenges.is_empty()).unwrap_or(true)
+  }
+
+  fn has_challenge(&self, domain: &str) -> bool {
+    self.resolver.0.lock().map(|challenges| challenges.contains_key(&domain.to_lowercase())).unwrap_or(false)
+  }
+
+  /// returns a session presenting the challenge certificate if the data
+  /// holds the ClientHello of a TLS-ALPN-01 validation for a known domain
+  pub fn session_for(&self, client_hello: &[u8]) -> Option<ServerSession> {
+    let (server_name, protocols) = parse_client_hello(client_hello)?;
+
+    // the ACME server must only offer the acme-tls/1 protocol
+    if protocols.len() != 1 || &protocols[0][..] != ACME_TLS_ALPN_PROTOCOL {
+      return None;
+    }
+
+    match server_name {
+      Some(ref name) if self.has_challenge(name) => {
+        Some(ServerSession::new(&self.config))
+      },
+      _ => {
+        error!("ACME TLS-ALPN-01 validation for unknown domain {:?}", server_name);
+        None
+      }
+    }
+  }
+}
+
+struct Reader<'a> {
+  data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+  fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+    if self.data.len() < len {
+      return None;
+    }
+
+    let (taken, rest) = self.data.split_at(len);
+    self.data = rest;
+    Some(taken)
+  }
+
+  fn u8(&mut self) -> Option<usize> {
+    self.take(1).map(|b| b[0] as usize)
+  }
+
+  fn u16(&mut self) -> Option<usize> {
+    self.take(2).map(|b| ((b[0] as usize) << 8) | b[1] as usize)
+  }
+
+  fn u24(&mut self) -> Option<usize> {
+    self.take(3).map(|b| ((b[0] as usize) << 16) | ((b[1] as usize) << 8) | b[2] as usize)
+  }
+
+  /// reads a vector prefixed by its length, on `size` bytes
+  fn vec(&mut self, size: usize) -> Option<Reader<'a>> {
+    let len = match size {
+      1 => self.u8()?,
+      2 => self.u16()?,
+      _ => self.u24()?,
+    };
+    self.take(len).map(|data| Reader { data })
+  }
+}
+
+/// extracts the server name and the ALPN protocols from a TLS record
+/// holding a complete ClientHello
+pub fn parse_client_hello(data: &[u8]) -> Option<(Option<String>, Vec<Vec<u8>>)> {
+  let mut record = Reader { data };
+  // handshake content type, then the record version
+  if record.u8()? != 0x16 {
+    return None;
+  }
+  record.take(2)?;
+
+  let mut handshake = record.vec(2)?;
+  // ClientHello message type
+  if handshake.u8()? != 0x01 {
+    return None;
+  }
+
+  let mut hello = handshake.vec(3)?;
+  // client version and random
+  hello.take(2 + 32)?;
+  // session id, cipher suites and compression methods
+  hello.vec(1)?;
+  hello.vec(2)?;
+  hello.vec(1)?;
+
+  let mut server_name = None;
+  let mut protocols = Vec::new();
+
+  let mut extensions = match hello.vec(2) {
+    Some(extensions) => extensions,
+    None             => return Some((server_name, protocols)),
+  };
+
+  while !extensions.data.is_empty() {
+    let extension_type = extensions.u16()?;
+    let mut extension = extensions.vec(2)?;
+
+    match extension_type {
+      // server_name
+      0x0000 => {
+        let mut names = extension.vec(2)?;
+        while !names.data.is_empty() {
+          let name_type = names.u8()?;
+          let name = names.vec(2)?;
+          if name_type == 0 {
+            server_name = ::std::str::from_utf8(name.data).ok().map(String::from);
+          }
+        }
+      },
+      // application_layer_protocol_negotiation
+      0x0010 => {
+        let mut list = extension.vec(2)?;
+        while !list.data.is_empty() {
+          protocols.push(list.vec(1)?.data.to_vec());
+        }
+      },
+      _ => {},
+    }
+  }
+
+  Some((server_name, protocols))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::io::{BufReader,Cursor};
+  use rustls::{ClientConfig, ClientSession, ProtocolVersion, Session};
+  use rustls::internal::pemfile;
+  use sozu_command::proxy::CertificateAndKey;
+
+  fn client_hello(protocols: Vec<Vec<u8>>) -> Vec<u8> {
+    let mut config = ClientConfig::new();
+    config.alpn_protocols = protocols;
+    // the certificate is sent in clear in TLS 1.2, so we can verify it
+    config.versions = vec![ProtocolVersion::TLSv1_2];
+    let dns_name = webpki::DNSNameRef::try_from_ascii_str("lolcatho.st").unwrap();
+    let mut client = ClientSession::new(&Arc::new(config), dns_name);
+
+    let mut hello = Vec::new();
+    client.write_tls(&mut hello).unwrap();
+    hello
+  }
+
+  fn challenge(domain: &str) -> AcmeChallenge {
+    AcmeChallenge {
+      front: "127.0.0.1:8443".parse().unwrap(),
+      domain: String::from(domain),
+      certificate: CertificateAndKey {
+        certificate: String::from(include_str!("../../assets/certificate.pem")),
+        certificate_chain: vec![],
+        key: String::from(include_str!("../../assets/key.pem")),
+      },
+    }
+  }
+
+  #[test]
+  fn parse_hello() {
+    let hello = client_hello(vec![ACME_TLS_ALPN_PROTOCOL.to_vec(), b"http/1.1".to_vec()]);
+    assert_eq!(parse_client_hello(&hello), Some((Some(String::from("lolcatho.st")),
+      vec![ACME_TLS_ALPN_PROTOCOL.to_vec(), b"http/1.1".to_vec()])));
+
+    assert_eq!(parse_client_hello(&client_hello(vec![])), Some((Some(String::from("lolcatho.st")), vec![])));
+    assert_eq!(parse_client_hello(&hello[..hello.len() - 1]), None);
+    assert_eq!(parse_client_hello(b"GET / HTTP/1.1\r\n\r\n"), None);
+  }
+
+  #[test]
+  fn tls_alpn_validation_handshake() {
+    let challenges = AcmeChallenges::new();
+    let hello = client_hello(vec![ACME_TLS_ALPN_PROTOCOL.to_vec()]);
+
+    // no challenge for this domain yet
+    assert!(challenges.is_empty());
+    assert!(challenges.session_for(&hello).is_none());
+
+    assert!(challenges.add_challenge(challenge("LOLCATHO.st")));
+    // regular clients are not affected
+    assert!(challenges.session_for(&client_hello(vec![])).is_none());
+    assert!(challenges.session_for(&client_hello(vec![ACME_TLS_ALPN_PROTOCOL.to_vec(), b"h2".to_vec()])).is_none());
+
+    let mut session = challenges.session_for(&hello).expect("should answer the validation");
+    session.read_tls(&mut Cursor::new(&hello)).unwrap();
+    session.process_new_packets().unwrap();
+    assert_eq!(session.get_alpn_protocol(), Some(ACME_TLS_ALPN_PROTOCOL));
+
+    let mut server_flight = Vec::new();
+    while session.wants_write() {
+      session.write_tls(&mut server_flight).unwrap();
+    }
+
+    // the server's flight contains the challenge certificate
+    let mut pem = BufReader::new(&include_bytes!("../../assets/certificate.pem")[..]);
+    let der = pemfile::certs(&mut pem).unwrap().remove(0).0;
+    assert!(server_flight.windows(der.len()).any(|w| w == &der[..]));
+
+    assert!(challenges.remove_challenge(RemoveAcmeChallenge {
+      front: "127.0.0.1:8443".parse().unwrap(),
+      domain: String::from("lolcatho.st"),
+    }));
+    assert!(challenges.session_for(&hello).is_none());
+  }
+}
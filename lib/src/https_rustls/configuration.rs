@@ -11,7 +11,7 @@ use std::collections::HashMap;
 use slab::Slab;
 use std::net::SocketAddr;
 use std::str::from_utf8_unchecked;
-use rustls::{ServerConfig, ServerSession, NoClientAuth, ProtocolVersion,
+use rustls::{ServerConfig, ServerSession, NoClientAuth, AllowAnyAuthenticatedClient, RootCertStore, ProtocolVersion,
   ALL_CIPHERSUITES};
 use mio_extras::timer::Timeout;
 use time::Duration;
@@ -19,25 +19,29 @@ use time::Duration;
 use sozu_command::scm_socket::ScmSocket;
 use sozu_command::proxy::{Application,
   ProxyRequestData,HttpFront,HttpsListener,ProxyRequest,ProxyResponse,
-  ProxyResponseStatus,AddCertificate,RemoveCertificate,ReplaceCertificate,
+  ProxyResponseStatus,AddCertificate,RemoveCertificate,ReplaceCertificate,SetOcspResponse,CertFingerprint,TrailingSlash,HeaderMatch,
   TlsVersion,ProxyResponseData,Query, QueryCertificateType,QueryAnswer,
-  QueryAnswerCertificate};
+  QueryAnswerCertificate,ListenerTimeouts,ConnectionRateLimit,ListenerRouteMatch,TrafficSplit,TrafficSplitPercentage};
 use sozu_command::logging;
+use sozu_command::config::ProxyProtocolConfig;
 use sozu_command::buffer::Buffer;
 
-use protocol::http::{parser::{RRequestLine,hostname_and_port}, answers::{DefaultAnswers, CustomAnswers, HttpAnswers}};
+use protocol::http::{parser::{RRequestLine,hostname_and_port,hostname_without_port,header_value,request_cookie}, answers::{DefaultAnswers, CustomAnswers, HttpAnswers, connection_error_status}};
 use pool::Pool;
 use {AppId,ConnectionError,Protocol,
-  ProxySession,ProxyConfiguration,AcceptError,BackendConnectAction,BackendConnectionStatus};
+  ProxySession,ProxyConfiguration,AcceptError,BackendConnectAction,BackendConnectionStatus,ConnectionGuard};
 use backends::BackendMap;
-use server::{Server,ProxyChannel,ListenToken,ListenPortState,SessionToken,ListenSession,CONN_RETRIES};
+use request_limit::{self, Admission};
+use traffic_split;
+use server::{Server,ProxyChannel,ListenToken,ListenPortState,SessionToken,ListenSession};
 use socket::server_bind;
 use trie::*;
-use protocol::StickySession;
-use protocol::http::DefaultAnswerStatus;
+use protocol::{h2,StickySession};
+use protocol::http::{DefaultAnswerStatus, FrontendRule, frontend_match, tls_routing_host};
 use util::UnwrapLog;
 
 use super::resolver::CertificateResolverWrapper;
+use super::acme::AcmeChallenges;
 use super::session::Session;
 
 #[derive(Debug,Clone,PartialEq,Eq)]
@@ -45,6 +49,10 @@ pub struct TlsApp {
   pub app_id:           String,
   pub hostname:         String,
   pub path_begin:       String,
+  pub trailing_slash:   TrailingSlash,
+  pub method:           Option<String>,
+  pub header_match:     Option<HeaderMatch>,
+  pub split:            Option<TrafficSplit>,
 }
 
 pub type HostName  = String;
@@ -58,6 +66,7 @@ pub struct Listener {
   config:     HttpsListener,
   ssl_config: Arc<ServerConfig>,
   resolver:   Arc<CertificateResolverWrapper>,
+  acme_challenges: Rc<AcmeChallenges>,
   pub token:  Token,
   active:     bool,
 }
@@ -65,7 +74,23 @@ pub struct Listener {
 impl Listener {
   pub fn new(config: HttpsListener, token: Token) -> Listener {
 
-    let mut server_config = ServerConfig::new(NoClientAuth::new());
+    let client_cert_verifier = match config.client_ca {
+      None => NoClientAuth::new(),
+      Some(ref client_ca) => {
+        let mut roots = RootCertStore::empty();
+        match roots.add_pem_file(&mut client_ca.as_bytes()) {
+          Ok((_, 0)) => {},
+          Ok((_, invalid)) => error!("{} invalid client CA certificates for the listener on {}", invalid, config.front),
+          Err(()) => error!("could not parse the client CA certificates for the listener on {}", config.front),
+        }
+        if roots.is_empty() {
+          error!("no client CA certificate for the listener on {}, all client certificates will be refused", config.front);
+        }
+        AllowAnyAuthenticatedClient::new(roots)
+      },
+    };
+
+    let mut server_config = ServerConfig::new(client_cert_verifier);
     server_config.versions = config.versions.iter().map(|version| {
       match version {
         TlsVersion::SSLv2   => ProtocolVersion::SSLv2,
@@ -77,6 +102,8 @@ impl Listener {
       }
     }).collect();
 
+    server_config.alpn_protocols = h2::alpn_protocols(cfg!(feature = "h2"));
+
     let resolver = Arc::new(CertificateResolverWrapper::new());
     server_config.cert_resolver = resolver.clone();
 
@@ -101,14 +128,18 @@ impl Listener {
       server_config.ciphersuites = ciphers;
     }
 
+    let mut answers = HttpAnswers::new(&config.answer_404, &config.answer_503, config.retry_after);
+    answers.set_error_pages(&config.error_pages);
+
     Listener {
       address:    config.front.clone(),
       fronts:     TrieNode::root(),
-      answers:    Rc::new(RefCell::new(HttpAnswers::new(&config.answer_404, &config.answer_503))),
+      answers:    Rc::new(RefCell::new(answers)),
       ssl_config: Arc::new(server_config),
       listener: None,
       config,
       resolver,
+      acme_challenges: Rc::new(AcmeChallenges::new()),
       token,
       active: false,
     }
@@ -119,7 +150,7 @@ impl Listener {
       return Some(self.token);
     }
 
-    let listener = tcp_listener.or_else(|| server_bind(&self.config.front).map_err(|e| {
+    let listener = tcp_listener.or_else(|| server_bind(&self.config.front, self.config.interface.as_ref().map(|s| s.as_str())).map_err(|e| {
       error!("could not create listener {:?}: {:?}", self.config.front, e);
     }).ok());
 
@@ -137,24 +168,51 @@ impl Listener {
     Some(self.token)
   }
 
-  pub fn add_https_front(&mut self, tls_front: HttpFront) -> bool {
+  pub fn add_https_front(&mut self, tls_front: HttpFront) -> Result<(), String> {
     //FIXME: should clone he hostname then do a into() here
     let app = TlsApp {
       app_id:           tls_front.app_id.clone(),
       hostname:         tls_front.hostname.clone(),
       path_begin:       tls_front.path_begin.clone(),
+      trailing_slash:   tls_front.trailing_slash,
+      method:           tls_front.method.clone(),
+      header_match:     tls_front.header_match.clone(),
+      split:            tls_front.split.clone(),
     };
 
     if let Some((_,fronts)) = self.fronts.domain_lookup_mut(&tls_front.hostname.as_bytes(), false) {
+        let method = app.method.as_ref().map(|m| m.to_uppercase());
+        if let Some(other) = fronts.iter().find(|f| f.app_id != app.app_id && f.path_begin == app.path_begin
+          && f.header_match == app.header_match && f.method.as_ref().map(|m| m.to_uppercase()) == method) {
+          return Err(format!("the frontend {}{} is already used by the application {}",
+            app.hostname, app.path_begin, other.app_id));
+        }
         if ! fronts.contains(&app) {
           fronts.push(app.clone());
         }
     }
     if self.fronts.domain_lookup(&tls_front.hostname.as_bytes(), false).is_none() {
-      self.fronts.domain_insert(tls_front.hostname.into_bytes(), vec![app]);
+      let hostname = tls_front.hostname;
+      if self.fronts.domain_insert(hostname.clone().into_bytes(), vec![app]) == InsertResult::Failed {
+        return Err(format!("invalid hostname {}: a wildcard can only replace the leftmost label", hostname));
+      }
     }
+    Ok(())
+  }
 
-    true
+  /// changes the percentage of the traffic split of the application's frontends
+  /// on this hostname. Returns false if there is none
+  pub fn set_traffic_split(&mut self, split: &TrafficSplitPercentage) -> bool {
+    let mut found = false;
+    if let Some((_, fronts)) = self.fronts.domain_lookup_mut(split.hostname.as_bytes(), false) {
+      for front in fronts.iter_mut().filter(|front| front.app_id == split.app_id) {
+        if let Some(ref mut front_split) = front.split {
+          front_split.percentage = split.percentage;
+          found = true;
+        }
+      }
+    }
+    found
   }
 
   pub fn remove_https_front(&mut self, front: HttpFront) {
@@ -166,8 +224,9 @@ impl Listener {
         if let Some(pos) = fronts.iter()
           .position(|f| {
             f.app_id == front.app_id &&
-            f.hostname == front.hostname &&
-            f.path_begin == front.path_begin
+            f.hostname.eq_ignore_ascii_case(&front.hostname) &&
+            f.path_begin == front.path_begin &&
+            f.method == front.method
           }) {
 
           let front = fronts.remove(pos);
@@ -192,23 +251,14 @@ impl Listener {
     (*self.resolver).remove_certificate(remove_certificate)
   }
 
-  pub fn replace_certificate(&mut self, replace_certificate: ReplaceCertificate) {
+  pub fn replace_certificate(&mut self, replace_certificate: ReplaceCertificate) -> Option<CertFingerprint> {
     debug!("replacing certificate {:?}", replace_certificate);
-    let ReplaceCertificate { front, new_certificate, old_fingerprint, old_names, new_names } = replace_certificate;
-    let remove = RemoveCertificate {
-      front,
-      fingerprint: old_fingerprint,
-      names: old_names,
-    };
-    let add = AddCertificate {
-      front,
-      certificate: new_certificate,
-      names: new_names,
-    };
+    (*self.resolver).replace_certificate(replace_certificate)
+  }
 
-    //FIXME: handle results
-    (*self.resolver).remove_certificate(remove);
-    (*self.resolver).add_certificate(add);
+  pub fn set_ocsp_response(&mut self, set_ocsp_response: SetOcspResponse) -> bool {
+    debug!("setting the OCSP response of the certificate {}", set_ocsp_response.fingerprint);
+    (*self.resolver).set_ocsp_response(&set_ocsp_response.fingerprint, set_ocsp_response.ocsp_response)
   }
 
   fn accept(&mut self, token: ListenToken) -> Result<TcpStream, AcceptError> {
@@ -229,38 +279,19 @@ impl Listener {
   }
 
   // ToDo factor out with http.rs
-  pub fn frontend_from_request(&self, host: &str, uri: &str) -> Option<&TlsApp> {
-    let host: &str = if let Ok((i, (hostname, _))) = hostname_and_port(host.as_bytes()) {
-      if i != &b""[..] {
-        error!("invalid remaining chars after hostname");
+  pub fn frontend_from_request<H>(&self, host: &str, method: Option<&str>, uri: &str, header: H) -> Option<&TlsApp>
+    where H: Fn(&str) -> Option<String> {
+    // the fronts are stored without port
+    let host = match hostname_without_port(host) {
+      Some(hostname) => hostname,
+      None => {
+        error!("hostname parsing failed for: '{}'", host);
         return None;
       }
-
-      // it is alright to call from_utf8_unchecked,
-      // we already verified that there are only ascii
-      // chars in there
-      unsafe { from_utf8_unchecked(hostname) }
-    } else {
-      error!("hostname parsing failed for: '{}'", host);
-      return None;
     };
 
     if let Some((_,http_fronts)) = self.fronts.domain_lookup(host.as_bytes(), true) {
-      let matching_fronts = http_fronts.iter().filter(|f| uri.starts_with(&f.path_begin)); // ToDo match on uri
-      let mut front = None;
-
-      for f in matching_fronts {
-        if front.is_none() {
-          front = Some(f);
-        }
-
-        if let Some(ff) = front {
-          if f.path_begin.len() > ff.path_begin.len() {
-            front = Some(f)
-          }
-        }
-      }
-      front
+      frontend_match(http_fronts, method, uri, header, |f| FrontendRule { method: f.method.as_ref().map(|m| m.as_str()), path_begin: &f.path_begin, trailing_slash: f.trailing_slash, header_match: f.header_match.as_ref() })
     } else {
       None
     }
@@ -323,6 +354,49 @@ impl Proxy {
     })
   }
 
+  /// lists the frontends of every listener, sorted by listener address then hostname
+  pub fn routes(&self) -> Vec<HttpFront> {
+    let mut listeners: Vec<&Listener> = self.listeners.values().collect();
+    listeners.sort_by_key(|l| l.address);
+
+    listeners.iter().flat_map(|l| {
+      l.fronts.entries().into_iter().flat_map(move |&(_, ref apps)| apps.iter().map(move |app| HttpFront {
+        app_id:     app.app_id.clone(),
+        address:    l.address,
+        hostname:   app.hostname.clone(),
+        path_begin: app.path_begin.clone(),
+        trailing_slash: app.trailing_slash,
+        method:     app.method.clone(),
+        header_match: app.header_match.clone(),
+        split:      app.split.clone(),
+      }))
+    }).collect()
+  }
+
+  /// runs the frontend lookup of every listener for this request
+  pub fn route_match(&self, hostname: &str, method: Option<&str>, path: &str) -> Vec<ListenerRouteMatch> {
+    let mut listeners: Vec<&Listener> = self.listeners.values().collect();
+    listeners.sort_by_key(|l| l.address);
+
+    listeners.iter().map(|l| {
+      let frontend = l.frontend_from_request(hostname, method, path, |_| None).map(|app| HttpFront {
+        app_id:     app.app_id.clone(),
+        address:    l.address,
+        hostname:   app.hostname.clone(),
+        path_begin: app.path_begin.clone(),
+        trailing_slash: app.trailing_slash,
+        method:     app.method.clone(),
+        header_match: app.header_match.clone(),
+        split:      app.split.clone(),
+      });
+
+      ListenerRouteMatch {
+        listener: l.address,
+        route:    self.backends.borrow().route_match(frontend),
+      }
+    }).collect()
+  }
+
   pub fn add_application(&mut self, mut application: Application) {
     if let Some(answer_503) = application.answer_503.take() {
       for l in self.listeners.values_mut() {
@@ -341,6 +415,42 @@ impl Proxy {
     }
   }
 
+  /// removes the application with its frontends on every listener,
+  /// returns the number of frontends removed
+  pub fn purge_application(&mut self, app_id: &str) -> usize {
+    let fronts: Vec<HttpFront> = self.routes().into_iter().filter(|front| front.app_id == app_id).collect();
+    let count = fronts.len();
+    for front in fronts {
+      if let Some(listener) = self.listeners.values_mut().find(|l| l.address == front.address) {
+        listener.remove_https_front(front);
+      }
+    }
+
+    self.remove_application(app_id);
+    count
+  }
+
+  pub fn set_application_enabled(&mut self, app_id: &str, enabled: bool, id: String) -> ProxyResponse {
+    match self.applications.get_mut(app_id) {
+      Some(app) => {
+        app.enabled = enabled;
+        ProxyResponse{ id, status: ProxyResponseStatus::Ok, data: None }
+      },
+      None => ProxyResponse{ id, status: ProxyResponseStatus::Error(format!("no such application {}", app_id)), data: None },
+    }
+  }
+
+  /// the sessions use the new timeout from their next request
+  pub fn set_app_timeout(&mut self, app_id: &str, timeout: Option<u32>, id: String) -> ProxyResponse {
+    match self.applications.get_mut(app_id) {
+      Some(app) => {
+        app.response_timeout = timeout;
+        ProxyResponse{ id, status: ProxyResponseStatus::Ok, data: None }
+      },
+      None => ProxyResponse{ id, status: ProxyResponseStatus::Error(format!("no such application {}", app_id)), data: None },
+    }
+  }
+
   pub fn backend_from_request(&mut self, session: &mut Session, app_id: &str,
   front_should_stick: bool) -> Result<TcpStream,ConnectionError> {
     session.http_mut().map(|h| h.set_app_id(String::from(app_id)));
@@ -350,39 +460,44 @@ impl Proxy {
       .and_then(|r| r.get_sticky_session());
 
     let res = match (front_should_stick, sticky_session) {
-      (true, Some(sticky_session)) => {
+      // once the sticky backend failed, the request is retried on another one
+      (true, Some(sticky_session)) if session.tried_backends.is_empty() => {
         self.backends.borrow_mut().backend_from_sticky_session(app_id, &sticky_session)
           .map_err(|e| {
             debug!("Couldn't find a backend corresponding to sticky_session {} for app {}", sticky_session, app_id);
             e
           })
       },
-      _ => self.backends.borrow_mut().backend_from_app_id(app_id),
+      _ => self.backends.borrow_mut().untried_backend_from_app_id(app_id, &session.tried_backends),
     };
 
     match res {
       Err(e) => {
+        session.http().map(|http| http.trace_event(format_args!("no backend available for application {}: {:?}", app_id, e)));
         let answer = self.get_service_unavailable_answer(Some(app_id), &session.listen_token);
         session.set_answer(DefaultAnswerStatus::Answer503, answer);
         Err(e)
       },
       Ok((backend, conn))  => {
+        session.http().map(|http| http.trace_event(format_args!("selected backend {} at {}, sticky session: {:?}, tried backends: {:?}",
+          backend.borrow().backend_id, backend.borrow().address, sticky_session, session.tried_backends)));
         if front_should_stick {
           let sticky_name = self.listeners[&session.listen_token].config.sticky_name.clone();
           session.http_mut().map(|http| {
             http.sticky_session =
-              Some(StickySession::new(backend.borrow().sticky_id.clone().unwrap_or(backend.borrow().backend_id.clone())));
+              Some(StickySession::new(backend.borrow().sticky_session_id().to_string()));
             http.sticky_name = sticky_name;
           });
         }
         session.metrics.backend_id = Some(backend.borrow().backend_id.clone());
         session.metrics.backend_start();
+        session.tried_backends.push(backend.borrow().backend_id.clone());
 
         session.http_mut().map(|http| {
-          http.set_backend_id(backend.borrow().backend_id.clone());
+          http.set_backend(backend.clone());
         });
 
-        session.backend = Some(backend);
+        session.backend = Some(ConnectionGuard::new(backend));
 
         Ok(conn)
       }
@@ -392,9 +507,12 @@ impl Proxy {
   fn app_id_from_request(&mut self, session: &mut Session) -> Result<String, ConnectionError> {
     let listen_token = session.listen_token;
 
-    let h = session.http().and_then(|h| h.request.as_ref()).and_then(|r| r.get_host()).ok_or(ConnectionError::NoHostGiven)?;
+    let h = match session.http().and_then(|h| h.request.as_ref()).and_then(|r| r.get_host()) {
+      Some(h) => h,
+      None    => return Err(self.answer_connection_error(session, ConnectionError::NoHostGiven)),
+    };
 
-    let host: &str = if let Ok((i, (hostname, port))) = hostname_and_port(h.as_bytes()) {
+    let host: String = if let Ok((i, (hostname, port))) = hostname_and_port(h.as_bytes()) {
       if i != &b""[..] {
         error!("invalid remaining chars after hostname");
         let answer = self.listeners[&listen_token].answers.borrow().get(DefaultAnswerStatus::Answer400, None);
@@ -407,22 +525,19 @@ impl Proxy {
       // chars in there
       let hostname_str =  unsafe { from_utf8_unchecked(hostname) };
 
-      //FIXME: what if we don't use SNI?
       let servername: Option<String> = session.http()
         .and_then(|h| h.frontend.session.get_sni_hostname()).map(|s| s.to_string());
-      if servername.as_ref().map(|s| s.as_str()) != Some(hostname_str) {
-        error!("TLS SNI hostname '{:?}' and Host header '{}' don't match", servername, hostname_str);
-        let answer = self.listeners[&listen_token].answers.borrow().get(DefaultAnswerStatus::Answer404, None);
-        unwrap_msg!(session.http_mut()).set_answer(DefaultAnswerStatus::Answer404, answer);
-        return Err(ConnectionError::HostNotFound);
-      }
+      let reject_sni_mismatch = self.listeners[&listen_token].config.reject_sni_mismatch;
 
       //FIXME: we should check that the port is right too
-
-      if port == Some(&b"443"[..]) {
-        hostname_str
-      } else {
-        &h
+      match tls_routing_host(&h, hostname_str, port, servername.as_ref().map(|s| s.as_str()), reject_sni_mismatch) {
+        Some(host) => host,
+        None => {
+          error!("TLS SNI hostname '{:?}' and Host header '{}' don't match", servername, hostname_str);
+          let answer = self.listeners[&listen_token].answers.borrow().get(DefaultAnswerStatus::Answer404, None);
+          unwrap_msg!(session.http_mut()).set_answer(DefaultAnswerStatus::Answer404, answer);
+          return Err(ConnectionError::HostNotFound);
+        },
       }
     } else {
       error!("hostname parsing failed");
@@ -431,12 +546,19 @@ impl Proxy {
       return Err(ConnectionError::InvalidHost);
     };
 
-    let rl:&RRequestLine = session.http()
-      .and_then(|h| h.request.as_ref()).and_then(|r| r.get_request_line())
-      .ok_or(ConnectionError::NoRequestLineGiven)?;
+    let rl:&RRequestLine = match session.http().and_then(|h| h.request.as_ref()).and_then(|r| r.get_request_line()) {
+      Some(rl) => rl,
+      None     => return Err(self.answer_connection_error(session, ConnectionError::NoRequestLineGiven)),
+    };
+    let front_buf = session.http().and_then(|h| h.front_buf.as_ref());
+    let client_ip = session.http().and_then(|h| h.get_session_address()).map(|address| address.ip());
     match self.listeners.get(&listen_token).as_ref()
-      .and_then(|l| l.frontend_from_request(&host, &rl.uri))
-      .map(|ref front| front.app_id.clone()) {
+      .and_then(|l| l.frontend_from_request(&host, Some(&rl.method.to_string()), rl.path(),
+        |name| front_buf.and_then(|buf| header_value(buf, name.as_bytes()))))
+      .map(|front| traffic_split::app_id(&front.app_id, front.split.as_ref(),
+        |name| front_buf.and_then(|buf| request_cookie(buf, name)), client_ip).to_string())
+      // the default application only gets the requests that no frontend matches
+      .or_else(|| self.listeners.get(&listen_token).and_then(|l| l.config.default_app.clone())) {
       Some(app_id) => Ok(app_id),
       None => {
         let answer = self.listeners[&listen_token].answers.borrow().get(DefaultAnswerStatus::Answer404, None);
@@ -446,15 +568,83 @@ impl Proxy {
     }
   }
 
+  /// answers the requests of a disabled application with its maintenance answer,
+  /// or its 503 answer without it
+  fn check_application_enabled(&self, session: &mut Session, app_id: &str) -> Result<(), ConnectionError> {
+    let maintenance_answer = match self.applications.get(app_id) {
+      Some(app) if !app.enabled => app.maintenance_answer.clone(),
+      _ => return Ok(()),
+    };
+
+    let answer = match maintenance_answer {
+      Some(answer) => Rc::new(answer.into_bytes()),
+      None         => self.get_service_unavailable_answer(Some(app_id), &session.listen_token),
+    };
+    session.set_answer(DefaultAnswerStatus::Answer503, answer);
+    Err(ConnectionError::ApplicationDisabled)
+  }
+
+  /// takes a slot in the concurrency limit of the application. Returns false if
+  /// the request waits in the queue, and answers 503 if the queue is full
+  fn take_request_slot(&self, session: &mut Session, app_id: &str) -> Result<bool, ConnectionError> {
+    let limits = self.backends.borrow().request_limits.clone();
+    let current = session.http_mut().and_then(|http| http.take_request_slot());
+    let was_queued = current.as_ref().map(|slot| !slot.is_held()).unwrap_or(false);
+    match request_limit::admit(&limits, app_id, session.frontend_token, current) {
+      Admission::Admitted(slot) => {
+        session.http_mut().map(|http| http.set_request_slot(slot));
+        if was_queued {
+          session.front_readiness().interest.insert(Ready::readable());
+          session.back_readiness().map(|r| r.interest.insert(Ready::writable()));
+        }
+        Ok(true)
+      },
+      Admission::Queued(slot) => {
+        // nothing is read or sent to a kept alive backend connection until it gets a slot
+        session.http_mut().map(|http| http.set_request_slot(Some(slot)));
+        session.front_readiness().interest.remove(Ready::readable());
+        session.back_readiness().map(|r| r.interest.remove(Ready::writable()));
+        Ok(false)
+      },
+      Admission::Rejected => {
+        let answer = self.get_service_unavailable_answer(Some(app_id), &session.listen_token);
+        session.set_answer(DefaultAnswerStatus::Answer503, answer);
+        Err(ConnectionError::RequestQueueFull)
+      },
+    }
+  }
+
+  /// sets the default answer for a request that could not be sent to a backend
+  fn answer_connection_error(&self, session: &mut Session, error: ConnectionError) -> ConnectionError {
+    let status = connection_error_status(&error);
+    let answer = self.listeners[&session.listen_token].answers.borrow()
+      .get(status, session.app_id.as_ref().map(|app_id| app_id.as_str()));
+    session.set_answer(status, answer);
+    error
+  }
+
   fn check_circuit_breaker(&mut self, session: &mut Session) -> Result<(), ConnectionError> {
-    if session.connection_attempt == CONN_RETRIES {
+    let (connection_attempts, retry_non_idempotent) = {
+      let backends = self.backends.borrow();
+      (backends.connection_attempts, backends.retry_non_idempotent)
+    };
+
+    if session.connection_attempt >= connection_attempts {
       error!("{} max connection attempt reached", session.log_context());
-      let answer = self.get_service_unavailable_answer(session.app_id.as_ref().map(|app_id| app_id.as_str()), &session.listen_token);
-      session.set_answer(DefaultAnswerStatus::Answer503, answer);
-      Err(ConnectionError::NoBackendAvailable)
+    } else if session.connection_attempt > 0 && !retry_non_idempotent && !session.is_idempotent_request() {
+      error!("{} backend connection failed, not retrying a non idempotent request", session.log_context());
+    } else if session.connection_attempt > 0 && !session.app_id.as_ref()
+      .map(|app_id| self.backends.borrow_mut().take_retry(app_id)).unwrap_or(true) {
+      error!("{} backend connection failed, the retry budget of the application is exhausted", session.log_context());
     } else {
-      Ok(())
+      return Ok(());
     }
+
+    // the next request of the connection gets its own attempts
+    session.reset_connection_attempt();
+    let answer = self.get_service_unavailable_answer(session.app_id.as_ref().map(|app_id| app_id.as_str()), &session.listen_token);
+    session.set_answer(DefaultAnswerStatus::Answer503, answer);
+    Err(ConnectionError::NoBackendAvailable)
   }
 
   fn get_service_unavailable_answer(&self, app_id: Option<&str>, listen_token: &Token) -> Rc<Vec<u8>> {
@@ -488,7 +678,11 @@ impl ProxyConfiguration<Session> for Proxy {
         let c = Session::new(session, frontend_sock, session_token, Rc::downgrade(&self.pool),
           listener.config.public_address.unwrap_or(listener.config.front),
           listener.config.expect_proxy, listener.config.sticky_name.clone(),
-          timeout, listener.answers.clone(), Token(token.0), delay);
+          listener.config.preserve_proxy_connection, listener.config.ignore_unsupported_expect,
+          listener.config.unexpected_body_policy, Rc::new(listener.config.client_ip.clone()),
+          listener.config.header_limits, listener.config.max_keepalive_requests,
+          listener.config.compression.clone().map(Rc::new), timeout, listener.answers.clone(), listener.acme_challenges.clone(),
+          Token(token.0), delay, Rc::downgrade(&self.backends.borrow().connection_pool));
 
         Ok((Rc::new(RefCell::new(c)), false))
       } else {
@@ -504,6 +698,42 @@ impl ProxyConfiguration<Session> for Proxy {
     self.check_circuit_breaker(session)?;
 
     let app_id = self.app_id_from_request(session)?;
+    session.http_mut().map(|http| http.trace_application(&app_id));
+    self.check_application_enabled(session, &app_id)?;
+    if !self.take_request_slot(session, &app_id)? {
+      session.http().map(|http| http.trace_event(format_args!("waiting for a request slot of application {}", app_id)));
+      return Ok(BackendConnectAction::Queued);
+    }
+
+    if session.connection_attempt == 0 {
+      self.backends.borrow_mut().record_request(&app_id);
+    }
+
+    if self.applications.get(&app_id).map(|app| app.forward_tls_info).unwrap_or(false) {
+      session.http_mut().map(|http| http.add_tls_info_headers());
+    }
+
+    let add_forwarded_headers = self.applications.get(&app_id).map(|app| app.add_forwarded_headers).unwrap_or(true);
+    session.http_mut().map(|http| http.add_forwarded_headers(add_forwarded_headers));
+
+    if let Some(app) = self.applications.get(&app_id) {
+      session.http_mut().map(|http| http.add_header_rules(&app.request_headers, &app.response_headers, &app.request_id_headers));
+      session.http_mut().map(|http| http.set_host_rewriting(app.rewrite_location, app.backend_hosts.clone(), app.rewrite_cookie_domain));
+      session.http_mut().map(|http| http.set_failure_statuses(app.failure_statuses.clone()));
+      session.http_mut().map(|http| http.set_response_timeout(app.response_timeout.map(|t| Duration::milliseconds(i64::from(t)))));
+
+      let request_retries = if app.retry_unanswered_requests {
+        self.backends.borrow().connection_attempts.saturating_sub(1)
+      } else {
+        0
+      };
+      session.http_mut().map(|http| http.set_max_request_retries(request_retries));
+    }
+
+    let max_request_body_size = self.applications.get(&app_id).and_then(|app| app.max_request_body_size);
+    if session.http_mut().map(|http| http.limit_request_body_size(max_request_body_size)).unwrap_or(false) {
+      return Err(ConnectionError::PayloadTooLarge);
+    }
 
     if (session.http().and_then(|h| h.app_id.as_ref()) == Some(&app_id)) && session.back_connected == BackendConnectionStatus::Connected {
       let has_backend = session.backend.as_ref().map(|backend| {
@@ -518,6 +748,8 @@ impl ProxyConfiguration<Session> for Proxy {
         //matched on keepalive
         session.metrics.backend_id = session.backend.as_ref().map(|i| i.borrow().backend_id.clone());
         session.metrics.backend_start();
+        session.http().map(|http| http.trace_event(format_args!("reusing the keep-alive connection to backend {:?}",
+          session.metrics.backend_id)));
         return Ok(BackendConnectAction::Reuse);
       } else if let Some(token) = session.back_token() {
         session.close_backend(token, poll);
@@ -543,8 +775,12 @@ impl ProxyConfiguration<Session> for Proxy {
     let front_should_stick = self.applications.get(&app_id).map(|ref app| app.sticky_session).unwrap_or(false);
     let socket = self.backend_from_request(session, &app_id, front_should_stick)?;
 
+    let proxy_protocol_version = self.applications.get(&app_id)
+      .filter(|app| app.proxy_protocol == Some(ProxyProtocolConfig::SendHeader))
+      .map(|app| app.proxy_protocol_version);
     session.http_mut().map(|http| {
       http.app_id = Some(app_id.clone());
+      http.set_proxy_protocol_header(proxy_protocol_version);
     });
 
     // we still want to use the new socket
@@ -598,11 +834,30 @@ impl ProxyConfiguration<Session> for Proxy {
         self.remove_application(&application);
         ProxyResponse{ id: message.id, status: ProxyResponseStatus::Ok, data: None }
       },
+      ProxyRequestData::PurgeApplication(app_id) => {
+        let count = self.purge_application(&app_id);
+        debug!("{} purge application {}: {} frontends removed", message.id, app_id, count);
+        ProxyResponse{ id: message.id, status: ProxyResponseStatus::Ok, data: None }
+      },
+      ProxyRequestData::EnableApplication(app_id) => {
+        debug!("{} enable application {}", message.id, app_id);
+        self.set_application_enabled(&app_id, true, message.id)
+      },
+      ProxyRequestData::DisableApplication(app_id) => {
+        debug!("{} disable application {}", message.id, app_id);
+        self.set_application_enabled(&app_id, false, message.id)
+      },
+      ProxyRequestData::SetAppTimeout(timeout) => {
+        debug!("{} set the response timeout of application {} to {:?}", message.id, timeout.app_id, timeout.timeout);
+        self.set_app_timeout(&timeout.app_id, timeout.timeout, message.id)
+      },
       ProxyRequestData::AddHttpsFront(front) => {
         //info!("HTTPS\t{} add front {:?}", id, front);
         if let Some(listener) = self.listeners.values_mut().find(|l| l.address == front.address) {
-          listener.add_https_front(front);
-          ProxyResponse{ id: message.id, status: ProxyResponseStatus::Ok, data: None }
+          match listener.add_https_front(front) {
+            Ok(_) => ProxyResponse{ id: message.id, status: ProxyResponseStatus::Ok, data: None },
+            Err(err) => ProxyResponse{ id: message.id, status: ProxyResponseStatus::Error(err), data: None }
+          }
         } else {
           panic!("unknown listener: {:?}", front.address)
         }
@@ -616,6 +871,22 @@ impl ProxyConfiguration<Session> for Proxy {
           panic!("unknown listener: {:?}", front.address)
         }
       },
+      ProxyRequestData::SetTrafficSplit(split) => {
+        debug!("{} set traffic split {:?}", message.id, split);
+        for listener in self.listeners.values_mut() {
+          listener.set_traffic_split(&split);
+        }
+        ProxyResponse{ id: message.id, status: ProxyResponseStatus::Ok, data: None }
+      },
+      ProxyRequestData::SetDefaultApp(default) => {
+        debug!("{} set default app {:?}", message.id, default);
+        if let Some(listener) = self.listeners.values_mut().find(|l| l.address == default.front) {
+          listener.config.default_app = default.app_id;
+          ProxyResponse{ id: message.id, status: ProxyResponseStatus::Ok, data: None }
+        } else {
+          ProxyResponse{ id: message.id, status: ProxyResponseStatus::Error(format!("no HTTPS listener at address {:?}", default.front)), data: None }
+        }
+      },
       ProxyRequestData::AddCertificate(add_certificate) => {
         if let Some(listener) = self.listeners.values_mut().find(|l| l.address == add_certificate.front) {
           listener.add_certificate(add_certificate);
@@ -636,12 +907,54 @@ impl ProxyConfiguration<Session> for Proxy {
       ProxyRequestData::ReplaceCertificate(replace_certificate) => {
         //FIXME: should return an error if certificate still has fronts referencing it
         if let Some(listener) = self.listeners.values_mut().find(|l| l.address == replace_certificate.front) {
-          listener.replace_certificate(replace_certificate);
-          ProxyResponse{ id: message.id, status: ProxyResponseStatus::Ok, data: None }
+          if listener.replace_certificate(replace_certificate).is_some() {
+            ProxyResponse{ id: message.id, status: ProxyResponseStatus::Ok, data: None }
+          } else {
+            let err = String::from("could not load the new certificate, the old one is kept");
+            ProxyResponse{ id: message.id, status: ProxyResponseStatus::Error(err), data: None }
+          }
         } else {
           panic!()
         }
       },
+      ProxyRequestData::SetOcspResponse(set_ocsp_response) => {
+        let fingerprint = set_ocsp_response.fingerprint.clone();
+        match self.listeners.values_mut().find(|l| l.address == set_ocsp_response.front) {
+          Some(listener) => if listener.set_ocsp_response(set_ocsp_response) {
+            ProxyResponse{ id: message.id, status: ProxyResponseStatus::Ok, data: None }
+          } else {
+            let err = format!("no certificate with the fingerprint {}", fingerprint);
+            ProxyResponse{ id: message.id, status: ProxyResponseStatus::Error(err), data: None }
+          },
+          None => {
+            let err = format!("no HTTPS listener at {}", set_ocsp_response.front);
+            ProxyResponse{ id: message.id, status: ProxyResponseStatus::Error(err), data: None }
+          },
+        }
+      },
+      ProxyRequestData::AddAcmeChallenge(challenge) => {
+        if let Some(listener) = self.listeners.values_mut().find(|l| l.address == challenge.front) {
+          let domain = challenge.domain.clone();
+          if listener.acme_challenges.add_challenge(challenge) {
+            ProxyResponse{ id: message.id, status: ProxyResponseStatus::Ok, data: None }
+          } else {
+            let err = format!("could not load the ACME challenge certificate for {}", domain);
+            ProxyResponse{ id: message.id, status: ProxyResponseStatus::Error(err), data: None }
+          }
+        } else {
+          let err = format!("no HTTPS listener at address {:?}", challenge.front);
+          ProxyResponse{ id: message.id, status: ProxyResponseStatus::Error(err), data: None }
+        }
+      },
+      ProxyRequestData::RemoveAcmeChallenge(remove) => {
+        if let Some(listener) = self.listeners.values_mut().find(|l| l.address == remove.front) {
+          listener.acme_challenges.remove_challenge(remove);
+          ProxyResponse{ id: message.id, status: ProxyResponseStatus::Ok, data: None }
+        } else {
+          let err = format!("no HTTPS listener at address {:?}", remove.front);
+          ProxyResponse{ id: message.id, status: ProxyResponseStatus::Error(err), data: None }
+        }
+      },
       ProxyRequestData::RemoveListener(remove) => {
         debug!("removing HTTPS listener at address: {:?}", remove.front);
         if !self.remove_listener(remove.front) {
@@ -688,6 +1001,11 @@ impl ProxyConfiguration<Session> for Proxy {
         });
         ProxyResponse{ id: message.id, status: ProxyResponseStatus::Ok, data: None }
       },
+      ProxyRequestData::LogFormat(format) => {
+        debug!("{} changing log format to {:?}", message.id, format);
+        logging::LOGGER.with(|l| l.borrow_mut().set_format(format));
+        ProxyResponse{ id: message.id, status: ProxyResponseStatus::Ok, data: None }
+      },
       ProxyRequestData::Query(Query::Certificates(QueryCertificateType::All)) => {
         let res = self.listeners.iter().map(|(addr, listener)| {
           let mut domains = (&unwrap_msg!(listener.resolver.0.lock()).domains).to_hashmap();
@@ -724,6 +1042,28 @@ impl ProxyConfiguration<Session> for Proxy {
     ListenPortState::Available
     //if port == &self.address.port() { ListenPortState::InUse } else { ListenPortState::Available }
   }
+
+  fn listener_timeouts(&self, token: ListenToken) -> ListenerTimeouts {
+    self.listeners.get(&Token(token.0)).map(|listener| listener.config.timeouts).unwrap_or_default()
+  }
+
+  fn listener_rate_limit(&self, token: ListenToken) -> Option<ConnectionRateLimit> {
+    self.listeners.get(&Token(token.0)).and_then(|listener| listener.config.connection_rate_limit)
+  }
+
+  fn listener_expects_proxy_protocol(&self, token: ListenToken) -> bool {
+    self.listeners.get(&Token(token.0)).map(|listener| listener.config.expect_proxy).unwrap_or(false)
+  }
+
+  fn listener_max_sessions(&self, token: ListenToken) -> Option<usize> {
+    self.listeners.get(&Token(token.0)).and_then(|listener| listener.config.max_sessions)
+  }
+
+  fn pause_listener(&mut self, event_loop: &mut Poll, token: ListenToken, paused: bool) {
+    if let Some(sock) = self.listeners.get(&Token(token.0)).and_then(|listener| listener.listener.as_ref()) {
+      ::socket::pause_listener(event_loop, sock, Token(token.0), paused);
+    }
+  }
 }
 
 use server::HttpsProvider;
@@ -768,7 +1108,7 @@ pub fn start(config: HttpsListener, channel: ProxyChannel, max_buffers: usize, b
       let mut server_config: server::ServerConfig = Default::default();
       server_config.max_connections = max_buffers;
       let mut server  = Server::new(event_loop, channel, ScmSocket::new(scm_server.as_raw_fd()),
-      sessions, pool, backends, None, Some(HttpsProvider::Rustls(configuration)), None, server_config, None);
+      sessions, pool, None, backends, None, Some(HttpsProvider::Rustls(configuration)), None, server_config, None);
 
       info!("starting event loop");
       server.run();
@@ -776,3 +1116,289 @@ pub fn start(config: HttpsListener, channel: ProxyChannel, max_buffers: usize, b
   }
 }
 
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::io::Cursor;
+  use rustls::{ClientConfig, ClientSession, Session};
+  use webpki;
+  use sozu_command::proxy::CertificateAndKey;
+  use sozu_command::certificate::calculate_fingerprint;
+  use protocol::http::ClientCertificate;
+
+  /// ClientHello sent by the rustls client, with the supported_versions extension
+  fn rustls_client_hello() -> Vec<u8> {
+    let mut config = ClientConfig::new();
+    config.versions = vec![ProtocolVersion::TLSv1_2];
+    let dns_name = webpki::DNSNameRef::try_from_ascii_str("lolcatho.st").unwrap();
+    let mut client = ClientSession::new(&Arc::new(config), dns_name);
+    let mut hello = Vec::new();
+    client.write_tls(&mut hello).unwrap();
+    hello
+  }
+
+  /// ClientHello without extensions, as sent by TLS 1.0 and 1.1 clients
+  fn legacy_client_hello(version: [u8; 2]) -> Vec<u8> {
+    let mut body = Vec::new();
+    body.extend_from_slice(&version);
+    body.extend_from_slice(&[0u8; 32]);
+    // no session id, one cipher suite (TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256), null compression
+    body.extend_from_slice(&[0x00, 0x00, 0x02, 0xc0, 0x2f, 0x01, 0x00]);
+
+    let mut hello = vec![0x16, 0x03, 0x01, 0x00, body.len() as u8 + 4, 0x01, 0x00, 0x00, body.len() as u8];
+    hello.extend_from_slice(&body);
+    hello
+  }
+
+  /// returns true if the listener answers the ClientHello without an error
+  fn accepts_client_hello(listener: &Listener, hello: &[u8]) -> bool {
+    let mut session = ServerSession::new(&listener.ssl_config);
+    session.read_tls(&mut Cursor::new(hello)).unwrap();
+    session.process_new_packets().is_ok()
+  }
+
+  #[test]
+  fn minimum_tls_version() {
+    let config = HttpsListener {
+      front: "127.0.0.1:8443".parse().unwrap(),
+      versions: vec![TlsVersion::TLSv1_2, TlsVersion::TLSv1_3],
+      ..Default::default()
+    };
+    let mut listener = Listener::new(config, Token(0));
+    assert!(listener.add_certificate(AddCertificate {
+      front: "127.0.0.1:8443".parse().unwrap(),
+      certificate: CertificateAndKey {
+        certificate: String::from(include_str!("../../assets/certificate.pem")),
+        certificate_chain: vec![],
+        key: String::from(include_str!("../../assets/key.pem")),
+      },
+      names: vec![String::from("lolcatho.st")],
+    }));
+
+    assert!(accepts_client_hello(&listener, &rustls_client_hello()));
+    // TLS 1.0 and 1.1 handshakes are refused
+    assert!(!accepts_client_hello(&listener, &legacy_client_hello([0x03, 0x01])));
+    assert!(!accepts_client_hello(&listener, &legacy_client_hello([0x03, 0x02])));
+
+    let config = HttpsListener {
+      front: "127.0.0.1:8443".parse().unwrap(),
+      versions: vec![TlsVersion::TLSv1_3],
+      ..Default::default()
+    };
+    let listener = Listener::new(config, Token(0));
+    assert!(!accepts_client_hello(&listener, &rustls_client_hello()));
+  }
+
+  #[test]
+  fn conflicting_frontends() {
+    let mut listener = Listener::new(HttpsListener { front: "127.0.0.1:8443".parse().unwrap(), ..Default::default() }, Token(0));
+    // two certificates, with different fingerprints, for the same hostname
+    for &(certificate, key) in [(include_str!("../../assets/certificate.pem"), include_str!("../../assets/key.pem")),
+      (include_str!("../../assets/mtls-server-certificate.pem"), include_str!("../../assets/mtls-server-key.pem"))].iter() {
+      assert!(listener.add_certificate(AddCertificate {
+        front: "127.0.0.1:8443".parse().unwrap(),
+        certificate: CertificateAndKey { certificate: String::from(certificate), certificate_chain: vec![], key: String::from(key) },
+        names: vec![String::from("lolcatho.st")],
+      }));
+    }
+
+    let front = |app_id: &str| HttpFront { app_id: String::from(app_id), address: "127.0.0.1:8443".parse().unwrap(), hostname: String::from("lolcatho.st"), path_begin: String::from("/"), trailing_slash: TrailingSlash::Strict, method: None, header_match: None, split: None };
+    assert_eq!(listener.add_https_front(front("app_1")), Ok(()));
+    assert_eq!(listener.add_https_front(front("app_2")),
+      Err(String::from("the frontend lolcatho.st/ is already used by the application app_1")));
+    assert_eq!(listener.add_https_front(HttpFront { path_begin: String::from("/api"), ..front("app_2") }), Ok(()));
+
+    let app_ids = listener.fronts.domain_lookup(b"lolcatho.st", false)
+      .map(|(_, fronts)| fronts.iter().map(|f| f.app_id.as_str()).collect::<Vec<_>>());
+    assert_eq!(app_ids, Some(vec!["app_1", "app_2"]));
+  }
+
+  /// listener requiring a certificate signed by the test client CA
+  fn mtls_listener() -> Listener {
+    let config = HttpsListener {
+      front: "127.0.0.1:8443".parse().unwrap(),
+      versions: vec![TlsVersion::TLSv1_2, TlsVersion::TLSv1_3],
+      client_ca: Some(String::from(include_str!("../../assets/client-ca.pem"))),
+      ..Default::default()
+    };
+    let mut listener = Listener::new(config, Token(0));
+    assert!(listener.add_certificate(AddCertificate {
+      front: "127.0.0.1:8443".parse().unwrap(),
+      certificate: CertificateAndKey {
+        certificate: String::from(include_str!("../../assets/mtls-server-certificate.pem")),
+        certificate_chain: vec![],
+        key: String::from(include_str!("../../assets/mtls-server-key.pem")),
+      },
+      names: vec![String::from("localhost")],
+    }));
+    listener
+  }
+
+  /// runs a handshake between a client presenting the certificate, if any,
+  /// and the listener. Returns the server session if it succeeded
+  fn client_handshake(listener: &Listener, certificate: Option<(&str, &str)>) -> Option<ServerSession> {
+    use rustls::internal::pemfile;
+
+    let mut config = ClientConfig::new();
+    config.root_store.add_pem_file(&mut include_str!("../../assets/client-ca.pem").as_bytes()).unwrap();
+    if let Some((certificate, key)) = certificate {
+      let certificates = pemfile::certs(&mut certificate.as_bytes()).unwrap();
+      let key = pemfile::pkcs8_private_keys(&mut key.as_bytes()).unwrap().remove(0);
+      config.set_single_client_cert(certificates, key);
+    }
+
+    let dns_name = webpki::DNSNameRef::try_from_ascii_str("localhost").unwrap();
+    let mut client = ClientSession::new(&Arc::new(config), dns_name);
+    let mut server = ServerSession::new(&listener.ssl_config);
+
+    while client.is_handshaking() || server.is_handshaking() {
+      let mut data = Vec::new();
+      while client.wants_write() {
+        client.write_tls(&mut data).unwrap();
+      }
+      server.read_tls(&mut Cursor::new(&data)).unwrap();
+      server.process_new_packets().ok()?;
+
+      let mut data = Vec::new();
+      while server.wants_write() {
+        server.write_tls(&mut data).unwrap();
+      }
+      client.read_tls(&mut Cursor::new(&data)).unwrap();
+      client.process_new_packets().ok()?;
+    }
+
+    // with TLS 1.3, the server sees the client certificate after the client finished its handshake
+    let mut data = Vec::new();
+    while client.wants_write() {
+      client.write_tls(&mut data).unwrap();
+    }
+    server.read_tls(&mut Cursor::new(&data)).unwrap();
+    server.process_new_packets().ok()?;
+
+    Some(server)
+  }
+
+  #[test]
+  fn client_certificate_accepted() {
+    let listener = mtls_listener();
+    let server = client_handshake(&listener, Some((include_str!("../../assets/client-certificate.pem"),
+      include_str!("../../assets/client-key.pem")))).expect("the handshake should succeed");
+
+    let certificates = server.get_peer_certificates().expect("the client should have sent a certificate");
+    let certificate = ClientCertificate::from_der(&certificates[0].0);
+    assert_eq!(certificate.subject, "C=FR, O=Sozu, CN=client.example.com");
+    // openssl x509 -in assets/client-certificate.pem -noout -fingerprint -sha256
+    assert_eq!(certificate.fingerprint, "35bea37b253ee07c8a7f8c538e7a061e0db44c01b99bc3776b806f7bbdce9636");
+    assert_eq!(certificate.headers(), "X-Client-Cert-Subject: C=FR, O=Sozu, CN=client.example.com\r\n\
+      X-Client-Cert-Fingerprint: 35bea37b253ee07c8a7f8c538e7a061e0db44c01b99bc3776b806f7bbdce9636\r\n");
+  }
+
+  #[test]
+  fn missing_client_certificate_rejected() {
+    let listener = mtls_listener();
+    assert!(client_handshake(&listener, None).is_none());
+
+    // without client CA, no certificate is requested
+    let config = HttpsListener {
+      front: "127.0.0.1:8443".parse().unwrap(),
+      ..Default::default()
+    };
+    let mut listener = Listener::new(config, Token(0));
+    assert!(listener.add_certificate(AddCertificate {
+      front: "127.0.0.1:8443".parse().unwrap(),
+      certificate: CertificateAndKey {
+        certificate: String::from(include_str!("../../assets/mtls-server-certificate.pem")),
+        certificate_chain: vec![],
+        key: String::from(include_str!("../../assets/mtls-server-key.pem")),
+      },
+      names: vec![String::from("localhost")],
+    }));
+    let server = client_handshake(&listener, None).expect("the handshake should succeed");
+    assert!(server.get_peer_certificates().is_none());
+  }
+
+  #[test]
+  fn untrusted_client_certificate_rejected() {
+    let listener = mtls_listener();
+    assert!(client_handshake(&listener, Some((include_str!("../../assets/untrusted-client-certificate.pem"),
+      include_str!("../../assets/untrusted-client-key.pem")))).is_none());
+  }
+
+  /// runs a TLS 1.2 handshake with the listener, and returns what the server sent
+  fn server_handshake_data(listener: &Listener) -> Vec<u8> {
+    let mut config = ClientConfig::new();
+    config.versions = vec![ProtocolVersion::TLSv1_2];
+    config.root_store.add_pem_file(&mut include_str!("../../assets/client-ca.pem").as_bytes()).unwrap();
+
+    let dns_name = webpki::DNSNameRef::try_from_ascii_str("localhost").unwrap();
+    let mut client = ClientSession::new(&Arc::new(config), dns_name);
+    let mut server = ServerSession::new(&listener.ssl_config);
+
+    let mut sent = Vec::new();
+    while client.is_handshaking() || server.is_handshaking() {
+      let mut data = Vec::new();
+      while client.wants_write() {
+        client.write_tls(&mut data).unwrap();
+      }
+      server.read_tls(&mut Cursor::new(&data)).unwrap();
+      server.process_new_packets().unwrap();
+
+      let mut data = Vec::new();
+      while server.wants_write() {
+        server.write_tls(&mut data).unwrap();
+      }
+      client.read_tls(&mut Cursor::new(&data)).unwrap();
+      client.process_new_packets().unwrap();
+      sent.extend_from_slice(&data);
+    }
+    sent
+  }
+
+  #[test]
+  fn ocsp_staple_in_handshake() {
+    let config = HttpsListener {
+      front: "127.0.0.1:8443".parse().unwrap(),
+      ..Default::default()
+    };
+    let mut listener = Listener::new(config, Token(0));
+    assert!(listener.add_certificate(AddCertificate {
+      front: "127.0.0.1:8443".parse().unwrap(),
+      certificate: CertificateAndKey {
+        certificate: String::from(include_str!("../../assets/mtls-server-certificate.pem")),
+        certificate_chain: vec![],
+        key: String::from(include_str!("../../assets/mtls-server-key.pem")),
+      },
+      names: vec![String::from("localhost")],
+    }));
+    let fingerprint = CertFingerprint(calculate_fingerprint(include_bytes!("../../assets/mtls-server-certificate.pem")).unwrap());
+    let staple = b"sozu test OCSP staple".to_vec();
+
+    // without staple, the certificate is served normally
+    let data = server_handshake_data(&listener);
+    assert!(!data.windows(staple.len()).any(|w| w == &staple[..]));
+
+    assert!(listener.set_ocsp_response(SetOcspResponse {
+      front: "127.0.0.1:8443".parse().unwrap(),
+      fingerprint: fingerprint.clone(),
+      ocsp_response: staple.clone(),
+    }));
+    let data = server_handshake_data(&listener);
+    assert!(data.windows(staple.len()).any(|w| w == &staple[..]), "the staple should be sent in the handshake");
+
+    // an empty response removes the staple
+    assert!(listener.set_ocsp_response(SetOcspResponse {
+      front: "127.0.0.1:8443".parse().unwrap(),
+      fingerprint,
+      ocsp_response: vec![],
+    }));
+    let data = server_handshake_data(&listener);
+    assert!(!data.windows(staple.len()).any(|w| w == &staple[..]));
+
+    assert!(!listener.set_ocsp_response(SetOcspResponse {
+      front: "127.0.0.1:8443".parse().unwrap(),
+      fingerprint: CertFingerprint(vec![0; 32]),
+      ocsp_response: staple,
+    }));
+  }
+}
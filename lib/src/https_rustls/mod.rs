@@ -1,3 +1,4 @@
 pub mod configuration;
 pub mod session;
 pub mod resolver;
+pub mod acme;
@@ -6,13 +6,15 @@ use rustls::{ResolvesServerCert, SignatureScheme};
 use rustls::sign::{CertifiedKey, RSASigningKey};
 use rustls::internal::pemfile;
 
-use sozu_command::proxy::{CertificateAndKey, CertFingerprint, AddCertificate, RemoveCertificate};
-use sozu_command::certificate::calculate_fingerprint_from_der;
+use sozu_command::proxy::{CertificateAndKey, CertFingerprint, AddCertificate, RemoveCertificate, ReplaceCertificate};
+use sozu_command::certificate::{calculate_fingerprint_from_der, parse_certificate_info};
 
 use trie::TrieNode;
 
 struct TlsData {
   pub cert:     CertifiedKey,
+  /// names indexed for this certificate
+  pub names:    Vec<String>,
 }
 
 pub struct CertificateResolver {
@@ -30,33 +32,14 @@ impl CertificateResolver {
 
   pub fn add_certificate(&mut self, add_certificate: AddCertificate) -> Option<CertFingerprint> {
     if let Some(certified_key) = generate_certified_key(add_certificate.certificate) {
-      let fingerprint = calculate_fingerprint_from_der(&certified_key.cert[0].0);
-      if add_certificate.names.is_empty() {
-        //FIXME: waiting for https://github.com/briansmith/webpki/pull/65 to merge to get the DNS names
-        // create a untrusted::Input
-        // let input = untrusted::Input::from(&certs[0].0);
-        // create an EndEntityCert
-        // let ee = webpki::EndEntityCert::from(input).unwrap()
-        // get names
-        // let dns_names = ee.list_dns_names()
-        // names.extend(dns_names.drain(..).map(|name| name.to_String()));
-        error!("the rustls proxy cannot extract the names from the certificate (fingerprint={:?})", fingerprint);
+      let fingerprint = CertFingerprint(calculate_fingerprint_from_der(&certified_key.cert[0].0));
+      let names = names_or_certificate_names(add_certificate.names, &certified_key);
+      if names.is_empty() {
+        error!("could not find the names of the certificate (fingerprint={:?})", fingerprint);
         return None;
       }
 
-      let mut names = add_certificate.names;
-      //info!("cert fingerprint: {:?}", fingerprint);
-
-      let data = TlsData {
-        cert:     certified_key,
-      };
-
-      let fingerprint = CertFingerprint(fingerprint);
-      self.certificates.insert(fingerprint.clone(), data);
-      for name in names.drain(..) {
-        self.domains.domain_insert(name.into_bytes(), fingerprint.clone());
-      }
-
+      self.insert(fingerprint.clone(), certified_key, names);
       Some(fingerprint)
     } else {
       None
@@ -64,28 +47,71 @@ impl CertificateResolver {
   }
 
   pub fn remove_certificate(&mut self, remove_certificate: RemoveCertificate) {
-    if let Some(_data) = self.certificates.get(&remove_certificate.fingerprint) {
-      //let cert = &data.cert.cert[0];
-      if remove_certificate.names.is_empty() {
-        //FIXME: waiting for https://github.com/briansmith/webpki/pull/65 to merge to get the DNS names
-        // create a untrusted::Input
-        // let input = untrusted::Input::from(&certs[0].0);
-        // create an EndEntityCert
-        // let ee = webpki::EndEntityCert::from(input).unwrap()
-        // get names
-        // let dns_names = ee.list_dns_names()
-        // names.extend(dns_names.drain(..).map(|name| name.to_String()));
-        unimplemented!("the rustls proxy cannot extract the names from the certificate");
+    let RemoveCertificate { fingerprint, names, .. } = remove_certificate;
+
+    if let Some(data) = self.certificates.remove(&fingerprint) {
+      let names = if names.is_empty() { data.names } else { names };
+      self.remove_names(&fingerprint, names);
+    }
+  }
+
+  /// indexes every name of the certificate, wildcards included
+  fn insert(&mut self, fingerprint: CertFingerprint, cert: CertifiedKey, names: Vec<String>) {
+    for name in names.iter() {
+      self.domains.domain_insert(name.to_lowercase().into_bytes(), fingerprint.clone());
+    }
+    self.certificates.insert(fingerprint, TlsData { cert, names });
+  }
+
+  /// removes the names that still point to this certificate, another
+  /// certificate may have been added for them since
+  fn remove_names(&mut self, fingerprint: &CertFingerprint, names: Vec<String>) {
+    for name in names {
+      let name = name.to_lowercase().into_bytes();
+      if self.domains.domain_lookup(&name, false).map(|kv| &kv.1 == fingerprint).unwrap_or(false) {
+        self.domains.domain_remove(&name);
       }
+    }
+  }
 
-      let names = remove_certificate.names;
+  /// swaps the old certificate for the new one in one step. If the new
+  /// certificate cannot be loaded, the old one is kept. Sessions that
+  /// already finished their handshake keep using the old certificate
+  pub fn replace_certificate(&mut self, replace_certificate: ReplaceCertificate) -> Option<CertFingerprint> {
+    let ReplaceCertificate { new_certificate, old_fingerprint, old_names, new_names, .. } = replace_certificate;
 
-      for name in names {
-        self.domains.domain_remove(&name.into_bytes());
+    let certified_key = match generate_certified_key(new_certificate) {
+      Some(certified_key) => certified_key,
+      None => {
+        error!("could not load the new certificate, keeping the certificate with fingerprint {:?}", old_fingerprint);
+        return None;
       }
+    };
+    let fingerprint = CertFingerprint(calculate_fingerprint_from_der(&certified_key.cert[0].0));
+    let new_names = names_or_certificate_names(new_names, &certified_key);
+    if new_names.is_empty() {
+      error!("could not find the names of the certificate (fingerprint={:?})", fingerprint);
+      return None;
     }
 
-    self.certificates.remove(&remove_certificate.fingerprint);
+    let old_data = self.certificates.remove(&old_fingerprint);
+    let old_names = if old_names.is_empty() { old_data.map(|data| data.names).unwrap_or_default() } else { old_names };
+    self.remove_names(&old_fingerprint, old_names);
+
+    self.insert(fingerprint.clone(), certified_key, new_names);
+    Some(fingerprint)
+  }
+
+  /// staples the OCSP response to the handshakes using the certificate, or removes
+  /// the staple if the response is empty. Returns false if the certificate is unknown
+  pub fn set_ocsp_response(&mut self, fingerprint: &CertFingerprint, ocsp_response: Vec<u8>) -> bool {
+    match self.certificates.get_mut(fingerprint) {
+      Some(data) => {
+        data.cert.ocsp = if ocsp_response.is_empty() { None } else { Some(ocsp_response) };
+        true
+      },
+      None => false,
+    }
   }
 }
 
@@ -110,6 +136,22 @@ impl CertificateResolverWrapper {
     }
 
   }
+
+  pub fn replace_certificate(&self, replace_certificate: ReplaceCertificate) -> Option<CertFingerprint> {
+    if let Ok(ref mut resolver) = self.0.try_lock() {
+      resolver.replace_certificate(replace_certificate)
+    } else {
+      None
+    }
+  }
+
+  pub fn set_ocsp_response(&self, fingerprint: &CertFingerprint, ocsp_response: Vec<u8>) -> bool {
+    if let Ok(ref mut resolver) = self.0.try_lock() {
+      resolver.set_ocsp_response(fingerprint, ocsp_response)
+    } else {
+      false
+    }
+  }
 }
 
 impl ResolvesServerCert for CertificateResolverWrapper {
@@ -123,6 +165,7 @@ impl ResolvesServerCert for CertificateResolverWrapper {
       return None;
     }
     let name: &str = server_name.unwrap().into();
+    let name = name.to_lowercase();
 
     trace!("trying to resolve name: {:?} for signature scheme: {:?}", name, sigschemes);
     if let Ok(ref mut resolver) = self.0.try_lock() {
@@ -138,6 +181,16 @@ impl ResolvesServerCert for CertificateResolverWrapper {
   }
 }
 
+/// the names sent with the order, or the DNS names of the subject
+/// alternative names (the common name if there are none)
+fn names_or_certificate_names(names: Vec<String>, certified_key: &CertifiedKey) -> Vec<String> {
+  if !names.is_empty() {
+    return names;
+  }
+
+  parse_certificate_info(&certified_key.cert[0].0).map(|info| info.domains).unwrap_or_default()
+}
+
 pub fn generate_certified_key(certificate_and_key: CertificateAndKey) -> Option<CertifiedKey> {
   let mut chain = Vec::new();
 
@@ -195,3 +248,166 @@ pub fn generate_certified_key(certificate_and_key: CertificateAndKey) -> Option<
 
   None
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use sozu_command::certificate::calculate_fingerprint;
+
+  fn resolve(resolver: &CertificateResolverWrapper, name: &str) -> Option<CertifiedKey> {
+    let dns_name = webpki::DNSNameRef::try_from_ascii_str(name).unwrap();
+    resolver.resolve(Some(dns_name), &[])
+  }
+
+  fn lolcatho_st() -> CertificateAndKey {
+    CertificateAndKey {
+      certificate:       String::from(include_str!("../../assets/certificate.pem")),
+      certificate_chain: vec![],
+      key:               String::from(include_str!("../../assets/key.pem")),
+    }
+  }
+
+  fn test_local() -> CertificateAndKey {
+    CertificateAndKey {
+      certificate:       String::from(include_str!("../../assets/cert_test.pem")),
+      certificate_chain: vec![],
+      key:               String::from(include_str!("../../assets/key_test.pem")),
+    }
+  }
+
+  #[test]
+  fn replace_certificate() {
+    let front = "127.0.0.1:8443".parse().unwrap();
+    let resolver = CertificateResolverWrapper::new();
+    let old_fingerprint = resolver.add_certificate(AddCertificate {
+      front,
+      certificate: lolcatho_st(),
+      names: vec![String::from("lolcatho.st")],
+    }).expect("should add the certificate");
+    assert_eq!(old_fingerprint.0, calculate_fingerprint(include_bytes!("../../assets/certificate.pem")).unwrap());
+
+    // a session established with the old certificate
+    let in_flight = resolve(&resolver, "lolcatho.st").expect("should find the certificate");
+
+    // the old certificate is kept if the new one is invalid
+    let mut invalid = test_local();
+    invalid.key = String::from("invalid key");
+    assert_eq!(resolver.replace_certificate(ReplaceCertificate {
+      front,
+      new_certificate: invalid,
+      old_fingerprint: old_fingerprint.clone(),
+      old_names: vec![String::from("lolcatho.st")],
+      new_names: vec![String::from("lolcatho.st")],
+    }), None);
+    assert_eq!(resolve(&resolver, "lolcatho.st").unwrap().cert, in_flight.cert);
+
+    let new_fingerprint = resolver.replace_certificate(ReplaceCertificate {
+      front,
+      new_certificate: test_local(),
+      old_fingerprint: old_fingerprint.clone(),
+      old_names: vec![String::from("lolcatho.st")],
+      new_names: vec![String::from("lolcatho.st"), String::from("test.local")],
+    }).expect("should replace the certificate");
+    assert_eq!(new_fingerprint.0, calculate_fingerprint(include_bytes!("../../assets/cert_test.pem")).unwrap());
+
+    let new_cert = resolve(&resolver, "lolcatho.st").expect("should find the new certificate");
+    assert_eq!(calculate_fingerprint_from_der(&new_cert.cert[0].0), new_fingerprint.0);
+    assert!(resolve(&resolver, "test.local").is_some());
+    assert!(!resolver.0.lock().unwrap().certificates.contains_key(&old_fingerprint));
+
+    // the session keeps its certificate
+    assert_eq!(calculate_fingerprint_from_der(&in_flight.cert[0].0), old_fingerprint.0);
+  }
+
+  fn multi_domain() -> CertificateAndKey {
+    CertificateAndKey {
+      certificate:       String::from(include_str!("../../assets/multi-domain-certificate.pem")),
+      certificate_chain: vec![],
+      key:               String::from(include_str!("../../assets/multi-domain-key.pem")),
+    }
+  }
+
+  #[test]
+  fn subject_alternative_names() {
+    let front = "127.0.0.1:8443".parse().unwrap();
+    let resolver = CertificateResolverWrapper::new();
+    // the names are extracted from the certificate
+    let fingerprint = resolver.add_certificate(AddCertificate {
+      front,
+      certificate: multi_domain(),
+      names: vec![],
+    }).expect("should add the certificate");
+    resolver.add_certificate(AddCertificate {
+      front,
+      certificate: lolcatho_st(),
+      names: vec![String::from("lolcatho.st")],
+    }).expect("should add the certificate");
+
+    let found = |name: &str| resolve(&resolver, name).map(|cert| CertFingerprint(calculate_fingerprint_from_der(&cert.cert[0].0)));
+
+    // exact SAN matches, and the common name is not the only name
+    assert_eq!(found("example.com"), Some(fingerprint.clone()));
+    assert_eq!(found("www.example.com"), Some(fingerprint.clone()));
+    assert_eq!(found("WWW.Example.com"), Some(fingerprint.clone()));
+    // wildcard SAN
+    assert_eq!(found("v1.api.example.com"), Some(fingerprint.clone()));
+    assert_eq!(found("api.example.com"), None);
+    assert_eq!(found("a.v1.api.example.com"), None);
+    // unknown SNI
+    assert_eq!(found("unknown.example.com"), None);
+    assert_ne!(found("lolcatho.st"), Some(fingerprint.clone()));
+
+    resolver.remove_certificate(RemoveCertificate {
+      front,
+      fingerprint: fingerprint.clone(),
+      names: vec![],
+    });
+    assert_eq!(found("www.example.com"), None);
+    assert_eq!(found("v1.api.example.com"), None);
+    assert!(found("lolcatho.st").is_some());
+  }
+
+  #[test]
+  fn unknown_sni_handshake_failure() {
+    use rustls::{ClientConfig, ClientSession, ServerConfig, ServerSession, NoClientAuth, Session};
+    use std::io::Cursor;
+
+    let resolver = Arc::new(CertificateResolverWrapper::new());
+    resolver.add_certificate(AddCertificate {
+      front: "127.0.0.1:8443".parse().unwrap(),
+      certificate: multi_domain(),
+      names: vec![],
+    }).expect("should add the certificate");
+
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config.cert_resolver = resolver.clone();
+    let config = Arc::new(config);
+
+    let handshake = |name: &str| {
+      let dns_name = webpki::DNSNameRef::try_from_ascii_str(name).unwrap();
+      let mut client = ClientSession::new(&Arc::new(ClientConfig::new()), dns_name);
+      let mut hello = Vec::new();
+      client.write_tls(&mut hello).unwrap();
+
+      let mut server = ServerSession::new(&config);
+      server.read_tls(&mut Cursor::new(&hello)).unwrap();
+      let result = server.process_new_packets();
+      let mut answer = Vec::new();
+      while server.wants_write() {
+        server.write_tls(&mut answer).unwrap();
+      }
+      (result.is_ok(), answer)
+    };
+
+    let (ok, answer) = handshake("www.example.com");
+    assert!(ok);
+    // the ServerHello is a handshake record
+    assert_eq!(answer.first(), Some(&0x16));
+
+    let (ok, answer) = handshake("unknown.example.com");
+    assert!(!ok);
+    // the server answers with a fatal alert record
+    assert_eq!(&answer[..1], &[0x15]);
+    assert_eq!(answer[5], 2);
+  }
+}
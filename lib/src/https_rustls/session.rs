@@ -1,6 +1,6 @@
 use std::rc::{Rc,Weak};
 use std::cell::RefCell;
-use std::net::{Shutdown,SocketAddr};
+use std::net::{IpAddr,Shutdown,SocketAddr};
 use mio::*;
 use mio::net::*;
 use mio::unix::UnixReady;
@@ -10,32 +10,36 @@ use uuid::Uuid;
 use rustls::{ServerSession,Session as ClientSession,ProtocolVersion,SupportedCipherSuite,CipherSuite};
 use mio_extras::timer::{Timer, Timeout};
 use sozu_command::buffer::Buffer;
-use sozu_command::proxy::ProxyEvent;
+use sozu_command::proxy::{ProxyEvent,UnexpectedBodyPolicy,ClientIpConfig,HeaderLimits,ResponseCompression};
 
 use protocol::http::parser::RequestState;
 use pool::Pool;
-use {Backend,SessionResult,Protocol,Readiness,SessionMetrics, ProxySession,
-  BackendConnectionStatus, CloseResult};
+use backend_pool::ConnectionPool;
+use {SessionResult,Protocol,Readiness,SessionMetrics, ProxySession,
+  BackendConnectionStatus, CloseResult, ConnectionGuard};
 use socket::FrontRustls;
-use protocol::{ProtocolResult,Http,Pipe};
+use protocol::{ProtocolResult,Http,Http2,Pipe};
+use protocol::h2::ALPN_H2;
 use protocol::rustls::TlsHandshake;
-use protocol::http::{DefaultAnswerStatus, TimeoutStatus, answers::HttpAnswers};
+use protocol::http::{self, ClientCertificate, DefaultAnswerStatus, TimeoutStatus, answers::HttpAnswers};
 use protocol::proxy_protocol::expect::ExpectProxyProtocol;
 use retry::RetryPolicy;
 use util::UnwrapLog;
 use buffer_queue::BufferQueue;
-use server::push_event;
+use server::{push_event,ListenToken};
+use super::acme::AcmeChallenges;
 
 pub enum State {
   Expect(ExpectProxyProtocol<TcpStream>, ServerSession),
   Handshake(TlsHandshake),
   Http(Http<FrontRustls>),
-  WebSocket(Pipe<FrontRustls>)
+  WebSocket(Pipe<FrontRustls>),
+  Http2(Http2<FrontRustls>),
 }
 
 pub struct Session {
   pub frontend_token: Token,
-  pub backend:        Option<Rc<RefCell<Backend>>>,
+  pub backend:        Option<ConnectionGuard>,
   pub back_connected: BackendConnectionStatus,
   protocol:           Option<State>,
   pub public_address: SocketAddr,
@@ -43,18 +47,36 @@ pub struct Session {
   pub metrics:        SessionMetrics,
   pub app_id:         Option<String>,
   sticky_name:        String,
+  preserve_proxy_connection: bool,
+  ignore_unsupported_expect: bool,
+  unexpected_body_policy: UnexpectedBodyPolicy,
+  client_ip:          Rc<ClientIpConfig>,
+  header_limits:      HeaderLimits,
+  max_keepalive_requests: Option<usize>,
+  compression:        Option<Rc<ResponseCompression>>,
   timeout:            Timeout,
   last_event:         SteadyTime,
   pub listen_token:   Token,
   pub connection_attempt: u8,
+  /// ids of the backends the current request failed to connect to
+  pub tried_backends: Vec<String>,
   peer_address:       Option<SocketAddr>,
   answers:            Rc<RefCell<HttpAnswers>>,
+  proxy_protocol_client: Option<IpAddr>,
+  acme_challenges:    Rc<AcmeChallenges>,
+  /// receives the idle backend connections when the session ends
+  connection_pool:    Weak<RefCell<ConnectionPool>>,
 }
 
 impl Session {
   pub fn new(ssl: ServerSession, sock: TcpStream, token: Token, pool: Weak<RefCell<Pool<Buffer>>>,
-    public_address: SocketAddr, expect_proxy: bool, sticky_name: String, timeout: Timeout,
-    answers: Rc<RefCell<HttpAnswers>>, listen_token: Token, delay: Duration) -> Session {
+    public_address: SocketAddr, expect_proxy: bool, sticky_name: String, preserve_proxy_connection: bool,
+    ignore_unsupported_expect: bool, unexpected_body_policy: UnexpectedBodyPolicy, client_ip: Rc<ClientIpConfig>,
+    header_limits: HeaderLimits, max_keepalive_requests: Option<usize>,
+    compression: Option<Rc<ResponseCompression>>, timeout: Timeout,
+    answers: Rc<RefCell<HttpAnswers>>,
+    acme_challenges: Rc<AcmeChallenges>, listen_token: Token, delay: Duration,
+    connection_pool: Weak<RefCell<ConnectionPool>>) -> Session {
     let peer_address = if expect_proxy {
       // Will be defined later once the expect proxy header has been received and parsed
       None
@@ -69,7 +91,7 @@ impl Session {
       Some(State::Expect(ExpectProxyProtocol::new(sock, token, request_id), ssl))
     } else {
       gauge_add!("protocol.tls.handshake", 1);
-      Some(State::Handshake(TlsHandshake::new(ssl, sock, request_id)))
+      Some(State::Handshake(TlsHandshake::new(ssl, sock, request_id, acme_challenges.clone())))
     };
 
     let metrics = SessionMetrics::new(Some(delay));
@@ -84,12 +106,23 @@ impl Session {
       metrics,
       app_id:         None,
       sticky_name,
+      preserve_proxy_connection,
+      ignore_unsupported_expect,
+      unexpected_body_policy,
+      client_ip,
+      header_limits,
+      max_keepalive_requests,
+      compression,
       timeout,
       last_event:     SteadyTime::now(),
       listen_token,
       connection_attempt: 0,
+      tried_backends:     Vec::new(),
       peer_address,
       answers,
+      acme_challenges,
+      proxy_protocol_client: None,
+      connection_pool,
     };
     session.front_readiness().interest = UnixReady::from(Ready::readable()) | UnixReady::hup() | UnixReady::error();
     session
@@ -123,28 +156,60 @@ impl Session {
     });
   }
 
+  /// gives an idle keep-alive backend connection to the connection pool instead of
+  /// closing it. The socket is removed from the session if the pool takes it
+  fn pool_backend_connection(&mut self, poll: &mut Poll) {
+    let connection_pool = match self.connection_pool.upgrade() {
+      Some(connection_pool) => connection_pool,
+      None => return,
+    };
+    let (app_id, backend) = match (self.app_id.as_ref(), self.backend.as_ref()) {
+      (Some(app_id), Some(backend)) => (app_id.clone(), Rc::clone(backend)),
+      _ => return,
+    };
+    if connection_pool.borrow().max_idle == 0 {
+      return;
+    }
+
+    let socket = match self.protocol {
+      Some(State::Http(ref mut http)) if http.is_backend_reusable() => http.backend.take(),
+      _ => None,
+    };
+
+    if let Some(socket) = socket {
+      if let Err(e) = poll.deregister(&socket) {
+        error!("error deregistering back socket({:?}): {:?}", socket, e);
+        return;
+      }
+
+      let result = connection_pool.borrow_mut().checkin(&app_id, &backend.borrow(), socket, SteadyTime::now());
+      if result.is_ok() {
+        debug!("{}\tthe idle backend connection goes to the connection pool", self.log_context());
+      }
+    }
+  }
+
   pub fn upgrade(&mut self) -> bool {
     let protocol = unwrap_msg!(self.protocol.take());
 
     if let State::Expect(expect, ssl) = protocol {
       debug!("switching to TLS handshake");
-      if let Some(ref addresses) = expect.addresses {
-        if let (Some(public_address), Some(session_address)) = (addresses.destination(), addresses.source()) {
-          self.public_address = public_address;
-          self.peer_address = Some(session_address);
-
-          let ExpectProxyProtocol {
-            frontend, readiness, request_id, .. } = expect;
-
-          let mut tls = TlsHandshake::new(ssl, frontend, request_id);
-          tls.readiness.event = readiness.event;
-          tls.readiness.event.insert(Ready::readable());
-
-          gauge_add!("protocol.proxy.expect", -1);
-          gauge_add!("protocol.tls.handshake", 1);
-          self.protocol = Some(State::Handshake(tls));
-          return true;
-        }
+      if let Some((public_address, session_address)) = expect.session_addresses() {
+        self.public_address = public_address;
+        self.peer_address = Some(session_address);
+        self.proxy_protocol_client = Some(session_address.ip());
+
+        let ExpectProxyProtocol {
+          frontend, readiness, request_id, .. } = expect;
+
+        let mut tls = TlsHandshake::new(ssl, frontend, request_id, self.acme_challenges.clone());
+        tls.readiness.event = readiness.event;
+        tls.readiness.event.insert(Ready::readable());
+
+        gauge_add!("protocol.proxy.expect", -1);
+        gauge_add!("protocol.tls.handshake", 1);
+        self.protocol = Some(State::Handshake(tls));
+        return true;
       }
 
       error!("failed to upgrade from expect");
@@ -165,16 +230,46 @@ impl Session {
       handshake.session.get_negotiated_ciphersuite().map(|cipher| {
         incr!(ciphersuite_str(cipher));
       });
+      let mut tls_info = match (handshake.session.get_protocol_version(), handshake.session.get_negotiated_ciphersuite()) {
+        (Some(version), Some(cipher)) => Some(tls_info_headers(version, cipher.suite)),
+        _ => None,
+      };
+
+      // the certificate chain was already verified by the listener's client certificate verifier
+      let client_certificate = handshake.session.get_peer_certificates()
+        .and_then(|certificates| certificates.first().map(|certificate| ClientCertificate::from_der(&certificate.0)));
+      if let Some(ref certificate) = client_certificate {
+        info!("{}\tclient certificate: subject \"{}\", fingerprint {}",
+          handshake.request_id, certificate.subject, certificate.fingerprint);
+        tls_info = tls_info.map(|headers| headers + &certificate.headers());
+      }
 
+      let is_h2 = handshake.session.get_alpn_protocol() == Some(ALPN_H2);
       let front_stream = FrontRustls {
         stream:  handshake.stream,
         session: handshake.session,
       };
 
       let readiness = handshake.readiness.clone();
+      if is_h2 {
+        // the plaintext already decrypted by the session is read in the next readable call
+        let mut h2 = Http2::new(front_stream, self.frontend_token, handshake.request_id);
+        gauge_add!("protocol.tls.handshake", -1);
+        gauge_add!("protocol.http2", 1);
+        h2.readiness.event = readiness.event;
+        self.protocol = Some(State::Http2(h2));
+        return true;
+      }
+
       let mut http = Http::new(front_stream, self.frontend_token, handshake.request_id,
                                self.pool.clone(), self.public_address, self.peer_address,
-                               self.sticky_name.clone(), Protocol::HTTPS);
+                               self.sticky_name.clone(), self.preserve_proxy_connection,
+                               self.ignore_unsupported_expect, self.unexpected_body_policy,
+                               Protocol::HTTPS);
+      http.client_ip = self.client_ip.clone();
+      http.header_limits = self.header_limits;
+      http.set_max_keepalive_requests(self.max_keepalive_requests);
+      http.compression = self.compression.clone();
 
       let res = http.frontend.session.read(front_buf.space());
       match res {
@@ -196,6 +291,8 @@ impl Session {
       gauge_add!("protocol.tls.handshake", -1);
       gauge_add!("protocol.https", 1);
       http.front_buf = Some(buf);
+      http.tls_info_headers = tls_info;
+      http.client_certificate = client_certificate;
       http.front_readiness = readiness;
       http.front_readiness.interest = UnixReady::from(Ready::readable()) | UnixReady::hup() | UnixReady::error();
 
@@ -254,7 +351,8 @@ impl Session {
   fn front_hup(&mut self)     -> SessionResult {
     match *unwrap_msg!(self.protocol.as_mut()) {
       State::Http(ref mut http)      => http.front_hup(),
-      State::WebSocket(ref mut pipe) => pipe.front_hup(&mut self.metrics),
+      State::WebSocket(ref mut pipe) => pipe.front_hup(),
+      State::Http2(ref mut h2)       => h2.front_hup(),
       State::Handshake(_)            => {
         SessionResult::CloseSession
       },
@@ -267,7 +365,8 @@ impl Session {
   fn back_hup(&mut self)      -> SessionResult {
     match *unwrap_msg!(self.protocol.as_mut()) {
       State::Http(ref mut http)      => http.back_hup(),
-      State::WebSocket(ref mut pipe) => pipe.back_hup(&mut self.metrics),
+      State::WebSocket(ref mut pipe) => pipe.back_hup(),
+      State::Http2(_)                => SessionResult::CloseSession,
       State::Handshake(_)            => {
         error!("why a backend HUP event while still in frontend handshake?");
         SessionResult::CloseSession
@@ -293,6 +392,7 @@ impl Session {
       State::Handshake(ref mut handshake) => handshake.readable(),
       State::Http(ref mut http)           => (ProtocolResult::Continue, http.readable(&mut self.metrics)),
       State::WebSocket(ref mut pipe)      => (ProtocolResult::Continue, pipe.readable(&mut self.metrics)),
+      State::Http2(ref mut h2)            => (ProtocolResult::Continue, h2.readable(&mut self.metrics)),
     };
 
     if upgrade == ProtocolResult::Continue {
@@ -310,6 +410,7 @@ impl Session {
       State::Handshake(ref mut handshake) => handshake.writable(),
       State::Http(ref mut http)           => (ProtocolResult::Continue, http.writable(&mut self.metrics)),
       State::WebSocket(ref mut pipe)      => (ProtocolResult::Continue, pipe.writable(&mut self.metrics)),
+      State::Http2(ref mut h2)            => (ProtocolResult::Continue, h2.writable(&mut self.metrics)),
     };
 
     if upgrade == ProtocolResult::Continue {
@@ -330,9 +431,19 @@ impl Session {
       State::Expect(_,_)             => return SessionResult::CloseSession,
       State::Http(ref mut http)      => http.back_readable(&mut self.metrics),
       State::Handshake(_)            => (ProtocolResult::Continue, SessionResult::CloseSession),
+      State::Http2(_)                => (ProtocolResult::Continue, SessionResult::CloseSession),
       State::WebSocket(ref mut pipe) => (ProtocolResult::Continue, pipe.back_readable(&mut self.metrics)),
     };
 
+    // the backend broke its response, the request goes to another one
+    if let SessionResult::ReconnectBackend(_,_) = result {
+      if self.http_mut().map(|http| http.take_backend_failure()).unwrap_or(false) {
+        if let Some(backend_id) = self.backend.as_ref().map(|backend| backend.borrow().backend_id.clone()) {
+          self.tried_backends.push(backend_id);
+        }
+      }
+    }
+
     if upgrade == ProtocolResult::Continue {
       result
     } else if self.upgrade() {
@@ -349,6 +460,7 @@ impl Session {
     match *unwrap_msg!(self.protocol.as_mut()) {
       State::Expect(_,_)                  => SessionResult::CloseSession,
       State::Handshake(_)                 => SessionResult::CloseSession,
+      State::Http2(_)                     => SessionResult::CloseSession,
       State::Http(ref mut http)           => http.back_writable(&mut self.metrics),
       State::WebSocket(ref mut pipe)      => pipe.back_writable(&mut self.metrics),
     }
@@ -360,6 +472,7 @@ impl Session {
       State::Handshake(ref handshake) => &handshake.stream,
       State::Http(ref http)           => http.front_socket(),
       State::WebSocket(ref pipe)      => pipe.front_socket(),
+      State::Http2(ref h2)            => h2.front_socket(),
     }
   }
 
@@ -367,6 +480,7 @@ impl Session {
     match unwrap_msg!(self.protocol.as_ref()) {
       State::Expect(_,_)         => None,
       State::Handshake(_)        => None,
+      State::Http2(_)            => None,
       State::Http(ref http)      => http.back_socket(),
       State::WebSocket(ref pipe) => pipe.back_socket(),
     }
@@ -376,6 +490,7 @@ impl Session {
     match unwrap_msg!(self.protocol.as_ref()) {
       State::Expect(_,_)         => None,
       State::Handshake(_)        => None,
+      State::Http2(_)            => None,
       State::Http(ref http)      => http.back_token(),
       State::WebSocket(ref pipe) => pipe.back_token(),
     }
@@ -408,9 +523,12 @@ impl Session {
         let backend = &mut (*backend.borrow_mut());
         let was_unavailable = backend.retry_policy.is_down();
 
-        //successful connection, reset failure counter
-        backend.failures = 0;
-        backend.retry_policy.succeed();
+        //successful connection, reset failure counter, unless the failures
+        //come from its answers
+        if !backend.failing_answers {
+          backend.failures = 0;
+          backend.retry_policy.succeed();
+        }
 
         if was_unavailable {
             incr!("up", self.app_id.as_ref().map(|s| s.as_str()), self.metrics.backend_id.as_ref().map(|s| s.as_str()));
@@ -424,10 +542,10 @@ impl Session {
   }
 
   fn remove_backend(&mut self) {
-    if let Some(backend) = self.backend.take() {
+    if let Some(mut backend) = self.backend.take() {
       self.http_mut().map(|h| h.clear_back_token());
 
-      (*backend.borrow_mut()).dec_connections();
+      backend.close();
     }
   }
 
@@ -437,6 +555,7 @@ impl Session {
       State::Handshake(ref mut handshake) => &mut handshake.readiness,
       State::Http(ref mut http)           => http.front_readiness(),
       State::WebSocket(ref mut pipe)      => &mut pipe.front_readiness,
+      State::Http2(ref mut h2)            => &mut h2.readiness,
     }
   }
 
@@ -452,6 +571,8 @@ impl Session {
     self.backend.as_ref().map(|backend| {
       let ref mut backend = *backend.borrow_mut();
       backend.failures += 1;
+      backend.last_failure = Some(SteadyTime::now());
+      backend.record_outcome(true, SteadyTime::now());
 
       let already_unavailable = backend.retry_policy.is_down();
       backend.retry_policy.fail();
@@ -465,8 +586,14 @@ impl Session {
     });
   }
 
-  fn reset_connection_attempt(&mut self) {
+  pub fn reset_connection_attempt(&mut self) {
     self.connection_attempt = 0;
+    self.tried_backends.clear();
+  }
+
+  pub fn is_idempotent_request(&self) -> bool {
+    self.http().and_then(|http| http.request.as_ref()).and_then(|request| request.get_request_line())
+      .map(|request_line| request_line.method.is_idempotent()).unwrap_or(true)
   }
 }
 
@@ -506,6 +633,7 @@ impl ProxySession for Session {
       Some(State::Handshake(_)) => gauge_add!("protocol.tls.handshake", -1),
       Some(State::Http(_)) => gauge_add!("protocol.https", -1),
       Some(State::WebSocket(_)) => gauge_add!("protocol.wss", -1),
+      Some(State::Http2(_)) => gauge_add!("protocol.http2", -1),
       None => {},
     }
 
@@ -514,11 +642,34 @@ impl ProxySession for Session {
     result
   }
 
-  fn timeout(&mut self, token: Token, timer: &mut Timer<Token>, front_timeout: &Duration) -> SessionResult {
+  fn timeout(&mut self, token: Token, timer: &mut Timer<Token>, front_timeout: &Duration,
+    idle_timeout: Option<&Duration>, header_timeout: Option<&Duration>, connect_timeout: &Duration) -> SessionResult {
     if self.frontend_token == token {
-      let dur = SteadyTime::now() - self.last_event;
+      let now = SteadyTime::now();
+      self.metrics.update_activity(self.last_event);
+      if idle_timeout.map(|t| self.metrics.is_idle_at(*t, now)).unwrap_or(false) {
+        info!("{}\tclosing idle session", self.log_context());
+        incr!("idle_timeout");
+        return SessionResult::CloseSession;
+      }
+
+      // the client keeps sending data, but not the complete header block
+      let header_time_left = header_timeout.and_then(|t| self.http().and_then(|h| h.header_time_left(*t, now)));
+      if header_time_left.map(|left| left <= Duration::zero()).unwrap_or(false) {
+        info!("{}\trequest headers not received in time, closing", self.log_context());
+        incr!("http.header_timeout");
+        let answer = self.answers.borrow().get(DefaultAnswerStatus::Answer408, None);
+        self.set_answer(DefaultAnswerStatus::Answer408, answer);
+        return self.writable();
+      }
+
+      let dur = now - self.last_event;
       if dur < *front_timeout {
-        timer.set_timeout((*front_timeout - dur).to_std().unwrap(), token);
+        let mut delay = self.metrics.next_timeout_at(*front_timeout - dur, idle_timeout, now);
+        if let Some(header_timeout) = header_timeout {
+          delay = ::std::cmp::min(delay, header_time_left.unwrap_or(*header_timeout));
+        }
+        timer.set_timeout(delay.to_std().unwrap(), token);
         SessionResult::Continue
       } else {
         match self.http().map(|h| h.timeout_status()) {
@@ -537,8 +688,30 @@ impl ProxySession for Session {
           }
         }
       }
+    } else if self.back_token() == Some(token) && self.response_time_left()
+      .map(|left| left <= Duration::zero()).unwrap_or(false) {
+      error!("{} the backend did not answer before the response timeout of the application", self.log_context());
+      incr!("backend.response_timeout");
+      // the client already got part of the response, it cannot get an error answer
+      if self.http().map(|h| h.response_started()).unwrap_or(true) {
+        return SessionResult::CloseSession;
+      }
+      let answer = self.answers.borrow().get(DefaultAnswerStatus::Answer504, None);
+      self.set_answer(DefaultAnswerStatus::Answer504, answer);
+      self.writable()
+    } else if self.back_token() == Some(token) && self.back_connected() == BackendConnectionStatus::Connecting {
+      // the timer could have been set for a previous connection attempt
+      if !self.metrics.is_connect_timed_out_at(*connect_timeout, SteadyTime::now()) {
+        return SessionResult::Continue;
+      }
+
+      error!("{} timeout connecting to backend, trying again", self.log_context());
+      incr!("backend.connect_timeout");
+      self.connection_attempt += 1;
+      self.fail_backend_connection();
+      SessionResult::ReconnectBackend(Some(self.frontend_token), Some(token))
     } else {
-      //invalid token, obsolete timeout triggered
+      // invalid token, obsolete timeout triggered
       SessionResult::Continue
     }
   }
@@ -548,6 +721,9 @@ impl ProxySession for Session {
   }
 
   fn close_backend(&mut self, _: Token, poll: &mut Poll) {
+    if self.back_connected() == BackendConnectionStatus::Connected {
+      self.pool_backend_connection(poll);
+    }
     self.remove_backend();
 
     let back_connected = self.back_connected();
@@ -583,6 +759,7 @@ impl ProxySession for Session {
 
   fn process_events(&mut self, token: Token, events: Ready) {
     trace!("token {:?} got event {}", token, super::super::unix_ready_to_string(UnixReady::from(events)));
+    self.metrics.update_activity(self.last_event);
     self.last_event = SteadyTime::now();
     self.metrics.wait_start();
 
@@ -626,6 +803,10 @@ impl ProxySession for Session {
         SessionResult::CloseSession => {
           return order;
         },
+        SessionResult::Continue => {
+          // a half closed frontend can still receive data
+          self.front_readiness().event.remove(UnixReady::hup());
+        },
         _ => {
           self.front_readiness().event.remove(UnixReady::hup());
           return order;
@@ -728,6 +909,14 @@ impl ProxySession for Session {
     SessionResult::Continue
   }
 
+  fn back_connection_status(&self) -> BackendConnectionStatus {
+    self.back_connected
+  }
+
+  fn response_time_left(&self) -> Option<Duration> {
+    self.http().and_then(|http| http.response_time_left(SteadyTime::now()))
+  }
+
   fn shutting_down(&mut self) -> SessionResult {
     match &mut self.protocol {
       Some(State::Http(h)) => h.shutting_down(),
@@ -736,6 +925,11 @@ impl ProxySession for Session {
     }
   }
 
+  fn take_proxy_protocol_client(&mut self) -> Option<(ListenToken, IpAddr)> {
+    let listen_token = ListenToken(self.listen_token.0);
+    self.proxy_protocol_client.take().map(|ip| (listen_token, ip))
+  }
+
   fn last_event(&self) -> SteadyTime {
     self.last_event
   }
@@ -746,6 +940,7 @@ impl ProxySession for Session {
       Some(State::Handshake(_)) => String::from("Handshake"),
       Some(State::Http(h))      => h.print_state("HTTPS"),
       Some(State::WebSocket(_)) => String::from("WSS"),
+      Some(State::Http2(_))     => String::from("HTTP2"),
       None                      => String::from("None"),
     };
 
@@ -754,6 +949,7 @@ impl ProxySession for Session {
       State::Handshake(ref handshake) => &handshake.readiness,
       State::Http(ref http)           => &http.front_readiness,
       State::WebSocket(ref pipe)      => &pipe.front_readiness,
+      State::Http2(ref h2)            => &h2.readiness,
     };
 
     error!("zombie session[{:?} => {:?}], state => readiness: {:?}, protocol: {}, app_id: {:?}, back_connected: {:?}, metrics: {:?}",
@@ -782,6 +978,12 @@ fn version_str(version: ProtocolVersion) -> &'static str {
   }
 }
 
+/// TLS version and cipher suite names, as in "TLSv1.3" and "TLS13_AES_128_GCM_SHA256"
+fn tls_info_headers(version: ProtocolVersion, suite: CipherSuite) -> String {
+  let version = version_str(version).trim_start_matches("tls.version.").replace('_', ".");
+  http::tls_info_headers(&version, &format!("{:?}", suite))
+}
+
 fn ciphersuite_str(cipher: &'static SupportedCipherSuite) -> &'static str {
   match cipher.suite {
     CipherSuite::TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256 => "tls.cipher.TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256",
@@ -801,6 +1003,14 @@ fn ciphersuite_str(cipher: &'static SupportedCipherSuite) -> &'static str {
 mod tests {
   use super::*;
 
+  #[test]
+  fn tls_info_from_handshake() {
+    assert_eq!(tls_info_headers(ProtocolVersion::TLSv1_3, CipherSuite::TLS13_AES_128_GCM_SHA256),
+      "X-Forwarded-TLS-Version: TLSv1.3\r\nX-Forwarded-TLS-Cipher: TLS13_AES_128_GCM_SHA256\r\n");
+    assert_eq!(tls_info_headers(ProtocolVersion::TLSv1_2, CipherSuite::TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384),
+      "X-Forwarded-TLS-Version: TLSv1.2\r\nX-Forwarded-TLS-Cipher: TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384\r\n");
+  }
+
   /*
   #[test]
   #[cfg(target_pointer_width = "64")]
@@ -179,6 +179,7 @@ extern crate quickcheck;
 #[cfg(feature = "use-openssl")]
 extern crate openssl_sys;
 extern crate iovec;
+extern crate flate2;
 
 #[macro_use] pub mod util;
 #[macro_use] pub mod metrics;
@@ -191,8 +192,17 @@ pub mod protocol;
 pub mod http;
 pub mod backends;
 pub mod retry;
+pub mod outlier;
+pub mod retry_budget;
+pub mod request_limit;
 pub mod load_balancing;
 pub mod features;
+pub mod tracing;
+pub mod fd_limit;
+pub mod rate_limit;
+pub mod session_limit;
+pub mod backend_pool;
+pub mod traffic_split;
 
 #[cfg(feature = "splice")]
 mod splice;
@@ -210,13 +220,15 @@ use mio::unix::UnixReady;
 use mio::net::TcpStream;
 use std::fmt;
 use std::str;
-use std::net::SocketAddr;
+use std::io;
+use std::net::{IpAddr,SocketAddr,ToSocketAddrs};
 use std::rc::Rc;
+use std::ops::Deref;
 use std::cell::RefCell;
 use time::{SteadyTime,Duration};
 use mio_extras::timer::{Timer,Timeout};
 
-use sozu_command::proxy::{ProxyRequest,ProxyResponse,LoadBalancingParams};
+use sozu_command::proxy::{ProxyRequest,ProxyResponse,ProxyEvent,LoadBalancingParams,ListenerTimeouts,ConnectionRateLimit};
 
 use self::retry::RetryPolicy;
 
@@ -246,12 +258,27 @@ pub trait ProxySession {
   fn process_events(&mut self, token: Token, events: Ready);
   fn close(&mut self, poll: &mut Poll) -> CloseResult;
   fn close_backend(&mut self, token: Token, poll: &mut Poll);
-  fn timeout(&mut self, t: Token, timer: &mut Timer<Token>, front_timeout: &Duration) -> SessionResult;
+  fn timeout(&mut self, t: Token, timer: &mut Timer<Token>, front_timeout: &Duration,
+    idle_timeout: Option<&Duration>, header_timeout: Option<&Duration>, connect_timeout: &Duration) -> SessionResult;
   fn cancel_timeouts(&self, timer: &mut Timer<Token>);
   fn last_event(&self) -> SteadyTime;
   fn print_state(&self);
   fn tokens(&self) -> Vec<Token>;
   fn shutting_down(&mut self) -> SessionResult;
+  /// client address conveyed by the PROXY protocol header, with the listener that
+  /// accepted the session. It is returned once, after the header was parsed
+  fn take_proxy_protocol_client(&mut self) -> Option<(ListenToken, IpAddr)> {
+    None
+  }
+  /// state of the connection to the backend, counted in the session gauges
+  fn back_connection_status(&self) -> BackendConnectionStatus {
+    BackendConnectionStatus::NotConnected
+  }
+  /// time left to the backend to answer the current request, when the
+  /// application of the request has a response timeout
+  fn response_time_left(&self) -> Option<Duration> {
+    None
+  }
 }
 
 #[derive(Clone,Copy,Debug,PartialEq)]
@@ -266,6 +293,9 @@ pub enum BackendConnectAction {
   New,
   Reuse,
   Replace,
+  /// the request waits for a slot in the concurrency limit of its application,
+  /// the session tries again once it gets one
+  Queued,
 }
 
 #[derive(Debug,PartialEq)]
@@ -284,6 +314,17 @@ pub trait ProxyConfiguration<Session> {
   fn create_session(&mut self, socket: TcpStream, token: ListenToken, event_loop: &mut Poll, session_token: Token, timeout: Timeout, delay: Duration)
     -> Result<(Rc<RefCell<Session>>, bool), AcceptError>;
   fn listen_port_state(&self, port: &u16) -> ListenPortState;
+  /// timeouts configured on the listener, overriding the global ones
+  fn listener_timeouts(&self, token: ListenToken) -> ListenerTimeouts;
+  /// limit on the new connections accepted on the listener
+  fn listener_rate_limit(&self, token: ListenToken) -> Option<ConnectionRateLimit>;
+  /// true if the listener's sessions convey their client address in a PROXY protocol
+  /// header, the per client rate limit is then checked once the header is parsed
+  fn listener_expects_proxy_protocol(&self, token: ListenToken) -> bool;
+  /// maximum number of concurrent sessions on the listener
+  fn listener_max_sessions(&self, token: ListenToken) -> Option<usize>;
+  /// stops or resumes the readable events of the listener's socket
+  fn pause_listener(&mut self, event_loop: &mut Poll, token: ListenToken, paused: bool);
 }
 
 #[derive(Debug,PartialEq,Eq)]
@@ -381,8 +422,29 @@ pub enum ConnectionError {
   InvalidHost,
   HostNotFound,
   NoBackendAvailable,
-  ToBeDefined,
-  HttpsRedirect
+  HttpsRedirect,
+  PayloadTooLarge,
+  ApplicationDisabled,
+  /// the application has too many requests in flight and its queue is full
+  RequestQueueFull,
+}
+
+impl ConnectionError {
+  /// name of the counter incremented when a session fails with this error, to
+  /// tell routing errors from backend outages
+  pub fn metric_name(&self) -> &'static str {
+    match *self {
+      ConnectionError::NoHostGiven        => "connection_errors.no_host_given",
+      ConnectionError::NoRequestLineGiven => "connection_errors.no_request_line_given",
+      ConnectionError::InvalidHost        => "connection_errors.invalid_host",
+      ConnectionError::HostNotFound       => "connection_errors.host_not_found",
+      ConnectionError::NoBackendAvailable => "connection_errors.no_backend_available",
+      ConnectionError::HttpsRedirect      => "connection_errors.https_redirect",
+      ConnectionError::PayloadTooLarge    => "connection_errors.payload_too_large",
+      ConnectionError::ApplicationDisabled => "connection_errors.application_disabled",
+      ConnectionError::RequestQueueFull   => "connection_errors.request_queue_full",
+    }
+  }
 }
 
 #[derive(Debug,PartialEq,Eq)]
@@ -407,34 +469,160 @@ pub struct Backend {
   pub retry_policy:              retry::RetryPolicyWrapper,
   pub active_connections:        usize,
   pub failures:                  usize,
+  /// time of the last failed connection attempt
+  pub last_failure:              Option<SteadyTime>,
   pub load_balancing_parameters: Option<LoadBalancingParams>,
   pub backup:                    bool,
+  /// exponentially weighted moving average of the response times, in microseconds
+  pub response_time:             Option<u64>,
+  /// "host:port" resolved again when connecting, at most every `RESOLUTION_INTERVAL`
+  pub hostname:                  Option<String>,
+  pub last_resolution:           Option<SteadyTime>,
+  /// outcomes of the last requests, to eject the backend if too many failed
+  pub outlier:                   outlier::OutlierStats,
+  /// the last answer had one of the failure statuses of its application, the
+  /// failures are not reset by the next successful connection
+  pub failing_answers:           bool,
 }
 
+/// minimum duration in seconds between two resolutions of a backend's hostname.
+/// The resolution blocks the event loop, so it must not happen on every connection
+pub const RESOLUTION_INTERVAL: i64 = 30;
+
 impl Backend {
   pub fn new(backend_id: &str, address: SocketAddr, sticky_id: Option<String>, load_balancing_parameters: Option<LoadBalancingParams>, backup: Option<bool>) -> Backend {
-    let desired_policy = retry::ExponentialBackoffPolicy::new(6);
     Backend {
       sticky_id,
       backend_id:         backend_id.to_string(),
       address,
       status:             BackendStatus::Normal,
-      retry_policy:       desired_policy.into(),
+      retry_policy:       retry::default_policy(),
       active_connections: 0,
       failures:           0,
+      last_failure:       None,
       load_balancing_parameters,
       backup: backup.unwrap_or(false),
+      response_time:      None,
+      hostname:           None,
+      last_resolution:    None,
+      outlier:            outlier::OutlierStats::new(),
+      failing_answers:    false,
+    }
+  }
+
+  /// resolves the hostname again if the last resolution is too old, and
+  /// connects to the first address found. A resolution failure counts as
+  /// a connection failure
+  pub fn refresh_address<F>(&mut self, now: SteadyTime, resolve: F) -> Result<(), ConnectionError>
+    where F: FnOnce(&str) -> io::Result<Vec<SocketAddr>> {
+    let hostname = match self.hostname {
+      Some(ref hostname) => hostname.clone(),
+      None               => return Ok(()),
+    };
+
+    if self.last_resolution.map(|last| now - last < Duration::seconds(RESOLUTION_INTERVAL)).unwrap_or(false) {
+      return Ok(());
+    }
+
+    match resolve(&hostname).map(|addresses| addresses.into_iter().next()) {
+      Ok(Some(address)) => {
+        if address != self.address {
+          info!("backend {} ({}) now resolves to {}", self.backend_id, hostname, address);
+          self.address = address;
+        }
+        self.last_resolution = Some(now);
+        Ok(())
+      },
+      res => {
+        error!("could not resolve {} for backend {}: {:?}", hostname, self.backend_id, res);
+        self.retry_policy.fail();
+        self.failures += 1;
+        self.last_failure = Some(now);
+        Err(ConnectionError::NoBackendAvailable)
+      },
     }
   }
 
+  /// stops opening new connections to this backend. It is closed right away
+  /// if it has no active connections, otherwise once the last one ends
   pub fn set_closing(&mut self) {
-    self.status = BackendStatus::Closing;
+    self.status = if self.active_connections == 0 {
+      BackendStatus::Closed
+    } else {
+      BackendStatus::Closing
+    };
+  }
+
+  /// forgets the connection failures, the retry policy does not wait before
+  /// the next try anymore and an ejected backend is readmitted. The status
+  /// is not changed, a drained backend stays drained
+  pub fn reset(&mut self) {
+    self.retry_policy.succeed();
+    self.failures = 0;
+    self.last_failure = None;
+    self.outlier = outlier::OutlierStats::new();
+    self.failing_answers = false;
+  }
+
+  /// counts an answer with one of the failure statuses of the application like
+  /// a failed connection
+  pub fn fail_answer(&mut self, now: SteadyTime) {
+    self.failures += 1;
+    self.last_failure = Some(now);
+    self.failing_answers = true;
+
+    let already_unavailable = self.retry_policy.is_down();
+    self.retry_policy.fail();
+    if !already_unavailable && self.retry_policy.is_down() {
+      error!("backend server {} at {} is down: {} failed answers", self.backend_id, self.address, self.failures);
+      server::push_event(ProxyEvent::BackendDown(self.backend_id.clone(), self.address));
+    }
+  }
+
+  /// a good answer after failed ones resets the failures
+  pub fn succeed_answer(&mut self) {
+    if self.failing_answers {
+      self.failing_answers = false;
+      self.failures = 0;
+      self.retry_policy.succeed();
+    }
+  }
+
+  /// counts the outcome of a request for the outlier detection, if it is
+  /// enabled. A failed connection or a 5xx answer is an error
+  pub fn record_outcome(&mut self, error: bool, now: SteadyTime) {
+    if let Some(config) = outlier::outlier_detection() {
+      if self.outlier.record(&config, error, now) {
+        error!("backend server {} at {} is ejected for {} seconds: too many failed requests",
+          self.backend_id, self.address, config.ejection_time);
+        incr!("backend.ejected");
+        server::push_event(ProxyEvent::BackendEjected(self.backend_id.clone(), self.address));
+      }
+    }
+  }
+
+  pub fn is_ejected(&self, now: SteadyTime) -> bool {
+    self.outlier.is_ejected(now)
   }
 
   pub fn retry_policy(&mut self) -> &mut retry::RetryPolicyWrapper {
     &mut self.retry_policy
   }
 
+  /// value of the sticky session cookie pinning a client to this backend
+  pub fn sticky_session_id(&self) -> &str {
+    self.sticky_id.as_ref().map(|s| s.as_str()).unwrap_or(&self.backend_id)
+  }
+
+  /// adds a response time to the moving average, a new sample weighing for a fifth
+  pub fn record_response_time(&mut self, response_time: Duration) {
+    let sample = response_time.num_microseconds().unwrap_or(i64::max_value()).max(0) as u64;
+    self.response_time = Some(match self.response_time {
+      None          => sample,
+      Some(average) => (average.saturating_mul(4)).saturating_add(sample) / 5,
+    });
+  }
+
   pub fn can_open(&self) -> bool {
     if let Some(action) = self.retry_policy.can_try() {
       self.status == BackendStatus::Normal && action == retry::RetryAction::OKAY
@@ -452,19 +640,23 @@ impl Backend {
     }
   }
 
+  /// a call without active connections is a bug in the accounting: it is
+  /// logged, and neither the count nor the status change
   pub fn dec_connections(&mut self) -> Option<usize> {
+    if self.active_connections == 0 {
+      error!("backend {} at {}: closing a connection while there is no active connection",
+        self.backend_id, self.address);
+      return if self.status == BackendStatus::Normal { Some(0) } else { None };
+    }
+
     match self.status {
       BackendStatus::Normal => {
-        if self.active_connections > 0 {
-          self.active_connections -= 1;
-        }
+        self.active_connections -= 1;
         Some(self.active_connections)
       }
       BackendStatus::Closed  => None,
       BackendStatus::Closing => {
-        if self.active_connections > 0 {
-          self.active_connections -= 1;
-        }
+        self.active_connections -= 1;
         if self.active_connections == 0 {
           self.status = BackendStatus::Closed;
           None
@@ -475,25 +667,71 @@ impl Backend {
     }
   }
 
-  pub fn try_connect(&mut self) -> Result<mio::tcp::TcpStream, ConnectionError> {
+  pub fn try_connect(&mut self, socket_config: &socket::SocketConfig) -> Result<mio::tcp::TcpStream, ConnectionError> {
     if self.status != BackendStatus::Normal {
       return Err(ConnectionError::NoBackendAvailable);
     }
 
+    self.refresh_address(SteadyTime::now(), |hostname| hostname.to_socket_addrs().map(|addresses| addresses.collect()))?;
+
     //FIXME: what happens if the connect() call fails with EINPROGRESS?
     let conn = mio::tcp::TcpStream::connect(&self.address).map_err(|_| ConnectionError::NoBackendAvailable);
-    if conn.is_ok() {
+    if let Ok(ref stream) = conn {
       //self.retry_policy.succeed();
       self.inc_connections();
+      if let Err(e) = socket::configure_socket(stream, socket_config) {
+        error!("could not set the options of the socket to backend {}: {:?}", self.address, e);
+      }
     } else {
       self.retry_policy.fail();
       self.failures += 1;
+      self.last_failure = Some(SteadyTime::now());
     }
 
     conn
   }
 }
 
+/// a connection counted in the `active_connections` of its backend. The count
+/// is decremented once, by `close` or when the guard is dropped, however many
+/// times the session closes it
+#[derive(Debug)]
+pub struct ConnectionGuard {
+  backend: Rc<RefCell<Backend>>,
+  closed:  bool,
+}
+
+impl ConnectionGuard {
+  /// the connection must already be counted by `inc_connections`
+  pub fn new(backend: Rc<RefCell<Backend>>) -> ConnectionGuard {
+    ConnectionGuard { backend, closed: false }
+  }
+
+  /// returns the connections left like `dec_connections`, and None if it
+  /// was already closed
+  pub fn close(&mut self) -> Option<usize> {
+    if self.closed {
+      return None;
+    }
+    self.closed = true;
+    self.backend.borrow_mut().dec_connections()
+  }
+}
+
+impl Deref for ConnectionGuard {
+  type Target = Rc<RefCell<Backend>>;
+
+  fn deref(&self) -> &Rc<RefCell<Backend>> {
+    &self.backend
+  }
+}
+
+impl Drop for ConnectionGuard {
+  fn drop(&mut self) {
+    self.close();
+  }
+}
+
 #[derive(Clone)]
 pub struct Readiness {
   pub event:    UnixReady,
@@ -569,6 +807,8 @@ pub struct SessionMetrics {
   /// date at which we started working on the request
   pub service_start: Option<SteadyTime>,
   pub wait_start:    SteadyTime,
+  /// date at which the request line was received
+  pub request_start: Option<SteadyTime>,
 
   pub backend_id:    Option<String>,
   pub backend_start: Option<SteadyTime>,
@@ -576,6 +816,11 @@ pub struct SessionMetrics {
   pub backend_stop:  Option<SteadyTime>,
   pub backend_bin:   usize,
   pub backend_bout:  usize,
+
+  /// last time bytes were transferred on the frontend or backend socket
+  pub last_activity: SteadyTime,
+  /// bytes transferred at the last activity check
+  activity_bytes:    usize,
 }
 
 impl SessionMetrics {
@@ -588,22 +833,32 @@ impl SessionMetrics {
       bout:          0,
       service_start: None,
       wait_start:    SteadyTime::now(),
+      request_start: None,
       backend_id:    None,
       backend_start: None,
       backend_connected: None,
       backend_stop:  None,
       backend_bin:   0,
       backend_bout:  0,
+      last_activity: SteadyTime::now(),
+      activity_bytes: 0,
     }
   }
 
   pub fn reset(&mut self) {
+    // the byte counters are reset during the handling of an event
+    if self.transferred() != self.activity_bytes {
+      self.last_activity = SteadyTime::now();
+    }
+    self.activity_bytes = 0;
+
     self.start         = None;
     self.service_time  = Duration::seconds(0);
     self.wait_time     = Duration::seconds(0);
     self.bin           = 0;
     self.bout          = 0;
     self.service_start = None;
+    self.request_start = None;
     self.backend_start = None;
     self.backend_connected = None;
     self.backend_stop  = None;
@@ -645,6 +900,21 @@ impl SessionMetrics {
     }
   }
 
+  pub fn request_start(&mut self) {
+    if self.request_start.is_none() {
+      self.request_start = Some(SteadyTime::now());
+    }
+  }
+
+  /// time elapsed since the request line was received. Unlike the response time,
+  /// it does not include the connection setup or the wait for the request
+  pub fn latency(&self) -> Duration {
+    match self.request_start {
+      Some(start) => SteadyTime::now() - start,
+      None        => self.response_time(),
+    }
+  }
+
   pub fn response_time(&self) -> Duration {
     match self.start {
       Some(start) => SteadyTime::now() - start,
@@ -674,6 +944,42 @@ impl SessionMetrics {
     }
   }
 
+  pub fn transferred(&self) -> usize {
+    self.bin + self.bout + self.backend_bin + self.backend_bout
+  }
+
+  /// the bytes transferred since the last call were transferred while
+  /// handling the last event, so that event is the last activity
+  pub fn update_activity(&mut self, last_event: SteadyTime) {
+    let transferred = self.transferred();
+    if transferred != self.activity_bytes {
+      self.activity_bytes = transferred;
+      self.last_activity = last_event;
+    }
+  }
+
+  pub fn is_idle_at(&self, idle_timeout: Duration, now: SteadyTime) -> bool {
+    now - self.last_activity >= idle_timeout
+  }
+
+  /// delay until the next timeout check: the front timeout delay, or the
+  /// idle timeout if it expires earlier
+  pub fn next_timeout_at(&self, front_delay: Duration, idle_timeout: Option<&Duration>, now: SteadyTime) -> Duration {
+    match idle_timeout {
+      Some(idle_timeout) => ::std::cmp::min(front_delay, self.last_activity + *idle_timeout - now),
+      None               => front_delay,
+    }
+  }
+
+  /// true if the backend connection started `connect_timeout` ago
+  /// or more, and is not established yet
+  pub fn is_connect_timed_out_at(&self, connect_timeout: Duration, now: SteadyTime) -> bool {
+    match (self.backend_start, self.backend_connected) {
+      (Some(start), None) => now - start >= connect_timeout,
+      _                   => false,
+    }
+  }
+
   pub fn backend_connection_time(&self) -> Option<Duration> {
     match (self.backend_start, self.backend_connected) {
       (Some(start), Some(end)) => {
@@ -711,3 +1017,178 @@ impl fmt::Display for LogDuration {
   }
 }
 
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn idle_timeout() {
+    let mut metrics = SessionMetrics::new(None);
+    let start = metrics.last_activity;
+    let idle_timeout = Duration::seconds(30);
+
+    assert!(!metrics.is_idle_at(idle_timeout, start + Duration::seconds(29)));
+    assert!(metrics.is_idle_at(idle_timeout, start + Duration::seconds(30)));
+
+    // events without data do not count as activity
+    metrics.update_activity(start + Duration::seconds(20));
+    assert!(metrics.is_idle_at(idle_timeout, start + Duration::seconds(30)));
+
+    metrics.bin += 100;
+    metrics.update_activity(start + Duration::seconds(20));
+    assert!(!metrics.is_idle_at(idle_timeout, start + Duration::seconds(49)));
+    assert!(metrics.is_idle_at(idle_timeout, start + Duration::seconds(50)));
+    assert_eq!(metrics.next_timeout_at(Duration::seconds(60), Some(&idle_timeout), start + Duration::seconds(40)),
+      Duration::seconds(10));
+    assert_eq!(metrics.next_timeout_at(Duration::seconds(5), Some(&idle_timeout), start + Duration::seconds(40)),
+      Duration::seconds(5));
+    assert_eq!(metrics.next_timeout_at(Duration::seconds(60), None, start + Duration::seconds(40)),
+      Duration::seconds(60));
+
+    // a new request on a keep-alive connection resets the counters
+    metrics.backend_bout += 100;
+    metrics.reset();
+    metrics.bout += 10;
+    metrics.update_activity(start + Duration::seconds(60));
+    assert!(!metrics.is_idle_at(idle_timeout, start + Duration::seconds(89)));
+    assert!(metrics.is_idle_at(idle_timeout, start + Duration::seconds(90)));
+  }
+
+  #[test]
+  fn retry_policy_defaults() {
+    use retry::RetryPolicy;
+    use sozu_command::proxy::RetryPolicyDefaults;
+
+    let address = "127.0.0.1:1024".parse().unwrap();
+    let mut existing = Backend::new("app-0", address, None, None, None);
+    assert_eq!(existing.retry_policy().max_tries(), retry::DEFAULT_MAX_TRIES);
+
+    assert!(retry::set_default_policy(&RetryPolicyDefaults { max_tries: 0, max_interval: None }).is_err());
+    assert!(retry::set_default_policy(&RetryPolicyDefaults { max_tries: 2, max_interval: Some(0) }).is_err());
+    assert!(retry::set_default_policy(&RetryPolicyDefaults { max_tries: 2, max_interval: Some(30) }).is_ok());
+
+    let added = Backend::new("app-1", address, None, None, None);
+    assert_eq!(added.retry_policy.max_tries(), 2);
+    assert_eq!(added.retry_policy.max_interval(), Some(::std::time::Duration::from_secs(30)));
+    // backends created before the change keep their policy
+    assert_eq!(existing.retry_policy().max_tries(), retry::DEFAULT_MAX_TRIES);
+  }
+
+  #[test]
+  fn hostname_resolution() {
+    use retry::RetryPolicy;
+
+    let mut backend = Backend::new("app-0", "127.0.0.1:1024".parse().unwrap(), None, None, None);
+    backend.hostname = Some(String::from("backend.example.com:8080"));
+    let now = SteadyTime::now();
+
+    assert_eq!(backend.refresh_address(now, |hostname| {
+      assert_eq!(hostname, "backend.example.com:8080");
+      Ok(vec!["10.0.0.2:8080".parse().unwrap(), "10.0.0.3:8080".parse().unwrap()])
+    }), Ok(()));
+    assert_eq!(backend.address, "10.0.0.2:8080".parse().unwrap());
+    assert_eq!(backend.last_resolution, Some(now));
+
+    // not resolved again before the interval
+    let later = now + Duration::seconds(RESOLUTION_INTERVAL - 1);
+    assert_eq!(backend.refresh_address(later, |_| panic!("should not resolve")), Ok(()));
+
+    let later = now + Duration::seconds(RESOLUTION_INTERVAL);
+    assert_eq!(backend.refresh_address(later, |_| Ok(vec!["10.0.0.4:8080".parse().unwrap()])), Ok(()));
+    assert_eq!(backend.address, "10.0.0.4:8080".parse().unwrap());
+    assert_eq!(backend.failures, 0);
+    assert_eq!(backend.retry_policy.current_tries(), 0);
+
+    // the resolution runs and fails, the backend keeps its last address
+    let later = later + Duration::seconds(RESOLUTION_INTERVAL);
+    assert_eq!(backend.refresh_address(later, |_| Err(io::Error::new(io::ErrorKind::Other, "no such host"))),
+      Err(ConnectionError::NoBackendAvailable));
+    assert_eq!(backend.address, "10.0.0.4:8080".parse().unwrap());
+    assert_eq!(backend.failures, 1);
+    assert_eq!(backend.last_failure, Some(later));
+    assert_eq!(backend.retry_policy.current_tries(), 1);
+
+    // an empty answer is a failure too
+    assert_eq!(backend.refresh_address(later, |_| Ok(Vec::new())), Err(ConnectionError::NoBackendAvailable));
+    assert_eq!(backend.failures, 2);
+
+    // the backends without hostname are never resolved
+    let mut backend = Backend::new("app-1", "127.0.0.1:1025".parse().unwrap(), None, None, None);
+    assert_eq!(backend.refresh_address(now, |_| panic!("should not resolve")), Ok(()));
+  }
+
+  #[test]
+  fn connection_error_metric_names() {
+    let errors = [ConnectionError::NoHostGiven, ConnectionError::NoRequestLineGiven, ConnectionError::InvalidHost,
+      ConnectionError::HostNotFound, ConnectionError::NoBackendAvailable, ConnectionError::HttpsRedirect,
+      ConnectionError::PayloadTooLarge, ConnectionError::ApplicationDisabled];
+    let names: ::std::collections::HashSet<&str> = errors.iter().map(|error| error.metric_name()).collect();
+
+    // each error is counted on its own
+    assert_eq!(names.len(), errors.len());
+    assert_eq!(ConnectionError::HostNotFound.metric_name(), "connection_errors.host_not_found");
+    assert_eq!(ConnectionError::NoBackendAvailable.metric_name(), "connection_errors.no_backend_available");
+  }
+
+  fn connected_backend(connections: usize) -> Rc<RefCell<Backend>> {
+    let backend = Rc::new(RefCell::new(Backend::new("app-0", "127.0.0.1:1026".parse().unwrap(), None, None, None)));
+    for _ in 0..connections {
+      backend.borrow_mut().inc_connections();
+    }
+    backend
+  }
+
+  #[test]
+  fn connection_guard_double_close() {
+    let backend = connected_backend(2);
+    let mut guard = ConnectionGuard::new(backend.clone());
+
+    assert_eq!(guard.close(), Some(1));
+    assert_eq!(guard.close(), None);
+    assert_eq!(backend.borrow().active_connections, 1);
+
+    // dropping a closed guard does not decrement again
+    drop(guard);
+    assert_eq!(backend.borrow().active_connections, 1);
+
+    // and dropping an open one decrements once
+    drop(ConnectionGuard::new(backend.clone()));
+    assert_eq!(backend.borrow().active_connections, 0);
+  }
+
+  #[test]
+  fn connection_guard_on_closing_backend() {
+    let backend = connected_backend(2);
+    let first = ConnectionGuard::new(backend.clone());
+    let mut second = ConnectionGuard::new(backend.clone());
+    backend.borrow_mut().set_closing();
+    assert_eq!(backend.borrow().status, BackendStatus::Closing);
+
+    drop(first);
+    assert_eq!(backend.borrow().status, BackendStatus::Closing);
+    assert_eq!(second.close(), None);
+    assert_eq!(backend.borrow().status, BackendStatus::Closed);
+    assert_eq!(backend.borrow().active_connections, 0);
+
+    // closing again after the backend closed changes nothing
+    assert_eq!(second.close(), None);
+    drop(second);
+    assert_eq!(backend.borrow().status, BackendStatus::Closed);
+    assert_eq!(backend.borrow().active_connections, 0);
+  }
+
+  #[test]
+  fn dec_connections_without_connections() {
+    let mut backend = Backend::new("app-0", "127.0.0.1:1026".parse().unwrap(), None, None, None);
+    assert_eq!(backend.dec_connections(), Some(0));
+    assert_eq!(backend.active_connections, 0);
+    assert_eq!(backend.status, BackendStatus::Normal);
+
+    // a closing backend without connections is not marked as closed by a
+    // spurious decrement
+    backend.status = BackendStatus::Closing;
+    assert_eq!(backend.dec_connections(), None);
+    assert_eq!(backend.status, BackendStatus::Closing);
+    assert_eq!(backend.active_connections, 0);
+  }
+}
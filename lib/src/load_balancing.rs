@@ -1,4 +1,4 @@
-use rand::{thread_rng, seq::SliceRandom};
+use rand::{Rng, RngCore, seq::SliceRandom};
 
 use Backend;
 
@@ -6,31 +6,50 @@ use std::{ rc::Rc, cell::RefCell };
 use std::fmt::Debug;
 
 pub trait LoadBalancingAlgorithm: Debug {
-  fn next_available_backend(&mut self, backends: &Vec<Rc<RefCell<Backend>>>) -> Option<Rc<RefCell<Backend>>>;
+  /// the random choices come from `rng`, the proxies pass `thread_rng`, and the
+  /// tests a seeded generator to get the same selections at every run
+  fn next_available_backend(&mut self, backends: &Vec<Rc<RefCell<Backend>>>, rng: &mut RngCore) -> Option<Rc<RefCell<Backend>>>;
 }
 
+/// the rotation resumes after the last selected backend, so adding or removing
+/// backends (or backends becoming unavailable) does not skip or repeat one
 #[derive(Debug)]
 pub struct RoundRobinAlgorithm {
+  /// position following the last selected backend
   pub next_backend: u32,
+  pub last_backend_id: Option<String>,
 }
 
 impl LoadBalancingAlgorithm for RoundRobinAlgorithm {
 
-  fn next_available_backend(&mut self , backends: &Vec<Rc<RefCell<Backend>>>) -> Option<Rc<RefCell<Backend>>> {
-    let res = backends.get(self.next_backend as usize % backends.len())
-                      .map(|backend| (*backend).clone());
+  fn next_available_backend(&mut self , backends: &Vec<Rc<RefCell<Backend>>>, _rng: &mut RngCore) -> Option<Rc<RefCell<Backend>>> {
+    if backends.is_empty() {
+      return None;
+    }
 
-    self.next_backend = (self.next_backend + 1) % backends.len() as u32;
-    res
+    let position = match self.last_backend_id {
+      None => self.next_backend as usize % backends.len(),
+      Some(ref id) => match backends.iter().position(|backend| &backend.borrow().backend_id == id) {
+        Some(last) => (last + 1) % backends.len(),
+        // the last backend was removed, its successor took its position
+        None => (self.next_backend as usize).saturating_sub(1) % backends.len(),
+      },
+    };
+
+    let backend = backends[position].clone();
+    self.next_backend = position as u32 + 1;
+    self.last_backend_id = Some(backend.borrow().backend_id.clone());
+    Some(backend)
   }
 
 }
 
 impl RoundRobinAlgorithm {
 
-  fn new() -> Self {
+  pub fn new() -> Self {
     Self {
       next_backend: 0,
+      last_backend_id: None,
     }
   }
 
@@ -41,10 +60,8 @@ pub struct RandomAlgorithm;
 
 impl LoadBalancingAlgorithm for RandomAlgorithm {
 
-  fn next_available_backend(&mut self, backends: &Vec<Rc<RefCell<Backend>>>) -> Option<Rc<RefCell<Backend>>> {
-    let mut rng = thread_rng();
-
-    (*backends).choose(&mut rng)
+  fn next_available_backend(&mut self, backends: &Vec<Rc<RefCell<Backend>>>, rng: &mut RngCore) -> Option<Rc<RefCell<Backend>>> {
+    (*backends).choose(rng)
       .map(|backend| (*backend).clone())
   }
 
@@ -55,7 +72,7 @@ pub struct LeastConnectionsAlgorithm;
 
 impl LoadBalancingAlgorithm for LeastConnectionsAlgorithm {
 
-  fn next_available_backend(&mut self, backends: &Vec<Rc<RefCell<Backend>>>) -> Option<Rc<RefCell<Backend>>> {
+  fn next_available_backend(&mut self, backends: &Vec<Rc<RefCell<Backend>>>, _rng: &mut RngCore) -> Option<Rc<RefCell<Backend>>> {
     backends
       .iter()
       .min_by_key(|backend| backend.borrow().active_connections)
@@ -64,12 +81,64 @@ impl LoadBalancingAlgorithm for LeastConnectionsAlgorithm {
 
 }
 
+/// part of the traffic spread evenly between the backends, whatever their response time,
+/// so a slow backend keeps receiving requests and its average can recover
+pub const LEAST_LATENCY_MINIMUM_SHARE: f64 = 0.2;
+
+/// random selection weighted by the inverse of the backends' average response
+/// time. The backends without measurements yet count as fast as the fastest one
+#[derive(Debug)]
+pub struct LeastLatencyAlgorithm;
+
+impl LeastLatencyAlgorithm {
+  /// probability of selecting each backend
+  pub fn shares(backends: &Vec<Rc<RefCell<Backend>>>) -> Vec<f64> {
+    let fastest = backends.iter().filter_map(|backend| backend.borrow().response_time).min().unwrap_or(1);
+    let weights = backends.iter()
+      .map(|backend| 1.0 / backend.borrow().response_time.unwrap_or(fastest).max(1) as f64)
+      .collect::<Vec<_>>();
+    let total: f64 = weights.iter().sum();
+    let even_share = 1.0 / backends.len() as f64;
+
+    weights.iter()
+      .map(|weight| LEAST_LATENCY_MINIMUM_SHARE * even_share + (1.0 - LEAST_LATENCY_MINIMUM_SHARE) * weight / total)
+      .collect()
+  }
+}
+
+impl LoadBalancingAlgorithm for LeastLatencyAlgorithm {
+
+  fn next_available_backend(&mut self, backends: &Vec<Rc<RefCell<Backend>>>, rng: &mut RngCore) -> Option<Rc<RefCell<Backend>>> {
+    if backends.is_empty() {
+      return None;
+    }
+
+    let mut choice = rng.gen::<f64>();
+    for (backend, share) in backends.iter().zip(LeastLatencyAlgorithm::shares(backends)) {
+      if choice < share {
+        return Some(backend.clone());
+      }
+      choice -= share;
+    }
+
+    // rounding errors
+    backends.last().cloned()
+  }
+
+}
+
 #[cfg(test)]
 mod test {
   use super::*;
   use std::net::{IpAddr, Ipv4Addr, SocketAddr};
   use BackendStatus;
+  use outlier::OutlierStats;
   use retry::{RetryPolicyWrapper, ExponentialBackoffPolicy};
+  use rand::{SeedableRng, rngs::StdRng};
+
+  fn rng() -> StdRng {
+    StdRng::seed_from_u64(42)
+  }
 
   fn create_backend(id: String, connections: Option<usize>) -> Backend {
     Backend {
@@ -80,8 +149,14 @@ mod test {
       retry_policy: RetryPolicyWrapper::ExponentialBackoff(ExponentialBackoffPolicy::new(1)),
       active_connections: connections.unwrap_or(0),
       failures: 0,
+      last_failure: None,
       load_balancing_parameters: None,
       backup: false,
+      response_time: None,
+      hostname: None,
+      last_resolution: None,
+      outlier: OutlierStats::new(),
+      failing_answers: false,
     }
   }
 
@@ -97,7 +172,7 @@ mod test {
 
     let mut least_connection_algorithm = LeastConnectionsAlgorithm{};
 
-    let backend_res = least_connection_algorithm.next_available_backend(&backends).unwrap();
+    let backend_res = least_connection_algorithm.next_available_backend(&backends, &mut rng()).unwrap();
     let backend = backend_res.borrow();
 
     assert!(*backend == *backend_with_least_connection.borrow());
@@ -109,7 +184,7 @@ mod test {
 
     let mut least_connection_algorithm = LeastConnectionsAlgorithm{};
 
-    let backend = least_connection_algorithm.next_available_backend(&backends);
+    let backend = least_connection_algorithm.next_available_backend(&backends, &mut rng());
     assert!(backend.is_none());
   }
 
@@ -121,13 +196,120 @@ mod test {
       Rc::new(RefCell::new(create_backend("yoto".to_string(), None)))
     ];
 
-    let mut roundrobin = RoundRobinAlgorithm { next_backend: 1 };
-    let backend = roundrobin.next_available_backend(&backends);
+    let mut roundrobin = RoundRobinAlgorithm { next_backend: 1, last_backend_id: None };
+    let backend = roundrobin.next_available_backend(&backends, &mut rng());
     assert_eq!(backend.as_ref(), backends.get(1));
 
     backends.remove(1);
 
-    let backend2 = roundrobin.next_available_backend(&backends);
-    assert_eq!(backend2.as_ref(),  backends.get(0));
+    // "yoto" came after the removed backend, it must not be skipped
+    let backend2 = roundrobin.next_available_backend(&backends, &mut rng());
+    assert_eq!(backend2.as_ref(),  backends.get(1));
+
+    let backend3 = roundrobin.next_available_backend(&backends, &mut rng());
+    assert_eq!(backend3.as_ref(),  backends.get(0));
+  }
+
+  #[test]
+  fn it_should_keep_a_fair_roundrobin_when_backends_are_added_or_removed() {
+    fn select(roundrobin: &mut RoundRobinAlgorithm, backends: &Vec<Rc<RefCell<Backend>>>) -> String {
+      roundrobin.next_available_backend(backends, &mut rng()).unwrap().borrow().backend_id.clone()
+    }
+
+    let mut backends = vec![
+      Rc::new(RefCell::new(create_backend("a".to_string(), None))),
+      Rc::new(RefCell::new(create_backend("b".to_string(), None))),
+      Rc::new(RefCell::new(create_backend("c".to_string(), None))),
+    ];
+
+    let mut roundrobin = RoundRobinAlgorithm::new();
+    assert_eq!(select(&mut roundrobin, &backends), "a");
+    assert_eq!(select(&mut roundrobin, &backends), "b");
+
+    // removing the backend that was just selected
+    backends.remove(1);
+    assert_eq!(select(&mut roundrobin, &backends), "c");
+    assert_eq!(select(&mut roundrobin, &backends), "a");
+
+    // adding and removing a backend before the last selected one
+    backends.insert(0, Rc::new(RefCell::new(create_backend("z".to_string(), None))));
+    assert_eq!(select(&mut roundrobin, &backends), "c");
+    backends.remove(0);
+    assert_eq!(select(&mut roundrobin, &backends), "a");
+
+    // adding a backend mid rotation
+    backends.push(Rc::new(RefCell::new(create_backend("d".to_string(), None))));
+
+    let mut counts = ::std::collections::HashMap::new();
+    for _ in 0..30 {
+      *counts.entry(select(&mut roundrobin, &backends)).or_insert(0) += 1;
+    }
+
+    assert_eq!(counts.len(), 3);
+    assert!(counts.values().all(|count| *count == 10), "unfair distribution: {:?}", counts);
+  }
+
+  #[test]
+  fn least_latency_favors_the_fast_backend() {
+    let fast = Rc::new(RefCell::new(create_backend("fast".to_string(), None)));
+    let slow = Rc::new(RefCell::new(create_backend("slow".to_string(), None)));
+    for _ in 0..10 {
+      fast.borrow_mut().record_response_time(::time::Duration::milliseconds(10));
+      slow.borrow_mut().record_response_time(::time::Duration::milliseconds(200));
+    }
+    assert_eq!(fast.borrow().response_time, Some(10_000));
+
+    let backends = vec![slow.clone(), fast.clone()];
+    let mut least_latency = LeastLatencyAlgorithm{};
+    let mut rng = rng();
+    let mut fast_count = 0;
+    let mut slow_count = 0;
+    for _ in 0..10_000 {
+      let backend = least_latency.next_available_backend(&backends, &mut rng).unwrap();
+      if backend.borrow().backend_id == "fast" {
+        fast_count += 1;
+      } else {
+        slow_count += 1;
+      }
+    }
+
+    // the fast backend should get about 86% of the traffic, 14% for the slow one
+    assert!(fast_count > 8_000, "fast: {}, slow: {}", fast_count, slow_count);
+    // the slow backend keeps at least its minimum share (10% with 2 backends)
+    assert!(slow_count > 1_000, "fast: {}, slow: {}", fast_count, slow_count);
+  }
+
+  #[test]
+  fn least_latency_without_measurements() {
+    let measured = Rc::new(RefCell::new(create_backend("measured".to_string(), None)));
+    measured.borrow_mut().record_response_time(::time::Duration::milliseconds(50));
+    let backends = vec![
+      measured,
+      Rc::new(RefCell::new(create_backend("new".to_string(), None))),
+    ];
+
+    // a new backend counts as fast as the fastest one
+    let shares = LeastLatencyAlgorithm::shares(&backends);
+    assert!((shares[0] - 0.5).abs() < 1e-9 && (shares[1] - 0.5).abs() < 1e-9, "shares: {:?}", shares);
+
+    let mut least_latency = LeastLatencyAlgorithm{};
+    assert!(least_latency.next_available_backend(&vec![], &mut rng()).is_none());
+  }
+
+  #[test]
+  fn seeded_random_selection() {
+    let backends = (0..10)
+      .map(|i| Rc::new(RefCell::new(create_backend(format!("backend-{}", i), None))))
+      .collect::<Vec<_>>();
+
+    let select = |rng: &mut StdRng| -> Vec<String> {
+      let mut random = RandomAlgorithm{};
+      (0..20).map(|_| random.next_available_backend(&backends, rng).unwrap().borrow().backend_id.clone()).collect()
+    };
+
+    // the same seed gives the same sequence of backends
+    let sequence = select(&mut rng());
+    assert_eq!(sequence, select(&mut rng()));
+    assert_ne!(sequence, select(&mut StdRng::seed_from_u64(43)));
   }
 }
@@ -1,10 +1,11 @@
 use std::str;
-use std::time::{Duration,Instant};
+use std::time::{Duration,Instant,SystemTime,UNIX_EPOCH};
 use std::iter::repeat;
 use std::collections::BTreeMap;
 use std::collections::VecDeque;
 use hdrhistogram::Histogram;
-use sozu_command::proxy::{FilteredData,MetricsData,Percentiles,FilteredTimeSerie,AppMetricsData};
+use sozu_command::proxy::{FilteredData,MetricsData,Percentiles,Bucket,FilteredTimeSerie,AppMetricsData};
+use sozu_command::config::DEFAULT_LATENCY_BUCKETS;
 
 use super::{MetricData,Subscriber};
 
@@ -30,6 +31,23 @@ impl AggregatedMetric {
     }
   }
 
+  /// zeroes the counters and empties the histograms. The gauges are kept: they
+  /// are the current state of the worker, like the number of connections.
+  /// Returns true if the metric was reset
+  fn reset(&mut self) -> bool {
+    match *self {
+      AggregatedMetric::Gauge(_) => false,
+      AggregatedMetric::Count(ref mut v) => {
+        *v = 0;
+        true
+      },
+      AggregatedMetric::Time(ref mut h) => {
+        h.reset();
+        true
+      },
+    }
+  }
+
   fn update(&mut self, key: &'static str, m: MetricData) {
     match (self, m) {
       (&mut AggregatedMetric::Gauge(ref mut v1), MetricData::Gauge(v2)) => {
@@ -49,7 +67,9 @@ impl AggregatedMetric {
   }
 }
 
-pub fn histogram_to_percentiles(hist: &Histogram<u32>) -> Percentiles {
+/// the bucket counts are cumulative, and rounded to the histogram's precision
+/// (3 significant digits) for boundaries above 2048
+pub fn histogram_to_percentiles(hist: &Histogram<u32>, buckets: &[u64]) -> Percentiles {
   Percentiles {
     samples:  hist.len(),
     p_50:     hist.value_at_percentile(50.0),
@@ -59,15 +79,16 @@ pub fn histogram_to_percentiles(hist: &Histogram<u32>) -> Percentiles {
     p_99_99:  hist.value_at_percentile(99.99),
     p_99_999: hist.value_at_percentile(99.999),
     p_100:    hist.value_at_percentile(100.0),
+    buckets:  buckets.iter().map(|&le| Bucket { le, count: hist.count_between(0, le) }).collect(),
   }
 }
 
-pub fn aggregated_to_filtered(value: &AggregatedMetric) -> FilteredData {
+pub fn aggregated_to_filtered(value: &AggregatedMetric, buckets: &[u64]) -> FilteredData {
   match value {
     &AggregatedMetric::Gauge(i) => FilteredData::Gauge(i),
     &AggregatedMetric::Count(i) => FilteredData::Count(i),
     &AggregatedMetric::Time(ref hist) => {
-      FilteredData::Percentiles(histogram_to_percentiles(&hist))
+      FilteredData::Percentiles(histogram_to_percentiles(&hist, buckets))
     },
   }
 }
@@ -105,6 +126,8 @@ pub struct LocalDrain {
   //pub request_counter: TimeSerie,
   use_tagged_metrics:  bool,
   origin:              String,
+  /// boundaries of the buckets of the time metrics
+  latency_buckets:     Vec<u64>,
 }
 
 impl LocalDrain {
@@ -118,32 +141,43 @@ impl LocalDrain {
       //request_counter: TimeSerie::new(),
       use_tagged_metrics: false,
       origin:      String::from("x"),
+      latency_buckets: DEFAULT_LATENCY_BUCKETS.to_vec(),
     }
   }
 
+  pub fn set_latency_buckets(&mut self, buckets: Vec<u64>) {
+    self.latency_buckets = buckets;
+  }
+
   pub fn dump_metrics_data(&mut self) -> MetricsData {
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map(millis).unwrap_or(0);
+
     MetricsData {
       proxy:        self.dump_process_data(),
       applications: self.dump_app_data(),
+      timestamp,
+      uptime:       millis(self.created.elapsed()),
     }
   }
 
   pub fn dump_process_data(&mut self) -> BTreeMap<String, FilteredData> {
+    let buckets = &self.latency_buckets;
     let data: BTreeMap<String, FilteredData> = self.data.iter().map(|(ref key, ref value)| {
-      (key.to_string(), aggregated_to_filtered(value))
+      (key.to_string(), aggregated_to_filtered(value, buckets))
     }).collect();
 
     data
   }
 
   pub fn dump_app_data(&mut self) -> BTreeMap<String,AppMetricsData> {
+    let buckets = &self.latency_buckets;
     let data = self.app_data.iter().map(|(ref app_id, ref app)| {
       let data = app.data.iter().map(|(ref key, ref value)| {
-         (key.to_string(), aggregated_to_filtered(value))
+         (key.to_string(), aggregated_to_filtered(value, buckets))
        }).collect();
       let backends = app.backend_data.iter().map(|(ref backend_id, ref backend_data)| {
         let b = backend_data.iter().map(|(ref key, ref value)| {
-         (key.to_string(), aggregated_to_filtered(value))
+         (key.to_string(), aggregated_to_filtered(value, buckets))
         }).collect();
 
         (backend_id.to_string(), b)
@@ -160,6 +194,21 @@ impl LocalDrain {
   pub fn clear(&mut self) {
     self.app_data.clear();
   }
+
+  /// resets the proxy, application and backend metrics, and returns the number
+  /// of metric series that were reset. The sessions record their measurements
+  /// when they end, so the requests in flight are counted after the reset
+  pub fn reset(&mut self) -> usize {
+    let mut count = self.data.values_mut().map(|metric| metric.reset()).filter(|&reset| reset).count();
+    for app in self.app_data.values_mut() {
+      count += app.data.values_mut().map(|metric| metric.reset()).filter(|&reset| reset).count();
+      for backend in app.backend_data.values_mut() {
+        count += backend.values_mut().map(|metric| metric.reset()).filter(|&reset| reset).count();
+      }
+    }
+
+    count
+  }
 }
 
 
@@ -406,3 +455,77 @@ impl TimeSerie {
     self.last_sent = 0;
   }
 }
+
+fn millis(duration: Duration) -> u64 {
+  duration.as_secs() * 1000 + u64::from(duration.subsec_millis())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn collection_time() {
+    let mut drain = LocalDrain::new(String::from("sozu"));
+    let first = drain.dump_metrics_data();
+    assert!(first.timestamp > 0);
+
+    ::std::thread::sleep(Duration::from_millis(10));
+    let second = drain.dump_metrics_data();
+    assert!(second.timestamp >= first.timestamp);
+    assert!(second.uptime >= first.uptime + 10);
+  }
+
+  #[test]
+  fn reset_metrics() {
+    let mut drain = LocalDrain::new(String::from("sozu"));
+    drain.receive_metric("bytes_in", None, None, MetricData::Count(1000));
+    drain.receive_metric("bytes_in", None, None, MetricData::Count(500));
+    drain.receive_metric("client.connections", None, None, MetricData::Gauge(3));
+    drain.receive_metric("request_time", Some("app_1"), None, MetricData::Time(12));
+    drain.receive_metric("http.requests", Some("app_1"), Some("app_1-0"), MetricData::Count(2));
+    drain.receive_metric("connections", Some("app_1"), Some("app_1-0"), MetricData::GaugeAdd(1));
+
+    assert_eq!(drain.reset(), 3);
+
+    let proxy = drain.dump_process_data();
+    assert_eq!(proxy.get("bytes_in"), Some(&FilteredData::Count(0)));
+    assert_eq!(proxy.get("client.connections"), Some(&FilteredData::Gauge(3)));
+
+    let apps = drain.dump_app_data();
+    let app = apps.get("app_1").expect("the application should still be there");
+    match app.data.get("request_time") {
+      Some(&FilteredData::Percentiles(ref percentiles)) => assert_eq!(percentiles.samples, 0),
+      data => panic!("expected percentiles, got {:?}", data),
+    }
+    let backend = app.backends.get("app_1-0").expect("the backend should still be there");
+    assert_eq!(backend.get("http.requests"), Some(&FilteredData::Count(0)));
+    assert_eq!(backend.get("connections"), Some(&FilteredData::Gauge(1)));
+
+    // the metrics keep counting after the reset
+    drain.receive_metric("bytes_in", None, None, MetricData::Count(10));
+    assert_eq!(drain.dump_process_data().get("bytes_in"), Some(&FilteredData::Count(10)));
+  }
+
+  #[test]
+  fn latency_buckets() {
+    let mut drain = LocalDrain::new(String::from("sozu"));
+    drain.set_latency_buckets(vec![1, 10, 100]);
+    for &time in &[0, 1, 2, 10, 11, 100, 101, 5000] {
+      drain.receive_metric("request_time", None, None, MetricData::Time(time));
+    }
+
+    match drain.dump_process_data().get("request_time") {
+      Some(&FilteredData::Percentiles(ref percentiles)) => {
+        assert_eq!(percentiles.samples, 8);
+        // the samples equal to a boundary are in its bucket
+        assert_eq!(percentiles.buckets, vec![
+          Bucket { le: 1,   count: 2 },
+          Bucket { le: 10,  count: 4 },
+          Bucket { le: 100, count: 6 },
+        ]);
+      },
+      data => panic!("expected percentiles, got {:?}", data),
+    }
+  }
+}
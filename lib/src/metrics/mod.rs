@@ -10,6 +10,7 @@ use sozu_command::proxy::{FilteredData,MetricsData};
 mod network_drain;
 mod local_drain;
 mod writer;
+pub mod prometheus;
 
 use self::network_drain::NetworkDrain;
 use self::local_drain::LocalDrain;
@@ -109,6 +110,13 @@ pub fn setup<O: Into<String>>(metrics_host: &SocketAddr, origin: O, use_tagged_m
   });
 }
 
+/// sets the boundaries of the buckets reported with the percentiles of the time metrics
+pub fn set_latency_buckets(buckets: Vec<u64>) {
+  METRICS.with(|metrics| {
+    (*metrics.borrow_mut()).set_up_latency_buckets(buckets);
+  });
+}
+
 pub trait Subscriber {
   fn receive_metric(&mut self, label: &'static str, app_id: Option<&str>, backend_id: Option<&str>, metric: MetricData);
 }
@@ -144,6 +152,10 @@ impl Aggregator {
     self.network.as_mut().map(|n| n.use_tagged_metrics = tagged);
   }
 
+  pub fn set_up_latency_buckets(&mut self, buckets: Vec<u64>) {
+    self.local.set_latency_buckets(buckets);
+  }
+
   pub fn socket(&self) -> Option<&UdpSocket> {
     self.network.as_ref().map(|n| &n.remote.get_ref().socket)
   }
@@ -183,6 +195,12 @@ impl Aggregator {
   pub fn clear_local(&mut self) {
     self.local.clear();
   }
+
+  /// resets the metrics kept for the `Metrics` order. The metrics sent
+  /// to the network drain are not affected
+  pub fn reset_local(&mut self) -> usize {
+    self.local.reset()
+  }
 }
 
 impl Subscriber for Aggregator {
@@ -0,0 +1,183 @@
+//! formats the aggregated metrics in the Prometheus text exposition format
+//!
+//! The metrics of the workers get a `worker` label, the ones of the
+//! applications and backends also get `app_id` and `backend_id` labels.
+//! Counts are counters, percentiles are summaries, or histograms when they
+//! have latency buckets, and the other metrics are gauges
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use sozu_command::proxy::{AggregatedMetricsData,FilteredData};
+
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// samples of the metrics with the same name, written together after their type
+struct Family {
+  kind:    &'static str,
+  samples: Vec<String>,
+}
+
+pub fn exposition(data: &AggregatedMetricsData, prefix: &str) -> String {
+  let mut families: BTreeMap<String, Family> = BTreeMap::new();
+
+  for (key, value) in data.master.iter() {
+    add_metric(&mut families, prefix, key, value, &[]);
+  }
+
+  for (worker_id, worker) in data.workers.iter() {
+    for (key, value) in worker.proxy.iter() {
+      add_metric(&mut families, prefix, key, value, &[("worker", worker_id)]);
+    }
+
+    for (app_id, app) in worker.applications.iter() {
+      for (key, value) in app.data.iter() {
+        add_metric(&mut families, prefix, key, value, &[("worker", worker_id), ("app_id", app_id)]);
+      }
+
+      for (backend_id, backend) in app.backends.iter() {
+        for (key, value) in backend.iter() {
+          add_metric(&mut families, prefix, key, value,
+            &[("worker", worker_id), ("app_id", app_id), ("backend_id", backend_id)]);
+        }
+      }
+    }
+  }
+
+  let mut out = String::new();
+  for (name, family) in families.iter() {
+    let _ = writeln!(out, "# TYPE {} {}", name, family.kind);
+    for sample in family.samples.iter() {
+      out.push_str(sample);
+      out.push('\n');
+    }
+  }
+  out
+}
+
+fn add_metric(families: &mut BTreeMap<String, Family>, prefix: &str, key: &str, value: &FilteredData,
+  labels: &[(&str, &str)]) {
+  let name = metric_name(prefix, key);
+
+  let (kind, samples) = match *value {
+    FilteredData::Count(count) => ("counter", vec![sample(&name, labels, None, count)]),
+    FilteredData::Gauge(gauge) => ("gauge", vec![sample(&name, labels, None, gauge)]),
+    FilteredData::Time(time)   => ("gauge", vec![sample(&name, labels, None, time)]),
+    FilteredData::TimeSerie(ref serie) => ("gauge", vec![sample(&name, labels, None, serie.last_second)]),
+    FilteredData::Percentiles(ref p) if !p.buckets.is_empty() => {
+      let bucket_name = format!("{}_bucket", name);
+      let mut samples: Vec<String> = p.buckets.iter()
+        .map(|bucket| sample(&bucket_name, labels, Some(("le", &bucket.le.to_string())), bucket.count))
+        .collect();
+      samples.push(sample(&bucket_name, labels, Some(("le", "+Inf")), p.samples));
+      samples.push(sample(&format!("{}_count", name), labels, None, p.samples));
+      ("histogram", samples)
+    },
+    FilteredData::Percentiles(ref p) => {
+      let mut samples: Vec<String> = [("0.5", p.p_50), ("0.9", p.p_90), ("0.99", p.p_99), ("0.999", p.p_99_9),
+        ("0.9999", p.p_99_99), ("0.99999", p.p_99_999), ("1", p.p_100)].iter()
+        .map(|&(quantile, value)| sample(&name, labels, Some(("quantile", quantile)), value))
+        .collect();
+      samples.push(sample(&format!("{}_count", name), labels, None, p.samples));
+      ("summary", samples)
+    },
+  };
+
+  let family = families.entry(name.clone()).or_insert_with(|| Family { kind, samples: Vec::new() });
+  // the same key could have another type in a different scope
+  if family.kind == kind {
+    family.samples.extend(samples);
+  } else {
+    debug!("metric {} is a {} and a {}, skipping the {} samples", name, family.kind, kind, kind);
+  }
+}
+
+/// the metric keys can contain dots, which are not accepted in the names
+fn metric_name(prefix: &str, key: &str) -> String {
+  let name = if prefix.is_empty() { key.to_string() } else { format!("{}_{}", prefix, key) };
+  name.chars().enumerate().map(|(i, c)| {
+    if c.is_ascii_alphabetic() || c == '_' || c == ':' || (i > 0 && c.is_ascii_digit()) { c } else { '_' }
+  }).collect()
+}
+
+fn sample<V: ::std::fmt::Display>(name: &str, labels: &[(&str, &str)], extra: Option<(&str, &str)>, value: V) -> String {
+  let labels: Vec<String> = labels.iter().cloned().chain(extra)
+    .map(|(label, value)| format!("{}=\"{}\"", label, escape_label(value)))
+    .collect();
+
+  if labels.is_empty() {
+    format!("{} {}", name, value)
+  } else {
+    format!("{}{{{}}} {}", name, labels.join(","), value)
+  }
+}
+
+fn escape_label(value: &str) -> String {
+  value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use sozu_command::proxy::{MetricsData,AppMetricsData,Percentiles,Bucket};
+
+  fn percentiles(buckets: Vec<Bucket>) -> FilteredData {
+    FilteredData::Percentiles(Percentiles {
+      samples: 10, p_50: 1, p_90: 2, p_99: 3, p_99_9: 4, p_99_99: 5, p_99_999: 6, p_100: 7, buckets,
+    })
+  }
+
+  #[test]
+  fn format_metrics() {
+    let mut master = BTreeMap::new();
+    master.insert(String::from("configuration.applications"), FilteredData::Gauge(2));
+
+    let mut proxy = BTreeMap::new();
+    proxy.insert(String::from("http.requests"), FilteredData::Count(42));
+    proxy.insert(String::from("event_loop_time"), percentiles(Vec::new()));
+
+    let mut backend = BTreeMap::new();
+    backend.insert(String::from("bytes_out"), FilteredData::Count(1024));
+    let mut backends = BTreeMap::new();
+    backends.insert(String::from("app_1-0"), backend);
+
+    let mut data = BTreeMap::new();
+    data.insert(String::from("http.requests"), FilteredData::Count(12));
+    data.insert(String::from("request_time"), percentiles(vec![Bucket { le: 10, count: 4 }, Bucket { le: 100, count: 9 }]));
+    let mut applications = BTreeMap::new();
+    applications.insert(String::from("app \"1\""), AppMetricsData { data, backends });
+
+    let mut workers = BTreeMap::new();
+    workers.insert(String::from("0"), MetricsData { proxy, applications, timestamp: 0, uptime: 0 });
+
+    let exposition = exposition(&AggregatedMetricsData { master, workers }, "sozu");
+    assert_eq!(exposition, "\
+# TYPE sozu_bytes_out counter
+sozu_bytes_out{worker=\"0\",app_id=\"app \\\"1\\\"\",backend_id=\"app_1-0\"} 1024
+# TYPE sozu_configuration_applications gauge
+sozu_configuration_applications 2
+# TYPE sozu_event_loop_time summary
+sozu_event_loop_time{worker=\"0\",quantile=\"0.5\"} 1
+sozu_event_loop_time{worker=\"0\",quantile=\"0.9\"} 2
+sozu_event_loop_time{worker=\"0\",quantile=\"0.99\"} 3
+sozu_event_loop_time{worker=\"0\",quantile=\"0.999\"} 4
+sozu_event_loop_time{worker=\"0\",quantile=\"0.9999\"} 5
+sozu_event_loop_time{worker=\"0\",quantile=\"0.99999\"} 6
+sozu_event_loop_time{worker=\"0\",quantile=\"1\"} 7
+sozu_event_loop_time_count{worker=\"0\"} 10
+# TYPE sozu_http_requests counter
+sozu_http_requests{worker=\"0\"} 42
+sozu_http_requests{worker=\"0\",app_id=\"app \\\"1\\\"\"} 12
+# TYPE sozu_request_time histogram
+sozu_request_time_bucket{worker=\"0\",app_id=\"app \\\"1\\\"\",le=\"10\"} 4
+sozu_request_time_bucket{worker=\"0\",app_id=\"app \\\"1\\\"\",le=\"100\"} 9
+sozu_request_time_bucket{worker=\"0\",app_id=\"app \\\"1\\\"\",le=\"+Inf\"} 10
+sozu_request_time_count{worker=\"0\",app_id=\"app \\\"1\\\"\"} 10
+");
+  }
+
+  #[test]
+  fn metric_names() {
+    assert_eq!(metric_name("sozu", "http.status.2xx"), "sozu_http_status_2xx");
+    assert_eq!(metric_name("sozu-metrics", "bytes_in"), "sozu_metrics_bytes_in");
+    assert_eq!(metric_name("", "2xx"), "_xx");
+  }
+}
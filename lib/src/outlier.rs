@@ -0,0 +1,178 @@
+//! outlier detection of the backends
+//!
+//! The retry policy only sees the connection failures. A backend that accepts
+//! the connections but answers with errors keeps being selected, so the outcome
+//! of each request is also counted here: a failed connection or a 5xx answer is
+//! an error. Once the backend got at least `min_requests` requests over the last
+//! window, and the percentage of errors is above `error_percentage`, it is
+//! ejected: it is not selected for `ejection_time`, then it gets requests again
+//! with fresh counters.
+//!
+//! The counters of the previous window are kept, and the ratio is computed over
+//! the previous and current windows, so that it does not drop to zero every time
+//! a window starts.
+use std::cell::Cell;
+use time::{Duration, SteadyTime};
+use sozu_command::config::OutlierDetection;
+
+thread_local! {
+  static OUTLIER_DETECTION: Cell<Option<OutlierDetection>> = Cell::new(None);
+}
+
+/// outlier detection parameters of the backends of this worker, None disables it
+pub fn set_outlier_detection(config: Option<OutlierDetection>) {
+  OUTLIER_DETECTION.with(|outlier_detection| outlier_detection.set(config));
+}
+
+pub fn outlier_detection() -> Option<OutlierDetection> {
+  OUTLIER_DETECTION.with(|outlier_detection| outlier_detection.get())
+}
+
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct OutlierStats {
+  window_start:      Option<SteadyTime>,
+  requests:          u32,
+  errors:            u32,
+  previous_requests: u32,
+  previous_errors:   u32,
+  /// the backend is not selected until this time
+  pub ejected_until: Option<SteadyTime>,
+}
+
+impl OutlierStats {
+  pub fn new() -> OutlierStats {
+    OutlierStats {
+      window_start:      None,
+      requests:          0,
+      errors:            0,
+      previous_requests: 0,
+      previous_errors:   0,
+      ejected_until:     None,
+    }
+  }
+
+  pub fn is_ejected(&self, now: SteadyTime) -> bool {
+    self.ejected_until.map(|until| now < until).unwrap_or(false)
+  }
+
+  /// counts the outcome of a request, and returns true if the backend was
+  /// ejected because of it. The requests ending while the backend is ejected,
+  /// that were sent before the ejection, are not counted
+  pub fn record(&mut self, config: &OutlierDetection, error: bool, now: SteadyTime) -> bool {
+    if self.is_ejected(now) {
+      return false;
+    }
+
+    self.rotate(config, now);
+    self.requests += 1;
+    if error {
+      self.errors += 1;
+    }
+
+    let requests = u64::from(self.requests + self.previous_requests);
+    let errors = u64::from(self.errors + self.previous_errors);
+    if requests >= u64::from(config.min_requests) && errors * 100 > requests * u64::from(config.error_percentage) {
+      *self = OutlierStats::new();
+      self.ejected_until = Some(now + Duration::seconds(i64::from(config.ejection_time)));
+      true
+    } else {
+      false
+    }
+  }
+
+  /// starts a new window if the current one is over
+  fn rotate(&mut self, config: &OutlierDetection, now: SteadyTime) {
+    let window = Duration::seconds(i64::from(config.window.max(1)));
+    match self.window_start {
+      Some(start) if now - start < window => {},
+      Some(start) if now - start < window * 2 => {
+        self.previous_requests = self.requests;
+        self.previous_errors   = self.errors;
+        self.requests          = 0;
+        self.errors            = 0;
+        self.window_start      = Some(start + window);
+      },
+      _ => {
+        self.previous_requests = 0;
+        self.previous_errors   = 0;
+        self.requests          = 0;
+        self.errors            = 0;
+        self.window_start      = Some(now);
+      },
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn config() -> OutlierDetection {
+    OutlierDetection { error_percentage: 50, min_requests: 4, window: 10, ejection_time: 30 }
+  }
+
+  #[test]
+  fn ejection_above_threshold() {
+    let config = config();
+    let now = SteadyTime::now();
+    let mut stats = OutlierStats::new();
+
+    // not enough requests to check the ratio
+    assert!(!stats.record(&config, true, now));
+    assert!(!stats.record(&config, true, now));
+    assert!(!stats.record(&config, true, now));
+    assert!(!stats.is_ejected(now));
+
+    assert!(stats.record(&config, true, now));
+    assert!(stats.is_ejected(now));
+    assert!(stats.is_ejected(now + Duration::seconds(29)));
+
+    // the answers of the requests sent before the ejection are ignored
+    assert!(!stats.record(&config, true, now + Duration::seconds(1)));
+
+    // readmitted after the ejection time, with fresh counters
+    let readmission = now + Duration::seconds(30);
+    assert!(!stats.is_ejected(readmission));
+    assert!(!stats.record(&config, true, readmission));
+    assert!(!stats.is_ejected(readmission));
+  }
+
+  #[test]
+  fn no_ejection_below_threshold() {
+    let config = config();
+    let now = SteadyTime::now();
+    let mut stats = OutlierStats::new();
+
+    // exactly 50% of errors is not above the threshold
+    for i in 0..20 {
+      assert!(!stats.record(&config, i % 2 == 1, now));
+    }
+    assert!(!stats.is_ejected(now));
+
+    // an error takes the ratio above it
+    assert!(stats.record(&config, true, now));
+  }
+
+  #[test]
+  fn rolling_window() {
+    let config = config();
+    let now = SteadyTime::now();
+    let mut stats = OutlierStats::new();
+
+    for _ in 0..10 {
+      stats.record(&config, false, now);
+    }
+    stats.record(&config, true, now);
+    stats.record(&config, true, now);
+
+    // the successes of the previous window still count
+    let next_window = now + Duration::seconds(15);
+    for _ in 0..5 {
+      assert!(!stats.record(&config, true, next_window));
+    }
+
+    // once they are too old, the errors of the last window eject the backend
+    let later = now + Duration::seconds(25);
+    assert!(stats.record(&config, true, later));
+  }
+}
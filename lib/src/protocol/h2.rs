@@ -0,0 +1,438 @@
+//! HTTP/2 frontend connections
+//!
+//! When the `h2` feature is enabled, HTTPS listeners advertise `h2` through
+//! ALPN. This module implements the connection preface and the frame layer.
+//! Streams are not forwarded to backends yet: the first request of a
+//! connection is refused with a GOAWAY frame and the `HTTP_1_1_REQUIRED`
+//! error code, which makes clients retry it over HTTP/1.1
+use mio::*;
+use mio::net::*;
+use mio::unix::UnixReady;
+use uuid::adapter::Hyphenated;
+use {SessionResult,Readiness,SessionMetrics};
+use socket::{SocketHandler,SocketResult};
+
+pub const ALPN_H2: &[u8] = b"h2";
+pub const ALPN_HTTP_11: &[u8] = b"http/1.1";
+
+/// sent by the client before its first frame
+pub const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+pub const FRAME_HEADER_SIZE: usize = 9;
+/// SETTINGS_MAX_FRAME_SIZE default value, we do not announce a larger one
+pub const MAX_FRAME_SIZE: u32 = 16384;
+
+pub const FLAG_ACK: u8 = 0x1;
+
+pub const NO_ERROR: u32 = 0x0;
+pub const PROTOCOL_ERROR: u32 = 0x1;
+pub const FRAME_SIZE_ERROR: u32 = 0x6;
+pub const HTTP_1_1_REQUIRED: u32 = 0xd;
+
+/// ALPN protocols of HTTPS listeners, by order of preference
+pub fn alpn_protocols(h2: bool) -> Vec<Vec<u8>> {
+  if h2 {
+    vec![ALPN_H2.to_vec(), ALPN_HTTP_11.to_vec()]
+  } else {
+    Vec::new()
+  }
+}
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum FrameType {
+  Data,
+  Headers,
+  Priority,
+  RstStream,
+  Settings,
+  PushPromise,
+  Ping,
+  GoAway,
+  WindowUpdate,
+  Continuation,
+  Unknown(u8),
+}
+
+impl From<u8> for FrameType {
+  fn from(t: u8) -> FrameType {
+    match t {
+      0x0 => FrameType::Data,
+      0x1 => FrameType::Headers,
+      0x2 => FrameType::Priority,
+      0x3 => FrameType::RstStream,
+      0x4 => FrameType::Settings,
+      0x5 => FrameType::PushPromise,
+      0x6 => FrameType::Ping,
+      0x7 => FrameType::GoAway,
+      0x8 => FrameType::WindowUpdate,
+      0x9 => FrameType::Continuation,
+      t   => FrameType::Unknown(t),
+    }
+  }
+}
+
+impl From<FrameType> for u8 {
+  fn from(t: FrameType) -> u8 {
+    match t {
+      FrameType::Data         => 0x0,
+      FrameType::Headers      => 0x1,
+      FrameType::Priority     => 0x2,
+      FrameType::RstStream    => 0x3,
+      FrameType::Settings     => 0x4,
+      FrameType::PushPromise  => 0x5,
+      FrameType::Ping         => 0x6,
+      FrameType::GoAway       => 0x7,
+      FrameType::WindowUpdate => 0x8,
+      FrameType::Continuation => 0x9,
+      FrameType::Unknown(t)   => t,
+    }
+  }
+}
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub struct FrameHeader {
+  pub payload_len: u32,
+  pub frame_type:  FrameType,
+  pub flags:       u8,
+  pub stream_id:   u32,
+}
+
+/// returns None if there is not enough data for a frame header
+pub fn parse_frame_header(i: &[u8]) -> Option<FrameHeader> {
+  if i.len() < FRAME_HEADER_SIZE {
+    return None;
+  }
+
+  Some(FrameHeader {
+    payload_len: (i[0] as u32) << 16 | (i[1] as u32) << 8 | i[2] as u32,
+    frame_type:  FrameType::from(i[3]),
+    flags:       i[4],
+    // the first bit is reserved
+    stream_id:   ((i[5] as u32) << 24 | (i[6] as u32) << 16 | (i[7] as u32) << 8 | i[8] as u32) & 0x7fff_ffff,
+  })
+}
+
+pub fn gen_frame_header(header: &FrameHeader, out: &mut Vec<u8>) {
+  out.extend_from_slice(&[
+    (header.payload_len >> 16) as u8, (header.payload_len >> 8) as u8, header.payload_len as u8,
+    u8::from(header.frame_type),
+    header.flags,
+  ]);
+  out.extend_from_slice(&(header.stream_id & 0x7fff_ffff).to_be_bytes());
+}
+
+pub fn gen_settings(settings: &[(u16, u32)], out: &mut Vec<u8>) {
+  gen_frame_header(&FrameHeader {
+    payload_len: 6 * settings.len() as u32,
+    frame_type:  FrameType::Settings,
+    flags:       0,
+    stream_id:   0,
+  }, out);
+
+  for &(id, value) in settings {
+    out.extend_from_slice(&id.to_be_bytes());
+    out.extend_from_slice(&value.to_be_bytes());
+  }
+}
+
+pub fn gen_settings_ack(out: &mut Vec<u8>) {
+  gen_frame_header(&FrameHeader { payload_len: 0, frame_type: FrameType::Settings, flags: FLAG_ACK, stream_id: 0 }, out);
+}
+
+pub fn gen_ping_ack(opaque_data: &[u8], out: &mut Vec<u8>) {
+  gen_frame_header(&FrameHeader {
+    payload_len: opaque_data.len() as u32,
+    frame_type:  FrameType::Ping,
+    flags:       FLAG_ACK,
+    stream_id:   0,
+  }, out);
+  out.extend_from_slice(opaque_data);
+}
+
+pub fn gen_goaway(last_stream_id: u32, error_code: u32, out: &mut Vec<u8>) {
+  gen_frame_header(&FrameHeader { payload_len: 8, frame_type: FrameType::GoAway, flags: 0, stream_id: 0 }, out);
+  out.extend_from_slice(&(last_stream_id & 0x7fff_ffff).to_be_bytes());
+  out.extend_from_slice(&error_code.to_be_bytes());
+}
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum ConnectionState {
+  /// waiting for the client preface
+  Preface,
+  Frames,
+  /// a GOAWAY frame was queued, the connection closes once it is sent
+  GoAway,
+}
+
+pub struct Http2<Front:SocketHandler> {
+  pub frontend:       Front,
+  frontend_token:     Token,
+  pub readiness:      Readiness,
+  pub request_id:     Hyphenated,
+  pub state:          ConnectionState,
+  input:              Vec<u8>,
+  output:             Vec<u8>,
+}
+
+impl<Front:SocketHandler> Http2<Front> {
+  pub fn new(frontend: Front, frontend_token: Token, request_id: Hyphenated) -> Http2<Front> {
+    Http2 {
+      frontend,
+      frontend_token,
+      readiness: Readiness {
+        interest: UnixReady::from(Ready::readable()) | UnixReady::hup() | UnixReady::error(),
+        event:    UnixReady::from(Ready::empty()),
+      },
+      request_id,
+      state:  ConnectionState::Preface,
+      input:  Vec::new(),
+      output: Vec::new(),
+    }
+  }
+
+  pub fn front_socket(&self) -> &TcpStream {
+    self.frontend.socket_ref()
+  }
+
+  /// adds data already read from the frontend, like the plaintext decrypted
+  /// at the end of the TLS handshake
+  pub fn extend_input(&mut self, data: &[u8]) {
+    self.input.extend_from_slice(data);
+  }
+
+  pub fn front_hup(&mut self) -> SessionResult {
+    SessionResult::CloseSession
+  }
+
+  pub fn readable(&mut self, metrics: &mut SessionMetrics) -> SessionResult {
+    let mut buf = [0u8; 16384];
+    loop {
+      let (sz, res) = self.frontend.socket_read(&mut buf);
+      if sz > 0 {
+        count!("bytes_in", sz as i64);
+        metrics.bin += sz;
+        self.input.extend_from_slice(&buf[..sz]);
+      }
+
+      match res {
+        SocketResult::Continue => {},
+        SocketResult::WouldBlock => {
+          self.readiness.event.remove(Ready::readable());
+          break;
+        },
+        SocketResult::Closed | SocketResult::Error => {
+          metrics.service_stop();
+          self.readiness.reset();
+          return SessionResult::CloseSession;
+        },
+      }
+    }
+
+    self.process_input();
+    if !self.output.is_empty() {
+      self.readiness.interest.insert(Ready::writable());
+    }
+    SessionResult::Continue
+  }
+
+  pub fn writable(&mut self, metrics: &mut SessionMetrics) -> SessionResult {
+    if !self.output.is_empty() {
+      let (sz, res) = self.frontend.socket_write(&self.output);
+      if sz > 0 {
+        count!("bytes_out", sz as i64);
+        metrics.bout += sz;
+        self.output.drain(..sz);
+      }
+
+      match res {
+        SocketResult::Error | SocketResult::Closed => {
+          metrics.service_stop();
+          self.readiness.reset();
+          return SessionResult::CloseSession;
+        },
+        SocketResult::WouldBlock => {
+          self.readiness.event.remove(Ready::writable());
+        },
+        SocketResult::Continue => {},
+      }
+    }
+
+    if self.output.is_empty() {
+      if self.state == ConnectionState::GoAway {
+        metrics.service_stop();
+        self.readiness.reset();
+        return SessionResult::CloseSession;
+      }
+      self.readiness.interest.remove(Ready::writable());
+    }
+    SessionResult::Continue
+  }
+
+  /// consumes the complete frames of the input buffer, and queues the answers
+  pub fn process_input(&mut self) {
+    if self.state == ConnectionState::Preface {
+      if self.input.len() < PREFACE.len() {
+        if !PREFACE.starts_with(&self.input) {
+          self.go_away(0, PROTOCOL_ERROR);
+        }
+        return;
+      }
+      if !self.input.starts_with(PREFACE) {
+        error!("{}\tinvalid HTTP/2 connection preface", self.request_id);
+        self.go_away(0, PROTOCOL_ERROR);
+        return;
+      }
+
+      self.input.drain(..PREFACE.len());
+      // the server preface is a SETTINGS frame, the defaults suit us
+      gen_settings(&[], &mut self.output);
+      self.state = ConnectionState::Frames;
+    }
+
+    while self.state == ConnectionState::Frames {
+      let header = match parse_frame_header(&self.input) {
+        Some(header) => header,
+        None         => return,
+      };
+
+      if header.payload_len > MAX_FRAME_SIZE {
+        self.go_away(0, FRAME_SIZE_ERROR);
+        return;
+      }
+
+      let frame_size = FRAME_HEADER_SIZE + header.payload_len as usize;
+      if self.input.len() < frame_size {
+        return;
+      }
+      let payload: Vec<u8> = self.input.drain(..frame_size).skip(FRAME_HEADER_SIZE).collect();
+
+      match header.frame_type {
+        FrameType::Settings if header.flags & FLAG_ACK == 0 => gen_settings_ack(&mut self.output),
+        FrameType::Ping if header.flags & FLAG_ACK == 0 => gen_ping_ack(&payload, &mut self.output),
+        FrameType::Headers => {
+          debug!("{}\t{:?} refusing HTTP/2 stream {}, HTTP/1.1 is required", self.request_id, self.frontend_token, header.stream_id);
+          incr!("http2.downgrades");
+          // no stream was processed, the client can retry all of them
+          self.go_away(0, HTTP_1_1_REQUIRED);
+        },
+        FrameType::GoAway => self.go_away(0, NO_ERROR),
+        _ => {},
+      }
+    }
+  }
+
+  fn go_away(&mut self, last_stream_id: u32, error_code: u32) {
+    gen_goaway(last_stream_id, error_code, &mut self.output);
+    self.input.clear();
+    self.readiness.interest.remove(Ready::readable());
+    self.state = ConnectionState::GoAway;
+  }
+
+  #[cfg(test)]
+  fn output(&self) -> &[u8] {
+    &self.output
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::sync::Arc;
+  use std::io::Cursor;
+  use rustls::{ClientConfig, ClientSession, NoClientAuth, ServerConfig, ServerSession, Session};
+  use rustls::internal::pemfile::{certs, pkcs8_private_keys};
+  use uuid::Uuid;
+  use webpki;
+
+  fn negotiated_protocol(server_protocols: Vec<Vec<u8>>, client_protocols: Vec<Vec<u8>>) -> Option<Vec<u8>> {
+    let mut server_config = ServerConfig::new(NoClientAuth::new());
+    let chain = certs(&mut Cursor::new(&include_bytes!("../../assets/certificate.pem")[..])).unwrap();
+    let key = pkcs8_private_keys(&mut Cursor::new(&include_bytes!("../../assets/key.pem")[..])).unwrap().remove(0);
+    server_config.set_single_cert(chain, key).unwrap();
+    server_config.alpn_protocols = server_protocols;
+
+    let mut client_config = ClientConfig::new();
+    client_config.alpn_protocols = client_protocols;
+    let dns_name = webpki::DNSNameRef::try_from_ascii_str("lolcatho.st").unwrap();
+    let mut client = ClientSession::new(&Arc::new(client_config), dns_name);
+    let mut server = ServerSession::new(&Arc::new(server_config));
+
+    let mut hello = Vec::new();
+    client.write_tls(&mut hello).unwrap();
+    server.read_tls(&mut Cursor::new(&hello)).unwrap();
+    server.process_new_packets().unwrap();
+    server.get_alpn_protocol().map(|p| p.to_vec())
+  }
+
+  #[test]
+  fn alpn_selection() {
+    assert_eq!(
+      negotiated_protocol(alpn_protocols(true), vec![b"http/1.1".to_vec(), b"h2".to_vec()]),
+      Some(b"h2".to_vec())
+    );
+    assert_eq!(
+      negotiated_protocol(alpn_protocols(true), vec![b"http/1.1".to_vec()]),
+      Some(b"http/1.1".to_vec())
+    );
+    assert_eq!(negotiated_protocol(alpn_protocols(true), vec![]), None);
+    assert_eq!(negotiated_protocol(alpn_protocols(false), vec![b"h2".to_vec(), b"http/1.1".to_vec()]), None);
+  }
+
+  #[test]
+  fn frame_header() {
+    let header = FrameHeader { payload_len: 0x012345, frame_type: FrameType::Headers, flags: 0x5, stream_id: 7 };
+    let mut out = Vec::new();
+    gen_frame_header(&header, &mut out);
+    assert_eq!(&out[..], &[0x01, 0x23, 0x45, 0x01, 0x05, 0x00, 0x00, 0x00, 0x07][..]);
+    assert_eq!(parse_frame_header(&out), Some(header));
+    assert_eq!(parse_frame_header(&out[..8]), None);
+
+    // the reserved bit is ignored
+    out[5] = 0x80;
+    assert_eq!(parse_frame_header(&out).map(|h| h.stream_id), Some(7));
+  }
+
+  #[test]
+  fn refuses_streams() {
+    let front = TcpStream::connect(&"127.0.0.1:1".parse().unwrap()).unwrap();
+    let mut h2 = Http2::new(front, Token(0), Uuid::new_v4().to_hyphenated());
+
+    h2.extend_input(&PREFACE[..10]);
+    h2.process_input();
+    assert_eq!(h2.state, ConnectionState::Preface);
+    assert!(h2.output().is_empty());
+
+    h2.extend_input(&PREFACE[10..]);
+    let mut client_settings = Vec::new();
+    gen_settings(&[(0x3, 100)], &mut client_settings);
+    h2.extend_input(&client_settings);
+    h2.process_input();
+    assert_eq!(h2.state, ConnectionState::Frames);
+
+    let mut expected = Vec::new();
+    gen_settings(&[], &mut expected);
+    gen_settings_ack(&mut expected);
+    assert_eq!(h2.output(), &expected[..]);
+
+    let mut headers = Vec::new();
+    gen_frame_header(&FrameHeader { payload_len: 3, frame_type: FrameType::Headers, flags: 0x4, stream_id: 1 }, &mut headers);
+    headers.extend_from_slice(&[0x82, 0x87, 0x84]);
+    h2.extend_input(&headers);
+    h2.process_input();
+    assert_eq!(h2.state, ConnectionState::GoAway);
+    gen_goaway(0, HTTP_1_1_REQUIRED, &mut expected);
+    assert_eq!(h2.output(), &expected[..]);
+  }
+
+  #[test]
+  fn invalid_preface() {
+    let front = TcpStream::connect(&"127.0.0.1:1".parse().unwrap()).unwrap();
+    let mut h2 = Http2::new(front, Token(0), Uuid::new_v4().to_hyphenated());
+    h2.extend_input(b"GET / HTTP/1.1\r\n");
+    h2.process_input();
+    assert_eq!(h2.state, ConnectionState::GoAway);
+
+    let mut expected = Vec::new();
+    gen_goaway(0, PROTOCOL_ERROR, &mut expected);
+    assert_eq!(h2.output(), &expected[..]);
+  }
+}
@@ -1,6 +1,8 @@
 use std::rc::Rc;
-use std::collections::HashMap;
-use AppId;
+use std::collections::{HashMap,BTreeMap};
+use rand::{thread_rng, Rng};
+use sozu_command::proxy::{RetryAfter,ErrorPage};
+use {AppId,ConnectionError};
 use super::DefaultAnswerStatus;
 
 #[allow(non_snake_case)]
@@ -13,6 +15,12 @@ pub struct DefaultAnswers {
   pub RequestTimeout:     Rc<Vec<u8>>,
   /// 413
   pub PayloadTooLarge:    Rc<Vec<u8>>,
+  /// 414
+  pub UriTooLong:         Rc<Vec<u8>>,
+  /// 417
+  pub ExpectationFailed:  Rc<Vec<u8>>,
+  /// 431
+  pub RequestHeaderFieldsTooLarge: Rc<Vec<u8>>,
   /// 503
   pub ServiceUnavailable: Rc<Vec<u8>>,
   /// 504
@@ -25,12 +33,13 @@ pub struct CustomAnswers {
 }
 
 pub struct HttpAnswers {
-  pub default: DefaultAnswers,
-  pub custom:  HashMap<AppId, CustomAnswers>,
+  pub default:     DefaultAnswers,
+  pub custom:      HashMap<AppId, CustomAnswers>,
+  pub retry_after: Option<RetryAfter>,
 }
 
 impl HttpAnswers {
-  pub fn new(answer_404: &str, answer_503: &str) -> Self {
+  pub fn new(answer_404: &str, answer_503: &str, retry_after: Option<RetryAfter>) -> Self {
     HttpAnswers {
       default: DefaultAnswers {
         BadRequest: Rc::new(Vec::from(
@@ -43,12 +52,49 @@ impl HttpAnswers {
         PayloadTooLarge: Rc::new(Vec::from(
           &b"HTTP/1.1 413 Payload Too Large\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n"[..]
         )),
+        UriTooLong: Rc::new(Vec::from(
+          &b"HTTP/1.1 414 URI Too Long\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n"[..]
+        )),
+        ExpectationFailed: Rc::new(Vec::from(
+          &b"HTTP/1.1 417 Expectation Failed\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n"[..]
+        )),
+        RequestHeaderFieldsTooLarge: Rc::new(Vec::from(
+          &b"HTTP/1.1 431 Request Header Fields Too Large\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n"[..]
+        )),
         ServiceUnavailable: Rc::new(Vec::from(answer_503.as_bytes())),
         GatewayTimeout: Rc::new(Vec::from(
           &b"HTTP/1.1 504 Gateway Timeout\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n"[..]
         )),
       },
       custom: HashMap::new(),
+      retry_after,
+    }
+  }
+
+  /// replaces the built-in answers with the configured error pages.
+  /// The 503 answers of applications with a custom answer are not changed
+  pub fn set_error_pages(&mut self, error_pages: &BTreeMap<u16, ErrorPage>) {
+    for (status, page) in error_pages.iter() {
+      let answer = match error_page_answer(*status, page) {
+        Some(answer) => Rc::new(answer),
+        None => {
+          error!("there is no default answer for status {}, ignoring its error page", status);
+          continue;
+        }
+      };
+
+      match *status {
+        400 => self.default.BadRequest = answer,
+        404 => self.default.NotFound = answer,
+        408 => self.default.RequestTimeout = answer,
+        413 => self.default.PayloadTooLarge = answer,
+        414 => self.default.UriTooLong = answer,
+        417 => self.default.ExpectationFailed = answer,
+        431 => self.default.RequestHeaderFieldsTooLarge = answer,
+        503 => self.default.ServiceUnavailable = answer,
+        504 => self.default.GatewayTimeout = answer,
+        _   => unreachable!(),
+      }
     }
   }
 
@@ -71,12 +117,205 @@ impl HttpAnswers {
       DefaultAnswerStatus::Answer404 => self.default.NotFound.clone(),
       DefaultAnswerStatus::Answer408 => self.default.RequestTimeout.clone(),
       DefaultAnswerStatus::Answer413 => self.default.PayloadTooLarge.clone(),
-      DefaultAnswerStatus::Answer503 => app_id.and_then(|id: &str| self.custom.get(id))
-        .and_then(|c| c.ServiceUnavailable.clone()).unwrap_or_else(|| self.default.ServiceUnavailable.clone()),
+      DefaultAnswerStatus::Answer414 => self.default.UriTooLong.clone(),
+      DefaultAnswerStatus::Answer417 => self.default.ExpectationFailed.clone(),
+      DefaultAnswerStatus::Answer431 => self.default.RequestHeaderFieldsTooLarge.clone(),
+      DefaultAnswerStatus::Answer502 => panic!("the 502 answer is generated dynamically"),
+      DefaultAnswerStatus::Answer503 => {
+        let answer = app_id.and_then(|id: &str| self.custom.get(id))
+          .and_then(|c| c.ServiceUnavailable.clone()).unwrap_or_else(|| self.default.ServiceUnavailable.clone());
+
+        match self.retry_after {
+          Some(ref retry_after) => Rc::new(add_retry_after(&answer, jittered_retry_after(retry_after))),
+          None                  => answer,
+        }
+      },
       DefaultAnswerStatus::Answer504 => self.default.GatewayTimeout.clone(),
+      DefaultAnswerStatus::AnswerTransformer => panic!("the transformer answer is generated dynamically"),
+
+    }
+
+  }
+
+}
+
+/// answer sent to the client when its request could not be routed to a
+/// backend, instead of closing the connection
+pub fn connection_error_status(error: &ConnectionError) -> DefaultAnswerStatus {
+  match *error {
+    ConnectionError::NoHostGiven | ConnectionError::NoRequestLineGiven |
+      ConnectionError::InvalidHost        => DefaultAnswerStatus::Answer400,
+    ConnectionError::HostNotFound         => DefaultAnswerStatus::Answer404,
+    ConnectionError::PayloadTooLarge      => DefaultAnswerStatus::Answer413,
+    ConnectionError::HttpsRedirect        => DefaultAnswerStatus::Answer301,
+    ConnectionError::NoBackendAvailable | ConnectionError::ApplicationDisabled |
+      ConnectionError::RequestQueueFull   => DefaultAnswerStatus::Answer503,
+  }
+}
+
+/// generates a complete answer for an error page, None if sozu never sends this status
+pub fn error_page_answer(status: u16, page: &ErrorPage) -> Option<Vec<u8>> {
+  let reason = match status {
+    400 => "Bad Request",
+    404 => "Not Found",
+    408 => "Request Timeout",
+    413 => "Payload Too Large",
+    414 => "URI Too Long",
+    417 => "Expectation Failed",
+    431 => "Request Header Fields Too Large",
+    503 => "Service Unavailable",
+    504 => "Gateway Timeout",
+    _   => return None,
+  };
+
+  let mut answer = format!("HTTP/1.1 {} {}\r\nCache-Control: no-cache\r\nConnection: close\r\n\
+    Content-Type: {}\r\nContent-Length: {}\r\n\r\n", status, reason, page.content_type, page.body.len())
+    .into_bytes();
+  answer.extend_from_slice(page.body.as_bytes());
+  Some(answer)
+}
+
+/// chooses a random delay in the configured window, so that clients
+/// receiving the answer at the same time do not retry at the same time
+pub fn jittered_retry_after(retry_after: &RetryAfter) -> u32 {
+  let (min, max) = if retry_after.min <= retry_after.max {
+    (retry_after.min, retry_after.max)
+  } else {
+    (retry_after.max, retry_after.min)
+  };
+
+  thread_rng().gen_range(min, max + 1)
+}
 
+/// inserts a `Retry-After` header right after the status line, unless the
+/// answer already has one
+pub fn add_retry_after(answer: &[u8], seconds: u32) -> Vec<u8> {
+  let status_line_end = match answer.windows(2).position(|w| w == &b"\r\n"[..]) {
+    Some(index) => index + 2,
+    None        => return answer.to_vec(),
+  };
+
+  let headers_end = answer.windows(4).position(|w| w == &b"\r\n\r\n"[..]).unwrap_or(answer.len());
+  let has_header = answer[..headers_end].split(|c| *c == b'\n')
+    .any(|line| line.len() >= 12 && line[..12].eq_ignore_ascii_case(b"retry-after:"));
+  if has_header {
+    return answer.to_vec();
+  }
+
+  let header = format!("Retry-After: {}\r\n", seconds);
+  let mut v = Vec::with_capacity(answer.len() + header.len());
+  v.extend_from_slice(&answer[..status_line_end]);
+  v.extend_from_slice(header.as_bytes());
+  v.extend_from_slice(&answer[status_line_end..]);
+  v
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::str;
+
+  fn retry_after_value(answer: &[u8]) -> Option<u32> {
+    str::from_utf8(answer).unwrap().split("\r\n")
+      .find(|line| line.starts_with("Retry-After: "))
+      .map(|line| line["Retry-After: ".len()..].parse().unwrap())
+  }
+
+  #[test]
+  fn retry_after_is_jittered_in_the_window() {
+    let answers = HttpAnswers::new("HTTP/1.1 404 Not Found\r\n\r\n",
+      "HTTP/1.1 503 Service Unavailable\r\nConnection: close\r\n\r\n",
+      Some(RetryAfter { min: 10, max: 20 }));
+
+    let mut values = ::std::collections::HashSet::new();
+    for _ in 0..1000 {
+      let answer = answers.get(DefaultAnswerStatus::Answer503, None);
+      assert!(answer.starts_with(b"HTTP/1.1 503 Service Unavailable\r\nRetry-After: "));
+      let value = retry_after_value(&answer).expect("missing Retry-After header");
+      assert!(value >= 10 && value <= 20, "Retry-After value out of range: {}", value);
+      values.insert(value);
+    }
+
+    // clients should not all get the same value
+    assert!(values.len() > 1);
+    assert!(retry_after_value(&answers.get(DefaultAnswerStatus::Answer404, None)).is_none());
+  }
+
+  #[test]
+  fn retry_after_is_optional() {
+    let mut answers = HttpAnswers::new("HTTP/1.1 404 Not Found\r\n\r\n",
+      "HTTP/1.1 503 Service Unavailable\r\n\r\n", None);
+    assert!(retry_after_value(&answers.get(DefaultAnswerStatus::Answer503, None)).is_none());
+
+    // an existing header is kept
+    answers.retry_after = Some(RetryAfter { min: 30, max: 5 });
+    answers.add_custom_answer("app_1", "HTTP/1.1 503 Service Unavailable\r\nretry-after: 120\r\n\r\n");
+    let answer = answers.get(DefaultAnswerStatus::Answer503, Some("app_1"));
+    assert_eq!(&answer[..], &b"HTTP/1.1 503 Service Unavailable\r\nretry-after: 120\r\n\r\n"[..]);
+
+    let value = retry_after_value(&answers.get(DefaultAnswerStatus::Answer503, None)).unwrap();
+    assert!(value >= 5 && value <= 30);
+  }
+
+  #[test]
+  fn connection_errors_are_answered() {
+    let answers = HttpAnswers::new("HTTP/1.1 404 Not Found\r\nContent-Length: 9\r\n\r\nnot found",
+      "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 11\r\n\r\nunavailable", None);
+
+    let expected = vec![
+      (ConnectionError::NoHostGiven,        "HTTP/1.1 400 Bad Request\r\n", ""),
+      (ConnectionError::NoRequestLineGiven, "HTTP/1.1 400 Bad Request\r\n", ""),
+      (ConnectionError::InvalidHost,        "HTTP/1.1 400 Bad Request\r\n", ""),
+      (ConnectionError::HostNotFound,       "HTTP/1.1 404 Not Found\r\n", "not found"),
+      (ConnectionError::PayloadTooLarge,    "HTTP/1.1 413 Payload Too Large\r\n", ""),
+      (ConnectionError::NoBackendAvailable, "HTTP/1.1 503 Service Unavailable\r\n", "unavailable"),
+      (ConnectionError::ApplicationDisabled, "HTTP/1.1 503 Service Unavailable\r\n", "unavailable"),
+    ];
+
+    for (error, status_line, body) in expected {
+      let answer = answers.get(connection_error_status(&error), None);
+      let answer = str::from_utf8(&answer).unwrap();
+      assert!(answer.starts_with(status_line), "{:?} answered with {}", error, answer);
+      assert!(answer.ends_with(&format!("\r\n\r\n{}", body)), "{:?} answered with {}", error, answer);
     }
 
+    // the redirection depends on the request, it is generated by the proxy
+    assert_eq!(connection_error_status(&ConnectionError::HttpsRedirect), DefaultAnswerStatus::Answer301);
   }
 
+  #[test]
+  fn error_pages_replace_default_answers() {
+    let mut answers = HttpAnswers::new("HTTP/1.1 404 Not Found\r\n\r\n",
+      "HTTP/1.1 503 Service Unavailable\r\n\r\n", None);
+    answers.add_custom_answer("app_1", "HTTP/1.1 503 app_1 is in maintenance\r\n\r\n");
+
+    let mut error_pages = BTreeMap::new();
+    error_pages.insert(503, ErrorPage {
+      body: String::from("<h1>back soon</h1>"),
+      content_type: String::from("text/html; charset=utf-8"),
+    });
+    error_pages.insert(413, ErrorPage {
+      body: String::from("{\"error\":\"too large\"}"),
+      content_type: String::from("application/json"),
+    });
+    // sozu never answers with a 418
+    error_pages.insert(418, ErrorPage { body: String::from("teapot"), content_type: String::from("text/plain") });
+    answers.set_error_pages(&error_pages);
+
+    assert_eq!(&answers.get(DefaultAnswerStatus::Answer503, None)[..],
+      &b"HTTP/1.1 503 Service Unavailable\r\nCache-Control: no-cache\r\nConnection: close\r\n\
+      Content-Type: text/html; charset=utf-8\r\nContent-Length: 18\r\n\r\n<h1>back soon</h1>"[..]);
+    assert_eq!(&answers.get(DefaultAnswerStatus::Answer413, None)[..],
+      &b"HTTP/1.1 413 Payload Too Large\r\nCache-Control: no-cache\r\nConnection: close\r\n\
+      Content-Type: application/json\r\nContent-Length: 21\r\n\r\n{\"error\":\"too large\"}"[..]);
+
+    // the application's own answer is more specific than the error page
+    assert_eq!(&answers.get(DefaultAnswerStatus::Answer503, Some("app_1"))[..],
+      &b"HTTP/1.1 503 app_1 is in maintenance\r\n\r\n"[..]);
+
+    // statuses without an error page keep their default answer
+    assert_eq!(&answers.get(DefaultAnswerStatus::Answer404, None)[..], &b"HTTP/1.1 404 Not Found\r\n\r\n"[..]);
+    assert_eq!(&answers.get(DefaultAnswerStatus::Answer400, None)[..],
+      &b"HTTP/1.1 400 Bad Request\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n"[..]);
+  }
 }
@@ -0,0 +1,172 @@
+//! effective client address of HTTP requests
+//!
+//! The client address can come from the TCP connection, the PROXY protocol
+//! header, or headers set by proxies in front of sozu. The listener's
+//! `ClientIpConfig` lists the sources to try in order. The headers can be
+//! forged by any client, so they are only used when the connection comes
+//! from one of the trusted proxies.
+use std::net::{IpAddr,SocketAddr};
+use sozu_command::proxy::{ClientIpConfig,ClientIpSource,IpNetwork};
+
+/// sources used when the listener does not configure any
+const DEFAULT_SOURCES: &[ClientIpSource] = &[ClientIpSource::ProxyProtocol, ClientIpSource::Peer];
+
+/// returns the client address of a request. `proxy_protocol` is the source address of
+/// the PROXY protocol header, `forwarded_for` the addresses of the X-Forwarded-For
+/// headers and `header` returns the value of a request header. The addresses found
+/// in headers have the port 0
+pub fn client_address<F>(config: &ClientIpConfig, peer: Option<SocketAddr>, proxy_protocol: Option<SocketAddr>,
+  forwarded_for: Option<&str>, header: F) -> Option<SocketAddr>
+  where F: Fn(&str) -> Option<String> {
+  let connection = proxy_protocol.or(peer);
+  let from_trusted_proxy = connection.map(|address| is_trusted(&config.trusted_proxies, &address.ip()))
+    .unwrap_or(false);
+
+  let sources = if config.sources.is_empty() {
+    DEFAULT_SOURCES
+  } else {
+    &config.sources[..]
+  };
+
+  for source in sources {
+    let address = match *source {
+      ClientIpSource::Peer => peer,
+      ClientIpSource::ProxyProtocol => proxy_protocol,
+      ClientIpSource::XForwardedFor if from_trusted_proxy => {
+        forwarded_for.and_then(|addresses| last_untrusted_address(&config.trusted_proxies, addresses))
+          .map(|ip| SocketAddr::new(ip, 0))
+      },
+      ClientIpSource::Header { ref name } if from_trusted_proxy => {
+        header(name).and_then(|value| parse_address(&value))
+      },
+      _ => None,
+    };
+
+    if address.is_some() {
+      return address;
+    }
+  }
+
+  connection
+}
+
+fn is_trusted(trusted_proxies: &[IpNetwork], ip: &IpAddr) -> bool {
+  trusted_proxies.iter().any(|network| network.contains(ip))
+}
+
+/// every proxy appends the address it received the request from, so the client is
+/// the last address that is not one of our proxies. If they all are, it is the first one
+fn last_untrusted_address(trusted_proxies: &[IpNetwork], forwarded_for: &str) -> Option<IpAddr> {
+  let mut first = None;
+  for element in forwarded_for.rsplit(',') {
+    let ip = match parse_address(element) {
+      Some(address) => address.ip(),
+      // we cannot know who added the elements before an invalid one
+      None => return None,
+    };
+
+    if !is_trusted(trusted_proxies, &ip) {
+      return Some(ip);
+    }
+    first = Some(ip);
+  }
+
+  first
+}
+
+/// parses an IP address with an optional port, IPv6 addresses with a port being in brackets
+fn parse_address(s: &str) -> Option<SocketAddr> {
+  let s = s.trim();
+  s.parse::<SocketAddr>().ok().or_else(|| {
+    s.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>().ok().map(|ip| SocketAddr::new(ip, 0))
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn config(sources: Vec<ClientIpSource>, trusted_proxies: &[&str]) -> ClientIpConfig {
+    ClientIpConfig {
+      sources,
+      trusted_proxies: trusted_proxies.iter().map(|network| network.parse().unwrap()).collect(),
+    }
+  }
+
+  fn address(s: &str) -> Option<SocketAddr> {
+    Some(s.parse().unwrap())
+  }
+
+  fn no_header(_: &str) -> Option<String> {
+    None
+  }
+
+  #[test]
+  fn default_sources() {
+    let config = config(vec![], &[]);
+    assert_eq!(client_address(&config, address("10.0.0.1:1234"), None, Some("1.2.3.4"), no_header),
+      address("10.0.0.1:1234"));
+    assert_eq!(client_address(&config, address("10.0.0.1:1234"), address("1.2.3.4:5678"), None, no_header),
+      address("1.2.3.4:5678"));
+    assert_eq!(client_address(&config, None, None, None, no_header), None);
+  }
+
+  #[test]
+  fn forwarded_for_needs_a_trusted_proxy() {
+    let config = config(vec![ClientIpSource::XForwardedFor, ClientIpSource::Peer], &["10.0.0.0/8"]);
+    assert_eq!(client_address(&config, address("10.1.2.3:1234"), None, Some("1.2.3.4, 10.0.0.2"), no_header),
+      address("1.2.3.4:0"));
+    // a client sending its own header
+    assert_eq!(client_address(&config, address("192.168.1.1:1234"), None, Some("1.2.3.4"), no_header),
+      address("192.168.1.1:1234"));
+    // the proxy did not add the header
+    assert_eq!(client_address(&config, address("10.1.2.3:1234"), None, None, no_header),
+      address("10.1.2.3:1234"));
+  }
+
+  #[test]
+  fn forwarded_for_skips_trusted_proxies() {
+    let config = config(vec![ClientIpSource::XForwardedFor], &["10.0.0.0/8", "2001:db8::/32"]);
+    // the client forged the first element
+    assert_eq!(client_address(&config, address("10.1.2.3:1234"), None, Some("6.6.6.6, 1.2.3.4, 10.0.0.2"), no_header),
+      address("1.2.3.4:0"));
+    assert_eq!(client_address(&config, address("10.1.2.3:1234"), None, Some("[2001:db8::1]:80,2001:db8::2"), no_header),
+      address("[2001:db8::1]:0"));
+    assert_eq!(client_address(&config, address("10.1.2.3:1234"), None, Some("1.2.3.4, garbage, 10.0.0.2"), no_header),
+      address("10.1.2.3:1234"));
+  }
+
+  #[test]
+  fn header_source() {
+    let real_ip = |name: &str| if name == "X-Real-IP" { Some(String::from("1.2.3.4")) } else { None };
+    let config = config(vec![
+      ClientIpSource::Header { name: String::from("CF-Connecting-IP") },
+      ClientIpSource::Header { name: String::from("X-Real-IP") },
+      ClientIpSource::XForwardedFor,
+    ], &["10.0.0.1"]);
+
+    assert_eq!(client_address(&config, address("10.0.0.1:1234"), None, Some("5.6.7.8"), real_ip),
+      address("1.2.3.4:0"));
+    assert_eq!(client_address(&config, address("10.0.0.1:1234"), None, Some("5.6.7.8"), no_header),
+      address("5.6.7.8:0"));
+    assert_eq!(client_address(&config, address("10.0.0.2:1234"), None, Some("5.6.7.8"), real_ip),
+      address("10.0.0.2:1234"));
+  }
+
+  #[test]
+  fn proxy_protocol_source() {
+    let config = config(vec![ClientIpSource::XForwardedFor, ClientIpSource::ProxyProtocol, ClientIpSource::Peer],
+      &["10.0.0.0/8"]);
+    // the PROXY protocol address is the one checked against the trusted proxies
+    assert_eq!(client_address(&config, address("192.168.1.1:1234"), address("10.0.0.5:4000"), Some("1.2.3.4"), no_header),
+      address("1.2.3.4:0"));
+    assert_eq!(client_address(&config, address("10.1.1.1:1234"), address("8.8.8.8:4000"), Some("1.2.3.4"), no_header),
+      address("8.8.8.8:4000"));
+    assert_eq!(client_address(&config, address("10.1.1.1:1234"), None, None, no_header),
+      address("10.1.1.1:1234"));
+
+    let config = self::config(vec![ClientIpSource::Peer], &[]);
+    assert_eq!(client_address(&config, address("10.1.1.1:1234"), address("8.8.8.8:4000"), None, no_header),
+      address("10.1.1.1:1234"));
+  }
+}
@@ -0,0 +1,285 @@
+//! gzip compression of the response bodies
+//!
+//! The compression starts once the response headers are parsed: the
+//! Content-Length header is removed and the compressed body is sent with chunked
+//! encoding. The body stays in the back buffer until it is compressed: each
+//! part received is deleted from the output queue and replaced by a chunk of
+//! compressed data. The encoder is flushed after each part, so every part
+//! produces output and frees its room in the buffer once it is sent.
+//! A chunked body is decoded before being compressed, its trailers are dropped.
+use std::io::Write;
+use std::mem;
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use buffer_queue::{BufferQueue,OutputElement};
+
+/// the Accept-Encoding header value accepts gzip, with a quality above 0
+pub fn accepts_gzip(accept_encoding: &str) -> bool {
+  accept_encoding.split(',').any(|coding| {
+    let mut params = coding.split(';');
+    let name = params.next().unwrap_or("").trim();
+    if !(name.eq_ignore_ascii_case("gzip") || name.eq_ignore_ascii_case("x-gzip") || name == "*") {
+      return false;
+    }
+
+    params.filter_map(|param| {
+      let mut kv = param.splitn(2, '=');
+      match (kv.next().map(str::trim), kv.next()) {
+        (Some(key), Some(value)) if key.eq_ignore_ascii_case("q") => value.trim().parse::<f32>().ok(),
+        _ => None,
+      }
+    }).next().map(|quality| quality > 0.0).unwrap_or(true)
+  })
+}
+
+/// the media type of the Content-Type header value is one of the types.
+/// A `type/*` entry matches all the subtypes
+pub fn is_compressible(content_type: &str, types: &[String]) -> bool {
+  let media_type = content_type.split(';').next().unwrap_or("").trim();
+  types.iter().any(|t| {
+    if t.ends_with("/*") {
+      media_type.len() > t.len() - 1 && media_type[..t.len() - 1].eq_ignore_ascii_case(&t[..t.len() - 1])
+    } else {
+      media_type.eq_ignore_ascii_case(t)
+    }
+  })
+}
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+enum ChunkState {
+  Size(usize),
+  Extension(usize),
+  SizeEnd(usize),
+  Data(usize),
+  DataEnd,
+  DataEndLf,
+  /// the boolean is true at the beginning of a trailer line
+  Trailers(bool),
+  TrailersEnd,
+  Done,
+}
+
+/// extracts the data of a chunked body. The parser already validated the chunks,
+/// so the invalid bytes are not reported
+#[derive(Debug)]
+pub struct Dechunker {
+  state: ChunkState,
+}
+
+impl Dechunker {
+  pub fn new() -> Dechunker {
+    Dechunker { state: ChunkState::Size(0) }
+  }
+
+  /// appends the chunk data found in this part of the body to `out`
+  pub fn decode(&mut self, mut input: &[u8], out: &mut Vec<u8>) {
+    while !input.is_empty() {
+      if let ChunkState::Data(remaining) = self.state {
+        let size = if remaining < input.len() { remaining } else { input.len() };
+        out.extend_from_slice(&input[..size]);
+        input = &input[size..];
+        self.state = if size == remaining { ChunkState::DataEnd } else { ChunkState::Data(remaining - size) };
+        continue;
+      }
+
+      let byte = input[0];
+      input = &input[1..];
+      self.state = match self.state {
+        ChunkState::Size(size) => match (byte as char).to_digit(16) {
+          Some(digit) => ChunkState::Size(size * 16 + digit as usize),
+          None if byte == b'\r' => ChunkState::SizeEnd(size),
+          None => ChunkState::Extension(size),
+        },
+        ChunkState::Extension(size) => if byte == b'\r' { ChunkState::SizeEnd(size) } else { ChunkState::Extension(size) },
+        ChunkState::SizeEnd(0) => ChunkState::Trailers(true),
+        ChunkState::SizeEnd(size) => ChunkState::Data(size),
+        ChunkState::DataEnd => ChunkState::DataEndLf,
+        ChunkState::DataEndLf => ChunkState::Size(0),
+        ChunkState::Trailers(true) if byte == b'\r' => ChunkState::TrailersEnd,
+        ChunkState::Trailers(_) => ChunkState::Trailers(byte == b'\n'),
+        ChunkState::TrailersEnd | ChunkState::Done => ChunkState::Done,
+        ChunkState::Data(_) => unreachable!(),
+      };
+    }
+  }
+}
+
+/// compression of the body of the current response
+pub struct ResponseCompressor {
+  encoder:  GzEncoder<Vec<u8>>,
+  /// set if the body sent by the backend is chunked
+  chunks:   Option<Dechunker>,
+  /// position in the stream of the first body byte not compressed yet
+  position: usize,
+  finished: bool,
+}
+
+impl ResponseCompressor {
+  /// `body_start` is the position in the stream of the end of the response headers
+  pub fn new(body_start: usize, chunked: bool) -> ResponseCompressor {
+    ResponseCompressor {
+      encoder:  GzEncoder::new(Vec::new(), Compression::default()),
+      chunks:   if chunked { Some(Dechunker::new()) } else { None },
+      position: body_start,
+      finished: false,
+    }
+  }
+
+  /// compresses the body data parsed since the last call and replaces it in the
+  /// output queue by a chunk of compressed data. `complete` is set when the whole
+  /// body was parsed, to write the end of the gzip stream and the last chunk
+  pub fn compress(&mut self, buf: &mut BufferQueue, complete: bool) {
+    if self.finished {
+      return;
+    }
+
+    // the parser copies the body as is, its data is sent by the compressor
+    truncate_output(buf, self.position);
+
+    let end = buf.parsed_position;
+    if end > self.position {
+      {
+        let data = &buf.buffer.data()[self.position - buf.buffer_position..end - buf.buffer_position];
+        let result = match self.chunks {
+          Some(ref mut chunks) => {
+            let mut body = Vec::new();
+            chunks.decode(data, &mut body);
+            self.encoder.write_all(&body)
+          },
+          None => self.encoder.write_all(data),
+        };
+        // writing to a vector does not fail
+        let _ = result.and_then(|_| self.encoder.flush());
+      }
+      buf.delete_output(end - self.position);
+      self.position = end;
+    }
+
+    if complete {
+      let _ = self.encoder.try_finish();
+      self.finished = true;
+    }
+
+    let compressed = mem::replace(self.encoder.get_mut(), Vec::new());
+    let mut output = Vec::new();
+    if !compressed.is_empty() {
+      output.extend_from_slice(format!("{:x}\r\n", compressed.len()).as_bytes());
+      output.extend_from_slice(&compressed);
+      output.extend_from_slice(b"\r\n");
+    }
+    if self.finished {
+      output.extend_from_slice(b"0\r\n\r\n");
+    }
+    if !output.is_empty() {
+      buf.insert_output(output);
+    }
+  }
+}
+
+/// removes the parts of the output queue's slices after this position in the stream
+fn truncate_output(buf: &mut BufferQueue, position: usize) {
+  let mut current = buf.buffer_position;
+  let mut index = 0;
+  while index < buf.output_queue.len() {
+    match buf.output_queue[index] {
+      OutputElement::Slice(sz) if current + sz > position => {
+        if position > current {
+          buf.output_queue[index] = OutputElement::Slice(position - current);
+          current = position;
+        } else {
+          buf.output_queue.remove(index);
+          continue;
+        }
+      },
+      OutputElement::Slice(sz) | OutputElement::Delete(sz) => current += sz,
+      _ => {},
+    }
+    index += 1;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::io::Read;
+  use flate2::read::GzDecoder;
+  use buffer_queue::buf_with_capacity;
+
+  #[test]
+  fn accept_encoding() {
+    assert!(accepts_gzip("gzip"));
+    assert!(accepts_gzip("deflate, GZIP;q=0.5"));
+    assert!(accepts_gzip("br;q=1.0, *"));
+    assert!(!accepts_gzip("gzip;q=0"));
+    assert!(!accepts_gzip("identity"));
+    assert!(!accepts_gzip(""));
+  }
+
+  #[test]
+  fn compressible_types() {
+    let types = vec![String::from("text/*"), String::from("application/json")];
+    assert!(is_compressible("text/html; charset=utf-8", &types));
+    assert!(is_compressible("Application/JSON", &types));
+    assert!(!is_compressible("image/png", &types));
+    assert!(!is_compressible("text", &types));
+  }
+
+  #[test]
+  fn dechunk() {
+    let body = b"5;ext=1\r\nhello\r\n7\r\n, world\r\n0\r\nTrailer: value\r\n\r\n";
+    // the same data, decoded in one part or byte by byte
+    let mut out = Vec::new();
+    Dechunker::new().decode(&body[..], &mut out);
+    assert_eq!(&out[..], &b"hello, world"[..]);
+
+    let mut dechunker = Dechunker::new();
+    let mut out = Vec::new();
+    for byte in body.iter() {
+      dechunker.decode(&[*byte], &mut out);
+    }
+    assert_eq!(&out[..], &b"hello, world"[..]);
+    assert_eq!(dechunker.state, ChunkState::Done);
+  }
+
+  #[test]
+  fn compress_body_parts() {
+    let (_pool, mut buf) = buf_with_capacity(16384);
+    let headers = b"HTTP/1.1 200 OK\r\n\r\n";
+    let body = "compressible text ".repeat(100);
+
+    // headers and the first part of the body, with the body slice of the parser
+    buf.buffer.write(&headers[..]).unwrap();
+    buf.buffer.write(body[..1000].as_bytes()).unwrap();
+    buf.sliced_input(headers.len() + 1000);
+    buf.consume_parsed_data(headers.len() + body.len());
+    buf.slice_output(headers.len() + body.len());
+
+    let mut compressor = ResponseCompressor::new(headers.len(), false);
+    compressor.compress(&mut buf, false);
+
+    buf.buffer.write(body[1000..].as_bytes()).unwrap();
+    buf.sliced_input(body.len() - 1000);
+    compressor.compress(&mut buf, true);
+
+    let mut output = Vec::new();
+    while buf.output_data_size() > 0 {
+      let size = {
+        let data = buf.next_output_data();
+        output.extend_from_slice(data);
+        data.len()
+      };
+      buf.consume_output_data(size);
+    }
+    assert!(buf.can_restart_parsing());
+
+    assert!(output.starts_with(&headers[..]));
+    let mut chunks = Vec::new();
+    let mut dechunker = Dechunker::new();
+    dechunker.decode(&output[headers.len()..], &mut chunks);
+    assert_eq!(dechunker.state, ChunkState::Done);
+
+    let mut decompressed = String::new();
+    GzDecoder::new(&chunks[..]).read_to_string(&mut decompressed).unwrap();
+    assert_eq!(decompressed, body);
+  }
+}
@@ -0,0 +1,251 @@
+//! extension point to run custom logic on HTTP requests and responses
+//!
+//! A transformer is registered per worker thread with `set_transformer`, and is
+//! called once the headers of a request (or a response) are parsed. It can add
+//! headers, or stop the request and send its own answer to the client.
+//!
+//! Transformers run synchronously in the event loop, so they cannot be
+//! interrupted. To avoid a bad transformer degrading the whole worker:
+//! - a transformer that panics is removed right away
+//! - a call taking longer than the configured duration is logged, and the
+//! transformer is removed after `MAX_SLOW_CALLS` consecutive slow calls
+//! - headers with invalid names or values are not added
+use std::cell::RefCell;
+use std::net::SocketAddr;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use time::{Duration, SteadyTime};
+
+use super::parser::{RRequestLine, RStatusLine};
+
+/// number of consecutive calls over the time budget before the transformer is removed
+pub const MAX_SLOW_CALLS: usize = 3;
+
+thread_local! {
+  static TRANSFORMER: RefCell<Option<Registered>> = RefCell::new(None);
+}
+
+/// request data available to the transformer
+pub struct RequestInfo<'a> {
+  pub request_line:   &'a RRequestLine,
+  pub host:           &'a str,
+  pub client_address: Option<SocketAddr>,
+}
+
+/// response data available to the transformer
+pub struct ResponseInfo<'a> {
+  pub request_line: Option<&'a RRequestLine>,
+  pub host:         Option<&'a str>,
+  pub status_line:  &'a RStatusLine,
+  pub app_id:       Option<&'a str>,
+}
+
+#[derive(Debug,Clone,PartialEq)]
+pub enum Transform {
+  /// forward the message unmodified
+  Continue,
+  /// forward the message with these additional headers
+  AddHeaders(Vec<(String, String)>),
+  /// do not forward the message, send this complete HTTP answer to the client and close
+  Answer(Vec<u8>),
+}
+
+pub trait HttpTransformer {
+  fn on_request(&mut self, _request: &RequestInfo) -> Transform {
+    Transform::Continue
+  }
+
+  fn on_response(&mut self, _response: &ResponseInfo) -> Transform {
+    Transform::Continue
+  }
+}
+
+struct Registered {
+  transformer:  Box<HttpTransformer>,
+  max_duration: Duration,
+  slow_calls:   usize,
+}
+
+/// registers the transformer for the current worker thread, replacing the previous one
+pub fn set_transformer(transformer: Box<HttpTransformer>, max_duration: Duration) {
+  TRANSFORMER.with(|t| {
+    *t.borrow_mut() = Some(Registered { transformer, max_duration, slow_calls: 0 });
+  });
+}
+
+pub fn remove_transformer() {
+  TRANSFORMER.with(|t| t.borrow_mut().take());
+}
+
+pub fn has_transformer() -> bool {
+  TRANSFORMER.with(|t| t.borrow().is_some())
+}
+
+pub fn transform_request(request: &RequestInfo) -> Transform {
+  call("request", |transformer| transformer.on_request(request))
+}
+
+pub fn transform_response(response: &ResponseInfo) -> Transform {
+  call("response", |transformer| transformer.on_response(response))
+}
+
+fn call<F>(phase: &str, f: F) -> Transform
+  where F: FnOnce(&mut HttpTransformer) -> Transform {
+  // the transformer is taken out during the call, so that it can
+  // register another transformer without a double borrow
+  let mut registered = match TRANSFORMER.with(|t| t.borrow_mut().take()) {
+    Some(registered) => registered,
+    None             => return Transform::Continue,
+  };
+
+  let start = SteadyTime::now();
+  let res = catch_unwind(AssertUnwindSafe(|| f(&mut *registered.transformer)));
+  let elapsed = SteadyTime::now() - start;
+
+  let transform = match res {
+    Ok(transform) => transform,
+    Err(_) => {
+      error!("the HTTP transformer panicked on a {}, removing it", phase);
+      incr!("http.transformer.panics");
+      return Transform::Continue;
+    }
+  };
+
+  if elapsed > registered.max_duration {
+    registered.slow_calls += 1;
+    error!("the HTTP transformer took {}ms on a {} ({} consecutive slow calls)",
+      elapsed.num_milliseconds(), phase, registered.slow_calls);
+    incr!("http.transformer.slow_calls");
+  } else {
+    registered.slow_calls = 0;
+  }
+
+  if registered.slow_calls >= MAX_SLOW_CALLS {
+    error!("the HTTP transformer is too slow, removing it");
+  } else {
+    TRANSFORMER.with(|t| {
+      let mut t = t.borrow_mut();
+      if t.is_none() {
+        *t = Some(registered);
+      }
+    });
+  }
+
+  match transform {
+    Transform::AddHeaders(headers) => Transform::AddHeaders(headers.into_iter().filter(|&(ref name, ref value)| {
+      let valid = is_valid_header(name, value);
+      if !valid {
+        error!("the HTTP transformer returned an invalid header: {:?}: {:?}", name, value);
+      }
+      valid
+    }).collect()),
+    t => t,
+  }
+}
+
+pub fn is_valid_header(name: &str, value: &str) -> bool {
+  !name.is_empty()
+    && name.bytes().all(|c| c > 32 && c < 127 && c != b':')
+    && value.bytes().all(|c| c != b'\r' && c != b'\n')
+}
+
+/// formats the headers to be inserted in a request or response
+pub fn format_headers(headers: &[(String, String)]) -> String {
+  let mut s = String::new();
+  for &(ref name, ref value) in headers.iter() {
+    s.push_str(name);
+    s.push_str(": ");
+    s.push_str(value);
+    s.push_str("\r\n");
+  }
+  s
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::{thread, time};
+  use protocol::http::parser::{Method, Version};
+
+  struct Counter(usize);
+
+  impl HttpTransformer for Counter {
+    fn on_request(&mut self, request: &RequestInfo) -> Transform {
+      self.0 += 1;
+      Transform::AddHeaders(vec![
+        (String::from("X-Count"), self.0.to_string()),
+        (String::from("X-Host"), request.host.to_string()),
+        (String::from("Bad Name"), String::from("value")),
+        (String::from("X-Injection"), String::from("a\r\nb")),
+      ])
+    }
+  }
+
+  struct Panics;
+
+  impl HttpTransformer for Panics {
+    fn on_request(&mut self, _request: &RequestInfo) -> Transform {
+      panic!("transformer error");
+    }
+  }
+
+  struct Slow;
+
+  impl HttpTransformer for Slow {
+    fn on_request(&mut self, _request: &RequestInfo) -> Transform {
+      thread::sleep(time::Duration::from_millis(20));
+      Transform::Answer(Vec::from(&b"HTTP/1.1 403 Forbidden\r\n\r\n"[..]))
+    }
+  }
+
+  fn request_line() -> RRequestLine {
+    RRequestLine { method: Method::Get, uri: String::from("/"), version: Version::V11 }
+  }
+
+  #[test]
+  fn transformer_headers() {
+    let request_line = request_line();
+    let info = RequestInfo { request_line: &request_line, host: "example.com", client_address: None };
+
+    assert_eq!(transform_request(&info), Transform::Continue);
+
+    set_transformer(Box::new(Counter(0)), Duration::seconds(1));
+    let expected = vec![
+      (String::from("X-Count"), String::from("1")),
+      (String::from("X-Host"), String::from("example.com")),
+    ];
+    assert_eq!(transform_request(&info), Transform::AddHeaders(expected.clone()));
+    assert_eq!(&format_headers(&expected), "X-Count: 1\r\nX-Host: example.com\r\n");
+
+    // the transformer keeps its state between calls
+    if let Transform::AddHeaders(headers) = transform_request(&info) {
+      assert_eq!(headers[0], (String::from("X-Count"), String::from("2")));
+    } else {
+      panic!("expected headers");
+    }
+    remove_transformer();
+  }
+
+  #[test]
+  fn panicking_transformer_is_removed() {
+    let request_line = request_line();
+    let info = RequestInfo { request_line: &request_line, host: "example.com", client_address: None };
+
+    set_transformer(Box::new(Panics), Duration::seconds(1));
+    assert_eq!(transform_request(&info), Transform::Continue);
+    assert!(!has_transformer());
+  }
+
+  #[test]
+  fn slow_transformer_is_removed() {
+    let request_line = request_line();
+    let info = RequestInfo { request_line: &request_line, host: "example.com", client_address: None };
+
+    set_transformer(Box::new(Slow), Duration::milliseconds(1));
+    for _ in 0..MAX_SLOW_CALLS {
+      assert!(has_transformer());
+      // the answer is still used, the transformer is only removed for the next requests
+      assert_eq!(transform_request(&info), Transform::Answer(Vec::from(&b"HTTP/1.1 403 Forbidden\r\n\r\n"[..])));
+    }
+    assert!(!has_transformer());
+  }
+}
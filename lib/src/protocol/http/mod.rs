@@ -1,4 +1,5 @@
 use std::cmp::min;
+use std::str::from_utf8;
 use std::rc::{Rc,Weak};
 use std::cell::RefCell;
 use std::net::{SocketAddr,IpAddr};
@@ -8,20 +9,35 @@ use mio::tcp::TcpStream;
 use uuid::{Uuid, adapter::Hyphenated};
 use time::{SteadyTime, Duration};
 use sozu_command::buffer::Buffer;
-use super::super::{SessionResult,Protocol,Readiness,SessionMetrics, LogDuration};
+use sozu_command::certificate;
+use sozu_command::logging::AccessRecord;
+use sozu_command::proxy::{HeaderRule,HeaderMatch,UnexpectedBodyPolicy,TrailingSlash,ClientIpConfig,HeaderLimits,ResponseCompression};
+use sozu_command::config::ProxyProtocolVersion;
+use super::super::{SessionResult,Protocol,Readiness,SessionMetrics, LogDuration, Backend};
 use buffer_queue::BufferQueue;
 use socket::{SocketHandler, SocketResult, TransportProtocol};
 use protocol::ProtocolResult;
+use protocol::proxy_protocol::header::ProxyProtocolHeader;
 use pool::Pool;
+use request_limit::RequestSlot;
 use util::UnwrapLog;
+use tracing;
 
 pub mod parser;
 mod cookies;
 pub mod answers;
+pub mod hooks;
+pub mod client_ip;
+pub mod compression;
 
-use self::parser::{parse_request_until_stop, parse_response_until_stop,
-  RequestState, ResponseState, Chunk, Continue, RRequestLine, RStatusLine,
-  Method, compare_no_case};
+use self::hooks::Transform;
+use self::compression::{ResponseCompressor,accepts_gzip,is_compressible};
+use self::parser::{parse_request_until_stop, is_missing_host, parse_response_until_stop, delete_headers, replace_header, replace_headers, header_value, hostname_without_port,
+  RequestState, ResponseState, Chunk, Continue, LengthInformation, RRequestLine, RStatusLine,
+  Method, Version, HeaderBlockSize, line_length, compare_no_case};
+
+/// above this size, the bytes sent for a request are not kept to send it again
+pub const MAX_RESENT_REQUEST_SIZE: usize = 16384;
 
 #[derive(Clone)]
 pub struct StickySession {
@@ -50,8 +66,14 @@ pub enum DefaultAnswerStatus {
   Answer404,
   Answer408,
   Answer413,
+  Answer414,
+  Answer417,
+  Answer431,
+  Answer502,
   Answer503,
   Answer504,
+  /// answer sent by the HTTP transformer
+  AnswerTransformer,
 }
 
 #[derive(Debug,Clone,Copy,PartialEq)]
@@ -72,12 +94,34 @@ pub struct Http<Front:SocketHandler> {
   pub app_id:         Option<String>,
   pub request_id:     Hyphenated,
   pub backend_id:     Option<String>,
+  /// backend of the current request, its response times feed the adaptive load balancing
+  selected_backend:   Option<Rc<RefCell<Backend>>>,
+  /// backends selected for the current request, including the connection retries
+  backend_attempts:   u8,
   pub front_readiness:Readiness,
   pub back_readiness: Readiness,
   pub public_address: SocketAddr,
   pub session_address: Option<SocketAddr>,
   pub backend_address: Option<SocketAddr>,
+  /// how the listener finds the client address of the requests
+  pub client_ip:      Rc<ClientIpConfig>,
+  /// client address of the current request, if the listener configures its sources
+  client_address:     Option<SocketAddr>,
   pub sticky_name:    String,
+  /// forward the Proxy-Connection header instead of removing it
+  pub preserve_proxy_connection: bool,
+  /// forward requests with an unsupported Expect header instead of answering 417
+  pub ignore_unsupported_expect: bool,
+  /// what to do with a GET, HEAD or TRACE request carrying a body
+  pub unexpected_body_policy: UnexpectedBodyPolicy,
+  /// limits on the size and number of the request headers, set by the listener
+  pub header_limits:  HeaderLimits,
+  /// header lines of the current request received before the end of its header block
+  req_header_block:   HeaderBlockSize,
+  /// header lines of the current response received before the end of its header block
+  res_header_block:   HeaderBlockSize,
+  /// reception of the first byte of the current request, until the end of its header block
+  req_header_start:   Option<SteadyTime>,
   pub sticky_session: Option<StickySession>,
   pub protocol:       Protocol,
   pub request:        Option<RequestState>,
@@ -87,15 +131,74 @@ pub struct Http<Front:SocketHandler> {
   pub added_req_header: String,
   pub added_res_header: String,
   pub keepalive_count: usize,
+  /// the front connection is closed after this number of requests
+  max_keepalive_requests: Option<usize>,
   pub backend_stop:    Option<SteadyTime>,
   pub closing:         bool,
+  /// set when the current request matches one of the traces sent by sozuctl
+  pub traced:          bool,
+  /// set when the backend closed the connection before the end of the request
+  pub back_write_closed: bool,
+  /// headers describing the frontend TLS connection, added to the requests
+  /// of the applications configured with `forward_tls_info`
+  pub tls_info_headers: Option<String>,
+  tls_info_added:      bool,
+  /// certificate verified during the TLS handshake, if the listener requires one
+  pub client_certificate: Option<ClientCertificate>,
+  forwarded_headers_added: bool,
+  /// headers deleted from the current request and response by the application's header rules
+  request_headers_to_delete:  Vec<String>,
+  response_headers_to_delete: Vec<String>,
+  header_rules_added:  bool,
+  /// set by the application of the current request
+  rewrite_location:    bool,
+  backend_hosts:       Vec<String>,
+  rewrite_cookie_domain: bool,
+  /// answer statuses counted as failures of the backend, set by the application of the current request
+  failure_statuses:    Vec<u16>,
+  /// time given to the backend to answer the current request, set by its application
+  response_timeout:    Option<Duration>,
+  /// maximum size of the current request's body, set by its application
+  max_request_body_size: Option<usize>,
+  /// gzip compression of the responses, set by the listener
+  pub compression:     Option<Rc<ResponseCompression>>,
+  /// the client of the current request accepts gzip encoded responses
+  accepts_gzip:        bool,
+  /// compression of the current response's body
+  compressor:          Option<ResponseCompressor>,
+  /// the client shut down its side of the connection after sending its request
+  front_read_closed:   bool,
+  /// PROXY protocol header not written yet on the new backend connection
+  backend_proxy_header: Option<Vec<u8>>,
+  /// number of times the current request can be sent again on a new backend
+  /// connection when the backend closes it without answering, set by its application
+  max_request_retries: u8,
+  request_retries:     u8,
+  /// bytes of the current request written to the backend connection
+  request_bytes_sent:  usize,
+  /// copy of those bytes, kept while the request is idempotent and small enough to be sent again
+  sent_request:        Option<Vec<u8>>,
+  /// a PROXY protocol header describing this client was sent on the backend connection
+  proxy_protocol_connection: bool,
+  /// the backend connection is idle after a complete response and the backend keeps
+  /// it alive, it can be used by other sessions once this one ends
+  backend_reusable:    bool,
+  /// the last read from the backend found the connection closed
+  back_read_closed:    bool,
+  /// the backend closed the connection in the middle of its response, the retry
+  /// of the request should not go to the same backend
+  backend_failed:      bool,
+  /// slot of the current request in the concurrency limit of its application,
+  /// given back when the request ends
+  request_slot:        Option<RequestSlot>,
   pool:                Weak<RefCell<Pool<Buffer>>>,
 }
 
 impl<Front:SocketHandler> Http<Front> {
   pub fn new(sock: Front, token: Token, request_id: Hyphenated, pool: Weak<RefCell<Pool<Buffer>>>,
     public_address: SocketAddr, session_address: Option<SocketAddr>, sticky_name: String,
-    protocol: Protocol) -> Http<Front> {
+    preserve_proxy_connection: bool, ignore_unsupported_expect: bool,
+    unexpected_body_policy: UnexpectedBodyPolicy, protocol: Protocol) -> Http<Front> {
 
     let mut session = Http {
       frontend:           sock,
@@ -108,12 +211,23 @@ impl<Front:SocketHandler> Http<Front> {
       app_id:             None,
       request_id,
       backend_id:         None,
+      selected_backend:   None,
+      backend_attempts:   0,
       front_readiness:    Readiness::new(),
       back_readiness:     Readiness::new(),
       public_address,
       session_address,
       backend_address:    None,
+      client_ip:          Rc::new(ClientIpConfig::default()),
+      client_address:     None,
       sticky_name,
+      preserve_proxy_connection,
+      ignore_unsupported_expect,
+      unexpected_body_policy,
+      header_limits:      HeaderLimits::default(),
+      req_header_block:   HeaderBlockSize::default(),
+      res_header_block:   HeaderBlockSize::default(),
+      req_header_start:   None,
       sticky_session:     None,
       protocol,
       request:        Some(RequestState::Initial),
@@ -123,11 +237,41 @@ impl<Front:SocketHandler> Http<Front> {
       added_req_header: String::from(""),
       added_res_header: String::from(""),
       keepalive_count: 0,
+      max_keepalive_requests: None,
       backend_stop:    None,
       closing:         false,
+      traced:          false,
+      back_write_closed: false,
+      tls_info_headers: None,
+      tls_info_added:   false,
+      client_certificate: None,
+      forwarded_headers_added: false,
+      request_headers_to_delete:  Vec::new(),
+      response_headers_to_delete: Vec::new(),
+      header_rules_added: false,
+      rewrite_location: false,
+      backend_hosts:    Vec::new(),
+      rewrite_cookie_domain: false,
+      failure_statuses: Vec::new(),
+      response_timeout: None,
+      max_request_body_size: None,
+      compression:      None,
+      accepts_gzip:     false,
+      compressor:       None,
+      front_read_closed: false,
+      backend_proxy_header: None,
+      max_request_retries: 0,
+      request_retries:   0,
+      request_bytes_sent: 0,
+      sent_request:      None,
+      proxy_protocol_connection: false,
+      backend_reusable:  false,
+      back_read_closed:  false,
+      backend_failed:    false,
+      request_slot:      None,
       pool,
     };
-    session.added_req_header = session.added_request_header(public_address, session_address);
+    session.added_req_header = session.added_request_header();
     session.added_res_header = session.added_response_header();
 
     session
@@ -142,19 +286,47 @@ impl<Front:SocketHandler> Http<Front> {
     self.response = Some(ResponseState::Initial);
     self.req_header_end = None;
     self.res_header_end = None;
-    self.added_req_header = self.added_request_header(self.public_address, self.session_address);
+    self.req_header_block = HeaderBlockSize::default();
+    self.res_header_block = HeaderBlockSize::default();
+    self.back_write_closed = false;
+    self.client_address = None;
+    self.tls_info_added = false;
+    self.forwarded_headers_added = false;
+    self.request_headers_to_delete.clear();
+    self.response_headers_to_delete.clear();
+    self.header_rules_added = false;
+    self.rewrite_location = false;
+    self.backend_hosts.clear();
+    self.rewrite_cookie_domain = false;
+    self.failure_statuses.clear();
+    self.response_timeout = None;
+    self.max_request_body_size = None;
+    self.accepts_gzip = false;
+    self.compressor = None;
+    self.max_request_retries = 0;
+    self.request_retries = 0;
+    self.request_bytes_sent = 0;
+    self.sent_request = None;
+    self.backend_failed = false;
+    self.backend_attempts = 0;
+    self.request_slot = None;
+    self.keepalive_count += 1;
+    self.close_at_request_limit();
+    self.added_req_header = self.added_request_header();
     self.added_res_header = self.added_response_header();
 
     // if HTTP requests are pipelined, we might still have some data in the front buffer
     if self.front_buf.as_ref().map(|buf| !buf.empty()).unwrap_or(false) {
       self.front_readiness.event.insert(Ready::readable());
+      self.req_header_start = Some(SteadyTime::now());
     } else {
       self.front_buf = None;
+      self.req_header_start = None;
     }
 
     self.back_buf = None;
     self.request_id = request_id;
-    self.keepalive_count += 1;
+    self.traced = false;
   }
 
   pub fn log_context(&self) -> LogContext {
@@ -177,6 +349,24 @@ impl<Front:SocketHandler> Http<Front> {
       prefix, self.request, self.req_header_end, self.response, self.res_header_end)
   }
 
+  /// verbose log line of a traced session, with the tokens of its connections
+  pub fn trace_event(&self, event: ::std::fmt::Arguments) {
+    if self.traced {
+      verbose!("{}[{} -> {}] {}", self.log_context(), self.frontend_token.0,
+        self.backend_token.map(|t| t.0.to_string()).unwrap_or_else(|| String::from("-")), event);
+    }
+  }
+
+  /// the requests of the applications traced with `sozuctl trace-app` are
+  /// traced once they are routed
+  pub fn trace_application(&mut self, app_id: &str) {
+    if !self.traced && tracing::is_app_traced(app_id) {
+      self.traced = true;
+      self.trace_event(format_args!("{} request routed to application {}, {}", SessionAddress(self.get_session_address()),
+        app_id, self.print_state("state")));
+    }
+  }
+
   pub fn set_answer(&mut self, answer: DefaultAnswerStatus, buf: Rc<Vec<u8>>)  {
     self.front_buf = None;
     self.back_buf = None;
@@ -190,9 +380,21 @@ impl<Front:SocketHandler> Http<Front> {
         DefaultAnswerStatus::Answer404 => incr!("http.404.errors"),
         DefaultAnswerStatus::Answer408 => incr!("http.408.errors"),
         DefaultAnswerStatus::Answer413 => incr!("http.413.errors"),
+        DefaultAnswerStatus::Answer414 => incr!("http.414.errors"),
+        DefaultAnswerStatus::Answer417 => incr!("http.417.errors"),
+        DefaultAnswerStatus::Answer431 => incr!("http.431.errors"),
+        DefaultAnswerStatus::Answer502 => incr!("http.502.errors"),
         DefaultAnswerStatus::Answer503 => incr!("http.503.errors"),
         DefaultAnswerStatus::Answer504 => incr!("http.504.errors"),
+        DefaultAnswerStatus::AnswerTransformer => incr!("http.transformer.answers"),
       };
+
+      // the backend broke its response or did not answer in time
+      if answer == DefaultAnswerStatus::Answer502 || answer == DefaultAnswerStatus::Answer504 {
+        if let Some(ref backend) = self.selected_backend {
+          backend.borrow_mut().record_outcome(true, SteadyTime::now());
+        }
+      }
     }
 
     self.status = SessionStatus::DefaultAnswer(answer, buf, 0);
@@ -201,10 +403,549 @@ impl<Front:SocketHandler> Http<Front> {
 
   }
 
-  pub fn added_request_header(&self, public_address: SocketAddr, client_address: Option<SocketAddr>) -> String {
-    let peer = client_address.or_else(|| self.front_socket().peer_addr().ok()).map(|addr| (addr.ip(), addr.port()));
-    let front = (public_address.ip(), public_address.port());
+  /// answers 417 to a request with an Expect header we do not support, unless the
+  /// listener is configured to forward it. Returns true if the request was answered
+  fn reject_unsupported_expect(&mut self) -> bool {
+    if self.ignore_unsupported_expect {
+      return false;
+    }
+
+    let unsupported = self.request.as_ref().and_then(|r| r.get_keep_alive())
+      .map(|conn| conn.continues == Continue::Unsupported).unwrap_or(false);
+    if unsupported {
+      let answer_417 = "HTTP/1.1 417 Expectation Failed\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n";
+      self.set_answer(DefaultAnswerStatus::Answer417, Rc::new(Vec::from(answer_417.as_bytes())));
+    }
+    unsupported
+  }
+
+  /// answers 400 to a GET, HEAD or TRACE request with a body, unless the listener
+  /// is configured to forward it. Returns true if the request was answered
+  fn reject_unexpected_body(&mut self) -> bool {
+    if self.unexpected_body_policy == UnexpectedBodyPolicy::Forward {
+      return false;
+    }
+
+    let unexpected = self.request.as_ref().map(|r| r.has_unexpected_body()).unwrap_or(false);
+    if unexpected {
+      error!("{}	rejecting a {} request with a body", self.log_context(),
+        self.get_request_line().map(|line| line.method.to_string()).unwrap_or_default());
+      let answer_400 = "HTTP/1.1 400 Bad Request\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n";
+      self.set_answer(DefaultAnswerStatus::Answer400, Rc::new(Vec::from(answer_400.as_bytes())));
+    }
+    unexpected
+  }
+
+  /// calls the HTTP transformer once the request line and host are parsed.
+  /// Returns true if the transformer answered the request
+  fn transform_request(&mut self) -> bool {
+    if !hooks::has_transformer() {
+      return false;
+    }
+
+    let transform = match (self.get_request_line(), self.get_host()) {
+      (Some(request_line), Some(host)) => hooks::transform_request(&hooks::RequestInfo {
+        request_line,
+        host,
+        client_address: self.get_session_address(),
+      }),
+      _ => return false,
+    };
+
+    match transform {
+      Transform::Continue => false,
+      Transform::AddHeaders(headers) => {
+        let headers = hooks::format_headers(&headers);
+        self.add_request_headers(&headers);
+        false
+      },
+      Transform::Answer(answer) => {
+        self.set_answer(DefaultAnswerStatus::AnswerTransformer, Rc::new(answer));
+        true
+      },
+    }
+  }
+
+  fn add_request_headers(&mut self, headers: &str) {
+    // if the end of the headers was already parsed, the added request
+    // header was inserted in the buffer, otherwise it will be inserted later
+    if self.req_header_end.is_some() {
+      self.front_buf.as_mut().map(|buf| buf.extend_last_insert(headers.as_bytes()));
+    } else {
+      self.added_req_header.push_str(headers);
+    }
+  }
+
+  /// adds the frontend TLS connection headers to the current request. The
+  /// connection to the backend can be retried, so they are only added once
+  pub fn add_tls_info_headers(&mut self) {
+    if self.tls_info_added {
+      return;
+    }
+
+    if let Some(headers) = self.tls_info_headers.take() {
+      self.add_request_headers(&headers);
+      self.tls_info_headers = Some(headers);
+      self.tls_info_added = true;
+    }
+  }
+
+  /// adds the Forwarded and X-Forwarded-* headers to the current request, or
+  /// only the X-Forwarded-For header sent by the client if the application
+  /// does not use them. They are only added once per request
+  pub fn add_forwarded_headers(&mut self, enabled: bool) {
+    if self.forwarded_headers_added {
+      return;
+    }
+
+    let previous = self.request.as_ref().and_then(|r| r.get_keep_alive())
+      .and_then(|conn| conn.forwarded_for.clone());
+    let headers = if enabled {
+      self.forwarded_headers(previous.as_ref().map(|s| s.as_str()))
+    } else {
+      previous.map(|addresses| format!("X-Forwarded-For: {}\r\n", addresses)).unwrap_or_default()
+    };
+
+    self.add_request_headers(&headers);
+    self.forwarded_headers_added = true;
+  }
+
+  /// applies the application's header rules to the current request, and prepares
+  /// the ones for its response. The request id is also sent under the application's
+  /// other names for it. They are only applied once per request
+  pub fn add_header_rules(&mut self, request_rules: &[HeaderRule], response_rules: &[HeaderRule],
+    request_id_headers: &[String]) {
+    if self.header_rules_added {
+      return;
+    }
+
+    let (mut to_delete, mut to_add) = header_rules(request_rules);
+    // the request id replaces the one sent by the client
+    let request_id = self.request_id.to_string();
+    for name in request_id_headers.iter().filter(|name| !name.eq_ignore_ascii_case("Sozu-Id")) {
+      if !hooks::is_valid_header(name, &request_id) {
+        error!("invalid request id header name in the application's configuration: {:?}", name);
+        continue;
+      }
+
+      to_delete.push(name.clone());
+      to_add.push_str(&format!("{}: {}\r\n", name, request_id));
+    }
+    self.request_headers_to_delete = to_delete;
+    self.delete_request_headers();
+    self.add_request_headers(&to_add);
+
+    // the response was not parsed yet, its added header will be inserted at the end of its headers
+    let (to_delete, to_add) = header_rules(response_rules);
+    self.response_headers_to_delete = to_delete;
+    self.added_res_header.push_str(&to_add);
+
+    self.header_rules_added = true;
+  }
+
+  pub fn set_host_rewriting(&mut self, rewrite_location: bool, backend_hosts: Vec<String>, rewrite_cookie_domain: bool) {
+    self.rewrite_location = rewrite_location;
+    self.backend_hosts = backend_hosts;
+    self.rewrite_cookie_domain = rewrite_cookie_domain;
+  }
+
+  pub fn set_failure_statuses(&mut self, statuses: Vec<u16>) {
+    self.failure_statuses = statuses;
+  }
+
+  /// the backend must send the complete response before this duration, from the
+  /// first byte of the request. Without it, only the front timeout applies
+  pub fn set_response_timeout(&mut self, timeout: Option<Duration>) {
+    self.response_timeout = timeout;
+  }
+
+  pub fn set_request_slot(&mut self, slot: Option<RequestSlot>) {
+    self.request_slot = slot;
+  }
+
+  pub fn take_request_slot(&mut self) -> Option<RequestSlot> {
+    self.request_slot.take()
+  }
+
+  pub fn set_max_request_retries(&mut self, retries: u8) {
+    self.max_request_retries = retries;
+  }
+
+  /// true if the request can be sent again on a new backend connection: the backend
+  /// did not answer, and either no byte of the request reached it, or the request is
+  /// idempotent and we kept a copy of what was sent
+  fn can_resend_request(&self) -> bool {
+    self.status == SessionStatus::Normal
+      && self.request_retries < self.max_request_retries
+      && self.request.as_ref().map(|r| r.has_host()).unwrap_or(false)
+      && self.response == Some(ResponseState::Initial)
+      && self.back_buf.as_ref().map(|buf| buf.bytes_in() == 0).unwrap_or(true)
+      && (self.request_bytes_sent == 0 || self.sent_request.is_some())
+  }
+
+  /// the backend closed the connection without answering: if possible, prepare the
+  /// request to be written again from the start and ask for a new backend connection
+  fn resend_request(&mut self) -> Option<SessionResult> {
+    if !self.can_resend_request() {
+      return None;
+    }
+
+    if self.front_buf.is_none() {
+      self.front_buf = self.pool.upgrade().and_then(|p| p.borrow_mut().checkout()).map(BufferQueue::with_buffer);
+      if self.front_buf.is_none() {
+        return None;
+      }
+    }
+
+    let sent = self.sent_request.as_mut().map(|data| ::std::mem::replace(data, Vec::new())).unwrap_or_default();
+    if !sent.is_empty() {
+      self.front_buf.as_mut().map(|buf| buf.prepend_output(sent));
+    }
+
+    info!("{}\tbackend closed the connection without answering, sending the request again", self.log_context());
+    incr!("http.request_retries");
+    self.request_retries += 1;
+    self.request_bytes_sent = 0;
+    self.back_write_closed = false;
+    self.back_buf = None;
+    Some(SessionResult::ReconnectBackend(Some(self.frontend_token), self.backend_token))
+  }
+
+  /// called when connecting to a new backend: the header carrying the client
+  /// address is written before the first request sent on the connection
+  pub fn set_proxy_protocol_header(&mut self, version: Option<ProxyProtocolVersion>) {
+    self.proxy_protocol_connection = version.is_some();
+    self.backend_proxy_header = version.map(|version| {
+      ProxyProtocolHeader::for_connection(version, self.get_session_address(), Some(self.public_address)).into_bytes()
+    });
+  }
+
+  /// sets the maximum body size of the current request. Returns true if
+  /// the request is already over it and was answered with a 413
+  pub fn limit_request_body_size(&mut self, limit: Option<usize>) -> bool {
+    self.max_request_body_size = limit;
+    self.reject_large_request_body()
+  }
+
+  /// answers 413 if the request body is over the limit. The announced Content-Length
+  /// is checked as soon as it is parsed, and chunked bodies as the chunks are parsed
+  /// (including their chunk headers), so the limit is enforced before the data is forwarded
+  fn reject_large_request_body(&mut self) -> bool {
+    let limit = match self.max_request_body_size {
+      Some(limit) => limit,
+      None        => return false,
+    };
+
+    let body_size = match self.request {
+      Some(RequestState::HasLength(_, _, LengthInformation::Length(sz))) |
+      Some(RequestState::HasHostAndLength(_, _, _, LengthInformation::Length(sz))) |
+      Some(RequestState::RequestWithBody(_, _, _, sz)) => sz,
+      Some(RequestState::RequestWithBodyChunks(_, _, _, _)) => {
+        match (self.req_header_end, self.front_buf.as_ref()) {
+          (Some(header_end), Some(buf)) => buf.start_parsing_position - header_end,
+          _ => 0,
+        }
+      },
+      _ => 0,
+    };
+
+    if body_size > limit {
+      error!("{}\trequest body of at least {} bytes is over the limit of {} bytes",
+        self.log_context(), body_size, limit);
+      let answer_413 = "HTTP/1.1 413 Payload Too Large\r\nContent-Length: 0\r\n\r\n";
+      self.set_answer(DefaultAnswerStatus::Answer413, Rc::new(Vec::from(answer_413.as_bytes())));
+      true
+    } else {
+      false
+    }
+  }
+
+  /// answers 431 if the request header block is over the listener's size or
+  /// header count limit, and 414 if the request line is over its size limit.
+  /// The data received is measured before it is parsed, so the headers over
+  /// the limits are never forwarded to the backend, and a request line without
+  /// an end is rejected once its partial line gets over the limit.
+  /// The parser consumes all the complete lines it gets, so they are only
+  /// measured once
+  fn reject_large_headers(&mut self) -> bool {
+    let (block, size, request_line) = match self.front_buf.as_ref() {
+      Some(buf) => {
+        let data = buf.unparsed_data();
+        let block = self.req_header_block.measure(data);
+        let size = if block.complete {
+          block.size
+        } else {
+          self.req_header_block.size + data.len()
+        };
+        // without a complete line, the data is the start of the request line
+        let request_line = if block.lines > 0 {
+          block.request_line
+        } else {
+          line_length(data)
+        };
+        (block, size, request_line)
+      },
+      None => return false,
+    };
+    self.req_header_block = block;
+
+    let limits = self.header_limits;
+    if request_line > limits.max_request_line_size {
+      error!("{}	request line of at least {} bytes is over the limit of {} bytes",
+        self.log_context(), request_line, limits.max_request_line_size);
+      let answer_414 = "HTTP/1.1 414 URI Too Long\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n";
+      self.set_answer(DefaultAnswerStatus::Answer414, Rc::new(Vec::from(answer_414.as_bytes())));
+      true
+    } else if size > limits.max_size || block.header_count() > limits.max_count {
+      error!("{}	request header block of at least {} bytes and {} headers is over the limits of {} bytes and {} headers",
+        self.log_context(), size, block.header_count(), limits.max_size, limits.max_count);
+      let answer_431 = "HTTP/1.1 431 Request Header Fields Too Large\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n";
+      self.set_answer(DefaultAnswerStatus::Answer431, Rc::new(Vec::from(answer_431.as_bytes())));
+      true
+    } else {
+      false
+    }
+  }
+
+  /// answers 502 if the header block of the response gets over the size limit,
+  /// so a backend cannot make the proxy buffer enormous headers. Nothing can be
+  /// answered once a part of the response was sent to the client, so the session
+  /// is closed instead. The connection to the backend is closed in both cases
+  fn reject_large_response_headers(&mut self) -> Option<SessionResult> {
+    let (block, size, bytes_out) = match self.back_buf.as_ref() {
+      Some(buf) => {
+        let data = buf.unparsed_data();
+        let block = self.res_header_block.measure(data);
+        let size = if block.complete {
+          block.size
+        } else {
+          self.res_header_block.size + data.len()
+        };
+        (block, size, buf.bytes_out())
+      },
+      None => return None,
+    };
+    self.res_header_block = block;
+
+    let max_size = self.header_limits.max_response_size;
+    if size <= max_size {
+      return None;
+    }
+
+    incr!("http.response_headers_too_large");
+    error!("{}	response header block of at least {} bytes is over the limit of {} bytes",
+      self.log_context(), size, max_size);
+    if bytes_out > 0 {
+      self.front_readiness.reset();
+      self.back_readiness.reset();
+      return Some(SessionResult::CloseSession);
+    }
+
+    let answer_502 = "HTTP/1.1 502 Bad Gateway\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n";
+    self.set_answer(DefaultAnswerStatus::Answer502, Rc::new(Vec::from(answer_502.as_bytes())));
+    Some(SessionResult::Continue)
+  }
+
+  /// looks for an Accept-Encoding header accepting gzip among the request headers
+  /// parsed since the last call, if the listener compresses the responses
+  fn update_accepted_encoding(&mut self) {
+    if self.compression.is_none() || self.accepts_gzip {
+      return;
+    }
+
+    self.accepts_gzip = self.front_buf.as_ref().and_then(|buf| header_value(buf, b"Accept-Encoding"))
+      .map(|value| accepts_gzip(&value)).unwrap_or(false);
+  }
+
+  /// deletes the request headers removed by the header rules,
+  /// among the ones parsed since the last call
+  fn delete_request_headers(&mut self) {
+    if let Some(buf) = self.front_buf.as_mut() {
+      delete_headers(buf, &self.request_headers_to_delete);
+    }
+  }
+
+  /// finds the client address of the request from the sources configured on the listener
+  fn update_client_address(&mut self) {
+    if self.client_ip.sources.is_empty() {
+      return;
+    }
+
+    let peer = self.frontend.socket_ref().peer_addr().ok();
+    // the session address is the PROXY protocol source on listeners expecting it
+    let proxy_protocol = self.session_address.filter(|address| Some(*address) != peer);
+    let forwarded_for = self.request.as_ref().and_then(|r| r.get_keep_alive())
+      .and_then(|conn| conn.forwarded_for.clone());
+
+    let front_buf = self.front_buf.as_ref();
+    self.client_address = client_ip::client_address(&self.client_ip, peer, proxy_protocol,
+      forwarded_for.as_ref().map(|s| s.as_str()),
+      |name| front_buf.and_then(|buf| header_value(buf, name.as_bytes())));
+  }
+
+  /// rewrites the Location header of the response if it points at the backend,
+  /// or at the public host with the wrong scheme, and the Domain attribute of
+  /// the Set-Cookie headers naming one of the backend hosts
+  fn rewrite_backend_hosts(&mut self) {
+    if !self.rewrite_location && !self.rewrite_cookie_domain {
+      return;
+    }
+
+    let host = match self.get_host() {
+      Some(host) => host.to_string(),
+      None       => return,
+    };
+    let scheme = match self.protocol {
+      Protocol::HTTPS => "https",
+      _               => "http",
+    };
+    let backend_address = self.backend_address;
+    let backend_hosts = &self.backend_hosts;
+
+    if let Some(buf) = self.back_buf.as_mut() {
+      let mut headers = String::new();
+      if self.rewrite_location {
+        let location = replace_header(buf, b"Location", |value| {
+          from_utf8(value).ok().and_then(|value| {
+            rewritten_location(value, backend_address.as_ref(), backend_hosts, &host, scheme)
+          })
+        });
+
+        if let Some(location) = location {
+          debug!("{}	rewriting the Location header to {}", self.request_id, location);
+          headers.push_str(&format!("Location: {}\r\n", location));
+        }
+      }
+
+      if self.rewrite_cookie_domain && !backend_hosts.is_empty() {
+        let cookies = replace_headers(buf, b"Set-Cookie", |value| {
+          from_utf8(value).ok().and_then(|value| rewritten_cookie_domain(value, backend_hosts, &host))
+        });
+        for cookie in cookies {
+          debug!("{}	rewriting the domain of the cookie {}", self.request_id, cookie);
+          headers.push_str(&format!("Set-Cookie: {}\r\n", cookie));
+        }
+      }
+
+      if !headers.is_empty() {
+        buf.extend_last_insert(headers.as_bytes());
+      }
+    }
+  }
+
+  /// calls the HTTP transformer once the response headers are parsed.
+  /// Returns true if the transformer replaced the response
+  fn transform_response(&mut self) -> bool {
+    if !hooks::has_transformer() {
+      return false;
+    }
+
+    let transform = match self.get_response_status() {
+      Some(status_line) => hooks::transform_response(&hooks::ResponseInfo {
+        request_line: self.get_request_line(),
+        host: self.get_host(),
+        status_line,
+        app_id: self.app_id.as_ref().map(|s| s.as_str()),
+      }),
+      None => return false,
+    };
+
+    match transform {
+      Transform::Continue => false,
+      Transform::AddHeaders(headers) => {
+        let headers = hooks::format_headers(&headers);
+        self.back_buf.as_mut().map(|buf| buf.extend_last_insert(headers.as_bytes()));
+        false
+      },
+      Transform::Answer(answer) => {
+        self.set_answer(DefaultAnswerStatus::AnswerTransformer, Rc::new(answer));
+        true
+      },
+    }
+  }
+
+  /// starts the compression of the response body once its headers are parsed, if the
+  /// client accepts gzip and the backend sent a compressible body that is not encoded
+  fn start_compression(&mut self) {
+    let config = match self.compression {
+      Some(ref config) if self.accepts_gzip => config.clone(),
+      _ => return,
+    };
+
+    let chunked = match self.response {
+      Some(ResponseState::ResponseWithBody(_,_,length)) if length >= config.min_size => false,
+      Some(ResponseState::ResponseWithBodyChunks(_,_,_)) => true,
+      _ => return,
+    };
+
+    // partial content cannot be compressed, and HTTP/1.0 clients do not support chunks
+    if self.get_response_status().map(|status_line| status_line.status == 206).unwrap_or(true)
+      || self.get_request_line().map(|request_line| request_line.version == Version::V10).unwrap_or(true) {
+      return;
+    }
+
+    let header_end = match self.res_header_end {
+      Some(header_end) => header_end,
+      None => return,
+    };
+    let buf = match self.back_buf.as_mut() {
+      Some(buf) => buf,
+      None => return,
+    };
+
+    // the headers are only checked if none of them were sent yet
+    if buf.bytes_out() > 0 || header_value(buf, b"Content-Encoding").is_some()
+      || header_value(buf, b"Cache-Control").map(|value| value.to_ascii_lowercase().contains("no-transform")).unwrap_or(false)
+      || !header_value(buf, b"Content-Type").map(|value| is_compressible(&value, &config.content_types)).unwrap_or(false) {
+      return;
+    }
+
+    if !chunked {
+      if replace_header(buf, b"Content-Length", |_| Some(String::new())).is_none() {
+        return;
+      }
+      buf.extend_last_insert(b"Transfer-Encoding: chunked\r\n");
+    }
+    buf.extend_last_insert(b"Content-Encoding: gzip\r\nVary: Accept-Encoding\r\n");
+
+    incr!("http.compressed_responses");
+    self.compressor = Some(ResponseCompressor::new(header_end, chunked));
+  }
+
+  /// compresses the response body received since the last call
+  fn compress_response(&mut self) {
+    let complete = match self.response {
+      Some(ResponseState::ResponseWithBody(_,_,_)) |
+      Some(ResponseState::ResponseWithBodyChunks(_,_,Chunk::Ended)) => {
+        self.back_buf.as_ref().map(|buf| !buf.needs_input()).unwrap_or(false)
+      },
+      _ => false,
+    };
+
+    if let (Some(compressor), Some(buf)) = (self.compressor.as_mut(), self.back_buf.as_mut()) {
+      compressor.compress(buf, complete);
+    }
+  }
+
+  /// the response to the request that reaches the limit gets a
+  /// `Connection: close` header, and the front connection is closed after it
+  pub fn set_max_keepalive_requests(&mut self, max: Option<usize>) {
+    self.max_keepalive_requests = max;
+    self.close_at_request_limit();
+    self.added_req_header = self.added_request_header();
+    self.added_res_header = self.added_response_header();
+  }
+
+  fn close_at_request_limit(&mut self) {
+    if self.max_keepalive_requests.map(|max| self.keepalive_count + 1 >= max).unwrap_or(false) {
+      if !self.closing {
+        incr!("http.keepalive_limit");
+      }
+      self.closing = true;
+    }
+  }
 
+  pub fn added_request_header(&self) -> String {
     //FIXME: should update the Connection header directly if present
     let closing_header = if self.closing {
       "Connection: close\r\n"
@@ -212,44 +953,33 @@ impl<Front:SocketHandler> Http<Front> {
       ""
     };
 
+    format!("Sozu-Id: {}\r\n{}", self.request_id, closing_header)
+  }
+
+  pub fn forwarded_headers(&self, previous_forwarded_for: Option<&str>) -> String {
+    let peer = self.session_address.or_else(|| self.front_socket().peer_addr().ok()).map(|addr| (addr.ip(), addr.port()));
+    let front = (self.public_address.ip(), self.public_address.port());
+
     if let (Some((peer_ip, peer_port)), (front, front_port)) = (peer, front) {
       let proto = match self.protocol() {
         Protocol::HTTP  => "http",
         Protocol::HTTPS => "https",
         _               => unreachable!()
       };
+      let forwarded_for = forwarded_for(previous_forwarded_for, peer_ip);
 
       //FIXME: in the "for", we don't put the other values we could get from a preexisting forward header
-      match (peer_ip, peer_port, front) {
-        (IpAddr::V4(_), peer_port, IpAddr::V4(_)) => {
-          format!("Forwarded: proto={};for={}:{};by={}\r\nX-Forwarded-Proto: {}\r\nX-Forwarded-For: {}\r\n\
-                  X-Forwarded-Port: {}\r\nSozu-Id: {}\r\n{}",
-            proto, peer_ip, peer_port, front, proto, peer_ip, front_port, self.request_id, closing_header)
-        },
-        (IpAddr::V4(_), peer_port, IpAddr::V6(_)) => {
-          format!("Forwarded: proto={};for={}:{};by=\"{}\"\r\nX-Forwarded-Proto: {}\r\nX-Forwarded-For: {}\r\n\
-                  X-Forwarded-Port: {}\r\nSozu-Id: {}\r\n{}",
-            proto, peer_ip, peer_port, front, proto, peer_ip, front_port, self.request_id, closing_header)
-        },
-        (IpAddr::V6(_), peer_port, IpAddr::V4(_)) => {
-          format!("Forwarded: proto={};for=\"{}:{}\";by={}\r\nX-Forwarded-Proto: {}\r\nX-Forwarded-For: {}\r\n\
-                  X-Forwarded-Port: {}\r\nSozu-Id: {}\r\n{}",
-            proto, peer_ip, peer_port, front, proto, peer_ip, front_port, self.request_id, closing_header)
-        },
-        (IpAddr::V6(_), peer_port, IpAddr::V6(_)) => {
-          format!("Forwarded: proto={};for=\"{}:{}\";by=\"{}\"\r\nX-Forwarded-Proto: {}\r\nX-Forwarded-For: {}\r\n\
-                  X-Forwarded-Port: {}\r\nSozu-Id: {}\r\n{}",
-            proto, peer_ip, peer_port, front, proto, peer_ip, front_port, self.request_id, closing_header)
-        },
-      }
+      format!("Forwarded: proto={};for={};by={}\r\nX-Forwarded-Proto: {}\r\nX-Forwarded-For: {}\r\n\
+              X-Forwarded-Port: {}\r\n",
+        proto, forwarded_node(peer_ip, Some(peer_port)), forwarded_node(front, None), proto, forwarded_for, front_port)
     } else {
-      format!("Sozu-Id: {}\r\n{}", self.request_id, closing_header)
+      previous_forwarded_for.map(|addresses| format!("X-Forwarded-For: {}\r\n", addresses)).unwrap_or_default()
     }
   }
 
   pub fn added_response_header(&self) -> String {
     if self.closing {
-      format!("Sozu-Id: {}\r\nConnection: close", self.request_id)
+      format!("Sozu-Id: {}\r\nConnection: close\r\n", self.request_id)
     } else {
       format!("Sozu-Id: {}\r\n", self.request_id)
     }
@@ -309,14 +1039,27 @@ impl<Front:SocketHandler> Http<Front> {
   pub fn set_back_socket(&mut self, socket: TcpStream, address: SocketAddr) {
     self.backend = Some(socket);
     self.backend_address = Some(address);
+    self.backend_reusable = false;
+  }
+
+  pub fn is_backend_reusable(&self) -> bool {
+    self.backend_reusable && self.backend.is_some()
+  }
+
+  /// returns true once if the backend broke its response and the request is sent
+  /// again, so the session can exclude that backend from the next connection
+  pub fn take_backend_failure(&mut self) -> bool {
+    ::std::mem::replace(&mut self.backend_failed, false)
   }
 
   pub fn set_app_id(&mut self, app_id: String) {
     self.app_id = Some(app_id);
   }
 
-  pub fn set_backend_id(&mut self, backend_id: String) {
-    self.backend_id = Some(backend_id);
+  pub fn set_backend(&mut self, backend: Rc<RefCell<Backend>>) {
+    self.backend_id = Some(backend.borrow().backend_id.clone());
+    self.selected_backend = Some(backend);
+    self.backend_attempts = self.backend_attempts.saturating_add(1);
   }
 
   pub fn set_back_token(&mut self, token: Token) {
@@ -356,6 +1099,32 @@ impl<Front:SocketHandler> Http<Front> {
     None
   }
 
+  /// time left to receive the header block of the current request, from the
+  /// reception of its first byte. None if the header block was received, or
+  /// if the request did not start yet
+  pub fn header_time_left(&self, header_timeout: Duration, now: SteadyTime) -> Option<Duration> {
+    match (self.req_header_start, self.req_header_end, &self.status) {
+      (Some(start), None, SessionStatus::Normal) => Some(start + header_timeout - now),
+      _ => None,
+    }
+  }
+
+  /// time left to the backend to answer the current request, if its application
+  /// has a response timeout. None if the request is not waiting for a response
+  pub fn response_time_left(&self, now: SteadyTime) -> Option<Duration> {
+    match (self.response_timeout, self.req_header_start, &self.status) {
+      (Some(timeout), Some(start), SessionStatus::Normal) if self.timeout_status() == TimeoutStatus::Response => {
+        Some(start + timeout - now)
+      },
+      _ => None,
+    }
+  }
+
+  /// part of the response was received from the backend
+  pub fn response_started(&self) -> bool {
+    self.response != Some(ResponseState::Initial)
+  }
+
   pub fn timeout_status(&self) -> TimeoutStatus {
     match self.request.as_ref() {
       Some(RequestState::Request(_,_,_)) | Some(RequestState::RequestWithBody(_,_,_,_)) |
@@ -376,14 +1145,43 @@ impl<Front:SocketHandler> Http<Front> {
     let addr:Option<SocketAddr> = self.backend.as_ref().and_then(|sock| sock.peer_addr().ok());
     self.backend       = None;
     self.backend_token = None;
+    self.backend_reusable = false;
     (self.app_id.clone(), addr)
   }
 
   pub fn front_hup(&mut self) -> SessionResult {
-    SessionResult::CloseSession
+    // some clients shut down their side of the connection after sending
+    // a complete request, and still wait for the response
+    if self.request_received() {
+      debug!("{}\tfront socket half closed after the request", self.log_context());
+      self.front_read_closed = true;
+      self.front_readiness.interest.remove(Ready::readable());
+      SessionResult::Continue
+    } else {
+      SessionResult::CloseSession
+    }
+  }
+
+  /// the whole request was read from the front socket
+  fn request_received(&self) -> bool {
+    match self.request {
+      Some(RequestState::Request(_,_,_)) |
+      Some(RequestState::RequestWithBodyChunks(_,_,_,Chunk::Ended)) => true,
+      // the front buffer is released once the body was sent to the backend
+      Some(RequestState::RequestWithBody(_,_,_,_)) => {
+        self.front_buf.as_ref().map(|buf| !buf.needs_input()).unwrap_or(true)
+      },
+      _ => false,
+    }
   }
 
   pub fn back_hup(&mut self) -> SessionResult {
+    // the default answer replacing the backend's response is written before closing
+    if let SessionStatus::DefaultAnswer(_,_,_) = self.status {
+      self.back_readiness.interest = UnixReady::from(Ready::empty());
+      return SessionResult::Continue;
+    }
+
     if let Some(ref mut buf) = self.back_buf {
       //FIXME: closing the session might not be a good idea if we do keep alive on the front here?
       if buf.output_data_size() == 0 || buf.next_output_data().is_empty() {
@@ -391,7 +1189,7 @@ impl<Front:SocketHandler> Http<Front> {
           self.back_readiness.interest.insert(Ready::readable());
           SessionResult::Continue
         } else {
-          SessionResult::CloseSession
+          self.resend_request().unwrap_or(SessionResult::CloseSession)
         }
       } else {
         self.front_readiness.interest.insert(Ready::writable());
@@ -400,9 +1198,28 @@ impl<Front:SocketHandler> Http<Front> {
         }
         SessionResult::Continue
       }
+    } else if self.back_readiness.event.is_readable() {
+      // the backend may have answered before closing, while the request was uploaded
+      self.back_readiness.interest.insert(Ready::readable());
+      SessionResult::Continue
     } else {
-      SessionResult::CloseSession
+      self.resend_request().unwrap_or(SessionResult::CloseSession)
+    }
+  }
+
+  /// the backend closed the connection before the end of the request: stop
+  /// sending the request, and forward the answer if there is one
+  pub fn back_closed_during_request(&mut self) {
+    if !self.back_write_closed {
+      debug!("{}\tback socket closed during the request", self.log_context());
+      incr!("http.backend_closed_during_request");
     }
+    self.back_write_closed = true;
+    self.closing = true;
+    self.front_readiness.interest.remove(Ready::readable());
+    self.back_readiness.interest.remove(Ready::writable());
+    self.back_readiness.interest.insert(Ready::readable());
+    self.back_readiness.event.insert(Ready::readable());
   }
 
   pub fn shutting_down(&mut self) -> SessionResult {
@@ -430,7 +1247,7 @@ impl<Front:SocketHandler> Http<Front> {
   }
 
   pub fn get_session_address(&self) -> Option<SocketAddr> {
-    self.session_address.or_else(|| self.frontend.socket_ref().peer_addr().ok())
+    self.client_address.or(self.session_address).or_else(|| self.frontend.socket_ref().peer_addr().ok())
   }
 
   pub fn get_backend_address(&self) -> Option<SocketAddr> {
@@ -464,6 +1281,30 @@ impl<Front:SocketHandler> Http<Front> {
     }
   }
 
+  /// fields of the access log, written when the logs are in JSON
+  fn access_record<'a>(&'a self, metrics: &SessionMetrics, status: Option<u16>, error: Option<&'a str>) -> AccessRecord<'a> {
+    AccessRecord {
+      request_id:    self.request_id.to_string(),
+      app_id:        self.app_id.as_deref(),
+      backend_id:    self.backend_id.as_deref(),
+      client:        self.get_session_address(),
+      backend:       self.get_backend_address(),
+      protocol:      self.protocol_string(),
+      method:        self.get_request_line().map(|line| line.method.to_string()),
+      host:          self.get_host(),
+      path:          self.get_request_line().map(|line| line.uri.as_str()),
+      status,
+      response_time: metrics.response_time().num_milliseconds(),
+      latency:       metrics.latency().num_milliseconds(),
+      service_time:  metrics.service_time().num_milliseconds(),
+      bytes_in:      metrics.bin,
+      bytes_out:     metrics.bout,
+      error,
+      retries:       self.backend_attempts.saturating_sub(1),
+      client_certificate: self.client_certificate.as_ref().map(|certificate| certificate.subject.as_str()),
+    }
+  }
+
   pub fn log_request_success(&self, metrics: &SessionMetrics) {
     let session = SessionAddress(self.get_session_address());
     let backend = SessionAddress(self.get_backend_address());
@@ -484,16 +1325,49 @@ impl<Front:SocketHandler> Http<Front> {
       if let Some(backend_response_time) = metrics.backend_response_time() {
         record_backend_metrics!(app_id, backend_id, backend_response_time.num_milliseconds(),
           metrics.backend_connection_time(), metrics.backend_bin, metrics.backend_bout);
+        if let Some(ref backend) = self.selected_backend {
+          backend.borrow_mut().record_response_time(backend_response_time);
+        }
       }
     }
 
+    self.record_backend_answer();
+
     let proto = self.protocol_string();
 
-    info_access!("{}{} -> {}\t{} {} {} {}\t{} {} {}\t{}",
+    let record = self.access_record(metrics, self.get_response_status().map(|line| line.status), None);
+    info_access!(@record &record; "{}{} -> {}\t{} {} {} {}\t{} {} {}\t{}",
       self.log_context(), session, backend,
       LogDuration(response_time), LogDuration(service_time),
       metrics.bin, metrics.bout,
       proto, host, request_line, status_line);
+
+    if self.traced {
+      verbose!("{}{}", self.log_context(), self.print_state("request success"));
+    }
+  }
+
+  /// the answers with one of the failure statuses of the application count as
+  /// failures of the backend, and the 5xx ones as errors for the outlier detection
+  fn record_backend_answer(&self) {
+    let backend = match self.selected_backend {
+      Some(ref backend) => backend,
+      None              => return,
+    };
+    let status = match self.get_response_status() {
+      Some(line) => line.status,
+      None       => return,
+    };
+
+    let mut backend = backend.borrow_mut();
+    let failed = self.failure_statuses.contains(&status);
+    if failed {
+      incr!("http.failed_answers", self.app_id.as_ref().map(|s| s.as_str()), self.backend_id.as_ref().map(|s| s.as_str()));
+      backend.fail_answer(SteadyTime::now());
+    } else {
+      backend.succeed_answer();
+    }
+    backend.record_outcome(failed || status >= 500, SteadyTime::now());
   }
 
   pub fn log_default_answer_success(&self, metrics: &SessionMetrics) {
@@ -506,8 +1380,13 @@ impl<Front:SocketHandler> Http<Front> {
       SessionStatus::DefaultAnswer(DefaultAnswerStatus::Answer404, _, _) => "404 Not Found",
       SessionStatus::DefaultAnswer(DefaultAnswerStatus::Answer408, _, _) => "408 Request Timeout",
       SessionStatus::DefaultAnswer(DefaultAnswerStatus::Answer413, _, _) => "413 Payload Too Large",
+      SessionStatus::DefaultAnswer(DefaultAnswerStatus::Answer414, _, _) => "414 URI Too Long",
+      SessionStatus::DefaultAnswer(DefaultAnswerStatus::Answer417, _, _) => "417 Expectation Failed",
+      SessionStatus::DefaultAnswer(DefaultAnswerStatus::Answer431, _, _) => "431 Request Header Fields Too Large",
+      SessionStatus::DefaultAnswer(DefaultAnswerStatus::Answer502, _, _) => "502 Bad Gateway",
       SessionStatus::DefaultAnswer(DefaultAnswerStatus::Answer503, _, _) => "503 Service Unavailable",
       SessionStatus::DefaultAnswer(DefaultAnswerStatus::Answer504, _, _) => "504 Gateway Timeout",
+      SessionStatus::DefaultAnswer(DefaultAnswerStatus::AnswerTransformer, _, _) => "Transformer Answer",
     };
 
     let host         = OptionalString::new(self.get_host());
@@ -523,11 +1402,17 @@ impl<Front:SocketHandler> Http<Front> {
 
     let proto = self.protocol_string();
 
-    info_access!("{}{} -> X\t{} {} {} {}\t{} {} {}\t{}",
+    // the default answers begin with their status code
+    let record = self.access_record(metrics, status_line.get(..3).and_then(|code| code.parse().ok()), None);
+    info_access!(@record &record; "{}{} -> X\t{} {} {} {}\t{} {} {}\t{}",
       self.log_context(), session,
       LogDuration(response_time), LogDuration(service_time),
       metrics.bin, metrics.bout,
       proto, host, request_line, status_line);
+
+    if self.traced {
+      verbose!("{}{}", self.log_context(), self.print_state("default answer"));
+    }
   }
 
   pub fn log_request_error(&mut self, metrics: &mut SessionMetrics, message: &str) {
@@ -558,10 +1443,15 @@ impl<Front:SocketHandler> Http<Front> {
 
     let proto = self.protocol_string();
 
-    error_access!("{}{} -> {}\t{} {} {} {}\t{} {} {}\t{} | {}",
+    let record = self.access_record(metrics, self.get_response_status().map(|line| line.status), Some(message));
+    error_access!(@record &record; "{}{} -> {}\t{} {} {} {}\t{} {} {}\t{} | {}",
       self.log_context(), session, backend,
       LogDuration(response_time), LogDuration(service_time), metrics.bin, metrics.bout,
       proto, host, request_line, status_line, message);
+
+    if self.traced {
+      verbose!("{}{}", self.log_context(), self.print_state("request error"));
+    }
   }
 
   // Read content from the session
@@ -587,7 +1477,18 @@ impl<Front:SocketHandler> Http<Front> {
     }
 
     if self.front_buf.as_ref().unwrap().buffer.available_space() == 0 {
-      if self.backend_token == None {
+      let can_forward = self.backend_token.is_some() && self.front_buf.as_ref()
+        .map(|buf| buf.output_data_size() > 0 && !buf.next_output_data().is_empty()).unwrap();
+
+      if self.req_header_end.is_none() && !can_forward {
+        // the buffer is full of headers that cannot be parsed or forwarded
+        // to make room: a header line does not fit in the buffer
+        error!("{}	request headers do not fit in the buffer", self.log_context());
+        let answer_431 = "HTTP/1.1 431 Request Header Fields Too Large\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n";
+        self.set_answer(DefaultAnswerStatus::Answer431, Rc::new(Vec::from(answer_431.as_bytes())));
+        self.front_readiness.interest.remove(Ready::readable());
+        self.front_readiness.interest.insert(Ready::writable());
+      } else if self.backend_token == None {
         let answer_413 = "HTTP/1.1 413 Payload Too Large\r\nContent-Length: 0\r\n\r\n";
         self.set_answer(DefaultAnswerStatus::Answer413, Rc::new(Vec::from(answer_413.as_bytes())));
         self.front_readiness.interest.remove(Ready::readable());
@@ -599,13 +1500,26 @@ impl<Front:SocketHandler> Http<Front> {
       return SessionResult::Continue;
     }
 
+    // the backend reads slower than the client sends: stop reading until
+    // part of the request was sent to the backend
+    if self.backend_token.is_some() && self.front_buf.as_ref().unwrap().is_above_high_water_mark() {
+      self.front_readiness.interest.remove(Ready::readable());
+      self.back_readiness.interest.insert(Ready::writable());
+      return SessionResult::Continue;
+    }
+
     let (sz, res) = self.frontend.socket_read(self.front_buf.as_mut().unwrap().buffer.space());
     debug!("{}\tFRONT: read {} bytes", self.log_context(), sz);
+    self.trace_event(format_args!("front read {} bytes: {:?}", sz, res));
 
     if sz > 0 {
       count!("bytes_in", sz as i64);
       metrics.bin += sz;
 
+      if self.req_header_end.is_none() && self.req_header_start.is_none() {
+        self.req_header_start = Some(SteadyTime::now());
+      }
+
       self.front_buf.as_mut().map(|front_buf| {
         front_buf.buffer.fill(sz);
         front_buf.sliced_input(sz);
@@ -647,6 +1561,12 @@ impl<Front:SocketHandler> Http<Front> {
         }
         return SessionResult::CloseSession;
       },
+      SocketResult::Closed if sz > 0 || self.request_received() => {
+        // the request is parsed below, the session is closed if it is incomplete
+        self.front_read_closed = true;
+        self.front_readiness.interest.remove(Ready::readable());
+        self.front_readiness.event.remove(Ready::readable());
+      },
       SocketResult::Closed => {
         //we were in keep alive but the peer closed the connection
         if self.request == Some(RequestState::Initial) {
@@ -677,7 +1597,18 @@ impl<Front:SocketHandler> Http<Front> {
       SocketResult::Continue => {}
     };
 
-    self.readable_parse(metrics)
+    let previous_state = if self.traced { self.request.clone() } else { None };
+    let res = self.readable_parse(metrics);
+    if previous_state.is_some() && self.request != previous_state {
+      self.trace_event(format_args!("request state {:?} -> {:?}", previous_state, self.request));
+    }
+
+    if self.front_read_closed && res != SessionResult::CloseSession && !self.request_received() {
+      self.frontend.read_error();
+      self.log_request_error(metrics, "front socket was closed before the end of the request, closing the session");
+      return SessionResult::CloseSession;
+    }
+    res
   }
 
 
@@ -686,14 +1617,33 @@ impl<Front:SocketHandler> Http<Front> {
     // if there's no host, continue parsing until we find it
     let has_host = self.request.as_ref().map(|r| r.has_host()).unwrap_or(false);
     if !has_host {
+      if self.req_header_end.is_none() && self.reject_large_headers() {
+        return SessionResult::Continue;
+      }
+
       let (request_state, header_end) = (self.request.take().unwrap(), self.req_header_end.take());
       let (request_state, header_end) = parse_request_until_stop(request_state,
         header_end, &mut self.front_buf.as_mut().unwrap(),
         &self.added_req_header,
-        &self.sticky_name);
+        &self.sticky_name, self.preserve_proxy_connection);
 
       self.request = Some(request_state);
       self.req_header_end = header_end;
+      self.update_accepted_encoding();
+
+      let missing_host = self.front_buf.as_ref()
+        .map(|buf| is_missing_host(unwrap_msg!(self.request.as_ref()), buf.unparsed_data()))
+        .unwrap_or(false);
+      if missing_host {
+        // the proxy answers with the NoHostGiven error when it looks for the frontend
+        if is_initial {
+          gauge_add!("http.active_requests", 1);
+          incr!("http.requests");
+          metrics.request_start();
+        }
+        self.front_readiness.interest.remove(Ready::readable());
+        return SessionResult::ConnectBackend;
+      }
 
       if unwrap_msg!(self.request.as_ref()).is_front_error() {
         self.log_request_error(metrics, "front parsing error, closing the connection");
@@ -711,9 +1661,31 @@ impl<Front:SocketHandler> Http<Front> {
       if is_initial && !is_now_initial {
         gauge_add!("http.active_requests", 1);
         incr!("http.requests");
+        metrics.request_start();
       }
 
       if unwrap_msg!(self.request.as_ref()).has_host() {
+        self.update_client_address();
+        // avoid looking up the peer address when nothing is traced
+        self.traced = tracing::has_traces() && tracing::is_traced(self.get_host(),
+          self.get_request_line().map(|line| line.uri.as_str()),
+          self.get_session_address().map(|addr| addr.ip()));
+        if self.traced {
+          verbose!("{}{} {}", self.log_context(), SessionAddress(self.get_session_address()),
+            self.print_state("request parsed"));
+        }
+
+        if self.reject_unsupported_expect() || self.reject_unexpected_body() || self.transform_request() {
+          return SessionResult::Continue;
+        }
+
+        self.backend_reusable = false;
+        // only the idempotent requests are sent again once they reached a backend
+        self.sent_request = if self.get_request_line().map(|line| line.method.is_idempotent()).unwrap_or(false) {
+          Some(Vec::new())
+        } else {
+          None
+        };
         self.back_readiness.interest.insert(Ready::writable());
         return SessionResult::ConnectBackend;
       } else {
@@ -746,7 +1718,7 @@ impl<Front:SocketHandler> Http<Front> {
           let (request_state, header_end) = parse_request_until_stop(request_state,
             header_end, &mut self.front_buf.as_mut().unwrap(),
             &self.added_req_header,
-            &self.sticky_name);
+            &self.sticky_name, self.preserve_proxy_connection);
 
           self.request = Some(request_state);
           self.req_header_end = header_end;
@@ -756,6 +1728,10 @@ impl<Front:SocketHandler> Http<Front> {
             return SessionResult::CloseSession;
           }
 
+          if self.reject_large_request_body() {
+            return SessionResult::Continue;
+          }
+
           if let Some(RequestState::RequestWithBodyChunks(_,_,_,Chunk::Ended)) = self.request {
             self.front_readiness.interest.remove(Ready::readable());
           }
@@ -764,20 +1740,34 @@ impl<Front:SocketHandler> Http<Front> {
         SessionResult::Continue
       },
     _ => {
+        if self.req_header_end.is_none() && self.reject_large_headers() {
+          return SessionResult::Continue;
+        }
+
         let (request_state, header_end) = (self.request.take().unwrap(), self.req_header_end.take());
+        let had_header_end = header_end.is_some();
         let (request_state, header_end) = parse_request_until_stop(request_state,
           header_end, &mut self.front_buf.as_mut().unwrap(),
           &self.added_req_header,
-          &self.sticky_name);
+          &self.sticky_name, self.preserve_proxy_connection);
 
         self.request = Some(request_state);
         self.req_header_end = header_end;
+        if !had_header_end {
+          self.delete_request_headers();
+          self.update_accepted_encoding();
+        }
 
         if unwrap_msg!(self.request.as_ref()).is_front_error() {
           self.log_request_error(metrics, "front parsing error, closing the connection");
           return SessionResult::CloseSession;
         }
 
+        if (!had_header_end && (self.reject_unsupported_expect() || self.reject_unexpected_body()))
+          || self.reject_large_request_body() {
+          return SessionResult::Continue;
+        }
+
         if let Some(RequestState::Request(_,_,_)) = self.request {
           self.front_readiness.interest.remove(Ready::readable());
         }
@@ -802,6 +1792,7 @@ impl<Front:SocketHandler> Http<Front> {
 
       count!("bytes_out", sz as i64);
       metrics.bout += sz;
+      self.trace_event(format_args!("front wrote {} bytes of the default answer: {:?}", sz, res));
 
       if res != SocketResult::Continue {
         self.front_readiness.event.remove(Ready::writable());
@@ -858,6 +1849,7 @@ impl<Front:SocketHandler> Http<Front> {
         self.front_readiness.interest.remove(Ready::writable());
         count!("bytes_out", sz as i64);
         metrics.bout += sz;
+        self.trace_event(format_args!("front wrote {} bytes of {}: {:?}", sz, output_size, res));
         return SessionResult::Continue;
       }
       //let (current_sz, current_res) = self.frontend.socket_write(self.back_buf.as_ref().unwrap().next_output_data());
@@ -877,6 +1869,7 @@ impl<Front:SocketHandler> Http<Front> {
     }
     count!("bytes_out", sz as i64);
     metrics.bout += sz;
+    self.trace_event(format_args!("front wrote {} bytes of {}: {:?}", sz, output_size, res));
 
     if let Some((front,back)) = self.tokens() {
       debug!("{}\tFRONT [{}<-{}]: wrote {} bytes of {}, buffer position {} restart position {}", self.log_context(), front.0, back.0, sz, output_size, self.back_buf.as_ref().unwrap().buffer_position, self.back_buf.as_ref().unwrap().start_parsing_position);
@@ -924,6 +1917,7 @@ impl<Front:SocketHandler> Http<Front> {
 
       self.response = Some(ResponseState::Initial);
       self.res_header_end = None;
+      self.res_header_block = HeaderBlockSize::default();
       self.request.as_mut().map(|r| r.get_mut_connection().map(|conn| conn.continues = Continue::None));
 
       return SessionResult::Continue;
@@ -943,6 +1937,9 @@ impl<Front:SocketHandler> Http<Front> {
         self.log_request_success(&metrics);
         metrics.reset();
 
+        self.backend_reusable = back_keep_alive && !self.back_write_closed && !self.proxy_protocol_connection
+          && self.back_buf.as_ref().map(|buf| buf.empty()).unwrap_or(true);
+
         if self.closing {
           debug!("{} closing proxy, no keep alive", self.log_context());
           self.front_readiness.reset();
@@ -950,6 +1947,14 @@ impl<Front:SocketHandler> Http<Front> {
           return SessionResult::CloseSession
         }
 
+        // the client cannot send another request
+        if self.front_read_closed {
+          debug!("{} front socket half closed, no keep alive", self.log_context());
+          self.front_readiness.reset();
+          self.back_readiness.reset();
+          return SessionResult::CloseSession
+        }
+
         //FIXME: we could get smarter about this
         // with no keepalive on backend, we could open a new backend ConnectionError
         // with no keepalive on front but keepalive on backend, we could have
@@ -1030,9 +2035,41 @@ impl<Front:SocketHandler> Http<Front> {
       return SessionResult::CloseSession;
     }
 
+    if let Some(header) = self.backend_proxy_header.take() {
+      let (sz, socket_res) = unwrap_msg!(self.backend.as_mut()).socket_write(&header);
+      metrics.backend_bout += sz;
+      if sz < header.len() {
+        self.backend_proxy_header = Some(header[sz..].to_vec());
+      }
+
+      match socket_res {
+        SocketResult::Error | SocketResult::Closed => {
+          if let Some(order) = self.resend_request() {
+            return order;
+          }
+          incr!("proxy_protocol.errors");
+          self.log_request_error(metrics, "back socket write error while sending the PROXY protocol header, closing connection");
+          return SessionResult::CloseSession;
+        },
+        SocketResult::WouldBlock => {
+          self.back_readiness.event.remove(Ready::writable());
+          return SessionResult::Continue;
+        },
+        SocketResult::Continue => {
+          if self.backend_proxy_header.is_some() {
+            return SessionResult::Continue;
+          }
+        },
+      }
+    }
+
     let mut sz = 0usize;
     let mut socket_res = SocketResult::Continue;
 
+    if self.max_request_retries == 0 {
+      self.sent_request = None;
+    }
+
     {
       let sock = unwrap_msg!(self.backend.as_mut());
       while socket_res == SocketResult::Continue && self.front_buf.as_ref().unwrap().output_data_size() > 0 {
@@ -1041,6 +2078,8 @@ impl<Front:SocketHandler> Http<Front> {
           self.front_readiness.interest.insert(Ready::readable());
           self.back_readiness.interest.remove(Ready::writable());
           metrics.backend_bout += sz;
+          self.request_bytes_sent += sz;
+          self.trace_event(format_args!("back wrote {} bytes of {}: {:?}", sz, output_size, socket_res));
           return SessionResult::Continue;
         }
         /*
@@ -1053,21 +2092,33 @@ impl<Front:SocketHandler> Http<Front> {
         let (current_sz, current_res) = sock.socket_write_vectored(&bufs);
         //println!("vectored io returned {:?}", (current_sz, current_res));
         socket_res = current_res;
+        keep_sent_data(&mut self.sent_request, &bufs, current_sz);
         self.front_buf.as_mut().unwrap().consume_output_data(current_sz);
         sz += current_sz;
       }
     }
 
     metrics.backend_bout += sz;
+    self.request_bytes_sent += sz;
+    self.trace_event(format_args!("back wrote {} bytes of {}: {:?}", sz, output_size, socket_res));
 
     if let Some((front,back)) = tokens {
       debug!("{}\tBACK [{}->{}]: wrote {} bytes of {}", self.log_context(), front.0, back.0, sz, output_size);
     }
     match socket_res {
-      SocketResult::Error | SocketResult::Closed => {
+      SocketResult::Error => {
+        if let Some(order) = self.resend_request() {
+          return order;
+        }
         self.log_request_error(metrics, "back socket write error, closing connection");
         return SessionResult::CloseSession;
       },
+      SocketResult::Closed => {
+        // EPIPE or ECONNRESET: the backend closed the connection before the end
+        // of the request, usually after sending an early answer (like a 413)
+        self.back_closed_during_request();
+        return SessionResult::Continue;
+      },
       SocketResult::WouldBlock => {
         self.back_readiness.event.remove(Ready::writable());
 
@@ -1132,6 +2183,73 @@ impl<Front:SocketHandler> Http<Front> {
 
   // Read content from application
   pub fn back_readable(&mut self, metrics: &mut SessionMetrics) -> (ProtocolResult, SessionResult) {
+    self.back_read_closed = false;
+    let previous_state = if self.traced { self.response.clone() } else { None };
+    let result = self.read_response(metrics);
+    if previous_state.is_some() && self.response != previous_state {
+      self.trace_event(format_args!("response state {:?} -> {:?}", previous_state, self.response));
+    }
+
+    if result == (ProtocolResult::Continue, SessionResult::Continue) && self.back_read_closed
+      && self.is_response_incomplete() {
+      return (ProtocolResult::Continue, self.back_closed_during_response(metrics));
+    }
+
+    result
+  }
+
+  /// true while the backend has not sent the end of the response to the current
+  /// request, according to its Content-Length or chunked encoding
+  fn is_response_incomplete(&self) -> bool {
+    if !self.request.as_ref().map(|r| r.has_host()).unwrap_or(false) {
+      return false;
+    }
+
+    match self.response {
+      Some(ResponseState::Response(_,_))
+      | Some(ResponseState::ResponseUpgrade(_,_,_))
+      | Some(ResponseState::ResponseWithBodyCloseDelimited(_,_,_))
+      | Some(ResponseState::ResponseWithBodyChunks(_,_,Chunk::Ended))
+      | Some(ResponseState::ResponseWithBodyChunks(_,_,Chunk::Error))
+      | Some(ResponseState::Error(_,_,_,_,_))
+      | None => false,
+      Some(ResponseState::ResponseWithBody(_,_,_)) => self.back_buf.as_ref().map(|buf| buf.needs_input()).unwrap_or(true),
+      _ => true,
+    }
+  }
+
+  /// the backend closed the connection before the end of its response. If nothing
+  /// was written to the client yet, the request is sent to another backend when
+  /// possible, or answered with a 502. Otherwise the client connection is closed,
+  /// since the response cannot be completed
+  fn back_closed_during_response(&mut self, metrics: &mut SessionMetrics) -> SessionResult {
+    incr!("http.backend_closed_during_response");
+
+    if self.back_buf.as_ref().map(|buf| buf.bytes_out() > 0).unwrap_or(false) {
+      self.log_request_error(metrics, "back socket closed during the response, closing connection");
+      self.front_readiness.reset();
+      self.back_readiness.reset();
+      return SessionResult::CloseSession;
+    }
+
+    self.response = Some(ResponseState::Initial);
+    self.res_header_end = None;
+    self.res_header_block = HeaderBlockSize::default();
+    self.back_buf = None;
+    self.front_readiness.interest.remove(Ready::writable());
+
+    if let Some(order) = self.resend_request() {
+      self.backend_failed = true;
+      return order;
+    }
+
+    error!("{}	back socket closed during the response, answering 502", self.log_context());
+    let answer_502 = "HTTP/1.1 502 Bad Gateway\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n";
+    self.set_answer(DefaultAnswerStatus::Answer502, Rc::new(Vec::from(answer_502.as_bytes())));
+    SessionResult::Continue
+  }
+
+  fn read_response(&mut self, metrics: &mut SessionMetrics) -> (ProtocolResult, SessionResult) {
     if let SessionStatus::DefaultAnswer(_,_,_) = self.status {
       error!("{}\tsending default answer, should not read from back socket", self.log_context());
       self.back_readiness.interest.remove(Ready::readable());
@@ -1154,6 +2272,14 @@ impl<Front:SocketHandler> Http<Front> {
       return (ProtocolResult::Continue, SessionResult::Continue);
     }
 
+    // the client reads slower than the backend sends: stop reading until
+    // part of the response was sent to the client
+    if self.back_buf.as_ref().unwrap().is_above_high_water_mark() {
+      self.back_readiness.interest.remove(Ready::readable());
+      self.front_readiness.interest.insert(Ready::writable());
+      return (ProtocolResult::Continue, SessionResult::Continue);
+    }
+
     let tokens     = self.tokens();
 
     if self.backend.is_none() {
@@ -1176,16 +2302,33 @@ impl<Front:SocketHandler> Http<Front> {
     if let Some((front,back)) = tokens {
       debug!("{}\tBACK  [{}<-{}]: read {} bytes", self.log_context(), front.0, back.0, sz);
     }
+    self.trace_event(format_args!("back read {} bytes: {:?}", sz, r));
 
     if r != SocketResult::Continue || sz == 0 {
       self.back_readiness.event.remove(Ready::readable());
     }
+    self.back_read_closed = r == SocketResult::Error || r == SocketResult::Closed;
+
+    if (r == SocketResult::Error || r == SocketResult::Closed) && sz == 0 {
+      if let Some(order) = self.resend_request() {
+        return (ProtocolResult::Continue, order);
+      }
+    }
 
     if r == SocketResult::Error {
+      if self.is_response_incomplete() {
+        return (ProtocolResult::Continue, SessionResult::Continue);
+      }
       self.log_request_error(metrics, "back socket read error, closing connection");
       return (ProtocolResult::Continue, SessionResult::CloseSession);
     }
 
+    if self.back_write_closed && r == SocketResult::Closed && sz == 0
+      && self.response == Some(ResponseState::Initial) {
+      self.log_request_error(metrics, "back socket closed during the request without answering, closing connection");
+      return (ProtocolResult::Continue, SessionResult::CloseSession);
+    }
+
     // isolate that here because the "ref protocol" and the self.state = " make borrowing conflicts
     if let Some(ResponseState::ResponseUpgrade(_,_, ref protocol)) = self.response {
       debug!("got an upgrade state[{}]: {:?}", line!(), protocol);
@@ -1203,6 +2346,7 @@ impl<Front:SocketHandler> Http<Front> {
         (ProtocolResult::Continue, SessionResult::CloseSession)
       },
       Some(ResponseState::ResponseWithBody(_,_,_)) => {
+        self.compress_response();
         self.front_readiness.interest.insert(Ready::writable());
         if ! self.back_buf.as_ref().unwrap().needs_input() {
           metrics.backend_stop();
@@ -1265,6 +2409,7 @@ impl<Front:SocketHandler> Http<Front> {
             self.back_readiness.interest.remove(Ready::readable());
           }
         }
+        self.compress_response();
         self.front_readiness.interest.insert(Ready::writable());
         (ProtocolResult::Continue, SessionResult::Continue)
       },
@@ -1296,9 +2441,16 @@ impl<Front:SocketHandler> Http<Front> {
       },
       Some(ResponseState::Error(_,_,_,_,_)) => panic!("{}\tback read should have stopped on responsestate error", self.log_context()),
       _ => {
+        if self.res_header_end.is_none() {
+          if let Some(result) = self.reject_large_response_headers() {
+            return (ProtocolResult::Continue, result);
+          }
+        }
+
         let (response_state, header_end, is_head) =
             (self.response.take().unwrap(), self.res_header_end.take(),
               self.request.as_ref().map(|request| request.is_head()).unwrap_or(false));
+        let had_header_end = header_end.is_some();
 
         {
           let sticky_session = self.sticky_session.as_ref().and_then(|session| {
@@ -1318,11 +2470,30 @@ impl<Front:SocketHandler> Http<Front> {
           self.res_header_end = header_end2;
         };
 
+        if !had_header_end {
+          if let Some(buf) = self.back_buf.as_mut() {
+            delete_headers(buf, &self.response_headers_to_delete);
+          }
+
+          if self.res_header_end.is_some() {
+            self.rewrite_backend_hosts();
+          }
+        }
+
         if unwrap_msg!(self.response.as_ref()).is_back_error() {
           self.log_request_error(metrics, "back socket parse error, closing connection");
           return (ProtocolResult::Continue, SessionResult::CloseSession);
         }
 
+        if !had_header_end && self.res_header_end.is_some() && self.transform_response() {
+          return (ProtocolResult::Continue, SessionResult::Continue);
+        }
+
+        if !had_header_end && self.res_header_end.is_some() {
+          self.start_compression();
+        }
+        self.compress_response();
+
         if let Some(ResponseState::Response(_,_)) = self.response {
           metrics.backend_stop();
           self.backend_stop = Some(SteadyTime::now());
@@ -1373,6 +2544,209 @@ fn save_http_status_metric(rs_status_line : Option<&RStatusLine>) {
   }
 }
 
+/// appends the `size` first bytes of `bufs` to the copy of the request sent to the
+/// backend, or drops the copy once it grows over `MAX_RESENT_REQUEST_SIZE`
+fn keep_sent_data(sent: &mut Option<Vec<u8>>, bufs: &[&iovec::IoVec], size: usize) {
+  if sent.as_ref().map(|data| data.len() + size > MAX_RESENT_REQUEST_SIZE).unwrap_or(false) {
+    *sent = None;
+  }
+
+  if let Some(ref mut data) = *sent {
+    let mut remaining = size;
+    for buf in bufs.iter() {
+      if remaining == 0 {
+        break;
+      }
+      let len = min(remaining, buf.len());
+      data.extend_from_slice(&buf[..len]);
+      remaining -= len;
+    }
+  }
+}
+
+/// selects the frontend with the longest `path_begin` that is a prefix of the
+/// request path, so that `/api` is chosen over `/` for `/api/users`,
+/// whatever the order in which the frontends were added.
+/// If several frontends have the same `path_begin`, the first one is chosen
+pub fn longest_path_prefix<'a, T, F>(fronts: &'a [T], uri: &str, path_begin: F) -> Option<&'a T>
+  where F: Fn(&T) -> &str {
+  longest_path_match(fronts, uri, |f| (path_begin(f), TrailingSlash::Strict))
+}
+
+/// like `longest_path_prefix`, but each frontend has its own trailing slash
+/// mode. The trailing slashes of a normalized `path_begin` are not counted
+/// in its length: for `/api/x`, a strict `/api/` frontend wins over a
+/// normalized `/api/` frontend, which is equivalent to `/api`
+pub fn longest_path_match<'a, T, F>(fronts: &'a [T], uri: &str, path: F) -> Option<&'a T>
+  where F: Fn(&T) -> (&str, TrailingSlash) {
+  longest_match(fronts.iter(), uri, |f| { let (path_begin, trailing_slash) = path(f); (path_begin, trailing_slash, false) })
+}
+
+/// the routing conditions of a frontend, besides its hostname
+pub struct FrontendRule<'a> {
+  pub method:         Option<&'a str>,
+  pub path_begin:     &'a str,
+  pub trailing_slash: TrailingSlash,
+  pub header_match:   Option<&'a HeaderMatch>,
+}
+
+impl<'a> FrontendRule<'a> {
+  fn path(&self) -> (&'a str, TrailingSlash, bool) {
+    (self.path_begin, self.trailing_slash, self.header_match.is_some())
+  }
+}
+
+/// selects the frontend of a request among the frontends of its hostname: the
+/// longest path match among the frontends restricted to the request's method,
+/// then among the frontends accepting any method. The methods are compared
+/// without case.
+/// A frontend with a `header_match` is only used if the request has this header
+/// with this exact value, and it wins over the frontends with the same path length
+/// and no header match. `header` returns the value of a request header
+pub fn frontend_match<'a, T, F, H>(fronts: &'a [T], method: Option<&str>, uri: &str, header: H, frontend: F) -> Option<&'a T>
+  where F: Fn(&T) -> FrontendRule, H: Fn(&str) -> Option<String> {
+  let header_matches = |rule: &FrontendRule| rule.header_match
+    .map(|h| header(&h.name).map(|value| value == h.value).unwrap_or(false))
+    .unwrap_or(true);
+
+  method.and_then(|method| {
+    let restricted = fronts.iter().filter(|f| {
+      let rule = frontend(f);
+      rule.method.map(|m| m.eq_ignore_ascii_case(method)).unwrap_or(false) && header_matches(&rule)
+    });
+    longest_match(restricted, uri, |f| frontend(f).path())
+  }).or_else(|| {
+    let any_method = fronts.iter().filter(|f| {
+      let rule = frontend(f);
+      rule.method.is_none() && header_matches(&rule)
+    });
+    longest_match(any_method, uri, |f| frontend(f).path())
+  })
+}
+
+/// the frontend with the longest path, then with a header match
+fn longest_match<'a, T: 'a, I, F>(fronts: I, uri: &str, path: F) -> Option<&'a T>
+  where I: Iterator<Item=&'a T>, F: Fn(&T) -> (&str, TrailingSlash, bool) {
+  let mut front: Option<(&'a T, (usize, bool))> = None;
+
+  for f in fronts {
+    let (path_begin, trailing_slash, header_match) = path(f);
+    if !path_matches(path_begin, trailing_slash, uri) {
+      continue;
+    }
+
+    let len = match trailing_slash {
+      TrailingSlash::Strict    => path_begin.len(),
+      TrailingSlash::Normalize => path_begin.trim_end_matches('/').len(),
+    };
+    if front.map(|(_, specificity)| (len, header_match) > specificity).unwrap_or(true) {
+      front = Some((f, (len, header_match)));
+    }
+  }
+
+  front.map(|(f, _)| f)
+}
+
+/// returns true if the request path matches the `path_begin` of a frontend
+pub fn path_matches(path_begin: &str, trailing_slash: TrailingSlash, uri: &str) -> bool {
+  match trailing_slash {
+    TrailingSlash::Strict => uri.starts_with(path_begin),
+    TrailingSlash::Normalize => {
+      let prefix = path_begin.trim_end_matches('/');
+      uri.starts_with(prefix) && match uri.as_bytes().get(prefix.len()) {
+        None | Some(b'/') | Some(b'?') => true,
+        _ => false,
+      }
+    },
+  }
+}
+
+/// headers describing the frontend TLS connection, sent to the backends
+/// of the applications configured with `forward_tls_info`
+pub fn tls_info_headers(version: &str, cipher: &str) -> String {
+  format!("X-Forwarded-TLS-Version: {}\r\nX-Forwarded-TLS-Cipher: {}\r\n", version, cipher)
+}
+
+/// host used to route a HTTPS request: the TLS SNI hostname, with the port of
+/// the Host header unless it is 443, or the Host header if the client sent no
+/// SNI. `hostname` and `port` are the parts of the Host header `host`.
+/// Returns None if `reject_mismatch` is set and the Host header does not match
+/// the SNI hostname, or there is no SNI
+pub fn tls_routing_host(host: &str, hostname: &str, port: Option<&[u8]>, sni: Option<&str>,
+  reject_mismatch: bool) -> Option<String> {
+  let sni = match sni {
+    Some(sni) => sni,
+    None if reject_mismatch => return None,
+    None => return Some(if port == Some(&b"443"[..]) { hostname.to_string() } else { host.to_string() }),
+  };
+
+  if !sni.eq_ignore_ascii_case(hostname) {
+    if reject_mismatch {
+      return None;
+    }
+    debug!("TLS SNI hostname '{}' and Host header '{}' don't match, routing on the SNI hostname", sni, host);
+  }
+
+  Some(match port {
+    Some(port) if port != &b"443"[..] => format!("{}:{}", sni, String::from_utf8_lossy(port)),
+    _ => sni.to_string(),
+  })
+}
+
+/// certificate presented by a HTTPS client and verified against the listener's client CA
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct ClientCertificate {
+  /// subject name, like "C=FR, O=Sozu, CN=client.example.com"
+  pub subject:     String,
+  /// hex encoded SHA-256 fingerprint of the DER certificate
+  pub fingerprint: String,
+}
+
+impl ClientCertificate {
+  pub fn from_der(der: &[u8]) -> ClientCertificate {
+    ClientCertificate {
+      subject:     certificate::subject_name(der).unwrap_or_default(),
+      fingerprint: certificate::calculate_fingerprint_from_der(der).iter()
+        .map(|byte| format!("{:02x}", byte)).collect(),
+    }
+  }
+
+  /// headers added to the TLS connection headers sent to the backends
+  pub fn headers(&self) -> String {
+    format!("X-Client-Cert-Subject: {}\r\nX-Client-Cert-Fingerprint: {}\r\n", self.subject, self.fingerprint)
+  }
+}
+
+/// node of a Forwarded header (RFC 7239). IPv6 addresses are written between
+/// brackets, and quoted since they contain colons
+fn forwarded_node(ip: IpAddr, port: Option<u16>) -> String {
+  match (ip, port) {
+    (IpAddr::V4(ip), Some(port)) => format!("{}:{}", ip, port),
+    (IpAddr::V4(ip), None)       => ip.to_string(),
+    (IpAddr::V6(ip), Some(port)) => format!("\"[{}]:{}\"", ip, port),
+    (IpAddr::V6(ip), None)       => format!("\"[{}]\"", ip),
+  }
+}
+
+/// X-Forwarded-For value: the addresses added by the previous proxies,
+/// from one or several headers, followed by the client address, unless
+/// it is already the last one
+pub fn forwarded_for(previous: Option<&str>, client: IpAddr) -> String {
+  let client = client.to_string();
+  let mut addresses: Vec<&str> = Vec::new();
+  for address in previous.unwrap_or("").split(',').map(|a| a.trim()).filter(|a| !a.is_empty()) {
+    if addresses.last() != Some(&address) {
+      addresses.push(address);
+    }
+  }
+
+  if addresses.last() != Some(&client.as_str()) {
+    addresses.push(&client);
+  }
+
+  addresses.join(", ")
+}
+
 pub struct LogContext<'a> {
   pub request_id: Hyphenated,
   pub app_id:     Option<&'a str>,
@@ -1395,6 +2769,8 @@ impl std::fmt::Display for SessionAddress {
   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
     match self.0 {
       None => write!(f, "-"),
+      // the addresses found in headers have no port
+      Some(addr) if addr.port() == 0 => write!(f, "{}", addr.ip()),
       Some(SocketAddr::V4(addr)) => write!(f, "{}", addr),
       Some(SocketAddr::V6(addr)) => write!(f, "{}", addr),
     }
@@ -1465,9 +2841,503 @@ impl<'a> std::fmt::Display for OptionalStatus<'a> {
   }
 }
 
+/// returns the public URL for an absolute Location, if it points at the backend,
+/// one of the backend hosts with or without a port, or at the public host with
+/// another scheme. Relative URLs are not modified, the client resolves them
+/// against the public URL
+pub fn rewritten_location(location: &str, backend_address: Option<&SocketAddr>, backend_hosts: &[String],
+  host: &str, scheme: &str) -> Option<String> {
+  let location = location.trim();
+  let (location_scheme, rest) = if location.starts_with("//") {
+    // scheme relative URL
+    (None, &location[2..])
+  } else {
+    match location.find("://") {
+      Some(index) => (Some(&location[..index]), &location[index+3..]),
+      None        => return None,
+    }
+  };
+
+  let authority_end = rest.find(|c| c == '/' || c == '?' || c == '#').unwrap_or(rest.len());
+  let (authority, path) = rest.split_at(authority_end);
+
+  let hostname = hostname_without_port(authority).unwrap_or(authority);
+  let to_backend = backend_address.map(|address| address.to_string() == authority).unwrap_or(false)
+    || backend_hosts.iter().any(|backend_host| backend_host.eq_ignore_ascii_case(hostname)
+      || backend_host.eq_ignore_ascii_case(authority));
+  let to_host = authority.eq_ignore_ascii_case(host);
+  if !to_backend && !to_host {
+    return None;
+  }
+
+  match location_scheme {
+    None => if to_backend {
+      Some(format!("//{}{}", host, path))
+    } else {
+      None
+    },
+    Some(location_scheme) => if to_backend || !location_scheme.eq_ignore_ascii_case(scheme) {
+      Some(format!("{}://{}{}", scheme, host, path))
+    } else {
+      None
+    },
+  }
+}
+
+/// returns the Set-Cookie value with the Domain attribute set to the public host,
+/// without its port, if it was one of the backend hosts. A leading dot is ignored
+pub fn rewritten_cookie_domain(cookie: &str, backend_hosts: &[String], host: &str) -> Option<String> {
+  let public_domain = hostname_without_port(host).unwrap_or(host);
+  let mut rewritten = false;
+  // the first pair is the name and value of the cookie
+  let attributes: Vec<String> = cookie.split(';').enumerate().map(|(index, attribute)| {
+    let mut parts = attribute.splitn(2, '=');
+    let name = parts.next().unwrap_or("");
+    let domain = match parts.next() {
+      Some(value) if index > 0 && name.trim().eq_ignore_ascii_case("domain") => value.trim(),
+      _ => return attribute.to_string(),
+    };
+
+    let domain = if domain.starts_with('.') { &domain[1..] } else { domain };
+    if backend_hosts.iter().any(|backend_host| backend_host.eq_ignore_ascii_case(domain)) {
+      rewritten = true;
+      let leading_space = if attribute.starts_with(' ') { " " } else { "" };
+      format!("{}{}={}", leading_space, name.trim(), public_domain)
+    } else {
+      attribute.to_string()
+    }
+  }).collect();
+
+  if rewritten {
+    Some(attributes.join(";"))
+  } else {
+    None
+  }
+}
+
+/// returns the names of the headers deleted by the rules, and the headers they add
+fn header_rules(rules: &[HeaderRule]) -> (Vec<String>, String) {
+  let mut to_delete = Vec::new();
+  let mut to_add = String::new();
+
+  for rule in rules.iter() {
+    match *rule {
+      HeaderRule::Remove { ref name } => to_delete.push(name.clone()),
+      HeaderRule::Add { ref name, ref value, replace } => {
+        if !hooks::is_valid_header(name, value) {
+          error!("invalid header in the application's header rules: {:?}: {:?}", name, value);
+          continue;
+        }
+
+        if replace {
+          to_delete.push(name.clone());
+        }
+        to_add.push_str(&format!("{}: {}\r\n", name, value));
+      },
+    }
+  }
+
+  (to_delete, to_add)
+}
+
 #[cfg(test)]
 mod tests {
   use super::*;
+  use super::parser::{Connection, RRequestLine, Version};
+
+  #[test]
+  fn sent_request_copy() {
+    let first = b"GET / HTTP/1.1\r\n";
+    let second = b"Host: example.com\r\n\r\n";
+    let bufs = [iovec::IoVec::from_bytes(&first[..]).unwrap(), iovec::IoVec::from_bytes(&second[..]).unwrap()];
+
+    // a partial write stops in the middle of the second slice
+    let mut sent = Some(Vec::new());
+    keep_sent_data(&mut sent, &bufs, first.len() + 4);
+    assert_eq!(sent.as_ref().map(|data| &data[..]), Some(&b"GET / HTTP/1.1\r\nHost"[..]));
+
+    let large = vec![0u8; MAX_RESENT_REQUEST_SIZE];
+    let bufs = [iovec::IoVec::from_bytes(&large[..]).unwrap()];
+    keep_sent_data(&mut sent, &bufs, large.len());
+    assert_eq!(sent, None);
+
+    keep_sent_data(&mut sent, &bufs, 10);
+    assert_eq!(sent, None);
+  }
+
+  #[test]
+  fn location_rewriting() {
+    let backend: SocketAddr = "10.0.0.1:8080".parse().unwrap();
+    let backend = Some(&backend);
+
+    assert_eq!(rewritten_location("http://10.0.0.1:8080/login?next=%2F", backend, &[], "example.com", "https"),
+      Some(String::from("https://example.com/login?next=%2F")));
+    assert_eq!(rewritten_location("http://10.0.0.1:8080", backend, &[], "example.com", "https"),
+      Some(String::from("https://example.com")));
+    assert_eq!(rewritten_location("//10.0.0.1:8080/login", backend, &[], "example.com", "https"),
+      Some(String::from("//example.com/login")));
+    // the backend generated the URL with the scheme it received
+    assert_eq!(rewritten_location("http://Example.com/login", backend, &[], "example.com", "https"),
+      Some(String::from("https://example.com/login")));
+
+    assert_eq!(rewritten_location("https://example.com/login", backend, &[], "example.com", "https"), None);
+    assert_eq!(rewritten_location("/login", backend, &[], "example.com", "https"), None);
+    assert_eq!(rewritten_location("login", backend, &[], "example.com", "https"), None);
+    assert_eq!(rewritten_location("https://accounts.example.org/auth", backend, &[], "example.com", "https"), None);
+    assert_eq!(rewritten_location("http://10.0.0.2:8080/login", backend, &[], "example.com", "https"), None);
+  }
+
+  #[test]
+  fn backend_host_rewriting() {
+    let backend: SocketAddr = "10.0.0.1:8080".parse().unwrap();
+    let backend = Some(&backend);
+    let hosts = vec![String::from("app.internal"), String::from("api.internal:8080")];
+
+    assert_eq!(rewritten_location("http://app.internal:8080/login?next=%2F", backend, &hosts, "example.com", "https"),
+      Some(String::from("https://example.com/login?next=%2F")));
+    assert_eq!(rewritten_location("http://APP.internal", backend, &hosts, "example.com:8443", "https"),
+      Some(String::from("https://example.com:8443")));
+    assert_eq!(rewritten_location("//app.internal/login", backend, &hosts, "example.com", "https"),
+      Some(String::from("//example.com/login")));
+    assert_eq!(rewritten_location("http://api.internal:8080/v1", backend, &hosts, "example.com", "http"),
+      Some(String::from("http://example.com/v1")));
+    assert_eq!(rewritten_location("http://10.0.0.1:8080/login", backend, &hosts, "example.com", "https"),
+      Some(String::from("https://example.com/login")));
+    assert_eq!(rewritten_location("http://app.internal.example.org/login", backend, &hosts, "example.com", "https"), None);
+    assert_eq!(rewritten_location("/login", backend, &hosts, "example.com", "https"), None);
+
+    assert_eq!(rewritten_cookie_domain("session=abc; Path=/; Domain=app.internal; HttpOnly", &hosts, "example.com:8443"),
+      Some(String::from("session=abc; Path=/; Domain=example.com; HttpOnly")));
+    assert_eq!(rewritten_cookie_domain("session=abc;domain=.APP.internal", &hosts, "example.com"),
+      Some(String::from("session=abc;domain=example.com")));
+    // the cookie value is not an attribute
+    assert_eq!(rewritten_cookie_domain("domain=app.internal; Path=/", &hosts, "example.com"), None);
+    assert_eq!(rewritten_cookie_domain("session=abc; Domain=example.org", &hosts, "example.com"), None);
+    assert_eq!(rewritten_cookie_domain("session=abc; Path=/", &hosts, "example.com"), None);
+  }
+
+  #[test]
+  fn longest_path_prefix_wins() {
+    let fronts = vec![("/api", 1), ("/", 2), ("/api/users", 3), ("/static", 4), ("/api", 5)];
+    let lookup = |uri| longest_path_prefix(&fronts, uri, |f| f.0).map(|f| f.1);
+
+    assert_eq!(lookup("/api/users/1"), Some(3));
+    assert_eq!(lookup("/api/user"), Some(1));
+    assert_eq!(lookup("/api"), Some(1));
+    assert_eq!(lookup("/static/style.css"), Some(4));
+    assert_eq!(lookup("/index.html"), Some(2));
+    assert_eq!(lookup("/"), Some(2));
+
+    let no_root = vec![("/api", 1), ("/static", 2)];
+    assert_eq!(longest_path_prefix(&no_root, "/index.html", |f| f.0), None);
+  }
+
+  #[test]
+  fn trailing_slash_modes() {
+    use self::TrailingSlash::*;
+
+    for &(path_begin, mode, uri, expected) in [
+      ("/api",  Strict,    "/api",   true),
+      ("/api",  Strict,    "/api/",  true),
+      ("/api",  Strict,    "/api/x", true),
+      ("/api",  Strict,    "/apix",  true),
+      ("/api/", Strict,    "/api",   false),
+      ("/api/", Strict,    "/api/x", true),
+      ("/api",  Normalize, "/api",   true),
+      ("/api",  Normalize, "/api/",  true),
+      ("/api",  Normalize, "/api/x", true),
+      ("/api",  Normalize, "/apix",  false),
+      ("/api",  Normalize, "/api?q", true),
+      ("/api/", Normalize, "/api",   true),
+      ("/api/", Normalize, "/api/x", true),
+      ("/",     Normalize, "/x",     true),
+    ].iter() {
+      assert_eq!(path_matches(path_begin, mode, uri), expected,
+        "{} ({:?}) against {}", path_begin, mode, uri);
+    }
+
+    // a strict path keeps its trailing slash in the length comparison
+    let fronts = vec![("/api/", Normalize, 1), ("/api/", Strict, 2), ("/", Strict, 3)];
+    let lookup = |uri| longest_path_match(&fronts, uri, |f| (f.0, f.1)).map(|f| f.2);
+    assert_eq!(lookup("/api/x"), Some(2));
+    assert_eq!(lookup("/api"), Some(1));
+    assert_eq!(lookup("/apix"), Some(3));
+  }
+
+  fn forwarding_session(protocol: Protocol, forwarded_for: Option<&str>) -> (Http<TcpStream>, std::net::TcpListener) {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("could not bind");
+    let stream = std::net::TcpStream::connect(listener.local_addr().unwrap()).expect("could not connect");
+    let front = TcpStream::from_stream(stream).expect("could not create the mio stream");
+
+    let mut http = Http::new(front, Token(0), Uuid::new_v4().to_hyphenated(), Weak::new(),
+      "127.0.0.1:8080".parse().unwrap(), Some("10.0.0.1:1234".parse().unwrap()),
+      String::from("SOZUBALANCEID"), false, false, UnexpectedBodyPolicy::Reject, protocol);
+
+    let mut conn = Connection::new();
+    conn.forwarded_for = forwarded_for.map(String::from);
+    http.request = Some(RequestState::HasHost(
+      RRequestLine { method: Method::Get, uri: String::from("/"), version: Version::V11 },
+      conn, String::from("localhost")));
+    http.added_req_header = String::new();
+
+    (http, listener)
+  }
+
+  #[test]
+  fn failure_statuses() {
+    use super::parser::RStatusLine;
+
+    let (mut http, _listener) = forwarding_session(Protocol::HTTP, None);
+    let backend = Rc::new(RefCell::new(Backend::new("app_1-0", "127.0.0.1:1081".parse().unwrap(), None, None, None)));
+    http.set_backend(backend.clone());
+    let metrics = SessionMetrics::new(None);
+    let answer = |http: &mut Http<TcpStream>, status: u16| {
+      http.response = Some(ResponseState::Response(
+        RStatusLine { version: Version::V11, status, reason: String::new() }, Connection::new()));
+      http.log_request_success(&metrics);
+    };
+
+    // without failure statuses, the answers do not change the backend's failures
+    answer(&mut http, 503);
+    assert_eq!(backend.borrow().failures, 0);
+    assert!(backend.borrow().can_open());
+
+    http.set_failure_statuses(vec![502, 503, 504]);
+    answer(&mut http, 500);
+    assert_eq!(backend.borrow().failures, 0);
+
+    answer(&mut http, 503);
+    assert_eq!(backend.borrow().failures, 1);
+    // the retry policy waits before selecting the backend again
+    assert!(!backend.borrow().can_open());
+    answer(&mut http, 503);
+    assert_eq!(backend.borrow().failures, 2);
+
+    // the next successful connection will not reset them
+    assert!(backend.borrow().failing_answers);
+
+    // a good answer does
+    answer(&mut http, 200);
+    assert_eq!(backend.borrow().failures, 0);
+    assert!(!backend.borrow().failing_answers);
+  }
+
+  #[test]
+  fn access_log_record() {
+    use mio_uds::UnixDatagram;
+    use sozu_command::logging::{self, LogFormat, LoggerBackend, parse_logging_spec};
+    use super::parser::RStatusLine;
+
+    let (backend, receiver) = UnixDatagram::pair().expect("should create a socket pair");
+    logging::LOGGER.with(|l| {
+      let mut logger = l.borrow_mut();
+      logger.set_directives(parse_logging_spec("info"));
+      logger.backend = LoggerBackend::Unix(backend);
+      logger.set_format(LogFormat::Json);
+    });
+
+    let (mut http, _listener) = forwarding_session(Protocol::HTTP, None);
+    let request_id = http.request_id.to_string();
+    http.request = Some(RequestState::Request(
+      RRequestLine { method: Method::Post, uri: String::from("/api/users"), version: Version::V11 },
+      Connection::new(), String::from("example.com")));
+    http.response = Some(ResponseState::Response(
+      RStatusLine { version: Version::V11, status: 201, reason: String::from("Created") },
+      Connection::new()));
+    http.app_id = Some(String::from("app_1"));
+    http.backend_id = Some(String::from("app_1-0"));
+    http.backend_address = Some("10.0.0.2:8080".parse().unwrap());
+
+    let mut metrics = SessionMetrics::new(None);
+    metrics.request_start();
+    metrics.bin = 150;
+    metrics.bout = 1200;
+    http.log_request_success(&metrics);
+
+    let mut buf = [0u8; 4096];
+    let size = receiver.recv(&mut buf).expect("should receive the access log");
+    let record = std::str::from_utf8(&buf[..size]).unwrap();
+    assert!(record.starts_with('{') && record.ends_with("}\n"), "{}", record);
+    for field in [
+      format!("\"request_id\":\"{}\"", request_id),
+      String::from("\"client\":\"10.0.0.1:1234\""),
+      String::from("\"host\":\"example.com\""),
+      String::from("\"method\":\"POST\""),
+      String::from("\"path\":\"/api/users\""),
+      String::from("\"status\":201"),
+      String::from("\"bytes_in\":150"),
+      String::from("\"bytes_out\":1200"),
+      String::from("\"app_id\":\"app_1\""),
+      String::from("\"backend_id\":\"app_1-0\""),
+      String::from("\"backend\":\"10.0.0.2:8080\""),
+      String::from("\"latency\":"),
+      String::from("\"retries\":0"),
+    ].iter() {
+      assert!(record.contains(field.as_str()), "{} not in {}", field, record);
+    }
+
+    // the connection to the first backend failed, the second one served the request
+    let failed = Rc::new(RefCell::new(Backend::new("app_1-0", "10.0.0.2:8080".parse().unwrap(), None, None, None)));
+    let served = Rc::new(RefCell::new(Backend::new("app_1-1", "10.0.0.3:8080".parse().unwrap(), None, None, None)));
+    http.set_backend(failed);
+    http.set_backend(served);
+    http.backend_address = Some("10.0.0.3:8080".parse().unwrap());
+    http.log_request_success(&metrics);
+
+    let size = receiver.recv(&mut buf).expect("should receive the access log");
+    let record = std::str::from_utf8(&buf[..size]).unwrap();
+    for field in &["\"retries\":1", "\"backend_id\":\"app_1-1\"", "\"backend\":\"10.0.0.3:8080\""] {
+      assert!(record.contains(field), "{} not in {}", field, record);
+    }
+
+    // the next request of the connection starts over
+    http.reset();
+    assert_eq!(http.access_record(&metrics, None, None).retries, 0);
+  }
+
+  #[test]
+  fn high_water_mark_flow_control() {
+    use buffer_queue::{buf_with_capacity, set_high_water_mark};
+    use super::parser::RStatusLine;
+    use std::io::Write;
+
+    set_high_water_mark(Some(8));
+    let (_back_pool, mut back_buf) = buf_with_capacity(64);
+    let (_front_pool, mut front_buf) = buf_with_capacity(64);
+    set_high_water_mark(None);
+    let mut metrics = SessionMetrics::new(None);
+
+    // slow client: the backend is not read until the response was sent to the client
+    let (mut http, _listener) = forwarding_session(Protocol::HTTP, None);
+    back_buf.write_all(&b"0123456789"[..]).unwrap();
+    back_buf.consume_parsed_data(10);
+    back_buf.slice_output(10);
+    http.back_buf = Some(back_buf);
+    http.response = Some(ResponseState::ResponseWithBodyCloseDelimited(
+      RStatusLine { version: Version::V11, status: 200, reason: String::from("OK") },
+      Connection::new(), false));
+    http.back_readiness.interest.insert(Ready::readable());
+
+    assert_eq!(http.read_response(&mut metrics), (ProtocolResult::Continue, SessionResult::Continue));
+    assert!(!http.back_readiness.interest.is_readable());
+    assert!(http.front_readiness.interest.is_writable());
+
+    assert_eq!(http.writable(&mut metrics), SessionResult::Continue);
+    assert_eq!(metrics.bout, 10);
+    assert!(http.back_readiness.interest.is_readable());
+
+    // slow backend: the client is not read until the request was sent to the backend
+    let backend_listener = std::net::TcpListener::bind("127.0.0.1:0").expect("could not bind");
+    let backend = std::net::TcpStream::connect(backend_listener.local_addr().unwrap()).expect("could not connect");
+    front_buf.write_all(&b"0123456789"[..]).unwrap();
+    front_buf.consume_parsed_data(10);
+    front_buf.slice_output(10);
+    http.front_buf = Some(front_buf);
+    http.backend = Some(TcpStream::from_stream(backend).expect("could not create the mio stream"));
+    http.backend_token = Some(Token(1));
+    http.front_readiness.interest.insert(Ready::readable());
+
+    assert_eq!(http.readable(&mut metrics), SessionResult::Continue);
+    assert!(!http.front_readiness.interest.is_readable());
+    assert!(http.back_readiness.interest.is_writable());
+
+    assert_eq!(http.back_writable(&mut metrics), SessionResult::Continue);
+    assert_eq!(metrics.backend_bout, 10);
+    assert!(http.front_readiness.interest.is_readable());
+  }
+
+  #[test]
+  fn forwarded_headers_fresh_request() {
+    let (mut http, _listener) = forwarding_session(Protocol::HTTP, None);
+    http.add_forwarded_headers(true);
+    // headers are only added once, even if the backend connection is retried
+    http.add_forwarded_headers(true);
+
+    assert_eq!(&http.added_req_header,
+      "Forwarded: proto=http;for=10.0.0.1:1234;by=127.0.0.1\r\nX-Forwarded-Proto: http\r\n\
+      X-Forwarded-For: 10.0.0.1\r\nX-Forwarded-Port: 8080\r\n");
+  }
+
+  #[test]
+  fn forwarded_headers_ipv6() {
+    let (mut http, _listener) = forwarding_session(Protocol::HTTP, None);
+    http.session_address = Some("[2001:db8:cafe::17]:4711".parse().unwrap());
+    http.add_forwarded_headers(true);
+    assert_eq!(&http.added_req_header,
+      "Forwarded: proto=http;for=\"[2001:db8:cafe::17]:4711\";by=127.0.0.1\r\nX-Forwarded-Proto: http\r\n\
+      X-Forwarded-For: 2001:db8:cafe::17\r\nX-Forwarded-Port: 8080\r\n");
+
+    let (mut http, _listener) = forwarding_session(Protocol::HTTP, None);
+    http.public_address = "[::1]:8080".parse().unwrap();
+    http.add_forwarded_headers(true);
+    assert!(http.added_req_header.starts_with("Forwarded: proto=http;for=10.0.0.1:1234;by=\"[::1]\"\r\n"),
+      "{}", http.added_req_header);
+  }
+
+  #[test]
+  fn forwarded_headers_existing_forwarded_for() {
+    let (mut http, _listener) = forwarding_session(Protocol::HTTP, Some("203.0.113.7, 198.51.100.2"));
+    http.add_forwarded_headers(true);
+    assert!(http.added_req_header.contains("X-Forwarded-For: 203.0.113.7, 198.51.100.2, 10.0.0.1\r\n"));
+
+    // without the forwarding headers, the client's value is kept as is
+    let (mut http, _listener) = forwarding_session(Protocol::HTTP, Some("203.0.113.7, 198.51.100.2"));
+    http.add_forwarded_headers(false);
+    assert_eq!(&http.added_req_header, "X-Forwarded-For: 203.0.113.7, 198.51.100.2\r\n");
+
+    assert_eq!(forwarded_for(Some("203.0.113.7,,203.0.113.7 , 10.0.0.1"), "10.0.0.1".parse().unwrap()),
+      "203.0.113.7, 10.0.0.1");
+    assert_eq!(forwarded_for(Some(""), "10.0.0.1".parse().unwrap()), "10.0.0.1");
+  }
+
+  #[test]
+  fn forwarded_headers_https() {
+    let (mut http, _listener) = forwarding_session(Protocol::HTTPS, None);
+    http.add_forwarded_headers(true);
+
+    assert!(http.added_req_header.starts_with("Forwarded: proto=https;"));
+    assert!(http.added_req_header.contains("X-Forwarded-Proto: https\r\n"));
+  }
+
+  #[test]
+  fn request_id_headers() {
+    let (mut http, _listener) = forwarding_session(Protocol::HTTP, None);
+    let names = ["X-Request-Id", "X-Correlation-Id", "sozu-id", "Invalid Name"].iter()
+      .map(|s| String::from(*s)).collect::<Vec<_>>();
+    http.add_header_rules(&[], &[], &names);
+    http.add_header_rules(&[], &[], &names);
+
+    let id = http.request_id;
+    assert_eq!(http.added_req_header, format!("X-Request-Id: {}\r\nX-Correlation-Id: {}\r\n", id, id));
+    // the ids sent by the client are removed
+    assert_eq!(http.request_headers_to_delete, vec![String::from("X-Request-Id"), String::from("X-Correlation-Id")]);
+  }
+
+  #[test]
+  fn sni_routing() {
+    let route = |host: &str, sni: Option<&str>, reject_mismatch: bool| {
+      let (hostname, port) = match host.find(':') {
+        Some(i) => (&host[..i], Some(host[i + 1..].as_bytes())),
+        None    => (host, None),
+      };
+      tls_routing_host(host, hostname, port, sni, reject_mismatch)
+    };
+
+    assert_eq!(route("lolcatho.st", Some("lolcatho.st"), false), Some(String::from("lolcatho.st")));
+    assert_eq!(route("lolcatho.st:443", Some("lolcatho.st"), false), Some(String::from("lolcatho.st")));
+    assert_eq!(route("LolCatHo.st:8443", Some("lolcatho.st"), true), Some(String::from("lolcatho.st:8443")));
+
+    // the SNI hostname wins over the Host header
+    assert_eq!(route("other.example.com", Some("lolcatho.st"), false), Some(String::from("lolcatho.st")));
+    assert_eq!(route("other.example.com:8443", Some("lolcatho.st"), false), Some(String::from("lolcatho.st:8443")));
+    // without SNI the Host header is used
+    assert_eq!(route("other.example.com:443", None, false), Some(String::from("other.example.com")));
+    assert_eq!(route("other.example.com:8443", None, false), Some(String::from("other.example.com:8443")));
+
+    // the mismatches are rejected if the listener requires it
+    assert_eq!(route("other.example.com", Some("lolcatho.st"), true), None);
+    assert_eq!(route("other.example.com", None, true), None);
+  }
 
   /*
   #[test]
@@ -1,5 +1,5 @@
 use sozu_command::buffer::Buffer;
-use buffer_queue::BufferQueue;
+use buffer_queue::{BufferQueue,OutputElement};
 use protocol::StickySession;
 use super::cookies::{RequestCookie, parse_request_cookies};
 use features::FEATURES;
@@ -96,6 +96,22 @@ impl Method {
       Method::Custom(String::from(unsafe { str::from_utf8_unchecked(s) }))
     }
   }
+
+  /// GET, HEAD and TRACE request bodies have no defined semantics (RFC 7231)
+  pub fn accepts_body(&self) -> bool {
+    match *self {
+      Method::Get | Method::Head | Method::Trace => false,
+      _                                          => true,
+    }
+  }
+
+  /// sending the request several times has the same effect as sending it once (RFC 7231)
+  pub fn is_idempotent(&self) -> bool {
+    match *self {
+      Method::Get | Method::Head | Method::Options | Method::Trace | Method::Put | Method::Delete => true,
+      _ => false,
+    }
+  }
 }
 
 impl fmt::Display for Method {
@@ -146,6 +162,34 @@ impl RRequestLine {
       None
     }
   }
+
+  /// the absolute form, like `GET http://example.com/index.html HTTP/1.1`, is
+  /// sent by the clients configured to use a forward proxy
+  pub fn is_absolute_form(&self) -> bool {
+    !self.uri.starts_with('/')
+  }
+
+  /// path and query of the request target, the frontends match on it for
+  /// the absolute form too
+  pub fn path(&self) -> &str {
+    if !self.is_absolute_form() {
+      return &self.uri;
+    }
+
+    let authority_start = self.uri.find("://").map(|i| i + 3).unwrap_or(0);
+    match self.uri[authority_start..].find('/') {
+      Some(i) => &self.uri[authority_start + i..],
+      None    => "/",
+    }
+  }
+}
+
+/// host of an absolute form request target, without the port, as the Host
+/// headers are compared to it
+fn absolute_form_host(uri: &str) -> Option<String> {
+  Url::parse(uri).ok()
+    .filter(|url| url.scheme() == "http" || url.scheme() == "https")
+    .and_then(|url| url.host_str().map(|s| s.to_string()))
 }
 
 #[derive(PartialEq,Debug)]
@@ -315,10 +359,18 @@ fn is_hostname_char(i: u8) -> bool {
   b"-.".contains(&i)
 }
 
+fn is_ipv6_char(i: u8) -> bool {
+  is_hex_digit(i) || b":.".contains(&i)
+}
+
+// the hostname of a bracketed IPv6 address keeps its brackets
 named!(pub hostname_and_port<(&[u8],Option<&[u8]>)>,
   terminated!(
     pair!(
-      take_while1_complete!(is_hostname_char),
+      alt_complete!(
+        recognize!(delimited!(tag!("["), take_while1_complete!(is_ipv6_char), tag!("]"))) |
+        take_while1_complete!(is_hostname_char)
+      ),
       opt!(complete!(preceded!(
         tag!(":"),
         digit_complete
@@ -328,6 +380,23 @@ named!(pub hostname_and_port<(&[u8],Option<&[u8]>)>,
   )
 );
 
+/// hostname of a Host header value used for the frontend lookup, without its port.
+/// Returns None if the value is not a hostname with an optional port
+pub fn hostname_without_port(host: &str) -> Option<&str> {
+  match hostname_and_port(host.as_bytes()) {
+    Ok((i, (hostname, _))) if i.is_empty() && !hostname.is_empty() => {
+      // the parser accepts an empty port, the remaining chars must be digits
+      let port = &host[hostname.len()..];
+      if port.is_empty() || (port.len() > 1 && port[1..].bytes().all(|c| c.is_ascii_digit())) {
+        Some(&host[..hostname.len()])
+      } else {
+        None
+      }
+    },
+    _ => None,
+  }
+}
+
 use std::str::from_utf8;
 use nom::{Err,Needed};
 
@@ -336,22 +405,47 @@ pub fn is_hex_digit(chr: u8) -> bool {
   (chr >= 0x41 && chr <= 0x46) ||
   (chr >= 0x61 && chr <= 0x66)
 }
+/// larger chunk sizes are refused, the parsing positions are added to them
+pub const MAX_CHUNK_SIZE: usize = ::std::u32::MAX as usize;
+
 pub fn chunk_size(input: &[u8]) -> IResult<&[u8], usize> {
   let (i, s) = try_parse!(input, map_res!(take_while!(is_hex_digit), from_utf8));
   if i.is_empty() {
     return Err(Err::Incomplete(Needed::Unknown));
   }
   match usize::from_str_radix(s, 16) {
-    Ok(sz) => Ok((i, sz)),
-    Err(_) => Err(Err::Error(error_position!(input, ::nom::ErrorKind::MapRes)))
+    Ok(sz) if sz <= MAX_CHUNK_SIZE => Ok((i, sz)),
+    _ => Err(Err::Error(error_position!(input, ::nom::ErrorKind::MapRes)))
   }
 }
 
-named!(pub chunk_header<usize>, terminated!(chunk_size, crlf));
+// chunk extensions (`;name=value`) are forwarded without being interpreted
+named!(pub chunk_extensions, preceded!(char!(';'), take_while!(is_header_value_char)));
+
+named!(pub chunk_header<usize>, terminated!(chunk_size, pair!(opt!(chunk_extensions), crlf)));
 named!(pub end_of_chunk_and_header<usize>, preceded!(crlf, chunk_header));
 
 named!(pub trailer_line, terminated!(take_while1!(is_header_value_char), crlf));
 
+// header fields that must not appear in a trailer section (RFC 7230 section 4.1.2):
+// message framing, routing, request modifiers, authentication, response control
+// data and payload processing headers
+static DISALLOWED_TRAILERS: &'static [&'static [u8]] = &[
+  b"transfer-encoding", b"content-length", b"host", b"connection", b"keep-alive",
+  b"upgrade", b"trailer", b"te", b"cache-control", b"expect", b"max-forwards",
+  b"pragma", b"range", b"authorization", b"proxy-authenticate", b"proxy-authorization",
+  b"www-authenticate", b"cookie", b"set-cookie", b"age", b"expires", b"date",
+  b"location", b"retry-after", b"vary", b"warning", b"content-encoding",
+  b"content-type", b"content-range",
+];
+
+pub fn is_allowed_trailer(name: &[u8]) -> bool {
+  // conditional headers (If-Match, If-Modified-Since, etc) are request modifiers
+  let is_conditional = name.len() > 3 && compare_no_case(&name[..3], b"if-");
+
+  !is_conditional && !DISALLOWED_TRAILERS.iter().any(|disallowed| compare_no_case(name, disallowed))
+}
+
 #[derive(PartialEq,Debug,Clone,Copy)]
 pub enum Chunk {
   Initial,
@@ -419,14 +513,19 @@ impl Chunk {
           Err(_) => (0, Chunk::Error)
         }
       },
-      // we parse a crlf then stop
+      // we parse the trailers if there are some, then a crlf then stop
       Chunk::CopyingLastHeader => {
         match crlf(buf) {
           Ok((i, _)) => {
             (buf.offset(i), Chunk::Ended)
           },
           Err(Err::Incomplete(_)) => (0, Chunk::CopyingLastHeader),
-          Err(_) => (0, Chunk::Error)
+          Err(_) => match message_header(buf) {
+            // trailer field, we stay in the same state until the final crlf
+            Ok((i, _)) => (buf.offset(i), Chunk::CopyingLastHeader),
+            Err(Err::Incomplete(_)) => (0, Chunk::CopyingLastHeader),
+            Err(_) => (0, Chunk::Error)
+          }
         }
       },
       _ => { (0, Chunk::Error) }
@@ -439,6 +538,21 @@ impl Chunk {
     let mut position      = 0;
     let length            = buf.len();
     loop {
+      // disallowed trailers are removed instead of being forwarded. If we already
+      // parsed some data, we return first, and they will be deleted on the next call
+      if current_state == Chunk::CopyingLastHeader {
+        let remaining = &buf[position..];
+        if let Ok((i, header)) = message_header(remaining) {
+          if !is_allowed_trailer(header.name) {
+            if position == 0 {
+              return (BufferMove::Delete(remaining.offset(i)), current_state);
+            } else {
+              break;
+            }
+          }
+        }
+      }
+
       let (mv, new_state) = current_state.parse_one(&buf[position..]);
       current_state = new_state;
       position += mv;
@@ -582,8 +696,9 @@ impl<'a> Header<'a> {
       }
     } else if compare_no_case(self.name, b"upgrade") {
       HeaderValue::Upgrade(self.value)
+    } else if compare_no_case(self.name, b"x-forwarded-for") {
+      HeaderValue::XForwardedFor(self.value)
     } else if compare_no_case(self.name, b"forwarded")   ||
-        compare_no_case(self.name, b"x-forwarded-for")   ||
         compare_no_case(self.name, b"x-forwarded-proto") ||
         compare_no_case(self.name, b"x-forwarded-port") {
       HeaderValue::Forwarded
@@ -591,7 +706,7 @@ impl<'a> Header<'a> {
       if compare_no_case(self.value, b"100-continue") {
         HeaderValue::ExpectContinue
       } else {
-        HeaderValue::Error
+        HeaderValue::UnsupportedExpect
       }
     } else if compare_no_case(self.name, b"cookie") {
       match parse_request_cookies(self.value) {
@@ -640,6 +755,11 @@ impl<'a> Header<'a> {
     } else {
       let mut b = (compare_no_case(&self.name, b"connection") && !compare_no_case(&self.value, b"upgrade")) ||
       compare_no_case(&self.name, b"sozu-id")           ||
+      // set by sozu from the frontend TLS connection, must not be spoofed by clients
+      compare_no_case(&self.name, b"x-forwarded-tls-version") ||
+      compare_no_case(&self.name, b"x-forwarded-tls-cipher")  ||
+      // the addresses are kept and sent again with the client's appended
+      compare_no_case(&self.name, b"x-forwarded-for")   ||
       {
         let mut res = false;
         if let Some(ref to_delete) = conn.to_delete {
@@ -656,7 +776,6 @@ impl<'a> Header<'a> {
 
       if !FEATURES.with(|features| features.borrow().get("forwarded-fix").map(|f| f.is_true()).unwrap_or(false)) {
         b |= compare_no_case(&self.name, b"forwarded")         ||
-             compare_no_case(&self.name, b"x-forwarded-for")   ||
              compare_no_case(&self.name, b"x-forwarded-proto") ||
              compare_no_case(&self.name, b"x-forwarded-port");
       }
@@ -665,6 +784,12 @@ impl<'a> Header<'a> {
     }
   }
 
+  /// the non standard Proxy-Connection header is sent by some clients
+  /// instead of Connection, it should not be forwarded to the backend
+  pub fn is_proxy_connection(&self) -> bool {
+    compare_no_case(&self.name, b"proxy-connection")
+  }
+
   pub fn must_mutate(&self) -> bool {
     compare_no_case(&self.name, b"cookie")
   }
@@ -764,10 +889,12 @@ pub enum HeaderValue<'a> {
   Cookie(Vec<RequestCookie<'a>>),
   Other(&'a[u8],&'a[u8]),
   Forwarded,
+  XForwardedFor(&'a[u8]),
   ExpectContinue,
+  /// Expect header with a value other than 100-continue
+  UnsupportedExpect,
   /*
   Forwarded(Vec<&'a[u8]>),
-  XForwardedFor(Vec<&'a[u8]>),
   XForwardedProto(ForwardedProtocol),
   XForwardedPort(u16),
   */
@@ -787,6 +914,8 @@ pub enum LengthInformation {
 pub enum Continue {
   None,
   Expects(usize),
+  /// the request has an expectation we cannot meet, it should be answered with a 417
+  Unsupported,
 }
 /*
 #[derive(Debug,Clone,PartialEq)]
@@ -806,6 +935,8 @@ pub struct Connection {
   pub to_delete:      Option<HashSet<Vec<u8>>>,
   pub continues:      Continue,
   pub sticky_session: Option<String>,
+  /// addresses from the X-Forwarded-For headers of the request
+  pub forwarded_for:  Option<String>,
 }
 
 impl Connection {
@@ -817,9 +948,18 @@ impl Connection {
       continues:      Continue::None,
       to_delete:      None,
       sticky_session: None,
+      forwarded_for:  None,
     }
   }
 
+  pub fn add_forwarded_for(&mut self, addresses: &[u8]) {
+    let addresses = String::from_utf8_lossy(addresses);
+    self.forwarded_for = Some(match self.forwarded_for.take() {
+      Some(previous) => format!("{}, {}", previous, addresses),
+      None           => addresses.into_owned(),
+    });
+  }
+
   pub fn keep_alive() -> Connection {
     Connection {
       keep_alive:     Some(true),
@@ -828,6 +968,7 @@ impl Connection {
       continues:      Continue::None,
       to_delete:      None,
       sticky_session: None,
+      forwarded_for:  None,
     }
   }
 
@@ -838,7 +979,8 @@ impl Connection {
       upgrade:        None,
       continues:      Continue::None,
       to_delete:      None,
-      sticky_session: None
+      sticky_session: None,
+      forwarded_for:  None,
     }
   }
 }
@@ -913,6 +1055,22 @@ impl RequestState {
     }
   }
 
+  /// true if the request announced a body (a positive Content-Length, or
+  /// chunked transfer encoding) while its method does not accept one
+  pub fn has_unexpected_body(&self) -> bool {
+    let (rl, has_body) = match *self {
+      RequestState::HasLength(ref rl, _, LengthInformation::Length(sz))           |
+      RequestState::HasHostAndLength(ref rl, _, _, LengthInformation::Length(sz)) |
+      RequestState::RequestWithBody(ref rl, _, _, sz) => (rl, sz > 0),
+      RequestState::HasLength(ref rl, _, LengthInformation::Chunked)              |
+      RequestState::HasHostAndLength(ref rl, _, _, LengthInformation::Chunked)    |
+      RequestState::RequestWithBodyChunks(ref rl, _, _, _) => (rl, true),
+      _ => return false,
+    };
+
+    has_body && !rl.method.accepts_body()
+  }
+
   pub fn get_host(&self) -> Option<&str> {
     match *self {
       RequestState::HasHost(_, _, ref host)             |
@@ -1162,6 +1320,16 @@ pub fn default_request_result<O>(state: RequestState, res: IResult<&[u8], O>) ->
 pub fn validate_request_header(mut state: RequestState, header: &Header, sticky_name: &str) -> RequestState {
   match header.value() {
     HeaderValue::Host(host) => {
+      // the host of an absolute form request target takes the precedence, the
+      // Host header is only accepted if it designates the same host
+      if state.get_request_line().map(|rl| rl.is_absolute_form()).unwrap_or(false) {
+        let same_host = match (state.get_host(), hostname_without_port(&host)) {
+          (Some(h), Some(hostname)) => h.eq_ignore_ascii_case(hostname),
+          _                         => false,
+        };
+        return if same_host { state } else { state.into_error() };
+      }
+
       match state {
         RequestState::HasRequestLine(rl, conn) => RequestState::HasHost(rl, conn, host),
         RequestState::HasLength(rl, conn, l)   => RequestState::HasHostAndLength(rl, conn, host, l),
@@ -1205,13 +1373,24 @@ pub fn validate_request_header(mut state: RequestState, header: &Header, sticky_
     },
     HeaderValue::ExpectContinue => {
       if state.get_mut_connection().map(|conn| {
-        conn.continues = Continue::Expects(0);
+        if conn.continues != Continue::Unsupported {
+          conn.continues = Continue::Expects(0);
+        }
       }).is_some() {
         state
       } else {
         state.into_error()
       }
-    }
+    },
+    HeaderValue::UnsupportedExpect => {
+      if state.get_mut_connection().map(|conn| {
+        conn.continues = Continue::Unsupported;
+      }).is_some() {
+        state
+      } else {
+        state.into_error()
+      }
+    },
 
     /*
     HeaderValue::Forwarded(_)  => RequestState::Error(ErrorState::InvalidHttp),
@@ -1222,6 +1401,11 @@ pub fn validate_request_header(mut state: RequestState, header: &Header, sticky_
     // FIXME: there should be an error for unsupported encoding
     HeaderValue::Encoding(_) => state.into_error(),
     HeaderValue::Forwarded   => state,
+    HeaderValue::XForwardedFor(addresses) => {
+      let mut st = state;
+      st.get_mut_connection().map(|conn| conn.add_forwarded_for(addresses));
+      st
+    },
     HeaderValue::Other(_,_)  => state,
     //FIXME: for now, we don't look at what is asked in upgrade since the backend is the one deciding
     HeaderValue::Upgrade(s)  => {
@@ -1251,7 +1435,8 @@ pub fn parse_header<'a>(buf: &'a mut Buffer, state: RequestState, sticky_name: &
   }
 }
 
-pub fn parse_request(state: RequestState, buf: &[u8], sticky_name: &str) -> (BufferMove, RequestState) {
+pub fn parse_request(state: RequestState, buf: &[u8], sticky_name: &str, preserve_proxy_connection: bool)
+  -> (BufferMove, RequestState) {
   match state {
     RequestState::Initial => {
       match request_line(buf) {
@@ -1259,9 +1444,11 @@ pub fn parse_request(state: RequestState, buf: &[u8], sticky_name: &str) -> (Buf
           if let Some(rl) = RRequestLine::from_request_line(r) {
 
             let conn = Connection::new();
-            //FIXME: what if it's not absolute path or complete URL, but an authority with CONNECT?
-            if rl.uri.len() > 0 && rl.uri.as_bytes()[0] != b'/' {
-              if let Some(host) = Url::parse(&rl.uri).ok().and_then(|u| u.host_str().map(|s| s.to_string())) {
+            if rl.method == Method::Connect {
+              // the proxy does not open tunnels to the hosts asked by the clients
+              (BufferMove::None, (RequestState::Initial).into_error())
+            } else if rl.is_absolute_form() {
+              if let Some(host) = absolute_form_host(&rl.uri) {
                 (BufferMove::Advance(buf.offset(i)), RequestState::HasHost(rl, conn, host))
               } else {
                 (BufferMove::None, (RequestState::Initial).into_error())
@@ -1285,7 +1472,8 @@ pub fn parse_request(state: RequestState, buf: &[u8], sticky_name: &str) -> (Buf
     RequestState::HasRequestLine(rl, conn) => {
       match message_header(buf) {
         Ok((i, header)) => {
-          let mv = if header.should_delete(&conn, sticky_name) {
+          let mv = if header.should_delete(&conn, sticky_name)
+            || (!preserve_proxy_connection && header.is_proxy_connection()) {
             BufferMove::Delete(buf.offset(i))
           } else if header.must_mutate() {
             BufferMove::Multiple(header.mutate_header(buf, buf.offset(i), sticky_name))
@@ -1300,7 +1488,8 @@ pub fn parse_request(state: RequestState, buf: &[u8], sticky_name: &str) -> (Buf
     RequestState::HasHost(rl, conn, h) => {
       match message_header(buf) {
         Ok((i, header)) => {
-          let mv = if header.should_delete(&conn, sticky_name) {
+          let mv = if header.should_delete(&conn, sticky_name)
+            || (!preserve_proxy_connection && header.is_proxy_connection()) {
             BufferMove::Delete(buf.offset(i))
           } else if header.must_mutate() {
             BufferMove::Multiple(header.mutate_header(buf, buf.offset(i), sticky_name))
@@ -1326,7 +1515,8 @@ pub fn parse_request(state: RequestState, buf: &[u8], sticky_name: &str) -> (Buf
     RequestState::HasLength(rl, conn, l) => {
       match message_header(buf) {
         Ok((i, header)) => {
-          let mv = if header.should_delete(&conn, sticky_name) {
+          let mv = if header.should_delete(&conn, sticky_name)
+            || (!preserve_proxy_connection && header.is_proxy_connection()) {
             BufferMove::Delete(buf.offset(i))
           } else if header.must_mutate() {
             BufferMove::Multiple(header.mutate_header(buf, buf.offset(i), sticky_name))
@@ -1341,7 +1531,8 @@ pub fn parse_request(state: RequestState, buf: &[u8], sticky_name: &str) -> (Buf
     RequestState::HasHostAndLength(rl, conn, h, l) => {
       match message_header(buf) {
         Ok((i, header)) => {
-          let mv = if header.should_delete(&conn, sticky_name) {
+          let mv = if header.should_delete(&conn, sticky_name)
+            || (!preserve_proxy_connection && header.is_proxy_connection()) {
             BufferMove::Delete(buf.offset(i))
           } else if header.must_mutate() {
             BufferMove::Multiple(header.mutate_header(buf, buf.offset(i), sticky_name))
@@ -1370,8 +1561,13 @@ pub fn parse_request(state: RequestState, buf: &[u8], sticky_name: &str) -> (Buf
     },
     RequestState::RequestWithBodyChunks(rl, conn, h, ch) => {
       let (advance, chunk_state) = ch.parse(buf);
-      //FIXME: should handle Chunk::Error here
-      (advance, RequestState::RequestWithBodyChunks(rl, conn, h, chunk_state))
+      let state = RequestState::RequestWithBodyChunks(rl, conn, h, chunk_state);
+      // a malformed chunk cannot be skipped, the connection must be closed
+      if chunk_state.is_error() {
+        (advance, state.into_error())
+      } else {
+        (advance, state)
+      }
     },
     _ => {
       error!("PARSER\tunimplemented state: {:?}", state);
@@ -1443,9 +1639,13 @@ pub fn validate_response_header(mut state: ResponseState, header: &Header, is_he
       trace!("parsed a protocol: {:?}", proto);
       trace!("state is {:?}", state);
       match state {
-        ResponseState::HasStatusLine(sl, mut conn) => {
+        // only a 101 answer switches protocols, a 426 Upgrade Required
+        // or any other status is a normal response
+        ResponseState::HasStatusLine(sl, mut conn) => if sl.status == 101 {
           conn.upgrade = Some(proto.clone());
           ResponseState::HasUpgrade(sl, conn, proto)
+        } else {
+          ResponseState::HasStatusLine(sl, conn)
         },
         s                                       => s.into_error(),
       }
@@ -1461,8 +1661,9 @@ pub fn validate_response_header(mut state: ResponseState, header: &Header, is_he
     HeaderValue::XForwardedPort(_) => ResponseState::Error(ErrorState::InvalidHttp),
     */
     HeaderValue::Forwarded   => state,
+    HeaderValue::XForwardedFor(_) => state,
     HeaderValue::Other(_,_)  => state,
-    HeaderValue::ExpectContinue => {
+    HeaderValue::ExpectContinue | HeaderValue::UnsupportedExpect => {
       // we should not get that one from the server
       state.into_error()
     },
@@ -1580,7 +1781,12 @@ pub fn parse_response(state: ResponseState, buf: &[u8], is_head: bool, sticky_na
     },
     ResponseState::ResponseWithBodyChunks(rl, conn, ch) => {
       let (advance, chunk_state) = ch.parse(buf);
-      (advance, ResponseState::ResponseWithBodyChunks(rl, conn, chunk_state))
+      let state = ResponseState::ResponseWithBodyChunks(rl, conn, chunk_state);
+      if chunk_state.is_error() {
+        (advance, state.into_error())
+      } else {
+        (advance, state)
+      }
     },
     ResponseState::ResponseWithBodyCloseDelimited(rl, conn, b) => {
       (BufferMove::Advance(buf.len()), ResponseState::ResponseWithBodyCloseDelimited(rl, conn, b))
@@ -1592,11 +1798,76 @@ pub fn parse_response(state: ResponseState, buf: &[u8], is_head: bool, sticky_na
   }
 }
 
+/// complete lines received for a request header block, the request line included
+#[derive(Debug,Clone,Copy,Default,PartialEq)]
+pub struct HeaderBlockSize {
+  /// bytes of the complete lines
+  pub size:     usize,
+  pub lines:    usize,
+  /// bytes of the request line without the line ending, once it is complete
+  pub request_line: usize,
+  /// the empty line ending the header block was received
+  pub complete: bool,
+}
+
+impl HeaderBlockSize {
+  /// number of headers, without the request line
+  pub fn header_count(&self) -> usize {
+    self.lines.saturating_sub(1)
+  }
+
+  /// adds the complete lines at the start of `buf`, which comes right after
+  /// the lines already measured
+  pub fn measure(mut self, buf: &[u8]) -> HeaderBlockSize {
+    let mut position = 0;
+    while !self.complete {
+      let end = match buf[position..].iter().position(|c| *c == b'\n') {
+        Some(end) => position + end + 1,
+        None      => break,
+      };
+      let line = &buf[position..end];
+      position = end;
+      self.size += line.len();
+      if self.lines > 0 && (line == &b"\r\n"[..] || line == &b"\n"[..]) {
+        self.complete = true;
+      } else {
+        if self.lines == 0 {
+          self.request_line = line_length(line);
+        }
+        self.lines += 1;
+      }
+    }
+    self
+  }
+}
+
+/// size of a line without its line ending. A partial line ending with the
+/// carriage return of its line ending is measured without it
+pub fn line_length(line: &[u8]) -> usize {
+  if line.ends_with(b"\r\n") {
+    line.len() - 2
+  } else if line.ends_with(b"\n") || line.ends_with(b"\r") {
+    line.len() - 1
+  } else {
+    line.len()
+  }
+}
+
+/// the header block ended without a Host header, and the request line did not
+/// give the host either. `unparsed` is the data left after the parsing error
+pub fn is_missing_host(state: &RequestState, unparsed: &[u8]) -> bool {
+  match *state {
+    RequestState::Error(Some(_), _, None, _, _) => unparsed.starts_with(b"\r\n"),
+    _ => false,
+  }
+}
+
 pub fn parse_request_until_stop(mut current_state: RequestState, mut header_end: Option<usize>,
-  buf: &mut BufferQueue, added_req_header: &str, sticky_name: &str)
+  buf: &mut BufferQueue, added_req_header: &str, sticky_name: &str, preserve_proxy_connection: bool)
   -> (RequestState, Option<usize>) {
   loop {
-    let (mv, new_state) = parse_request(current_state, buf.unparsed_data(), sticky_name);
+    let (mv, new_state) = parse_request(current_state, buf.unparsed_data(), sticky_name,
+      preserve_proxy_connection);
     //println!("PARSER\t{}\tinput:\n{}\nmv: {:?}, new state: {:?}\n", request_id, &buf.unparsed_data().to_hex(16), mv, new_state);
     //trace!("PARSER\t{}\tinput:\n{}\nmv: {:?}, new state: {:?}\n", request_id, &buf.unparsed_data().to_hex(16), mv, new_state);
     //trace!("PARSER\t{}\tmv: {:?}, new state: {:?}\n", request_id, mv, new_state);
@@ -1621,12 +1892,12 @@ pub fn parse_request_until_stop(mut current_state: RequestState, mut header_end:
               buf.insert_output(Vec::from(added_req_header.as_bytes()));
 
               // If we got "Expects: 100-continue", the body will be sent later
-              if conn.continues == Continue::None {
-                buf.slice_output(sz+content_length);
-                buf.consume_parsed_data(content_length);
-              } else {
+              if let Continue::Expects(_) = conn.continues {
                 buf.slice_output(sz);
                 conn.continues = Continue::Expects(content_length);
+              } else {
+                buf.slice_output(sz+content_length);
+                buf.consume_parsed_data(content_length);
               }
             },
             _ => {
@@ -1830,11 +2101,143 @@ fn add_sticky_session_to_response(buf: &mut BufferQueue,
   }
 }
 
+/// deletes the already parsed headers named in `names`, that were not sent yet.
+/// It is used when the headers to delete are only known after some of them were
+/// parsed, like the ones configured on the application chosen from the host.
+/// It stops at the headers added by sozu, which mark the end of the headers
+pub fn delete_headers(buf: &mut BufferQueue, names: &[String]) {
+  if names.is_empty() {
+    return;
+  }
+
+  let mut to_delete = Vec::new();
+  {
+    let data = buf.buffer.data();
+    let mut offset = 0;
+    for (index, element) in buf.output_queue.iter().enumerate() {
+      match *element {
+        OutputElement::Slice(sz) => {
+          if offset + sz > data.len() {
+            break;
+          }
+
+          if let Ok((remaining, header)) = message_header(&data[offset..offset+sz]) {
+            if remaining.is_empty() && names.iter().any(|name| compare_no_case(header.name, name.as_bytes())) {
+              to_delete.push((index, sz));
+            }
+          }
+          offset += sz;
+        },
+        OutputElement::Delete(sz) => offset += sz,
+        OutputElement::Insert(_) | OutputElement::Splice(_) => break,
+      }
+    }
+  }
+
+  for (index, sz) in to_delete {
+    buf.output_queue[index] = OutputElement::Delete(sz);
+  }
+}
+
+/// returns the index in the output queue, the offset in the buffer and the size of
+/// the first header with this name in the parsed headers of the buffer
+fn find_header(buf: &BufferQueue, name: &[u8]) -> Option<(usize, usize, usize)> {
+  let data = buf.buffer.data();
+  let mut offset = 0;
+  for (index, element) in buf.output_queue.iter().enumerate() {
+    match *element {
+      OutputElement::Slice(sz) => {
+        if offset + sz > data.len() {
+          break;
+        }
+
+        if let Ok((remaining, header)) = message_header(&data[offset..offset+sz]) {
+          if remaining.is_empty() && compare_no_case(header.name, name) {
+            return Some((index, offset, sz));
+          }
+        }
+        offset += sz;
+      },
+      OutputElement::Delete(sz) => offset += sz,
+      OutputElement::Insert(_) | OutputElement::Splice(_) => break,
+    }
+  }
+
+  None
+}
+
+/// value of the first header with this name in the parsed headers of the buffer
+pub fn header_value(buf: &BufferQueue, name: &[u8]) -> Option<String> {
+  find_header(buf, name).and_then(|(_, offset, sz)| {
+    message_header(&buf.buffer.data()[offset..offset+sz]).ok()
+      .and_then(|(_, header)| str::from_utf8(header.value).ok().map(String::from))
+  })
+}
+
+/// value of the cookie with this name in the first Cookie header of the buffer
+pub fn request_cookie(buf: &BufferQueue, name: &str) -> Option<String> {
+  let cookies = header_value(buf, b"Cookie")?;
+  parse_request_cookies(cookies.as_bytes())?.iter()
+    .find(|cookie| cookie.name == name.as_bytes())
+    .and_then(|cookie| str::from_utf8(cookie.value).ok().map(String::from))
+}
+
+/// calls `f` on the value of the first header with this name in the parsed headers of the
+/// buffer. If it returns a new value, the header is deleted and the new value is returned,
+/// to be inserted by the caller
+pub fn replace_header<F>(buf: &mut BufferQueue, name: &[u8], f: F) -> Option<String>
+  where F: FnOnce(&[u8]) -> Option<String> {
+  let (index, offset, sz) = find_header(buf, name)?;
+  let value = message_header(&buf.buffer.data()[offset..offset+sz]).ok()
+    .and_then(|(_, header)| f(header.value))?;
+
+  buf.output_queue[index] = OutputElement::Delete(sz);
+  Some(value)
+}
+
+/// like `replace_header`, for all the headers with this name in the parsed headers
+/// of the buffer. Returns the new values, in the order of the headers
+pub fn replace_headers<F>(buf: &mut BufferQueue, name: &[u8], mut f: F) -> Vec<String>
+  where F: FnMut(&[u8]) -> Option<String> {
+  let mut replaced = Vec::new();
+  let mut values = Vec::new();
+  {
+    let data = buf.buffer.data();
+    let mut offset = 0;
+    for (index, element) in buf.output_queue.iter().enumerate() {
+      match *element {
+        OutputElement::Slice(sz) => {
+          if offset + sz > data.len() {
+            break;
+          }
+
+          if let Ok((remaining, header)) = message_header(&data[offset..offset+sz]) {
+            if remaining.is_empty() && compare_no_case(header.name, name) {
+              if let Some(value) = f(header.value) {
+                replaced.push((index, sz));
+                values.push(value);
+              }
+            }
+          }
+          offset += sz;
+        },
+        OutputElement::Delete(sz) => offset += sz,
+        OutputElement::Insert(_) | OutputElement::Splice(_) => break,
+      }
+    }
+  }
+
+  for (index, sz) in replaced {
+    buf.output_queue[index] = OutputElement::Delete(sz);
+  }
+  values
+}
+
 #[cfg(test)]
 mod tests {
   use super::*;
   use nom::{Err,ErrorKind,HexDisplay};
-  use buffer_queue::{OutputElement,buf_with_capacity};
+  use buffer_queue::buf_with_capacity;
   use std::io::Write;
 
   /*
@@ -1934,7 +2337,7 @@ mod tests {
       println!("buffer input: {:?}", buf.input_queue);
 
       //let result = parse_request(initial, input);
-      let result = parse_request_until_stop(initial, None, &mut buf, "", "SOZUBALANCEID");
+      let result = parse_request_until_stop(initial, None, &mut buf, "", "SOZUBALANCEID", false);
       println!("result: {:?}", result);
       println!("input length: {}", input.len());
       println!("buffer input: {:?}", buf.input_queue);
@@ -1973,7 +2376,7 @@ mod tests {
       println!("buffer input: {:?}", buf.input_queue);
 
       //let result = parse_request(initial, input);
-      let result = parse_request_until_stop(initial, None, &mut buf, "", "SOZUBALANCEID");
+      let result = parse_request_until_stop(initial, None, &mut buf, "", "SOZUBALANCEID", false);
       println!("result: {:?}", result);
       println!("input length: {}", input.len());
       println!("buffer input: {:?}", buf.input_queue);
@@ -1993,6 +2396,54 @@ mod tests {
       );
   }
 
+  #[test]
+  fn parse_state_host_in_url_matching_host_test() {
+      let input =
+          b"GET http://example.com:8888/index.html HTTP/1.1\r\n\
+            Host: Example.com:8888\r\n\
+            Accept: */*\r\n\
+            \r\n";
+      let initial = RequestState::Initial;
+      let (pool, mut buf) = buf_with_capacity(2048);
+      buf.write(&input[..]).unwrap();
+
+      let result = parse_request_until_stop(initial, None, &mut buf, "", "SOZUBALANCEID", false);
+      println!("result: {:?}", result);
+      assert_eq!(
+        result.0,
+        RequestState::Request(
+          RRequestLine { method: Method::Get, uri: String::from("http://example.com:8888/index.html"), version: Version::V11 },
+          Connection::new(),
+          String::from("example.com")
+        )
+      );
+  }
+
+  #[test]
+  fn absolute_form_path_test() {
+      let request_line = |uri: &str| RRequestLine { method: Method::Get, uri: String::from(uri), version: Version::V11 };
+      assert!(!request_line("/index.html").is_absolute_form());
+      assert_eq!(request_line("/index.html?a=b").path(), "/index.html?a=b");
+      assert!(request_line("http://example.com:8888/index.html").is_absolute_form());
+      assert_eq!(request_line("http://example.com:8888/api/index.html?a=b").path(), "/api/index.html?a=b");
+      assert_eq!(request_line("https://example.com").path(), "/");
+  }
+
+  #[test]
+  fn parse_connect_request_test() {
+      let input =
+          b"CONNECT example.com:443 HTTP/1.1\r\n\
+            Host: example.com:443\r\n\
+            \r\n";
+      let initial = RequestState::Initial;
+      let (pool, mut buf) = buf_with_capacity(2048);
+      buf.write(&input[..]).unwrap();
+
+      let result = parse_request_until_stop(initial, None, &mut buf, "", "SOZUBALANCEID", false);
+      println!("result: {:?}", result);
+      assert_eq!(result.0, RequestState::Error(None, None, None, None, None));
+  }
+
   #[test]
   fn parse_state_content_length_test() {
       let input =
@@ -2008,7 +2459,7 @@ mod tests {
       println!("buffer input: {:?}", buf.input_queue);
 
       //let result = parse_request(initial, input);
-      let result = parse_request_until_stop(initial, None, &mut buf, "", "SOZUBALANCEID");
+      let result = parse_request_until_stop(initial, None, &mut buf, "", "SOZUBALANCEID", false);
       println!("result: {:?}", result);
       println!("input length: {}", input.len());
       println!("buffer input: {:?}", buf.input_queue);
@@ -2060,7 +2511,7 @@ mod tests {
       println!("unparsed data after consume(26):\n{}", buf.unparsed_data().to_hex(16));
       println!("buffer output: {:?}", buf.output_queue);
 
-      let result = parse_request_until_stop(initial, None, &mut buf, "", "SOZUBALANCEID");
+      let result = parse_request_until_stop(initial, None, &mut buf, "", "SOZUBALANCEID", false);
       println!("unparsed data after parsing:\n{}", buf.unparsed_data().to_hex(16));
       println!("result: {:?}", result);
       println!("input length: {}", input.len());
@@ -2099,7 +2550,7 @@ mod tests {
       buf.write(&input[..]).unwrap();
 
       //let result = parse_request(initial, input);
-      let result = parse_request_until_stop(initial, None, &mut buf, "", "SOZUBALANCEID");
+      let result = parse_request_until_stop(initial, None, &mut buf, "", "SOZUBALANCEID", false);
       println!("result: {:?}", result);
       assert_eq!(buf.start_parsing_position, 116);
       assert_eq!(
@@ -2131,7 +2582,7 @@ mod tests {
       let (pool, mut buf) = buf_with_capacity(2048);
       buf.write(&input[..]).unwrap();
 
-      let result = parse_request_until_stop(initial, None, &mut buf, "", "SOZUBALANCEID");
+      let result = parse_request_until_stop(initial, None, &mut buf, "", "SOZUBALANCEID", false);
       println!("result: {:?}", result);
       assert_eq!(buf.start_parsing_position, 128);
       assert_eq!(
@@ -2163,7 +2614,7 @@ mod tests {
       buf.write(&input[..]).unwrap();
 
       //let result = parse_request(initial, input);
-      let result = parse_request_until_stop(initial, None, &mut buf, "", "SOZUBALANCEID");
+      let result = parse_request_until_stop(initial, None, &mut buf, "", "SOZUBALANCEID", false);
       println!("result: {:?}", result);
       assert_eq!(buf.start_parsing_position, 136);
       assert_eq!(
@@ -2180,6 +2631,39 @@ mod tests {
       );
   }
 
+  #[test]
+  fn parse_request_with_unsupported_expect() {
+      let input =
+          b"POST /upload HTTP/1.1\r\n\
+            Host: localhost:8888\r\n\
+            Expect: 200-ok\r\n\
+            Content-Length: 2\r\n\
+            \r\n\
+            ok";
+      let initial = RequestState::Initial;
+      let (pool, mut buf) = buf_with_capacity(2048);
+      buf.write(&input[..]).unwrap();
+
+      let result = parse_request_until_stop(initial, None, &mut buf, "", "SOZUBALANCEID", false);
+      println!("result: {:?}", result);
+      let mut conn = Connection::new();
+      conn.continues = Continue::Unsupported;
+      // the body is not held back like with 100-continue
+      assert_eq!(buf.start_parsing_position, input.len());
+      assert_eq!(
+        result,
+        (
+          RequestState::RequestWithBody(
+            RRequestLine { method: Method::Post, uri: String::from("/upload"), version: Version::V11 },
+            conn,
+            String::from("localhost:8888"),
+            2
+          ),
+          Some(input.len() - 2)
+        )
+      );
+  }
+
   #[test]
   fn parse_request_without_length() {
       setup_test_logger!();
@@ -2193,7 +2677,7 @@ mod tests {
       buf.write(&input[..]).unwrap();
 
       //let result = parse_request(initial, input);
-      let result = parse_request_until_stop(initial, None, &mut buf, "", "SOZUBALANCEID");
+      let result = parse_request_until_stop(initial, None, &mut buf, "", "SOZUBALANCEID", false);
       println!("result: {:?}", result);
       println!("input length: {}", input.len());
       println!("buffer output: {:?}", buf.output_queue);
@@ -2226,7 +2710,7 @@ mod tests {
       buf.write(&input[..]).unwrap();
 
       //let result = parse_request(initial, input);
-      let result = parse_request_until_stop(initial, None, &mut buf, "", "SOZUBALANCEID");
+      let result = parse_request_until_stop(initial, None, &mut buf, "", "SOZUBALANCEID", false);
       println!("result: {:?}", result);
       assert_eq!(buf.start_parsing_position, 40);
       assert_eq!(
@@ -2256,7 +2740,7 @@ mod tests {
       buf.write(&input[..]).unwrap();
 
       //let result = parse_request(initial, input);
-      let result = parse_request_until_stop(initial, None, &mut buf, "", "SOZUBALANCEID");
+      let result = parse_request_until_stop(initial, None, &mut buf, "", "SOZUBALANCEID", false);
       println!("result: {:?}", result);
       println!("input length: {}", input.len());
       println!("buffer output: {:?}", buf.output_queue);
@@ -2290,7 +2774,7 @@ mod tests {
       buf.write(&input[..]).unwrap();
 
       //let result = parse_request(initial, input);
-      let result = parse_request_until_stop(initial, None, &mut buf, "", "SOZUBALANCEID");
+      let result = parse_request_until_stop(initial, None, &mut buf, "", "SOZUBALANCEID", false);
       println!("end buf:\n{}", buf.buffer.data().to_hex(16));
       println!("result: {:?}", result);
       assert_eq!(buf.output_queue, vec!(
@@ -2324,7 +2808,7 @@ mod tests {
       buf.write(&input[..]).unwrap();
 
       //let result = parse_request(initial, input);
-      let result = parse_request_until_stop(initial, None, &mut buf, "", "SOZUBALANCEID");
+      let result = parse_request_until_stop(initial, None, &mut buf, "", "SOZUBALANCEID", false);
       println!("end buf:\n{}", buf.buffer.data().to_hex(16));
       println!("result: {:?}", result);
       assert_eq!(buf.output_queue, vec!(
@@ -2360,7 +2844,7 @@ mod tests {
       buf.write(&input[..]).unwrap();
 
       let new_header = b"Sozu-Id: 123456789\r\n";
-      let result = parse_request_until_stop(initial, None, &mut buf, "Sozu-Id: 123456789\r\n", "SOZUBALANCEID");
+      let result = parse_request_until_stop(initial, None, &mut buf, "Sozu-Id: 123456789\r\n", "SOZUBALANCEID", false);
       println!("result: {:?}", result);
       println!("input length: {}", input.len());
       println!("buffer output: {:?}", buf.output_queue);
@@ -2384,6 +2868,41 @@ mod tests {
       );
   }
 
+  #[test]
+  fn delete_parsed_headers() {
+      setup_test_logger!();
+      let (_pool, mut buf) = buf_with_capacity(2048);
+      buf.write(&b"GET / HTTP/1.1\r\nHost: localhost\r\nX-Internal: a\r\nAccept: */*\r\n"[..]).unwrap();
+      let names = vec![String::from("x-internal"), String::from("X-Missing")];
+
+      let (state, header_end) = parse_request_until_stop(RequestState::Initial, None, &mut buf,
+        "Sozu-Id: 123456789\r\n", "SOZUBALANCEID", false);
+      delete_headers(&mut buf, &names);
+      assert_eq!(buf.output_queue, vec!(
+        OutputElement::Slice(16), OutputElement::Slice(17), OutputElement::Delete(15), OutputElement::Slice(13)));
+
+      // the headers parsed later are deleted too, and deleting again does not change the others
+      buf.write(&b"X-Internal: b\r\n\r\n"[..]).unwrap();
+      let (state, header_end) = parse_request_until_stop(state, header_end, &mut buf,
+        "Sozu-Id: 123456789\r\n", "SOZUBALANCEID", false);
+      delete_headers(&mut buf, &names);
+      assert!(header_end.is_some());
+      assert!(state.is_proxying());
+      assert_eq!(buf.output_queue, vec!(
+        OutputElement::Slice(16), OutputElement::Slice(17), OutputElement::Delete(15), OutputElement::Slice(13),
+        OutputElement::Delete(15), OutputElement::Insert(Vec::from(&b"Sozu-Id: 123456789\r\n"[..])),
+        OutputElement::Slice(2)));
+
+      let (_pool, mut buf) = buf_with_capacity(2048);
+      buf.write(&b"HTTP/1.1 200 OK\r\nServer: x\r\nContent-Length: 0\r\n\r\n"[..]).unwrap();
+      let _ = parse_response_until_stop(ResponseState::Initial, None, &mut buf, false,
+        "Sozu-Id: 123456789\r\n", "SOZUBALANCEID", None);
+      delete_headers(&mut buf, &[String::from("Server")]);
+      assert_eq!(buf.output_queue, vec!(
+        OutputElement::Slice(17), OutputElement::Delete(11), OutputElement::Slice(19),
+        OutputElement::Insert(Vec::from(&b"Sozu-Id: 123456789\r\n"[..])), OutputElement::Slice(2)));
+  }
+
   #[test]
   fn parse_request_delete_forwarded_headers() {
       setup_test_logger!();
@@ -2400,7 +2919,7 @@ mod tests {
       buf.write(&input[..]).unwrap();
 
       let new_header = b"Sozu-Id: 123456789\r\n";
-      let result = parse_request_until_stop(initial, None, &mut buf, "Sozu-Id: 123456789\r\n", "SOZUBALANCEID");
+      let result = parse_request_until_stop(initial, None, &mut buf, "Sozu-Id: 123456789\r\n", "SOZUBALANCEID", false);
       println!("result: {:?}", result);
       println!("input length: {}", input.len());
       println!("buffer output: {:?}", buf.output_queue);
@@ -2418,12 +2937,15 @@ mod tests {
       OutputElement::Slice(2)));
       println!("buf:\n{}", buf.buffer.data().to_hex(16));
       assert_eq!(buf.start_parsing_position, 179);
+      // the X-Forwarded-For addresses are kept to be sent again
+      let mut connection = Connection::new();
+      connection.forwarded_for = Some(String::from("127.0.0.1"));
       assert_eq!(
         result,
         (
           RequestState::Request(
             RRequestLine { method: Method::Get, uri: String::from("/index.html"), version: Version::V11 },
-            Connection::new(),
+            connection,
             String::from("localhost:8888"),
           ),
           Some(179)
@@ -2431,6 +2953,67 @@ mod tests {
       );
   }
 
+  #[test]
+  fn parse_request_proxy_connection() {
+      setup_test_logger!();
+      let input =
+          b"GET /index.html HTTP/1.1\r\n\
+            Host: localhost:8888\r\n\
+            Proxy-Connection: keep-alive\r\n\
+            \r\n";
+      let new_header = b"Sozu-Id: 123456789\r\n";
+
+      // removed by default
+      let (pool, mut buf) = buf_with_capacity(2048);
+      buf.write(&input[..]).unwrap();
+      let result = parse_request_until_stop(RequestState::Initial, None, &mut buf, "Sozu-Id: 123456789\r\n", "SOZUBALANCEID", false);
+      println!("result: {:?}", result);
+      assert_eq!(buf.output_queue, vec!(
+        OutputElement::Slice(26), OutputElement::Slice(22),
+        // Proxy-Connection
+        OutputElement::Delete(30),
+        OutputElement::Insert(Vec::from(&new_header[..])),
+      OutputElement::Slice(2)));
+      assert_eq!(result.1, Some(80));
+
+      // forwarded if the listener is configured to preserve it
+      let (pool, mut buf) = buf_with_capacity(2048);
+      buf.write(&input[..]).unwrap();
+      let result = parse_request_until_stop(RequestState::Initial, None, &mut buf, "Sozu-Id: 123456789\r\n", "SOZUBALANCEID", true);
+      println!("result: {:?}", result);
+      assert_eq!(buf.output_queue, vec!(
+        OutputElement::Slice(26), OutputElement::Slice(22),
+        OutputElement::Slice(30),
+        OutputElement::Insert(Vec::from(&new_header[..])),
+      OutputElement::Slice(2)));
+      assert_eq!(result.1, Some(80));
+  }
+
+  #[test]
+  fn parse_request_tls_info_headers() {
+      setup_test_logger!();
+      let input =
+          b"GET /index.html HTTP/1.1\r\n\
+            Host: localhost:8888\r\n\
+            X-Forwarded-TLS-Version: SSLv3\r\n\
+            x-forwarded-tls-cipher: NULL\r\n\
+            \r\n";
+      let new_header = b"Sozu-Id: 123456789\r\n";
+
+      let (pool, mut buf) = buf_with_capacity(2048);
+      buf.write(&input[..]).unwrap();
+      let result = parse_request_until_stop(RequestState::Initial, None, &mut buf, "Sozu-Id: 123456789\r\n", "SOZUBALANCEID", false);
+      println!("result: {:?}", result);
+      assert_eq!(buf.output_queue, vec!(
+        OutputElement::Slice(26), OutputElement::Slice(22),
+        // the client supplied TLS information is removed
+        OutputElement::Delete(32),
+        OutputElement::Delete(30),
+        OutputElement::Insert(Vec::from(&new_header[..])),
+      OutputElement::Slice(2)));
+      assert_eq!(result.1, Some(112));
+  }
+
   #[test]
   fn parse_chunk() {
     let input =
@@ -2484,6 +3067,52 @@ mod tests {
     );
   }
 
+  #[test]
+  fn parse_chunk_with_trailers() {
+    let input =
+      b"4\r\n\
+      Wiki\r\n\
+      0\r\n\
+      X-Checksum: 1234\r\n\
+      Content-Length: 4\r\n\
+      Grpc-Status: 0\r\n\
+      \r\n";
+
+    let initial = Chunk::Initial;
+
+    let res = initial.parse(&input[..]);
+    println!("result: {:?}", res);
+    assert_eq!(
+      res,
+      (BufferMove::Advance(30), Chunk::CopyingLastHeader)
+    );
+
+    // disallowed trailers are removed
+    let res2 = res.1.parse(&input[30..]);
+    println!("result: {:?}", res2);
+    assert_eq!(
+      res2,
+      (BufferMove::Delete(19), Chunk::CopyingLastHeader)
+    );
+
+    let res3 = res2.1.parse(&input[49..]);
+    println!("result: {:?}", res3);
+    assert_eq!(
+      res3,
+      (BufferMove::Advance(18), Chunk::Ended)
+    );
+  }
+
+  #[test]
+  fn allowed_trailers() {
+    assert!(is_allowed_trailer(b"X-Checksum"));
+    assert!(is_allowed_trailer(b"grpc-message"));
+    assert!(!is_allowed_trailer(b"Content-Length"));
+    assert!(!is_allowed_trailer(b"transfer-encoding"));
+    assert!(!is_allowed_trailer(b"Trailer"));
+    assert!(!is_allowed_trailer(b"If-None-Match"));
+  }
+
   #[test]
   fn parse_requests_and_chunks_test() {
       let input =
@@ -2506,7 +3135,7 @@ mod tests {
       buf.write(&input[..]).unwrap();
 
       //let result = parse_request(initial, input);
-      let result = parse_request_until_stop(initial, None, &mut buf, "", "SOZUBALANCEID");
+      let result = parse_request_until_stop(initial, None, &mut buf, "", "SOZUBALANCEID", false);
       println!("result: {:?}", result);
       assert_eq!(buf.start_parsing_position, 160);
       assert_eq!(
@@ -2545,7 +3174,7 @@ mod tests {
       buf.write(&input[..125]).unwrap();
       println!("parsing\n{}", buf.buffer.data().to_hex(16));
 
-      let result = parse_request_until_stop(initial, None, &mut buf, "", "SOZUBALANCEID");
+      let result = parse_request_until_stop(initial, None, &mut buf, "", "SOZUBALANCEID", false);
       println!("result({}): {:?}", line!(), result);
       assert_eq!(buf.start_parsing_position, 124);
       assert_eq!(
@@ -2565,7 +3194,7 @@ mod tests {
       buf.write(&input[125..140]).unwrap();
       println!("parsing\n{}", buf.buffer.data().to_hex(16));
 
-      let result = parse_request_until_stop(result.0, result.1, &mut buf, "", "SOZUBALANCEID");
+      let result = parse_request_until_stop(result.0, result.1, &mut buf, "", "SOZUBALANCEID", false);
       println!("result({}): {:?}", line!(), result);
       assert_eq!(buf.start_parsing_position, 153);
       assert_eq!(
@@ -2583,7 +3212,7 @@ mod tests {
 
       buf.write(&input[153..]).unwrap();
       println!("parsing\n{}", buf.buffer.data().to_hex(16));
-      let result = parse_request_until_stop(result.0, result.1, &mut buf, "", "SOZUBALANCEID");
+      let result = parse_request_until_stop(result.0, result.1, &mut buf, "", "SOZUBALANCEID", false);
       println!("result({}): {:?}", line!(), result);
       assert_eq!(buf.start_parsing_position, 160);
       assert_eq!(
@@ -2600,6 +3229,44 @@ mod tests {
       );
   }
 
+  #[test]
+  fn parse_response_and_chunks_with_trailers_test() {
+      let input =
+          b"HTTP/1.1 200 OK\r\n\
+            Transfer-Encoding: chunked\r\n\
+            Trailer: X-Checksum, Content-Length\r\n\
+            \r\n\
+            4\r\n\
+            Wiki\r\n\
+            0\r\n\
+            X-Checksum: 1234\r\n\
+            Content-Length: 4\r\n\
+            \r\n";
+      let initial = ResponseState::Initial;
+      let (pool, mut buf) = buf_with_capacity(2048);
+      buf.write(&input[..]).unwrap();
+
+      let result = parse_response_until_stop(initial, None, &mut buf, false, "", "SOZUBALANCEID", None);
+      println!("result: {:?}", result);
+      println!("buffer output: {:?}", buf.output_queue);
+      assert_eq!(buf.output_queue, vec!(
+        OutputElement::Slice(17), OutputElement::Slice(28), OutputElement::Slice(37),
+        OutputElement::Insert(vec!()), OutputElement::Slice(2),
+        OutputElement::Slice(30), OutputElement::Delete(19), OutputElement::Slice(2)));
+      assert_eq!(buf.start_parsing_position, input.len());
+      assert_eq!(
+        result,
+        (
+          ResponseState::ResponseWithBodyChunks(
+            RStatusLine { version: Version::V11, status: 200, reason: String::from("OK") },
+            Connection::new(),
+            Chunk::Ended
+          ),
+          Some(84)
+        )
+      );
+  }
+
   #[test]
   fn parse_response_and_chunks_partial_test() {
       let input =
@@ -2795,6 +3462,129 @@ mod tests {
       );
   }
 
+  #[test]
+  fn parse_chunk_extensions() {
+    assert_eq!(chunk_header(&b"4;name=value\r\n"[..]), Ok((&b""[..], 4)));
+    assert_eq!(chunk_header(&b"a;name\r\n"[..]), Ok((&b""[..], 10)));
+    match chunk_header(&b"4;name=val"[..]) {
+      Err(Err::Incomplete(_)) => {},
+      res => panic!("the chunk header should be incomplete: {:?}", res),
+    }
+
+    let input = b"4;name=value\r\nWiki\r\n0;last\r\n\r\n";
+    assert_eq!(Chunk::Initial.parse(&input[..]), (BufferMove::Advance(input.len()), Chunk::Ended));
+  }
+
+  /// copies data in the buffer like a socket read
+  fn receive(buf: &mut BufferQueue, data: &[u8]) {
+    buf.buffer.space()[..data.len()].copy_from_slice(data);
+    buf.buffer.fill(data.len());
+    buf.sliced_input(data.len());
+  }
+
+  #[test]
+  fn parse_request_chunks_with_trailers_across_reads_test() {
+      let input =
+          b"POST /upload HTTP/1.1\r\n\
+            Host: localhost:8888\r\n\
+            Transfer-Encoding: chunked\r\n\
+            Trailer: X-Checksum\r\n\
+            \r\n\
+            4;name=value\r\n\
+            Wiki\r\n\
+            5\r\n\
+            pedia\r\n\
+            0\r\n\
+            X-Checksum: 1234\r\n\
+            Content-Length: 9\r\n\
+            \r\n";
+      let (_pool, mut buf) = buf_with_capacity(2048);
+      let mut result = (RequestState::Initial, None);
+
+      // the reads end in the headers, in a chunk header, in the middle of the
+      // chunk data, between the last chunk and the trailers, and in a trailer
+      for range in [(0, 93), (93, 102), (102, 112), (112, 121), (121, 129), (129, 140), (140, input.len())].iter() {
+        receive(&mut buf, &input[range.0..range.1]);
+        result = parse_request_until_stop(result.0, result.1, &mut buf, "", "SOZUBALANCEID", false);
+        println!("result({}): {:?}", range.1, result);
+        assert!(!result.0.is_front_error(), "parsing error after {} bytes", range.1);
+      }
+
+      assert_eq!(buf.start_parsing_position, input.len());
+      assert_eq!(
+        result,
+        (
+          RequestState::RequestWithBodyChunks(
+            RRequestLine { method: Method::Post, uri: String::from("/upload"), version: Version::V11 },
+            Connection::new(),
+            String::from("localhost:8888"),
+            Chunk::Ended
+          ),
+          Some(96)
+        )
+      );
+
+      // the chunks and the allowed trailer are forwarded, the Content-Length trailer is removed
+      let deleted: usize = buf.output_queue.iter().map(|element| match *element {
+        OutputElement::Delete(sz) => sz,
+        _                         => 0,
+      }).sum();
+      assert_eq!(deleted, 19);
+  }
+
+  #[test]
+  fn parse_malformed_chunk_size_test() {
+      let input =
+          b"POST /upload HTTP/1.1\r\n\
+            Host: localhost:8888\r\n\
+            Transfer-Encoding: chunked\r\n\
+            \r\n\
+            4\r\n\
+            Wiki\r\n\
+            zz\r\n\
+            pedia\r\n\
+            0\r\n\
+            \r\n";
+      let (_pool, mut buf) = buf_with_capacity(2048);
+      receive(&mut buf, &input[..]);
+
+      let result = parse_request_until_stop(RequestState::Initial, None, &mut buf, "", "SOZUBALANCEID", false);
+      println!("result: {:?}", result);
+      assert_eq!(
+        result.0,
+        RequestState::Error(
+          Some(RRequestLine { method: Method::Post, uri: String::from("/upload"), version: Version::V11 }),
+          Some(Connection::new()),
+          Some(String::from("localhost:8888")),
+          None,
+          Some(Chunk::Error)
+        )
+      );
+
+      // a size that does not fit in the parsing positions
+      let input =
+          b"HTTP/1.1 200 OK\r\n\
+            Transfer-Encoding: chunked\r\n\
+            \r\n\
+            10000000000\r\n\
+            Wiki\r\n";
+      let (_pool, mut buf) = buf_with_capacity(2048);
+      receive(&mut buf, &input[..]);
+
+      let result = parse_response_until_stop(ResponseState::Initial, None, &mut buf, false, "", "SOZUBALANCEID", None);
+      println!("result: {:?}", result);
+      assert_eq!(
+        result.0,
+        ResponseState::Error(
+          Some(RStatusLine { version: Version::V11, status: 200, reason: String::from("OK") }),
+          Some(Connection::new()),
+          None,
+          None,
+          Some(Chunk::Error)
+        )
+      );
+  }
+
   #[test]
   fn parse_response_302() {
     let input =
@@ -2910,6 +3700,7 @@ mod tests {
               continues:   Continue::None,
               to_delete:   None,
               sticky_session: None,
+              forwarded_for:  None,
             },
           ),
           Some(66)
@@ -2917,6 +3708,56 @@ mod tests {
       );
   }
 
+  #[test]
+  fn parse_response_101_upgrade() {
+      let input =
+          b"HTTP/1.1 101 Switching Protocols\r\n\
+            Upgrade: websocket\r\n\
+            Connection: Upgrade\r\n\
+            \r\n";
+      let initial = ResponseState::Initial;
+      let is_head = false;
+      let (pool, mut buf) = buf_with_capacity(2048);
+      buf.write(&input[..]).unwrap();
+
+      let result = parse_response_until_stop(initial, None, &mut buf, is_head, "", "SOZUBALANCEID", None);
+      println!("result: {:?}", result);
+      match result.0 {
+        ResponseState::ResponseUpgrade(sl, conn, protocol) => {
+          assert_eq!(sl.status, 101);
+          assert!(conn.has_upgrade);
+          assert_eq!(&protocol, "websocket");
+        },
+        s => panic!("expected an upgrade, got {:?}", s),
+      }
+  }
+
+  #[test]
+  fn parse_response_426_upgrade_required() {
+      let input =
+          b"HTTP/1.1 426 Upgrade Required\r\n\
+            Upgrade: websocket\r\n\
+            Connection: Upgrade\r\n\
+            Content-Length: 0\r\n\
+            \r\n";
+      let initial = ResponseState::Initial;
+      let is_head = false;
+      let (pool, mut buf) = buf_with_capacity(2048);
+      buf.write(&input[..]).unwrap();
+
+      // the session must keep parsing HTTP instead of switching to a pipe
+      let result = parse_response_until_stop(initial, None, &mut buf, is_head, "", "SOZUBALANCEID", None);
+      println!("result: {:?}", result);
+      match result.0 {
+        ResponseState::ResponseWithBody(sl, conn, length) => {
+          assert_eq!(sl.status, 426);
+          assert_eq!(conn.upgrade, None);
+          assert_eq!(length, 0);
+        },
+        s => panic!("expected a normal response, got {:?}", s),
+      }
+  }
+
   #[test]
   fn hostname_parsing_test() {
     assert_eq!(
@@ -2933,6 +3774,41 @@ mod tests {
       hostname_and_port(&b"example.com:8080"[..]),
       Ok((&b""[..], (&b"example.com"[..], Some(&b"8080"[..]))))
     );
+
+    assert_eq!(
+      hostname_and_port(&b"[2001:db8::1]:8080"[..]),
+      Ok((&b""[..], (&b"[2001:db8::1]"[..], Some(&b"8080"[..]))))
+    );
+  }
+
+  #[test]
+  fn hostname_without_port_test() {
+    assert_eq!(hostname_without_port("example.com"), Some("example.com"));
+    assert_eq!(hostname_without_port("example.com:8080"), Some("example.com"));
+    assert_eq!(hostname_without_port("[::1]"), Some("[::1]"));
+    assert_eq!(hostname_without_port("[::ffff:192.0.2.1]:443"), Some("[::ffff:192.0.2.1]"));
+    assert_eq!(hostname_without_port("example.com:"), None);
+    assert_eq!(hostname_without_port("[::1]8080"), None);
+    assert_eq!(hostname_without_port("[example.com]"), None);
+    assert_eq!(hostname_without_port(""), None);
+  }
+
+  #[test]
+  fn missing_host_test() {
+    let (_pool, mut buf) = buf_with_capacity(2048);
+    buf.buffer.write(&b"GET / HTTP/1.1\r\nAccept: */*\r\n\r\n"[..]).unwrap();
+    buf.sliced_input(31);
+    let (state, _) = parse_request_until_stop(RequestState::Initial, None, &mut buf, "", "SOZUBALANCEID", false);
+    assert!(state.is_front_error());
+    assert!(is_missing_host(&state, buf.unparsed_data()));
+
+    // an invalid header is not a missing host
+    let (_pool, mut buf) = buf_with_capacity(2048);
+    buf.buffer.write(&b"GET / HTTP/1.1\r\nAccept */*\r\n\r\n"[..]).unwrap();
+    buf.sliced_input(30);
+    let (state, _) = parse_request_until_stop(RequestState::Initial, None, &mut buf, "", "SOZUBALANCEID", false);
+    assert!(state.is_front_error());
+    assert!(!is_missing_host(&state, buf.unparsed_data()));
   }
 
 
@@ -3004,7 +3880,7 @@ mod tests {
       println!("buffer input: {:?}", buf.input_queue);
 
       //let result = parse_request(initial, input);
-      let result = parse_request_until_stop(initial, None, &mut buf, "", "SOZUBALANCEID");
+      let result = parse_request_until_stop(initial, None, &mut buf, "", "SOZUBALANCEID", false);
       println!("result: {:?}", result);
       println!("input length: {}", input.len());
       println!("buffer input: {:?}", buf.input_queue);
@@ -3025,7 +3901,8 @@ mod tests {
               upgrade:     Some("WebSocket".to_string()),
               continues:   Continue::None,
               to_delete:   None,
-              sticky_session: None
+              sticky_session: None,
+              forwarded_for:  None,
             },
             String::from("localhost:8888"),
           ),
@@ -3208,6 +4085,63 @@ mod tests {
     assert_eq!(moves3, expected3);
     assert_eq!(moves4, expected4);
   }
+
+  #[test]
+  fn unexpected_bodies() {
+    let body = |method: &[u8], headers: &[u8]| {
+      let mut input = Vec::new();
+      input.extend_from_slice(method);
+      input.extend_from_slice(b" / HTTP/1.1\r\nHost: localhost:8888\r\n");
+      input.extend_from_slice(headers);
+      input.extend_from_slice(b"\r\n");
+
+      let (_pool, mut buf) = buf_with_capacity(2048);
+      buf.write(&input[..]).unwrap();
+      let (state, _) = parse_request_until_stop(RequestState::Initial, None, &mut buf, "", "SOZUBALANCEID", false);
+      state.has_unexpected_body()
+    };
+
+    assert!(body(b"GET", b"Content-Length: 2\r\n"));
+    assert!(body(b"HEAD", b"Transfer-Encoding: chunked\r\n"));
+    assert!(body(b"TRACE", b"Content-Length: 10\r\n"));
+    assert!(!body(b"GET", b""));
+    assert!(!body(b"GET", b"Content-Length: 0\r\n"));
+    assert!(!body(b"POST", b"Content-Length: 2\r\n"));
+    assert!(!body(b"PUT", b"Transfer-Encoding: chunked\r\n"));
+
+    // the length is known before the end of the headers
+    let state = RequestState::HasLength(
+      RRequestLine { method: Method::Get, uri: String::from("/"), version: Version::V11 },
+      Connection::new(),
+      LengthInformation::Length(2)
+    );
+    assert!(state.has_unexpected_body());
+  }
+
+  #[test]
+  fn header_block_size() {
+    let request = b"GET / HTTP/1.1\r\nHost: localhost:8888\r\nAccept: */*\r\n\r\nbody";
+    let block = HeaderBlockSize::default().measure(&request[..]);
+    assert_eq!(block, HeaderBlockSize { size: request.len() - 4, lines: 3, request_line: 14, complete: true });
+    assert_eq!(block.header_count(), 2);
+
+    // the lines are measured as they are received, the partial ones are left out
+    let block = HeaderBlockSize::default().measure(&request[..20]);
+    assert_eq!(block, HeaderBlockSize { size: 16, lines: 1, request_line: 14, complete: false });
+    let block = block.measure(&request[16..40]);
+    assert_eq!(block, HeaderBlockSize { size: 38, lines: 2, request_line: 14, complete: false });
+    let block = block.measure(&request[38..]);
+    assert_eq!(block, HeaderBlockSize { size: request.len() - 4, lines: 3, request_line: 14, complete: true });
+    assert_eq!(block.measure(&b"GET / HTTP/1.1\r\n\r\n"[..]), block);
+
+    // the request line is measured without its line ending
+    let block = HeaderBlockSize::default().measure(&b"GET / HTTP/1.1\n"[..]);
+    assert_eq!(block.request_line, 14);
+    let block = HeaderBlockSize::default().measure(&b"GET / HTTP/1.1\r"[..]);
+    assert_eq!(block.lines, 0);
+    assert_eq!(line_length(&b"GET / HTTP/1.1\r"[..]), 14);
+    assert_eq!(line_length(&b"GET / HT"[..]), 8);
+  }
 }
 
 #[cfg(all(feature = "unstable", test))]
@@ -3231,7 +4165,7 @@ mod bench {
     let mut buf = BufferQueue::with_capacity(data.len());
 
     buf.write(&data[..]).unwrap();
-    let res1 = parse_request_until_stop(RequestState::Initial, None, &mut buf, "", "");
+    let res1 = parse_request_until_stop(RequestState::Initial, None, &mut buf, "", "", false);
     println!("res: {:?}", res1);
 
     b.bytes = data.len() as u64;
@@ -3243,7 +4177,7 @@ mod bench {
       buf.sliced_input(data.len());
 
       let initial = RequestState::Initial;
-      let res2 = parse_request_until_stop(initial, None, &mut buf, "", "");
+      let res2 = parse_request_until_stop(initial, None, &mut buf, "", "", false);
       assert_eq!(res1, res2);
     });
   }
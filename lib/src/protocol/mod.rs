@@ -52,6 +52,7 @@ macro_rules! empty (
 
 pub mod http;
 pub mod pipe;
+pub mod h2;
 #[cfg(feature = "use-openssl")]
 pub mod openssl;
 pub mod rustls;
@@ -60,6 +61,7 @@ pub mod proxy_protocol;
 #[cfg(feature = "use-openssl")]
 pub use self::openssl::TlsHandshake;
 pub use self::pipe::Pipe;
+pub use self::h2::Http2;
 pub use self::http::{Http,StickySession};
 pub use self::proxy_protocol::send::SendProxyProtocol;
 
@@ -1,9 +1,10 @@
-use std::net::SocketAddr;
+use std::net::{SocketAddr,Shutdown};
 use mio::*;
 use mio::tcp::TcpStream;
 use mio::unix::UnixReady;
 use uuid::adapter::Hyphenated;
 use sozu_command::buffer::Buffer;
+use sozu_command::logging::AccessRecord;
 use {SessionResult,Readiness,SessionMetrics};
 use socket::{SocketHandler,SocketResult,TransportProtocol};
 use pool::Checkout;
@@ -23,6 +24,38 @@ enum ConnectionStatus {
   Closed,
 }
 
+impl ConnectionStatus {
+  fn can_read(&self) -> bool {
+    match *self {
+      ConnectionStatus::Normal | ConnectionStatus::ReadOpen => true,
+      _ => false,
+    }
+  }
+
+  fn can_write(&self) -> bool {
+    match *self {
+      ConnectionStatus::Normal | ConnectionStatus::WriteOpen => true,
+      _ => false,
+    }
+  }
+
+  fn close_read(self) -> ConnectionStatus {
+    match self {
+      ConnectionStatus::Normal   => ConnectionStatus::WriteOpen,
+      ConnectionStatus::ReadOpen => ConnectionStatus::Closed,
+      s => s,
+    }
+  }
+
+  fn close_write(self) -> ConnectionStatus {
+    match self {
+      ConnectionStatus::Normal    => ConnectionStatus::ReadOpen,
+      ConnectionStatus::WriteOpen => ConnectionStatus::Closed,
+      s => s,
+    }
+  }
+}
+
 pub struct Pipe<Front:SocketHandler> {
   pub frontend:       Front,
   backend:            Option<TcpStream>,
@@ -38,9 +71,15 @@ pub struct Pipe<Front:SocketHandler> {
   pub back_readiness: Readiness,
   pub log_ctx:        String,
   session_address:    Option<SocketAddr>,
+  /// address of the backend, kept to log it after the backend closed the connection
+  backend_address:    Option<SocketAddr>,
   protocol:           Protocol,
   frontend_status:    ConnectionStatus,
   backend_status:     ConnectionStatus,
+  /// the access log of the session was written
+  logged:             bool,
+  /// backends the TCP session tried to connect to
+  backend_attempts:   u8,
 }
 
 impl<Front:SocketHandler> Pipe<Front> {
@@ -81,9 +120,12 @@ impl<Front:SocketHandler> Pipe<Front> {
       },
       log_ctx,
       session_address,
+      backend_address:    None,
       protocol,
       frontend_status,
       backend_status,
+      logged:             false,
+      backend_attempts:   0,
     };
 
     trace!("created pipe");
@@ -127,6 +169,9 @@ impl<Front:SocketHandler> Pipe<Front> {
   }
 
   pub fn set_backend_id(&mut self, backend_id: Option<String>) {
+    if backend_id.is_some() {
+      self.backend_attempts = self.backend_attempts.saturating_add(1);
+    }
     self.backend_id = backend_id;
     self.reset_log_context();
   }
@@ -155,8 +200,12 @@ impl<Front:SocketHandler> Pipe<Front> {
     self.session_address.or_else(|| self.frontend.socket_ref().peer_addr().ok())
   }
 
+  pub fn set_backend_address(&mut self, address: Option<SocketAddr>) {
+    self.backend_address = address;
+  }
+
   pub fn get_backend_address(&self) -> Option<SocketAddr> {
-    self.backend.as_ref().and_then(|backend| backend.peer_addr().ok())
+    self.backend_address.or_else(|| self.backend.as_ref().and_then(|backend| backend.peer_addr().ok()))
   }
 
   fn protocol_string(&self) -> &'static str {
@@ -178,7 +227,37 @@ impl<Front:SocketHandler> Pipe<Front> {
     }
   }
 
-  pub fn log_request_success(&self, metrics: &SessionMetrics) {
+  fn access_record<'a>(&'a self, metrics: &SessionMetrics, error: Option<&'a str>) -> AccessRecord<'a> {
+    AccessRecord {
+      request_id:    self.request_id.to_string(),
+      app_id:        self.app_id.as_ref().map(|s| s.as_str()),
+      backend_id:    self.backend_id.as_ref().map(|s| s.as_str()),
+      client:        self.get_session_address(),
+      backend:       self.get_backend_address(),
+      protocol:      self.protocol_string(),
+      method:        None,
+      host:          None,
+      path:          None,
+      status:        None,
+      response_time: metrics.response_time().num_milliseconds(),
+      latency:       metrics.latency().num_milliseconds(),
+      service_time:  metrics.service_time().num_milliseconds(),
+      bytes_in:      metrics.bin,
+      bytes_out:     metrics.bout,
+      error,
+      retries:       self.backend_attempts.saturating_sub(1),
+      client_certificate: None,
+    }
+  }
+
+  /// writes the access log of the session, once: a session closed after
+  /// logging does not log again
+  pub fn log_request_success(&mut self, metrics: &SessionMetrics) {
+    if self.logged {
+      return;
+    }
+    self.logged = true;
+
     let session = match self.get_session_address() {
       None => String::from("-"),
       Some(SocketAddr::V4(addr)) => format!("{}", addr),
@@ -206,14 +285,20 @@ impl<Front:SocketHandler> Pipe<Front> {
 
     let proto = self.protocol_string();
 
-    info_access!("{}{} -> {}\t{} {} {} {}\t{} {}",
+    let record = self.access_record(metrics, None);
+    info_access!(@record &record; "{}{} -> {}\t{} {} {} {}\t{} {}",
       self.log_ctx, session, backend,
       LogDuration(response_time), LogDuration(service_time),
       metrics.bin, metrics.bout,
       proto, self.websocket_context.as_ref().map(|s| s.as_str()).unwrap_or("-"));
   }
 
-  pub fn log_request_error(&self, metrics: &SessionMetrics, message: &str) {
+  pub fn log_request_error(&mut self, metrics: &SessionMetrics, message: &str) {
+    if self.logged {
+      return;
+    }
+    self.logged = true;
+
     let session = match self.get_session_address() {
       None => String::from("-"),
       Some(SocketAddr::V4(addr)) => format!("{}", addr),
@@ -241,78 +326,79 @@ impl<Front:SocketHandler> Pipe<Front> {
 
     let proto = self.protocol_string();
 
-    error_access!("{}{} -> {}\t{} {} {} {}\t{} {} | {}",
+    let record = self.access_record(metrics, Some(message));
+    error_access!(@record &record; "{}{} -> {}\t{} {} {} {}\t{} {} | {}",
       self.log_ctx, session, backend,
       LogDuration(response_time), LogDuration(service_time),
       metrics.bin, metrics.bout,
       proto, self.websocket_context.as_ref().map(|s| s.as_str()).unwrap_or("-"), message);
   }
 
+  /// the session stays open as long as data can still flow in one direction:
+  /// a half closed connection keeps forwarding the other side
   pub fn check_connections(&self) -> bool {
+    let front_to_back = self.backend_status.can_write()
+      && (self.frontend_status.can_read() || self.front_buf.available_data() > 0);
+    let back_to_front = self.frontend_status.can_write()
+      && (self.backend_status.can_read() || self.back_buf.available_data() > 0);
 
-    let res = match (self.frontend_status, self.backend_status) {
-
-      //(ConnectionStatus::Normal, ConnectionStatus::Normal) => true,
-      //(ConnectionStatus::Normal, ConnectionStatus::ReadOpen) => true,
-      (ConnectionStatus::Normal, ConnectionStatus::WriteOpen) => {
-        // technically we should keep it open, but we'll assume that if the front
-        // is not readable and there is no in flight data front -> back or back -> front,
-        // we'll close the session, otherwise it interacts badly with HTTP connections
-        // with Connection: close header and no Content-length
-        self.front_readiness.event.is_readable() || self.front_buf.available_data() > 0 || self.back_buf.available_data() > 0
-      },
-      (ConnectionStatus::Normal, ConnectionStatus::Closed) => self.back_buf.available_data() > 0,
-
-      (ConnectionStatus::WriteOpen, ConnectionStatus::Normal) => {
-        // technically we should keep it open, but we'll assume that if the back
-        // is not readable and there is no in flight data back -> front or front -> back, we'll close the session
-        self.back_readiness.event.is_readable() || self.back_buf.available_data() > 0 || self.front_buf.available_data() > 0
-      },
-      //(ConnectionStatus::WriteOpen, ConnectionStatus::ReadOpen) => true,
-      (ConnectionStatus::WriteOpen, ConnectionStatus::WriteOpen) => self.front_buf.available_data() > 0 || self.back_buf.available_data() > 0,
-      (ConnectionStatus::WriteOpen, ConnectionStatus::Closed) => self.back_buf.available_data() > 0,
+    //info!("check_connections: front = {:?}, back = {:?} => {}", self.frontend_status, self.backend_status, front_to_back || back_to_front);
+    front_to_back || back_to_front
+  }
 
-      //(ConnectionStatus::ReadOpen, ConnectionStatus::Normal) => true,
-      (ConnectionStatus::ReadOpen, ConnectionStatus::ReadOpen) => false,
-      //(ConnectionStatus::ReadOpen, ConnectionStatus::WriteOpen) => true,
-      (ConnectionStatus::ReadOpen, ConnectionStatus::Closed) => false,
+  /// once one side sent its end of stream and all of its data was forwarded,
+  /// shuts down the write side of the other socket so the peer sees it too
+  fn propagate_half_close(&mut self) {
+    if !self.frontend_status.can_read() && self.front_buf.available_data() == 0
+      && self.backend_status.can_write() {
+      if let Some(ref backend) = self.backend {
+        debug!("{}\tfrontend closed, shutting down the backend's write side", self.log_ctx);
+        let _ = backend.shutdown(Shutdown::Write);
+      }
+      self.backend_status = self.backend_status.close_write();
+    }
 
-      (ConnectionStatus::Closed, ConnectionStatus::Normal) => self.front_buf.available_data() > 0,
-      (ConnectionStatus::Closed, ConnectionStatus::ReadOpen) => false,
-      (ConnectionStatus::Closed, ConnectionStatus::WriteOpen) => self.front_buf.available_data() > 0,
-      (ConnectionStatus::Closed, ConnectionStatus::Closed) => false,
+    if self.backend.is_some() && !self.backend_status.can_read() && self.back_buf.available_data() == 0
+      && self.frontend_status.can_write() {
+      if self.protocol == Protocol::HTTPS {
+        // TLS sessions cannot be half closed, everything was sent so stop writing
+        self.frontend_status = ConnectionStatus::Closed;
+      } else {
+        debug!("{}\tbackend closed, shutting down the frontend's write side", self.log_ctx);
+        let _ = self.frontend.socket_ref().shutdown(Shutdown::Write);
+        self.frontend_status = self.frontend_status.close_write();
+      }
+    }
+  }
 
-      _ => true,
-    };
+  fn half_close(&mut self, metrics: &mut SessionMetrics) -> SessionResult {
+    self.propagate_half_close();
 
-    //info!("check_connections: front = {:?}, back = {:?} => {}", self.frontend_status, self.backend_status, res);
-    res
+    if self.check_connections() {
+      SessionResult::Continue
+    } else {
+      metrics.service_stop();
+      self.front_readiness.reset();
+      self.back_readiness.reset();
+      self.log_request_success(metrics);
+      SessionResult::CloseSession
+    }
   }
 
-  pub fn front_hup(&mut self, metrics: &mut SessionMetrics) -> SessionResult {
-    self.log_request_success(metrics);
-    self.frontend_status = ConnectionStatus::Closed;
-    SessionResult::CloseSession
+  // the client might only have closed its write side, so the rest of its
+  // data is read until the end of stream, then propagated to the backend
+  pub fn front_hup(&mut self) -> SessionResult {
+    self.front_readiness.interest.remove(UnixReady::hup());
+    self.front_readiness.interest.insert(Ready::readable());
+    self.front_readiness.event.insert(Ready::readable());
+    SessionResult::Continue
   }
 
-  pub fn back_hup(&mut self, metrics: &mut SessionMetrics) -> SessionResult {
-    self.backend_status = ConnectionStatus::Closed;
-    if self.back_buf.available_data() == 0 {
-      if self.back_readiness.event.is_readable() {
-        self.back_readiness().interest.insert(Ready::readable());
-        error!("Pipe::back_hup: backend connection closed but the kernel still holds some data. readiness: {:?} -> {:?}", self.front_readiness, self.back_readiness);
-        SessionResult::Continue
-      } else {
-        self.log_request_success(metrics);
-        SessionResult::CloseSession
-      }
-    } else {
-      self.front_readiness().interest.insert(Ready::writable());
-      if self.back_readiness.event.is_readable() {
-        self.back_readiness.interest.insert(Ready::readable());
-      }
-      SessionResult::Continue
-    }
+  pub fn back_hup(&mut self) -> SessionResult {
+    self.back_readiness.interest.remove(UnixReady::hup());
+    self.back_readiness.interest.insert(Ready::readable());
+    self.back_readiness.event.insert(Ready::readable());
+    SessionResult::Continue
   }
 
   // Read content from the session
@@ -342,11 +428,19 @@ impl<Front:SocketHandler> Pipe<Front> {
       self.front_readiness.event.remove(Ready::readable());
 
       if res == SocketResult::Continue {
-        self.frontend_status = match self.frontend_status {
-          ConnectionStatus::Normal => ConnectionStatus::WriteOpen,
-          ConnectionStatus::ReadOpen => ConnectionStatus::Closed,
-          s => s,
-        };
+        self.frontend_status = self.frontend_status.close_read();
+      }
+    }
+
+    if res == SocketResult::Closed {
+      // the client will not send anything else, but can still receive data
+      self.front_readiness.event.remove(Ready::readable());
+      self.front_readiness.interest.remove(Ready::readable());
+      self.frontend_status = self.frontend_status.close_read();
+
+      let order = self.half_close(metrics);
+      if order != SessionResult::Continue {
+        return order;
       }
     }
 
@@ -367,13 +461,7 @@ impl<Front:SocketHandler> Pipe<Front> {
         self.log_request_error(metrics, "front socket read error");
         return SessionResult::CloseSession;
       },
-      SocketResult::Closed => {
-        metrics.service_stop();
-        self.front_readiness.reset();
-        self.back_readiness.reset();
-        self.log_request_success(metrics);
-        return SessionResult::CloseSession;
-      },
+      SocketResult::Closed => {},
       SocketResult::WouldBlock => {
         self.front_readiness.event.remove(Ready::readable());
       },
@@ -390,7 +478,7 @@ impl<Front:SocketHandler> Pipe<Front> {
     if self.back_buf.available_data() == 0 {
       self.back_readiness.interest.insert(Ready::readable());
       self.front_readiness.interest.remove(Ready::writable());
-      return SessionResult::Continue;
+      return self.half_close(metrics);
     }
 
     let mut sz = 0usize;
@@ -402,7 +490,7 @@ impl<Front:SocketHandler> Pipe<Front> {
         metrics.bout += sz;
         self.back_readiness.interest.insert(Ready::readable());
         self.front_readiness.interest.remove(Ready::writable());
-        return SessionResult::Continue;
+        return self.half_close(metrics);
       }
       let (current_sz, current_res) = self.frontend.socket_write(self.back_buf.data());
       res = current_res;
@@ -410,11 +498,7 @@ impl<Front:SocketHandler> Pipe<Front> {
       sz += current_sz;
 
       if current_sz == 0 && res == SocketResult::Continue {
-        self.frontend_status = match self.frontend_status {
-          ConnectionStatus::Normal => ConnectionStatus::ReadOpen,
-          ConnectionStatus::WriteOpen => ConnectionStatus::Closed,
-          s => s,
-        };
+        self.frontend_status = self.frontend_status.close_write();
       }
 
       if !self.check_connections() {
@@ -470,7 +554,7 @@ impl<Front:SocketHandler> Pipe<Front> {
     if self.front_buf.available_data() == 0 {
       self.front_readiness.interest.insert(Ready::readable());
       self.back_readiness.interest.remove(Ready::writable());
-      return SessionResult::Continue;
+      return self.half_close(metrics);
     }
 
     let tokens = self.tokens();
@@ -483,9 +567,7 @@ impl<Front:SocketHandler> Pipe<Front> {
       while socket_res == SocketResult::Continue {
         // no more data in buffer, stop here
         if self.front_buf.available_data() == 0 {
-          self.front_readiness.interest.insert(Ready::readable());
-          self.back_readiness.interest.remove(Ready::writable());
-          return SessionResult::Continue;
+          break;
         }
 
         let (current_sz, current_res) = backend.socket_write(self.front_buf.data());
@@ -495,19 +577,19 @@ impl<Front:SocketHandler> Pipe<Front> {
 
 
         if current_sz == 0 && current_res == SocketResult::Continue {
-          self.backend_status = match self.backend_status {
-            ConnectionStatus::Normal => ConnectionStatus::ReadOpen,
-            ConnectionStatus::WriteOpen => ConnectionStatus::Closed,
-            s => s,
-          };
-
+          self.backend_status = self.backend_status.close_write();
         }
-
       }
     }
 
     metrics.backend_bout += sz;
 
+    if self.front_buf.available_data() == 0 && socket_res == SocketResult::Continue {
+      self.front_readiness.interest.insert(Ready::readable());
+      self.back_readiness.interest.remove(Ready::writable());
+      return self.half_close(metrics);
+    }
+
     if !self.check_connections() {
       metrics.service_stop();
       self.front_readiness.reset();
@@ -570,21 +652,10 @@ impl<Front:SocketHandler> Pipe<Front> {
         metrics.backend_bin += sz;
       }
 
-      if sz == 0 && r == SocketResult::Closed {
-        self.backend_status = match self.backend_status {
-          ConnectionStatus::Normal => ConnectionStatus::WriteOpen,
-          ConnectionStatus::ReadOpen => ConnectionStatus::Closed,
-          s => s,
-        };
-
-        if !self.check_connections() {
-          metrics.service_stop();
-          self.front_readiness.reset();
-          self.back_readiness.reset();
-          self.log_request_success(metrics);
-          return SessionResult::CloseSession;
-        }
-
+      if r == SocketResult::Closed {
+        // the backend will not send anything else, but can still receive data
+        self.back_readiness.interest.remove(Ready::readable());
+        self.backend_status = self.backend_status.close_read();
       }
 
       match r {
@@ -597,13 +668,7 @@ impl<Front:SocketHandler> Pipe<Front> {
           return SessionResult::CloseSession;
         },
         SocketResult::Closed => {
-          if !self.check_connections() {
-            metrics.service_stop();
-            self.front_readiness.reset();
-            self.back_readiness.reset();
-            self.log_request_success(metrics);
-            return SessionResult::CloseSession;
-          }
+          return self.half_close(metrics);
         },
         SocketResult::WouldBlock => {
           self.back_readiness.event.remove(Ready::readable());
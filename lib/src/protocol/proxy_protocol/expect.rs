@@ -1,4 +1,5 @@
 use std::io::Read;
+use std::net::SocketAddr;
 
 use mio::*;
 use mio::tcp::TcpStream;
@@ -13,12 +14,17 @@ use SessionMetrics;
 use protocol::pipe::Pipe;
 use pool::Checkout;
 use sozu_command::buffer::Buffer;
-use super::parser::parse_v2_header;
-use super::header::ProxyAddr;
+use super::parser::{parse_v1_header, parse_v2_header, V1_MIN_LEN, V1_MAX_LEN};
+use super::header::{ProxyAddr, ProtocolSupportedV1};
 use Protocol;
 
+/// the header is read in steps, to never consume the data following it
 #[derive(Clone,Copy)]
 pub enum HeaderLen {
+  /// length of the shortest header, enough to tell the versions apart
+  Start,
+  /// version 1 header, read until the CRLF
+  V1,
   V4,
   V6,
   Unix
@@ -43,7 +49,7 @@ impl <Front:SocketHandler + Read>ExpectProxyProtocol<Front> {
       request_id,
       buf: [0; 232],
       index: 0,
-      header_len: HeaderLen::V4,
+      header_len: HeaderLen::Start,
       readiness: Readiness {
         interest:  UnixReady::from(Ready::readable()) | UnixReady::hup() | UnixReady::error(),
         event: UnixReady::from(Ready::empty()),
@@ -54,6 +60,13 @@ impl <Front:SocketHandler + Read>ExpectProxyProtocol<Front> {
 
   pub fn readable(&mut self, metrics: &mut SessionMetrics) -> (ProtocolResult, SessionResult) {
     let total_len = match self.header_len {
+      HeaderLen::Start => V1_MIN_LEN,
+      // at least the CRLF is missing, or the LF following a CR
+      HeaderLen::V1   => if self.buf[..self.index].ends_with(b"\r") {
+        self.index + 1
+      } else {
+        self.index + 2
+      }.min(V1_MAX_LEN),
       HeaderLen::V4   => 28,
       HeaderLen::V6   => 52,
       HeaderLen::Unix => 232,
@@ -88,6 +101,22 @@ impl <Front:SocketHandler + Read>ExpectProxyProtocol<Front> {
       self.readiness.event.remove(Ready::readable());
     }
 
+    if let HeaderLen::Start = self.header_len {
+      if self.index < V1_MIN_LEN {
+        return (ProtocolResult::Continue, SessionResult::Continue);
+      }
+
+      self.header_len = if self.buf.starts_with(b"PROXY ") {
+        HeaderLen::V1
+      } else {
+        HeaderLen::V4
+      };
+    }
+
+    if let HeaderLen::V1 = self.header_len {
+      return self.parse_v1(metrics);
+    }
+
     match parse_v2_header(&self.buf[..self.index]) {
       Ok((rest, header)) => {
         trace!("got expect header: {:?}, rest.len() = {}", header, rest.len());
@@ -96,6 +125,7 @@ impl <Front:SocketHandler + Read>ExpectProxyProtocol<Front> {
       },
       Err(Err::Incomplete(_)) => {
         match self.header_len {
+          HeaderLen::Start | HeaderLen::V1 => {},
           HeaderLen::V4 => if self.index == 28 {
             self.header_len = HeaderLen::V6;
           },
@@ -122,6 +152,42 @@ impl <Front:SocketHandler + Read>ExpectProxyProtocol<Front> {
     }
   }
 
+  fn parse_v1(&mut self, metrics: &mut SessionMetrics) -> (ProtocolResult, SessionResult) {
+    match parse_v1_header(&self.buf[..self.index]) {
+      Ok((_, header)) => {
+        trace!("got expect header: {:?}", header);
+        self.addresses = Some(match header.protocol {
+          ProtocolSupportedV1::UNKNOWN => ProxyAddr::AfUnspec,
+          _ => ProxyAddr::from(header.addr_src, header.addr_dst),
+        });
+        (ProtocolResult::Upgrade, SessionResult::Continue)
+      },
+      Err(Err::Incomplete(_)) if self.index < V1_MAX_LEN => (ProtocolResult::Continue, SessionResult::Continue),
+      Err(e) => {
+        error!("[{:?}] front socket parse error, closing the connection: {:?}", self.frontend_token, e);
+        metrics.service_stop();
+        incr!("proxy_protocol.errors");
+        self.readiness.reset();
+        (ProtocolResult::Continue, SessionResult::CloseSession)
+      }
+    }
+  }
+
+  /// public and client addresses of the session, once the header is parsed. The LOCAL
+  /// and UNKNOWN headers do not convey any, so the connection's own addresses are used
+  pub fn session_addresses(&self) -> Option<(SocketAddr, SocketAddr)> {
+    match self.addresses {
+      Some(ProxyAddr::AfUnspec) => {
+        let socket = self.front_socket();
+        socket.local_addr().ok().and_then(|public| socket.peer_addr().ok().map(|peer| (public, peer)))
+      },
+      Some(ref addresses) => addresses.destination().and_then(|public| {
+        addresses.source().map(|client| (public, client))
+      }),
+      None => None,
+    }
+  }
+
   pub fn front_socket(&self) -> &TcpStream {
     self.frontend.socket_ref()
   }
@@ -216,6 +282,67 @@ mod expect_test {
     };
   }
 
+  // sends the data in one write, and reads it with an ExpectProxyProtocol until it stops
+  fn expect_header(address: &str, data: &'static [u8]) -> (ExpectProxyProtocol<TcpStream>, (ProtocolResult, SessionResult)) {
+    let address: SocketAddr = address.parse().expect("parse address error");
+    let listener = TcpListener::bind(&address).expect("could not bind");
+
+    let client = thread::spawn(move || {
+      let mut stream = StdTcpStream::connect(&address).expect("could not connect");
+      stream.write_all(data).unwrap();
+      // keep the connection open while the header is read
+      thread::sleep(::std::time::Duration::from_millis(100));
+    });
+
+    let stream = loop {
+      if let Ok((stream, _addr)) = listener.accept() {
+        break stream;
+      }
+    };
+
+    let mut session_metrics = SessionMetrics::new(None);
+    let mut expect_pp = ExpectProxyProtocol::new(stream, Token(0), Uuid::new_v4().to_hyphenated());
+
+    let mut res = (ProtocolResult::Continue, SessionResult::Continue);
+    while res == (ProtocolResult::Continue, SessionResult::Continue) {
+      res = expect_pp.readable(&mut session_metrics);
+    }
+
+    client.join().expect("should join");
+    (expect_pp, res)
+  }
+
+  #[test]
+  fn middleware_should_receive_a_proxy_protocol_v1_header() {
+    setup_test_logger!();
+    let (expect_pp, res) = expect_header("127.0.0.1:3501",
+      b"PROXY TCP4 125.25.10.1 10.4.5.8 8080 4200\r\nGET / HTTP/1.1\r\n\r\n");
+
+    assert_eq!(res, (ProtocolResult::Upgrade, SessionResult::Continue));
+    // the request following the header is left in the socket
+    assert_eq!(expect_pp.index, 43);
+    assert_eq!(expect_pp.session_addresses(),
+      Some(("10.4.5.8:4200".parse().unwrap(), "125.25.10.1:8080".parse().unwrap())));
+
+    // the connection's own addresses are used without a conveyed address
+    let (expect_pp, res) = expect_header("127.0.0.1:3502", b"PROXY UNKNOWN\r\nGET / HTTP/1.1\r\n\r\n");
+    assert_eq!(res, (ProtocolResult::Upgrade, SessionResult::Continue));
+    assert_eq!(expect_pp.index, 15);
+    let socket = expect_pp.front_socket();
+    assert_eq!(expect_pp.session_addresses(),
+      Some((socket.local_addr().unwrap(), socket.peer_addr().unwrap())));
+  }
+
+  #[test]
+  fn middleware_should_close_the_connection_on_a_malformed_header() {
+    setup_test_logger!();
+    let (_, res) = expect_header("127.0.0.1:3503", b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    assert_eq!(res, (ProtocolResult::Continue, SessionResult::CloseSession));
+
+    let (_, res) = expect_header("127.0.0.1:3504", b"PROXY TCP4 125.25.10.1 ::1 8080 4200\r\n");
+    assert_eq!(res, (ProtocolResult::Continue, SessionResult::CloseSession));
+  }
+
   // Connect to the next middleware and send a proxy protocol header
   fn start_upfront_middleware(next_middleware_addr: SocketAddr, barrier: Arc<Barrier>) -> JoinHandle<()> {
     thread::spawn(move|| {
@@ -1,5 +1,6 @@
 use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6};
 use std::fmt;
+use sozu_command::config::ProxyProtocolVersion;
 
 #[derive(PartialEq, Debug)]
 pub enum ProxyProtocolHeader {
@@ -8,6 +9,24 @@ pub enum ProxyProtocolHeader {
 }
 
 impl ProxyProtocolHeader {
+  /// header sent to a backend for a connection from `source` to `destination`.
+  /// If an address is unknown or they are not of the same family, the header
+  /// does not carry them, and the backend uses the connection's own addresses
+  pub fn for_connection(version: ProxyProtocolVersion, source: Option<SocketAddr>,
+    destination: Option<SocketAddr>) -> ProxyProtocolHeader {
+    let addresses = match (source, destination) {
+      (Some(src), Some(dst)) if src.is_ipv4() == dst.is_ipv4() => Some((src, dst)),
+      _ => None,
+    };
+
+    match (version, addresses) {
+      (ProxyProtocolVersion::V1, Some((src, dst))) => ProxyProtocolHeader::V1(HeaderV1::new(src, dst)),
+      (ProxyProtocolVersion::V1, None) => ProxyProtocolHeader::V1(HeaderV1::unknown()),
+      (ProxyProtocolVersion::V2, Some((src, dst))) => ProxyProtocolHeader::V2(HeaderV2::new(Command::Proxy, src, dst)),
+      (ProxyProtocolVersion::V2, None) => ProxyProtocolHeader::V2(HeaderV2::local()),
+    }
+  }
+
   // Use this method to writte the header in the backend socket
   pub fn into_bytes(&self) -> Vec<u8> {
     match *self {
@@ -67,6 +86,16 @@ impl HeaderV1 {
     }
   }
 
+  /// `PROXY UNKNOWN` header, the addresses are not sent
+  pub fn unknown() -> Self {
+    let unspecified = SocketAddr::V4(SocketAddrV4::new(0.into(), 0));
+    HeaderV1 {
+      protocol: ProtocolSupportedV1::UNKNOWN,
+      addr_src: unspecified,
+      addr_dst: unspecified,
+    }
+  }
+
   pub fn into_bytes(&self) -> Vec<u8> {
     if self.protocol.eq(&ProtocolSupportedV1::UNKNOWN) {
       format!("{} {}\r\n",
@@ -111,7 +140,8 @@ mod test {
       SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0x9c, 0x76)), 80)
     );
 
-    let header_to_cmp = "PROXY TCP6 ::0.0.255.255 ::0.156.0.118 80 80\r\n".as_bytes();
+    // the addresses use the RFC 5952 text representation
+    let header_to_cmp = "PROXY TCP6 ::ffff ::9c:76 80 80\r\n".as_bytes();
     assert_eq!(header_to_cmp, &header.into_bytes()[..]);
   }
 }
@@ -140,6 +170,15 @@ impl HeaderV2 {
     }
   }
 
+  /// LOCAL command without addresses, the receiver uses the connection's addresses
+  pub fn local() -> Self {
+    HeaderV2 {
+      command: Command::Local,
+      family: get_family(&ProxyAddr::AfUnspec),
+      addr: ProxyAddr::AfUnspec,
+    }
+  }
+
   pub fn into_bytes(&self) -> Vec<u8> {
     let mut header = Vec::with_capacity(self.len());
 
@@ -361,3 +400,70 @@ mod test_v2 {
     assert_eq!(&expected[..], &header.into_bytes()[..]);
   }
 }
+
+#[cfg(test)]
+mod test_for_connection {
+
+  use super::*;
+
+  fn address(s: &str) -> Option<SocketAddr> {
+    Some(s.parse().unwrap())
+  }
+
+  #[test]
+  fn v1_headers() {
+    let header = ProxyProtocolHeader::for_connection(ProxyProtocolVersion::V1,
+      address("192.168.1.10:51234"), address("10.0.0.1:80"));
+    assert_eq!(&b"PROXY TCP4 192.168.1.10 10.0.0.1 51234 80\r\n"[..], &header.into_bytes()[..]);
+
+    let header = ProxyProtocolHeader::for_connection(ProxyProtocolVersion::V1,
+      address("[2001:db8::10]:51234"), address("[2001:db8::1]:443"));
+    assert_eq!(&b"PROXY TCP6 2001:db8::10 2001:db8::1 51234 443\r\n"[..], &header.into_bytes()[..]);
+
+    let header = ProxyProtocolHeader::for_connection(ProxyProtocolVersion::V1, None, address("10.0.0.1:80"));
+    assert_eq!(&b"PROXY UNKNOWN\r\n"[..], &header.into_bytes()[..]);
+    let header = ProxyProtocolHeader::for_connection(ProxyProtocolVersion::V1,
+      address("192.168.1.10:51234"), address("[2001:db8::1]:443"));
+    assert_eq!(&b"PROXY UNKNOWN\r\n"[..], &header.into_bytes()[..]);
+  }
+
+  #[test]
+  fn v2_headers() {
+    let header = ProxyProtocolHeader::for_connection(ProxyProtocolVersion::V2,
+      address("192.168.1.10:51234"), address("10.0.0.1:80"));
+    let expected = &[
+      0x0D, 0x0A, 0x0D, 0x0A, 0x00, 0x0D, 0x0A, 0x51, 0x55, 0x49, 0x54, 0x0A, // signature
+      0x21,                                                                   // version 2 and command PROXY
+      0x11,                                                                   // TCP over IPv4
+      0x00, 0x0C,                                                             // address length = 12
+      0xC0, 0xA8, 0x01, 0x0A,                                                 // source address
+      0x0A, 0x00, 0x00, 0x01,                                                 // destination address
+      0xC8, 0x22,                                                             // source port
+      0x00, 0x50,                                                             // destination port
+    ];
+    assert_eq!(&expected[..], &header.into_bytes()[..]);
+
+    let header = ProxyProtocolHeader::for_connection(ProxyProtocolVersion::V2,
+      address("[2001:db8::10]:51234"), address("[2001:db8::1]:443"));
+    let expected = &[
+      0x0D, 0x0A, 0x0D, 0x0A, 0x00, 0x0D, 0x0A, 0x51, 0x55, 0x49, 0x54, 0x0A,                         // signature
+      0x21,                                                                                           // version 2 and command PROXY
+      0x21,                                                                                           // TCP over IPv6
+      0x00, 0x24,                                                                                     // address length = 36
+      0x20, 0x01, 0x0D, 0xB8, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x10, // source address
+      0x20, 0x01, 0x0D, 0xB8, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, // destination address
+      0xC8, 0x22,                                                                                     // source port
+      0x01, 0xBB,                                                                                     // destination port
+    ];
+    assert_eq!(&expected[..], &header.into_bytes()[..]);
+
+    let header = ProxyProtocolHeader::for_connection(ProxyProtocolVersion::V2, None, address("10.0.0.1:80"));
+    let expected = &[
+      0x0D, 0x0A, 0x0D, 0x0A, 0x00, 0x0D, 0x0A, 0x51, 0x55, 0x49, 0x54, 0x0A, // signature
+      0x20,                                                                   // version 2 and command LOCAL
+      0x00,                                                                   // AF_UNSPEC
+      0x00, 0x00,                                                             // no address
+    ];
+    assert_eq!(&expected[..], &header.into_bytes()[..]);
+  }
+}
@@ -1,7 +1,8 @@
 use nom::{be_u8, be_u16};
 
-use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
 use std::convert::From;
+use std::str::{from_utf8, FromStr};
 
 use protocol::proxy_protocol::header::*;
 
@@ -85,6 +86,68 @@ pub fn slice_to_ipv6(sl: &[u8]) -> Ipv6Addr {
   Ipv6Addr::from(arr)
 }
 
+/// length of the shortest version 1 header, `PROXY UNKNOWN\r\n`
+pub const V1_MIN_LEN: usize = 15;
+/// length of the longest version 1 header, with the CRLF
+pub const V1_MAX_LEN: usize = 107;
+
+named!(v1_ip<IpAddr>,
+  map_res!(map_res!(take_until_and_consume!(" "), from_utf8), FromStr::from_str)
+);
+
+named!(v1_port<u16>,
+  map_res!(map_res!(take_until_and_consume!(" "), from_utf8), FromStr::from_str)
+);
+
+named!(v1_last_port<u16>,
+  map_res!(map_res!(take_until_and_consume!("\r\n"), from_utf8), FromStr::from_str)
+);
+
+named!(v1_protocol<ProtocolSupportedV1>,
+  alt!(
+    value!(ProtocolSupportedV1::TCP4, tag!("TCP4 ")) |
+    value!(ProtocolSupportedV1::TCP6, tag!("TCP6 "))
+  )
+);
+
+named!(v1_addresses<HeaderV1>,
+  map_opt!(
+    do_parse!(
+      protocol: v1_protocol >>
+      src_ip: v1_ip >>
+      dst_ip: v1_ip >>
+      src_port: v1_port >>
+      dst_port: v1_last_port >>
+      ((protocol, src_ip, dst_ip, src_port, dst_port))
+    ),
+    |(protocol, src_ip, dst_ip, src_port, dst_port)| v1_header(protocol, src_ip, dst_ip, src_port, dst_port)
+  )
+);
+
+// the addresses must be of the announced family
+fn v1_header(protocol: ProtocolSupportedV1, src_ip: IpAddr, dst_ip: IpAddr, src_port: u16, dst_port: u16) -> Option<HeaderV1> {
+  match (&protocol, src_ip, dst_ip) {
+    (&ProtocolSupportedV1::TCP4, IpAddr::V4(_), IpAddr::V4(_)) |
+    (&ProtocolSupportedV1::TCP6, IpAddr::V6(_), IpAddr::V6(_)) => Some(HeaderV1 {
+      protocol,
+      addr_src: SocketAddr::new(src_ip, src_port),
+      addr_dst: SocketAddr::new(dst_ip, dst_port),
+    }),
+    _ => None,
+  }
+}
+
+named!(pub parse_v1_header<HeaderV1>,
+  do_parse!(
+    tag!("PROXY ") >>
+    header: alt!(
+      do_parse!(tag!("UNKNOWN") >> take_until_and_consume!("\r\n") >> (HeaderV1::unknown())) |
+      v1_addresses
+    ) >>
+    (header)
+  )
+);
+
 #[cfg(test)]
 mod test {
 
@@ -245,4 +308,53 @@ mod test {
 
     assert_eq!(Err(Err::Incomplete(Size(16))), parse_v2_header(input));
   }
+
+  #[test]
+  fn it_should_parse_proxy_protocol_v1_headers() {
+    let input = &b"PROXY TCP4 192.168.1.10 10.0.0.1 51234 80\r\nGET / HTTP/1.1\r\n"[..];
+    let expected = HeaderV1::new("192.168.1.10:51234".parse().unwrap(), "10.0.0.1:80".parse().unwrap());
+    assert_eq!(Ok((&b"GET / HTTP/1.1\r\n"[..], expected)), parse_v1_header(input));
+
+    let input = &b"PROXY TCP6 2001:db8::10 2001:db8::1 51234 443\r\n"[..];
+    let expected = HeaderV1::new("[2001:db8::10]:51234".parse().unwrap(), "[2001:db8::1]:443".parse().unwrap());
+    assert_eq!(Ok((&[][..], expected)), parse_v1_header(input));
+
+    // anything can follow UNKNOWN, up to the CRLF
+    assert_eq!(Ok((&[][..], HeaderV1::unknown())), parse_v1_header(&b"PROXY UNKNOWN\r\n"[..]));
+    assert_eq!(Ok((&[][..], HeaderV1::unknown())),
+      parse_v1_header(&b"PROXY UNKNOWN ffff:: ffff:: 65535 65535\r\n"[..]));
+  }
+
+  #[test]
+  fn it_should_wait_for_the_end_of_a_proxy_protocol_v1_header() {
+    assert!(match parse_v1_header(&b"PROXY TCP4 192.168.1.10 10.0"[..]) {
+      Err(Err::Incomplete(_)) => true,
+      _ => false,
+    });
+    assert!(match parse_v1_header(&b"PROXY TCP4 192.168.1.10 10.0.0.1 51234 80\r"[..]) {
+      Err(Err::Incomplete(_)) => true,
+      _ => false,
+    });
+  }
+
+  #[test]
+  fn it_should_not_parse_malformed_proxy_protocol_v1_headers() {
+    let malformed: &[&[u8]] = &[
+      b"PROXY UDP4 192.168.1.10 10.0.0.1 51234 80\r\n",
+      b"PROXY TCP4 192.168.1.300 10.0.0.1 51234 80\r\n",
+      b"PROXY TCP4 192.168.1.10 10.0.0.1 65536 80\r\n",
+      b"PROXY TCP4 192.168.1.10 10.0.0.1 51234 http\r\n",
+      b"PROXY TCP4 2001:db8::10 2001:db8::1 51234 443\r\n",
+      b"PROXY TCP6 192.168.1.10 10.0.0.1 51234 80\r\n",
+      b"proxy TCP4 192.168.1.10 10.0.0.1 51234 80\r\n",
+      b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n",
+    ];
+
+    for input in malformed {
+      match parse_v1_header(input) {
+        Ok(_) | Err(Err::Incomplete(_)) => panic!("should not parse {:?}", from_utf8(input)),
+        Err(_) => {},
+      }
+    }
+  }
 }
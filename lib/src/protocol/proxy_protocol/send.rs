@@ -6,6 +6,7 @@ use mio::tcp::TcpStream;
 use mio::unix::UnixReady;
 use uuid::adapter::Hyphenated;
 use sozu_command::buffer::Buffer;
+use sozu_command::config::ProxyProtocolVersion;
 use {
   SessionMetrics,
   SessionResult,
@@ -29,11 +30,12 @@ pub struct SendProxyProtocol<Front:SocketHandler> {
   pub front_readiness:Readiness,
   pub back_readiness: Readiness,
   cursor_header:      usize,
+  version:            ProxyProtocolVersion,
 }
 
 impl <Front:SocketHandler + Read> SendProxyProtocol<Front> {
   pub fn new(frontend: Front, frontend_token: Token, request_id: Hyphenated,
-    backend: Option<TcpStream>) -> Self {
+    backend: Option<TcpStream>, version: ProxyProtocolVersion) -> Self {
     SendProxyProtocol {
       header: None,
       frontend,
@@ -50,6 +52,7 @@ impl <Front:SocketHandler + Read> SendProxyProtocol<Front> {
         event:    UnixReady::from(Ready::empty()),
       },
       cursor_header: 0,
+      version,
     }
   }
 
@@ -62,7 +65,7 @@ impl <Front:SocketHandler + Read> SendProxyProtocol<Front> {
     if self.header.is_none() {
       if let Ok(local_addr) = self.front_socket().local_addr() {
         if let Ok(frontend_addr) = self.front_socket().peer_addr() {
-          self.header = Some(ProxyProtocolHeader::V2(HeaderV2::new(Command::Proxy, frontend_addr, local_addr)).into_bytes());
+          self.header = Some(ProxyProtocolHeader::for_connection(self.version, Some(frontend_addr), Some(local_addr)).into_bytes());
         } else {
           return (ProtocolResult::Continue, SessionResult::CloseSession);
         }
@@ -221,7 +224,7 @@ mod send_test {
     let backend_stream = unsafe { TcpStream::from_raw_fd(fd) };
 
     let mut send_pp = SendProxyProtocol::new(client_stream, Token(0),
-      Uuid::new_v4().to_hyphenated(), Some(backend_stream));
+      Uuid::new_v4().to_hyphenated(), Some(backend_stream), ProxyProtocolVersion::V2);
     let mut session_metrics = SessionMetrics::new(None);
 
     send_pp.set_back_connected(BackendConnectionStatus::Connected);
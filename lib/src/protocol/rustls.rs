@@ -2,9 +2,11 @@ use mio::*;
 use mio::net::*;
 use mio::unix::UnixReady;
 use uuid::adapter::Hyphenated;
+use std::rc::Rc;
 use std::io::ErrorKind;
 use {SessionResult,Readiness};
 use protocol::ProtocolResult;
+use https_rustls::acme::AcmeChallenges;
 use rustls::{ServerSession, Session};
 
 pub enum TlsState {
@@ -19,10 +21,15 @@ pub struct TlsHandshake {
   pub session:   ServerSession,
   pub readiness: Readiness,
   pub request_id: Hyphenated,
+  /// set until the ClientHello was checked for an ACME validation
+  acme_challenges: Option<Rc<AcmeChallenges>>,
+  /// the session only answers an ACME TLS-ALPN-01 validation, and is closed after the handshake
+  pub acme_validation: bool,
 }
 
 impl TlsHandshake {
-  pub fn new(session: ServerSession, stream: TcpStream, request_id: Hyphenated) -> TlsHandshake {
+  pub fn new(session: ServerSession, stream: TcpStream, request_id: Hyphenated,
+    acme_challenges: Rc<AcmeChallenges>) -> TlsHandshake {
     TlsHandshake {
       stream,
       session,
@@ -32,12 +39,47 @@ impl TlsHandshake {
         event: UnixReady::from(Ready::empty()),
       },
       request_id,
+      acme_challenges: Some(acme_challenges),
+      acme_validation: false,
+    }
+  }
+
+  /// looks at the ClientHello without consuming it, and switches to the ACME
+  /// configuration if it is a validation for one of the challenges
+  fn check_acme_validation(&mut self) {
+    let challenges = match self.acme_challenges.take() {
+      Some(challenges) => challenges,
+      None             => return,
+    };
+
+    if challenges.is_empty() {
+      return;
+    }
+
+    // a ClientHello usually fits in one record, itself sent in one packet
+    let mut buf = [0u8; 16389];
+    match self.stream.peek(&mut buf) {
+      Ok(0) => {},
+      Ok(sz) => {
+        if let Some(session) = challenges.session_for(&buf[..sz]) {
+          info!("{}\tanswering an ACME TLS-ALPN-01 validation", self.request_id);
+          incr!("tls.acme.validations");
+          self.session = session;
+          self.acme_validation = true;
+        }
+      },
+      Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+        self.acme_challenges = Some(challenges);
+      },
+      Err(_) => {},
     }
   }
 
   pub fn readable(&mut self) -> (ProtocolResult,SessionResult) {
     let mut can_read  = true;
 
+    self.check_acme_validation();
+
     loop {
       let mut can_work = false;
 
@@ -88,6 +130,8 @@ impl TlsHandshake {
       // handshake might be finished but we still have something to send
       if self.session.wants_write() {
         (ProtocolResult::Continue, SessionResult::Continue)
+      } else if self.acme_validation {
+        (ProtocolResult::Continue, SessionResult::CloseSession)
       } else {
         self.readiness.interest.insert(Ready::readable());
         self.readiness.event.insert(Ready::readable());
@@ -141,6 +185,8 @@ impl TlsHandshake {
 
     if self.session.is_handshaking() {
       (ProtocolResult::Continue, SessionResult::Continue)
+    } else if self.acme_validation {
+      (ProtocolResult::Continue, SessionResult::CloseSession)
     } else if self.session.wants_read() {
       self.readiness.interest.insert(Ready::readable());
       (ProtocolResult::Upgrade, SessionResult::Continue)
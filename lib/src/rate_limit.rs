@@ -0,0 +1,203 @@
+//! rate limiting of the new connections accepted on a listener
+//!
+//! Each listener configuring a `ConnectionRateLimit` gets a token bucket of
+//! `burst` tokens, refilled by `rate` tokens per second. Accepting a connection
+//! takes a token, and the connections arriving while the bucket is empty are
+//! rejected. With `per_client`, every client address has its own bucket. Those
+//! are kept in a map of bounded size: a full bucket behaves like a new one, so
+//! the full buckets are removed first, then the least recently used ones.
+use std::collections::HashMap;
+use std::net::IpAddr;
+use time::SteadyTime;
+use sozu_command::proxy::ConnectionRateLimit;
+
+/// maximum number of client addresses tracked by a listener's limiter
+pub const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+#[derive(Debug,Clone)]
+pub struct TokenBucket {
+  /// tokens added per second
+  rate:      f64,
+  capacity:  f64,
+  tokens:    f64,
+  /// last time the bucket was refilled
+  updated:   SteadyTime,
+  /// last connection attempt counted by this bucket
+  last_used: SteadyTime,
+}
+
+impl TokenBucket {
+  /// creates a full bucket
+  pub fn new(rate: u32, burst: u32, now: SteadyTime) -> TokenBucket {
+    let capacity = f64::from(burst.max(1));
+    TokenBucket {
+      rate: f64::from(rate),
+      capacity,
+      tokens: capacity,
+      updated: now,
+      last_used: now,
+    }
+  }
+
+  fn refill(&mut self, now: SteadyTime) {
+    let elapsed = (now - self.updated).num_microseconds().unwrap_or(i64::max_value());
+    if elapsed > 0 {
+      self.tokens = (self.tokens + self.rate * elapsed as f64 / 1_000_000.0).min(self.capacity);
+      self.updated = now;
+    }
+  }
+
+  /// takes a token if there is one available
+  pub fn take(&mut self, now: SteadyTime) -> bool {
+    self.refill(now);
+    self.last_used = now;
+    if self.tokens >= 1.0 {
+      self.tokens -= 1.0;
+      true
+    } else {
+      false
+    }
+  }
+
+  pub fn is_full(&mut self, now: SteadyTime) -> bool {
+    self.refill(now);
+    self.tokens >= self.capacity
+  }
+}
+
+#[derive(Debug)]
+pub struct ConnectionRateLimiter {
+  pub config:  ConnectionRateLimit,
+  listener:    TokenBucket,
+  clients:     HashMap<IpAddr, TokenBucket>,
+  max_clients: usize,
+}
+
+impl ConnectionRateLimiter {
+  pub fn new(config: ConnectionRateLimit, now: SteadyTime) -> ConnectionRateLimiter {
+    ConnectionRateLimiter::with_max_clients(config, MAX_TRACKED_CLIENTS, now)
+  }
+
+  pub fn with_max_clients(config: ConnectionRateLimit, max_clients: usize, now: SteadyTime) -> ConnectionRateLimiter {
+    ConnectionRateLimiter {
+      config,
+      listener: TokenBucket::new(config.rate, config.burst, now),
+      clients: HashMap::new(),
+      max_clients: max_clients.max(1),
+    }
+  }
+
+  /// returns true if a new connection from this client can be accepted. Without
+  /// a client address, the per client limit counts against the listener's bucket
+  pub fn accept(&mut self, client: Option<IpAddr>, now: SteadyTime) -> bool {
+    let ip = match client {
+      Some(ip) if self.config.per_client => ip,
+      _ => return self.listener.take(now),
+    };
+
+    if !self.clients.contains_key(&ip) && self.clients.len() >= self.max_clients {
+      self.evict(now);
+    }
+
+    let config = self.config;
+    self.clients.entry(ip).or_insert_with(|| TokenBucket::new(config.rate, config.burst, now)).take(now)
+  }
+
+  pub fn tracked_clients(&self) -> usize {
+    self.clients.len()
+  }
+
+  fn evict(&mut self, now: SteadyTime) {
+    self.clients.retain(|_, bucket| !bucket.is_full(now));
+
+    if self.clients.len() >= self.max_clients {
+      let least_recently_used = self.clients.iter().min_by_key(|&(_, bucket)| bucket.last_used).map(|(ip, _)| *ip);
+      if let Some(ip) = least_recently_used {
+        self.clients.remove(&ip);
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use time::Duration;
+
+  fn limit(rate: u32, burst: u32, per_client: bool) -> ConnectionRateLimit {
+    ConnectionRateLimit { rate, burst, per_client }
+  }
+
+  #[test]
+  fn bucket_exhaustion_and_recovery() {
+    let start = SteadyTime::now();
+    let mut limiter = ConnectionRateLimiter::new(limit(10, 5, false), start);
+
+    // the burst is accepted at once, then the bucket is empty
+    for _ in 0..5 {
+      assert!(limiter.accept(None, start));
+    }
+    assert!(!limiter.accept(None, start));
+    assert!(!limiter.accept(None, start + Duration::milliseconds(50)));
+
+    // one token every 100ms
+    let now = start + Duration::milliseconds(100);
+    assert!(limiter.accept(None, now));
+    assert!(!limiter.accept(None, now));
+
+    // the bucket does not grow above the burst
+    let now = now + Duration::seconds(10);
+    for _ in 0..5 {
+      assert!(limiter.accept(None, now));
+    }
+    assert!(!limiter.accept(None, now));
+  }
+
+  #[test]
+  fn per_client_buckets() {
+    let start = SteadyTime::now();
+    let mut limiter = ConnectionRateLimiter::new(limit(1, 2, true), start);
+    let client_1 = Some("10.0.0.1".parse().unwrap());
+    let client_2 = Some("10.0.0.2".parse().unwrap());
+
+    assert!(limiter.accept(client_1, start));
+    assert!(limiter.accept(client_1, start));
+    assert!(!limiter.accept(client_1, start));
+    // another client has its own bucket
+    assert!(limiter.accept(client_2, start));
+
+    assert!(limiter.accept(client_1, start + Duration::seconds(1)));
+
+    // a listener wide limit does not look at the address
+    let mut limiter = ConnectionRateLimiter::new(limit(1, 2, false), start);
+    assert!(limiter.accept(client_1, start));
+    assert!(limiter.accept(client_2, start));
+    assert!(!limiter.accept(client_1, start));
+  }
+
+  #[test]
+  fn bounded_client_tracking() {
+    let start = SteadyTime::now();
+    let mut limiter = ConnectionRateLimiter::with_max_clients(limit(1, 1, true), 2, start);
+    let client_1 = Some("10.0.0.1".parse().unwrap());
+    let client_2 = Some("10.0.0.2".parse().unwrap());
+    let client_3 = Some("10.0.0.3".parse().unwrap());
+
+    assert!(limiter.accept(client_1, start));
+    assert!(limiter.accept(client_2, start + Duration::milliseconds(10)));
+    assert_eq!(limiter.tracked_clients(), 2);
+
+    // no bucket is full again, the least recently used one is removed
+    assert!(limiter.accept(client_3, start + Duration::milliseconds(20)));
+    assert_eq!(limiter.tracked_clients(), 2);
+    assert!(!limiter.accept(client_2, start + Duration::milliseconds(30)));
+    // client_1 was forgotten and gets a new bucket
+    assert!(limiter.accept(client_1, start + Duration::milliseconds(40)));
+    assert_eq!(limiter.tracked_clients(), 2);
+
+    // once refilled, the buckets are removed first
+    let later = start + Duration::seconds(5);
+    assert!(limiter.accept(Some("10.0.0.4".parse().unwrap()), later));
+    assert_eq!(limiter.tracked_clients(), 1);
+  }
+}
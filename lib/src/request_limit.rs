@@ -0,0 +1,341 @@
+//! concurrency limit of the applications
+//!
+//! An application with a request limit sends at most `max_requests` requests
+//! to its backends at once. The next requests wait in a queue of `queue_size`
+//! sessions and get a slot in their arrival order when a request ends. Once the
+//! queue is full, they get the application's 503 answer.
+//!
+//! A session keeps its slot or its place in the queue in a `RequestSlot`, which
+//! gives it back when it is dropped: once the response is sent, or when the
+//! session is closed. A queued session has no timeout of its own, it is closed
+//! by its front timeout if no slot frees in time.
+use std::rc::{Rc, Weak};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet, VecDeque};
+use mio::Token;
+use sozu_command::proxy::RequestLimit;
+
+use super::AppId;
+
+#[derive(Debug)]
+struct AppRequests {
+  limit:     RequestLimit,
+  in_flight: u32,
+  /// front tokens of the sessions waiting for a slot
+  queue:     VecDeque<Token>,
+  /// sessions that got the slot of a finished request, and did not take it yet
+  granted:   HashSet<Token>,
+}
+
+impl AppRequests {
+  /// gives the slot to the next session of the queue, or frees it
+  fn release(&mut self, woken: &mut Vec<Token>) {
+    match self.queue.pop_front() {
+      Some(token) => {
+        self.granted.insert(token);
+        woken.push(token);
+      },
+      None => self.in_flight = self.in_flight.saturating_sub(1),
+    }
+  }
+}
+
+#[derive(Debug)]
+pub struct RequestLimits {
+  apps:  HashMap<AppId, AppRequests>,
+  /// sessions that must try to connect to a backend again
+  woken: Vec<Token>,
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+enum SlotState {
+  Queued,
+  Held,
+}
+
+/// slot of a request in the concurrency limit of its application
+#[derive(Debug)]
+pub struct RequestSlot {
+  limits: Weak<RefCell<RequestLimits>>,
+  app_id: AppId,
+  token:  Token,
+  state:  SlotState,
+}
+
+impl RequestSlot {
+  pub fn is_held(&self) -> bool {
+    self.state == SlotState::Held
+  }
+}
+
+impl Drop for RequestSlot {
+  fn drop(&mut self) {
+    if let Some(limits) = self.limits.upgrade() {
+      let mut limits = limits.borrow_mut();
+      match self.state {
+        SlotState::Held   => limits.release(&self.app_id),
+        SlotState::Queued => limits.cancel(&self.app_id, self.token),
+      }
+    }
+  }
+}
+
+#[derive(Debug)]
+pub enum Admission {
+  /// the request can be sent, with the slot to keep until it ends if the
+  /// application has a limit
+  Admitted(Option<RequestSlot>),
+  /// the session must wait until it is woken up
+  Queued(RequestSlot),
+  /// the limit is reached and the queue is full
+  Rejected,
+}
+
+impl RequestLimits {
+  pub fn new() -> RequestLimits {
+    RequestLimits {
+      apps:  HashMap::new(),
+      woken: Vec::new(),
+    }
+  }
+
+  /// changes the limit of an application. The requests in flight keep their
+  /// slot, and the queued sessions are woken up if the new limit lets them in
+  pub fn set_limit(&mut self, app_id: &str, limit: Option<RequestLimit>) {
+    match limit {
+      Some(limit) => {
+        let app = self.apps.entry(app_id.to_string()).or_insert_with(|| AppRequests {
+          limit, in_flight: 0, queue: VecDeque::new(), granted: HashSet::new(),
+        });
+        app.limit = limit;
+        while app.in_flight < limit.max_requests {
+          match app.queue.pop_front() {
+            Some(token) => {
+              app.in_flight += 1;
+              app.granted.insert(token);
+              self.woken.push(token);
+            },
+            None => break,
+          }
+        }
+      },
+      None => if let Some(app) = self.apps.remove(app_id) {
+        // without a limit, they get in right away
+        self.woken.extend(app.queue);
+        self.woken.extend(app.granted);
+      },
+    }
+  }
+
+  pub fn remove_application(&mut self, app_id: &str) {
+    self.set_limit(app_id, None);
+  }
+
+  /// sessions that got a slot since the last call
+  pub fn take_woken(&mut self) -> Vec<Token> {
+    ::std::mem::replace(&mut self.woken, Vec::new())
+  }
+
+  /// number of requests in flight and of queued sessions of the application
+  pub fn usage(&self, app_id: &str) -> Option<(u32, usize)> {
+    self.apps.get(app_id).map(|app| (app.in_flight, app.queue.len()))
+  }
+
+  fn release(&mut self, app_id: &str) {
+    if let Some(app) = self.apps.get_mut(app_id) {
+      app.release(&mut self.woken);
+    }
+  }
+
+  /// a queued session is closed: it leaves the queue, or passes the slot it got.
+  /// Its token is not woken up, another session could get it
+  fn cancel(&mut self, app_id: &str, token: Token) {
+    self.woken.retain(|t| *t != token);
+    if let Some(app) = self.apps.get_mut(app_id) {
+      if app.granted.remove(&token) {
+        app.release(&mut self.woken);
+      } else {
+        app.queue.retain(|t| *t != token);
+      }
+    }
+  }
+}
+
+/// checks if the request of the session `token` can be sent to a backend of
+/// the application. `current` is the slot the session already has, for a
+/// request that connects again or that was woken up
+pub fn admit(limits: &Rc<RefCell<RequestLimits>>, app_id: &str, token: Token, current: Option<RequestSlot>) -> Admission {
+  let mut current = current;
+  if current.as_ref().map(|slot| slot.app_id != app_id || slot.token != token).unwrap_or(false) {
+    // gives it back before borrowing the limits
+    current = None;
+  }
+
+  let mut l = limits.borrow_mut();
+  let app = match l.apps.get_mut(app_id) {
+    Some(app) => app,
+    None      => {
+      // the limit was removed while the session waited
+      drop(l);
+      drop(current);
+      return Admission::Admitted(None);
+    },
+  };
+
+  match current {
+    Some(slot) => match slot.state {
+      SlotState::Held => Admission::Admitted(Some(slot)),
+      SlotState::Queued if app.granted.remove(&token) => {
+        let mut slot = slot;
+        slot.state = SlotState::Held;
+        Admission::Admitted(Some(slot))
+      },
+      SlotState::Queued => Admission::Queued(slot),
+    },
+    None => {
+      let state = if app.in_flight < app.limit.max_requests {
+        app.in_flight += 1;
+        SlotState::Held
+      } else if app.queue.len() < app.limit.queue_size as usize {
+        app.queue.push_back(token);
+        SlotState::Queued
+      } else {
+        return Admission::Rejected;
+      };
+
+      let slot = RequestSlot { limits: Rc::downgrade(limits), app_id: app_id.to_string(), token, state };
+      if state == SlotState::Held {
+        Admission::Admitted(Some(slot))
+      } else {
+        Admission::Queued(slot)
+      }
+    },
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn limits(max_requests: u32, queue_size: u32) -> Rc<RefCell<RequestLimits>> {
+    let limits = Rc::new(RefCell::new(RequestLimits::new()));
+    limits.borrow_mut().set_limit("app_1", Some(RequestLimit { max_requests, queue_size }));
+    limits
+  }
+
+  fn held(admission: Admission) -> RequestSlot {
+    match admission {
+      Admission::Admitted(Some(slot)) => slot,
+      a => panic!("expected an admitted request, got {:?}", a),
+    }
+  }
+
+  fn queued(admission: Admission) -> RequestSlot {
+    match admission {
+      Admission::Queued(slot) => slot,
+      a => panic!("expected a queued request, got {:?}", a),
+    }
+  }
+
+  #[test]
+  fn requests_under_the_limit() {
+    let limits = limits(2, 0);
+
+    let first  = held(admit(&limits, "app_1", Token(1), None));
+    let second = held(admit(&limits, "app_1", Token(2), None));
+    assert_eq!(limits.borrow().usage("app_1"), Some((2, 0)));
+
+    // a reconnection keeps its slot
+    let first = held(admit(&limits, "app_1", Token(1), Some(first)));
+    assert_eq!(limits.borrow().usage("app_1"), Some((2, 0)));
+
+    drop(first);
+    drop(second);
+    assert_eq!(limits.borrow().usage("app_1"), Some((0, 0)));
+    assert!(limits.borrow_mut().take_woken().is_empty());
+
+    // other applications are not limited
+    match admit(&limits, "app_2", Token(3), None) {
+      Admission::Admitted(None) => {},
+      a => panic!("unexpected admission {:?}", a),
+    }
+  }
+
+  #[test]
+  fn queued_requests_proceed_in_order() {
+    let limits = limits(1, 2);
+
+    let first  = held(admit(&limits, "app_1", Token(1), None));
+    let second = queued(admit(&limits, "app_1", Token(2), None));
+    let third  = queued(admit(&limits, "app_1", Token(3), None));
+    assert_eq!(limits.borrow().usage("app_1"), Some((1, 2)));
+
+    // still waiting
+    let second = queued(admit(&limits, "app_1", Token(2), Some(second)));
+
+    drop(first);
+    assert_eq!(limits.borrow_mut().take_woken(), vec![Token(2)]);
+    let second = held(admit(&limits, "app_1", Token(2), Some(second)));
+    assert_eq!(limits.borrow().usage("app_1"), Some((1, 1)));
+
+    drop(second);
+    assert_eq!(limits.borrow_mut().take_woken(), vec![Token(3)]);
+    let third = held(admit(&limits, "app_1", Token(3), Some(third)));
+
+    drop(third);
+    assert_eq!(limits.borrow().usage("app_1"), Some((0, 0)));
+  }
+
+  #[test]
+  fn overflow_is_rejected() {
+    let limits = limits(1, 1);
+
+    let _first  = held(admit(&limits, "app_1", Token(1), None));
+    let second  = queued(admit(&limits, "app_1", Token(2), None));
+    match admit(&limits, "app_1", Token(3), None) {
+      Admission::Rejected => {},
+      a => panic!("expected a rejected request, got {:?}", a),
+    }
+
+    // a closed session leaves the queue
+    drop(second);
+    assert_eq!(limits.borrow().usage("app_1"), Some((1, 0)));
+    let _third = queued(admit(&limits, "app_1", Token(3), None));
+  }
+
+  #[test]
+  fn closed_session_passes_its_slot() {
+    let limits = limits(1, 2);
+
+    let first  = held(admit(&limits, "app_1", Token(1), None));
+    let second = queued(admit(&limits, "app_1", Token(2), None));
+    let third  = queued(admit(&limits, "app_1", Token(3), None));
+
+    // the second session is closed after getting the slot, before using it
+    drop(first);
+    drop(second);
+    assert_eq!(limits.borrow_mut().take_woken(), vec![Token(3)]);
+    let _third = held(admit(&limits, "app_1", Token(3), Some(third)));
+    assert_eq!(limits.borrow().usage("app_1"), Some((1, 0)));
+  }
+
+  #[test]
+  fn changing_the_limit_wakes_up_the_queue() {
+    let limits = limits(1, 2);
+
+    let _first = held(admit(&limits, "app_1", Token(1), None));
+    let second = queued(admit(&limits, "app_1", Token(2), None));
+    let third  = queued(admit(&limits, "app_1", Token(3), None));
+
+    limits.borrow_mut().set_limit("app_1", Some(RequestLimit { max_requests: 2, queue_size: 2 }));
+    assert_eq!(limits.borrow_mut().take_woken(), vec![Token(2)]);
+    let _second = held(admit(&limits, "app_1", Token(2), Some(second)));
+
+    limits.borrow_mut().set_limit("app_1", None);
+    assert_eq!(limits.borrow_mut().take_woken(), vec![Token(3)]);
+    match admit(&limits, "app_1", Token(3), Some(third)) {
+      Admission::Admitted(None) => {},
+      a => panic!("unexpected admission {:?}", a),
+    }
+  }
+}
@@ -1,8 +1,53 @@
 use rand::{self, Rng};
 
 use std::{cmp, time};
+use std::cell::Cell;
 use std::fmt::Debug;
 
+use sozu_command::proxy::RetryPolicyDefaults;
+
+pub const DEFAULT_MAX_TRIES: usize = 6;
+
+thread_local! {
+  /// maximum tries, and maximum interval between two tries in seconds
+  static DEFAULT_POLICY: Cell<(usize, Option<u64>)> = Cell::new((DEFAULT_MAX_TRIES, None));
+}
+
+/// retry policy given to the backends created from now on in this worker
+pub fn default_policy() -> RetryPolicyWrapper {
+    let (max_tries, max_interval) = DEFAULT_POLICY.with(|policy| policy.get());
+    let policy = ExponentialBackoffPolicy::new(max_tries);
+    match max_interval {
+        Some(secs) => policy.with_max_interval(time::Duration::from_secs(secs)),
+        None       => policy,
+    }.into()
+}
+
+/// changes the policy of future backends, existing ones keep their own
+pub fn set_default_policy(defaults: &RetryPolicyDefaults) -> Result<(), String> {
+    if defaults.max_tries == 0 {
+        return Err(String::from("max_tries must be at least 1"));
+    }
+    if defaults.max_interval == Some(0) {
+        return Err(String::from("max_interval must be at least 1 second"));
+    }
+
+    info!("new backends will be retried up to {} times", defaults.max_tries);
+    DEFAULT_POLICY.with(|policy| policy.set((defaults.max_tries, defaults.max_interval)));
+    Ok(())
+}
+
+/// state of the retry policy, in circuit breaker terms
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetryState {
+    /// no failure since the last success
+    Closed,
+    /// in backoff, the backend is not tried until the next retry
+    Open,
+    /// the backoff is over, the next connection tells if the backend recovered
+    HalfOpen,
+}
+
 #[derive(Debug, PartialEq, Eq)]
 pub enum RetryAction {
     OKAY,
@@ -37,7 +82,9 @@ pub struct ExponentialBackoffPolicy {
     max_tries: usize,
     current_tries: usize,
     last_try: time::Instant,
-    wait: time::Duration
+    wait: time::Duration,
+    /// longest wait between two tries, without it the wait doubles at each failure
+    max_interval: Option<time::Duration>,
 }
 
 impl ExponentialBackoffPolicy {
@@ -46,7 +93,54 @@ impl ExponentialBackoffPolicy {
             max_tries,
             current_tries: 0,
             last_try: time::Instant::now(),
-            wait: time::Duration::default()
+            wait: time::Duration::default(),
+            max_interval: None,
+        }
+    }
+
+    /// a backend that recovered is tried again after at most `max_interval`
+    pub fn with_max_interval(mut self, max_interval: time::Duration) -> Self {
+        self.max_interval = Some(max_interval);
+        self
+    }
+
+    pub fn max_interval(&self) -> Option<time::Duration> {
+        self.max_interval
+    }
+
+    pub fn state(&self) -> RetryState {
+        if self.current_tries == 0 {
+            RetryState::Closed
+        } else if self.next_retry().is_some() {
+            RetryState::Open
+        } else {
+            RetryState::HalfOpen
+        }
+    }
+
+    /// instant from which the backend can be tried again, None if it is not in backoff
+    pub fn next_retry(&self) -> Option<time::Instant> {
+        if self.last_try.elapsed() < self.wait {
+            Some(self.last_try + self.wait)
+        } else {
+            None
+        }
+    }
+
+    /// random wait before the next try, up to 2^current_tries seconds
+    fn interval(&self) -> time::Duration {
+        let max_secs = cmp::max(1, 1u64.wrapping_shl(self.current_tries as u32));
+        let wait = if max_secs == 1 {
+            1
+        } else {
+            let mut rng = rand::thread_rng();
+            rng.gen_range(1, max_secs)
+        };
+
+        let wait = time::Duration::from_secs(wait);
+        match self.max_interval {
+            Some(max_interval) => cmp::min(wait, max_interval),
+            None               => wait,
         }
     }
 }
@@ -66,15 +160,7 @@ impl RetryPolicy for ExponentialBackoffPolicy {
           return;
         }
 
-        let max_secs = cmp::max(1, 1u64.wrapping_shl(self.current_tries as u32));
-        let wait = if max_secs == 1 {
-            1
-        } else {
-            let mut rng = rand::thread_rng();
-            rng.gen_range(1, max_secs)
-        };
-
-        self.wait = time::Duration::from_secs(wait);
+        self.wait = self.interval();
         self.last_try = time::Instant::now();
         self.current_tries = cmp::min(self.current_tries + 1, self.max_tries);
 
@@ -108,6 +194,26 @@ impl Into<RetryPolicyWrapper> for ExponentialBackoffPolicy {
     }
 }
 
+impl RetryPolicyWrapper {
+    pub fn max_interval(&self) -> Option<time::Duration> {
+        match *self {
+            RetryPolicyWrapper::ExponentialBackoff(ref policy) => policy.max_interval()
+        }
+    }
+
+    pub fn state(&self) -> RetryState {
+        match *self {
+            RetryPolicyWrapper::ExponentialBackoff(ref policy) => policy.state()
+        }
+    }
+
+    pub fn next_retry(&self) -> Option<time::Instant> {
+        match *self {
+            RetryPolicyWrapper::ExponentialBackoff(ref policy) => policy.next_retry()
+        }
+    }
+}
+
 impl RetryPolicy for RetryPolicyWrapper {
     fn max_tries(&self) -> usize {
         match *self {
@@ -148,7 +254,8 @@ impl RetryPolicy for RetryPolicyWrapper {
 
 #[cfg(test)]
 mod tests {
-    use super::{RetryAction, RetryPolicy, ExponentialBackoffPolicy};
+    use super::{RetryAction, RetryPolicy, RetryPolicyWrapper, RetryState, ExponentialBackoffPolicy};
+    use std::time::{Duration, Instant};
 
     const MAX_FAILS: usize = 10;
 
@@ -201,4 +308,48 @@ mod tests {
 
         assert_eq!(Some(RetryAction::WAIT), can_try)
     }
+
+    #[test]
+    fn max_interval() {
+        let mut policy = ExponentialBackoffPolicy::new(64).with_max_interval(Duration::from_secs(60));
+        assert_eq!(policy.max_interval(), Some(Duration::from_secs(60)));
+
+        // after 20 failures, the wait could go up to 2^20 seconds
+        policy.current_tries = 20;
+        let intervals: Vec<Duration> = (0..100).map(|_| policy.interval()).collect();
+        assert!(intervals.iter().all(|interval| *interval <= Duration::from_secs(60)), "intervals over the cap: {:?}", intervals);
+        assert!(intervals.contains(&Duration::from_secs(60)));
+
+        policy.fail();
+        assert!(policy.wait <= Duration::from_secs(60));
+        assert_eq!(policy.current_tries(), 21);
+
+        // the first intervals stay under the cap
+        let mut policy = ExponentialBackoffPolicy::new(64).with_max_interval(Duration::from_secs(60));
+        policy.current_tries = 3;
+        assert!((0..100).all(|_| policy.interval() < Duration::from_secs(8)));
+    }
+
+    #[test]
+    fn retry_state() {
+        let mut policy: RetryPolicyWrapper = ExponentialBackoffPolicy::new(MAX_FAILS).into();
+        assert_eq!(policy.state(), RetryState::Closed);
+        assert_eq!(policy.next_retry(), None);
+
+        policy.fail();
+        assert_eq!(policy.state(), RetryState::Open);
+        let next_retry = policy.next_retry().expect("the backend should be in backoff");
+        assert!(next_retry > Instant::now());
+        assert!(next_retry <= Instant::now() + Duration::from_secs(1));
+
+        // the backoff is over
+        match policy {
+            RetryPolicyWrapper::ExponentialBackoff(ref mut policy) => policy.wait = Duration::default()
+        }
+        assert_eq!(policy.state(), RetryState::HalfOpen);
+        assert_eq!(policy.next_retry(), None);
+
+        policy.succeed();
+        assert_eq!(policy.state(), RetryState::Closed);
+    }
 }
@@ -0,0 +1,134 @@
+//! retry budget of the applications
+//!
+//! When many backends fail at once, every request retrying its connection
+//! multiplies the load on the ones left. Each application counts its requests
+//! and the retries of their backend connections, and a retry is only allowed
+//! while the retries stay below `percentage` of the requests, or below
+//! `min_retries`. Once the budget is exhausted, the failed requests get their
+//! error answer right away.
+//!
+//! As for the outlier detection, the counts of the previous window are kept and
+//! added to the current ones, so the budget does not reset at once when a
+//! window starts.
+use time::{Duration, SteadyTime};
+use sozu_command::config::RetryBudget;
+
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct RetryBudgetCounter {
+  window_start:      Option<SteadyTime>,
+  requests:          u32,
+  retries:           u32,
+  previous_requests: u32,
+  previous_retries:  u32,
+}
+
+impl RetryBudgetCounter {
+  pub fn new() -> RetryBudgetCounter {
+    RetryBudgetCounter {
+      window_start:      None,
+      requests:          0,
+      retries:           0,
+      previous_requests: 0,
+      previous_retries:  0,
+    }
+  }
+
+  pub fn record_request(&mut self, config: &RetryBudget, now: SteadyTime) {
+    self.rotate(config, now);
+    self.requests = self.requests.saturating_add(1);
+  }
+
+  /// counts a retry and returns true if the budget allows it
+  pub fn take_retry(&mut self, config: &RetryBudget, now: SteadyTime) -> bool {
+    self.rotate(config, now);
+
+    let requests = u64::from(self.requests) + u64::from(self.previous_requests);
+    let retries = u64::from(self.retries) + u64::from(self.previous_retries);
+    let allowed = ::std::cmp::max(u64::from(config.min_retries), requests * u64::from(config.percentage) / 100);
+    if retries < allowed {
+      self.retries += 1;
+      true
+    } else {
+      false
+    }
+  }
+
+  /// starts a new window if the current one is over
+  fn rotate(&mut self, config: &RetryBudget, now: SteadyTime) {
+    let window = Duration::seconds(i64::from(config.window.max(1)));
+    match self.window_start {
+      Some(start) if now - start < window => {},
+      Some(start) if now - start < window * 2 => {
+        self.previous_requests = self.requests;
+        self.previous_retries  = self.retries;
+        self.requests          = 0;
+        self.retries           = 0;
+        self.window_start      = Some(start + window);
+      },
+      _ => {
+        *self = RetryBudgetCounter::new();
+        self.window_start = Some(now);
+      },
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn config() -> RetryBudget {
+    RetryBudget { percentage: 10, min_retries: 2, window: 10 }
+  }
+
+  #[test]
+  fn retries_under_budget() {
+    let config = config();
+    let now = SteadyTime::now();
+    let mut counter = RetryBudgetCounter::new();
+
+    // the minimum is allowed without traffic
+    assert!(counter.take_retry(&config, now));
+    assert!(counter.take_retry(&config, now));
+    assert!(!counter.take_retry(&config, now));
+
+    // 10% of 50 requests
+    for _ in 0..50 {
+      counter.record_request(&config, now);
+    }
+    for _ in 0..3 {
+      assert!(counter.take_retry(&config, now));
+    }
+    assert!(!counter.take_retry(&config, now));
+  }
+
+  #[test]
+  fn budget_recovers_as_the_window_rolls() {
+    let config = config();
+    let now = SteadyTime::now();
+    let mut counter = RetryBudgetCounter::new();
+
+    for _ in 0..30 {
+      counter.record_request(&config, now);
+    }
+    for _ in 0..3 {
+      assert!(counter.take_retry(&config, now));
+    }
+    assert!(!counter.take_retry(&config, now));
+
+    // the retries of the previous window still count
+    let next_window = now + Duration::seconds(12);
+    assert!(!counter.take_retry(&config, next_window));
+    for _ in 0..10 {
+      counter.record_request(&config, next_window);
+    }
+    assert!(counter.take_retry(&config, next_window));
+    assert!(!counter.take_retry(&config, next_window));
+
+    // then they are forgotten
+    let later = now + Duration::seconds(40);
+    assert!(counter.take_retry(&config, later));
+    assert!(counter.take_retry(&config, later));
+    assert!(!counter.take_retry(&config, later));
+  }
+}
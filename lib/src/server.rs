@@ -1,37 +1,51 @@
-use std::net::SocketAddr;
+use std::net::{IpAddr,SocketAddr};
+use std::io::Write;
 use std::rc::Rc;
 use std::cell::RefCell;
 use mio::net::*;
 use mio::*;
 use mio::unix::UnixReady;
-use std::collections::{HashSet,VecDeque};
+use std::collections::{HashMap,HashSet,VecDeque};
 use std::os::unix::io::{AsRawFd,FromRawFd,IntoRawFd};
 use slab::Slab;
 use time::{self, SteadyTime};
 use std::time::Duration;
 use mio_extras::timer::{Timer, Timeout};
 
-use sozu_command::config::Config;
+use sozu_command::config::{Config,OutlierDetection,RetryBudget};
 use sozu_command::channel::Channel;
 use sozu_command::scm_socket::{Listeners,ScmSocket};
-use sozu_command::state::{ConfigState,get_application_ids_by_domain, get_certificate};
+use sozu_command::state::{ConfigState,get_application_ids_by_domain, get_certificate, list_certificates};
 use sozu_command::proxy::{ProxyRequestData,MessageId,ProxyResponse, ProxyEvent,
   ProxyResponseData,ProxyResponseStatus,ProxyRequest,Topic,Query,QueryAnswer,
   QueryApplicationType,TlsProvider,ListenerType,HttpsListener,QueryAnswerCertificate,
-  QueryCertificateType};
+  QueryCertificateType,QueryAnswerRoutes,QueryAnswerRouteMatch,ListenerRouteMatch,HttpFront,
+  ListenerTimeouts,ConnectionRateLimit};
 use sozu_command::buffer::Buffer;
+use sozu_command::logging;
 
 use {SessionResult,ConnectionError,Protocol,ProxySession,
-  CloseResult,AcceptError,BackendConnectAction,ProxyConfiguration,Backend};
+  CloseResult,AcceptError,BackendConnectAction,BackendConnectionStatus,ProxyConfiguration,Backend};
 use {http,tcp};
 use pool::Pool;
 use metrics::METRICS;
 use backends::BackendMap;
 use features::FEATURES;
-
-// Number of retries to perform on a server after a connection failure
+use tracing::TRACES;
+use retry;
+use buffer_queue;
+use outlier;
+use fd_limit::{self, FdLimit};
+use socket::{SocketConfig, configure_socket};
+use rate_limit::ConnectionRateLimiter;
+use session_limit::SessionLimit;
+
+// Default number of backend connection attempts for a request
 pub const CONN_RETRIES: u8 = 3;
 
+/// answer written to the HTTP connections refused by a listener's rate limit
+const RATE_LIMITED_ANSWER: &[u8] = b"HTTP/1.1 429 Too Many Requests\r\nCache-Control: no-cache\r\nConnection: close\r\nContent-Length: 0\r\n\r\n";
+
 pub type ProxyChannel = Channel<ProxyResponse,ProxyRequest>;
 
 thread_local! {
@@ -44,6 +58,72 @@ pub fn push_queue(message: ProxyResponse) {
   });
 }
 
+/// key of the counter of received orders, by order type
+pub fn order_metric_key(order: &ProxyRequestData) -> &'static str {
+  match *order {
+    ProxyRequestData::AddApplication(_)      => "orders.add_application",
+    ProxyRequestData::RemoveApplication(_)   => "orders.remove_application",
+    ProxyRequestData::PurgeApplication(_)    => "orders.purge_application",
+    ProxyRequestData::EnableApplication(_)   => "orders.enable_application",
+    ProxyRequestData::DisableApplication(_)  => "orders.disable_application",
+    ProxyRequestData::SetAppTimeout(_)       => "orders.set_app_timeout",
+    ProxyRequestData::AddHttpFront(_)        => "orders.add_http_front",
+    ProxyRequestData::RemoveHttpFront(_)     => "orders.remove_http_front",
+    ProxyRequestData::AddHttpsFront(_)       => "orders.add_https_front",
+    ProxyRequestData::RemoveHttpsFront(_)    => "orders.remove_https_front",
+    ProxyRequestData::AddCertificate(_)      => "orders.add_certificate",
+    ProxyRequestData::ReplaceCertificate(_)  => "orders.replace_certificate",
+    ProxyRequestData::RemoveCertificate(_)   => "orders.remove_certificate",
+    ProxyRequestData::SetOcspResponse(_)     => "orders.set_ocsp_response",
+    ProxyRequestData::AddAcmeChallenge(_)    => "orders.add_acme_challenge",
+    ProxyRequestData::RemoveAcmeChallenge(_) => "orders.remove_acme_challenge",
+    ProxyRequestData::AddTcpFront(_)         => "orders.add_tcp_front",
+    ProxyRequestData::RemoveTcpFront(_)      => "orders.remove_tcp_front",
+    ProxyRequestData::AddBackend(_)          => "orders.add_backend",
+    ProxyRequestData::RemoveBackend(_)       => "orders.remove_backend",
+    ProxyRequestData::ReplaceBackend(_)      => "orders.replace_backend",
+    ProxyRequestData::DrainBackend(_)        => "orders.drain_backend",
+    ProxyRequestData::ResetBackend(_)        => "orders.reset_backend",
+    ProxyRequestData::RebalanceApplication(_) => "orders.rebalance_application",
+    ProxyRequestData::SetTrafficSplit(_)     => "orders.set_traffic_split",
+    ProxyRequestData::AddHttpListener(_)     => "orders.add_http_listener",
+    ProxyRequestData::AddHttpsListener(_)    => "orders.add_https_listener",
+    ProxyRequestData::AddTcpListener(_)      => "orders.add_tcp_listener",
+    ProxyRequestData::RemoveListener(_)      => "orders.remove_listener",
+    ProxyRequestData::SetDefaultApp(_)       => "orders.set_default_app",
+    ProxyRequestData::ActivateListener(_)    => "orders.activate_listener",
+    ProxyRequestData::DeactivateListener(_)  => "orders.deactivate_listener",
+    ProxyRequestData::Query(_)               => "orders.query",
+    ProxyRequestData::SoftStop               => "orders.soft_stop",
+    ProxyRequestData::DrainAndStop(_)        => "orders.drain_and_stop",
+    ProxyRequestData::HardStop               => "orders.hard_stop",
+    ProxyRequestData::Status                 => "orders.status",
+    ProxyRequestData::Metrics                => "orders.metrics",
+    ProxyRequestData::ResetMetrics           => "orders.reset_metrics",
+    ProxyRequestData::Logging(_)             => "orders.logging",
+    ProxyRequestData::LogFormat(_)           => "orders.log_format",
+    ProxyRequestData::Trace(_)               => "orders.trace",
+    ProxyRequestData::TraceApp(_)            => "orders.trace_app",
+    ProxyRequestData::StreamAccessLogs(_)    => "orders.stream_access_logs",
+    ProxyRequestData::SetRetryPolicy(_)      => "orders.set_retry_policy",
+    ProxyRequestData::ReturnListenSockets    => "orders.return_listen_sockets",
+    ProxyRequestData::ConfigurationLoaded    => "orders.configuration_loaded",
+  }
+}
+
+/// returns true if one of the responses queued after the first `start`
+/// ones is an error for this order
+fn queued_error(id: &str, start: usize) -> bool {
+  QUEUE.with(|queue| {
+    queue.borrow().iter().skip(start).any(|response| {
+      response.id == id && match response.status {
+        ProxyResponseStatus::Error(_) => true,
+        _ => false,
+      }
+    })
+  })
+}
+
 pub fn push_event(event: ProxyEvent) {
   QUEUE.with(|queue| {
     (*queue.borrow_mut()).push_back(ProxyResponse {
@@ -54,6 +134,21 @@ pub fn push_event(event: ProxyEvent) {
   });
 }
 
+/// queues the access logs buffered by the logger while they are streamed
+fn push_access_logs() {
+  let logs = logging::LOGGER.with(|logger| logger.borrow_mut().take_streamed_access_logs());
+  QUEUE.with(|queue| {
+    let mut queue = queue.borrow_mut();
+    for log in logs {
+      queue.push_back(ProxyResponse {
+        id:     "ACCESS-LOG".to_string(),
+        status: ProxyResponseStatus::Processing,
+        data:   Some(ProxyResponseData::AccessLog(log))
+      });
+    }
+  });
+}
+
 #[derive(PartialEq)]
 pub enum ListenPortState {
   Available,
@@ -89,11 +184,62 @@ impl From<SessionToken> for usize {
     }
 }
 
+/// timeouts applied to a session, from the global configuration
+/// or from the listener that accepted it
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub struct SessionTimeouts {
+  pub front:   time::Duration,
+  /// sessions that did not transfer any data for this duration are closed
+  pub idle:    Option<time::Duration>,
+  /// HTTP requests whose header block is not received in this duration,
+  /// from their first byte, get a 408 answer
+  pub header:  Option<time::Duration>,
+  pub connect: time::Duration,
+}
+
+impl SessionTimeouts {
+  /// replaces the global timeouts with the ones set on the listener
+  pub fn for_listener(&self, listener: &ListenerTimeouts) -> SessionTimeouts {
+    let seconds = |t: u32| time::Duration::seconds(i64::from(t));
+
+    SessionTimeouts {
+      front:   listener.front_timeout.map(seconds).unwrap_or(self.front),
+      idle:    listener.idle_timeout.map(seconds).or(self.idle),
+      header:  listener.header_timeout.map(seconds).or(self.header),
+      connect: listener.connect_timeout.map(seconds).unwrap_or(self.connect),
+    }
+  }
+
+  /// delay before the first timeout check of a new session
+  pub fn first(&self) -> time::Duration {
+    [self.idle, self.header].iter().filter_map(|t| *t).fold(self.front, ::std::cmp::min)
+  }
+}
+
 pub struct ServerConfig {
   pub max_connections:          usize,
   pub front_timeout:            u32,
   pub zombie_check_interval:    u32,
   pub accept_queue_timeout:     u32,
+  pub max_accepts_per_tick:     usize,
+  pub max_orders_per_tick:      usize,
+  pub fd_soft_limit:            Option<usize>,
+  pub idle_timeout:             Option<u32>,
+  pub header_timeout:           Option<u32>,
+  pub connect_timeout:          u32,
+  /// replaces front_timeout for the TCP sessions
+  pub tcp_front_timeout:        Option<u32>,
+  /// replaces connect_timeout for the TCP sessions
+  pub tcp_connect_timeout:      Option<u32>,
+  pub backend_failure_cooldown: Option<u32>,
+  pub backend_connection_attempts: u8,
+  pub retry_non_idempotent_requests: bool,
+  pub backend_pool_max_idle:    usize,
+  pub backend_pool_idle_timeout: u32,
+  pub buffer_high_water_mark:   Option<usize>,
+  pub socket_config:            SocketConfig,
+  pub outlier_detection:        Option<OutlierDetection>,
+  pub retry_budget:             Option<RetryBudget>,
 }
 
 impl ServerConfig {
@@ -103,6 +249,23 @@ impl ServerConfig {
       front_timeout: config.front_timeout,
       zombie_check_interval: config.zombie_check_interval,
       accept_queue_timeout: config.accept_queue_timeout,
+      max_accepts_per_tick: config.max_accepts_per_tick,
+      max_orders_per_tick: config.max_orders_per_tick,
+      fd_soft_limit: config.fd_soft_limit,
+      idle_timeout: config.idle_timeout,
+      header_timeout: config.header_timeout,
+      connect_timeout: config.connect_timeout,
+      tcp_front_timeout: config.tcp_front_timeout,
+      tcp_connect_timeout: config.tcp_connect_timeout,
+      backend_failure_cooldown: config.backend_failure_cooldown,
+      backend_connection_attempts: config.backend_connection_attempts,
+      retry_non_idempotent_requests: config.retry_non_idempotent_requests,
+      backend_pool_max_idle: config.backend_pool_max_idle,
+      backend_pool_idle_timeout: config.backend_pool_idle_timeout,
+      buffer_high_water_mark: config.buffer_high_water_mark,
+      socket_config: SocketConfig { keepalive: config.tcp_keepalive },
+      outlier_detection: config.outlier_detection,
+      retry_budget: config.retry_budget,
     }
   }
 }
@@ -114,6 +277,23 @@ impl Default for ServerConfig {
       front_timeout: 60,
       zombie_check_interval: 30*60,
       accept_queue_timeout: 60,
+      max_accepts_per_tick: 1024,
+      max_orders_per_tick: 128,
+      fd_soft_limit: None,
+      idle_timeout: None,
+      header_timeout: None,
+      connect_timeout: 3,
+      tcp_front_timeout: None,
+      tcp_connect_timeout: None,
+      backend_failure_cooldown: None,
+      backend_connection_attempts: CONN_RETRIES,
+      retry_non_idempotent_requests: true,
+      backend_pool_max_idle: 0,
+      backend_pool_idle_timeout: 30,
+      buffer_high_water_mark: None,
+      socket_config: SocketConfig::default(),
+      outlier_detection: None,
+      retry_budget: None,
     }
   }
 }
@@ -121,8 +301,15 @@ impl Default for ServerConfig {
 pub struct Server {
   pub poll:        Poll,
   shutting_down:   Option<MessageId>,
+  /// with a drain timeout, the sessions still active at this time are closed
+  drain_deadline:  Option<SteadyTime>,
+  /// sessions closed by the drain timeout, reported in the stop answer
+  drained_sessions: Option<usize>,
   accept_ready:    HashSet<ListenToken>,
   can_accept:      bool,
+  /// false until the initial configuration is loaded, the pending
+  /// connections are kept in the listen queue in the meantime
+  configured:      bool,
   channel:         ProxyChannel,
   http:            http::Proxy,
   https:           HttpsProvider,
@@ -132,23 +319,54 @@ pub struct Server {
   sessions:        Slab<Rc<RefCell<ProxySessionCast>>,SessionToken>,
   max_connections: usize,
   nb_connections:  usize,
-  front_timeout:   time::Duration,
+  timeouts:        SessionTimeouts,
+  /// global timeouts of the TCP sessions, before the listener ones
+  tcp_timeouts:    SessionTimeouts,
+  /// timeouts of the sessions accepted on listeners overriding the global ones,
+  /// indexed by frontend token
+  session_timeouts: HashMap<SessionToken, SessionTimeouts>,
   timer:           Timer<Token>,
   pool:            Rc<RefCell<Pool<Buffer>>>,
+  /// buffers of the TCP sessions, the same pool as the HTTP sessions
+  /// unless the TCP buffers have a different size
+  tcp_pool:        Rc<RefCell<Pool<Buffer>>>,
   backends:        Rc<RefCell<BackendMap>>,
   scm_listeners:   Option<Listeners>,
   zombie_check_interval: time::Duration,
   accept_queue:    VecDeque<(TcpStream, ListenToken, Protocol, SteadyTime)>,
   accept_queue_timeout: time::Duration,
+  max_accepts_per_tick: usize,
+  accepts_in_tick: usize,
+  max_orders_per_tick: usize,
+  orders_in_tick:  usize,
+  /// the order limit was reached, there might be orders left in the channel
+  orders_pending:  bool,
   base_sessions_count: usize,
+  fd_limit:        FdLimit,
+  socket_config:   SocketConfig,
+  /// connection rate limits of the listeners configuring one
+  rate_limiters:   HashMap<ListenToken, ConnectionRateLimiter>,
+  /// sessions of the listeners configuring a maximum
+  session_limits:  HashMap<ListenToken, SessionLimit>,
+  /// listener of the sessions counted in session_limits, indexed by frontend token
+  session_listeners: HashMap<SessionToken, ListenToken>,
 }
 
 impl Server {
   pub fn new_from_config(channel: ProxyChannel, scm: ScmSocket, config: Config, config_state: ConfigState) -> Self {
     let event_loop  = Poll::new().expect("could not create event loop");
+    let http_buffer_size = config.http_buffer_size;
     let pool = Rc::new(RefCell::new(
-      Pool::with_capacity(2*config.max_buffers, 0, || Buffer::with_capacity(config.buffer_size))
+      Pool::with_capacity(2*config.max_buffers, 0, || Buffer::with_capacity(http_buffer_size))
     ));
+    let tcp_buffer_size = config.tcp_buffer_size;
+    let tcp_pool = if tcp_buffer_size == http_buffer_size {
+      None
+    } else {
+      Some(Rc::new(RefCell::new(
+        Pool::with_capacity(2*config.max_buffers, 0, || Buffer::with_capacity(tcp_buffer_size))
+      )))
+    };
     let backends = Rc::new(RefCell::new(BackendMap::new()));
 
     //FIXME: we will use a few entries for the channel, metrics socket and the listeners
@@ -174,12 +392,14 @@ impl Server {
     let https = HttpsProvider::new(use_openssl, pool.clone(), backends.clone());
 
     let server_config = ServerConfig::from_config(&config);
-    Server::new(event_loop, channel, scm, sessions, pool, backends, None, Some(https), None, server_config, Some(config_state))
+    Server::new(event_loop, channel, scm, sessions, pool, tcp_pool, backends, None, Some(https), None,
+      server_config, Some(config_state))
   }
 
   pub fn new(poll: Poll, channel: ProxyChannel, scm: ScmSocket,
     sessions: Slab<Rc<RefCell<ProxySessionCast>>,SessionToken>,
     pool: Rc<RefCell<Pool<Buffer>>>,
+    tcp_pool: Option<Rc<RefCell<Pool<Buffer>>>>,
     backends: Rc<RefCell<BackendMap>>,
     http: Option<http::Proxy>,
     https: Option<HttpsProvider>,
@@ -198,6 +418,21 @@ impl Server {
       PollOpt::edge()
     ).expect("should register the channel");
 
+    backends.borrow_mut().failure_cooldown = server_config.backend_failure_cooldown
+      .map(|cooldown| time::Duration::seconds(i64::from(cooldown)));
+    backends.borrow_mut().socket_config = server_config.socket_config;
+    backends.borrow_mut().connection_attempts = server_config.backend_connection_attempts;
+    backends.borrow_mut().retry_non_idempotent = server_config.retry_non_idempotent_requests;
+    backends.borrow_mut().retry_budget = server_config.retry_budget;
+    {
+      let backends = backends.borrow();
+      let mut connection_pool = backends.connection_pool.borrow_mut();
+      connection_pool.max_idle = server_config.backend_pool_max_idle;
+      connection_pool.idle_timeout = time::Duration::seconds(i64::from(server_config.backend_pool_idle_timeout));
+    }
+    buffer_queue::set_high_water_mark(server_config.buffer_high_water_mark);
+    outlier::set_outlier_detection(server_config.outlier_detection);
+
     let timer = Timer::default();
     poll.register(
       &timer,
@@ -212,13 +447,30 @@ impl Server {
       }
     });
 
+    let timeouts = SessionTimeouts {
+      front:   time::Duration::seconds(i64::from(server_config.front_timeout)),
+      idle:    server_config.idle_timeout.map(|t| time::Duration::seconds(i64::from(t))),
+      header:  server_config.header_timeout.map(|t| time::Duration::seconds(i64::from(t))),
+      connect: time::Duration::seconds(i64::from(server_config.connect_timeout)),
+    };
+    let tcp_timeouts = timeouts.for_listener(&ListenerTimeouts {
+      front_timeout:   server_config.tcp_front_timeout,
+      connect_timeout: server_config.tcp_connect_timeout,
+      ..Default::default()
+    });
+
     let base_sessions_count = sessions.len();
+    // without a configuration state, the proxy is configured directly through the channel
+    let configured = config_state.is_none();
 
     let mut server = Server {
       poll,
       shutting_down:   None,
+      drain_deadline:  None,
+      drained_sessions: None,
       accept_ready:    HashSet::new(),
       can_accept:      true,
+      configured,
       channel,
       http:            http.unwrap_or_else(|| http::Proxy::new(pool.clone(), backends.clone())),
       https:           https.unwrap_or_else(|| HttpsProvider::new(false, pool.clone(), backends.clone())),
@@ -230,13 +482,27 @@ impl Server {
       nb_connections:  0,
       scm_listeners:   None,
       timer,
+      tcp_pool:        tcp_pool.unwrap_or_else(|| pool.clone()),
       pool,
       backends,
-      front_timeout: time::Duration::seconds(i64::from(server_config.front_timeout)),
+      timeouts,
+      tcp_timeouts,
+      session_timeouts: HashMap::new(),
       zombie_check_interval: time::Duration::seconds(i64::from(server_config.zombie_check_interval)),
       accept_queue:    VecDeque::new(),
       accept_queue_timeout: time::Duration::seconds(i64::from(server_config.accept_queue_timeout)),
+      // with a limit of 0, we would never accept new sessions
+      max_accepts_per_tick: ::std::cmp::max(1, server_config.max_accepts_per_tick),
+      accepts_in_tick: 0,
+      max_orders_per_tick: ::std::cmp::max(1, server_config.max_orders_per_tick),
+      orders_in_tick:  0,
+      orders_pending:  false,
       base_sessions_count,
+      fd_limit:        FdLimit::from_config(server_config.fd_soft_limit),
+      socket_config:   server_config.socket_config,
+      rate_limiters:   HashMap::new(),
+      session_limits:  HashMap::new(),
+      session_listeners: HashMap::new(),
     };
 
     // initialize the worker with the state we got from a file
@@ -274,6 +540,7 @@ impl Server {
     //FIXME: make those parameters configurable?
     let mut events = Events::with_capacity(1024);
     let poll_timeout = Some(Duration::from_millis(1000));
+    let no_timeout = Some(Duration::from_millis(0));
     let max_poll_errors = 10000;
     let mut current_poll_errors = 0;
     let mut last_zombie_check = SteadyTime::now();
@@ -285,7 +552,20 @@ impl Server {
         panic!("poll() calls failed {} times in a row", current_poll_errors);
       }
 
-      if let Err(error) = self.poll.poll(&mut events, poll_timeout) {
+      // if the accept limit was reached during the last iteration, some sockets
+      // are still waiting in the listen queue, and we will not get a new event
+      // for them, so we should not wait in poll(). Same for the orders left
+      // in the command channel
+      let timeout = if (self.can_accept && self.configured && !self.accept_ready.is_empty())
+        || self.orders_pending {
+        no_timeout
+      } else {
+        poll_timeout
+      };
+      self.accepts_in_tick = 0;
+      self.orders_in_tick = 0;
+
+      if let Err(error) = self.poll.poll(&mut events, timeout) {
         error!("Error while polling events: {:?}", error);
         current_poll_errors += 1;
         continue;
@@ -295,6 +575,10 @@ impl Server {
 
       self.send_queue();
 
+      if self.orders_pending && !self.handle_channel(&mut last_sessions_len) {
+        return;
+      }
+
       for event in events.iter() {
         if event.token() == Token(0) {
           let kind = event.readiness();
@@ -307,71 +591,9 @@ impl Server {
             continue;
           }
           self.channel.handle_events(kind);
-
-          // loop here because iterations has borrow issues
-          loop {
-            QUEUE.with(|queue| {
-              if !(*queue.borrow()).is_empty() {
-                self.channel.interest.insert(Ready::writable());
-              }
-            });
-
-            //trace!("WORKER[{}] channel readiness={:?}, interest={:?}, queue={} elements",
-            //  line!(), self.channel.readiness, self.channel.interest, self.queue.len());
-            if self.channel.readiness() == Ready::empty() {
-              break;
-            }
-
-            if self.channel.readiness().is_readable() {
-              if let Err(e) = self.channel.readable() {
-                error!("error reading from channel: {:?}", e);
-              }
-
-              loop {
-                let msg = self.channel.read_message();
-
-                // if the message was too large, we grow the buffer and retry to read if possible
-                if msg.is_none() {
-                  if (self.channel.interest & self.channel.readiness).is_readable() {
-                    if let Err(e) = self.channel.readable() {
-                      error!("error reading from channel: {:?}", e);
-                    }
-                    continue;
-                  } else {
-                    break;
-                  }
-                }
-
-                let msg = msg.expect("the message should be valid");
-                if let ProxyRequestData::HardStop = msg.order {
-                  let id_msg = msg.id.clone();
-                  self.notify(msg);
-                  self.channel.write_message(&ProxyResponse{ id: id_msg, status: ProxyResponseStatus::Ok, data: None});
-                  self.channel.run();
-                  return;
-                } else if let ProxyRequestData::SoftStop = msg.order {
-                  self.shutting_down = Some(msg.id.clone());
-                  last_sessions_len = self.sessions.len();
-                  self.notify(msg);
-                } else if let ProxyRequestData::ReturnListenSockets = msg.order {
-                  info!("received ReturnListenSockets order");
-                  self.return_listen_sockets();
-                } else {
-                  self.notify(msg);
-                }
-
-              }
-            }
-
-            QUEUE.with(|queue| {
-              if !(*queue.borrow()).is_empty() {
-                self.channel.interest.insert(Ready::writable());
-              }
-            });
-
-            self.send_queue();
+          if !self.handle_channel(&mut last_sessions_len) {
+            return;
           }
-
         } else if event.token() == Token(1) {
           while let Some(t) = self.timer.poll() {
             self.timeout(t);
@@ -386,6 +608,7 @@ impl Server {
       }
 
       self.handle_remaining_readiness();
+      self.wake_queued_requests();
       self.create_sessions();
 
       let now = SteadyTime::now();
@@ -447,6 +670,13 @@ impl Server {
       });
 
       if self.shutting_down.is_some() {
+        if self.drain_deadline.map(|deadline| SteadyTime::now() >= deadline).unwrap_or(false) {
+          self.drain_deadline = None;
+          let count = self.close_active_sessions();
+          warn!("drain timeout expired, closed {} active sessions", count);
+          self.drained_sessions = Some(count);
+        }
+
         let mut closing_tokens = HashSet::new();
         for session in self.sessions.iter_mut() {
           let res = session.borrow_mut().shutting_down();
@@ -469,7 +699,8 @@ impl Server {
           info!("last session stopped, shutting down!");
           self.channel.run();
           self.channel.set_blocking(true);
-          self.channel.write_message(&ProxyResponse{ id: self.shutting_down.take().expect("should have shut down correctly"), status: ProxyResponseStatus::Ok, data: None});
+          let data = self.drained_sessions.map(ProxyResponseData::DrainedSessions);
+          self.channel.write_message(&ProxyResponse{ id: self.shutting_down.take().expect("should have shut down correctly"), status: ProxyResponseStatus::Ok, data });
           return;
         } else if count < last_sessions_len {
           info!("shutting down, {} slab elements remaining (base: {})",
@@ -480,8 +711,112 @@ impl Server {
     }
   }
 
+  /// handles the orders received on the command channel, at most
+  /// `max_orders_per_tick` per event loop iteration, so that a flood of orders
+  /// does not delay the sessions. The remaining orders stay in the channel
+  /// and are handled in the next iterations. Returns false if the worker stops
+  fn handle_channel(&mut self, last_sessions_len: &mut usize) -> bool {
+    // loop here because iterations has borrow issues
+    loop {
+      QUEUE.with(|queue| {
+        if !(*queue.borrow()).is_empty() {
+          self.channel.interest.insert(Ready::writable());
+        }
+      });
+
+      // orders left in the buffer by the previous iteration are handled
+      // even if there is no new readiness event
+      if self.channel.readiness() == Ready::empty() && !self.orders_pending {
+        break;
+      }
+
+      if self.channel.readiness().is_readable() {
+        if let Err(e) = self.channel.readable() {
+          error!("error reading from channel: {:?}", e);
+        }
+      }
+
+      self.orders_pending = false;
+      loop {
+        if self.orders_in_tick >= self.max_orders_per_tick {
+          incr!("orders.throttled");
+          self.orders_pending = true;
+          break;
+        }
+
+        let msg = self.channel.read_message();
+
+        // if the message was too large, we grow the buffer and retry to read if possible
+        if msg.is_none() {
+          if (self.channel.interest & self.channel.readiness).is_readable() {
+            if let Err(e) = self.channel.readable() {
+              error!("error reading from channel: {:?}", e);
+            }
+            continue;
+          } else {
+            break;
+          }
+        }
+
+        self.orders_in_tick += 1;
+        let msg = msg.expect("the message should be valid");
+        incr!("orders.processed");
+        incr!(order_metric_key(&msg.order));
+        let id = msg.id.clone();
+        let queued = QUEUE.with(|queue| queue.borrow().len());
+        let start = SteadyTime::now();
+
+        if let ProxyRequestData::HardStop = msg.order {
+          let id_msg = msg.id.clone();
+          self.notify(msg);
+          self.channel.write_message(&ProxyResponse{ id: id_msg, status: ProxyResponseStatus::Ok, data: None});
+          self.channel.run();
+          return false;
+        } else if let ProxyRequestData::SoftStop = msg.order {
+          self.shutting_down = Some(msg.id.clone());
+          *last_sessions_len = self.sessions.len();
+          self.notify(msg);
+        } else if let ProxyRequestData::DrainAndStop(timeout) = msg.order {
+          info!("{} draining the sessions for at most {} seconds", msg.id, timeout);
+          self.shutting_down = Some(msg.id.clone());
+          self.drain_deadline = Some(SteadyTime::now() + time::Duration::seconds(i64::from(timeout)));
+          self.drained_sessions = Some(0);
+          *last_sessions_len = self.sessions.len();
+          // the proxies close their listeners like for a soft stop
+          self.notify(ProxyRequest { id: msg.id, order: ProxyRequestData::SoftStop });
+        } else if let ProxyRequestData::ReturnListenSockets = msg.order {
+          info!("received ReturnListenSockets order");
+          self.return_listen_sockets();
+        } else {
+          self.notify(msg);
+        }
+
+        time!("orders.processing_time", (SteadyTime::now() - start).num_milliseconds());
+        if queued_error(&id, queued) {
+          incr!("orders.errors");
+        }
+      }
+
+      QUEUE.with(|queue| {
+        if !(*queue.borrow()).is_empty() {
+          self.channel.interest.insert(Ready::writable());
+        }
+      });
+
+      self.send_queue();
+
+      if self.orders_pending {
+        break;
+      }
+    }
+
+    true
+  }
+
   fn send_queue(&mut self) {
     if self.channel.readiness.is_writable() {
+      // the logger keeps a bounded number of access logs until the channel can send them
+      push_access_logs();
       QUEUE.with(|q| {
         let mut queue = q.borrow_mut();
         loop {
@@ -512,6 +847,7 @@ impl Server {
   fn notify(&mut self, message: ProxyRequest) {
     if let ProxyRequestData::Metrics = message.order {
       //let id = message.id.clone();
+      self.update_session_gauges();
       METRICS.with(|metrics| {
         push_queue(ProxyResponse {
           id:     message.id.clone(),
@@ -524,6 +860,60 @@ impl Server {
       return;
     }
 
+    if let ProxyRequestData::ResetMetrics = message.order {
+      let count = METRICS.with(|metrics| (*metrics.borrow_mut()).reset_local());
+      info!("{} reset {} metric series", message.id, count);
+      push_queue(ProxyResponse {
+        id:     message.id.clone(),
+        status: ProxyResponseStatus::Ok,
+        data:   Some(ProxyResponseData::ResetMetrics(count)),
+      });
+      return;
+    }
+
+    if let ProxyRequestData::Trace(ref trace) = message.order {
+      TRACES.with(|traces| traces.borrow_mut().add(trace.clone()));
+      push_queue(ProxyResponse {
+        id:     message.id.clone(),
+        status: ProxyResponseStatus::Ok,
+        data:   None,
+      });
+      return;
+    }
+
+    if let ProxyRequestData::TraceApp(ref trace) = message.order {
+      TRACES.with(|traces| traces.borrow_mut().set_app(&trace.app_id, trace.enabled));
+      push_queue(ProxyResponse {
+        id:     message.id.clone(),
+        status: ProxyResponseStatus::Ok,
+        data:   None,
+      });
+      return;
+    }
+
+    if let ProxyRequestData::StreamAccessLogs(enabled) = message.order {
+      logging::LOGGER.with(|logger| logger.borrow_mut().set_access_log_stream(enabled));
+      push_queue(ProxyResponse {
+        id:     message.id.clone(),
+        status: ProxyResponseStatus::Ok,
+        data:   None,
+      });
+      return;
+    }
+
+    if let ProxyRequestData::SetRetryPolicy(ref defaults) = message.order {
+      let status = match retry::set_default_policy(defaults) {
+        Ok(()) => ProxyResponseStatus::Ok,
+        Err(e) => ProxyResponseStatus::Error(e),
+      };
+      push_queue(ProxyResponse {
+        id:     message.id.clone(),
+        status,
+        data:   None,
+      });
+      return;
+    }
+
     if let ProxyRequestData::Query(ref query) = message.order {
       match query {
         &Query::ApplicationsHashes => {
@@ -556,6 +946,38 @@ impl Server {
           });
           return;
         },
+        &Query::Routes => {
+          push_queue(ProxyResponse {
+            id:     message.id.clone(),
+            status: ProxyResponseStatus::Ok,
+            data:   Some(ProxyResponseData::Query(QueryAnswer::Routes(QueryAnswerRoutes {
+              http:  self.http.routes(),
+              https: self.https.routes(),
+            })))
+          });
+          return;
+        },
+        &Query::RouteMatch(ref q) => {
+          push_queue(ProxyResponse {
+            id:     message.id.clone(),
+            status: ProxyResponseStatus::Ok,
+            data:   Some(ProxyResponseData::Query(QueryAnswer::RouteMatch(QueryAnswerRouteMatch {
+              http:  self.http.route_match(&q.hostname, q.method.as_ref().map(|m| m.as_str()), &q.path),
+              https: self.https.route_match(&q.hostname, q.method.as_ref().map(|m| m.as_str()), &q.path),
+            })))
+          });
+          return;
+        },
+        &Query::BackendStats(ref q) => {
+          push_queue(ProxyResponse {
+            id:     message.id.clone(),
+            status: ProxyResponseStatus::Ok,
+            data:   Some(ProxyResponseData::Query(QueryAnswer::BackendStats(
+              self.backends.borrow().backend_stats(&q.app_id, &q.backend_id)
+            )))
+          });
+          return;
+        },
         &Query::Certificates(ref q) => {
           match q {
             // forward the query to the TLS implementation
@@ -572,6 +994,16 @@ impl Server {
               });
               return
             },
+            QueryCertificateType::List => {
+              push_queue(ProxyResponse {
+                id:     message.id.clone(),
+                status: ProxyResponseStatus::Ok,
+                data:   Some(ProxyResponseData::Query(QueryAnswer::Certificates(QueryAnswerCertificate::List(
+                  list_certificates(&self.config_state)
+                ))))
+              });
+              return
+            },
           }
         }
       }
@@ -587,11 +1019,25 @@ impl Server {
       ProxyRequest { order: ProxyRequestData::AddApplication(ref application), .. } => {
         self.backends.borrow_mut().set_load_balancing_policy_for_app(&application.app_id,
           application.load_balancing_policy);
+        self.backends.borrow_mut().set_fallback_backend_for_app(&application.app_id,
+          application.fallback_backend);
+        self.backends.borrow_mut().set_request_limit_for_app(&application.app_id,
+          application.request_limit);
+        //not returning because the message must still be handled by each proxy
+      },
+      ProxyRequest { order: ProxyRequestData::RemoveApplication(ref app_id), .. } => {
+        // the queued requests are sent without a limit
+        self.backends.borrow_mut().set_request_limit_for_app(app_id, None);
+        //not returning because the message must still be handled by each proxy
+      },
+      ProxyRequest { order: ProxyRequestData::PurgeApplication(ref app_id), .. } => {
+        self.backends.borrow_mut().remove_application(app_id);
         //not returning because the message must still be handled by each proxy
       },
       ProxyRequest { ref id, order: ProxyRequestData::AddBackend(ref backend) } => {
-        let new_backend = Backend::new(&backend.backend_id, backend.address,
+        let mut new_backend = Backend::new(&backend.backend_id, backend.address,
           backend.sticky_id.clone(), backend.load_balancing_parameters.clone(), backend.backup);
+        new_backend.hostname = backend.hostname.clone();
         self.backends.borrow_mut().add_backend(&backend.app_id, new_backend);
 
         let answer = ProxyResponse { id: id.to_string(), status: ProxyResponseStatus::Ok, data: None };
@@ -599,12 +1045,79 @@ impl Server {
         return;
       },
       ProxyRequest { ref id, order: ProxyRequestData::RemoveBackend(ref backend) } => {
-        self.backends.borrow_mut().remove_backend(&backend.app_id, &backend.address);
+        self.backends.borrow_mut().remove_backend(&backend.app_id, &backend.backend_id, &backend.address);
 
         let answer = ProxyResponse { id: id.to_string(), status: ProxyResponseStatus::Ok, data: None };
         push_queue(answer);
         return;
       },
+      ProxyRequest { ref id, order: ProxyRequestData::ReplaceBackend(ref backend) } => {
+        let status = match self.backends.borrow_mut().replace_backend_address(&backend.app_id,
+          &backend.backend_id, backend.address) {
+          Ok(()) => ProxyResponseStatus::Ok,
+          Err(e) => {
+            error!("could not replace backend {} of app {}: {}", backend.backend_id, backend.app_id, e);
+            ProxyResponseStatus::Error(e)
+          }
+        };
+
+        push_queue(ProxyResponse { id: id.to_string(), status, data: None });
+        return;
+      },
+      ProxyRequest { ref id, order: ProxyRequestData::DrainBackend(ref backend) } => {
+        let status = match self.backends.borrow_mut().drain_backend(&backend.app_id, &backend.backend_id) {
+          Ok(()) => ProxyResponseStatus::Ok,
+          Err(e) => {
+            error!("could not drain backend {} of app {}: {}", backend.backend_id, backend.app_id, e);
+            ProxyResponseStatus::Error(e)
+          }
+        };
+
+        push_queue(ProxyResponse { id: id.to_string(), status, data: None });
+        return;
+      },
+      ProxyRequest { ref id, order: ProxyRequestData::ResetBackend(ref backend) } => {
+        let status = match self.backends.borrow_mut().reset_backend(&backend.app_id, &backend.backend_id) {
+          Ok(()) => ProxyResponseStatus::Ok,
+          Err(e) => {
+            error!("could not reset backend {} of app {}: {}", backend.backend_id, backend.app_id, e);
+            ProxyResponseStatus::Error(e)
+          }
+        };
+
+        push_queue(ProxyResponse { id: id.to_string(), status, data: None });
+        return;
+      },
+      ProxyRequest { ref id, order: ProxyRequestData::RebalanceApplication(ref rebalance) } => {
+        let status = match self.backends.borrow_mut().rebalance(&rebalance.app_id, time::Duration::seconds(i64::from(rebalance.duration))) {
+          Ok(()) => ProxyResponseStatus::Ok,
+          Err(e) => {
+            error!("could not rebalance app {}: {}", rebalance.app_id, e);
+            ProxyResponseStatus::Error(e)
+          }
+        };
+
+        push_queue(ProxyResponse { id: id.to_string(), status, data: None });
+        return;
+      },
+      ProxyRequest { ref id, order: ProxyRequestData::ConfigurationLoaded } => {
+        if !self.configured {
+          info!("initial configuration loaded, accepting connections");
+          self.configured = true;
+        }
+
+        push_queue(ProxyResponse { id: id.to_string(), status: ProxyResponseStatus::Ok, data: None });
+        return;
+      },
+      // the worker is not ready to handle traffic yet
+      ProxyRequest { ref id, order: ProxyRequestData::Status } if !self.configured => {
+        push_queue(ProxyResponse {
+          id: id.to_string(),
+          status: ProxyResponseStatus::Error(String::from("waiting for the initial configuration")),
+          data: None
+        });
+        return;
+      },
       _ => {},
     };
 
@@ -682,6 +1195,17 @@ impl Server {
             push_queue(answer);
           }
         },
+        // Unix domain sockets are not sent to the new worker, it binds its own socket
+        ProxyRequest { ref id, order: ProxyRequestData::DeactivateListener(ref deactivate) }
+          if deactivate.proxy == ListenerType::HTTP && self.http.has_unix_listener(deactivate.front) => {
+          debug!("{} deactivate http unix socket listener {:?}", id, deactivate);
+          if let Some(token) = self.http.close_unix_listener(&mut self.poll, deactivate.front) {
+            if self.sessions.remove(token.0.into()).is_some() {
+              info!("removed listen token {:?}", token);
+            }
+          }
+          push_queue(ProxyResponse { id: id.to_string(), status: ProxyResponseStatus::Ok, data: None });
+        },
         ProxyRequest { ref id, order: ProxyRequestData::DeactivateListener(ref deactivate) } => {
           if deactivate.proxy == ListenerType::HTTP {
             debug!("{} deactivate http listener {:?}", id, deactivate);
@@ -868,7 +1392,7 @@ impl Server {
           let entry = entry.unwrap();
           let token = Token(entry.index().0);
 
-          let status = if self.tcp.add_listener(listener.clone(), self.pool.clone(), token).is_some() {
+          let status = if self.tcp.add_listener(listener.clone(), self.tcp_pool.clone(), token).is_some() {
             entry.insert(Rc::new(RefCell::new(ListenSession { protocol: Protocol::TCPListen })));
             self.base_sessions_count += 1;
             ProxyResponseStatus::Ok
@@ -999,10 +1523,41 @@ impl Server {
     Token(token.0)
   }
 
+  /// sets the gauges of the open sessions and of their backend connections,
+  /// sent with the metrics: a session is registered once for each of its tokens
+  pub fn update_session_gauges(&self) {
+    let mut front_tokens = HashSet::new();
+    let mut connecting = 0;
+    let mut connected = 0;
+    for session in self.sessions.iter() {
+      let session = session.borrow();
+      match session.tokens().first() {
+        Some(token) if front_tokens.insert(*token) => {},
+        _ => continue,
+      }
+
+      match session.back_connection_status() {
+        BackendConnectionStatus::Connecting   => connecting += 1,
+        BackendConnectionStatus::Connected    => connected += 1,
+        BackendConnectionStatus::NotConnected => {},
+      }
+    }
+
+    gauge!("sessions.front", front_tokens.len());
+    gauge!("sessions.back.connecting", connecting);
+    gauge!("sessions.back.connected", connected);
+    gauge!("backends.active_connections", self.backends.borrow().active_connections());
+  }
+
   pub fn close_session(&mut self, token: SessionToken) {
     if self.sessions.contains(token) {
       let session = self.sessions.remove(token).expect("session shoud be there");
       session.borrow().cancel_timeouts(&mut self.timer);
+      if let Some(front_token) = session.borrow().tokens().first() {
+        let front_token = self.to_session(*front_token);
+        self.session_timeouts.remove(&front_token);
+        self.remove_listener_session(front_token);
+      }
       let CloseResult { tokens } = session.borrow_mut().close(&mut self.poll);
 
       for tk in tokens.into_iter() {
@@ -1016,7 +1571,8 @@ impl Server {
     }
 
     // do not be ready to accept right away, wait until we get back to 10% capacity
-    if !self.can_accept && self.nb_connections < self.max_connections * 90 / 100 {
+    if !self.can_accept && self.nb_connections < self.max_connections * 90 / 100
+      && self.fd_limit.can_accept(self.open_fds()) {
       debug!("nb_connections = {}, max_connections = {}, starting to accept again", self.nb_connections, self.max_connections);
       gauge!("accept_queue.backpressure", 0);
       self.can_accept = true;
@@ -1031,6 +1587,7 @@ impl Server {
       return false;
     }
 
+    let timeouts = self.tcp_timeouts.for_listener(&self.tcp.listener_timeouts(token));
     //FIXME: we must handle separately the session limit since the sessions slab also has entries for listeners and backends
     let index = match self.sessions.vacant_entry() {
       None => {
@@ -1044,13 +1601,17 @@ impl Server {
       Some(entry) => {
         let session_token = Token(entry.index().0);
         let index = entry.index();
-        let timeout = self.timer.set_timeout(self.front_timeout.to_std().unwrap(), session_token);
+        let timeout = self.timer.set_timeout(timeouts.first().to_std().unwrap(), session_token);
         match self.tcp.create_session(socket, token, &mut self.poll, session_token, timeout, delay) {
           Ok((session, should_connect)) => {
             entry.insert(session);
+            if timeouts != self.timeouts {
+              self.session_timeouts.insert(SessionToken(session_token.0), timeouts);
+            }
             self.nb_connections += 1;
             assert!(self.nb_connections <= self.max_connections);
             gauge!("client.connections", self.nb_connections);
+            self.add_listener_session(token, Protocol::TCPListen, SessionToken(session_token.0));
 
             if should_connect {
               index
@@ -1088,6 +1649,7 @@ impl Server {
       return false;
     }
 
+    let timeouts = self.timeouts.for_listener(&self.http.listener_timeouts(token));
     //FIXME: we must handle separately the session limit since the sessions slab also has entries for listeners and backends
     match self.sessions.vacant_entry() {
       None => {
@@ -1099,13 +1661,17 @@ impl Server {
       },
       Some(entry) => {
         let session_token = Token(entry.index().0);
-        let timeout = self.timer.set_timeout(self.front_timeout.to_std().unwrap(), session_token);
+        let timeout = self.timer.set_timeout(timeouts.first().to_std().unwrap(), session_token);
         match self.http.create_session(socket, token, &mut self.poll, session_token, timeout, delay) {
           Ok((session, _)) => {
             entry.insert(session);
+            if timeouts != self.timeouts {
+              self.session_timeouts.insert(SessionToken(session_token.0), timeouts);
+            }
             self.nb_connections += 1;
             assert!(self.nb_connections <= self.max_connections);
             gauge!("client.connections", self.nb_connections);
+            self.add_listener_session(token, Protocol::HTTPListen, SessionToken(session_token.0));
             true
           },
           Err(AcceptError::IoError) => {
@@ -1135,6 +1701,7 @@ impl Server {
       return false;
     }
 
+    let timeouts = self.timeouts.for_listener(&self.https.listener_timeouts(token));
     //FIXME: we must handle separately the session limit since the sessions slab also has entries for listeners and backends
     match self.sessions.vacant_entry() {
       None => {
@@ -1146,13 +1713,17 @@ impl Server {
       },
       Some(entry) => {
         let session_token = Token(entry.index().0);
-        let timeout = self.timer.set_timeout(self.front_timeout.to_std().unwrap(), session_token);
+        let timeout = self.timer.set_timeout(timeouts.first().to_std().unwrap(), session_token);
         match self.https.create_session(socket, token, &mut self.poll, session_token, timeout, delay) {
           Ok((session, _)) => {
             entry.insert(session);
+            if timeouts != self.timeouts {
+              self.session_timeouts.insert(SessionToken(session_token.0), timeouts);
+            }
             self.nb_connections += 1;
             assert!(self.nb_connections <= self.max_connections);
             gauge!("client.connections", self.nb_connections);
+            self.add_listener_session(token, Protocol::HTTPSListen, SessionToken(session_token.0));
             true
           },
           Err(AcceptError::IoError) => {
@@ -1175,11 +1746,24 @@ impl Server {
   }
 
   pub fn accept(&mut self, token: ListenToken, protocol: Protocol) {
+    // an event received before the listener was paused
+    if self.session_limits.get(&token).map(|limit| limit.is_paused()).unwrap_or(false) {
+      self.accept_ready.remove(&token);
+      return;
+    }
+
     match protocol {
       Protocol::TCPListen   => {
-        loop {
+        while self.accepts_in_tick < self.max_accepts_per_tick {
+          if !self.check_fd_limit() {
+            break;
+          }
+
           match self.tcp.accept(token) {
-            Ok(sock) => self.accept_queue.push_back((sock, token, Protocol::TCPListen, SteadyTime::now())),
+            Ok(sock) => {
+              self.accepts_in_tick += 1;
+              self.accept_queue.push_back((sock, token, Protocol::TCPListen, SteadyTime::now()));
+            },
             Err(AcceptError::WouldBlock) => {
               self.accept_ready.remove(&token);
               break
@@ -1193,9 +1777,16 @@ impl Server {
         }
       },
       Protocol::HTTPListen  => {
-        loop {
+        while self.accepts_in_tick < self.max_accepts_per_tick {
+          if !self.check_fd_limit() {
+            break;
+          }
+
           match self.http.accept(token) {
-            Ok(sock) => self.accept_queue.push_back((sock, token, Protocol::HTTPListen, SteadyTime::now())),
+            Ok(sock) => {
+              self.accepts_in_tick += 1;
+              self.accept_queue.push_back((sock, token, Protocol::HTTPListen, SteadyTime::now()));
+            },
             Err(AcceptError::WouldBlock) => {
               self.accept_ready.remove(&token);
               break
@@ -1209,9 +1800,16 @@ impl Server {
         }
       },
       Protocol::HTTPSListen => {
-        loop {
+        while self.accepts_in_tick < self.max_accepts_per_tick {
+          if !self.check_fd_limit() {
+            break;
+          }
+
           match self.https.accept(token) {
-            Ok(sock) => self.accept_queue.push_back((sock, token, Protocol::HTTPSListen, SteadyTime::now())),
+            Ok(sock) => {
+              self.accepts_in_tick += 1;
+              self.accept_queue.push_back((sock, token, Protocol::HTTPSListen, SteadyTime::now()));
+            },
             Err(AcceptError::WouldBlock) => {
               self.accept_ready.remove(&token);
               break
@@ -1230,6 +1828,25 @@ impl Server {
     gauge!("accept_queue.count", self.accept_queue.len());
   }
 
+  /// every listener, frontend and backend socket has an entry in the sessions
+  /// slab, and accepted sockets wait in the accept queue before getting one
+  fn open_fds(&self) -> usize {
+    self.sessions.len() + self.accept_queue.len() + fd_limit::RESERVED_FDS
+  }
+
+  /// stops accepting new connections when the file descriptor
+  /// soft limit is reached, before accept() fails with EMFILE
+  fn check_fd_limit(&mut self) -> bool {
+    let open_fds = self.open_fds();
+    if self.fd_limit.can_accept(open_fds) {
+      true
+    } else {
+      gauge!("accept_queue.backpressure", 1);
+      self.can_accept = false;
+      false
+    }
+  }
+
   pub fn create_sessions(&mut self) {
     loop {
       if let Some((sock, token, protocol, timestamp)) = self.accept_queue.pop_back() {
@@ -1239,6 +1856,20 @@ impl Server {
           incr!("accept_queue.timeout");
           continue;
         }
+
+        if !self.check_rate_limit(token, protocol, &sock) {
+          continue;
+        }
+
+        // accepted in the same loop iteration as the session reaching the maximum
+        if !self.session_limits.get(&token).map(|limit| limit.can_accept()).unwrap_or(true) {
+          incr!("accept_queue.session_limit");
+          continue;
+        }
+
+        if let Err(e) = configure_socket(&sock, &self.socket_config) {
+          error!("could not set the options of the frontend socket: {:?}", e);
+        }
         //FIXME: check the timestamp
         match protocol {
           Protocol::TCPListen   => {
@@ -1266,6 +1897,167 @@ impl Server {
     gauge!("accept_queue.count", self.accept_queue.len());
   }
 
+  /// returns false if the listener's rate limit refuses the connection, which
+  /// is then closed. HTTP clients get a 429 answer first
+  fn check_rate_limit(&mut self, token: ListenToken, protocol: Protocol, sock: &TcpStream) -> bool {
+    let expects_proxy_protocol = match protocol {
+      Protocol::TCPListen   => self.tcp.listener_expects_proxy_protocol(token),
+      Protocol::HTTPListen  => self.http.listener_expects_proxy_protocol(token),
+      Protocol::HTTPSListen => self.https.listener_expects_proxy_protocol(token),
+      _ => false,
+    };
+
+    let now = SteadyTime::now();
+    let limiter = match self.rate_limiter(token, protocol, now) {
+      Some(limiter) => limiter,
+      None => return true,
+    };
+
+    // the peer is the upstream proxy, the client address is known once the header is parsed
+    if limiter.config.per_client && expects_proxy_protocol {
+      return true;
+    }
+
+    if limiter.accept(sock.peer_addr().ok().map(|address| address.ip()), now) {
+      return true;
+    }
+
+    incr!("accept_queue.rate_limited");
+    if protocol == Protocol::HTTPListen {
+      let _ = (&*sock).write(RATE_LIMITED_ANSWER);
+    }
+    false
+  }
+
+  /// returns false if the per client rate limit refuses the address conveyed by
+  /// the PROXY protocol header of a session accepted on this listener
+  fn check_proxy_protocol_rate_limit(&mut self, token: ListenToken, client: IpAddr) -> bool {
+    if !self.sessions.contains(SessionToken(token.0)) {
+      return true;
+    }
+
+    let protocol = self.sessions[SessionToken(token.0)].borrow().protocol();
+    let now = SteadyTime::now();
+    match self.rate_limiter(token, protocol, now) {
+      Some(limiter) => limiter.accept(Some(client), now),
+      None => true,
+    }
+  }
+
+  /// rate limiter of the listener, or None if the connections it accepts are not limited
+  fn rate_limiter(&mut self, token: ListenToken, protocol: Protocol, now: SteadyTime) -> Option<&mut ConnectionRateLimiter> {
+    let config = match protocol {
+      Protocol::TCPListen   => self.tcp.listener_rate_limit(token),
+      Protocol::HTTPListen  => self.http.listener_rate_limit(token),
+      Protocol::HTTPSListen => self.https.listener_rate_limit(token),
+      _ => None,
+    };
+
+    let config = match config {
+      Some(config) => config,
+      None => {
+        self.rate_limiters.remove(&token);
+        return None;
+      }
+    };
+
+    let limiter = self.rate_limiters.entry(token).or_insert_with(|| ConnectionRateLimiter::new(config, now));
+    // the listener was removed and added again with another limit
+    if limiter.config != config {
+      *limiter = ConnectionRateLimiter::new(config, now);
+    }
+
+    Some(limiter)
+  }
+
+  /// closes the client sessions, returns how many were closed
+  fn close_active_sessions(&mut self) -> usize {
+    let mut frontend_tokens = HashSet::new();
+    for session in self.sessions.iter() {
+      let session = session.borrow();
+      match session.protocol() {
+        Protocol::HTTPListen | Protocol::HTTPSListen | Protocol::TCPListen => {},
+        _ => {
+          frontend_tokens.insert(session.tokens()[0]);
+        },
+      }
+    }
+
+    for tk in frontend_tokens.iter() {
+      let session = self.to_session(*tk);
+      self.close_session(session);
+    }
+
+    frontend_tokens.len()
+  }
+
+  /// counts a session accepted on a listener configuring a maximum number of
+  /// sessions, and pauses the listener when it is reached
+  fn add_listener_session(&mut self, token: ListenToken, protocol: Protocol, session: SessionToken) {
+    let max_sessions = match protocol {
+      Protocol::TCPListen   => self.tcp.listener_max_sessions(token),
+      Protocol::HTTPListen  => self.http.listener_max_sessions(token),
+      Protocol::HTTPSListen => self.https.listener_max_sessions(token),
+      _ => None,
+    };
+
+    let max_sessions = match max_sessions {
+      Some(max_sessions) => max_sessions,
+      None => {
+        self.session_limits.remove(&token);
+        return;
+      }
+    };
+
+    let paused = {
+      let limit = self.session_limits.entry(token).or_insert_with(|| SessionLimit::new(max_sessions));
+      limit.set_max_sessions(max_sessions);
+      limit.add_session()
+    };
+    self.session_listeners.insert(session, token);
+
+    if paused {
+      info!("listener {:?} reached its maximum of {} sessions, pausing it", token, max_sessions);
+      incr!("listener.session_limit_reached");
+      self.pause_listener(token, protocol, true);
+    }
+  }
+
+  fn remove_listener_session(&mut self, session: SessionToken) {
+    let token = match self.session_listeners.remove(&session) {
+      Some(token) => token,
+      None => return,
+    };
+
+    let resumed = match self.session_limits.get_mut(&token) {
+      Some(limit) => limit.remove_session(),
+      None => false,
+    };
+
+    // the listener session might have been removed with the listener
+    if resumed && self.sessions.contains(SessionToken(token.0)) {
+      let protocol = self.sessions[SessionToken(token.0)].borrow().protocol();
+      info!("listener {:?} went back under its maximum number of sessions, resuming it", token);
+      self.pause_listener(token, protocol, false);
+    }
+  }
+
+  fn pause_listener(&mut self, token: ListenToken, protocol: Protocol, paused: bool) {
+    match protocol {
+      Protocol::TCPListen   => self.tcp.pause_listener(&mut self.poll, token, paused),
+      Protocol::HTTPListen  => self.http.pause_listener(&mut self.poll, token, paused),
+      Protocol::HTTPSListen => self.https.pause_listener(&mut self.poll, token, paused),
+      _ => {},
+    }
+
+    if paused {
+      self.accept_ready.remove(&token);
+    } else {
+      // connections might be waiting in the backlog
+      self.accept_ready.insert(token);
+    }
+  }
+
   pub fn connect_to_backend(&mut self, token: SessionToken) {
     if ! self.sessions.contains(token) {
       error!("invalid token in connect_to_backend");
@@ -1320,17 +2112,52 @@ impl Server {
       Ok(BackendConnectAction::Reuse) => {
         debug!("keepalive, reusing backend connection");
       }
-      Ok(BackendConnectAction::Replace) => {
+      Ok(BackendConnectAction::Queued) => {
+        debug!("the request waits for a slot of its application");
+        return;
       },
-      Ok(BackendConnectAction::New) => {
+      Ok(BackendConnectAction::Replace) | Ok(BackendConnectAction::New) => {
+        // the connection is non blocking: if the backend never completes
+        // the handshake, the session will try another one once this expires
+        let connect_timeout = self.session_timeouts(token).connect;
+        if let Some(back_token) = self.sessions[token].borrow().tokens().get(1) {
+          self.timer.set_timeout(connect_timeout.to_std().unwrap(), *back_token);
+        }
       },
-      Err(ConnectionError::HostNotFound) | Err(ConnectionError::NoBackendAvailable) |
-        Err(ConnectionError::HttpsRedirect) | Err(ConnectionError::InvalidHost) => {
+      Err(e) => {
+        incr!(e.metric_name());
+        // the HTTP proxies answer the client with a status matching the error,
+        // the session is closed once it is sent
         if protocol == Protocol::TCP {
           self.close_session(token);
         }
+        return;
       },
-      _ => self.close_session(token),
+    }
+
+    // the session answers 504 if the backend did not answer when this expires
+    let session = self.sessions[token].borrow();
+    if let (Some(left), Some(back_token)) = (session.response_time_left(), session.tokens().get(1).cloned()) {
+      self.timer.set_timeout(left.to_std().unwrap_or_default(), back_token);
+    }
+  }
+
+  /// the sessions that got a slot in the concurrency limit of their application
+  /// connect to a backend and send their request, or their error answer
+  fn wake_queued_requests(&mut self) {
+    loop {
+      let woken = self.backends.borrow().request_limits.borrow_mut().take_woken();
+      if woken.is_empty() {
+        break;
+      }
+
+      for token in woken {
+        let session_token = self.to_session(token);
+        if self.sessions.contains(session_token) {
+          self.connect_to_backend(session_token);
+          self.ready(token, Ready::empty());
+        }
+      }
     }
   }
 
@@ -1377,7 +2204,7 @@ impl Server {
           //info!("PROTOCOL IS LISTEN");
           if events.is_readable() {
             self.accept_ready.insert(ListenToken(token.0));
-            if self.can_accept {
+            if self.can_accept && self.configured {
               self.accept(ListenToken(token.0), protocol);
             }
             return;
@@ -1407,7 +2234,15 @@ impl Server {
           break;
         }
 
-        let order = self.sessions[session_token].borrow_mut().ready();
+        let mut order = self.sessions[session_token].borrow_mut().ready();
+
+        let proxy_protocol_client = self.sessions[session_token].borrow_mut().take_proxy_protocol_client();
+        if let Some((listen_token, client)) = proxy_protocol_client {
+          if !self.check_proxy_protocol_rate_limit(listen_token, client) {
+            incr!("proxy_protocol.rate_limited");
+            order = SessionResult::CloseSession;
+          }
+        }
         trace!("session[{:?} -> {:?}] got events {:?} and returned order {:?}", session_token, self.from_session(session_token), events, order);
         //FIXME: the CloseBackend message might not mean we have nothing else to do
         //with that session
@@ -1436,21 +2271,44 @@ impl Server {
     }
   }
 
+  /// timeouts of the session using this frontend or backend token
+  fn session_timeouts(&self, token: SessionToken) -> SessionTimeouts {
+    self.sessions[token].borrow().tokens().first()
+      .and_then(|front_token| self.session_timeouts.get(&self.to_session(*front_token)))
+      .cloned()
+      .unwrap_or(self.timeouts)
+  }
+
   pub fn timeout(&mut self, token: Token) {
     trace!("PROXY\t{:?} got timeout", token);
 
     let session_token = SessionToken(token.0);
     if self.sessions.contains(session_token) {
-      let order = self.sessions[session_token].borrow_mut().timeout(token, &mut self.timer, &self.front_timeout);
-      self.interpret_session_order(session_token, order);
+      let timeouts = self.session_timeouts(session_token);
+      let order = self.sessions[session_token].borrow_mut().timeout(token, &mut self.timer, &timeouts.front,
+        timeouts.idle.as_ref(), timeouts.header.as_ref(), &timeouts.connect);
+
+      // a backend connection timed out: once the backend is replaced, the session
+      // must run again to send the request to the new backend, or an error answer
+      if let SessionResult::ReconnectBackend(Some(front_token), _) = order {
+        self.interpret_session_order(session_token, order);
+        self.ready(front_token, Ready::empty());
+      } else {
+        self.interpret_session_order(session_token, order);
+      }
     }
   }
 
   pub fn handle_remaining_readiness(&mut self) {
     // try to accept again after handling all session events,
     // since we might have released a few session slots
-    if self.can_accept && !self.accept_ready.is_empty() {
+    if self.can_accept && self.configured && !self.accept_ready.is_empty() {
       loop {
+        if self.accepts_in_tick >= self.max_accepts_per_tick {
+          // the remaining sockets will be accepted in the next event loop iteration
+          break;
+        }
+
         if let Some(token) = self.accept_ready.iter().next().map(|token| ListenToken(token.0)) {
           let protocol = self.sessions[SessionToken(token.0)].borrow().protocol();
           self.accept(token, protocol);
@@ -1520,7 +2378,9 @@ impl ProxySession for ListenSession {
   fn close_backend(&mut self, _token: Token, _poll: &mut Poll) {
   }
 
-  fn timeout(&mut self, _token: Token, _timer: &mut Timer<Token>, _front_timeout: &time::Duration) -> SessionResult {
+  fn timeout(&mut self, _token: Token, _timer: &mut Timer<Token>, _front_timeout: &time::Duration,
+    _idle_timeout: Option<&time::Duration>, _header_timeout: Option<&time::Duration>,
+    _connect_timeout: &time::Duration) -> SessionResult {
     error!("called ProxySession::timeout(token={:?}, time, front_timeout = {:?}) on ListenSession {{ protocol: {:?} }}",
       _token, _front_timeout, self.protocol);
     SessionResult::CloseSession
@@ -1582,6 +2442,20 @@ impl HttpsProvider {
     }
   }
 
+  pub fn routes(&self) -> Vec<HttpFront> {
+    match self {
+      &HttpsProvider::Rustls(ref rustls)   => rustls.routes(),
+      &HttpsProvider::Openssl(ref openssl) => openssl.routes(),
+    }
+  }
+
+  pub fn route_match(&self, hostname: &str, method: Option<&str>, path: &str) -> Vec<ListenerRouteMatch> {
+    match self {
+      &HttpsProvider::Rustls(ref rustls)   => rustls.route_match(hostname, method, path),
+      &HttpsProvider::Openssl(ref openssl) => openssl.route_match(hostname, method, path),
+    }
+  }
+
   pub fn give_back_listeners(&mut self) -> Vec<(SocketAddr,TcpListener)> {
     match self {
       &mut HttpsProvider::Rustls(ref mut rustls)   => rustls.give_back_listeners(),
@@ -1603,6 +2477,41 @@ impl HttpsProvider {
     }
   }
 
+  pub fn listener_timeouts(&self, token: ListenToken) -> ListenerTimeouts {
+    match self {
+      &HttpsProvider::Rustls(ref rustls)   => rustls.listener_timeouts(token),
+      &HttpsProvider::Openssl(ref openssl) => openssl.listener_timeouts(token),
+    }
+  }
+
+  pub fn listener_rate_limit(&self, token: ListenToken) -> Option<ConnectionRateLimit> {
+    match self {
+      &HttpsProvider::Rustls(ref rustls)   => rustls.listener_rate_limit(token),
+      &HttpsProvider::Openssl(ref openssl) => openssl.listener_rate_limit(token),
+    }
+  }
+
+  pub fn listener_expects_proxy_protocol(&self, token: ListenToken) -> bool {
+    match self {
+      &HttpsProvider::Rustls(ref rustls)   => rustls.listener_expects_proxy_protocol(token),
+      &HttpsProvider::Openssl(ref openssl) => openssl.listener_expects_proxy_protocol(token),
+    }
+  }
+
+  pub fn listener_max_sessions(&self, token: ListenToken) -> Option<usize> {
+    match self {
+      &HttpsProvider::Rustls(ref rustls)   => rustls.listener_max_sessions(token),
+      &HttpsProvider::Openssl(ref openssl) => openssl.listener_max_sessions(token),
+    }
+  }
+
+  pub fn pause_listener(&mut self, poll: &mut Poll, token: ListenToken, paused: bool) {
+    match self {
+      &mut HttpsProvider::Rustls(ref mut rustls)   => rustls.pause_listener(poll, token, paused),
+      &mut HttpsProvider::Openssl(ref mut openssl) => openssl.pause_listener(poll, token, paused),
+    }
+  }
+
   pub fn create_session(&mut self, frontend_sock: TcpStream, token: ListenToken,
     poll: &mut Poll, session_token: Token, timeout: Timeout, delay: time::Duration)
     -> Result<(Rc<RefCell<ProxySessionCast>>,bool), AcceptError> {
@@ -1666,6 +2575,16 @@ impl HttpsProvider {
   }
 
 
+  pub fn routes(&self) -> Vec<HttpFront> {
+    let &HttpsProvider::Rustls(ref rustls) = self;
+    rustls.routes()
+  }
+
+  pub fn route_match(&self, hostname: &str, method: Option<&str>, path: &str) -> Vec<ListenerRouteMatch> {
+    let &HttpsProvider::Rustls(ref rustls) = self;
+    rustls.route_match(hostname, method, path)
+  }
+
   pub fn give_back_listeners(&mut self) -> Vec<(SocketAddr, TcpListener)> {
     let &mut HttpsProvider::Rustls(ref mut rustls) = self;
     rustls.give_back_listeners()
@@ -1681,6 +2600,31 @@ impl HttpsProvider {
     rustls.accept(token)
   }
 
+  pub fn listener_timeouts(&self, token: ListenToken) -> ListenerTimeouts {
+    let &HttpsProvider::Rustls(ref rustls) = self;
+    rustls.listener_timeouts(token)
+  }
+
+  pub fn listener_rate_limit(&self, token: ListenToken) -> Option<ConnectionRateLimit> {
+    let &HttpsProvider::Rustls(ref rustls) = self;
+    rustls.listener_rate_limit(token)
+  }
+
+  pub fn listener_expects_proxy_protocol(&self, token: ListenToken) -> bool {
+    let &HttpsProvider::Rustls(ref rustls) = self;
+    rustls.listener_expects_proxy_protocol(token)
+  }
+
+  pub fn listener_max_sessions(&self, token: ListenToken) -> Option<usize> {
+    let &HttpsProvider::Rustls(ref rustls) = self;
+    rustls.listener_max_sessions(token)
+  }
+
+  pub fn pause_listener(&mut self, poll: &mut Poll, token: ListenToken, paused: bool) {
+    let &mut HttpsProvider::Rustls(ref mut rustls) = self;
+    rustls.pause_listener(poll, token, paused);
+  }
+
   pub fn create_session(&mut self, frontend_sock: TcpStream, token: ListenToken,
     poll: &mut Poll, session_token: Token, timeout: Timeout, delay: time::Duration)
     -> Result<(Rc<RefCell<Session>>,bool), AcceptError> {
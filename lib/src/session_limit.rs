@@ -0,0 +1,115 @@
+//! maximum number of concurrent sessions of a listener
+//!
+//! When a listener reaches its maximum, the worker stops listening for its
+//! readable events instead of accepting connections only to close them. The
+//! new connections wait in the listening socket's backlog, and the listener
+//! is resumed once enough of its sessions ended (the low water mark, at 90%
+//! of the maximum), so it does not switch on and off at every session.
+
+#[derive(Debug,Clone,PartialEq)]
+pub struct SessionLimit {
+  max_sessions: usize,
+  sessions:     usize,
+  paused:       bool,
+}
+
+impl SessionLimit {
+  pub fn new(max_sessions: usize) -> SessionLimit {
+    SessionLimit {
+      max_sessions,
+      sessions: 0,
+      paused:   false,
+    }
+  }
+
+  pub fn max_sessions(&self) -> usize {
+    self.max_sessions
+  }
+
+  pub fn set_max_sessions(&mut self, max_sessions: usize) {
+    self.max_sessions = max_sessions;
+  }
+
+  pub fn sessions(&self) -> usize {
+    self.sessions
+  }
+
+  pub fn is_paused(&self) -> bool {
+    self.paused
+  }
+
+  /// the listener resumes accepting once it has this number of sessions or less
+  pub fn low_water_mark(&self) -> usize {
+    self.max_sessions * 90 / 100
+  }
+
+  /// returns true if the listener can get another session
+  pub fn can_accept(&self) -> bool {
+    self.sessions < self.max_sessions
+  }
+
+  /// counts a new session, returns true if the listener reached
+  /// its maximum and must be paused
+  pub fn add_session(&mut self) -> bool {
+    self.sessions += 1;
+    if !self.paused && !self.can_accept() {
+      self.paused = true;
+      true
+    } else {
+      false
+    }
+  }
+
+  /// counts a closed session, returns true if the listener was
+  /// paused and went back to the low water mark
+  pub fn remove_session(&mut self) -> bool {
+    self.sessions = self.sessions.saturating_sub(1);
+    if self.paused && self.sessions <= self.low_water_mark() {
+      self.paused = false;
+      true
+    } else {
+      false
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn pause_at_the_maximum_and_resume_at_the_low_water_mark() {
+    let mut limit = SessionLimit::new(20);
+    assert_eq!(limit.low_water_mark(), 18);
+
+    for _ in 0..19 {
+      assert!(!limit.add_session());
+    }
+    assert!(limit.can_accept());
+    assert!(limit.add_session());
+    assert!(limit.is_paused());
+    assert!(!limit.can_accept());
+
+    // still paused above the low water mark
+    assert!(!limit.remove_session());
+    assert!(limit.is_paused());
+    assert!(limit.remove_session());
+    assert!(!limit.is_paused());
+    assert_eq!(limit.sessions(), 18);
+
+    assert!(!limit.add_session());
+    assert!(limit.add_session());
+  }
+
+  #[test]
+  fn single_session_listener() {
+    let mut limit = SessionLimit::new(1);
+
+    assert!(limit.add_session());
+    assert!(!limit.can_accept());
+    assert!(limit.remove_session());
+    assert!(limit.can_accept());
+    assert!(!limit.remove_session());
+    assert_eq!(limit.sessions(), 0);
+  }
+}
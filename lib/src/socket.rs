@@ -1,9 +1,17 @@
+use std::fs;
 use std::io::{self,ErrorKind,Read,Write};
 use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::os::unix::fs::{FileTypeExt,MetadataExt,PermissionsExt};
+use std::os::unix::io::{FromRawFd,IntoRawFd};
+use mio::{Evented,Poll,Ready,PollOpt,Token};
 use mio::tcp::{TcpListener,TcpStream};
+use mio_uds::UnixListener;
 use rustls::{ServerSession, Session, ProtocolVersion};
 use net2::TcpBuilder;
 use net2::unix::UnixTcpBuilderExt;
+use sozu_command::config::TcpKeepalive;
+use sozu_command::proxy::UnixSocket;
 #[cfg(feature = "use-openssl")]
 use openssl::ssl::{ErrorCode, SslStream, SslVersion};
 
@@ -400,7 +408,9 @@ impl SocketHandler for FrontRustls {
   }
 }
 
-pub fn server_bind(addr: &SocketAddr) -> io::Result<TcpListener> {
+/// binds a listening socket to `addr`. If `interface` is set, the
+/// socket only accepts connections received on that network interface
+pub fn server_bind(addr: &SocketAddr, interface: Option<&str>) -> io::Result<TcpListener> {
   let sock = match *addr {
     SocketAddr::V4(..) => TcpBuilder::new_v4()?,
     SocketAddr::V6(..) => TcpBuilder::new_v6()?,
@@ -413,6 +423,10 @@ pub fn server_bind(addr: &SocketAddr) -> io::Result<TcpListener> {
 
   sock.reuse_port(true)?;
 
+  if let Some(interface) = interface {
+    bind_to_device(&sock, interface)?;
+  }
+
   // bind the socket
   sock.bind(addr)?;
 
@@ -422,3 +436,284 @@ pub fn server_bind(addr: &SocketAddr) -> io::Result<TcpListener> {
   TcpListener::from_std(listener)
 }
 
+#[cfg(target_os = "linux")]
+fn bind_to_device(sock: &TcpBuilder, interface: &str) -> io::Result<()> {
+  use std::os::unix::io::AsRawFd;
+
+  // the name must fit in IFNAMSIZ bytes, including the trailing null byte
+  if interface.is_empty() || interface.len() >= libc::IFNAMSIZ || interface.as_bytes().contains(&0) {
+    return Err(io::Error::new(ErrorKind::InvalidInput,
+      format!("invalid network interface name: {:?}", interface)));
+  }
+
+  let res = unsafe {
+    libc::setsockopt(sock.as_raw_fd(), libc::SOL_SOCKET, libc::SO_BINDTODEVICE,
+      interface.as_ptr() as *const libc::c_void, interface.len() as libc::socklen_t)
+  };
+
+  if res == 0 {
+    return Ok(());
+  }
+
+  let e = io::Error::last_os_error();
+  match e.raw_os_error() {
+    Some(libc::EPERM) => Err(io::Error::new(ErrorKind::PermissionDenied,
+      format!("binding to the network interface {} requires the CAP_NET_RAW capability", interface))),
+    Some(libc::ENODEV) => Err(io::Error::new(ErrorKind::NotFound,
+      format!("unknown network interface: {}", interface))),
+    _ => Err(e),
+  }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bind_to_device(_sock: &TcpBuilder, interface: &str) -> io::Result<()> {
+  Err(io::Error::new(ErrorKind::Other,
+    format!("cannot bind to the network interface {}: SO_BINDTODEVICE is only supported on Linux", interface)))
+}
+
+/// stops or resumes the readable events of a listening socket. While it is
+/// paused, the new connections wait in the socket's backlog
+pub fn pause_listener<E: Evented>(poll: &mut Poll, listener: &E, token: Token, paused: bool) {
+  let res = if paused {
+    poll.deregister(listener)
+  } else {
+    poll.register(listener, token, Ready::readable(), PollOpt::edge())
+  };
+
+  if let Err(e) = res {
+    error!("could not {} the listener {:?}: {:?}", if paused { "pause" } else { "resume" }, token, e);
+  }
+}
+
+/// listener bound on a Unix domain socket. The socket file is removed when
+/// the listener is dropped, unless another listener replaced it in the meantime,
+/// like the one of a new worker during an upgrade
+#[derive(Debug)]
+pub struct UnixSocketListener {
+  pub listener: UnixListener,
+  path:         PathBuf,
+  /// device and inode of the socket file we created
+  file_id:      Option<(u64, u64)>,
+}
+
+impl UnixSocketListener {
+  pub fn bind(config: &UnixSocket) -> io::Result<UnixSocketListener> {
+    let path = PathBuf::from(&config.path);
+
+    // the socket file left by a previous instance prevents the bind
+    match fs::symlink_metadata(&path) {
+      Ok(metadata) => {
+        if !metadata.file_type().is_socket() {
+          return Err(io::Error::new(ErrorKind::AlreadyExists,
+            format!("cannot bind the Unix domain socket {}: the file exists and is not a socket", config.path)));
+        }
+        fs::remove_file(&path)?;
+      },
+      Err(ref e) if e.kind() == ErrorKind::NotFound => {},
+      Err(e) => return Err(e),
+    }
+
+    let listener = UnixListener::bind(&path)?;
+    if let Some(mode) = config.mode {
+      fs::set_permissions(&path, fs::Permissions::from_mode(mode))?;
+    }
+
+    let file_id = fs::symlink_metadata(&path).ok().map(|metadata| (metadata.dev(), metadata.ino()));
+    Ok(UnixSocketListener { listener, path, file_id })
+  }
+
+  /// the sessions work on a `TcpStream`, so the accepted stream is wrapped in
+  /// one. It only supports reading, writing and shutting down, and has no address
+  pub fn accept(&self) -> io::Result<TcpStream> {
+    match self.listener.accept()? {
+      Some((stream, _)) => {
+        let stream = unsafe { ::std::net::TcpStream::from_raw_fd(stream.into_raw_fd()) };
+        TcpStream::from_stream(stream)
+      },
+      None => Err(io::Error::from(ErrorKind::WouldBlock)),
+    }
+  }
+}
+
+impl Drop for UnixSocketListener {
+  fn drop(&mut self) {
+    let file_id = fs::symlink_metadata(&self.path).ok().map(|metadata| (metadata.dev(), metadata.ino()));
+    if file_id.is_some() && file_id == self.file_id {
+      if let Err(e) = fs::remove_file(&self.path) {
+        error!("could not remove the Unix domain socket {:?}: {:?}", self.path, e);
+      }
+    }
+  }
+}
+
+/// options applied to the frontend sockets when they are accepted,
+/// and to the backend sockets when they connect
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct SocketConfig {
+  /// None leaves keepalive disabled
+  pub keepalive: Option<TcpKeepalive>,
+}
+
+impl Default for SocketConfig {
+  fn default() -> SocketConfig {
+    SocketConfig {
+      keepalive: Some(TcpKeepalive::default()),
+    }
+  }
+}
+
+pub fn configure_socket(stream: &TcpStream, config: &SocketConfig) -> io::Result<()> {
+  use std::os::unix::io::AsRawFd;
+
+  // the streams accepted on a Unix domain socket have no TCP options
+  if stream.local_addr().is_err() {
+    return Ok(());
+  }
+
+  if let Some(ref keepalive) = config.keepalive {
+    let fd = stream.as_raw_fd();
+    set_socket_option(fd, libc::SOL_SOCKET, libc::SO_KEEPALIVE, 1)?;
+    set_keepalive_parameters(fd, keepalive)?;
+  }
+
+  Ok(())
+}
+
+fn set_socket_option(fd: libc::c_int, level: libc::c_int, name: libc::c_int, value: u32) -> io::Result<()> {
+  let value = value as libc::c_int;
+  let res = unsafe {
+    libc::setsockopt(fd, level, name, &value as *const libc::c_int as *const libc::c_void,
+      ::std::mem::size_of::<libc::c_int>() as libc::socklen_t)
+  };
+
+  if res == 0 {
+    Ok(())
+  } else {
+    Err(io::Error::last_os_error())
+  }
+}
+
+#[cfg(target_os = "linux")]
+fn set_keepalive_parameters(fd: libc::c_int, keepalive: &TcpKeepalive) -> io::Result<()> {
+  set_socket_option(fd, libc::IPPROTO_TCP, libc::TCP_KEEPIDLE, keepalive.idle)?;
+  set_socket_option(fd, libc::IPPROTO_TCP, libc::TCP_KEEPINTVL, keepalive.interval)?;
+  set_socket_option(fd, libc::IPPROTO_TCP, libc::TCP_KEEPCNT, keepalive.probes)
+}
+
+// other platforms use the system's keepalive parameters
+#[cfg(not(target_os = "linux"))]
+fn set_keepalive_parameters(_fd: libc::c_int, _keepalive: &TcpKeepalive) -> io::Result<()> {
+  Ok(())
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+  use super::*;
+  use std::os::unix::io::AsRawFd;
+
+  fn bound_device(listener: &TcpListener) -> String {
+    let mut buf = [0u8; libc::IFNAMSIZ];
+    let mut len = buf.len() as libc::socklen_t;
+    let res = unsafe {
+      libc::getsockopt(listener.as_raw_fd(), libc::SOL_SOCKET, libc::SO_BINDTODEVICE,
+        buf.as_mut_ptr() as *mut libc::c_void, &mut len)
+    };
+    assert_eq!(res, 0);
+    let name = &buf[..len as usize];
+    String::from_utf8(name.iter().cloned().take_while(|c| *c != 0).collect()).unwrap()
+  }
+
+  fn socket_option(stream: &TcpStream, level: libc::c_int, name: libc::c_int) -> libc::c_int {
+    let mut value: libc::c_int = 0;
+    let mut len = ::std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let res = unsafe {
+      libc::getsockopt(stream.as_raw_fd(), level, name, &mut value as *mut libc::c_int as *mut libc::c_void, &mut len)
+    };
+    assert_eq!(res, 0);
+    value
+  }
+
+  #[test]
+  fn keepalive_options() {
+    let listener = server_bind(&"127.0.0.1:1075".parse().unwrap(), None).expect("could not bind");
+    let stream = TcpStream::connect(&"127.0.0.1:1075".parse().unwrap()).expect("could not connect");
+
+    configure_socket(&stream, &SocketConfig { keepalive: None }).unwrap();
+    assert_eq!(socket_option(&stream, libc::SOL_SOCKET, libc::SO_KEEPALIVE), 0);
+
+    let keepalive = TcpKeepalive { idle: 30, interval: 5, probes: 3 };
+    configure_socket(&stream, &SocketConfig { keepalive: Some(keepalive) }).unwrap();
+    assert_eq!(socket_option(&stream, libc::SOL_SOCKET, libc::SO_KEEPALIVE), 1);
+    assert_eq!(socket_option(&stream, libc::IPPROTO_TCP, libc::TCP_KEEPIDLE), 30);
+    assert_eq!(socket_option(&stream, libc::IPPROTO_TCP, libc::TCP_KEEPINTVL), 5);
+    assert_eq!(socket_option(&stream, libc::IPPROTO_TCP, libc::TCP_KEEPCNT), 3);
+    drop(listener);
+  }
+
+  #[test]
+  fn unix_socket_listener() {
+    use std::os::unix::net::UnixStream;
+
+    let path = ::std::env::temp_dir().join(format!("sozu-test-{}.sock", ::std::process::id()));
+    let config = UnixSocket { path: path.to_str().unwrap().to_string(), mode: Some(0o600) };
+    let listener = UnixSocketListener::bind(&config).expect("could not bind");
+    assert!(fs::symlink_metadata(&path).unwrap().file_type().is_socket());
+    assert_eq!(fs::symlink_metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+
+    let mut client = UnixStream::connect(&path).expect("could not connect");
+    let mut stream = listener.accept().expect("could not accept");
+    assert!(stream.local_addr().is_err());
+    configure_socket(&stream, &SocketConfig::default()).unwrap();
+
+    client.write_all(b"ping").unwrap();
+    let mut buf = [0u8; 4];
+    let mut read = 0;
+    while read < 4 {
+      match stream.read(&mut buf[read..]) {
+        Ok(sz) => read += sz,
+        Err(ref e) if e.kind() == ErrorKind::WouldBlock => ::std::thread::sleep(::std::time::Duration::from_millis(10)),
+        Err(e) => panic!("read error: {:?}", e),
+      }
+    }
+    assert_eq!(&buf, b"ping");
+    assert_eq!(listener.accept().unwrap_err().kind(), ErrorKind::WouldBlock);
+
+    drop(listener);
+    assert!(fs::symlink_metadata(&path).is_err());
+  }
+
+  #[test]
+  fn unix_socket_replacement() {
+    let path = ::std::env::temp_dir().join(format!("sozu-test-{}-replaced.sock", ::std::process::id()));
+    let config = UnixSocket { path: path.to_str().unwrap().to_string(), mode: None };
+
+    // a new listener replaces the socket file, the old one must not remove it
+    let old = UnixSocketListener::bind(&config).expect("could not bind");
+    let new = UnixSocketListener::bind(&config).expect("could not bind again");
+    drop(old);
+    assert!(fs::symlink_metadata(&path).is_ok());
+    drop(new);
+    assert!(fs::symlink_metadata(&path).is_err());
+
+    // regular files are not removed
+    fs::write(&path, b"data").unwrap();
+    assert_eq!(UnixSocketListener::bind(&config).unwrap_err().kind(), ErrorKind::AlreadyExists);
+    fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn bind_to_interface() {
+    let addr = "127.0.0.1:1060".parse().unwrap();
+    match server_bind(&addr, Some("lo")) {
+      Ok(listener) => assert_eq!(bound_device(&listener), "lo"),
+      // without CAP_NET_RAW, the error must be explicit
+      Err(e) => assert_eq!(e.kind(), ErrorKind::PermissionDenied),
+    }
+
+    let listener = server_bind(&"127.0.0.1:1061".parse().unwrap(), None).expect("could not bind");
+    assert_eq!(bound_device(&listener), "");
+
+    let e = server_bind(&"127.0.0.1:1062".parse().unwrap(), Some("a_very_long_interface_name")).unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::InvalidInput);
+  }
+}
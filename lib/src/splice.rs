@@ -1,9 +1,15 @@
-use libc::{c_int,c_uint,size_t};
-use libc::types::os::arch::posix88::{off_t,ssize_t};
-use mio::tcp::TcpStream;
-use std::io::{Error,ErrorKind};
+//! zero copy transfers between sockets, through a pipe
+//!
+//! Even when sozu is compiled with the `splice` feature, the running kernel
+//! (or a seccomp filter) might not support `splice()`, and return ENOSYS or
+//! EINVAL. The first call to `is_supported` probes it once for the process,
+//! and `transfer` falls back to a copy through a userspace buffer if it is
+//! not usable.
+use libc::{c_int,c_uint,size_t,off_t,ssize_t};
+use std::io::{Error,ErrorKind,Read,Write};
 use std::ptr;
 use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 const SPLICE_F_NONBLOCK: c_uint = 2;
 extern {
@@ -17,10 +23,92 @@ extern {
 
 pub type Pipe = [c_int ; 2];
 
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum SpliceSupport {
+  Unknown,
+  Supported,
+  Unsupported,
+}
+
+static SPLICE_SUPPORT: AtomicUsize = AtomicUsize::new(0);
+
+pub fn support() -> SpliceSupport {
+  match SPLICE_SUPPORT.load(Ordering::Relaxed) {
+    1 => SpliceSupport::Supported,
+    2 => SpliceSupport::Unsupported,
+    _ => SpliceSupport::Unknown,
+  }
+}
+
+/// overrides the probe result, `SpliceSupport::Unknown` makes the next call probe again
+pub fn set_support(support: SpliceSupport) {
+  let value = match support {
+    SpliceSupport::Unknown     => 0,
+    SpliceSupport::Supported   => 1,
+    SpliceSupport::Unsupported => 2,
+  };
+  SPLICE_SUPPORT.store(value, Ordering::Relaxed);
+}
+
+/// returns true if splice() can be used, probing the kernel on the first call
+pub fn is_supported() -> bool {
+  if support() == SpliceSupport::Unknown {
+    let supported = probe();
+    if !supported {
+      info!("SPLICE	splice() is not supported by the kernel, using buffer copies");
+    }
+    set_support(if supported { SpliceSupport::Supported } else { SpliceSupport::Unsupported });
+  }
+
+  support() == SpliceSupport::Supported
+}
+
+/// splices between two empty pipes: a kernel supporting it answers EAGAIN
+fn probe() -> bool {
+  let (from, to) = match (create_pipe(), create_pipe()) {
+    (Some(from), Some(to)) => (from, to),
+    (from, to) => {
+      for pipe in from.iter().chain(to.iter()) {
+        close_pipe(*pipe);
+      }
+      return false;
+    }
+  };
+
+  let res = unsafe { splice(from[0], ptr::null(), to[1], ptr::null(), 1, SPLICE_F_NONBLOCK) };
+  let supported = res >= 0 || !is_unsupported_error(&Error::last_os_error());
+
+  close_pipe(from);
+  close_pipe(to);
+  supported
+}
+
+fn is_unsupported_error(error: &Error) -> bool {
+  match error.raw_os_error() {
+    Some(code) => code == ::libc::ENOSYS || code == ::libc::EINVAL,
+    None       => false,
+  }
+}
+
+/// a splice call failed at runtime: the next transfers will use buffer copies
+fn check_error(error: &Error) {
+  if is_unsupported_error(error) && support() != SpliceSupport::Unsupported {
+    error!("SPLICE	splice() failed with {:?}, falling back to buffer copies", error);
+    set_support(SpliceSupport::Unsupported);
+  }
+}
+
+pub fn close_pipe(pipe: Pipe) {
+  unsafe {
+    ::libc::close(pipe[0]);
+    ::libc::close(pipe[1]);
+  }
+}
+
 pub fn create_pipe() -> Option<Pipe> {
   let mut p: Pipe = [0; 2];
   unsafe {
-    if pipe2(p.as_mut_ptr(), 0) == 0 {
+    if pipe2(p.as_mut_ptr(), ::libc::O_NONBLOCK) == 0 {
       Some(p)
     } else {
       None
@@ -32,7 +120,9 @@ pub fn splice_in(stream: &AsRawFd, pipe: Pipe) -> Option<usize> {
   unsafe {
     let res = splice(stream.as_raw_fd(), ptr::null(), pipe[1], ptr::null(), 2048, SPLICE_F_NONBLOCK);
     if res == -1 {
-      let err = Error::last_os_error().kind();
+      let error = Error::last_os_error();
+      check_error(&error);
+      let err = error.kind();
       if err != ErrorKind::WouldBlock {
         error!("SPLICE\terr transferring from tcp({}) to pipe({}): {:?}", stream.as_raw_fd(), pipe[1], err);
       }
@@ -48,7 +138,9 @@ pub fn splice_out(pipe: Pipe, stream: &AsRawFd) -> Option<usize> {
   unsafe {
     let res = splice(pipe[0], ptr::null(), stream.as_raw_fd(), ptr::null(), 2048, SPLICE_F_NONBLOCK);
     if res == -1 {
-      let err = Error::last_os_error().kind();
+      let error = Error::last_os_error();
+      check_error(&error);
+      let err = error.kind();
       if err != ErrorKind::WouldBlock {
         error!("SPLICE\terr transferring from pipe({}) to tcp({}): {:?}", pipe[0], stream.as_raw_fd(), err);
       }
@@ -60,6 +152,29 @@ pub fn splice_out(pipe: Pipe, stream: &AsRawFd) -> Option<usize> {
   }
 }
 
+/// moves data from one socket to the other, with splice() if it is supported,
+/// otherwise by copying it through the buffer.
+/// Returns the number of bytes written to the destination
+pub fn transfer<R,W>(from: &mut R, pipe: Pipe, to: &mut W, buffer: &mut [u8]) -> Option<usize>
+  where R: Read + AsRawFd, W: Write + AsRawFd {
+  if is_supported() {
+    if splice_in(from, pipe).is_some() {
+      // the data remaining in the pipe will be written by the next transfers
+      return Some(splice_out(pipe, to).unwrap_or(0));
+    }
+
+    // the splice call might have detected that it is not supported
+    if is_supported() {
+      return None;
+    }
+  }
+
+  match from.read(buffer) {
+    Ok(0) | Err(_) => None,
+    Ok(sz)         => to.write_all(&buffer[..sz]).ok().map(|_| sz),
+  }
+}
+
 #[cfg(test)]
 mod tests {
   use super::*;
@@ -91,6 +206,41 @@ mod tests {
     //assert!(false);
   }
 
+  fn connected_pair() -> (TcpStream, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("could not bind socket");
+    let client = TcpStream::connect(listener.local_addr().unwrap()).expect("could not connect tcp socket");
+    let (server, _) = listener.accept().expect("could not accept");
+    (client, server)
+  }
+
+  #[test]
+  fn transfer_fallback() {
+    let (mut client, mut front) = connected_pair();
+    let (mut back, mut backend) = connected_pair();
+    let pipe = create_pipe().expect("could not create pipe");
+    let mut buffer = [0; 128];
+
+    // the probe found that splice() is not usable: the data is copied
+    set_support(SpliceSupport::Unsupported);
+    client.write_all(&b"hello world"[..]).unwrap();
+    assert_eq!(transfer(&mut front, pipe, &mut back, &mut buffer[..]), Some(11));
+    let sz = backend.read(&mut buffer[..]).expect("could not read from stream");
+    assert_eq!(&buffer[..sz], &b"hello world"[..]);
+    assert!(!is_supported());
+
+    set_support(SpliceSupport::Unknown);
+    assert_eq!(is_supported(), probe());
+    assert_ne!(support(), SpliceSupport::Unknown);
+
+    // the transfer works the same way with splice()
+    client.write_all(&b"hello again"[..]).unwrap();
+    assert_eq!(transfer(&mut front, pipe, &mut back, &mut buffer[..]), Some(11));
+    let sz = backend.read(&mut buffer[..]).expect("could not read from stream");
+    assert_eq!(&buffer[..sz], &b"hello again"[..]);
+
+    close_pipe(pipe);
+  }
+
   fn start_server() {
     let listener = TcpListener::bind("127.0.0.1:4242").expect("could not bind socket");
     fn handle_client(stream: &mut TcpStream, id: u8) {
@@ -15,18 +15,19 @@ use uuid::adapter::Hyphenated;
 use mio_extras::timer::{Timer,Timeout};
 
 use sozu_command::scm_socket::ScmSocket;
-use sozu_command::config::{ProxyProtocolConfig, LoadBalancingAlgorithms};
+use sozu_command::config::{ProxyProtocolConfig, ProxyProtocolVersion, LoadBalancingAlgorithms};
 use sozu_command::proxy::{ProxyRequestData,ProxyRequest,ProxyResponse,ProxyResponseStatus,ProxyEvent};
 use sozu_command::proxy::TcpListener as TcpListenerConfig;
+use sozu_command::proxy::{ListenerTimeouts,ConnectionRateLimit};
 use sozu_command::logging;
 use sozu_command::buffer::Buffer;
 
-use {AppId,Backend,SessionResult,ConnectionError,Protocol,Readiness,SessionMetrics,
+use {AppId,SessionResult,ConnectionError,Protocol,Readiness,SessionMetrics,
   ProxySession,ProxyConfiguration,AcceptError,BackendConnectAction,BackendConnectionStatus,
-  CloseResult};
+  CloseResult,ConnectionGuard};
 use backends::BackendMap;
 use server::{Server,ProxyChannel,ListenToken,ListenPortState,SessionToken,
-  ListenSession, CONN_RETRIES, push_event};
+  ListenSession, push_event};
 use pool::{Pool,Checkout};
 use socket::server_bind;
 use protocol::{Pipe, ProtocolResult};
@@ -52,7 +53,7 @@ pub enum State {
 
 pub struct Session {
   sock:               TcpStream,
-  backend:            Option<Rc<RefCell<Backend>>>,
+  backend:            Option<ConnectionGuard>,
   frontend_token:     Token,
   backend_token:      Option<Token>,
   back_connected:     BackendConnectionStatus,
@@ -74,7 +75,7 @@ impl Session {
   fn new(sock: TcpStream, frontend_token: Token, accept_token: Token,
     front_buf: Checkout<Buffer>, back_buf: Checkout<Buffer>, app_id: Option<String>,
     backend_id: Option<String>, proxy_protocol: Option<ProxyProtocolConfig>,
-    timeout: Timeout, delay: Duration) -> Session {
+    proxy_protocol_version: ProxyProtocolVersion, timeout: Timeout, delay: Duration) -> Session {
     let s = sock.try_clone().expect("could not clone the socket");
     let frontend_address = sock.peer_addr().ok();
     let mut frontend_buffer = None;
@@ -98,7 +99,7 @@ impl Session {
         frontend_buffer = Some(front_buf);
         backend_buffer = Some(back_buf);
         gauge_add!("protocol.proxy.send", 1);
-        Some(State::SendProxyProtocol(SendProxyProtocol::new(s, frontend_token, request_id, None)))
+        Some(State::SendProxyProtocol(SendProxyProtocol::new(s, frontend_token, request_id, None, proxy_protocol_version)))
       },
       None => {
         gauge_add!("protocol.tcp", 1);
@@ -139,9 +140,7 @@ impl Session {
       Some(SocketAddr::V6(addr)) => format!("{}", addr),
     };
 
-    let backend_address = self.backend.as_ref().map(|backend| (*backend.borrow_mut()).address);
-
-    let backend = match backend_address {
+    let backend = match self.backend_address() {
       None => String::from("-"),
       Some(SocketAddr::V4(addr)) => format!("{}", addr),
       Some(SocketAddr::V6(addr)) => format!("{}", addr),
@@ -164,9 +163,28 @@ impl Session {
       response_time, service_time, self.metrics.bin, self.metrics.bout);
   }
 
+  fn backend_address(&self) -> Option<SocketAddr> {
+    self.backend.as_ref().map(|backend| backend.borrow().address)
+  }
+
+  /// access log line written when the backend connection is established
+  fn log_connection(&self) {
+    let frontend = match self.frontend_address {
+      None => String::from("-"),
+      Some(addr) => addr.to_string(),
+    };
+
+    let backend = match self.backend_address() {
+      None => String::from("-"),
+      Some(addr) => addr.to_string(),
+    };
+
+    info_access!("{}{} -> {}\tconnected", self.log_context(), frontend, backend);
+  }
+
   fn front_hup(&mut self) -> SessionResult {
     match self.protocol {
-      Some(State::Pipe(ref mut pipe)) => pipe.front_hup(&mut self.metrics),
+      Some(State::Pipe(ref mut pipe)) => pipe.front_hup(),
       _ => {
         self.log_request();
         SessionResult::CloseSession
@@ -176,7 +194,7 @@ impl Session {
 
   fn back_hup(&mut self) -> SessionResult {
     match self.protocol {
-      Some(State::Pipe(ref mut pipe)) => pipe.back_hup(&mut self.metrics),
+      Some(State::Pipe(ref mut pipe)) => pipe.back_hup(),
       _ => {
         self.log_request();
         SessionResult::CloseSession
@@ -297,6 +315,7 @@ impl Session {
       if self.back_buf.is_some() && self.front_buf.is_some() {
         let mut pipe = pp.into_pipe(self.front_buf.take().unwrap(), self.back_buf.take().unwrap());
         pipe.set_app_id(self.app_id.clone());
+        pipe.set_backend_address(self.backend_address());
         self.protocol = Some(State::Pipe(pipe));
         gauge_add!("protocol.proxy.send", -1);
         gauge_add!("protocol.tcp", 1);
@@ -309,6 +328,7 @@ impl Session {
       if self.back_buf.is_some() {
         let mut pipe = pp.into_pipe(self.back_buf.take().unwrap());
         pipe.set_app_id(self.app_id.clone());
+        pipe.set_backend_address(self.backend_address());
         self.protocol = Some(State::Pipe(pipe));
         gauge_add!("protocol.proxy.relay", -1);
         gauge_add!("protocol.tcp", 1);
@@ -426,8 +446,8 @@ impl Session {
   }
 
   fn remove_backend(&mut self) {
-    if let Some(backend) = self.backend.take() {
-      (*backend.borrow_mut()).dec_connections();
+    if let Some(mut backend) = self.backend.take() {
+      backend.close();
     }
 
     self.backend_token = None;
@@ -437,6 +457,7 @@ impl Session {
     self.backend.as_ref().map(|backend| {
       let ref mut backend = *backend.borrow_mut();
       backend.failures += 1;
+      backend.last_failure = Some(SteadyTime::now());
 
       let already_unavailable = backend.retry_policy.is_down();
       backend.retry_policy.fail();
@@ -480,6 +501,10 @@ impl Session {
 impl ProxySession for Session {
   fn close(&mut self, poll: &mut Poll) -> CloseResult {
     self.metrics.service_stop();
+    // sessions closed by a timeout or by the proxy were not logged yet
+    if let Some(State::Pipe(ref mut pipe)) = self.protocol {
+      pipe.log_request_success(&self.metrics);
+    }
     if let Err(e) = self.front_socket().shutdown(Shutdown::Both) {
       if e.kind() != ErrorKind::NotConnected {
         error!("error shutting down front socket({:?}): {:?}", self.front_socket(), e);
@@ -511,15 +536,36 @@ impl ProxySession for Session {
     result
   }
 
-  fn timeout(&mut self, token: Token, timer: &mut Timer<Token>, front_timeout: &Duration) -> SessionResult {
+  fn timeout(&mut self, token: Token, timer: &mut Timer<Token>, front_timeout: &Duration,
+    idle_timeout: Option<&Duration>, _header_timeout: Option<&Duration>, connect_timeout: &Duration) -> SessionResult {
     if self.frontend_token == token {
-      let dur = SteadyTime::now() - self.last_event;
+      let now = SteadyTime::now();
+      self.metrics.update_activity(self.last_event);
+      if idle_timeout.map(|t| self.metrics.is_idle_at(*t, now)).unwrap_or(false) {
+        info!("{}\tclosing idle session", self.log_context());
+        incr!("idle_timeout");
+        return SessionResult::CloseSession;
+      }
+
+      let dur = now - self.last_event;
       if dur < *front_timeout {
-        timer.set_timeout((*front_timeout - dur).to_std().unwrap(), token);
+        let delay = self.metrics.next_timeout_at(*front_timeout - dur, idle_timeout, now);
+        timer.set_timeout(delay.to_std().unwrap(), token);
         SessionResult::Continue
       } else {
         SessionResult::CloseSession
       }
+    } else if self.back_token() == Some(token) && self.back_connected() == BackendConnectionStatus::Connecting {
+      // the timer could have been set for a previous connection attempt
+      if !self.metrics.is_connect_timed_out_at(*connect_timeout, SteadyTime::now()) {
+        return SessionResult::Continue;
+      }
+
+      error!("{} timeout connecting to backend, trying again", self.log_context());
+      incr!("backend.connect_timeout");
+      self.connection_attempt += 1;
+      self.fail_backend_connection();
+      SessionResult::ReconnectBackend(Some(self.frontend_token), Some(token))
     } else {
       // invalid token, obsolete timeout triggered
       SessionResult::Continue
@@ -549,6 +595,7 @@ impl ProxySession for Session {
     }
 
     if back_connected == BackendConnectionStatus::Connected {
+      self.metrics.backend_stop();
       gauge_add!("connections", -1, self.app_id.as_ref().map(|s| s.as_str()), self.metrics.backend_id.as_ref().map(|s| s.as_str()));
     }
 
@@ -561,6 +608,7 @@ impl ProxySession for Session {
 
   fn process_events(&mut self, token: Token, events: Ready) {
     trace!("token {:?} got event {}", token, super::unix_ready_to_string(UnixReady::from(events)));
+    self.metrics.update_activity(self.last_event);
     self.last_event = SteadyTime::now();
     self.metrics.wait_start();
 
@@ -588,8 +636,10 @@ impl ProxySession for Session {
         let backend_token = self.backend_token;
         return SessionResult::ReconnectBackend(Some(self.frontend_token), backend_token);
       } else if self.back_readiness().unwrap().event != UnixReady::from(Ready::empty()) {
+        self.metrics().backend_connected();
         self.reset_connection_attempt();
         self.set_back_connected(BackendConnectionStatus::Connected);
+        self.log_connection();
       }
     }
 
@@ -599,6 +649,10 @@ impl ProxySession for Session {
         SessionResult::CloseSession => {
           return order;
         },
+        SessionResult::Continue => {
+          // a half closed frontend can still receive data
+          self.front_readiness().event.remove(UnixReady::hup());
+        },
         _ => {
           self.front_readiness().event.remove(UnixReady::hup());
           return order;
@@ -702,6 +756,10 @@ impl ProxySession for Session {
     SessionResult::Continue
   }
 
+  fn back_connection_status(&self) -> BackendConnectionStatus {
+    self.back_connected
+  }
+
   fn shutting_down(&mut self) -> SessionResult {
     SessionResult::CloseSession
   }
@@ -776,7 +834,7 @@ impl Listener {
       return Some(self.token);
     }
 
-    let listener = tcp_listener.or_else(|| server_bind(&self.config.front).map_err(|e| {
+    let listener = tcp_listener.or_else(|| server_bind(&self.config.front, self.config.interface.as_ref().map(|s| s.as_str())).map_err(|e| {
       error!("could not create listener {:?}: {:?}", self.config.front, e);
     }).ok());
 
@@ -798,7 +856,10 @@ impl Listener {
 #[derive(Debug)]
 pub struct ApplicationConfiguration {
   proxy_protocol: Option<ProxyProtocolConfig>,
+  proxy_protocol_version: ProxyProtocolVersion,
   load_balancing_policy: LoadBalancingAlgorithms,
+  /// the connections to a disabled application are closed
+  enabled: bool,
 }
 
 pub struct Proxy {
@@ -880,6 +941,30 @@ impl Proxy {
     }
   }
 
+  /// removes the application with its frontends, returns the number of frontends removed
+  pub fn purge_application(&mut self, app_id: &str) -> usize {
+    let addresses: Vec<SocketAddr> = self.listeners.values()
+      .filter(|l| l.app_id.as_ref().map(|id| id == app_id).unwrap_or(false))
+      .map(|l| l.address)
+      .collect();
+    for address in addresses.iter() {
+      self.remove_tcp_front(*address);
+    }
+
+    self.configs.remove(app_id);
+    addresses.len()
+  }
+
+  pub fn set_application_enabled(&mut self, app_id: &str, enabled: bool, id: String) -> ProxyResponse {
+    match self.configs.get_mut(app_id) {
+      Some(config) => {
+        config.enabled = enabled;
+        ProxyResponse{ id, status: ProxyResponseStatus::Ok, data: None }
+      },
+      None => ProxyResponse{ id, status: ProxyResponseStatus::Error(format!("no such application {}", app_id)), data: None },
+    }
+  }
+
 }
 
 impl ProxyConfiguration<Session> for Proxy {
@@ -894,12 +979,23 @@ impl ProxyConfiguration<Session> for Proxy {
     session.app_id = app_id.clone();
     let app_id = app_id.unwrap();
 
+    if self.configs.get(&app_id).map(|config| !config.enabled).unwrap_or(false) {
+      debug!("{} application {} is disabled", session.log_context(), app_id);
+      return Err(ConnectionError::ApplicationDisabled);
+    }
 
-    if session.connection_attempt == CONN_RETRIES {
+    if session.connection_attempt >= self.backends.borrow().connection_attempts {
       error!("{} max connection attempt reached", session.log_context());
       return Err(ConnectionError::NoBackendAvailable)
     }
 
+    if session.connection_attempt == 0 {
+      self.backends.borrow_mut().record_request(&app_id);
+    } else if !self.backends.borrow_mut().take_retry(&app_id) {
+      error!("{} backend connection failed, the retry budget of the application is exhausted", session.log_context());
+      return Err(ConnectionError::NoBackendAvailable)
+    }
+
     let conn = self.backends.borrow_mut().backend_from_app_id(&app_id);
     match conn {
       Ok((backend,  stream)) => {
@@ -922,6 +1018,10 @@ impl ProxyConfiguration<Session> for Proxy {
         session.metrics.backend_id = Some(backend.borrow().backend_id.clone());
         session.metrics.backend_start();
         session.set_backend_id(backend.borrow().backend_id.clone());
+        if let Some(State::Pipe(ref mut pipe)) = session.protocol {
+          pipe.set_backend_address(Some(backend.borrow().address));
+        }
+        session.backend = Some(ConnectionGuard::new(backend));
 
         Ok(BackendConnectAction::New)
       },
@@ -968,10 +1068,17 @@ impl ProxyConfiguration<Session> for Proxy {
         });
         ProxyResponse{ id: message.id, status: ProxyResponseStatus::Ok, data: None }
       },
+      ProxyRequestData::LogFormat(format) => {
+        info!("{} changing log format to {:?}", message.id, format);
+        logging::LOGGER.with(|l| l.borrow_mut().set_format(format));
+        ProxyResponse{ id: message.id, status: ProxyResponseStatus::Ok, data: None }
+      },
       ProxyRequestData::AddApplication(application) => {
         let config = ApplicationConfiguration {
           proxy_protocol: application.proxy_protocol,
+          proxy_protocol_version: application.proxy_protocol_version,
           load_balancing_policy: application.load_balancing_policy,
+          enabled: application.enabled,
         };
         self.configs.insert(application.app_id.clone(), config);
 
@@ -980,6 +1087,17 @@ impl ProxyConfiguration<Session> for Proxy {
       ProxyRequestData::RemoveApplication(_) => {
         ProxyResponse{ id: message.id, status: ProxyResponseStatus::Ok, data: None }
       },
+      ProxyRequestData::PurgeApplication(app_id) => {
+        let count = self.purge_application(&app_id);
+        debug!("{} purge application {}: {} frontends removed", message.id, app_id, count);
+        ProxyResponse{ id: message.id, status: ProxyResponseStatus::Ok, data: None }
+      },
+      ProxyRequestData::EnableApplication(app_id) => {
+        self.set_application_enabled(&app_id, true, message.id)
+      },
+      ProxyRequestData::DisableApplication(app_id) => {
+        self.set_application_enabled(&app_id, false, message.id)
+      },
       ProxyRequestData::RemoveListener(remove) => {
         if !self.remove_listener(remove.front) {
           ProxyResponse {
@@ -1034,12 +1152,16 @@ impl ProxyConfiguration<Session> for Proxy {
         let proxy_protocol = self.configs
                                 .get(listener.app_id.as_ref().unwrap())
                                 .and_then(|c| c.proxy_protocol.clone());
+        let proxy_protocol_version = self.configs
+                                .get(listener.app_id.as_ref().unwrap())
+                                .map(|c| c.proxy_protocol_version)
+                                .unwrap_or_default();
 
         if let Err(e) = frontend_sock.set_nodelay(true) {
           error!("error setting nodelay on front socket({:?}): {:?}", frontend_sock, e);
         }
         let c = Session::new(frontend_sock, session_token, internal_token,
-          front_buf, back_buf, listener.app_id.clone(), None, proxy_protocol.clone(), timeout,
+          front_buf, back_buf, listener.app_id.clone(), None, proxy_protocol.clone(), proxy_protocol_version, timeout,
           delay);
         incr!("tcp.requests");
 
@@ -1069,6 +1191,29 @@ impl ProxyConfiguration<Session> for Proxy {
       None => ListenPortState::Available
     }
   }
+
+  fn listener_timeouts(&self, token: ListenToken) -> ListenerTimeouts {
+    self.listeners.get(&Token(token.0)).map(|listener| listener.config.timeouts).unwrap_or_default()
+  }
+
+  fn listener_rate_limit(&self, token: ListenToken) -> Option<ConnectionRateLimit> {
+    self.listeners.get(&Token(token.0)).and_then(|listener| listener.config.connection_rate_limit)
+  }
+
+  fn listener_expects_proxy_protocol(&self, _token: ListenToken) -> bool {
+    // the TCP sessions keep limiting the address of the upstream proxy
+    false
+  }
+
+  fn listener_max_sessions(&self, token: ListenToken) -> Option<usize> {
+    self.listeners.get(&Token(token.0)).and_then(|listener| listener.config.max_sessions)
+  }
+
+  fn pause_listener(&mut self, event_loop: &mut Poll, token: ListenToken, paused: bool) {
+    if let Some(sock) = self.listeners.get(&Token(token.0)).and_then(|listener| listener.listener.as_ref()) {
+      ::socket::pause_listener(event_loop, sock, Token(token.0), paused);
+    }
+  }
 }
 
 
@@ -1113,7 +1258,7 @@ pub fn start(config: TcpListenerConfig, max_buffers: usize, buffer_size:usize, c
   let mut server_config: server::ServerConfig = Default::default();
   server_config.max_connections = max_buffers;
   let mut server = Server::new(poll, channel, ScmSocket::new(scm_server.as_raw_fd()), sessions,
-    pool, backends, None ,None, Some(configuration), server_config, None);
+    pool, None, backends, None ,None, Some(configuration), server_config, None);
 
   info!("starting event loop");
   server.run();
@@ -1133,7 +1278,11 @@ mod tests {
   use sozu_command::proxy::{self,TcpFront,LoadBalancingParams};
   use sozu_command::channel::Channel;
   use std::os::unix::io::IntoRawFd;
+  use server;
   static TEST_FINISHED: AtomicBool = ATOMIC_BOOL_INIT;
+  static BURST_TEST_FINISHED: AtomicBool = ATOMIC_BOOL_INIT;
+  static FLOOD_TEST_FINISHED: AtomicBool = ATOMIC_BOOL_INIT;
+  static IPV6_TEST_FINISHED: AtomicBool = ATOMIC_BOOL_INIT;
 
   /*
   #[test]
@@ -1152,7 +1301,7 @@ mod tests {
   fn mi() {
     setup_test_logger!();
     let barrier = Arc::new(Barrier::new(2));
-    start_server(barrier.clone());
+    start_server("127.0.0.1:5678", &TEST_FINISHED, barrier.clone());
     let tx = start_proxy();
     barrier.wait();
 
@@ -1196,9 +1345,399 @@ mod tests {
     TEST_FINISHED.store(true, Ordering::Relaxed);
   }
 
-  fn start_server(barrier: Arc<Barrier>) {
-    let listener = TcpListener::bind("127.0.0.1:5678").expect("could not parse address");
-    fn handle_client(stream: &mut TcpStream, id: u8) {
+  #[test]
+  fn accept_burst() {
+    setup_test_logger!();
+    let barrier = Arc::new(Barrier::new(2));
+    start_server("127.0.0.1:5680", &BURST_TEST_FINISHED, barrier.clone());
+
+    let mut server_config: server::ServerConfig = Default::default();
+    server_config.max_accepts_per_tick = 2;
+    let mut command = start_proxy_thread("127.0.0.1:1237".parse().unwrap(), server_config);
+    command.write_message(&ProxyRequest {
+      id: String::from("ID_BURST1"),
+      order: ProxyRequestData::AddTcpFront(TcpFront {
+        app_id: String::from("burst"),
+        address: "127.0.0.1:1237".parse().unwrap(),
+      })
+    });
+    command.write_message(&ProxyRequest {
+      id: String::from("ID_BURST2"),
+      order: ProxyRequestData::AddBackend(proxy::Backend {
+        app_id: String::from("burst"),
+        backend_id: String::from("burst-0"),
+        address: "127.0.0.1:5680".parse().unwrap(),
+        load_balancing_parameters: Some(LoadBalancingParams::default()),
+        sticky_id: None,
+        backup: None,
+        hostname: None,
+      })
+    });
+    println!("read_message: {:?}", command.read_message().unwrap());
+    println!("read_message: {:?}", command.read_message().unwrap());
+    barrier.wait();
+
+    let mut existing = TcpStream::connect("127.0.0.1:1237").expect("could not connect");
+    existing.set_read_timeout(Some(::std::time::Duration::from_secs(5))).unwrap();
+    let mut res = [0; 128];
+    existing.write(&b"hello"[..]).unwrap();
+    let sz = existing.read(&mut res[..]).expect("could not read from socket");
+    assert_eq!(&res[..sz], &b"hello"[..]);
+
+    let burst = thread::spawn(move|| {
+      let mut sockets = Vec::new();
+      for _ in 0..50 {
+        if let Ok(s) = TcpStream::connect("127.0.0.1:1237") {
+          sockets.push(s);
+        }
+      }
+      sockets
+    });
+
+    // the existing session must still be serviced while the burst of new
+    // connections is accepted
+    for i in 0..20 {
+      let msg = format!("ping {}", i);
+      existing.write(msg.as_bytes()).unwrap();
+      let sz = existing.read(&mut res[..]).expect("existing session stalled during the accept burst");
+      assert_eq!(&res[..sz], msg.as_bytes());
+    }
+
+    let sockets = burst.join().unwrap();
+    assert!(!sockets.is_empty());
+    BURST_TEST_FINISHED.store(true, Ordering::Relaxed);
+  }
+
+  #[test]
+  fn order_flood() {
+    setup_test_logger!();
+    let barrier = Arc::new(Barrier::new(2));
+    start_server("127.0.0.1:5681", &FLOOD_TEST_FINISHED, barrier.clone());
+
+    let mut server_config: server::ServerConfig = Default::default();
+    server_config.max_orders_per_tick = 2;
+    let mut command = start_proxy_thread("127.0.0.1:1238".parse().unwrap(), server_config);
+    command.write_message(&ProxyRequest {
+      id: String::from("ID_FLOOD1"),
+      order: ProxyRequestData::AddTcpFront(TcpFront {
+        app_id: String::from("flood"),
+        address: "127.0.0.1:1238".parse().unwrap(),
+      })
+    });
+    command.write_message(&ProxyRequest {
+      id: String::from("ID_FLOOD2"),
+      order: ProxyRequestData::AddBackend(proxy::Backend {
+        app_id: String::from("flood"),
+        backend_id: String::from("flood-0"),
+        address: "127.0.0.1:5681".parse().unwrap(),
+        load_balancing_parameters: Some(LoadBalancingParams::default()),
+        sticky_id: None,
+        backup: None,
+        hostname: None,
+      })
+    });
+    println!("read_message: {:?}", command.read_message().unwrap());
+    println!("read_message: {:?}", command.read_message().unwrap());
+    barrier.wait();
+
+    let mut existing = TcpStream::connect("127.0.0.1:1238").expect("could not connect");
+    existing.set_read_timeout(Some(::std::time::Duration::from_secs(5))).unwrap();
+    let mut res = [0; 128];
+    existing.write(&b"hello"[..]).unwrap();
+    let sz = existing.read(&mut res[..]).expect("could not read from socket");
+    assert_eq!(&res[..sz], &b"hello"[..]);
+
+    let orders = 500;
+    let flood = thread::spawn(move|| {
+      for i in 0..orders {
+        command.write_message(&ProxyRequest {
+          id: format!("ID_FLOOD-{}", i),
+          order: ProxyRequestData::AddBackend(proxy::Backend {
+            app_id: String::from("other"),
+            backend_id: format!("other-{}", i),
+            address: format!("127.0.0.1:{}", 10000 + i).parse().unwrap(),
+            load_balancing_parameters: Some(LoadBalancingParams::default()),
+            sticky_id: None,
+            backup: None,
+            hostname: None,
+          })
+        });
+      }
+
+      // the orders are delayed, not dropped
+      (0..orders).map(|i| {
+        let answer = command.read_message().expect("should receive an answer");
+        answer.id == format!("ID_FLOOD-{}", i) && answer.status == ProxyResponseStatus::Ok
+      }).filter(|ok| *ok).count()
+    });
+
+    // the existing session must still be serviced while the orders are handled
+    for i in 0..20 {
+      let msg = format!("ping {}", i);
+      existing.write(msg.as_bytes()).unwrap();
+      let sz = existing.read(&mut res[..]).expect("existing session stalled during the order flood");
+      assert_eq!(&res[..sz], msg.as_bytes());
+    }
+
+    assert_eq!(flood.join().unwrap(), orders);
+    FLOOD_TEST_FINISHED.store(true, Ordering::Relaxed);
+  }
+
+  #[test]
+  fn ipv6_front_and_backend() {
+    setup_test_logger!();
+    let barrier = Arc::new(Barrier::new(2));
+    start_server("[::1]:5682", &IPV6_TEST_FINISHED, barrier.clone());
+
+    let mut command = start_proxy_thread("[::1]:1125".parse().unwrap(), Default::default());
+    command.write_message(&ProxyRequest {
+      id: String::from("ID_IPV6_1"),
+      order: ProxyRequestData::AddTcpFront(TcpFront {
+        app_id: String::from("ipv6"),
+        address: "[::1]:1125".parse().unwrap(),
+      })
+    });
+    command.write_message(&ProxyRequest {
+      id: String::from("ID_IPV6_2"),
+      order: ProxyRequestData::AddBackend(proxy::Backend {
+        app_id: String::from("ipv6"),
+        backend_id: String::from("ipv6-0"),
+        address: "[::1]:5682".parse().unwrap(),
+        load_balancing_parameters: Some(LoadBalancingParams::default()),
+        sticky_id: None,
+        backup: None,
+        hostname: None,
+      })
+    });
+    assert_eq!(command.read_message().unwrap().status, ProxyResponseStatus::Ok);
+    assert_eq!(command.read_message().unwrap().status, ProxyResponseStatus::Ok);
+    barrier.wait();
+
+    let mut client = TcpStream::connect("[::1]:1125").expect("could not connect");
+    client.set_read_timeout(Some(::std::time::Duration::from_secs(5))).unwrap();
+    let mut res = [0; 128];
+    client.write(&b"hello over IPv6"[..]).unwrap();
+    let sz = client.read(&mut res[..]).expect("could not read from socket");
+    assert_eq!(&res[..sz], &b"hello over IPv6"[..]);
+    IPV6_TEST_FINISHED.store(true, Ordering::Relaxed);
+  }
+
+  #[test]
+  fn order_metrics() {
+    use sozu_command::proxy::{FilteredData, ListenerType, ProxyResponseData, RemoveListener};
+
+    setup_test_logger!();
+    let mut command = start_proxy_thread("127.0.0.1:1239".parse().unwrap(), Default::default());
+    command.write_message(&ProxyRequest {
+      id: String::from("ID_METRICS1"),
+      order: ProxyRequestData::AddTcpFront(TcpFront {
+        app_id: String::from("metrics"),
+        address: "127.0.0.1:1239".parse().unwrap(),
+      })
+    });
+    command.write_message(&ProxyRequest {
+      id: String::from("ID_METRICS2"),
+      order: ProxyRequestData::RemoveListener(RemoveListener {
+        front: "127.0.0.1:1240".parse().unwrap(),
+        proxy: ListenerType::TCP,
+      })
+    });
+    command.write_message(&ProxyRequest {
+      id: String::from("ID_METRICS3"),
+      order: ProxyRequestData::Metrics,
+    });
+
+    assert_eq!(command.read_message().unwrap().status, ProxyResponseStatus::Ok);
+    match command.read_message().unwrap().status {
+      ProxyResponseStatus::Error(_) => {},
+      status => panic!("expected an error, got {:?}", status),
+    }
+
+    let metrics = match command.read_message().unwrap().data {
+      Some(ProxyResponseData::Metrics(metrics)) => metrics.proxy,
+      data => panic!("expected metrics, got {:?}", data),
+    };
+
+    // the metrics order is counted before being processed
+    assert_eq!(metrics.get("orders.processed"), Some(&FilteredData::Count(3)));
+    assert_eq!(metrics.get("orders.add_tcp_front"), Some(&FilteredData::Count(1)));
+    assert_eq!(metrics.get("orders.remove_listener"), Some(&FilteredData::Count(1)));
+    assert_eq!(metrics.get("orders.errors"), Some(&FilteredData::Count(1)));
+    match metrics.get("orders.processing_time") {
+      Some(&FilteredData::Percentiles(ref percentiles)) => assert_eq!(percentiles.samples, 2),
+      data => panic!("the order processing time should be recorded, got {:?}", data),
+    }
+  }
+
+  #[test]
+  fn relay_accounting() {
+    use mio_uds::UnixDatagram;
+    use sozu_command::proxy::{FilteredData, ProxyResponseData};
+
+    setup_test_logger!();
+    let backend = TcpListener::bind("127.0.0.1:5683").expect("could not bind");
+    thread::spawn(move|| {
+      let (mut stream, _) = backend.accept().expect("could not accept");
+      let mut buf = [0; 10];
+      stream.read_exact(&mut buf[..]).expect("could not read from the relay");
+      assert_eq!(&buf[..], &b"ping relay"[..]);
+      stream.write_all(&b"pong from backend"[..]).unwrap();
+    });
+
+    let (log_backend, receiver) = UnixDatagram::pair().expect("should create a socket pair");
+    let mut command = start_logged_proxy_thread("127.0.0.1:1129".parse().unwrap(), Default::default(),
+      Some(logging::LoggerBackend::Unix(log_backend)));
+    command.write_message(&ProxyRequest {
+      id: String::from("ID_RELAY1"),
+      order: ProxyRequestData::AddTcpFront(TcpFront {
+        app_id: String::from("relay"),
+        address: "127.0.0.1:1129".parse().unwrap(),
+      })
+    });
+    command.write_message(&ProxyRequest {
+      id: String::from("ID_RELAY2"),
+      order: ProxyRequestData::AddBackend(proxy::Backend {
+        app_id: String::from("relay"),
+        backend_id: String::from("relay-0"),
+        address: "127.0.0.1:5683".parse().unwrap(),
+        load_balancing_parameters: Some(LoadBalancingParams::default()),
+        sticky_id: None,
+        backup: None,
+        hostname: None,
+      })
+    });
+    assert_eq!(command.read_message().unwrap().status, ProxyResponseStatus::Ok);
+    assert_eq!(command.read_message().unwrap().status, ProxyResponseStatus::Ok);
+
+    let mut client = TcpStream::connect("127.0.0.1:1129").expect("could not connect");
+    client.set_read_timeout(Some(::std::time::Duration::from_secs(5))).unwrap();
+    client.write_all(&b"ping relay"[..]).unwrap();
+    let mut response = Vec::new();
+    client.read_to_end(&mut response).expect("could not read from the relay");
+    assert_eq!(&response[..], &b"pong from backend"[..]);
+    let client_address = client.local_addr().unwrap().to_string();
+    drop(client);
+
+    let mut records = Vec::new();
+    let mut buf = [0u8; 4096];
+    // the session is closed when the proxy sees the client's end of stream
+    for _ in 0..50 {
+      match receiver.recv(&mut buf) {
+        Ok(size) => records.push(String::from_utf8_lossy(&buf[..size]).into_owned()),
+        Err(_)   => thread::sleep(::std::time::Duration::from_millis(100)),
+      }
+      if records.iter().any(|record| record.contains("\"bytes_in\"")) {
+        break;
+      }
+    }
+
+    let connected = records.iter().find(|record| record.contains("connected\"")).expect("the connection should be logged");
+    assert!(connected.contains(&format!("{} -> 127.0.0.1:5683", client_address)), "{}", connected);
+
+    let closed = records.last().unwrap();
+    for field in [
+      format!("\"client\":\"{}\"", client_address),
+      String::from("\"backend\":\"127.0.0.1:5683\""),
+      String::from("\"protocol\":\"TCP\""),
+      String::from("\"app_id\":\"relay\""),
+      String::from("\"backend_id\":\"relay-0\""),
+      String::from("\"bytes_in\":10"),
+      String::from("\"bytes_out\":17"),
+      String::from("\"response_time\":"),
+    ].iter() {
+      assert!(closed.contains(field.as_str()), "{} not in {}", field, closed);
+    }
+
+    command.write_message(&ProxyRequest { id: String::from("ID_RELAY3"), order: ProxyRequestData::Metrics });
+    let metrics = match command.read_message().unwrap().data {
+      Some(ProxyResponseData::Metrics(metrics)) => metrics,
+      data => panic!("expected metrics, got {:?}", data),
+    };
+    assert_eq!(metrics.proxy.get("bytes_in"), Some(&FilteredData::Count(10)));
+    assert_eq!(metrics.proxy.get("bytes_out"), Some(&FilteredData::Count(17)));
+    let backend = &metrics.applications["relay"].backends["relay-0"];
+    // bytes read from the backend and written to it
+    assert_eq!(backend.get("bytes_in"), Some(&FilteredData::Count(17)));
+    assert_eq!(backend.get("bytes_out"), Some(&FilteredData::Count(10)));
+  }
+
+  #[test]
+  fn backend_connect_timeout() {
+    use std::os::unix::io::AsRawFd;
+    use std::time::{Duration, Instant};
+    use sozu_command::proxy::{ProxyResponseData, Query, QueryAnswer, QueryBackendStats};
+
+    setup_test_logger!();
+    // with an accept queue of one connection, already used, the kernel drops
+    // the next SYN packets and the proxy's connection never completes
+    let backend = TcpListener::bind("127.0.0.1:5688").expect("could not bind");
+    unsafe { ::libc::listen(backend.as_raw_fd(), 0) };
+    let _queued = TcpStream::connect("127.0.0.1:5688").expect("could not connect");
+
+    // the TCP connect timeout replaces the global one of 3 seconds
+    let server_config = server::ServerConfig { tcp_connect_timeout: Some(1), ..Default::default() };
+    let mut command = start_proxy_thread("127.0.0.1:1137".parse().unwrap(), server_config);
+    command.write_message(&ProxyRequest {
+      id: String::from("ID_TIMEOUT1"),
+      order: ProxyRequestData::AddTcpFront(TcpFront {
+        app_id: String::from("database"),
+        address: "127.0.0.1:1137".parse().unwrap(),
+      })
+    });
+    command.write_message(&ProxyRequest {
+      id: String::from("ID_TIMEOUT2"),
+      order: ProxyRequestData::AddBackend(proxy::Backend {
+        app_id: String::from("database"),
+        backend_id: String::from("database-0"),
+        address: "127.0.0.1:5688".parse().unwrap(),
+        load_balancing_parameters: Some(LoadBalancingParams::default()),
+        sticky_id: None,
+        backup: None,
+        hostname: None,
+      })
+    });
+    assert_eq!(command.read_message().unwrap().status, ProxyResponseStatus::Ok);
+    assert_eq!(command.read_message().unwrap().status, ProxyResponseStatus::Ok);
+
+    let start_time = Instant::now();
+    let mut client = TcpStream::connect("127.0.0.1:1137").expect("could not connect");
+    client.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    let mut buf = [0; 16];
+    // the session is closed once the connection attempts failed
+    match client.read(&mut buf[..]) {
+      Ok(size) => assert_eq!(size, 0),
+      Err(e)   => assert_eq!(e.kind(), ::std::io::ErrorKind::ConnectionReset),
+    }
+    let elapsed = start_time.elapsed();
+    assert!(elapsed >= Duration::from_secs(1));
+    assert!(elapsed < Duration::from_secs(3), "the session was closed after {:?}", elapsed);
+
+    command.write_message(&ProxyRequest {
+      id: String::from("ID_TIMEOUT3"),
+      order: ProxyRequestData::Query(Query::BackendStats(QueryBackendStats {
+        app_id: String::from("database"),
+        backend_id: String::from("database-0"),
+      }))
+    });
+    // the events sent when the backend failed come before the answer
+    let answer = loop {
+      let message = command.read_message().expect("the proxy should answer");
+      if message.id == "ID_TIMEOUT3" {
+        break message;
+      }
+    };
+    match answer.data {
+      Some(ProxyResponseData::Query(QueryAnswer::BackendStats(Some(stats)))) => {
+        assert!(stats.failures >= 1, "{:?}", stats);
+        assert_eq!(stats.active_connections, 0);
+      },
+      data => panic!("expected the backend stats, got {:?}", data),
+    }
+  }
+
+  fn start_server(address: &str, finished: &'static AtomicBool, barrier: Arc<Barrier>) {
+    let listener = TcpListener::bind(address).expect("could not parse address");
+    fn handle_client(stream: &mut TcpStream, id: u8, finished: &AtomicBool) {
       let mut buf = [0; 128];
       let response = b" END";
       while let Ok(sz) = stream.read(&mut buf[..]) {
@@ -1206,7 +1745,7 @@ mod tests {
           println!("ECHO[{}] got \"{:?}\"", id, str::from_utf8(&buf[..sz]));
           stream.write(&buf[..sz]).unwrap();
         }
-        if TEST_FINISHED.load(Ordering::Relaxed) {
+        if finished.load(Ordering::Relaxed) {
           println!("backend server stopping");
           break;
         }
@@ -1221,7 +1760,7 @@ mod tests {
           Ok(mut stream) => {
             thread::spawn(move|| {
               println!("got a new client: {}", count);
-              handle_client(&mut stream, count)
+              handle_client(&mut stream, count, finished)
             });
           }
           Err(e) => { println!("connection failed"); }
@@ -1232,11 +1771,71 @@ mod tests {
   }
 
   pub fn start_proxy() -> Channel<ProxyRequest,ProxyResponse> {
-    use server::{self,ProxySessionCast};
+    let mut command = start_proxy_thread("127.0.0.1:1234".parse().unwrap(), Default::default());
+    {
+      let front = TcpFront {
+        app_id: String::from("yolo"),
+        address: "127.0.0.1:1234".parse().unwrap(),
+      };
+      let backend = proxy::Backend {
+        app_id: String::from("yolo"),
+        backend_id: String::from("yolo-0"),
+        address: "127.0.0.1:5678".parse().unwrap(),
+        load_balancing_parameters: Some(LoadBalancingParams::default()),
+        sticky_id: None,
+        backup: None,
+        hostname: None,
+      };
+
+      command.write_message(&ProxyRequest { id: String::from("ID_YOLO1"), order: ProxyRequestData::AddTcpFront(front) });
+      command.write_message(&ProxyRequest { id: String::from("ID_YOLO2"), order: ProxyRequestData::AddBackend(backend) });
+    }
+    {
+      let front = TcpFront {
+        app_id: String::from("yolo"),
+        address: "127.0.0.1:1235".parse().unwrap(),
+      };
+      let backend = proxy::Backend {
+        app_id: String::from("yolo"),
+        backend_id: String::from("yolo-0"),
+        address: "127.0.0.1:5678".parse().unwrap(),
+        load_balancing_parameters: Some(LoadBalancingParams::default()),
+        sticky_id: None,
+        backup: None,
+        hostname: None,
+      };
+      command.write_message(&ProxyRequest { id: String::from("ID_YOLO3"), order: ProxyRequestData::AddTcpFront(front) });
+      command.write_message(&ProxyRequest { id: String::from("ID_YOLO4"), order: ProxyRequestData::AddBackend(backend) });
+    }
+
+    println!("read_message: {:?}", command.read_message().unwrap());
+    println!("read_message: {:?}", command.read_message().unwrap());
+    println!("read_message: {:?}", command.read_message().unwrap());
+    println!("read_message: {:?}", command.read_message().unwrap());
+
+    command
+  }
+
+  fn start_proxy_thread(front: SocketAddr, server_config: server::ServerConfig) -> Channel<ProxyRequest,ProxyResponse> {
+    start_logged_proxy_thread(front, server_config, None)
+  }
+
+  /// the proxy thread writes its logs in JSON to the logger backend, if there is one
+  fn start_logged_proxy_thread(front: SocketAddr, mut server_config: server::ServerConfig,
+    logger: Option<logging::LoggerBackend>) -> Channel<ProxyRequest,ProxyResponse> {
+    use server::ProxySessionCast;
 
     info!("listen for connections");
     let (mut command, channel) = Channel::generate(1000, 10000).expect("should create a channel");
     thread::spawn(move|| {
+      if let Some(backend) = logger {
+        logging::LOGGER.with(|l| {
+          let mut logger = l.borrow_mut();
+          logger.set_directives(logging::parse_logging_spec("info"));
+          logger.backend = backend;
+          logger.set_format(logging::LogFormat::Json);
+        });
+      }
       info!("starting event loop");
       let mut poll = Poll::new().expect("could not create event loop");
       let max_buffers = 100;
@@ -1265,9 +1864,13 @@ mod tests {
 
       let mut configuration = Proxy::new(backends.clone());
       let listener_config = TcpListenerConfig {
-        front: "127.0.0.1:1234".parse().unwrap(),
+        front,
         public_address: None,
         expect_proxy: false,
+        interface: None,
+        timeouts: Default::default(),
+        connection_rate_limit: None,
+        max_sessions: None,
       };
 
       {
@@ -1286,55 +1889,15 @@ mod tests {
         tcp:  Vec::new(),
       });
 
-      let mut server_config: server::ServerConfig = Default::default();
       server_config.max_connections = max_buffers;
       let mut s   = Server::new(poll, channel, ScmSocket::new(scm_server.into_raw_fd()),
-        sessions, pool, backends, None, None, Some(configuration), server_config, None);
+        sessions, pool, None, backends, None, None, Some(configuration), server_config, None);
       info!("will run");
       s.run();
       info!("ending event loop");
     });
 
     command.set_blocking(true);
-    {
-      let front = TcpFront {
-        app_id: String::from("yolo"),
-        address: "127.0.0.1:1234".parse().unwrap(),
-      };
-      let backend = proxy::Backend {
-        app_id: String::from("yolo"),
-        backend_id: String::from("yolo-0"),
-        address: "127.0.0.1:5678".parse().unwrap(),
-        load_balancing_parameters: Some(LoadBalancingParams::default()),
-        sticky_id: None,
-        backup: None,
-      };
-
-      command.write_message(&ProxyRequest { id: String::from("ID_YOLO1"), order: ProxyRequestData::AddTcpFront(front) });
-      command.write_message(&ProxyRequest { id: String::from("ID_YOLO2"), order: ProxyRequestData::AddBackend(backend) });
-    }
-    {
-      let front = TcpFront {
-        app_id: String::from("yolo"),
-        address: "127.0.0.1:1235".parse().unwrap(),
-      };
-      let backend = proxy::Backend {
-        app_id: String::from("yolo"),
-        backend_id: String::from("yolo-0"),
-        address: "127.0.0.1:5678".parse().unwrap(),
-        load_balancing_parameters: Some(LoadBalancingParams::default()),
-        sticky_id: None,
-        backup: None,
-      };
-      command.write_message(&ProxyRequest { id: String::from("ID_YOLO3"), order: ProxyRequestData::AddTcpFront(front) });
-      command.write_message(&ProxyRequest { id: String::from("ID_YOLO4"), order: ProxyRequestData::AddBackend(backend) });
-    }
-
-    println!("read_message: {:?}", command.read_message().unwrap());
-    println!("read_message: {:?}", command.read_message().unwrap());
-    println!("read_message: {:?}", command.read_message().unwrap());
-    println!("read_message: {:?}", command.read_message().unwrap());
-
     command
   }
 }
@@ -0,0 +1,153 @@
+use std::cell::RefCell;
+use std::net::IpAddr;
+use std::collections::HashSet;
+use time::{Duration, SteadyTime};
+
+use sozu_command::proxy::{Trace, TraceFilter};
+
+thread_local! {
+  pub static TRACES: RefCell<Traces> = RefCell::new(Traces::new());
+}
+
+/// temporary filters selecting requests that will be logged verbosely,
+/// independently of the current logging level
+pub struct Traces {
+  traces: Vec<(TraceFilter, SteadyTime)>,
+  /// applications whose requests are traced until it is disabled
+  apps:   HashSet<String>,
+}
+
+impl Traces {
+  pub fn new() -> Traces {
+    Traces {
+      traces: Vec::new(),
+      apps:   HashSet::new(),
+    }
+  }
+
+  pub fn add(&mut self, trace: Trace) {
+    self.add_at(trace, SteadyTime::now());
+  }
+
+  pub fn add_at(&mut self, trace: Trace, now: SteadyTime) {
+    let expiration = now + Duration::seconds(trace.duration as i64);
+    info!("tracing requests matching {:?} for {} seconds", trace.filter, trace.duration);
+    self.traces.push((trace.filter, expiration));
+  }
+
+  pub fn is_traced(&mut self, hostname: Option<&str>, path: Option<&str>, client_ip: Option<IpAddr>) -> bool {
+    self.is_traced_at(hostname, path, client_ip, SteadyTime::now())
+  }
+
+  pub fn is_traced_at(&mut self, hostname: Option<&str>, path: Option<&str>, client_ip: Option<IpAddr>,
+    now: SteadyTime) -> bool {
+    if self.traces.is_empty() {
+      return false;
+    }
+
+    self.traces.retain(|&(_, expiration)| expiration > now);
+    self.traces.iter().any(|&(ref filter, _)| filter.matches(hostname, path, client_ip))
+  }
+
+  pub fn len(&self) -> usize {
+    self.traces.len()
+  }
+
+  pub fn set_app(&mut self, app_id: &str, enabled: bool) {
+    if enabled {
+      info!("tracing the requests of application {}", app_id);
+      self.apps.insert(app_id.to_string());
+    } else if self.apps.remove(app_id) {
+      info!("stopped tracing the requests of application {}", app_id);
+    }
+  }
+
+  pub fn is_app_traced(&self, app_id: &str) -> bool {
+    self.apps.contains(app_id)
+  }
+}
+
+pub fn has_traces() -> bool {
+  TRACES.with(|traces| traces.borrow().len() > 0)
+}
+
+pub fn is_traced(hostname: Option<&str>, path: Option<&str>, client_ip: Option<IpAddr>) -> bool {
+  TRACES.with(|traces| traces.borrow_mut().is_traced(hostname, path, client_ip))
+}
+
+pub fn is_app_traced(app_id: &str) -> bool {
+  TRACES.with(|traces| traces.borrow().is_app_traced(app_id))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn trace(hostname: Option<&str>, path_begin: Option<&str>, client_ip: Option<&str>, duration: u64) -> Trace {
+    Trace {
+      filter: TraceFilter {
+        hostname: hostname.map(String::from),
+        path_begin: path_begin.map(String::from),
+        client_ip: client_ip.map(|ip| ip.parse().unwrap()),
+      },
+      duration,
+    }
+  }
+
+  #[test]
+  fn only_matching_requests_are_traced() {
+    let now = SteadyTime::now();
+    let mut traces = Traces::new();
+    assert!(!traces.is_traced_at(Some("example.com"), Some("/"), None, now));
+
+    traces.add_at(trace(Some("example.com"), Some("/api"), None, 60), now);
+    traces.add_at(trace(None, None, Some("10.0.0.1"), 60), now);
+
+    assert!(traces.is_traced_at(Some("example.com"), Some("/api/users"), None, now));
+    assert!(traces.is_traced_at(Some("EXAMPLE.com"), Some("/api"), None, now));
+    assert!(!traces.is_traced_at(Some("example.com"), Some("/static"), None, now));
+    assert!(!traces.is_traced_at(Some("other.com"), Some("/api"), None, now));
+    assert!(!traces.is_traced_at(None, Some("/api"), None, now));
+
+    let ip = "10.0.0.1".parse().ok();
+    let other_ip = "10.0.0.2".parse().ok();
+    assert!(traces.is_traced_at(Some("other.com"), Some("/"), ip, now));
+    assert!(!traces.is_traced_at(Some("other.com"), Some("/"), other_ip, now));
+  }
+
+  #[test]
+  fn traces_expire() {
+    let now = SteadyTime::now();
+    let mut traces = Traces::new();
+    traces.add_at(trace(Some("example.com"), None, None, 10), now);
+    traces.add_at(trace(Some("example.com"), None, None, 60), now);
+
+    assert!(traces.is_traced_at(Some("example.com"), None, None, now + Duration::seconds(9)));
+    assert_eq!(traces.len(), 2);
+
+    assert!(traces.is_traced_at(Some("example.com"), None, None, now + Duration::seconds(30)));
+    assert_eq!(traces.len(), 1);
+
+    assert!(!traces.is_traced_at(Some("example.com"), None, None, now + Duration::seconds(60)));
+    assert_eq!(traces.len(), 0);
+  }
+
+  #[test]
+  fn only_traced_apps_are_traced() {
+    let mut traces = Traces::new();
+    assert!(!traces.is_app_traced("app_1"));
+
+    traces.set_app("app_1", true);
+    assert!(traces.is_app_traced("app_1"));
+    assert!(!traces.is_app_traced("app_2"));
+    assert!(!traces.is_app_traced("app"));
+
+    // the filters do not apply to the traced applications
+    assert!(!traces.is_traced(Some("example.com"), Some("/"), None));
+
+    traces.set_app("app_2", false);
+    assert!(traces.is_app_traced("app_1"));
+    traces.set_app("app_1", false);
+    assert!(!traces.is_app_traced("app_1"));
+  }
+}
@@ -0,0 +1,91 @@
+//! percentage based traffic splitting between a frontend's application and
+//! a canary application
+//!
+//! A client identifier, the value of a cookie or the client address, is hashed
+//! into one of 100 buckets. The clients in the buckets below the percentage go
+//! to the canary application. The hash does not depend on the worker or on the
+//! process, so a client stays on the same side, and raising the percentage only
+//! moves clients from the stable application to the canary one.
+use std::net::IpAddr;
+use sozu_command::proxy::TrafficSplit;
+
+/// bucket of a client identifier, from 0 to 99 (FNV-1a hash)
+pub fn client_bucket(client_id: &[u8]) -> u8 {
+  let mut hash: u32 = 0x811c_9dc5;
+  for byte in client_id {
+    hash ^= u32::from(*byte);
+    hash = hash.wrapping_mul(0x0100_0193);
+  }
+  (hash % 100) as u8
+}
+
+/// application receiving the request of a frontend. `cookie` returns the value of
+/// a request cookie. Without a client identifier, the request goes to the
+/// frontend's application
+pub fn app_id<'a, C>(app_id: &'a str, split: Option<&'a TrafficSplit>, cookie: C, client: Option<IpAddr>) -> &'a str
+  where C: Fn(&str) -> Option<String> {
+  let split = match split {
+    Some(split) => split,
+    None        => return app_id,
+  };
+
+  let client_id = split.cookie.as_ref().and_then(|name| cookie(name))
+    .or_else(|| client.map(|ip| ip.to_string()));
+
+  match client_id {
+    Some(id) if client_bucket(id.as_bytes()) < split.percentage => &split.canary_app_id,
+    _ => app_id,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn canary(percentage: u8, cookie: Option<&str>) -> TrafficSplit {
+    TrafficSplit { canary_app_id: String::from("app_canary"), percentage, cookie: cookie.map(String::from) }
+  }
+
+  #[test]
+  fn split_ratio() {
+    let split = canary(20, None);
+    let canary_clients = (0..10_000u32)
+      .map(|i| IpAddr::from([10, (i >> 16) as u8, (i >> 8) as u8, i as u8]))
+      .filter(|ip| app_id("app_stable", Some(&split), |_| None, Some(*ip)) == "app_canary")
+      .count();
+    assert!(canary_clients > 1_800 && canary_clients < 2_200, "{} clients out of 10000 went to the canary", canary_clients);
+
+    let ip = Some("10.0.0.1".parse().unwrap());
+    assert_eq!(app_id("app_stable", Some(&canary(0, None)), |_| None, ip), "app_stable");
+    assert_eq!(app_id("app_stable", Some(&canary(100, None)), |_| None, ip), "app_canary");
+    assert_eq!(app_id("app_stable", None, |_| None, ip), "app_stable");
+  }
+
+  #[test]
+  fn sticky_client() {
+    let split = canary(50, Some("SOZU_CANARY"));
+    let cookie = |value: &'static str| move |name: &str| if name == "SOZU_CANARY" { Some(String::from(value)) } else { None };
+
+    // the cookie is used before the address
+    let side = app_id("app_stable", Some(&split), cookie("client-42"), None);
+    for i in 0..100u8 {
+      assert_eq!(app_id("app_stable", Some(&split), cookie("client-42"), Some(IpAddr::from([10, 0, 0, i]))), side);
+    }
+
+    // raising the percentage does not send canary clients back to the stable application
+    let raised = canary(80, Some("SOZU_CANARY"));
+    for i in 0..1000u32 {
+      let id = format!("client-{}", i);
+      let bucket = client_bucket(id.as_bytes());
+      let before = app_id("app_stable", Some(&split), |_| Some(id.clone()), None);
+      let after = app_id("app_stable", Some(&raised), |_| Some(id.clone()), None);
+      assert_eq!(before == "app_canary", bucket < 50);
+      if before == "app_canary" {
+        assert_eq!(after, "app_canary");
+      }
+    }
+
+    // no cookie and no address
+    assert_eq!(app_id("app_stable", Some(&canary(100, Some("SOZU_CANARY"))), |_| None, None), "app_stable");
+  }
+}
@@ -219,16 +219,23 @@ impl<V:Debug+Clone> TrieNode<V> {
     None
   }
 
-  // specific version that will handle wildcard domains
+  // specific version that will handle wildcard domains.
+  // A wildcard can only replace the complete leftmost label (`*.example.com`),
+  // and matches exactly one label
   pub fn domain_insert(&mut self, key: Key, value: V) -> InsertResult {
-    let mut partial_key = key.clone();
+    if !is_valid_wildcard(&key) {
+      return InsertResult::Failed;
+    }
+
+    // the original key is stored, but hostnames are matched case insensitively
+    let mut partial_key = key.to_ascii_lowercase();
     partial_key.reverse();
     self.insert_recursive(&partial_key, &key, value)
   }
 
   // specific version that will handle wildcard domains
   pub fn domain_remove(&mut self, key: &Key) -> RemoveResult {
-    let mut partial_key = key.clone();
+    let mut partial_key = key.to_ascii_lowercase();
     partial_key.reverse();
     self.remove_recursive(&partial_key)
   }
@@ -240,7 +247,7 @@ impl<V:Debug+Clone> TrieNode<V> {
     }
 
     //println!("looking up: {}", str::from_utf8(key).unwrap());
-    let mut partial_key = key.to_vec();
+    let mut partial_key = key.to_ascii_lowercase();
     partial_key.reverse();
     let res = self.domain_lookup_recursive(&partial_key, accept_wildcard);
     //println!(" => {:?}", res.map(|(k,v)| (str::from_utf8(k).unwrap().to_owned(), v)));
@@ -286,7 +293,10 @@ impl<V:Debug+Clone> TrieNode<V> {
               return Some(r);
             }
           } else if partial_key.len() == child_key.len() {
-            return self.children[index].key_value.as_ref();
+            // an intermediate node has no value, a wildcard sibling might still match
+            if let Some(r) = self.children[index].key_value.as_ref() {
+              return Some(r);
+            }
           }
         }
       }
@@ -297,7 +307,7 @@ impl<V:Debug+Clone> TrieNode<V> {
 
   // specific version that will handle wildcard domains
   pub fn domain_lookup_mut(&mut self, key: &[u8], accept_wildcard: bool) -> Option<&mut KeyValue<Key,V>> {
-    let mut partial_key = key.to_vec();
+    let mut partial_key = key.to_ascii_lowercase();
     partial_key.reverse();
     self.domain_lookup_mut_recursive(&partial_key, accept_wildcard)
   }
@@ -336,11 +346,15 @@ impl<V:Debug+Clone> TrieNode<V> {
         },
         None    => {
           if partial_key.len() > child_key.len() {
-            return self.children[index].domain_lookup_mut_recursive(&partial_key[child_key.len()..], accept_wildcard);
+            // the immutable lookup tells us if we can return from the child,
+            // or if we must fall back to a wildcard from this node
+            if self.children[index].domain_lookup_recursive(&partial_key[child_key.len()..], accept_wildcard).is_some() {
+              return self.children[index].domain_lookup_mut_recursive(&partial_key[child_key.len()..], accept_wildcard);
+            }
           } else if partial_key.len() == child_key.len() {
-            return self.children[index].key_value.as_mut();
-          } else {
-            return None;
+            if self.children[index].key_value.is_some() {
+              return self.children[index].key_value.as_mut();
+            }
           }
         }
       }
@@ -368,6 +382,26 @@ impl<V:Debug+Clone> TrieNode<V> {
     }
   }
 
+  /// lists the key-value pairs of the trie, sorted by key, without modifying it
+  pub fn entries(&self) -> Vec<&KeyValue<Key,V>> {
+    let mut v = Vec::new();
+
+    self.entries_recursive(&mut v);
+    v.sort_by(|a, b| a.0.cmp(&b.0));
+
+    v
+  }
+
+  pub fn entries_recursive<'a>(&'a self, v: &mut Vec<&'a KeyValue<Key,V>>) {
+    if let Some(ref kv) = self.key_value {
+      v.push(kv);
+    }
+
+    for child in self.children.iter() {
+      child.entries_recursive(v);
+    }
+  }
+
   pub fn to_hashmap(&self) -> HashMap<Key, V> {
     let mut h = HashMap::new();
 
@@ -387,6 +421,15 @@ impl<V:Debug+Clone> TrieNode<V> {
   }
 }
 
+/// a wildcard is only accepted as the complete leftmost label
+fn is_valid_wildcard(key: &[u8]) -> bool {
+  match key.iter().position(|&c| c == b'*') {
+    None    => true,
+    Some(0) => key.len() > 2 && key[1] == b'.' && !(&key[1..]).contains(&b'*'),
+    Some(_) => false,
+  }
+}
+
 #[cfg(test)]
 mod tests {
   use super::*;
@@ -507,6 +550,105 @@ mod tests {
     assert_eq!(root.domain_lookup(&b"blah.test.alldomains.org"[..], true), None);
   }
 
+  #[test]
+  fn wildcard_domains() {
+    let mut root: TrieNode<u8> = TrieNode::root();
+
+    // the exact domains are inserted before and after the wildcard
+    assert_eq!(root.domain_insert(Vec::from(&b"aa.example.com"[..]), 1), InsertResult::Ok);
+    assert_eq!(root.domain_insert(Vec::from(&b"*.example.com"[..]), 2), InsertResult::Ok);
+    assert_eq!(root.domain_insert(Vec::from(&b"ab.example.com"[..]), 3), InsertResult::Ok);
+    assert_eq!(root.domain_insert(Vec::from(&b"www.example.com"[..]), 4), InsertResult::Ok);
+    root.print();
+
+    // exact matches take precedence
+    assert_eq!(root.domain_lookup(&b"aa.example.com"[..], true), Some(&((&b"aa.example.com"[..]).to_vec(), 1)));
+    assert_eq!(root.domain_lookup(&b"ab.example.com"[..], true), Some(&((&b"ab.example.com"[..]).to_vec(), 3)));
+    assert_eq!(root.domain_lookup(&b"www.example.com"[..], true), Some(&((&b"www.example.com"[..]).to_vec(), 4)));
+
+    assert_eq!(root.domain_lookup(&b"b.example.com"[..], true), Some(&((&b"*.example.com"[..]).to_vec(), 2)));
+    // "a" is an intermediate node without value in the trie
+    assert_eq!(root.domain_lookup(&b"a.example.com"[..], true), Some(&((&b"*.example.com"[..]).to_vec(), 2)));
+    assert_eq!(root.domain_lookup(&b"ww.example.com"[..], true), Some(&((&b"*.example.com"[..]).to_vec(), 2)));
+    assert_eq!(root.domain_lookup_mut(&b"a.example.com"[..], true).map(|kv| kv.1), Some(2));
+    assert_eq!(root.domain_lookup_mut(&b"aa.example.com"[..], true).map(|kv| kv.1), Some(1));
+
+    // the wildcard only matches a single label
+    assert_eq!(root.domain_lookup(&b"example.com"[..], true), None);
+    assert_eq!(root.domain_lookup(&b"a.b.example.com"[..], true), None);
+    assert_eq!(root.domain_lookup(&b"x.aa.example.com"[..], true), None);
+    assert_eq!(root.domain_lookup(&b"a.example.org"[..], true), None);
+    assert_eq!(root.domain_lookup(&b"aexample.com"[..], true), None);
+
+    // without wildcards, only exact matches are returned
+    assert_eq!(root.domain_lookup(&b"a.example.com"[..], false), None);
+    assert_eq!(root.domain_lookup_mut(&b"a.example.com"[..], false), None);
+    assert_eq!(root.domain_lookup(&b"*.example.com"[..], false), Some(&((&b"*.example.com"[..]).to_vec(), 2)));
+
+    assert_eq!(root.domain_insert(Vec::from(&b"a.*.example.com"[..]), 5), InsertResult::Failed);
+    assert_eq!(root.domain_insert(Vec::from(&b"*a.example.com"[..]), 5), InsertResult::Failed);
+    assert_eq!(root.domain_insert(Vec::from(&b"*.*.example.com"[..]), 5), InsertResult::Failed);
+    assert_eq!(root.domain_insert(Vec::from(&b"*."[..]), 5), InsertResult::Failed);
+  }
+
+  #[test]
+  fn case_insensitive_domains() {
+    let mut root: TrieNode<u8> = TrieNode::root();
+
+    assert_eq!(root.domain_insert(Vec::from(&b"Example.com"[..]), 1), InsertResult::Ok);
+    assert_eq!(root.domain_insert(Vec::from(&b"*.Wildcard.ORG"[..]), 2), InsertResult::Ok);
+    // the same domain with another case is the same key
+    assert_eq!(root.domain_insert(Vec::from(&b"EXAMPLE.COM"[..]), 3), InsertResult::Existing);
+
+    // the original key is kept
+    assert_eq!(root.domain_lookup(&b"example.com"[..], true), Some(&((&b"Example.com"[..]).to_vec(), 1)));
+    assert_eq!(root.domain_lookup(&b"EXAMPLE.COM"[..], false), Some(&((&b"Example.com"[..]).to_vec(), 1)));
+    assert_eq!(root.domain_lookup(&b"Test.wildcard.org"[..], true), Some(&((&b"*.Wildcard.ORG"[..]).to_vec(), 2)));
+    assert_eq!(root.domain_lookup_mut(&b"eXaMpLe.CoM"[..], false).map(|kv| kv.1), Some(1));
+
+    // only ASCII characters are case folded
+    assert_eq!(root.domain_insert(Vec::from("ÉCOLE.fr".as_bytes()), 4), InsertResult::Ok);
+    assert_eq!(root.domain_lookup("école.fr".as_bytes(), true), None);
+    assert_eq!(root.domain_lookup("ÉCOLE.FR".as_bytes(), true), Some(&(Vec::from("ÉCOLE.fr".as_bytes()), 4)));
+
+    // a domain added in lowercase can be removed in uppercase, and the opposite
+    assert_eq!(root.domain_remove(&Vec::from(&b"EXAMPLE.com"[..])), RemoveResult::Ok);
+    assert_eq!(root.domain_lookup(&b"example.com"[..], true), None);
+    assert_eq!(root.domain_remove(&Vec::from(&b"*.wildcard.org"[..])), RemoveResult::Ok);
+    assert_eq!(root.domain_lookup(&b"test.wildcard.org"[..], true), None);
+  }
+
+  #[test]
+  fn dump_entries() {
+    let mut root: TrieNode<u8> = TrieNode::root();
+    let domains: &[&[u8]] = &[b"www.example.com", b"example.com", b"*.example.com",
+      b"api.example.com", b"test.org", b"www.test.org", b"*.clever-cloud.com"];
+    for (i, domain) in domains.iter().enumerate() {
+      assert_eq!(root.domain_insert(domain.to_vec(), i as u8), InsertResult::Ok);
+    }
+
+    let expected: Vec<KeyValue<Key,u8>> = vec![
+      (b"*.clever-cloud.com".to_vec(), 6),
+      (b"*.example.com".to_vec(), 2),
+      (b"api.example.com".to_vec(), 3),
+      (b"example.com".to_vec(), 1),
+      (b"test.org".to_vec(), 4),
+      (b"www.example.com".to_vec(), 0),
+      (b"www.test.org".to_vec(), 5),
+    ];
+    let entries: Vec<KeyValue<Key,u8>> = root.entries().into_iter().cloned().collect();
+    assert_eq!(entries, expected);
+
+    // listing the entries does not change the trie
+    let entries: Vec<KeyValue<Key,u8>> = root.entries().into_iter().cloned().collect();
+    assert_eq!(entries, expected);
+    assert_eq!(root.domain_lookup(b"www.example.com", false), Some(&(b"www.example.com".to_vec(), 0)));
+
+    assert_eq!(root.domain_remove(&b"example.com".to_vec()), RemoveResult::Ok);
+    assert_eq!(root.entries().len(), expected.len() - 1);
+    assert!(root.entries().iter().all(|kv| kv.0 != b"example.com".to_vec()));
+  }
+
   fn hm_insert(h: HashMap<String, u32>) -> bool {
     let mut root: TrieNode<u32> = TrieNode::root();
 